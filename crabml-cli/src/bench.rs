@@ -0,0 +1,131 @@
+use std::time::Instant;
+
+use clap::Args;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::AttentionKernel;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+
+const FILLER: &str = "The river ran slowly through the old town, past the bakery and the school. ";
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// The checkpoint file to benchmark
+    #[arg(short, long)]
+    model: String,
+
+    /// The attention kernels to compare, like naive,chunked:128,flash, or all
+    #[arg(long, value_delimiter = ',', default_value = "naive")]
+    attention: Vec<String>,
+
+    /// The length of the prompt to prefill in tokens
+    #[arg(long, default_value_t = 512)]
+    prompt_len: usize,
+
+    /// The number of the tokens to decode after the prompt
+    #[arg(long, default_value_t = 32)]
+    steps: usize,
+
+    /// The number of the runs of each kernel, the fastest one is reported
+    #[arg(long, default_value_t = 1)]
+    runs: usize,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+}
+
+/// the prefill and decode speeds of a kernel.
+struct BenchResult {
+    kernel: AttentionKernel,
+    prefill_tokens_per_second: f64,
+    decode_tokens_per_second: f64,
+}
+
+/// times the prefill of a long prompt and the decoding after it on each attention kernel, the
+/// kernels are expected to generate the same tokens.
+pub fn run_bench(args: &BenchArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let model = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .with_temperature(0.0)
+        .load(&gf)?;
+    let mut runner = Llama2Runner::new(&model, model.conf.seq_len, false)?;
+    let prompt_len = args
+        .prompt_len
+        .min(model.conf.seq_len.saturating_sub(args.steps + 1));
+    let prompt_tokens = filler_tokens(&runner, prompt_len)?;
+
+    let mut results: Vec<BenchResult> = vec![];
+    let mut outputs: Vec<String> = vec![];
+    for kernel in parse_kernels(&args.attention)? {
+        runner.set_attention_kernel(kernel);
+        let mut result = BenchResult {
+            kernel,
+            prefill_tokens_per_second: 0.0,
+            decode_tokens_per_second: 0.0,
+        };
+        for _ in 0..args.runs.max(1) {
+            runner.reset()?;
+            let started_at = Instant::now();
+            let (pos, _, token) = runner.prefill_tokens(prompt_tokens.clone(), true, true)?;
+            let prefill_elapsed = started_at.elapsed().as_secs_f64();
+
+            let started_at = Instant::now();
+            let output = runner
+                .generate(pos, token, Some(args.steps))
+                .collect::<Result<Vec<String>>>()?;
+            let decode_elapsed = started_at.elapsed().as_secs_f64();
+
+            result.prefill_tokens_per_second = result
+                .prefill_tokens_per_second
+                .max(prompt_tokens.len() as f64 / prefill_elapsed);
+            result.decode_tokens_per_second = result
+                .decode_tokens_per_second
+                .max(output.len() as f64 / decode_elapsed);
+            outputs.push(output.join(""));
+        }
+        results.push(result);
+    }
+
+    println!(
+        "{:<16}{:>16}{:>16}",
+        "attention", "prefill tok/s", "decode tok/s"
+    );
+    for result in results.iter() {
+        println!(
+            "{:<16}{:>16.2}{:>16.2}",
+            result.kernel.to_string(),
+            result.prefill_tokens_per_second,
+            result.decode_tokens_per_second
+        );
+    }
+    if outputs.windows(2).any(|w| w[0] != w[1]) {
+        eprintln!("warning: the kernels generated different tokens on the same prompt");
+    }
+    Ok(())
+}
+
+fn parse_kernels(names: &[String]) -> Result<Vec<AttentionKernel>> {
+    if names.iter().any(|name| name == "all") {
+        return Ok(vec![
+            AttentionKernel::Naive,
+            AttentionKernel::Chunked(64),
+            AttentionKernel::Chunked(256),
+            AttentionKernel::Flash,
+        ]);
+    }
+    names.iter().map(|name| name.parse()).collect()
+}
+
+// the prompt repeats the filler sentence until it's prompt_len tokens long, with the BOS
+fn filler_tokens<T: Tensor>(runner: &Llama2Runner<T>, prompt_len: usize) -> Result<Vec<usize>> {
+    let n_repeats = prompt_len / 8 + 1;
+    let mut tokens = runner
+        .tokenizer()
+        .encode(&FILLER.repeat(n_repeats), true, false)?;
+    tokens.truncate(prompt_len.max(1));
+    Ok(tokens)
+}
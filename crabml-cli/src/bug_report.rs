@@ -0,0 +1,105 @@
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFile;
+use crabml_llama2::bug_report::model_hash;
+use crabml_llama2::bug_report::FailureReport;
+use serde_json::json;
+use serde_json::Value;
+
+const BUG_REPORT_VERSION: u32 = 1;
+
+/// the JSON artifact of a failed forward, to be attached to an issue. it identifies the model
+/// by its hash instead of including it, the tensors are summarized by their checksums and the
+/// first values if asked.
+pub fn bug_report_json(report: &FailureReport, model_path: &str, gf: &GGUFFile) -> Value {
+    let inputs = report
+        .inputs
+        .iter()
+        .map(|t| {
+            json!({
+                "name": t.name,
+                "shape": t.shape,
+                "dtype": t.dtype.map(|dtype| dtype.to_string()),
+                "checksum": t.checksum.map(|hash| format!("{:016x}", hash)),
+                "values": t.values,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": BUG_REPORT_VERSION,
+        "error": report.error,
+        "op": report.op,
+        "layer": report.layer,
+        "pos": report.pos,
+        "tokens": report.tokens,
+        "panicked": report.panicked,
+        "inputs": inputs,
+        "backend": report.backend,
+        "model": {
+            "file": model_path,
+            "hash": model_hash(gf),
+            "architecture": gf.architecture(),
+            "name": gf.metadata().get_string("general.name"),
+        },
+        "crabml_version": env!("CARGO_PKG_VERSION"),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+    })
+}
+
+pub fn write_bug_report(
+    path: &str,
+    report: &FailureReport,
+    model_path: &str,
+    gf: &GGUFFile,
+) -> Result<()> {
+    let json = bug_report_json(report, model_path, gf);
+    std::fs::write(path, serde_json::to_string_pretty(&json).unwrap()).map_err(|err| {
+        Error::new(
+            ErrorKind::IOError,
+            format!("failed to write the bug report into {}", path),
+        )
+        .with_cause(err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml_llama2::bug_report::TensorSummary;
+
+    use super::*;
+
+    #[test]
+    fn test_bug_report_json() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let report = FailureReport {
+            error: "TensorError: bad shape".to_string(),
+            op: "matmul".to_string(),
+            layer: Some(3),
+            pos: 12,
+            tokens: vec![450],
+            inputs: vec![TensorSummary {
+                name: "x".to_string(),
+                shape: vec![1, 288],
+                dtype: Some(GGMLType::F32),
+                checksum: Some(0xabc),
+                values: vec![0.5],
+            }],
+            backend: "CpuTensor".to_string(),
+            panicked: false,
+        };
+        let json = bug_report_json(&report, "model.gguf", &gf);
+        assert_eq!(json["op"], "matmul");
+        assert_eq!(json["layer"], 3);
+        assert_eq!(json["inputs"][0]["checksum"], "0000000000000abc");
+        assert_eq!(json["inputs"][0]["dtype"], "F32");
+        assert_eq!(json["model"]["architecture"], "llama");
+        // the hash is stable over the loads of the same file
+        assert_eq!(json["model"]["hash"], model_hash(&gl.open()?));
+        Ok(())
+    }
+}
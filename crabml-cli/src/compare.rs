@@ -0,0 +1,160 @@
+use clap::Args;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::loss::kl_divergence;
+use crabml::loss::LossAccumulator;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+use crabml_llama2::Llama2Sampler;
+
+#[derive(Args, Debug)]
+pub struct CompareArgs {
+    /// The two models to compare, the first one is taken as the reference
+    #[arg(short, long, required = true)]
+    model: Vec<String>,
+
+    /// The prompt fed into both models
+    #[arg(short, long)]
+    prompt: String,
+
+    /// The number of tokens to generate greedily by the reference model after the prompt
+    #[arg(short, long, default_value_t = 32)]
+    steps: usize,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// Print the stats of every token, not only the diverging ones
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+}
+
+/// the stats of a single position.
+struct TokenDiff {
+    pos: usize,
+    token: usize,
+    kl: f32,
+    top1_a: usize,
+    top1_b: usize,
+}
+
+/// runs both models on the same tokens and reports the per token KL divergence and the top-1
+/// agreement. the tokens are the prompt followed by the greedy generation of the reference
+/// model, both models are fed with the same tokens, so a single divergence does not throw the
+/// rest of the comparison off.
+pub fn run_compare(args: &CompareArgs) -> Result<()> {
+    if args.model.len() != 2 {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "expect exactly 2 models to compare, but got {}",
+                args.model.len()
+            ),
+        )
+            .into());
+    }
+
+    let gl_a = GGUFFileLoader::new(&args.model[0], false)?;
+    let gf_a = gl_a.open()?;
+    let gl_b = GGUFFileLoader::new(&args.model[1], false)?;
+    let gf_b = gl_b.open()?;
+    let model_a = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .load(&gf_a)?;
+    let model_b = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .load(&gf_b)?;
+    if model_a.conf.vocab_size != model_b.conf.vocab_size {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "the vocab size mismatches: {} vs {}",
+                model_a.conf.vocab_size, model_b.conf.vocab_size
+            ),
+        )
+            .into());
+    }
+
+    let seq_len = model_a.conf.seq_len.min(model_b.conf.seq_len);
+    let mut runner_a = Llama2Runner::new(&model_a, seq_len, false)?;
+    let mut runner_b = Llama2Runner::new(&model_b, seq_len, false)?;
+
+    let prompt_tokens = runner_a.tokenizer().encode(&args.prompt, true, false)?;
+    let max_tokens = (prompt_tokens.len() + args.steps).min(seq_len);
+    let mut tokens = prompt_tokens.clone();
+    let mut diffs = vec![];
+    let mut pos = 0;
+    while pos < tokens.len() && pos < max_tokens {
+        let token = tokens[pos];
+        let logits_a = runner_a.forward(&[token], pos)?.to_vec();
+        let logits_b = runner_b.forward(&[token], pos)?.to_vec();
+        let top1_a = Llama2Sampler::sample_argmax(&logits_a)?;
+        let top1_b = Llama2Sampler::sample_argmax(&logits_b)?;
+        diffs.push(TokenDiff {
+            pos,
+            token,
+            kl: kl_divergence(&logits_a, &logits_b)?,
+            top1_a,
+            top1_b,
+        });
+
+        // continue with the greedy generation of the reference model after the prompt
+        if pos + 1 == tokens.len() && tokens.len() < max_tokens {
+            if top1_a == runner_a.tokenizer().eos_token() {
+                break;
+            }
+            tokens.push(top1_a);
+        }
+        pos += 1;
+    }
+
+    let tokenizer = runner_a.tokenizer();
+    let mut kl_stats = LossAccumulator::new();
+    let mut agreed = 0;
+    println!(
+        "{:>5} {:>10}  {:<20} {:<20} {:<20}",
+        "pos", "kl", "input", "top1 a", "top1 b"
+    );
+    for diff in diffs.iter() {
+        kl_stats.push(diff.kl);
+        let diverged = diff.top1_a != diff.top1_b;
+        if !diverged {
+            agreed += 1;
+        }
+        if diverged || args.verbose {
+            let input = format!("{:?}", tokenizer.token(diff.token));
+            let top1_a = format!("{:?}", tokenizer.token(diff.top1_a));
+            let top1_b = format!("{:?}", tokenizer.token(diff.top1_b));
+            let mark = if diverged { "  <- diverged" } else { "" };
+            println!(
+                "{:>5} {:>10.6}  {:<20} {:<20} {:<20}{}",
+                diff.pos, diff.kl, input, top1_a, top1_b, mark
+            );
+        }
+    }
+
+    let diverging_positions = diffs
+        .iter()
+        .filter(|d| d.top1_a != d.top1_b)
+        .map(|d| d.pos)
+        .collect::<Vec<_>>();
+    println!();
+    println!("a: {}", args.model[0]);
+    println!("b: {}", args.model[1]);
+    println!(
+        "tokens: {} ({} from the prompt)",
+        diffs.len(),
+        prompt_tokens.len().min(diffs.len())
+    );
+    println!("mean KL(a || b): {:.6}", kl_stats.mean());
+    println!("max KL(a || b): {:.6}", kl_stats.max());
+    println!(
+        "top-1 agreement: {:.2}% ({}/{})",
+        agreed as f64 * 100.0 / diffs.len().max(1) as f64,
+        agreed,
+        diffs.len()
+    );
+    println!("diverging positions: {:?}", diverging_positions);
+    Ok(())
+}
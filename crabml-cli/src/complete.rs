@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::backends::cpu::CpuTensor;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::TokenID;
+use crabml_llama2::infill::FimTokens;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+use crabml_llama2::sampler::SamplerState;
+use crabml_llama2::CpuLlama2Model;
+use crabml_llama2::Llama2Sampler;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::request_log::RequestLogEntry;
+use crate::request_log::RequestLogWriter;
+use crate::request_log::RequestOutcome;
+use crate::request_log::REQUEST_LOG_VERSION;
+
+// the error codes of JSON-RPC and LSP
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const REQUEST_CANCELLED: i64 = -32800;
+
+#[derive(Args, Debug)]
+pub struct CompleteArgs {
+    /// The code model to complete with, the models trained on FIM take the suffix into account
+    #[arg(short, long)]
+    model: String,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// The max number of tokens of a completion, if not given in the request
+    #[arg(long, default_value_t = 16)]
+    max_tokens: usize,
+
+    /// The context length of each file, defaults to the one of the model
+    #[arg(long)]
+    context: Option<usize>,
+
+    /// The number of files whose KV cache are kept, the least recently used one is dropped
+    #[arg(long, default_value_t = 4)]
+    max_files: usize,
+
+    /// Wait for the next keystroke for a while before completing, a newer request on the same
+    /// file supersedes the waiting one
+    #[arg(long, default_value_t = 30)]
+    debounce_ms: u64,
+
+    /// Only take the last N bytes of the code before the cursor
+    #[arg(long, default_value_t = 8192)]
+    max_prefix_bytes: usize,
+
+    /// Only take the first N bytes of the code after the cursor
+    #[arg(long, default_value_t = 2048)]
+    max_suffix_bytes: usize,
+
+    /// The temperature of sampling, greedy decoding by default, which is the most predictable
+    /// on completing code
+    #[arg(long, default_value_t = 0.0)]
+    temperature: f32,
+
+    /// The seed of the sampler of each file
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Append each request into a JSONL log with its prompt tokens, options, sampler state,
+    /// outcome and output, which `crabml replay` re-executes to reproduce it
+    #[arg(long)]
+    request_log: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompleteParams {
+    /// the file being edited, the KV cache is kept per file
+    path: String,
+    /// the code before the cursor
+    prefix: String,
+    /// the code after the cursor
+    #[serde(default)]
+    suffix: String,
+    max_tokens: Option<usize>,
+}
+
+#[derive(Debug)]
+enum Message {
+    Complete {
+        id: Value,
+        params: CompleteParams,
+    },
+    Cancel {
+        id: Value,
+    },
+    Shutdown {
+        id: Value,
+    },
+    Exit,
+    Invalid {
+        id: Option<Value>,
+        code: i64,
+        message: String,
+    },
+}
+
+impl Message {
+    fn parse(mut value: Value) -> Self {
+        let id = value.get_mut("id").map(Value::take);
+        let params = value
+            .get_mut("params")
+            .map(Value::take)
+            .unwrap_or(Value::Null);
+        let method = value.get("method").and_then(Value::as_str).unwrap_or("");
+        match (method, id) {
+            ("complete", Some(id)) => match serde_json::from_value(params) {
+                Ok(params) => Self::Complete { id, params },
+                Err(err) => Self::Invalid {
+                    id: Some(id),
+                    code: INVALID_PARAMS,
+                    message: err.to_string(),
+                },
+            },
+            ("$/cancelRequest", _) => Self::Cancel {
+                id: params.get("id").cloned().unwrap_or(Value::Null),
+            },
+            ("shutdown", Some(id)) => Self::Shutdown { id },
+            ("exit", _) => Self::Exit,
+            (method, id) => Self::Invalid {
+                id,
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method {:?}", method),
+            },
+        }
+    }
+}
+
+/// the messages read from stdin by a background thread, so the newer keystrokes can be seen
+/// while a completion is running.
+struct Inbox {
+    rx: Receiver<Message>,
+    pending: VecDeque<Message>,
+}
+
+impl Inbox {
+    fn next(&mut self) -> Option<Message> {
+        self.pending.pop_front().or_else(|| self.rx.recv().ok())
+    }
+
+    fn poll(&mut self) {
+        self.pending.extend(self.rx.try_iter());
+    }
+
+    fn wait(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            match self.rx.recv_timeout(deadline - now) {
+                Ok(msg) => self.pending.push_back(msg),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// the request is cancelled, or there's a newer request on the same file.
+    fn is_superseded(&self, id: &Value, path: &str) -> bool {
+        self.pending.iter().any(|msg| match msg {
+            Message::Complete { params, .. } => params.path == path,
+            Message::Cancel { id: cancelled } => cancelled == id,
+            _ => false,
+        })
+    }
+}
+
+/// the KV cache of a file, it holds the tokens of the last prompt.
+pub(crate) struct FileSession<'a> {
+    runner: Llama2Runner<CpuTensor<'a>>,
+    tokens: Vec<TokenID>,
+    last_used: Instant,
+    n_requests: usize,
+}
+
+impl<'a> FileSession<'a> {
+    /// the coins flipped by the sampler of the session.
+    pub(crate) fn sampler_coins(&self) -> u64 {
+        self.runner.sampler().state().n_coins
+    }
+
+    /// set the sampler to the state after n_coins coins, like the one recorded in the log.
+    pub(crate) fn seek_sampler(&mut self, n_coins: u64) {
+        let state = SamplerState {
+            n_coins,
+            ..self.runner.sampler().state()
+        };
+        let vocab_size = self.runner.conf().vocab_size;
+        self.runner
+            .set_sampler(Llama2Sampler::from_state(vocab_size, &state));
+    }
+
+    pub(crate) fn new(model: &CpuLlama2Model<'a>, seq_len: usize) -> Result<Self> {
+        let mut runner = Llama2Runner::new(model, seq_len, false)?;
+        if let Some(fim) = FimTokens::detect(&model.tokenizer) {
+            runner.set_stop_tokens(fim.eot.into_iter().collect());
+        }
+        Ok(Self {
+            runner,
+            tokens: vec![],
+            last_used: Instant::now(),
+            n_requests: 0,
+        })
+    }
+}
+
+pub(crate) struct Completion {
+    pub(crate) text: String,
+    pub(crate) n_tokens: usize,
+    pub(crate) prompt_tokens: usize,
+    pub(crate) cached_tokens: usize,
+    /// superseded while generating, the text is the one generated until then
+    pub(crate) cancelled: bool,
+}
+
+/// serves the code completions over stdin/stdout in the JSON-RPC framing of LSP, so the editor
+/// plugins can talk to it like a language server:
+///
+/// --> {"jsonrpc": "2.0", "id": 1, "method": "complete",
+///      "params": {"path": "src/main.rs", "prefix": "fn main() {", "suffix": "}"}}
+/// <-- {"jsonrpc": "2.0", "id": 1, "result": {"text": "...", "cached_tokens": 12, ...}}
+///
+/// the requests are debounced, a request is replied with the RequestCancelled error once it's
+/// superseded by a newer request on the same file, even in the middle of the generation.
+pub fn run_complete_server(args: &CompleteArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    // greedy decoding is the most predictable on completing code
+    let model = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .with_temperature(args.temperature)
+        .with_seed(args.seed)
+        .load(&gf)?;
+    let mut request_log = args
+        .request_log
+        .as_deref()
+        .map(RequestLogWriter::open)
+        .transpose()?;
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        loop {
+            let msg = match read_message(&mut stdin) {
+                Ok(Some(value)) => Message::parse(value),
+                // a malformed message is answered with a parse error, the server keeps reading
+                // the next messages. the id of the message is unknown, it's replied with null.
+                Err(err) if err.kind == ErrorKind::BadInput => Message::Invalid {
+                    id: Some(Value::Null),
+                    code: PARSE_ERROR,
+                    message: err.to_string(),
+                },
+                Ok(None) | Err(_) => break,
+            };
+            if tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut inbox = Inbox {
+        rx,
+        pending: VecDeque::new(),
+    };
+    let mut sessions: HashMap<String, FileSession> = HashMap::new();
+    while let Some(msg) = inbox.next() {
+        match msg {
+            Message::Complete { id, params } => {
+                inbox.wait(Duration::from_millis(args.debounce_ms));
+                let mut entry = RequestLogEntry {
+                    version: REQUEST_LOG_VERSION,
+                    id: id.clone(),
+                    model: args.model.clone(),
+                    path: params.path.clone(),
+                    new_session: false,
+                    prompt_tokens: vec![],
+                    cached_tokens: 0,
+                    max_tokens: params.max_tokens.unwrap_or(args.max_tokens),
+                    temperature: args.temperature,
+                    seed: args.seed,
+                    sampler_coins: None,
+                    outcome: RequestOutcome::Debounced,
+                    error: None,
+                    n_tokens: 0,
+                    text: String::new(),
+                };
+                if inbox.is_superseded(&id, &params.path) {
+                    write_log(&mut request_log, &entry)?;
+                    reply_error(&id, REQUEST_CANCELLED, "superseded by a newer request")?;
+                    continue;
+                }
+                let session = file_session(&mut sessions, &model, &params.path, args)?;
+                entry.new_session = session.n_requests == 0;
+                entry.sampler_coins = Some(session.sampler_coins());
+                let result = build_prompt(&model, &params, args).and_then(|prompt_tokens| {
+                    entry.prompt_tokens = prompt_tokens.clone();
+                    complete_tokens(session, prompt_tokens, entry.max_tokens, || {
+                        inbox.poll();
+                        inbox.is_superseded(&id, &params.path)
+                    })
+                });
+                match result {
+                    Ok(c) => {
+                        entry.outcome = match c.cancelled {
+                            true => RequestOutcome::Cancelled,
+                            false => RequestOutcome::Completed,
+                        };
+                        entry.cached_tokens = c.cached_tokens;
+                        entry.n_tokens = c.n_tokens;
+                        entry.text = c.text.clone();
+                        write_log(&mut request_log, &entry)?;
+                        if c.cancelled {
+                            reply_error(&id, REQUEST_CANCELLED, "cancelled")?;
+                            continue;
+                        }
+                        let result = json!({
+                            "text": c.text,
+                            "prompt_tokens": c.prompt_tokens,
+                            "cached_tokens": c.cached_tokens,
+                        });
+                        reply(&id, result)?;
+                    }
+                    Err(err) => {
+                        entry.outcome = RequestOutcome::Failed;
+                        entry.error = Some(err.to_string());
+                        write_log(&mut request_log, &entry)?;
+                        reply_error(&id, INTERNAL_ERROR, &err.to_string())?;
+                    }
+                }
+            }
+            // the cancelled requests are dropped on debouncing, or stopped while generating
+            Message::Cancel { .. } => {}
+            Message::Shutdown { id } => reply(&id, Value::Null)?,
+            Message::Exit => break,
+            Message::Invalid {
+                id: Some(id),
+                code,
+                message,
+            } => reply_error(&id, code, &message)?,
+            Message::Invalid { id: None, .. } => {}
+        }
+    }
+    Ok(())
+}
+
+fn file_session<'a, 'b>(
+    sessions: &'b mut HashMap<String, FileSession<'a>>,
+    model: &CpuLlama2Model<'a>,
+    path: &str,
+    args: &CompleteArgs,
+) -> Result<&'b mut FileSession<'a>> {
+    if !sessions.contains_key(path) {
+        if sessions.len() >= args.max_files.max(1) {
+            let lru = sessions
+                .iter()
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(path, _)| path.clone())
+                .unwrap();
+            sessions.remove(&lru);
+        }
+        let seq_len = args.context.unwrap_or(model.conf.seq_len);
+        sessions.insert(path.to_string(), FileSession::new(model, seq_len)?);
+    }
+    let session = sessions.get_mut(path).unwrap();
+    session.last_used = Instant::now();
+    Ok(session)
+}
+
+fn write_log(request_log: &mut Option<RequestLogWriter>, entry: &RequestLogEntry) -> Result<()> {
+    match request_log {
+        Some(log) => log.write(entry),
+        None => Ok(()),
+    }
+}
+
+// the prompt of the code around the cursor, in the FIM format if the model is trained on it
+fn build_prompt(
+    model: &CpuLlama2Model,
+    params: &CompleteParams,
+    args: &CompleteArgs,
+) -> Result<Vec<TokenID>> {
+    let tokenizer = &model.tokenizer;
+    let prefix = tail_lines(&params.prefix, args.max_prefix_bytes);
+    let suffix = head_lines(&params.suffix, args.max_suffix_bytes);
+    match FimTokens::detect(tokenizer) {
+        Some(fim) => fim.build_prompt(tokenizer, prefix, suffix),
+        None => tokenizer.encode(prefix, tokenizer.options().add_bos, false),
+    }
+}
+
+/// complete the prompt tokens on the KV cache of the session, is_cancelled is checked after
+/// each generated piece. the replay of the request log runs the requests through here as well.
+pub(crate) fn complete_tokens(
+    session: &mut FileSession,
+    prompt_tokens: Vec<TokenID>,
+    max_tokens: usize,
+    mut is_cancelled: impl FnMut() -> bool,
+) -> Result<Completion> {
+    session.n_requests += 1;
+    let runner = &mut session.runner;
+    if prompt_tokens.is_empty() || prompt_tokens.len() + max_tokens > runner.context_limit() {
+        return Err(Error::new(
+            ErrorKind::ContextOverflow,
+            format!(
+                "the prompt has {} tokens, does not fit into the context limit {}",
+                prompt_tokens.len(),
+                runner.context_limit()
+            ),
+        ));
+    }
+
+    // reuse the KV cache of the common prefix with the last prompt of the file, the last token
+    // is always forwarded to get the logits.
+    let cached_tokens = session
+        .tokens
+        .iter()
+        .zip(prompt_tokens.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(prompt_tokens.len() - 1);
+    runner.truncate(cached_tokens)?;
+    session.tokens.truncate(cached_tokens);
+
+    let (pos, _prev_token, token) =
+        runner.prefill_tokens(prompt_tokens[cached_tokens..].to_vec(), false, true)?;
+    session.tokens = prompt_tokens;
+
+    let mut text = String::new();
+    let mut n_tokens = 0;
+    let mut cancelled = false;
+    for piece in runner.generate(pos, token, Some(max_tokens)) {
+        text.push_str(&piece?);
+        n_tokens += 1;
+        if is_cancelled() {
+            cancelled = true;
+            break;
+        }
+    }
+
+    // the completion is rarely accepted as is, only keep the prompt in the cache
+    runner.truncate(session.tokens.len())?;
+    Ok(Completion {
+        text,
+        n_tokens,
+        prompt_tokens: session.tokens.len(),
+        cached_tokens,
+        cancelled,
+    })
+}
+
+/// the last lines of the text within max_bytes, the cut is at a line start, so the prompt
+/// keeps the same head while the user types after it.
+fn tail_lines(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut start = text.len() - max_bytes;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    match text[start..].find('\n') {
+        Some(pos) => &text[start + pos + 1..],
+        None => &text[start..],
+    }
+}
+
+/// the first lines of the text within max_bytes.
+fn head_lines(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    match text[..end].rfind('\n') {
+        Some(pos) => &text[..pos + 1],
+        None => &text[..end],
+    }
+}
+
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(io_error)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(len) = line.strip_prefix("Content-Length:") {
+            content_length = len.trim().parse::<usize>().ok();
+        }
+    }
+    let content_length = content_length.ok_or_else(|| {
+        Error::new(
+            ErrorKind::BadInput,
+            "missing the Content-Length header in the message",
+        )
+    })?;
+    let mut buf = vec![0; content_length];
+    reader.read_exact(&mut buf).map_err(io_error)?;
+    serde_json::from_slice(&buf)
+        .map(Some)
+        .map_err(|err| Error::new(ErrorKind::BadInput, err.to_string()))
+}
+
+fn write_message(value: Value) -> Result<()> {
+    let body = value.to_string();
+    let mut stdout = std::io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body).map_err(io_error)?;
+    stdout.flush().map_err(io_error)
+}
+
+fn io_error(err: std::io::Error) -> Error {
+    Error::new(ErrorKind::IOError, "failed to talk to the editor").with_cause(err)
+}
+
+fn reply(id: &Value, result: Value) -> Result<()> {
+    write_message(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+}
+
+fn reply_error(id: &Value, code: i64, message: &str) -> Result<()> {
+    write_message(json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim_lines() {
+        let text = "fn a() {}\nfn b() {}\nfn c() {}\n";
+        assert_eq!(tail_lines(text, 100), text);
+        assert_eq!(tail_lines(text, 15), "fn c() {}\n");
+        assert_eq!(head_lines(text, 15), "fn a() {}\n");
+    }
+
+    #[test]
+    fn test_read_message() -> Result<()> {
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"complete","params":{"path":"a.rs","prefix":"fn"}}"#;
+        let input = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let value = read_message(&mut input.as_bytes())?.unwrap();
+        match Message::parse(value) {
+            Message::Complete { id, params } => {
+                assert_eq!(id, json!(1));
+                assert_eq!(params.path, "a.rs");
+                assert_eq!(params.suffix, "");
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(read_message(&mut "".as_bytes())?.is_none());
+
+        // a malformed message does not stop the reading of the next one
+        let input = format!(
+            "Content-Length: 5\r\n\r\n{{abc}}Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let mut reader = input.as_bytes();
+        let err = read_message(&mut reader).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        assert!(matches!(
+            Message::parse(read_message(&mut reader)?.unwrap()),
+            Message::Complete { .. }
+        ));
+        assert!(read_message(&mut reader)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::sync::Arc;
+
+use clap::Args;
+use clap::ValueEnum;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFTensorInfo;
+use crabml::gguf::KEY_GENERAL_FILE_TYPE;
+use crabml::gguf_edit::convert_f32_data;
+use crabml::gguf_edit::GGUFEditor;
+use crabml::progress::ProgressReporterRef;
+use crabml::progress::ProgressStage;
+
+use crate::report_progress;
+
+// the file types of llama.cpp in general.file_type
+const FILE_TYPE_MOSTLY_F16: u32 = 1;
+const FILE_TYPE_MOSTLY_Q4_0: u32 = 2;
+const FILE_TYPE_MOSTLY_Q4_1: u32 = 3;
+const FILE_TYPE_MOSTLY_Q8_0: u32 = 7;
+const FILE_TYPE_MOSTLY_Q5_0: u32 = 8;
+const FILE_TYPE_MOSTLY_Q5_1: u32 = 9;
+const FILE_TYPE_MOSTLY_Q2_K: u32 = 10;
+const FILE_TYPE_MOSTLY_Q3_K_S: u32 = 11;
+const FILE_TYPE_MOSTLY_Q4_K_S: u32 = 14;
+const FILE_TYPE_MOSTLY_Q5_K_S: u32 = 16;
+const FILE_TYPE_MOSTLY_Q6_K: u32 = 18;
+const FILE_TYPE_MOSTLY_BF16: u32 = 32;
+
+// the embedding and the output head, the output head is tied to the embedding on the models
+// without output.weight
+const HEAD_TENSORS: &[&str] = &["token_embd.weight", "output.weight"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutType {
+    F16,
+    Bf16,
+    #[value(name = "q8_0")]
+    Q8_0,
+    #[value(name = "q6_k")]
+    Q6K,
+    #[value(name = "q5_k")]
+    Q5K,
+    #[value(name = "q5_1")]
+    Q5_1,
+    #[value(name = "q5_0")]
+    Q5_0,
+    #[value(name = "q4_k")]
+    Q4K,
+    #[value(name = "q4_1")]
+    Q4_1,
+    #[value(name = "q4_0")]
+    Q4_0,
+    #[value(name = "q3_k")]
+    Q3K,
+    #[value(name = "q2_k")]
+    Q2K,
+}
+
+impl OutType {
+    fn ggml_type(self) -> GGMLType {
+        match self {
+            OutType::F16 => GGMLType::F16,
+            OutType::Bf16 => GGMLType::BF16,
+            OutType::Q8_0 => GGMLType::Q8_0,
+            OutType::Q6K => GGMLType::Q6K,
+            OutType::Q5K => GGMLType::Q5K,
+            OutType::Q5_1 => GGMLType::Q5_1,
+            OutType::Q5_0 => GGMLType::Q5_0,
+            OutType::Q4K => GGMLType::Q4K,
+            OutType::Q4_1 => GGMLType::Q4_1,
+            OutType::Q4_0 => GGMLType::Q4_0,
+            OutType::Q3K => GGMLType::Q3K,
+            OutType::Q2K => GGMLType::Q2K,
+        }
+    }
+
+    fn file_type(self) -> u32 {
+        match self {
+            OutType::F16 => FILE_TYPE_MOSTLY_F16,
+            OutType::Bf16 => FILE_TYPE_MOSTLY_BF16,
+            OutType::Q8_0 => FILE_TYPE_MOSTLY_Q8_0,
+            OutType::Q6K => FILE_TYPE_MOSTLY_Q6_K,
+            OutType::Q5K => FILE_TYPE_MOSTLY_Q5_K_S,
+            OutType::Q5_1 => FILE_TYPE_MOSTLY_Q5_1,
+            OutType::Q5_0 => FILE_TYPE_MOSTLY_Q5_0,
+            OutType::Q4K => FILE_TYPE_MOSTLY_Q4_K_S,
+            OutType::Q4_1 => FILE_TYPE_MOSTLY_Q4_1,
+            OutType::Q4_0 => FILE_TYPE_MOSTLY_Q4_0,
+            OutType::Q3K => FILE_TYPE_MOSTLY_Q3_K_S,
+            OutType::Q2K => FILE_TYPE_MOSTLY_Q2_K,
+        }
+    }
+
+    // the types of 4 bits or less, whose error on the head shows up in every token
+    fn is_low_bits(self) -> bool {
+        matches!(
+            self,
+            OutType::Q4K | OutType::Q4_1 | OutType::Q4_0 | OutType::Q3K | OutType::Q2K
+        )
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// The GGUF file with the f32 tensors to convert
+    #[arg(short, long)]
+    model: String,
+
+    /// The converted GGUF file
+    #[arg(short, long)]
+    output: String,
+
+    /// The type to convert the f32 tensors into
+    #[arg(long, value_enum, default_value_t = OutType::F16)]
+    outtype: OutType,
+
+    /// Only convert the tensors matching any of the patterns, like "blk.*.ffn_*", where `*`
+    /// matches any characters. All the tensors are converted by default
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Keep the tensors matching any of the patterns in f32, like "output.*"
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// The type of the embedding and the output head. They're kept in q6_k, or q8_0 on the rows
+    /// q6_k can not take, when the outtype is 4 bits or less, and in the outtype otherwise
+    #[arg(long, value_enum)]
+    head_outtype: Option<OutType>,
+
+    /// Show the progress of quantizing the tensors
+    #[arg(long, default_value_t = false)]
+    progress: bool,
+}
+
+/// converts the f32 tensors of a GGUF file into f16, bf16 or a quantized type, the other
+/// tensors and the metadata are copied as is. the 1-D tensors like the norms are always kept in
+/// f32, they're tiny and the kernels take them in f32, and so are the tensors whose rows are
+/// not made of whole blocks of the type.
+pub fn run_convert(args: &ConvertArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+
+    let reporter: Option<ProgressReporterRef> = if args.progress {
+        Some(Arc::new(report_progress))
+    } else {
+        None
+    };
+    let to_convert = gf
+        .tensor_infos()
+        .iter()
+        .filter(|info| should_convert(info, args))
+        .collect::<Vec<_>>();
+
+    // convert the data before the editor, the editor borrows the buffers
+    let mut converted = vec![];
+    for (i, info) in to_convert.iter().enumerate() {
+        if let Some(reporter) = &reporter {
+            reporter.report(ProgressStage::Quantize, i, to_convert.len());
+        }
+        let typ = tensor_type(info, args);
+        let row_len = info.dimensions()[0];
+        if row_len % typ.block_size() != 0 {
+            eprintln!(
+                "keep {} in f32, its rows of {} elements can not be quantized into {}",
+                info.name(),
+                row_len,
+                typ
+            );
+            continue;
+        }
+        let n_elements = info.dimensions().iter().product();
+        let data = convert_f32_data(info.data(), n_elements, typ)?;
+        converted.push((info.name().to_string(), typ, data));
+    }
+    if let Some(reporter) = &reporter {
+        reporter.report(ProgressStage::Quantize, to_convert.len(), to_convert.len());
+    }
+
+    let mut editor = GGUFEditor::new(&gf);
+    for (name, typ, data) in converted.iter() {
+        editor.set_tensor_data(name, *typ, data)?;
+        eprintln!("convert {} into {}", name, typ);
+    }
+    if !converted.is_empty() && gf.metadata().get_u32(KEY_GENERAL_FILE_TYPE).is_some() {
+        let file_type = args.outtype.file_type();
+        editor.set(KEY_GENERAL_FILE_TYPE, GGUFMetadataValue::U32(file_type))?;
+    }
+    editor.write_to_file(&args.output)?;
+    eprintln!(
+        "converted {} of {} tensors, written to {}",
+        converted.len(),
+        gf.tensor_infos().len(),
+        args.output
+    );
+    Ok(())
+}
+
+fn should_convert(info: &GGUFTensorInfo, args: &ConvertArgs) -> bool {
+    let name = info.name();
+    info.typ() == GGMLType::F32
+        && info.dimensions().len() >= 2
+        && (args.include.is_empty() || args.include.iter().any(|p| matches_pattern(p, name)))
+        && !args.exclude.iter().any(|p| matches_pattern(p, name))
+}
+
+/// the type to convert a tensor into. the embedding and the output head are kept in a higher
+/// precision on the very low bits outtypes, their error damages the output quality far more
+/// than the same error in the layers.
+fn tensor_type(info: &GGUFTensorInfo, args: &ConvertArgs) -> GGMLType {
+    if !HEAD_TENSORS.contains(&info.name()) {
+        return args.outtype.ggml_type();
+    }
+    if let Some(head_outtype) = args.head_outtype {
+        return head_outtype.ggml_type();
+    }
+    if !args.outtype.is_low_bits() {
+        return args.outtype.ggml_type();
+    }
+    // like llama.cpp, fall back to q8_0 on the rows of the small models q6_k can not take
+    if info.dimensions()[0] % GGMLType::Q6K.block_size() == 0 {
+        GGMLType::Q6K
+    } else {
+        GGMLType::Q8_0
+    }
+}
+
+/// matches the name against a pattern, where `*` matches any characters, including none.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let parts = pattern.split('*').collect::<Vec<_>>();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if parts.len() == 1 {
+        return name == pattern;
+    }
+    if name.len() < first.len() + last.len() || !name.starts_with(first) || !name.ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in parts[1..parts.len() - 1].iter() {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml_llama2::llama2::Llama2Runner;
+    use crabml_llama2::model::CpuLlama2ModelLoader;
+
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("output.*", "output.weight"));
+        assert!(matches_pattern("output.weight", "output.weight"));
+        assert!(!matches_pattern("output.*", "output_norm.weight"));
+        assert!(matches_pattern("blk.*.ffn_*", "blk.0.ffn_up.weight"));
+        assert!(!matches_pattern("blk.*.ffn_*", "blk.0.attn_q.weight"));
+        assert!(matches_pattern("*", "token_embd.weight"));
+        assert!(matches_pattern("a*a", "aa"));
+        assert!(!matches_pattern("a*a", "a"));
+    }
+
+    #[test]
+    fn test_convert() -> Result<()> {
+        let path = std::env::temp_dir().join("crabml-test-convert-f16.gguf");
+        let args = ConvertArgs {
+            model: "../testdata/tinyllamas-stories-260k-f32.gguf".to_string(),
+            output: path.to_str().unwrap().to_string(),
+            outtype: OutType::F16,
+            include: vec![],
+            exclude: vec!["output.*".to_string()],
+            head_outtype: None,
+            progress: false,
+        };
+        run_convert(&args)?;
+
+        let gl = GGUFFileLoader::new(&args.model, false)?;
+        let gf = gl.open()?;
+        let converted_gl = GGUFFileLoader::new(&args.output, false)?;
+        let converted = converted_gl.open()?;
+        let typ = |name: &str| converted.get_tensor_info(name).unwrap().typ();
+        assert_eq!(typ("token_embd.weight"), GGMLType::F16);
+        assert_eq!(typ("blk.0.attn_q.weight"), GGMLType::F16);
+        assert_eq!(typ("output.weight"), GGMLType::F32);
+        assert_eq!(typ("output_norm.weight"), GGMLType::F32);
+
+        // the f16 values are rounded from the f32 ones
+        let info = gf.get_tensor_info("blk.0.attn_q.weight").unwrap();
+        let n_elements = info.dimensions().iter().product();
+        let want = convert_f32_data(info.data(), n_elements, GGMLType::F16)?;
+        let got = converted.get_tensor_info("blk.0.attn_q.weight").unwrap();
+        assert_eq!(&got.data()[..want.len()], &want[..]);
+        assert_eq!(got.dimensions(), info.dimensions());
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_low_bits_head() -> Result<()> {
+        let path = std::env::temp_dir().join("crabml-test-convert-q4_0.gguf");
+        let mut args = ConvertArgs {
+            model: "../testdata/tinyllamas-stories-15m-f32.gguf".to_string(),
+            output: path.to_str().unwrap().to_string(),
+            outtype: OutType::Q4_0,
+            include: vec![],
+            exclude: vec![],
+            head_outtype: None,
+            progress: false,
+        };
+        run_convert(&args)?;
+
+        // the rows of 288 elements are not whole blocks of q6_k, the head falls back to q8_0
+        {
+            let gl = GGUFFileLoader::new(&args.output, false)?;
+            let gf = gl.open()?;
+            let typ = |name: &str| gf.get_tensor_info(name).unwrap().typ();
+            assert_eq!(typ("token_embd.weight"), GGMLType::Q8_0);
+            assert_eq!(typ("output.weight"), GGMLType::Q8_0);
+            assert_eq!(typ("blk.0.attn_q.weight"), GGMLType::Q4_0);
+            assert_eq!(typ("blk.0.ffn_down.weight"), GGMLType::Q4_0);
+            assert_eq!(typ("output_norm.weight"), GGMLType::F32);
+            assert_eq!(
+                gf.metadata().get_u32(KEY_GENERAL_FILE_TYPE),
+                Some(FILE_TYPE_MOSTLY_Q4_0)
+            );
+
+            // the loader takes the tensors in their own types
+            let lm = CpuLlama2ModelLoader::new()
+                .with_temperature(0.0)
+                .load(&gf)?;
+            let mut runner = Llama2Runner::new(&lm, 100, false)?;
+            let output = runner
+                .prefill_and_generate("Lily is a cute cat, ", 8)?
+                .collect::<Result<Vec<String>>>()?;
+            assert!(!output.concat().is_empty());
+        }
+
+        // the override takes the head into the outtype
+        args.head_outtype = Some(OutType::Q4_0);
+        run_convert(&args)?;
+        let gl = GGUFFileLoader::new(&args.output, false)?;
+        let gf = gl.open()?;
+        assert_eq!(
+            gf.get_tensor_info("output.weight").unwrap().typ(),
+            GGMLType::Q4_0
+        );
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+}
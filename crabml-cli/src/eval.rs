@@ -0,0 +1,357 @@
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+
+use clap::Args;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::loss::cross_entropy;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+use crabml_llama2::Llama2Sampler;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Args, Debug)]
+pub struct EvalArgs {
+    /// The checkpoint file to evaluate
+    #[arg(short, long)]
+    model: String,
+
+    /// The task file in JSON lines, each line is a request like
+    /// {"request_type": "loglikelihood", "context": "..", "continuation": ".."}
+    #[arg(long)]
+    tasks: String,
+
+    /// Only evaluate the first N requests
+    #[arg(short, long)]
+    limit: Option<usize>,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// Print the result of every request as a JSON line
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+}
+
+/// the request types follow lm-evaluation-harness. a multiple choice request is expanded into
+/// a loglikelihood request on each choice, like HellaSwag and ARC.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "request_type", rename_all = "snake_case")]
+enum EvalRequest {
+    Loglikelihood {
+        context: String,
+        continuation: String,
+    },
+    MultipleChoice {
+        context: String,
+        choices: Vec<String>,
+        gold: usize,
+    },
+    GreedyUntil {
+        context: String,
+        #[serde(default)]
+        until: Vec<String>,
+        #[serde(default = "default_max_gen_toks")]
+        max_gen_toks: usize,
+        /// the expected generation, scored by exact match if given
+        target: Option<String>,
+    },
+}
+
+fn default_max_gen_toks() -> usize {
+    256
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum EvalResult {
+    Loglikelihood {
+        loglikelihood: f32,
+        is_greedy: bool,
+    },
+    MultipleChoice {
+        loglikelihoods: Vec<f32>,
+        acc: bool,
+        acc_norm: bool,
+    },
+    GreedyUntil {
+        generation: String,
+        exact_match: Option<bool>,
+    },
+}
+
+/// the aggregated metrics over all the requests.
+#[derive(Default)]
+struct EvalStats {
+    loglikelihood_count: usize,
+    loglikelihood_sum: f64,
+    greedy_count: usize,
+    choice_count: usize,
+    acc_count: usize,
+    acc_norm_count: usize,
+    generation_count: usize,
+    exact_match_total: usize,
+    exact_match_count: usize,
+}
+
+impl EvalStats {
+    fn push(&mut self, result: &EvalResult) {
+        match result {
+            EvalResult::Loglikelihood {
+                loglikelihood,
+                is_greedy,
+            } => {
+                self.loglikelihood_count += 1;
+                self.loglikelihood_sum += *loglikelihood as f64;
+                self.greedy_count += *is_greedy as usize;
+            }
+            EvalResult::MultipleChoice { acc, acc_norm, .. } => {
+                self.choice_count += 1;
+                self.acc_count += *acc as usize;
+                self.acc_norm_count += *acc_norm as usize;
+            }
+            EvalResult::GreedyUntil { exact_match, .. } => {
+                self.generation_count += 1;
+                if let Some(exact_match) = exact_match {
+                    self.exact_match_total += 1;
+                    self.exact_match_count += *exact_match as usize;
+                }
+            }
+        }
+    }
+
+    fn print(&self) {
+        if self.loglikelihood_count > 0 {
+            println!(
+                "loglikelihood: {} requests, mean loglikelihood {:.4}, greedy acc {:.4}",
+                self.loglikelihood_count,
+                self.loglikelihood_sum / self.loglikelihood_count as f64,
+                ratio(self.greedy_count, self.loglikelihood_count)
+            );
+        }
+        if self.choice_count > 0 {
+            println!(
+                "multiple_choice: {} requests, acc {:.4}, acc_norm {:.4}",
+                self.choice_count,
+                ratio(self.acc_count, self.choice_count),
+                ratio(self.acc_norm_count, self.choice_count)
+            );
+        }
+        if self.generation_count > 0 {
+            println!(
+                "greedy_until: {} requests, exact_match {:.4} ({} with target)",
+                self.generation_count,
+                ratio(self.exact_match_count, self.exact_match_total),
+                self.exact_match_total
+            );
+        }
+    }
+}
+
+fn ratio(n: usize, total: usize) -> f64 {
+    n as f64 / total.max(1) as f64
+}
+
+pub fn run_eval(args: &EvalArgs) -> Result<()> {
+    let requests = read_requests(&args.tasks, args.limit)?;
+
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    // greedy decoding for greedy_until
+    let model = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .with_temperature(0.0)
+        .load(&gf)?;
+    let mut runner = Llama2Runner::new(&model, model.conf.seq_len, false)?;
+
+    let mut stats = EvalStats::default();
+    for (i, request) in requests.iter().enumerate() {
+        let result = eval_request(&mut runner, request)?;
+        if args.verbose {
+            let line = serde_json::json!({ "id": i, "result": result });
+            println!("{}", line);
+        }
+        stats.push(&result);
+        eprint!("\revaluated: {}/{}", i + 1, requests.len());
+    }
+    eprintln!();
+
+    stats.print();
+    Ok(())
+}
+
+fn read_requests(path: &str, limit: Option<usize>) -> Result<Vec<EvalRequest>> {
+    let file = File::open(path).map_err(|err| {
+        Error::new(
+            ErrorKind::IOError,
+            format!("failed to open the task file {}", path),
+        )
+        .with_cause(err)
+    })?;
+
+    let mut requests = vec![];
+    for (lineno, line) in BufReader::new(file).lines().enumerate() {
+        if limit.is_some_and(|limit| requests.len() >= limit) {
+            break;
+        }
+        let line = line.map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to read the task file {}", path),
+            )
+            .with_cause(err)
+        })?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = serde_json::from_str(&line).map_err(|err| {
+            Error::new(
+                ErrorKind::FormatError,
+                format!("{}:{}: bad request: {}", path, lineno + 1, err),
+            )
+        })?;
+        requests.push(request);
+    }
+    Ok(requests)
+}
+
+fn eval_request<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    request: &EvalRequest,
+) -> Result<EvalResult> {
+    match request {
+        EvalRequest::Loglikelihood {
+            context,
+            continuation,
+        } => {
+            let (loglikelihood, is_greedy) = loglikelihood(runner, context, continuation)?;
+            Ok(EvalResult::Loglikelihood {
+                loglikelihood,
+                is_greedy,
+            })
+        }
+        EvalRequest::MultipleChoice {
+            context,
+            choices,
+            gold,
+        } => {
+            if *gold >= choices.len() {
+                return Err((
+                    ErrorKind::BadInput,
+                    format!("gold {} out of {} choices", gold, choices.len()),
+                )
+                    .into());
+            }
+            let loglikelihoods = choices
+                .iter()
+                .map(|choice| loglikelihood(runner, context, choice).map(|(ll, _)| ll))
+                .collect::<Result<Vec<_>>>()?;
+            // acc_norm normalizes the loglikelihood by the byte length of the choice, which
+            // does not prefer the short choices.
+            let normalized = loglikelihoods
+                .iter()
+                .zip(choices.iter())
+                .map(|(ll, choice)| ll / choice.len().max(1) as f32)
+                .collect::<Vec<_>>();
+            Ok(EvalResult::MultipleChoice {
+                acc: Llama2Sampler::sample_argmax(&loglikelihoods)? == *gold,
+                acc_norm: Llama2Sampler::sample_argmax(&normalized)? == *gold,
+                loglikelihoods,
+            })
+        }
+        EvalRequest::GreedyUntil {
+            context,
+            until,
+            max_gen_toks,
+            target,
+        } => {
+            let generation = greedy_until(runner, context, until, *max_gen_toks)?;
+            let exact_match = target.as_ref().map(|t| t.trim() == generation.trim());
+            Ok(EvalResult::GreedyUntil {
+                generation,
+                exact_match,
+            })
+        }
+    }
+}
+
+/// the sum of the log probabilities of the continuation tokens given the context, and whether
+/// the continuation is the greedy output of the model.
+fn loglikelihood<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    context: &str,
+    continuation: &str,
+) -> Result<(f32, bool)> {
+    let tokenizer = runner.tokenizer();
+    let context_tokens = tokenizer.encode(context, true, false)?;
+    let tokens = tokenizer.encode_append(&context_tokens, continuation)?;
+    // the tokens around the boundary may merge, take the common prefix as the context
+    let n_context = context_tokens
+        .iter()
+        .zip(tokens.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .max(1);
+    if n_context >= tokens.len() {
+        return Err((
+            ErrorKind::BadInput,
+            format!("empty continuation after the context {:?}", context),
+        )
+            .into());
+    }
+    if tokens.len() > runner.context_limit() {
+        return Err((
+            ErrorKind::ContextOverflow,
+            format!(
+                "the request has {} tokens, exceeds the context limit {}",
+                tokens.len(),
+                runner.context_limit()
+            ),
+        )
+            .into());
+    }
+
+    runner.reset()?;
+    let mut loglikelihood = 0.0;
+    let mut is_greedy = true;
+    for pos in 0..tokens.len() - 1 {
+        let logits = runner.forward(&[tokens[pos]], pos)?;
+        if pos + 1 < n_context {
+            continue;
+        }
+        let target = tokens[pos + 1];
+        loglikelihood -= cross_entropy(logits, target)?;
+        is_greedy &= Llama2Sampler::sample_argmax(logits)? == target;
+    }
+    Ok((loglikelihood, is_greedy))
+}
+
+/// generate greedily until any of the stop sequences, the stop sequence is not included.
+fn greedy_until<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    context: &str,
+    until: &[String],
+    max_gen_toks: usize,
+) -> Result<String> {
+    runner.reset()?;
+    let (pos, _prev_token, token) = runner.prefill(context, true, false)?;
+    let mut generation = String::new();
+    for text in runner.generate(pos, token, Some(max_gen_toks)) {
+        generation.push_str(&text?);
+        let stop_at = until
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| generation.find(s.as_str()))
+            .min();
+        if let Some(stop_at) = stop_at {
+            generation.truncate(stop_at);
+            break;
+        }
+    }
+    Ok(generation)
+}
@@ -0,0 +1,264 @@
+use clap::Args;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::ContextOverflowPolicy;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+
+// the haystack repeats these sentences, none of them has a number
+const HAYSTACK_SENTENCES: &[&str] = &[
+    "The river ran slowly through the old town, past the bakery and the school.",
+    "Every morning the children walked to the park to feed the ducks.",
+    "The mountains in the north were covered with snow for most of the year.",
+    "A small boat was tied to the dock, waiting for the fisherman to return.",
+    "The library on the hill kept thousands of books about the history of the sea.",
+    "In the evening the streets were quiet, and the lamps glowed in the windows.",
+    "The farmer planted apples, pears and plums in the field behind his house.",
+    "Once a week a market opened in the square, selling bread, cheese and flowers.",
+];
+
+const QUESTION: &str = "\nWhat is the secret number in the text above? The secret number is";
+
+#[derive(Args, Debug)]
+pub struct EvalLongctxArgs {
+    /// The checkpoint file to evaluate
+    #[arg(short, long)]
+    model: String,
+
+    /// The lengths of the prompts in tokens
+    #[arg(long, value_delimiter = ',', default_value = "256,512,1024")]
+    lengths: Vec<usize>,
+
+    /// Where to hide the needle in the haystack, from 0 (the beginning) to 1 (the end)
+    #[arg(long, value_delimiter = ',', default_value = "0,0.25,0.5,0.75,1")]
+    depths: Vec<f32>,
+
+    /// The number of needles to look for on each length and depth
+    #[arg(long, default_value_t = 3)]
+    trials: usize,
+
+    /// The seed of the secret numbers
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// The context limit of the session, defaults to the context length of the model. it can
+    /// be larger than the model's to stress the positions it was not trained on
+    #[arg(long)]
+    context_limit: Option<usize>,
+
+    /// Drop the oldest tokens of the prompts which overflow the context limit, instead of
+    /// counting them as failures
+    #[arg(long, default_value_t = false)]
+    truncate_prompt: bool,
+
+    /// Prefill the prompts in chunks of N tokens instead of one token at a time
+    #[arg(long)]
+    prefill_chunk_size: Option<usize>,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// Print the result of every needle as a JSON line
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+}
+
+/// the retrieval results of a length over the depths.
+struct LengthStats {
+    length: usize,
+    n_tokens_sum: usize,
+    n_prompts: usize,
+    // (depth) => (found, total)
+    found: Vec<(usize, usize)>,
+}
+
+/// a needle-in-a-haystack eval: a secret number is hidden at a depth of the filler text, and
+/// the model is asked to recall it at the end. a model which handles its context length well
+/// finds the needles at all the depths, the accuracy drops on the broken positions like a
+/// wrong rope scaling, or on the needles dropped by the truncation of the prompt.
+pub fn run_eval_longctx(args: &EvalLongctxArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let model = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .with_temperature(0.0)
+        .load(&gf)?;
+    let context_limit = args.context_limit.unwrap_or(model.conf.seq_len);
+    let policy = if args.truncate_prompt {
+        ContextOverflowPolicy::TruncatePrompt
+    } else {
+        ContextOverflowPolicy::Error
+    };
+    let mut runner =
+        Llama2Runner::new(&model, context_limit, false)?.with_context_overflow_policy(policy);
+    if let Some(chunk_size) = args.prefill_chunk_size {
+        runner = runner.with_prefill_chunk_size(chunk_size);
+    }
+    let batched = args.prefill_chunk_size.is_some();
+
+    let mut rng = args.seed;
+    let mut all_stats = vec![];
+    for &length in args.lengths.iter() {
+        let mut stats = LengthStats {
+            length,
+            n_tokens_sum: 0,
+            n_prompts: 0,
+            found: vec![(0, 0); args.depths.len()],
+        };
+        for (i, &depth) in args.depths.iter().enumerate() {
+            for _ in 0..args.trials {
+                let secret = next_secret(&mut rng);
+                let prompt = build_haystack_prompt(length, depth, secret, |text| {
+                    Ok(runner.tokenizer().encode(text, false, false)?.len())
+                })?;
+                let (n_tokens, generation) = match recall(&mut runner, &prompt, batched) {
+                    Ok(result) => result,
+                    Err(err) if err.kind == ErrorKind::ContextOverflow => (0, String::new()),
+                    Err(err) => return Err(err),
+                };
+                let found = generation.contains(&secret.to_string());
+                stats.n_tokens_sum += n_tokens;
+                stats.n_prompts += usize::from(n_tokens > 0);
+                stats.found[i].0 += usize::from(found);
+                stats.found[i].1 += 1;
+                if args.verbose {
+                    let line = serde_json::json!({
+                        "length": length,
+                        "depth": depth,
+                        "n_tokens": n_tokens,
+                        "secret": secret,
+                        "generation": generation,
+                        "found": found,
+                    });
+                    println!("{}", line);
+                }
+            }
+            eprint!("\revaluated: length {}, depth {:.2}", length, depth);
+        }
+        eprintln!();
+        all_stats.push(stats);
+    }
+
+    print_stats(&all_stats, &args.depths, context_limit);
+    Ok(())
+}
+
+// prefill the prompt and generate a few tokens greedily, returns the number of the prompt
+// tokens and the generation
+fn recall<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    prompt: &str,
+    batched: bool,
+) -> Result<(usize, String)> {
+    runner.reset()?;
+    let (pos, _prev_token, token) = runner.prefill(prompt, true, batched)?;
+    let generation = runner
+        .generate(pos, token, Some(8))
+        .collect::<Result<Vec<_>>>()?
+        .join("");
+    Ok((pos, generation))
+}
+
+fn print_stats(all_stats: &[LengthStats], depths: &[f32], context_limit: usize) {
+    println!("context limit: {}", context_limit);
+    print!("{:>8} {:>8}", "length", "tokens");
+    for depth in depths {
+        print!(" {:>8.2}", depth);
+    }
+    println!(" {:>8}", "acc");
+    for stats in all_stats {
+        let found = stats.found.iter().map(|(n, _)| n).sum::<usize>();
+        let total = stats.found.iter().map(|(_, n)| n).sum::<usize>();
+        print!(
+            "{:>8} {:>8}",
+            stats.length,
+            stats.n_tokens_sum / stats.n_prompts.max(1)
+        );
+        for (n, total) in stats.found.iter() {
+            print!(" {:>8}", format!("{}/{}", n, total));
+        }
+        println!(" {:>8.4}", found as f64 / total.max(1) as f64);
+    }
+}
+
+// a 5 digits number, the sequence only depends on the seed
+fn next_secret(state: &mut u64) -> u32 {
+    *state = state
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    10000 + ((*state >> 33) % 90000) as u32
+}
+
+/// fill the haystack sentences until the prompt takes about length tokens, and hide the needle
+/// after the sentence at the depth. count_tokens counts the tokens of a piece of text.
+fn build_haystack_prompt(
+    length: usize,
+    depth: f32,
+    secret: u32,
+    count_tokens: impl Fn(&str) -> Result<usize>,
+) -> Result<String> {
+    let needle = format!(" The secret number is {}.", secret);
+    // the BOS takes a token
+    let mut budget = length.saturating_sub(count_tokens(&needle)? + count_tokens(QUESTION)? + 1);
+    let sentence_tokens = HAYSTACK_SENTENCES
+        .iter()
+        .map(|s| count_tokens(s))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut sentences = vec![];
+    for i in (0..HAYSTACK_SENTENCES.len()).cycle() {
+        if sentence_tokens[i] > budget {
+            break;
+        }
+        budget -= sentence_tokens[i];
+        sentences.push(HAYSTACK_SENTENCES[i]);
+    }
+
+    let needle_at = (depth.clamp(0.0, 1.0) * sentences.len() as f32).round() as usize;
+    let mut prompt = String::new();
+    for (i, sentence) in sentences.iter().enumerate() {
+        if i == needle_at {
+            prompt.push_str(&needle);
+        }
+        prompt.push(' ');
+        prompt.push_str(sentence);
+    }
+    if needle_at >= sentences.len() {
+        prompt.push_str(&needle);
+    }
+    prompt.push_str(QUESTION);
+    Ok(prompt.trim_start().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_words(text: &str) -> Result<usize> {
+        Ok(text.split_whitespace().count())
+    }
+
+    #[test]
+    fn test_build_haystack_prompt() -> Result<()> {
+        let prompt = build_haystack_prompt(100, 0.0, 12345, count_words)?;
+        assert!(prompt.starts_with("The secret number is 12345."));
+        assert!(prompt.ends_with("The secret number is"));
+        assert!(count_words(&prompt)? <= 100);
+        assert!(count_words(&prompt)? > 80);
+
+        let prompt = build_haystack_prompt(100, 1.0, 12345, count_words)?;
+        assert!(prompt.ends_with(&format!("The secret number is 12345.{}", QUESTION)));
+
+        let prompt = build_haystack_prompt(100, 0.5, 12345, count_words)?;
+        let needle_at = prompt.find("12345").unwrap();
+        assert!(needle_at > prompt.len() / 4 && needle_at < prompt.len() * 3 / 4);
+
+        let mut rng = 0;
+        let secret = next_secret(&mut rng);
+        assert!((10000..100000).contains(&secret));
+        assert_ne!(secret, next_secret(&mut rng));
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::Write;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+
+#[derive(Args, Debug)]
+pub struct GenerateArgs {
+    /// The checkpoint file to load
+    #[arg(short, long)]
+    model: String,
+
+    /// The prompt to continue
+    #[arg(long, default_value = "")]
+    prompt: String,
+
+    /// The number of the tokens to generate
+    #[arg(short, long, default_value_t = 256)]
+    steps: usize,
+
+    /// The temperature of the sampling, 0 picks the most likely token greedily
+    #[arg(short, long, default_value_t = 1.0)]
+    temperature: f32,
+
+    /// Sample from the smallest set of the tokens whose probability exceeds top-p, 0 or 1 to
+    /// sample from all the tokens
+    #[arg(long, default_value_t = 0.9)]
+    top_p: f32,
+
+    /// Stop once the text contains the string, may be given more than once
+    #[arg(long)]
+    stop: Vec<String>,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+}
+
+/// continues the prompt on cpu, the tokens are streamed to stdout as they're sampled, and the
+/// speed of the decoding is printed at the end. the tokens are detokenized and written on a
+/// thread of their own, so the decode loop does not wait on the terminal.
+pub fn run_generate(args: &GenerateArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let model = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .with_temperature(args.temperature)
+        .with_probability(args.top_p)
+        .load(&gf)?;
+    let mut runner = Llama2Runner::new(&model, model.conf.seq_len, true)?;
+
+    let prefill_started_at = Instant::now();
+    let (pos, _, token) = runner.prefill(&args.prompt, true, true)?;
+    let prefill_elapsed = prefill_started_at.elapsed();
+
+    let mut stdout = std::io::stdout();
+    write!(stdout, "{}", args.prompt)?;
+    let started_at = Instant::now();
+    let output = runner.generate_pipelined(pos, token, Some(args.steps), &args.stop, |event| {
+        write!(stdout, "{}", event.piece)?;
+        stdout.flush()?;
+        Ok(())
+    })?;
+    let elapsed = started_at.elapsed().as_secs_f64();
+    let generated_tokens = output.tokens.len();
+    writeln!(stdout)?;
+
+    eprintln!("prompt: {} tokens, {}ms", pos, prefill_elapsed.as_millis());
+    eprintln!(
+        "generated: {} tokens, {:.2} tokens/s, {} threads",
+        generated_tokens,
+        generated_tokens as f64 / elapsed,
+        args.threads
+    );
+    Ok(())
+}
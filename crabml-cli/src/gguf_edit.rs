@@ -0,0 +1,130 @@
+use std::str::FromStr;
+
+use clap::Args;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf_edit::GGUFEditor;
+
+#[derive(Args, Debug)]
+pub struct GgufEditArgs {
+    /// The GGUF file to edit
+    #[arg(short, long)]
+    model: String,
+
+    /// Write the edited file into the path instead of replacing the model
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Set a metadata key, like llama.context_length=u32:4096 or general.name=str:tiny. The
+    /// types are u8, i8, u16, i16, u32, i32, u64, i64, f32, f64, bool and str
+    #[arg(long, value_name = "KEY=TYPE:VALUE")]
+    set: Vec<String>,
+
+    /// Set a string metadata key from the content of a file, like
+    /// tokenizer.chat_template=template.jinja
+    #[arg(long, value_name = "KEY=PATH")]
+    set_file: Vec<String>,
+
+    /// Remove a metadata key
+    #[arg(long, value_name = "KEY")]
+    remove: Vec<String>,
+
+    /// Rename a tensor
+    #[arg(long, value_name = "FROM=TO")]
+    rename_tensor: Vec<String>,
+}
+
+/// rewrites the metadata and the tensor names of a GGUF file, the tensor data is copied without
+/// re-encoding, so it takes about the time of copying the file.
+pub fn run_gguf_edit(args: &GgufEditArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+
+    // read the files before the editor, the editor borrows the strings
+    let file_values = args
+        .set_file
+        .iter()
+        .map(|arg| {
+            let (key, path) = split_arg(arg, '=')?;
+            let content = std::fs::read_to_string(path).map_err(|err| {
+                Error::new(ErrorKind::IOError, format!("failed to read {}", path)).with_cause(err)
+            })?;
+            Ok((key, content))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut editor = GGUFEditor::new(&gf);
+    for arg in args.set.iter() {
+        let (key, typed_value) = split_arg(arg, '=')?;
+        let (typ, value) = split_arg(typed_value, ':')?;
+        let value = parse_metadata_value(typ, value)?;
+        if let Some(old) = editor.set(key, value.clone())? {
+            eprintln!("set {}: {:?} => {:?}", key, old, value);
+        } else {
+            eprintln!("add {}: {:?}", key, value);
+        }
+    }
+    for (key, content) in file_values.iter() {
+        editor.set(key, GGUFMetadataValue::String(content))?;
+        eprintln!("set {} from a file of {} bytes", key, content.len());
+    }
+    for key in args.remove.iter() {
+        editor.remove(key)?;
+        eprintln!("remove {}", key);
+    }
+    for arg in args.rename_tensor.iter() {
+        let (from, to) = split_arg(arg, '=')?;
+        editor.rename_tensor(from, to)?;
+        eprintln!("rename tensor {} => {}", from, to);
+    }
+
+    let output = args.output.as_deref().unwrap_or(&args.model);
+    editor.write_to_file(output)?;
+    eprintln!("written to {}", output);
+    Ok(())
+}
+
+fn split_arg(arg: &str, sep: char) -> Result<(&str, &str)> {
+    arg.split_once(sep).ok_or_else(|| {
+        Error::new(
+            ErrorKind::BadInput,
+            format!("expected {:?} in the argument {}", sep, arg),
+        )
+    })
+}
+
+fn parse_metadata_value<'a>(typ: &str, value: &'a str) -> Result<GGUFMetadataValue<'a>> {
+    let value = match typ {
+        "u8" => GGUFMetadataValue::U8(parse(typ, value)?),
+        "i8" => GGUFMetadataValue::I8(parse(typ, value)?),
+        "u16" => GGUFMetadataValue::U16(parse(typ, value)?),
+        "i16" => GGUFMetadataValue::I16(parse(typ, value)?),
+        "u32" => GGUFMetadataValue::U32(parse(typ, value)?),
+        "i32" => GGUFMetadataValue::I32(parse(typ, value)?),
+        "u64" => GGUFMetadataValue::U64(parse(typ, value)?),
+        "i64" => GGUFMetadataValue::I64(parse(typ, value)?),
+        "f32" => GGUFMetadataValue::F32(parse(typ, value)?),
+        "f64" => GGUFMetadataValue::F64(parse(typ, value)?),
+        "bool" => GGUFMetadataValue::Bool(parse::<bool>(typ, value)? as u8),
+        "str" => GGUFMetadataValue::String(value),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("unsupported metadata type {}", typ),
+            ));
+        }
+    };
+    Ok(value)
+}
+
+fn parse<T: FromStr>(typ: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::BadInput,
+            format!("failed to parse {} as {}", value, typ),
+        )
+    })
+}
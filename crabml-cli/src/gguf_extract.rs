@@ -0,0 +1,134 @@
+use std::io::Write;
+
+use clap::Args;
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+
+#[derive(Args, Debug)]
+pub struct GgufExtractArgs {
+    /// The GGUF file to read the tensor from
+    model: String,
+
+    /// The name of the tensor, like blk.0.attn_q.weight
+    #[arg(short, long)]
+    tensor: String,
+
+    /// The .npy file to write, defaults to the name of the tensor
+    #[arg(short, long)]
+    output: Option<String>,
+}
+
+/// dequantizes a tensor into f32 and dumps it as a .npy file, the shape is in the numpy order,
+/// which is the reverse of the dimensions in GGUF.
+pub fn run_gguf_extract(args: &GgufExtractArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let info = gf.get_tensor_info(&args.tensor).ok_or_else(|| {
+        Error::new(
+            ErrorKind::TensorNotFound,
+            format!("failed to find tensor {}", args.tensor),
+        )
+    })?;
+
+    let shape = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+    let device = CpuTensorDevice::new();
+    let tensor = CpuTensor::from_bytes(info.data(), info.typ(), &shape, device)?;
+    let tensor = tensor.dequantize(GGMLType::F32)?;
+    // the data of the tensor is padded to the alignment, the padding is dequantized too
+    let n_elems = shape.iter().product::<usize>();
+    let data = tensor.buf().iter_f32().take(n_elems).collect::<Vec<_>>();
+    if data.len() != n_elems {
+        return Err(Error::new(
+            ErrorKind::FormatError,
+            format!(
+                "tensor {} of shape {:?} only has {} elements",
+                args.tensor,
+                shape,
+                data.len()
+            ),
+        ));
+    }
+
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| format!("{}.npy", args.tensor));
+    let mut file = std::fs::File::create(&output).map_err(|err| io_error(&output, err))?;
+    file.write_all(&encode_npy_f32(&data, &shape))
+        .map_err(|err| io_error(&output, err))?;
+    eprintln!(
+        "written {} {:?} of shape {:?} to {}",
+        args.tensor,
+        info.typ(),
+        shape,
+        output
+    );
+    Ok(())
+}
+
+fn io_error(path: &str, err: std::io::Error) -> Error {
+    Error::new(ErrorKind::IOError, format!("failed to write {}", path)).with_cause(err)
+}
+
+/// encode the data in the .npy format v1.0, the header is padded with spaces to a multiple of
+/// 64 bytes.
+fn encode_npy_f32(data: &[f32], shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // magic (6) + version (2) + header len (2) + header + '\n'
+    let total_len = (10 + header.len() + 1).next_multiple_of(64);
+    header.extend(std::iter::repeat(' ').take(total_len - 10 - header.len() - 1));
+    header.push('\n');
+
+    let mut buf = Vec::with_capacity(total_len + data.len() * 4);
+    buf.extend_from_slice(b"\x93NUMPY\x01\x00");
+    buf.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    buf.extend_from_slice(header.as_bytes());
+    for v in data {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_npy_f32() {
+        let buf = encode_npy_f32(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        assert_eq!(&buf[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([buf[8], buf[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&buf[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+        assert!(header.ends_with(" \n"));
+        assert_eq!(buf.len(), 10 + header_len + 6 * 4);
+        assert_eq!(
+            &buf[10 + header_len..10 + header_len + 4],
+            &1.0_f32.to_le_bytes()
+        );
+
+        let buf = encode_npy_f32(&[1.0], &[1]);
+        let header = String::from_utf8_lossy(&buf[10..]);
+        assert!(header.contains("'shape': (1,)"));
+    }
+}
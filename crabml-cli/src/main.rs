@@ -1,25 +1,127 @@
 extern crate jemallocator;
 
+mod bench;
+mod bug_report;
+mod compare;
+#[cfg(feature = "server")]
+mod complete;
+#[cfg(feature = "convert")]
+mod convert;
+mod eval;
+mod eval_longctx;
+mod generate;
+#[cfg(feature = "convert")]
+mod gguf_edit;
+mod gguf_extract;
+#[cfg(feature = "server")]
+mod replay;
+#[cfg(feature = "server")]
+mod request_log;
+#[cfg(feature = "soak")]
+mod soak;
+#[cfg(feature = "hf-tokenizer")]
+mod tokenizer_check;
+mod transcript;
+mod vocab;
+
 use std::io::Write;
+#[cfg(feature = "wgpu")]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use std::time::Instant;
 
 use clap::Parser;
+use clap::Subcommand;
 use clap::ValueEnum;
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::GemmBackend;
+#[cfg(feature = "wgpu")]
 use crabml::backends::wgpu::WgpuTensorDevice;
+#[cfg(feature = "wgpu")]
 use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+use crabml::backends::Backend;
+use crabml::backends::BackendCapabilities;
+use crabml::backends::BackendOptions;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGUFFile;
 use crabml::gguf::GGUFFileLoader;
 use crabml::gguf::GGUFMetadataValueType;
+use crabml::progress::ProgressReporterRef;
+use crabml::progress::ProgressStage;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
+use crabml_llama2::attention_map::AttentionMapOptions;
+use crabml_llama2::bug_report::FailureReportOptions;
+use crabml_llama2::llama2::DecodeTiming;
+use crabml_llama2::llama2::EosPolicy;
 use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::llama2::Niceness;
+use crabml_llama2::llama2::Throttle;
+use crabml_llama2::llama_cpp_session::LlamaCppSession;
+use crabml_llama2::loop_watchdog::LoopWatchdog;
 use crabml_llama2::model::CpuLlama2ModelLoader;
+use crabml_llama2::model::Llama2Config;
+use crabml_llama2::placement::parse_size;
+use crabml_llama2::placement::LayerPlacement;
+use crabml_llama2::placement::MemoryEstimate;
+use crabml_llama2::placement::PlacementDevice;
+use crabml_llama2::sparse_ffn::SparseFfnOptions;
+use crabml_llama2::speculative::SpeculativeDecoder;
+use crabml_llama2::ttft::TtftReport;
+use crabml_llama2::CpuLlama2Model;
 use crabml_llama2::Llama2Chat;
+use crabml_llama2::RequestId;
+#[cfg(feature = "wgpu")]
 use crabml_llama2::WgpuLlama2Model;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+use crate::bench::run_bench;
+use crate::bench::BenchArgs;
+use crate::bug_report::write_bug_report;
+use crate::compare::run_compare;
+use crate::compare::CompareArgs;
+#[cfg(feature = "server")]
+use crate::complete::run_complete_server;
+#[cfg(feature = "server")]
+use crate::complete::CompleteArgs;
+#[cfg(feature = "convert")]
+use crate::convert::run_convert;
+#[cfg(feature = "convert")]
+use crate::convert::ConvertArgs;
+use crate::eval::run_eval;
+use crate::eval::EvalArgs;
+use crate::eval_longctx::run_eval_longctx;
+use crate::eval_longctx::EvalLongctxArgs;
+use crate::generate::GenerateArgs;
+#[cfg(feature = "convert")]
+use crate::gguf_edit::run_gguf_edit;
+#[cfg(feature = "convert")]
+use crate::gguf_edit::GgufEditArgs;
+use crate::gguf_extract::run_gguf_extract;
+use crate::gguf_extract::GgufExtractArgs;
+#[cfg(feature = "server")]
+use crate::replay::run_replay;
+#[cfg(feature = "server")]
+use crate::replay::ReplayArgs;
+#[cfg(feature = "soak")]
+use crate::soak::run_soak;
+#[cfg(feature = "soak")]
+use crate::soak::SoakArgs;
+#[cfg(feature = "hf-tokenizer")]
+use crate::tokenizer_check::run_tokenizer_check;
+#[cfg(feature = "hf-tokenizer")]
+use crate::tokenizer_check::TokenizerCheckArgs;
+use crate::transcript::restore_turns;
+use crate::transcript::Transcript;
+use crate::transcript::TranscriptHeader;
+use crate::transcript::TranscriptWriter;
+use crate::vocab::run_vocab;
+use crate::vocab::VocabArgs;
+
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
@@ -37,6 +139,10 @@ struct CommandArgs {
     #[arg(short, long, default_value_t = 0.9)]
     probability: f32,
 
+    /// sample from the top k most likely tokens only, 0 keeps all the tokens
+    #[arg(long, default_value_t = 0)]
+    topk: usize,
+
     #[arg(short, long, default_value_t = 1.0)]
     temperature: f32,
 
@@ -53,17 +159,242 @@ struct CommandArgs {
     #[arg(long, default_value_t = false)]
     mlock: bool,
 
+    /// load the model file into large pages, only takes effect on Windows
+    #[arg(long, default_value_t = false)]
+    large_pages: bool,
+
+    /// run with a lower priority: use a single thread and yield between the layers
+    #[arg(long, default_value_t = false)]
+    nice: bool,
+
+    /// pace the decoding to at most N tokens per second, to keep the laptops cool
+    #[arg(long)]
+    max_tokens_per_second: Option<f64>,
+
+    /// concatenate the Q, K, V weights on loading to project them in a single matmul
+    #[arg(long, default_value_t = false)]
+    fused_qkv: bool,
+
+    /// repack the Q8_0 weights into the SIMD friendly layout on loading for a faster matmul
+    #[arg(long, default_value_t = false)]
+    prepack: bool,
+
+    /// experimental: run the linear layers in int8 on both the weights and the activations,
+    /// with a scale per row and per token. fast on the CPUs with VNNI, at some loss of
+    /// precision
+    #[arg(long, default_value_t = false)]
+    w8a8: bool,
+
+    /// load the tensors in the types which have no kernels, like I8 or I32, as f16 instead of
+    /// failing. the IQ quantizations can not be decoded yet
+    #[arg(long, default_value_t = false)]
+    dequantize_unsupported: bool,
+
+    /// run all the matmuls on the internal kernels, even if it's built with the blas feature
+    #[arg(long, default_value_t = false)]
+    no_blas: bool,
+
+    /// keep the KV cache in f32 on cpu, it's kept in f16 by default to halve its memory on the
+    /// long contexts
+    #[arg(long, default_value_t = false)]
+    f32_kv_cache: bool,
+
+    /// prefill the prompt in chunks of N tokens instead of one token at a time, cpu only
+    #[arg(long)]
+    prefill_chunk_size: Option<usize>,
+
+    /// take back the last token of the prompt and sample the first token from the ones
+    /// starting with its text, so a prompt cut in the middle of a word is completed smoothly
+    #[arg(long, default_value_t = false)]
+    token_healing: bool,
+
+    /// the attention kernel: naive, chunked[:N] which attends the queries of a batch in chunks
+    /// of N rows, or flash which fuses the attention without the score matrix, `bench
+    /// --attention all` compares them. cpu only
+    #[arg(long)]
+    attention: Option<String>,
+
+    /// skip the FFN activations whose absolute value is at most the threshold with their rows
+    /// of the down projection, 0 only skips the exact zeros of the ReLU models. cpu only
+    #[arg(long)]
+    sparse_ffn: Option<f32>,
+
+    /// the ID to tag this request in the logs, generated if not given
+    #[arg(long)]
+    request_id: Option<String>,
+
+    /// whether to prepend the BOS token to the prompt, overriding the add_bos_token of the
+    /// model file
+    #[arg(long)]
+    add_bos: Option<bool>,
+
+    /// on sampling the EOS token: stop, or continue which keeps generating past it up to the
+    /// steps, like sampling a base model over the end of its documents
+    #[arg(long)]
+    eos: Option<EosArg>,
+
+    /// complete the prompt as is on a base model: no chat template, no BOS or EOS token, and
+    /// the special tokens like `</s>` in the prompt are taken as plain text
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["chat", "suffix", "add_bos", "token_healing"]
+    )]
+    raw: bool,
+
+    /// fill in the middle with a code model: the prompt is the code before the cursor, and
+    /// this is the code after it
+    #[arg(long)]
+    suffix: Option<String>,
+
+    /// record the attention weights while generating, and write them into the JSON file
+    #[arg(long)]
+    attention_maps: Option<String>,
+
+    /// only record the attention weights of every N-th layer and every N-th head
+    #[arg(long, default_value_t = 1)]
+    attention_maps_stride: usize,
+
+    /// write a JSON report of the op, the layer and the inputs into the file if a forward fails
+    /// or panics, to attach to an issue. the forwards are slower with it
+    #[arg(long)]
+    bug_report: Option<String>,
+
+    /// keep the first N values of the inputs of the failed op in the bug report
+    #[arg(long, default_value_t = 0)]
+    bug_report_values: usize,
+
+    /// reuse the KV cache of a prompt cache saved by llama.cpp on the same model, only the
+    /// tokens of the prompt which are not in the cache are prefilled
+    #[arg(long)]
+    llama_cpp_session: Option<String>,
+
+    /// stop the generation when it's stuck in a cycle of repeating tokens
+    #[arg(long, default_value_t = false)]
+    loop_watchdog: bool,
+
+    /// a small model over the same tokenizer to draft the tokens for the speculative decoding,
+    /// the tokens are sampled greedily. cpu only
+    #[arg(long)]
+    draft_model: Option<String>,
+
+    /// the max number of the tokens drafted per step of the speculative decoding
+    #[arg(long, default_value_t = 4)]
+    draft_len: usize,
+
+    /// the seed of the sampler, a random one is picked if not given
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// save the chat into a JSONL transcript with the sampler settings and the seed, so the
+    /// session can be reproduced and shared
+    #[arg(long)]
+    transcript_out: Option<String>,
+
+    /// load a chat transcript and continue the session with its settings and messages
+    #[arg(long)]
+    transcript_in: Option<String>,
+
+    /// regenerate the replies of the loaded transcript instead of restoring them, and warn on
+    /// the replies which differ from the recorded ones
+    #[arg(long, default_value_t = false)]
+    replay: bool,
+
+    /// break down the time to the first token into the file mapping, the page faults of the
+    /// weights, the model loading, the tokenizer and the prefill, and print it after the first
+    /// reply, to tell whether a slow start is bound by the I/O or the compute
+    #[arg(long, default_value_t = false)]
+    ttft_report: bool,
+
+    /// show the progress of loading the model and prefilling the prompt
+    #[arg(long, default_value_t = false)]
+    progress: bool,
+
+    /// do not persist the compiled GPU pipelines into the user cache dir
+    #[arg(long, default_value_t = false)]
+    #[cfg_attr(not(feature = "wgpu"), allow(dead_code))]
+    no_pipeline_cache: bool,
+
     /// The prompt, if it's in chat mode, it will play as the system prompt
     prompt: Option<String>,
 
     #[arg(short = 'D', long, default_value_t = DeviceType::Cpu)]
     device: DeviceType,
+
+    /// a config file which places the layers, the output head and the token embedding on the
+    /// devices with their memory budgets, it's validated on loading and overrides the device. a
+    /// model split over the devices runs on cpu with the layers placed on wgpu offloaded
+    #[arg(long)]
+    placement: Option<String>,
+
+    /// the VRAM to fit the model in on wgpu, like 6G, it's detected from the driver if not
+    /// given. the layers which fit are planned and printed, and they're offloaded to wgpu while
+    /// the rest run on cpu if the model does not fit entirely
+    #[arg(long)]
+    vram: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Benchmark the prefill and the decoding on the attention kernels
+    Bench(BenchArgs),
+    /// Compare two models on the same inputs, like a quantized model against the f16 one
+    Compare(CompareArgs),
+    /// Serve the low latency code completions to the editors over stdin/stdout
+    #[cfg(feature = "server")]
+    Complete(CompleteArgs),
+    /// Convert the f32 tensors of a GGUF file into f16, bf16 or a quantized type
+    #[cfg(feature = "convert")]
+    Convert(ConvertArgs),
+    /// Evaluate a model over the loglikelihood and greedy_until requests of a task file
+    Eval(EvalArgs),
+    /// Evaluate the recall of a model over its context with the needle-in-a-haystack prompts
+    EvalLongctx(EvalLongctxArgs),
+    /// Continue a prompt on cpu and stream the tokens to stdout
+    Generate(GenerateArgs),
+    /// Edit the metadata and the tensor names of a GGUF file without re-encoding the tensors
+    #[cfg(feature = "convert")]
+    GgufEdit(GgufEditArgs),
+    /// Dequantize a tensor of a GGUF file into f32 and dump it as a .npy file
+    GgufExtract(GgufExtractArgs),
+    /// Re-execute the requests of a request log of the completion server to reproduce them
+    #[cfg(feature = "server")]
+    Replay(ReplayArgs),
+    /// Loop the generations over many sessions and fail if the memory keeps growing
+    #[cfg(feature = "soak")]
+    Soak(SoakArgs),
+    /// Cross-check the tokenizer of a model against its tokenizer.json of Hugging Face
+    #[cfg(feature = "hf-tokenizer")]
+    TokenizerCheck(TokenizerCheckArgs),
+    /// Print the vocab of a model with the types of the tokens
+    Vocab(VocabArgs),
+}
+
+/// what to do on sampling the EOS token, see `EosPolicy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum EosArg {
+    Stop,
+    Continue,
+}
+
+impl From<EosArg> for EosPolicy {
+    fn from(eos: EosArg) -> Self {
+        match eos {
+            EosArg::Stop => EosPolicy::Stop,
+            EosArg::Continue => EosPolicy::Continue,
+        }
+    }
 }
 
 #[derive(Clone, Debug, ValueEnum)]
 enum DeviceType {
     Cpu,
     Wgpu,
+    /// prefill the prompt on wgpu, and decode on cpu
+    Hybrid,
 }
 
 impl std::fmt::Display for DeviceType {
@@ -71,22 +402,97 @@ impl std::fmt::Display for DeviceType {
         match self {
             DeviceType::Cpu => write!(f, "cpu"),
             DeviceType::Wgpu => write!(f, "wgpu"),
+            DeviceType::Hybrid => write!(f, "hybrid"),
         }
     }
 }
 
-fn run<T: Tensor>(runner: &mut Llama2Runner<T>, args: &CommandArgs) -> Result<()> {
+fn run<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    args: &CommandArgs,
+    ttft: Option<TtftReport>,
+) -> Result<()> {
+    if args.loop_watchdog {
+        runner.set_loop_watchdog(Some(LoopWatchdog::new()));
+    }
+    if let Some(eos) = args.eos {
+        runner.set_eos_policy(eos.into());
+    }
     if args.chat {
-        run_chat(runner, args)?;
+        run_chat(runner, args, ttft)?;
     } else {
-        run_generate(runner, args)?;
+        run_generate(runner, args, ttft)?;
     }
 
     Ok(())
 }
 
-fn run_chat<T: Tensor>(runner: &mut Llama2Runner<T>, args: &CommandArgs) -> Result<()> {
-    let mut system_prompt = args.prompt.clone();
+fn run_chat<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    args: &CommandArgs,
+    mut ttft: Option<TtftReport>,
+) -> Result<()> {
+    let transcript = args
+        .transcript_in
+        .as_deref()
+        .map(Transcript::load)
+        .transpose()?;
+    let header = match &transcript {
+        Some(transcript) => transcript.header.clone(),
+        None => TranscriptHeader {
+            model: args.model.clone(),
+            temperature: args.temperature,
+            top_k: args.topk,
+            top_p: args.probability,
+            seed: args.seed.unwrap_or_else(random_seed),
+            system_prompt: args.prompt.clone(),
+        },
+    };
+    let vocab_size = runner.conf().vocab_size;
+    runner.set_sampler(header.sampler(vocab_size, 0));
+    let mut writer = args
+        .transcript_out
+        .as_deref()
+        .map(|path| TranscriptWriter::create(path, &header))
+        .transpose()?;
+
+    let mut system_prompt = header.system_prompt.clone();
+    match &transcript {
+        Some(transcript) if args.replay => {
+            for (line, recorded_reply) in transcript.turns.iter() {
+                println!(">> {}", line);
+                let mut chat = Llama2Chat::new(runner, line, system_prompt.take())?;
+                let (reply, n_tokens) = print_reply(&mut chat)?;
+                print_ttft_report(runner, &mut ttft, false)?;
+                if &reply != recorded_reply {
+                    eprintln!("warning: the reply differs from the recorded one");
+                }
+                if let Some(writer) = writer.as_mut() {
+                    let n_coins = runner.sampler().state().n_coins;
+                    writer.write_turn(line, &reply, n_tokens, runner.kv_cache_len(), n_coins)?;
+                }
+            }
+        }
+        Some(transcript) => {
+            // the restored replies are not sampled again, the sampler goes on after the coins
+            // flipped on them in the session. the coins of the turns before the last one are
+            // not recorded, they're written with the ones of the last turn
+            let n_coins = transcript.sampler_coins.unwrap_or(0);
+            restore_turns(runner, transcript, |runner, line, reply| {
+                println!(">> {}\n{}", line, reply);
+                if let Some(writer) = writer.as_mut() {
+                    let n_tokens = runner.tokenizer().encode(reply, false, false)?.len();
+                    writer.write_turn(line, reply, n_tokens, runner.kv_cache_len(), n_coins)?;
+                }
+                Ok(())
+            })?;
+            if !transcript.turns.is_empty() {
+                system_prompt = None;
+            }
+        }
+        None => {}
+    }
+
     let mut rl = Editor::<()>::new();
     loop {
         let line = match rl.readline(">> ") {
@@ -110,48 +516,147 @@ fn run_chat<T: Tensor>(runner: &mut Llama2Runner<T>, args: &CommandArgs) -> Resu
             }
         };
 
-        let mut chat = Llama2Chat::new(runner, &line, system_prompt.clone())?;
-
         // only put system prompt in the first round
-        if system_prompt.is_some() {
-            system_prompt = None;
-        }
+        let mut chat = Llama2Chat::new(runner, &line, system_prompt.take())?;
 
         // TODO: handle the user input while generating
-        let reply_iter = chat.reply()?;
-        for token in reply_iter {
-            print!("{}", token?);
-            std::io::stdout().flush().unwrap();
+        let (reply, n_tokens) = print_reply(&mut chat)?;
+        print_ttft_report(runner, &mut ttft, false)?;
+        if let Some(writer) = writer.as_mut() {
+            let n_coins = runner.sampler().state().n_coins;
+            writer.write_turn(&line, &reply, n_tokens, runner.kv_cache_len(), n_coins)?;
         }
-        chat.finish()?;
-        println!();
     }
 
     Ok(())
 }
 
-fn run_generate<U: Tensor>(runner: &mut Llama2Runner<U>, args: &CommandArgs) -> Result<()> {
+// stream the reply to stdout, returns the reply and the number of its tokens
+fn print_reply<T: Tensor>(chat: &mut Llama2Chat<T>) -> Result<(String, usize)> {
+    let mut reply = String::new();
+    let mut n_tokens = 0;
+    for token in chat.reply()? {
+        let token = token?;
+        print!("{}", token);
+        std::io::stdout().flush().unwrap();
+        reply.push_str(&token);
+        n_tokens += 1;
+    }
+    chat.finish()?;
+    println!();
+    Ok((reply, n_tokens))
+}
+
+// the model is loaded once the runner is about to run
+fn loaded_ttft(ttft: Option<TtftReport>, load_started_at: Instant) -> Option<TtftReport> {
+    ttft.map(|report| TtftReport {
+        model_load: load_started_at.elapsed(),
+        ..report
+    })
+}
+
+// print the TTFT report with the last prefill of the runner, only once. the faults of the
+// weights are split out of it by a prefill of the same length on the warm weights
+fn print_ttft_report<T: Tensor>(
+    runner: &Llama2Runner<T>,
+    ttft: &mut Option<TtftReport>,
+    batched: bool,
+) -> Result<()> {
+    let (Some(report), Some(timing)) = (ttft.take(), runner.prefill_timing()) else {
+        return Ok(());
+    };
+    let mut report = report.with_prefill(timing);
+    if let Some(warm) = runner.time_warm_prefill(timing.n_tokens, batched)? {
+        report = report.with_warm_prefill(warm.compute);
+    }
+    eprintln!("{}", report);
+    Ok(())
+}
+
+fn random_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// import the llama.cpp session and prefill the prompt after the prefix it shares with the
+// session, the last token of the prompt is always forwarded again to take its logits
+fn prefill_with_llama_cpp_session<U: Tensor>(
+    runner: &mut Llama2Runner<U>,
+    path: &str,
+    prompt: &str,
+    batched: bool,
+) -> Result<(usize, usize, usize)> {
+    let session = LlamaCppSession::load(path)?;
+    runner.import_llama_cpp_session(&session)?;
+
+    let options = *runner.tokenizer().options();
+    let tokens = runner
+        .tokenizer()
+        .encode(prompt, options.add_bos, options.add_eos)?;
+    let reused = session
+        .tokens()
+        .iter()
+        .zip(tokens.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(tokens.len().saturating_sub(1));
+    runner.truncate(reused)?;
+    eprintln!(
+        "reused {} of {} tokens in the llama.cpp session",
+        reused,
+        session.tokens().len()
+    );
+    let keep_head = reused == 0 && options.add_bos;
+    runner.prefill_tokens(tokens[reused..].to_vec(), keep_head, batched)
+}
+
+fn run_generate<U: Tensor>(
+    runner: &mut Llama2Runner<U>,
+    args: &CommandArgs,
+    mut ttft: Option<TtftReport>,
+) -> Result<()> {
     let metrics = runner.metrics.clone();
+    let request_id = match &args.request_id {
+        Some(id) => RequestId::from(id.as_str()),
+        None => RequestId::generate(),
+    };
+    runner.set_request_id(Some(request_id.clone()));
+    if args.attention_maps.is_some() {
+        let stride = args.attention_maps_stride;
+        let options = AttentionMapOptions::new()
+            .with_layer_stride(stride)
+            .with_head_stride(stride);
+        runner.set_attention_maps(Some(options));
+    }
     let prefill_started_at = Instant::now();
     let prompt = args.prompt.clone().unwrap_or("".to_string());
-    let (prefill_pos, _prev_token, token) = runner.prefill(&prompt, true, false)?;
+    let batched = args.prefill_chunk_size.is_some();
+    let (prefill_pos, _prev_token, token) = match (&args.suffix, &args.llama_cpp_session) {
+        (Some(suffix), _) => runner.prefill_infill(&prompt, suffix, batched)?,
+        (None, Some(path)) => prefill_with_llama_cpp_session(runner, path, &prompt, batched)?,
+        (None, None) if args.raw => runner.prefill_raw(&prompt, batched)?,
+        (None, None) if args.token_healing => runner.prefill_healed(&prompt, true, batched)?,
+        (None, None) => runner.prefill(&prompt, true, batched)?,
+    };
     let prefill_elapsed = prefill_started_at.elapsed();
     if args.verbose {
         dump_metrics(&runner.metrics);
     }
     runner.metrics.reset();
 
-    let mut output = runner.generate(prefill_pos, token, Some(args.steps));
+    let mut output = runner.generate_stream(prefill_pos, token, Some(args.steps));
     let mut generated_tokens = 0;
     let generation_started_at = Instant::now();
 
     print!("{}", &prompt);
     loop {
         let _t = metrics.total_walltime.track();
-        match output.next() {
+        match output.next_token() {
             Some(token) => {
                 generated_tokens += 1;
-                print!("{}", token?);
+                print!("{}", token?.piece);
                 std::io::stdout().flush().unwrap();
             }
             None => {
@@ -165,10 +670,22 @@ fn run_generate<U: Tensor>(runner: &mut Llama2Runner<U>, args: &CommandArgs) ->
         metrics.reset();
     }
 
+    if let Some(suffix) = &args.suffix {
+        print!("{}", suffix);
+    }
+
+    if let Some(path) = &args.attention_maps {
+        write_attention_maps(runner, path)?;
+    }
+
     let generation_elapsed = generation_started_at.elapsed().as_secs_f64();
     let generated_tokens_per_second = generated_tokens as f64 / generation_elapsed;
 
     println!();
+    println!("request: {}", request_id);
+    if let Some(reason) = runner.stop_reason() {
+        println!("finish reason: {}", reason.finish_reason());
+    }
     println!(
         "prompt: {} tokens, {}ms",
         prefill_pos,
@@ -178,10 +695,257 @@ fn run_generate<U: Tensor>(runner: &mut Llama2Runner<U>, args: &CommandArgs) ->
         "{} tokens/s, {} threads",
         generated_tokens_per_second, args.threads
     );
+    print_ttft_report(runner, &mut ttft, batched)?;
+
+    Ok(())
+}
+
+fn enable_bug_report<T: Tensor>(runner: &mut Llama2Runner<T>, args: &CommandArgs) {
+    if args.bug_report.is_some() {
+        let options = FailureReportOptions::new().with_dump_len(args.bug_report_values);
+        runner.set_failure_reports(Some(options));
+    }
+}
+
+// write the report of the failed forward if there's one
+fn save_bug_report<T: Tensor>(
+    runner: &Llama2Runner<T>,
+    args: &CommandArgs,
+    gf: &GGUFFile,
+) -> Result<()> {
+    if let (Some(path), Some(report)) = (&args.bug_report, runner.failure_report()) {
+        write_bug_report(path, report, &args.model, gf)?;
+        eprintln!(
+            "the bug report of the failed forward is written into {}",
+            path
+        );
+    }
+    Ok(())
+}
+
+// validate the placement of the model and pick the device to run on, the placement is returned
+// if the model is split over the devices
+fn placement_device(
+    path: &str,
+    gf: &GGUFFile,
+    conf: &Llama2Config,
+    f16_kv_cache: bool,
+) -> Result<(DeviceType, Option<LayerPlacement>)> {
+    let placement = LayerPlacement::load(path, conf.n_layers)?;
+    let use_f16_kv_cache = f16_kv_cache && placement.single_device() != Some(PlacementDevice::Wgpu);
+    let estimate = MemoryEstimate::from_gguf(gf, conf, conf.seq_len, use_f16_kv_cache);
+    for (device, bytes) in placement.validate(&estimate)? {
+        eprintln!("placement: {} MiB on {}", bytes >> 20, device);
+    }
+    let head_on_cpu = placement.output_device() == PlacementDevice::Cpu
+        && placement.embed_device() == PlacementDevice::Cpu;
+    match placement.single_device() {
+        Some(PlacementDevice::Cpu) => Ok((DeviceType::Cpu, None)),
+        Some(PlacementDevice::Wgpu) => Ok((DeviceType::Wgpu, None)),
+        None if head_on_cpu => Ok((DeviceType::Cpu, Some(placement))),
+        None => Err(Error::new(
+            ErrorKind::NotImplemented,
+            "the output head and the token embedding of a model split over the devices run on \
+             cpu, place them on cpu",
+        )),
+    }
+}
+
+// forward the layers placed on wgpu there, a runner per range of the consecutive layers holds
+// their weights, and the rest of the model stays on the cpu runner
+#[cfg(feature = "wgpu")]
+fn offload_layers<'a>(
+    mut runner: Llama2Runner<CpuTensor<'a>>,
+    model_cpu: &CpuLlama2Model<'a>,
+    placement: &LayerPlacement,
+    args: &CommandArgs,
+) -> Result<Llama2Runner<CpuTensor<'a>>> {
+    let conf = &model_cpu.conf;
+    // the staging buffer should hold the hidden states of a prefill chunk
+    let hidden_bytes = runner.prefill_chunk_size() * conf.embedding_dim * 4;
+    let device_wgpu = WgpuTensorDevice::new(wgpu_device_options(args, hidden_bytes))?;
+    for layers in placement.layer_ranges_on(PlacementDevice::Wgpu) {
+        let model_wgpu =
+            WgpuLlama2Model::from_cpu_layers(model_cpu, device_wgpu.clone(), layers.clone())?;
+        let runner_wgpu = Llama2Runner::new(&model_wgpu, conf.seq_len, false)?;
+        runner = runner.with_layer_offload(layers, Box::new(runner_wgpu));
+    }
+    Ok(runner)
+}
+
+#[cfg(not(feature = "wgpu"))]
+fn offload_layers<'a>(
+    _runner: Llama2Runner<CpuTensor<'a>>,
+    _model_cpu: &CpuLlama2Model<'a>,
+    _placement: &LayerPlacement,
+    _args: &CommandArgs,
+) -> Result<Llama2Runner<CpuTensor<'a>>> {
+    Err(Error::new(
+        ErrorKind::NotImplemented,
+        "placing the layers on wgpu needs the wgpu feature of crabml-cli",
+    ))
+}
+
+// plan the layers within the VRAM, the model runs on cpu with the layers which fit offloaded to
+// wgpu if it does not fit entirely, the plan is returned then
+fn plan_device(
+    budget: usize,
+    device: DeviceType,
+    gf: &GGUFFile,
+    conf: &Llama2Config,
+    f16_kv_cache: bool,
+    cpu_caps: &BackendCapabilities,
+) -> (DeviceType, Option<LayerPlacement>) {
+    if let DeviceType::Cpu = device {
+        return (device, None);
+    }
+    let estimate = MemoryEstimate::from_gguf(gf, conf, conf.seq_len, false);
+    let plan = LayerPlacement::plan(&estimate, PlacementDevice::Wgpu, budget);
+    eprintln!(
+        "plan: {}/{} layers fit into {} MiB of VRAM, {} MiB needed in total",
+        plan.n_layers_on(PlacementDevice::Wgpu),
+        conf.n_layers,
+        budget >> 20,
+        estimate.total_bytes() >> 20
+    );
+    for line in plan.to_string().lines() {
+        eprintln!("plan: {}", line);
+    }
+    if plan.single_device() == Some(PlacementDevice::Wgpu) {
+        return (device, None);
+    }
 
+    // the cpu keeps the KV cache in f16 unless --f32-kv-cache
+    let estimate = MemoryEstimate::from_gguf(gf, conf, conf.seq_len, f16_kv_cache);
+    if let Some(ram) = cpu_caps.available_memory {
+        if estimate.total_bytes() > ram {
+            eprintln!(
+                "plan: {} MiB needed on cpu, but only {} MiB of RAM is available",
+                estimate.total_bytes() >> 20,
+                ram >> 20
+            );
+        }
+    }
+    if plan.n_layers_on(PlacementDevice::Wgpu) == 0 {
+        eprintln!("plan: no layer fits into the VRAM, running on cpu");
+        return (DeviceType::Cpu, None);
+    }
+    eprintln!("plan: the model does not fit into the VRAM, running the rest of the layers on cpu");
+    (DeviceType::Cpu, Some(plan))
+}
+
+// the free VRAM of the GPU the model runs on, the note is printed if it can not be detected
+#[cfg(feature = "wgpu")]
+fn detect_vram(device: &DeviceType) -> Option<usize> {
+    if let DeviceType::Cpu = device {
+        return None;
+    }
+    let vram = WgpuTensorDevice::detect_vram(None);
+    if vram.is_none() {
+        eprintln!("plan: the VRAM is not detected, pass --vram to plan the layers into it");
+    }
+    vram
+}
+
+#[cfg(not(feature = "wgpu"))]
+fn detect_vram(_device: &DeviceType) -> Option<usize> {
+    None
+}
+
+// the flags which only take effect on cpu are rejected on the other devices, instead of being
+// silently ignored
+fn check_cpu_only_args(args: &CommandArgs, device: &DeviceType) -> Result<()> {
+    if let DeviceType::Cpu = device {
+        return Ok(());
+    }
+    let cpu_only = [
+        ("--prefill-chunk-size", args.prefill_chunk_size.is_some()),
+        ("--attention", args.attention.is_some()),
+        ("--sparse-ffn", args.sparse_ffn.is_some()),
+        ("--draft-model", args.draft_model.is_some()),
+    ];
+    match cpu_only.iter().find(|(_, given)| *given) {
+        Some((flag, _)) => Err(Error::new(
+            ErrorKind::BadInput,
+            format!("{} is cpu only, but the model runs on {}", flag, device),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn run_speculative<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    draft: &mut Llama2Runner<T>,
+    args: &CommandArgs,
+) -> Result<()> {
+    let prompt = args.prompt.clone().unwrap_or("".to_string());
+    let batched = args.prefill_chunk_size.is_some();
+    let mut decoder = SpeculativeDecoder::new(runner, draft)?.with_draft_len(args.draft_len);
+    let (pos, token) = decoder.prefill(&prompt, batched)?;
+
+    let generation_started_at = Instant::now();
+    print!("{}", &prompt);
+    let generated_tokens = decoder.generate(pos, token, args.steps, |token| {
+        print!("{}", token);
+        std::io::stdout().flush().unwrap();
+    })?;
+    let generation_elapsed = generation_started_at.elapsed().as_secs_f64();
+    let stats = decoder.stats().clone();
+
+    println!();
+    if let Some(reason) = runner.stop_reason() {
+        println!("finish reason: {}", reason.finish_reason());
+    }
+    println!(
+        "{} tokens/s, {} threads",
+        generated_tokens as f64 / generation_elapsed,
+        args.threads
+    );
+    println!(
+        "speculative: {} steps, {:.2} drafted/step, {:.1}% accepted, {:.0}ms saved (estimated)",
+        stats.n_steps,
+        stats.mean_draft_len(),
+        stats.acceptance_rate() * 100.0,
+        stats.time_saved_ms()
+    );
     Ok(())
 }
 
+// the pieces of the tokens are written along with the weights for the visualization tools
+fn write_attention_maps<T: Tensor>(runner: &Llama2Runner<T>, path: &str) -> Result<()> {
+    let maps = runner.attention_maps().unwrap();
+    let tokenizer = runner.tokenizer();
+    let rows = maps
+        .rows()
+        .iter()
+        .map(|row| {
+            serde_json::json!({
+                "layer": row.layer,
+                "head": row.head,
+                "pos": row.pos,
+                "weights": row.weights,
+            })
+        })
+        .collect::<Vec<_>>();
+    let json = serde_json::json!({
+        "n_layers": runner.conf().n_layers,
+        "n_heads": runner.conf().n_heads,
+        "tokens": maps
+            .tokens()
+            .iter()
+            .map(|id| tokenizer.token_to_piece(*id))
+            .collect::<Vec<_>>(),
+        "rows": rows,
+    });
+    std::fs::write(path, json.to_string()).map_err(|err| {
+        Error::new(
+            ErrorKind::IOError,
+            format!("failed to write the attention maps into {}", path),
+        )
+        .with_cause(err)
+    })
+}
+
 fn dump_metrics(metrics: &TensorMetrics) {
     println!();
     if metrics.forward_walltime.as_millis() == 0.0 {
@@ -221,41 +985,250 @@ fn main() -> Result<()> {
     let args = CommandArgs::parse();
     let start_time = Instant::now();
 
-    let mut thread_num = args.threads;
-    if thread_num == 0 {
-        thread_num = num_cpus::get();
+    match &args.command {
+        Some(Command::Bench(bench_args)) => return run_bench(bench_args),
+        Some(Command::Compare(compare_args)) => return run_compare(compare_args),
+        #[cfg(feature = "server")]
+        Some(Command::Complete(complete_args)) => return run_complete_server(complete_args),
+        #[cfg(feature = "convert")]
+        Some(Command::Convert(convert_args)) => return run_convert(convert_args),
+        Some(Command::Eval(eval_args)) => return run_eval(eval_args),
+        Some(Command::EvalLongctx(eval_args)) => return run_eval_longctx(eval_args),
+        // the run_generate of main runs the prompt of the top-level args
+        Some(Command::Generate(generate_args)) => return generate::run_generate(generate_args),
+        #[cfg(feature = "convert")]
+        Some(Command::GgufEdit(gguf_edit_args)) => return run_gguf_edit(gguf_edit_args),
+        Some(Command::GgufExtract(gguf_extract_args)) => {
+            return run_gguf_extract(gguf_extract_args);
+        }
+        #[cfg(feature = "server")]
+        Some(Command::Replay(replay_args)) => return run_replay(replay_args),
+        #[cfg(feature = "soak")]
+        Some(Command::Soak(soak_args)) => return run_soak(soak_args),
+        #[cfg(feature = "hf-tokenizer")]
+        Some(Command::TokenizerCheck(check_args)) => return run_tokenizer_check(check_args),
+        Some(Command::Vocab(vocab_args)) => return run_vocab(vocab_args),
+        None => {}
     }
 
+    let backend_options = BackendOptions::new().with_threads(args.threads);
+
     // it may takes a while to open the file if mlock is enabled
     eprintln!("loading model...");
-    let gl = GGUFFileLoader::new(&args.model, args.mlock)?;
+    let gl = if args.large_pages {
+        GGUFFileLoader::new_with_large_pages(&args.model)?
+    } else {
+        GGUFFileLoader::new(&args.model, args.mlock)?
+    };
+    if let Some(err) = gl.prefetch_error() {
+        eprintln!("warning: {}", err);
+    }
     let gf = gl.open()?;
+    let ttft = args.ttft_report.then(|| TtftReport {
+        file_mapping: start_time.elapsed(),
+        ..TtftReport::new()
+    });
+    let load_started_at = Instant::now();
+    let gl_draft = args
+        .draft_model
+        .as_deref()
+        .map(|path| GGUFFileLoader::new(path, args.mlock))
+        .transpose()?;
+    let gf_draft = gl_draft.as_ref().map(|gl| gl.open()).transpose()?;
 
     if args.verbose {
         dump_gguf_metadata(&gf);
     }
 
-    let model_cpu = CpuLlama2ModelLoader::new()
-        .with_thread_num(thread_num)
-        .load(&gf)?;
+    let progress_reporter: ProgressReporterRef = Arc::new(report_progress);
+    let mut model_loader = CpuLlama2ModelLoader::new()
+        .with_backend_options(backend_options)
+        .with_temperature(args.temperature)
+        .with_topk(args.topk)
+        .with_probability(args.probability)
+        .with_fused_qkv(args.fused_qkv)
+        .with_prepacking(args.prepack)
+        .with_w8a8(args.w8a8)
+        .with_dequantize_unsupported(args.dequantize_unsupported);
+    if let Some(seed) = args.seed {
+        model_loader = model_loader.with_seed(seed);
+    }
+    if let Some(add_bos) = args.add_bos {
+        model_loader = model_loader.with_add_bos(add_bos);
+    }
+    if args.no_blas {
+        model_loader = model_loader.with_gemm_backend(GemmBackend::Internal);
+    }
+    if args.progress {
+        model_loader = model_loader.with_progress_reporter(progress_reporter.clone());
+    }
+    let model_cpu = model_loader.load(&gf)?;
     let conf = model_cpu.conf.clone();
+    if args.verbose {
+        eprintln!("cpu: {}", model_cpu.device.capabilities());
+    }
 
-    match args.device {
+    let f16_kv_cache = !args.f32_kv_cache;
+    let (device, split) = match &args.placement {
+        Some(path) => placement_device(path, &gf, &conf, f16_kv_cache)?,
+        None => (args.device.clone(), None),
+    };
+    let vram = match &args.vram {
+        Some(vram) => Some(parse_size(vram).ok_or_else(|| {
+            Error::new(
+                ErrorKind::BadInput,
+                format!("invalid --vram {}, expect a size like 6G", vram),
+            )
+        })?),
+        None => detect_vram(&device),
+    };
+    let (device, split) = match vram {
+        Some(budget) if split.is_none() => {
+            let cpu_caps = model_cpu.device.capabilities();
+            plan_device(budget, device, &gf, &conf, f16_kv_cache, &cpu_caps)
+        }
+        _ => (device, split),
+    };
+    check_cpu_only_args(&args, &device)?;
+    match device {
         DeviceType::Cpu => {
-            let mut runner = Llama2Runner::new(&model_cpu, conf.seq_len, true)?;
+            let mut runner = Llama2Runner::new(&model_cpu, conf.seq_len, f16_kv_cache)?;
+            if args.progress {
+                runner = runner.with_progress_reporter(progress_reporter.clone());
+            }
+            if args.nice {
+                runner = runner.with_niceness(Niceness::background());
+            }
+            if let Some(tps) = args.max_tokens_per_second {
+                runner = runner.with_throttle_hook(Arc::new(pace_decoding(tps)));
+            }
+            if let Some(chunk_size) = args.prefill_chunk_size {
+                runner = runner.with_prefill_chunk_size(chunk_size);
+            }
+            if let Some(kernel) = &args.attention {
+                runner = runner.with_attention_kernel(kernel.parse()?);
+            }
+            if let Some(threshold) = args.sparse_ffn {
+                runner = runner.with_sparse_ffn(SparseFfnOptions::new(threshold));
+            }
+            if let Some(placement) = &split {
+                runner = offload_layers(runner, &model_cpu, placement, &args)?;
+            }
+            if let Some(gf_draft) = &gf_draft {
+                let model_draft = CpuLlama2ModelLoader::new()
+                    .with_backend_options(backend_options)
+                    .load(gf_draft)?;
+                let mut draft = Llama2Runner::new(&model_draft, conf.seq_len, f16_kv_cache)?;
+                eprintln!("model loaded: {}ms", start_time.elapsed().as_millis());
+                return run_speculative(&mut runner, &mut draft, &args);
+            }
+            enable_bug_report(&mut runner, &args);
             eprintln!("model loaded: {}ms", start_time.elapsed().as_millis());
-            run(&mut runner, &args)?;
+            let ttft = loaded_ttft(ttft, load_started_at);
+            let result = run(&mut runner, &args, ttft);
+            save_bug_report(&runner, &args, &gf)?;
+            result?;
         }
+        #[cfg(feature = "wgpu")]
         DeviceType::Wgpu => {
-            let device_wgpu = WgpuTensorDevice::new(
-                WgpuTensorDeviceOptions::new().with_staging_buf_bytes(conf.vocab_size * 4),
-            );
+            let device_wgpu =
+                WgpuTensorDevice::new(wgpu_device_options(&args, conf.vocab_size * 4))?;
+            if args.verbose {
+                eprintln!("wgpu: {}", device_wgpu.capabilities());
+            }
+            let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
+            let runner_wgpu = Llama2Runner::new(&model_wgpu, conf.seq_len, false)?;
+
+            // run on the gpu, and move the session to the cpu if the gpu fails
+            let mut runner = Llama2Runner::new(&model_cpu, conf.seq_len, f16_kv_cache)?
+                .with_forward_offload(Box::new(runner_wgpu));
+            if args.progress {
+                runner = runner.with_progress_reporter(progress_reporter.clone());
+            }
+            if let Some(tps) = args.max_tokens_per_second {
+                runner = runner.with_throttle_hook(Arc::new(pace_decoding(tps)));
+            }
+            enable_bug_report(&mut runner, &args);
+            let ttft = loaded_ttft(ttft, load_started_at);
+            let result = run(&mut runner, &args, ttft);
+            if let Some(cause) = runner.fallback_cause() {
+                eprintln!("fell back to cpu on the gpu failure: {}", cause);
+            }
+            save_bug_report(&runner, &args, &gf)?;
+            result?;
+        }
+        #[cfg(feature = "wgpu")]
+        DeviceType::Hybrid => {
+            // the staging buffer should hold the keys of a layer on moving the KV cache
+            let kv_bytes = conf.n_kv_heads * conf.seq_len * conf.head_size() * 4;
+            let device_wgpu = WgpuTensorDevice::new(wgpu_device_options(
+                &args,
+                kv_bytes.max(conf.vocab_size * 4),
+            ))?;
             let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
+            let runner_wgpu = Llama2Runner::new(&model_wgpu, conf.seq_len, false)?;
 
-            let mut runner = Llama2Runner::new(&model_wgpu, conf.seq_len, false)?;
-            run(&mut runner, &args)?;
+            let mut runner = Llama2Runner::new(&model_cpu, conf.seq_len, f16_kv_cache)?
+                .with_prefill_offload(Box::new(runner_wgpu));
+            if args.progress {
+                runner = runner.with_progress_reporter(progress_reporter.clone());
+            }
+            if args.nice {
+                runner = runner.with_niceness(Niceness::background());
+            }
+            if let Some(tps) = args.max_tokens_per_second {
+                runner = runner.with_throttle_hook(Arc::new(pace_decoding(tps)));
+            }
+            enable_bug_report(&mut runner, &args);
+            eprintln!("model loaded: {}ms", start_time.elapsed().as_millis());
+            let ttft = loaded_ttft(ttft, load_started_at);
+            let result = run(&mut runner, &args, ttft);
+            save_bug_report(&runner, &args, &gf)?;
+            result?;
+        }
+        #[cfg(not(feature = "wgpu"))]
+        device @ (DeviceType::Wgpu | DeviceType::Hybrid) => {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!("the {} device needs the wgpu feature of crabml-cli", device),
+            ));
         }
     }
 
     Ok(())
 }
+
+#[cfg(feature = "wgpu")]
+fn wgpu_device_options(args: &CommandArgs, staging_buf_bytes: usize) -> WgpuTensorDeviceOptions {
+    let options = WgpuTensorDeviceOptions::new().with_staging_buf_bytes(staging_buf_bytes);
+    match pipeline_cache_dir() {
+        Some(dir) if !args.no_pipeline_cache => options.with_pipeline_cache_dir(dir),
+        _ => options,
+    }
+}
+
+#[cfg(feature = "wgpu")]
+// the user cache dir like ~/.cache/crabml/pipelines, or %LOCALAPPDATA%\crabml\pipelines
+fn pipeline_cache_dir() -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_dir.join("crabml").join("pipelines"))
+}
+
+// sleep out the rest of the interval of each token
+fn pace_decoding(tokens_per_second: f64) -> impl Fn(&DecodeTiming) -> Throttle {
+    let interval = Duration::from_secs_f64(1.0 / tokens_per_second.max(1e-3));
+    move |timing: &DecodeTiming| Throttle {
+        pause: interval.checked_sub(timing.token_elapsed),
+        max_threads: None,
+    }
+}
+
+fn report_progress(stage: ProgressStage, completed: usize, total: usize) {
+    eprint!("\r{}: {}/{}", stage, completed, total);
+    if completed >= total {
+        eprintln!();
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+
+use crate::complete::complete_tokens;
+use crate::complete::FileSession;
+use crate::request_log::load_request_log;
+use crate::request_log::RequestOutcome;
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// The request log written by the completion server with --request-log
+    log: String,
+
+    /// The model to replay on, defaults to the one recorded in the log
+    #[arg(short, long)]
+    model: Option<String>,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// The context length of each file, defaults to the one of the model
+    #[arg(long)]
+    context: Option<usize>,
+}
+
+/// re-executes the requests of a request log in their order, each file on its own session like
+/// the server, and reports the completions which differ from the recorded ones. the requests
+/// run through the same path as the server, so the same model on the same build is expected
+/// to reproduce the completions bit for bit. a cancelled request is cancelled after the same
+/// number of tokens, and a failed one is expected to fail with the same error.
+pub fn run_replay(args: &ReplayArgs) -> Result<()> {
+    let entries = load_request_log(&args.log)?;
+    let first = match entries.first() {
+        Some(first) => first,
+        None => {
+            eprintln!("the request log {} is empty", args.log);
+            return Ok(());
+        }
+    };
+    if let Some(entry) = entries.iter().find(|e| {
+        e.model != first.model || e.temperature != first.temperature || e.seed != first.seed
+    }) {
+        return Err(Error::new(
+            ErrorKind::BadInput,
+            format!(
+                "the request {} was served with other settings than the first one, the log \
+                 should be split by the runs of the server",
+                entry.id
+            ),
+        ));
+    }
+
+    let model_path = args.model.as_deref().unwrap_or(&first.model);
+    let gl = GGUFFileLoader::new(model_path, false)?;
+    let gf = gl.open()?;
+    let model = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .with_temperature(first.temperature)
+        .with_seed(first.seed)
+        .load(&gf)?;
+    let seq_len = args.context.unwrap_or(model.conf.seq_len);
+
+    let mut sessions: HashMap<String, FileSession> = HashMap::new();
+    let mut n_differed = 0;
+    for entry in entries.iter() {
+        // the request was superseded before it touched the session
+        if entry.outcome == RequestOutcome::Debounced {
+            println!("{}\tdebounced", entry.id);
+            continue;
+        }
+        if entry.new_session || !sessions.contains_key(&entry.path) {
+            sessions.insert(entry.path.clone(), FileSession::new(&model, seq_len)?);
+        }
+        let session = sessions.get_mut(&entry.path).unwrap();
+        if let Some(n_coins) = entry.sampler_coins {
+            if session.sampler_coins() != n_coins {
+                eprintln!(
+                    "warning: request {} started after {} coins of the sampler, {} were \
+                     recorded",
+                    entry.id,
+                    session.sampler_coins(),
+                    n_coins
+                );
+                session.seek_sampler(n_coins);
+            }
+        }
+
+        let prompt_tokens = entry.prompt_tokens.clone();
+        let cancel_after = (entry.outcome == RequestOutcome::Cancelled).then_some(entry.n_tokens);
+        let mut n_tokens = 0;
+        let result = complete_tokens(session, prompt_tokens, entry.max_tokens, || {
+            n_tokens += 1;
+            cancel_after.is_some_and(|n| n_tokens >= n)
+        });
+        let replayed = match result {
+            Ok(c) => {
+                if c.cached_tokens != entry.cached_tokens {
+                    eprintln!(
+                        "warning: request {} reused {} cached tokens, {} were recorded",
+                        entry.id, c.cached_tokens, entry.cached_tokens
+                    );
+                }
+                let outcome = match c.cancelled {
+                    true => RequestOutcome::Cancelled,
+                    false => RequestOutcome::Completed,
+                };
+                (outcome, c.text)
+            }
+            Err(err) => (RequestOutcome::Failed, err.to_string()),
+        };
+        let recorded = match entry.outcome {
+            RequestOutcome::Failed => entry.error.clone().unwrap_or_default(),
+            _ => entry.text.clone(),
+        };
+        if replayed == (entry.outcome, recorded.clone()) {
+            println!("{}\tok", entry.id);
+        } else {
+            n_differed += 1;
+            println!("{}\tdiffers", entry.id);
+            println!("  recorded: {:?} {:?}", entry.outcome, recorded);
+            println!("  replayed: {:?} {:?}", replayed.0, replayed.1);
+        }
+    }
+    println!("replayed {} requests, {} differ", entries.len(), n_differed);
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tokenizer::TokenID;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+pub const REQUEST_LOG_VERSION: u32 = 2;
+
+/// how a request of the completion server ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestOutcome {
+    #[default]
+    Completed,
+
+    /// superseded while generating, after n_tokens tokens.
+    Cancelled,
+
+    /// superseded while it was debounced, it did not touch the session of its file.
+    Debounced,
+
+    /// failed with the error of the entry.
+    Failed,
+}
+
+/// a request of the completion server in a line of JSONL, the cancelled and the failed ones
+/// are logged as well, as they move the KV cache and the sampler of the session. it keeps the
+/// prompt in tokens instead of the text, so a replay does not depend on the trimming of the
+/// code around the cursor or the tokenizer. the requests of a file are replayed in their order
+/// on the same session, which reproduces its KV cache and the state of its sampler.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RequestLogEntry {
+    pub version: u32,
+    /// the id of the JSON-RPC request
+    pub id: Value,
+    pub model: String,
+    /// the file of the request, each file has its own session
+    pub path: String,
+    /// the session of the file was created on this request, either it's the first request of
+    /// the file or the last session was dropped as the least recently used one
+    pub new_session: bool,
+    pub prompt_tokens: Vec<TokenID>,
+    /// the number of the prompt tokens reused from the KV cache of the session
+    pub cached_tokens: usize,
+    pub max_tokens: usize,
+    pub temperature: f32,
+    /// the seed of the sampler of each session
+    pub seed: u64,
+    /// the coins flipped by the sampler of the session before the request, None on the logs
+    /// before it's recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sampler_coins: Option<u64>,
+    #[serde(default)]
+    pub outcome: RequestOutcome,
+    /// the error replied to the client on the failed requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// the number of the generated tokens
+    #[serde(default)]
+    pub n_tokens: usize,
+    /// the completion replied to the client, or the text generated until it's cancelled
+    pub text: String,
+}
+
+/// appends the requests to a log file, each line is flushed on writing, so the log
+/// is still readable if the server was killed.
+pub struct RequestLogWriter {
+    path: String,
+    w: BufWriter<File>,
+}
+
+impl RequestLogWriter {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| io_error(path, err))?;
+        Ok(Self {
+            path: path.to_string(),
+            w: BufWriter::new(file),
+        })
+    }
+
+    pub fn write(&mut self, entry: &RequestLogEntry) -> Result<()> {
+        let json = serde_json::to_string(entry).unwrap();
+        writeln!(self.w, "{}", json)
+            .and_then(|_| self.w.flush())
+            .map_err(|err| io_error(&self.path, err))
+    }
+}
+
+pub fn load_request_log(path: &str) -> Result<Vec<RequestLogEntry>> {
+    let file = File::open(path).map_err(|err| io_error(path, err))?;
+    read_request_log(BufReader::new(file))
+}
+
+pub fn read_request_log(r: impl BufRead) -> Result<Vec<RequestLogEntry>> {
+    let mut entries = vec![];
+    for (i, line) in r.lines().enumerate() {
+        let line = line.map_err(|err| {
+            Error::new(ErrorKind::IOError, "failed to read the request log").with_cause(err)
+        })?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: RequestLogEntry = serde_json::from_str(&line).map_err(|err| {
+            Error::new(
+                ErrorKind::FormatError,
+                format!("invalid line {} of the request log", i + 1),
+            )
+            .with_cause(err)
+        })?;
+        if entry.version > REQUEST_LOG_VERSION {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!("the request log version {} is not supported", entry.version),
+            ));
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn io_error(path: &str, err: std::io::Error) -> Error {
+    Error::new(
+        ErrorKind::IOError,
+        format!("failed to access the request log {}", path),
+    )
+    .with_cause(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_request_log_roundtrip() -> Result<()> {
+        let entry = RequestLogEntry {
+            version: REQUEST_LOG_VERSION,
+            id: json!(7),
+            model: "model.gguf".to_string(),
+            path: "src/main.rs".to_string(),
+            new_session: true,
+            prompt_tokens: vec![1, 2, 3],
+            cached_tokens: 0,
+            max_tokens: 16,
+            temperature: 0.0,
+            seed: 42,
+            sampler_coins: Some(0),
+            outcome: RequestOutcome::Completed,
+            error: None,
+            n_tokens: 5,
+            text: "fn main() {}\n".to_string(),
+        };
+        let path = std::env::temp_dir().join("crabml-test-request-log.jsonl");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        for _ in 0..2 {
+            // the log is appended across the runs of the server
+            let mut writer = RequestLogWriter::open(path)?;
+            writer.write(&entry)?;
+        }
+        assert_eq!(load_request_log(path)?, vec![entry.clone(), entry]);
+
+        let broken = r#"{"version":1,"id":1,"model":"model.gguf"}"#;
+        assert!(read_request_log(broken.as_bytes()).is_err());
+
+        // the logs of version 1 only have the completed requests
+        let v1 = r#"{"version":1,"id":1,"model":"model.gguf","path":"a.rs","new_session":true,
+            "prompt_tokens":[1],"cached_tokens":0,"max_tokens":16,"temperature":0.0,"seed":0,
+            "text":"x"}"#
+            .replace('\n', "");
+        let entries = read_request_log(v1.as_bytes())?;
+        assert_eq!(entries[0].outcome, RequestOutcome::Completed);
+        assert_eq!(entries[0].sampler_coins, None);
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use std::time::Instant;
+
+use clap::Args;
+#[cfg(feature = "wgpu")]
+use crabml::backends::wgpu::WgpuTensorDevice;
+#[cfg(feature = "wgpu")]
+use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+use crabml_llama2::placement::parse_size;
+#[cfg(feature = "wgpu")]
+use crabml_llama2::WgpuLlama2Model;
+
+// the prompts of different lengths, so the KV cache is filled and truncated to different sizes
+const PROMPTS: [&str; 4] = [
+    "Once upon a time",
+    "Lily and her brother went to the park to play with the big red ball, and",
+    "The little dog was sad because",
+    "Tom found a box in the garden. He opened it and saw a shiny key. He wondered",
+];
+
+#[derive(Args, Debug)]
+pub struct SoakArgs {
+    /// The checkpoint file to run
+    #[arg(short, long)]
+    model: String,
+
+    /// The number of the generations to run
+    #[arg(long, default_value_t = 2000)]
+    sessions: usize,
+
+    /// The number of the tokens to generate in each session
+    #[arg(long, default_value_t = 32)]
+    steps: usize,
+
+    /// The number of the long-lived runners which take turns on the sessions, a runner is
+    /// reset after each session
+    #[arg(long, default_value_t = 4)]
+    runners: usize,
+
+    /// Run every n-th session on a new runner which is dropped after it, 0 to disable
+    #[arg(long, default_value_t = 10)]
+    fresh_runner_every: usize,
+
+    /// Sample the memory after every n sessions
+    #[arg(long, default_value_t = 100)]
+    sample_every: usize,
+
+    /// The sessions to run before the first sample, the allocators and the caches fill up in
+    /// them
+    #[arg(long, default_value_t = 200)]
+    warmup: usize,
+
+    /// The growth allowed over the samples, like 16M. the memory which grows on every sample
+    /// beyond it is reported as a leak
+    #[arg(long, default_value = "16M")]
+    max_growth: String,
+
+    /// Offload the forwards to wgpu, and sample the GPU memory as well
+    #[arg(long, default_value_t = false)]
+    wgpu: bool,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+}
+
+/// the memory sampled after the warmup, the samples which are not available on the platform
+/// are left out.
+#[derive(Default)]
+struct MemorySamples {
+    rss: Vec<usize>,
+    gpu: Vec<usize>,
+
+    /// the share of the slots of the KV caches of the long-lived runners which are not taken
+    /// by their last sessions, see `kv_fragmentation`.
+    kv_fragmentation: Vec<f64>,
+}
+
+/// loops the generations over the long-lived and the fresh runners, and fails if the RSS or
+/// the GPU memory keeps growing, or a runner keeps the tokens of a session after reset.
+pub fn run_soak(args: &SoakArgs) -> Result<()> {
+    let max_growth = parse_size(&args.max_growth).ok_or_else(|| {
+        Error::new(
+            ErrorKind::BadInput,
+            format!(
+                "invalid --max-growth {}, expect a size like 16M",
+                args.max_growth
+            ),
+        )
+    })?;
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let model_cpu = CpuLlama2ModelLoader::new()
+        .with_thread_num(args.threads)
+        .load(&gf)?;
+    let seq_len = model_cpu.conf.seq_len;
+
+    #[cfg(feature = "wgpu")]
+    if args.wgpu {
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        )?;
+        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu.clone())?;
+        let new_runner = || -> Result<_> {
+            let runner_wgpu = Llama2Runner::new(&model_wgpu, seq_len, false)?;
+            Ok(Llama2Runner::new(&model_cpu, seq_len, true)?
+                .with_forward_offload(Box::new(runner_wgpu)))
+        };
+        return soak(args, max_growth, new_runner, || {
+            device_wgpu.allocated_bytes()
+        });
+    }
+    #[cfg(not(feature = "wgpu"))]
+    if args.wgpu {
+        return Err(Error::new(
+            ErrorKind::NotImplemented,
+            "--wgpu needs the wgpu feature of crabml-cli",
+        ));
+    }
+
+    let new_runner = || Llama2Runner::new(&model_cpu, seq_len, true);
+    soak(args, max_growth, new_runner, || None)
+}
+
+fn soak<T: Tensor>(
+    args: &SoakArgs,
+    max_growth: usize,
+    new_runner: impl Fn() -> Result<Llama2Runner<T>>,
+    gpu_bytes: impl Fn() -> Option<usize>,
+) -> Result<()> {
+    let mut runners = (0..args.runners.max(1))
+        .map(|_| new_runner())
+        .collect::<Result<Vec<_>>>()?;
+    let mut samples = MemorySamples::default();
+    // the tokens in the KV cache of each long-lived runner at the end of its last session
+    let mut kv_used = vec![0; runners.len()];
+    let mut n_tokens = 0;
+    let started_at = Instant::now();
+
+    for session in 1..=args.sessions {
+        let prompt = PROMPTS[session % PROMPTS.len()];
+        if args.fresh_runner_every > 0 && session % args.fresh_runner_every == 0 {
+            let mut runner = new_runner()?;
+            n_tokens += run_session(&mut runner, prompt, args.steps)?;
+        } else {
+            let n_runners = runners.len();
+            let runner = &mut runners[session % n_runners];
+            n_tokens += run_session(runner, prompt, args.steps)?;
+            kv_used[session % n_runners] = runner.kv_cache_len();
+            runner.reset()?;
+            if runner.kv_cache_len() > 0 {
+                return Err(Error::new(
+                    ErrorKind::Unexpected,
+                    format!(
+                        "soak: the runner keeps {} tokens after reset on the session {}",
+                        runner.kv_cache_len(),
+                        session
+                    ),
+                ));
+            }
+        }
+
+        if session <= args.warmup || session % args.sample_every.max(1) != 0 {
+            continue;
+        }
+        let rss = resident_bytes();
+        let gpu = gpu_bytes();
+        let capacities = runners
+            .iter()
+            .map(|r| r.context_limit())
+            .collect::<Vec<_>>();
+        let fragmentation = kv_fragmentation(&kv_used, &capacities);
+        samples.rss.extend(rss);
+        samples.gpu.extend(gpu);
+        samples.kv_fragmentation.push(fragmentation);
+        let mib = |bytes: Option<usize>| match bytes {
+            Some(bytes) => format!("{} MiB", bytes >> 20),
+            None => "-".to_string(),
+        };
+        println!(
+            "session {}: rss {}, gpu {}, kv fragmentation {:.1}%, {:.2} tokens/s",
+            session,
+            mib(rss),
+            mib(gpu),
+            fragmentation * 100.0,
+            n_tokens as f64 / started_at.elapsed().as_secs_f64()
+        );
+    }
+
+    check_growth("RSS", &samples.rss, max_growth)?;
+    check_growth("GPU memory", &samples.gpu, max_growth)?;
+    let n_samples = samples.kv_fragmentation.len().max(1) as f64;
+    println!(
+        "soak passed: {} sessions, {} tokens, {}s, kv fragmentation {:.1}% on average",
+        args.sessions,
+        n_tokens,
+        started_at.elapsed().as_secs(),
+        samples.kv_fragmentation.iter().sum::<f64>() / n_samples * 100.0
+    );
+    Ok(())
+}
+
+// prefill the prompt and generate the steps after it, returns the number of the generated tokens
+fn run_session<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    prompt: &str,
+    steps: usize,
+) -> Result<usize> {
+    let (pos, _, token) = runner.prefill(prompt, true, true)?;
+    let mut stream = runner.generate_stream(pos, token, Some(steps));
+    let mut n_tokens = 0;
+    while let Some(event) = stream.next_token() {
+        event?;
+        n_tokens += 1;
+    }
+    Ok(n_tokens)
+}
+
+fn check_growth(name: &str, samples: &[usize], max_growth: usize) -> Result<()> {
+    if !is_monotonic_growth(samples, max_growth) {
+        return Ok(());
+    }
+    Err(Error::new(
+        ErrorKind::Unexpected,
+        format!(
+            "soak: the {} grew on every sample from {} MiB to {} MiB, it may leak",
+            name,
+            samples[0] >> 20,
+            samples[samples.len() - 1] >> 20
+        ),
+    ))
+}
+
+// the allocators keep the freed memory for reuse, so the memory of a healthy process plateaus
+// after the warmup. it leaks if the samples never go down and grow beyond max_growth in total.
+fn is_monotonic_growth(samples: &[usize], max_growth: usize) -> bool {
+    samples.len() >= 3
+        && samples.windows(2).all(|w| w[1] >= w[0])
+        && samples[samples.len() - 1] - samples[0] > max_growth
+}
+
+// each runner allocates its KV cache for the whole context, and the slots a session leaves
+// empty can not be taken by the sessions on the other runners. the fragmentation of the pool of
+// the caches is the share of the empty slots, 0 if the sessions fill up the caches.
+fn kv_fragmentation(used: &[usize], capacities: &[usize]) -> f64 {
+    let capacity = capacities.iter().sum::<usize>();
+    if capacity == 0 {
+        return 0.0;
+    }
+    1.0 - used.iter().sum::<usize>() as f64 / capacity as f64
+}
+
+/// the resident memory of the process in bytes, only detected on linux.
+fn resident_bytes() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_monotonic_growth() {
+        let mib = |v: usize| v << 20;
+        assert!(is_monotonic_growth(
+            &[mib(100), mib(110), mib(120)],
+            mib(16)
+        ));
+        assert!(!is_monotonic_growth(
+            &[mib(100), mib(105), mib(110)],
+            mib(16)
+        ));
+
+        // a plateau with noise, and too few samples to tell
+        assert!(!is_monotonic_growth(
+            &[mib(100), mib(130), mib(120), mib(140)],
+            mib(16)
+        ));
+        assert!(!is_monotonic_growth(&[mib(100), mib(200)], mib(16)));
+        assert!(!is_monotonic_growth(&[], mib(16)));
+    }
+
+    #[test]
+    fn test_kv_fragmentation() {
+        assert_eq!(kv_fragmentation(&[256, 256], &[256, 256]), 0.0);
+        assert_eq!(kv_fragmentation(&[64, 0], &[256, 256]), 0.875);
+        assert_eq!(kv_fragmentation(&[0, 0], &[256, 256]), 1.0);
+        assert_eq!(kv_fragmentation(&[], &[]), 0.0);
+    }
+}
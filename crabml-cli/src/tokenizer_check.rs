@@ -0,0 +1,281 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::Args;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::TokenID;
+use crabml::tokenizer::Tokenizer;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+
+// the samples on the corners which the tokenizers usually disagree on: the leading and the
+// repeated spaces, the digits, the non-latin scripts, the emojis split into the byte tokens,
+// and the code
+const BUILTIN_CORPUS: &[&str] = &[
+    "Hello world",
+    " Hello world",
+    "Hello  world,   the   spaces\tand\ttabs",
+    "Once upon a time, there was a little girl named Lily.",
+    "line one\nline two\n\nline four\n",
+    "3.14159 is close to pi, 1234567890 and 007",
+    "I'm sure you've heard it: \"don't\", 'quoted' and isn't.",
+    "café, naïve, Ünïcödé, Ελληνικά, русский язык",
+    "日本語のテキストと中文文本",
+    "emoji 🦀🦀 and 👨‍👩‍👧 family",
+    "fn main() {\n    println!(\"{}\", 1 + 2);\n}",
+    "<html><body class=\"x\">&amp;</body></html>",
+    "    indented with four spaces",
+    "trailing spaces   ",
+    "",
+];
+
+#[derive(Args, Debug)]
+pub struct TokenizerCheckArgs {
+    /// The GGUF model whose tokenizer is checked
+    #[arg(short, long)]
+    model: String,
+
+    /// The tokenizer.json of the model on Hugging Face, defaults to the <model>.tokenizer.json
+    /// or the tokenizer.json beside the model
+    #[arg(long)]
+    hf_tokenizer: Option<String>,
+
+    /// A text file to check line by line, a few builtin samples are checked by default
+    #[arg(long)]
+    corpus: Option<String>,
+}
+
+/// the first sample of the corpus on which the two tokenizers disagree, the tokens are the
+/// whole encodings of the sample and index is the position of the first different token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub sample: usize,
+    pub text: String,
+    pub index: usize,
+    pub ours: Vec<TokenID>,
+    pub theirs: Vec<TokenID>,
+}
+
+/// encodes the samples of the corpus in the GGUF model and in the Hugging Face tokenizers, and
+/// reports the first divergence. the special tokens are left out on both sides, the BOS and EOS
+/// are added by the options of each side, which are not the concern of this check.
+pub fn run_tokenizer_check(args: &TokenizerCheckArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let tokenizer = CpuLlama2ModelLoader::new().load_tokenizer(&gf)?;
+
+    let hf_path = match &args.hf_tokenizer {
+        Some(path) => path.clone(),
+        None => hf_tokenizer_beside(&args.model).ok_or_else(|| {
+            Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "no tokenizer.json beside {}, pass --hf-tokenizer",
+                    args.model
+                ),
+            )
+        })?,
+    };
+    let hf = load_hf_tokenizer(&hf_path)?;
+    let corpus = match &args.corpus {
+        Some(path) => load_corpus(path)?,
+        None => BUILTIN_CORPUS.iter().map(|s| s.to_string()).collect(),
+    };
+
+    match check_corpus(&tokenizer, |text| hf_encode(&hf, text), &corpus)? {
+        None => {
+            println!("{} samples encoded the same", corpus.len());
+            Ok(())
+        }
+        Some(divergence) => {
+            print!("{}", format_divergence(&tokenizer, &divergence));
+            Err(Error::new(
+                ErrorKind::Unexpected,
+                format!(
+                    "the tokenizers diverge on the sample {}",
+                    divergence.sample + 1
+                ),
+            ))
+        }
+    }
+}
+
+/// encodes each sample on both sides until the first divergence.
+pub fn check_corpus(
+    tokenizer: &Tokenizer,
+    theirs: impl Fn(&str) -> Result<Vec<TokenID>>,
+    corpus: &[String],
+) -> Result<Option<Divergence>> {
+    for (i, text) in corpus.iter().enumerate() {
+        let ours = tokenizer.encode(text, false, false)?;
+        let theirs = theirs(text)?;
+        if let Some(index) = first_difference(&ours, &theirs) {
+            return Ok(Some(Divergence {
+                sample: i,
+                text: text.clone(),
+                index,
+                ours,
+                theirs,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// the position of the first different token, a longer encoding differs at the end of the
+/// shorter one.
+fn first_difference(a: &[TokenID], b: &[TokenID]) -> Option<usize> {
+    match a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+        Some(i) => Some(i),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+// prints the tokens around the divergence with their pieces, the identical prefix before it is
+// elided to a few tokens. the ids of the other tokenizer may be out of our vocab.
+fn format_divergence(tokenizer: &Tokenizer, d: &Divergence) -> String {
+    let context = d.index.saturating_sub(3);
+    let tokens = |ids: &[TokenID]| {
+        ids.iter()
+            .skip(context)
+            .take(8)
+            .map(|&id| match tokenizer.token_to_piece(id) {
+                Some(piece) => format!("{}:{:?}", id, piece),
+                None => format!("<id {} out of vocab>", id),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    format!(
+        "sample {}: {:?}\ndiverges at the token {} of {} vs {}\n  ours:   {}\n  theirs: {}\n",
+        d.sample + 1,
+        d.text,
+        d.index,
+        d.ours.len(),
+        d.theirs.len(),
+        tokens(&d.ours),
+        tokens(&d.theirs),
+    )
+}
+
+// the tokenizer.json named after the model is preferred, a directory may hold several models
+fn hf_tokenizer_beside(model: &str) -> Option<String> {
+    let model = Path::new(model);
+    let named = model.with_extension("tokenizer.json");
+    let shared = model.parent()?.join("tokenizer.json");
+    [named, shared]
+        .into_iter()
+        .find(|path| path.exists())
+        .map(|path| path.to_string_lossy().to_string())
+}
+
+fn load_hf_tokenizer(path: &str) -> Result<tokenizers::Tokenizer> {
+    tokenizers::Tokenizer::from_file(path).map_err(|err| {
+        let message = format!("failed to load the Hugging Face tokenizer {}", path);
+        hf_error(ErrorKind::FormatError, message, err)
+    })
+}
+
+fn hf_encode(hf: &tokenizers::Tokenizer, text: &str) -> Result<Vec<TokenID>> {
+    let encoding = hf.encode(text, false).map_err(|err| {
+        let message = format!("failed to encode {:?} in the Hugging Face tokenizer", text);
+        hf_error(ErrorKind::Unexpected, message, err)
+    })?;
+    Ok(encoding.get_ids().iter().map(|&id| id as TokenID).collect())
+}
+
+// the errors of the tokenizers crate are boxed, which can not be taken by `with_cause`
+fn hf_error(kind: ErrorKind, message: String, err: tokenizers::Error) -> Error {
+    let mut error = Error::new(kind, message);
+    error.cause = Some(Arc::from(err));
+    error
+}
+
+fn load_corpus(path: &str) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+        Error::new(
+            ErrorKind::IOError,
+            format!("failed to read the corpus {}", path),
+        )
+        .with_cause(err)
+    })?;
+    Ok(text.lines().map(|line| line.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_difference() {
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 2, 3]), None);
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 4, 3]), Some(1));
+        assert_eq!(first_difference(&[1, 2], &[1, 2, 3]), Some(2));
+        assert_eq!(first_difference(&[], &[]), None);
+    }
+
+    #[test]
+    fn test_check_corpus() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let tokenizer = CpuLlama2ModelLoader::new().load_tokenizer(&gf)?;
+        let corpus = BUILTIN_CORPUS
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+
+        let same = |text: &str| tokenizer.encode(text, false, false);
+        assert_eq!(check_corpus(&tokenizer, same, &corpus)?, None);
+
+        // a tokenizer which drops the last token of the fourth sample
+        let dropped = |text: &str| -> Result<Vec<TokenID>> {
+            let mut tokens = tokenizer.encode(text, false, false)?;
+            if text == corpus[3] {
+                tokens.pop();
+            }
+            Ok(tokens)
+        };
+        let d = check_corpus(&tokenizer, dropped, &corpus)?.unwrap();
+        assert_eq!(d.sample, 3);
+        assert_eq!(d.index, d.ours.len() - 1);
+        assert!(format_divergence(&tokenizer, &d).contains("diverges at the token"));
+
+        // a tokenizer with a larger vocab
+        let larger = |text: &str| -> Result<Vec<TokenID>> {
+            let mut tokens = tokenizer.encode(text, false, false)?;
+            tokens.push(tokenizer.vocab_size() + 7);
+            Ok(tokens)
+        };
+        let d = check_corpus(&tokenizer, larger, &corpus)?.unwrap();
+        let out_of_vocab = format!("<id {} out of vocab>", tokenizer.vocab_size() + 7);
+        assert!(format_divergence(&tokenizer, &d).contains(&out_of_vocab));
+        Ok(())
+    }
+
+    // checks the tokenizer of the model against its tokenizer.json of Hugging Face if it's
+    // put beside the model, like `testdata/tinyllamas-stories-15m-f32.tokenizer.json`, or
+    // it's skipped.
+    #[test]
+    fn test_testdata_against_hf() -> Result<()> {
+        let model = "../testdata/tinyllamas-stories-15m-f32.gguf";
+        let hf_path = match hf_tokenizer_beside(model) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let gl = GGUFFileLoader::new(model, false)?;
+        let gf = gl.open()?;
+        let tokenizer = CpuLlama2ModelLoader::new().load_tokenizer(&gf)?;
+        let hf = load_hf_tokenizer(&hf_path)?;
+        let corpus = BUILTIN_CORPUS
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        if let Some(d) = check_corpus(&tokenizer, |text| hf_encode(&hf, text), &corpus)? {
+            panic!("{}", format_divergence(&tokenizer, &d));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::sampler::Llama2SamplerRef;
+use crabml_llama2::sampler::SamplerState;
+use crabml_llama2::Llama2Chat;
+use crabml_llama2::Llama2Sampler;
+use serde::Deserialize;
+use serde::Serialize;
+
+const TRANSCRIPT_VERSION: u32 = 1;
+
+/// a line of a chat transcript in JSONL. the first line is the header with the settings of the
+/// session, and each message follows in its own line, so a transcript is still readable if the
+/// chat was killed in the middle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TranscriptLine {
+    Header {
+        version: u32,
+        model: String,
+        temperature: f32,
+        /// the top-k of the sampler, 0 on the transcripts before it's recorded
+        #[serde(default)]
+        top_k: usize,
+        top_p: f32,
+        /// the seed of the sampler, the same seed samples the same replies on the same model
+        seed: u64,
+        system_prompt: Option<String>,
+    },
+    Message {
+        role: Role,
+        content: String,
+        /// the number of the generated tokens, only on the replies
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        n_tokens: Option<usize>,
+        /// the number of the tokens in the context after this message
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        n_context_tokens: Option<usize>,
+        /// the coins flipped by the sampler after this message, only on the replies, the
+        /// sampler is restored to it on continuing the session
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sampler_coins: Option<u64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+/// the settings of a session in the header of a transcript.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptHeader {
+    pub model: String,
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub seed: u64,
+    pub system_prompt: Option<String>,
+}
+
+impl TranscriptHeader {
+    /// the sampler of the session after it flipped n_coins coins.
+    pub fn sampler(&self, vocab_size: usize, n_coins: u64) -> Llama2SamplerRef {
+        Llama2Sampler::from_state(vocab_size, &SamplerState {
+            temperature: self.temperature,
+            topk: self.top_k,
+            topp: self.top_p,
+            seed: Some(self.seed),
+            n_coins,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    pub header: TranscriptHeader,
+    // (user message, reply)
+    pub turns: Vec<(String, String)>,
+    /// the coins flipped by the sampler after the last reply, None on the transcripts before
+    /// it's recorded
+    pub sampler_coins: Option<u64>,
+}
+
+impl Transcript {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(|err| io_error(path, err))?;
+        Self::read(BufReader::new(file))
+    }
+
+    pub fn read(r: impl BufRead) -> Result<Self> {
+        let mut header = None;
+        let mut turns = vec![];
+        let mut sampler_coins = None;
+        let mut pending_user: Option<String> = None;
+        for (i, line) in r.lines().enumerate() {
+            let line = line.map_err(|err| {
+                Error::new(ErrorKind::IOError, "failed to read the transcript").with_cause(err)
+            })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line: TranscriptLine = serde_json::from_str(&line).map_err(|err| {
+                Error::new(
+                    ErrorKind::FormatError,
+                    format!("invalid line {} of the transcript", i + 1),
+                )
+                .with_cause(err)
+            })?;
+            match (line, &header) {
+                (
+                    TranscriptLine::Header {
+                        version,
+                        model,
+                        temperature,
+                        top_k,
+                        top_p,
+                        seed,
+                        system_prompt,
+                    },
+                    None,
+                ) => {
+                    if version > TRANSCRIPT_VERSION {
+                        return Err(Error::new(
+                            ErrorKind::NotImplemented,
+                            format!("the transcript version {} is not supported", version),
+                        ));
+                    }
+                    header = Some(TranscriptHeader {
+                        model,
+                        temperature,
+                        top_k,
+                        top_p,
+                        seed,
+                        system_prompt,
+                    });
+                }
+                (
+                    TranscriptLine::Message {
+                        role,
+                        content,
+                        sampler_coins: coins,
+                        ..
+                    },
+                    Some(_),
+                ) => match (role, pending_user.take()) {
+                    (Role::User, None) => pending_user = Some(content),
+                    (Role::Assistant, Some(user)) => {
+                        turns.push((user, content));
+                        sampler_coins = coins;
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::FormatError,
+                            format!(
+                                "line {} of the transcript: the user and the assistant \
+                                 should take turns",
+                                i + 1
+                            ),
+                        ));
+                    }
+                },
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::FormatError,
+                        format!(
+                            "line {} of the transcript: the header should be the first line",
+                            i + 1
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let header = header
+            .ok_or_else(|| Error::new(ErrorKind::FormatError, "the transcript has no header"))?;
+        // a user message without a reply is dropped, the chat was killed while replying
+        Ok(Self {
+            header,
+            turns,
+            sampler_coins,
+        })
+    }
+}
+
+/// put the turns of the transcript into the KV cache of the runner without sampling the replies
+/// again, on_turn is called after each turn. the prefills flip the coins of the sampler, so the
+/// sampler of the session is set after them, to go on after the coins of the last reply.
+pub fn restore_turns<T: Tensor>(
+    runner: &mut Llama2Runner<T>,
+    transcript: &Transcript,
+    mut on_turn: impl FnMut(&Llama2Runner<T>, &str, &str) -> Result<()>,
+) -> Result<()> {
+    let mut system_prompt = transcript.header.system_prompt.clone();
+    for (line, reply) in transcript.turns.iter() {
+        Llama2Chat::new(runner, line, system_prompt.take())?.restore(reply)?;
+        on_turn(runner, line, reply)?;
+    }
+    let vocab_size = runner.conf().vocab_size;
+    let n_coins = transcript.sampler_coins.unwrap_or(0);
+    runner.set_sampler(transcript.header.sampler(vocab_size, n_coins));
+    Ok(())
+}
+
+/// appends the lines of a transcript to a file, each line is flushed on writing.
+pub struct TranscriptWriter {
+    path: String,
+    w: BufWriter<File>,
+}
+
+impl TranscriptWriter {
+    pub fn create(path: &str, header: &TranscriptHeader) -> Result<Self> {
+        let file = File::create(path).map_err(|err| io_error(path, err))?;
+        let mut writer = Self {
+            path: path.to_string(),
+            w: BufWriter::new(file),
+        };
+        writer.write_line(&TranscriptLine::Header {
+            version: TRANSCRIPT_VERSION,
+            model: header.model.clone(),
+            temperature: header.temperature,
+            top_k: header.top_k,
+            top_p: header.top_p,
+            seed: header.seed,
+            system_prompt: header.system_prompt.clone(),
+        })?;
+        Ok(writer)
+    }
+
+    /// write a turn, n_tokens is the number of the generated tokens of the reply, and
+    /// sampler_coins is the coins flipped by the sampler after it.
+    pub fn write_turn(
+        &mut self,
+        user: &str,
+        reply: &str,
+        n_tokens: usize,
+        n_context_tokens: usize,
+        sampler_coins: u64,
+    ) -> Result<()> {
+        self.write_line(&TranscriptLine::Message {
+            role: Role::User,
+            content: user.to_string(),
+            n_tokens: None,
+            n_context_tokens: None,
+            sampler_coins: None,
+        })?;
+        self.write_line(&TranscriptLine::Message {
+            role: Role::Assistant,
+            content: reply.to_string(),
+            n_tokens: Some(n_tokens),
+            n_context_tokens: Some(n_context_tokens),
+            sampler_coins: Some(sampler_coins),
+        })
+    }
+
+    fn write_line(&mut self, line: &TranscriptLine) -> Result<()> {
+        let json = serde_json::to_string(line).unwrap();
+        writeln!(self.w, "{}", json)
+            .and_then(|_| self.w.flush())
+            .map_err(|err| io_error(&self.path, err))
+    }
+}
+
+fn io_error(path: &str, err: std::io::Error) -> Error {
+    Error::new(
+        ErrorKind::IOError,
+        format!("failed to access the transcript {}", path),
+    )
+    .with_cause(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensor;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml_llama2::model::CpuLlama2ModelLoader;
+    use crabml_llama2::stopping::MaxTokens;
+    use crabml_llama2::stopping::StoppingCriteriaList;
+
+    use super::*;
+
+    #[test]
+    fn test_transcript_roundtrip() -> Result<()> {
+        let header = TranscriptHeader {
+            model: "model.gguf".to_string(),
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.9,
+            seed: 42,
+            system_prompt: Some("be brief".to_string()),
+        };
+        let path = std::env::temp_dir().join("crabml-test-transcript.jsonl");
+        let path = path.to_str().unwrap();
+        let mut writer = TranscriptWriter::create(path, &header)?;
+        writer.write_turn("hi", "hello!", 3, 20, 3)?;
+        writer.write_turn("bye", "see you\n", 4, 40, 7)?;
+        drop(writer);
+
+        let content = std::fs::read_to_string(path).unwrap();
+        assert_eq!(content.lines().count(), 5);
+        assert!(
+            content
+                .lines()
+                .next()
+                .unwrap()
+                .contains(r#""type":"header""#)
+        );
+
+        let transcript = Transcript::load(path)?;
+        assert_eq!(transcript.header, header);
+        let turns = vec![
+            ("hi".to_string(), "hello!".to_string()),
+            ("bye".to_string(), "see you\n".to_string()),
+        ];
+        assert_eq!(transcript.turns, turns);
+        assert_eq!(transcript.sampler_coins, Some(7));
+
+        let broken = r#"{"type":"message","role":"user","content":"hi"}"#;
+        assert!(Transcript::read(broken.as_bytes()).is_err());
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_turns() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let header = TranscriptHeader {
+            model: "tinyllamas".to_string(),
+            temperature: 0.8,
+            top_k: 0,
+            top_p: 0.9,
+            seed: 42,
+            system_prompt: None,
+        };
+        let new_runner = || -> Result<Llama2Runner<CpuTensor>> {
+            let mut runner = Llama2Runner::new(&lm, 200, false)?;
+            runner.set_stopping_criteria(StoppingCriteriaList::new().with(MaxTokens(8)));
+            Ok(runner)
+        };
+        let reply = |runner: &mut Llama2Runner<CpuTensor>, line: &str| -> Result<String> {
+            let mut chat = Llama2Chat::new(runner, line, None)?;
+            let reply = chat.reply()?.collect::<Result<String>>()?;
+            chat.finish()?;
+            Ok(reply)
+        };
+
+        // the session writes a turn and the coins flipped on it
+        let mut runner = new_runner()?;
+        runner.set_sampler(header.sampler(runner.conf().vocab_size, 0));
+        let first = reply(&mut runner, "hi")?;
+        let n_coins = runner.sampler().state().n_coins;
+        assert!(n_coins > 0);
+
+        // the restored session goes on with the coins of the session, the restore prefills
+        // do not take the coins of the next reply
+        let transcript = Transcript {
+            header: header.clone(),
+            turns: vec![("hi".to_string(), first)],
+            sampler_coins: Some(n_coins),
+        };
+        let mut restored = new_runner()?;
+        let mut n_turns = 0;
+        restore_turns(&mut restored, &transcript, |_, _, _| {
+            n_turns += 1;
+            Ok(())
+        })?;
+        assert_eq!(n_turns, 1);
+        assert_eq!(restored.sampler().state(), runner.sampler().state());
+        Ok(())
+    }
+}
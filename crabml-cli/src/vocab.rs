@@ -0,0 +1,57 @@
+use clap::Args;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+
+#[derive(Args, Debug)]
+pub struct VocabArgs {
+    /// The model to read the vocab from
+    #[arg(short, long)]
+    model: String,
+
+    /// Only print the tokens whose pieces contain the text
+    #[arg(short, long)]
+    filter: Option<String>,
+
+    /// Print the tokens as JSON lines instead of tab separated values
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+/// dumps the vocab with the type and the bytes of every token, it's handy on debugging the
+/// tokenization mismatches or writing the external samplers.
+pub fn run_vocab(args: &VocabArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let tokenizer = CpuLlama2ModelLoader::new().load_tokenizer(&gf)?;
+
+    eprintln!(
+        "vocab size: {}, bos: {}, eos: {}, pad: {:?}, unk: {:?}",
+        tokenizer.vocab_size(),
+        tokenizer.bos_token(),
+        tokenizer.eos_token(),
+        tokenizer.pad_token(),
+        tokenizer.unk_token()
+    );
+    for id in 0..tokenizer.vocab_size() {
+        let piece = tokenizer.token_to_piece(id).unwrap_or_default();
+        if let Some(filter) = &args.filter {
+            if !piece.contains(filter.as_str()) {
+                continue;
+            }
+        }
+        let token_type = tokenizer.token_type(id);
+        if args.json {
+            let line = serde_json::json!({
+                "id": id,
+                "type": format!("{:?}", token_type),
+                "piece": piece,
+                "bytes": tokenizer.token_bytes(id),
+            });
+            println!("{}", line);
+        } else {
+            println!("{}\t{:?}\t{:?}", id, token_type, piece);
+        }
+    }
+    Ok(())
+}
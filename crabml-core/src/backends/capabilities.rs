@@ -0,0 +1,108 @@
+use std::fmt::Display;
+
+use crate::gguf::GGMLType;
+
+/// what a backend can load and run, so the models can be placed on the devices without trying.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendCapabilities {
+    /// the types of the tensors which can be loaded
+    pub dtypes: Vec<GGMLType>,
+
+    /// the quantized types which are multiplied by the kernels directly, the weights of the
+    /// other quantized types have to be dequantized before placing on this backend.
+    pub quant_kernels: Vec<GGMLType>,
+
+    /// the largest buffer in bytes which can be allocated for a tensor, None if unlimited.
+    pub max_buffer_bytes: Option<usize>,
+
+    /// whether the shaders can compute in f16.
+    pub shader_f16: bool,
+
+    /// the free memory of the device in bytes, None if it can not be detected, like the VRAM
+    /// of the GPUs whose drivers do not report it.
+    pub available_memory: Option<usize>,
+}
+
+impl BackendCapabilities {
+    pub fn supports_dtype(&self, typ: GGMLType) -> bool {
+        self.dtypes.contains(&typ)
+    }
+
+    pub fn has_quant_kernel(&self, typ: GGMLType) -> bool {
+        self.quant_kernels.contains(&typ)
+    }
+
+    /// whether a tensor of the bytes can be allocated in a single buffer.
+    pub fn fits_buffer(&self, bytes: usize) -> bool {
+        self.max_buffer_bytes.map_or(true, |max| bytes <= max)
+    }
+}
+
+impl Display for BackendCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |types: &[GGMLType]| {
+            types
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mib = |bytes: Option<usize>, none: &str| match bytes {
+            Some(bytes) => format!("{} MiB", bytes >> 20),
+            None => none.to_string(),
+        };
+        write!(
+            f,
+            "dtypes: {}, quant kernels: {}, max buffer: {}, shader f16: {}, available memory: {}",
+            join(&self.dtypes),
+            join(&self.quant_kernels),
+            mib(self.max_buffer_bytes, "unlimited"),
+            self.shader_f16,
+            mib(self.available_memory, "unknown")
+        )
+    }
+}
+
+/// the devices which the tensors are placed on.
+pub trait Backend {
+    fn capabilities(&self) -> BackendCapabilities;
+}
+
+/// the available RAM in bytes, only detected on linux.
+pub fn available_ram() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::CpuTensorDevice;
+
+    #[test]
+    fn test_cpu_capabilities() {
+        let caps = CpuTensorDevice::new().capabilities();
+        assert!(caps.supports_dtype(GGMLType::F16));
+        assert!(caps.has_quant_kernel(GGMLType::Q8_0));
+        assert!(!caps.has_quant_kernel(GGMLType::F32));
+        assert!(!caps.supports_dtype(GGMLType::I8));
+        assert!(caps.fits_buffer(usize::MAX));
+        assert!(!caps.shader_f16);
+
+        let caps = BackendCapabilities {
+            dtypes: vec![GGMLType::F32],
+            quant_kernels: vec![],
+            max_buffer_bytes: Some(128 << 20),
+            shader_f16: true,
+            available_memory: None,
+        };
+        assert!(!caps.fits_buffer((128 << 20) + 1));
+        assert_eq!(
+            caps.to_string(),
+            "dtypes: F32, quant kernels: , max buffer: 128 MiB, shader f16: true, available \
+             memory: unknown"
+        );
+    }
+}
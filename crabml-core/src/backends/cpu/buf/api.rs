@@ -1,13 +1,35 @@
 use std::borrow::Cow;
+use std::mem::size_of;
 
+use half::bf16;
 use half::f16;
 
+use super::buf_bf16::bf16_buf_from_bytes;
+use super::buf_bf16::dequantize_bf16_buf;
+use super::buf_bf16::quantize_f32_bf16;
+use super::buf_bf16::vec_dot_bf16_f32;
 use super::buf_f16::dequantize_f16_buf;
 use super::buf_f16::f16_buf_from_bytes;
 use super::buf_f16::quantize_f32_f16;
 use super::buf_f32::f32_buf_from_bytes;
 use super::buf_f32::vec_dot_f32_f32;
+use super::buf_q2_k::BlockQ2K;
+use super::buf_q3_k::BlockQ3K;
+use super::buf_q4_0::BlockQ4_0;
+use super::buf_q4_1::BlockQ4_1;
+use super::buf_q4_k::BlockQ4K;
+use super::buf_q5_0::BlockQ5_0;
+use super::buf_q5_1::BlockQ5_1;
+use super::buf_q5_k::BlockQ5K;
+use super::buf_q6_k::BlockQ6K;
+use super::buf_q8_0::BlockQ8_0;
+use super::buf_q8_1::BlockQ8_1;
+use super::buf_q8_k::BlockQ8K;
+use super::bytes::as_bytes;
+use super::util::QK_K;
 use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f16;
+use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f32;
+use crate::backends::cpu::buf::buf_q8_0::PackedBufQ8_0;
 use crate::backends::cpu::buf::QuantBufQ2K;
 use crate::backends::cpu::buf::QuantBufQ3K;
 use crate::backends::cpu::buf::QuantBufQ4K;
@@ -20,9 +42,12 @@ use crate::backends::cpu::buf::QuantBufQ6K;
 use crate::backends::cpu::buf::QuantBufQ8K;
 use crate::backends::cpu::buf::QuantBufQ8_0;
 use crate::backends::cpu::buf::QuantBufQ8_1;
+use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
+use crate::gguf::SUPPORTED_GGML_TYPES;
+use crate::w8a8::Int8Matrix;
 
 /// All the quantized tensor are read-only.
 #[derive(Debug)]
@@ -30,9 +55,12 @@ use crate::gguf::GGMLType;
 pub enum CpuTensorBuf<'a> {
     F32(Cow<'a, [f32]>),
     F16(Cow<'a, [f16]>),
+    BF16(Cow<'a, [bf16]>),
     Q2K(QuantBufQ2K<'a>),
     Q3K(QuantBufQ3K<'a>),
     Q8_0(QuantBufQ8_0<'a>),
+    Q8_0Packed(PackedBufQ8_0),
+    W8A8(Int8Matrix),
     Q8_1(QuantBufQ8_1<'a>),
     Q8K(QuantBufQ8K<'a>),
     Q4_0(QuantBufQ4_0<'a>),
@@ -45,10 +73,15 @@ pub enum CpuTensorBuf<'a> {
 }
 
 impl<'a> CpuTensorBuf<'a> {
+    /// take the raw bytes of a tensor, like the data of a tensor in the mmaped GGUF file. the
+    /// bytes are borrowed if they're aligned for the elements or the blocks of the type, or
+    /// copied into an owned buffer, see `cast_bytes`.
     pub fn from_raw_bytes(buf: &'a [u8], typ: GGMLType) -> Result<Self> {
+        check_raw_bytes(buf, typ)?;
         match typ {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(f32_buf_from_bytes(buf))),
             GGMLType::F16 => Ok(CpuTensorBuf::F16(f16_buf_from_bytes(buf))),
+            GGMLType::BF16 => Ok(CpuTensorBuf::BF16(bf16_buf_from_bytes(buf))),
             GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::from_bytes(buf))),
             GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::from_bytes(buf))),
             GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::from_bytes(buf))),
@@ -61,8 +94,89 @@ impl<'a> CpuTensorBuf<'a> {
             GGMLType::Q5_1 => Ok(CpuTensorBuf::Q5_1(QuantBufQ5_1::from_bytes(buf))),
             GGMLType::Q5K => Ok(CpuTensorBuf::Q5K(QuantBufQ5K::from_bytes(buf))),
             GGMLType::Q6K => Ok(CpuTensorBuf::Q6K(QuantBufQ6K::from_bytes(buf))),
-            _ => unimplemented!(),
+            _ => Err((
+                ErrorKind::NotImplemented,
+                format!("the tensors of {} are not supported on cpu", typ),
+            )
+                .into()),
+        }
+    }
+
+    /// the raw bytes of the elements or the blocks, in the layout of the tensor data in a GGUF
+    /// file, the reverse of `from_raw_bytes`. the packed buffers have no such layout.
+    pub fn as_raw_bytes(&self) -> Result<&[u8]> {
+        let bytes = match self {
+            CpuTensorBuf::F32(buf) => as_bytes(buf),
+            CpuTensorBuf::F16(buf) => as_bytes(buf),
+            CpuTensorBuf::BF16(buf) => as_bytes(buf),
+            CpuTensorBuf::Q2K(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q3K(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q8_0(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q8_1(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q8K(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q4_0(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q4_1(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q4K(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q5_0(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q5_1(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q5K(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q6K(buf) => as_bytes(&buf.blocks),
+            CpuTensorBuf::Q8_0Packed(_) | CpuTensorBuf::W8A8(_) => {
+                return Err((
+                    ErrorKind::NotImplemented,
+                    "as_raw_bytes: packed buffers are not supported",
+                )
+                    .into());
+            }
+        };
+        Ok(bytes)
+    }
+
+    /// the length in bytes of `n_elems` elements of the type, a format error if the elements
+    /// do not fill whole blocks of the type.
+    pub fn raw_bytes_len(typ: GGMLType, n_elems: usize) -> Result<usize> {
+        let block_bytes = raw_block_bytes(typ).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotImplemented,
+                format!("the tensors of {} are not supported on cpu", typ),
+            )
+        })?;
+        if n_elems % typ.block_size() != 0 {
+            return Err(Error::new(
+                ErrorKind::FormatError,
+                format!(
+                    "{} elements of {} do not fill the blocks of {} elements",
+                    n_elems,
+                    typ,
+                    typ.block_size()
+                ),
+            ));
         }
+        Ok(n_elems / typ.block_size() * block_bytes)
+    }
+
+    /// whether the tensors of the type can be loaded by `from_raw_bytes`.
+    pub fn is_supported_type(typ: GGMLType) -> bool {
+        SUPPORTED_GGML_TYPES.contains(&typ)
+    }
+
+    /// load the raw bytes into f16, the integer types which have no kernels are converted as
+    /// well. it takes 2 bytes per element in memory instead of mapping the file.
+    pub fn from_raw_bytes_f16(buf: &'a [u8], typ: GGMLType) -> Result<Self> {
+        check_raw_bytes(buf, typ)?;
+        let values: Vec<f32> = match typ {
+            GGMLType::I8 => buf.iter().map(|b| *b as i8 as f32).collect(),
+            GGMLType::I16 => buf
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32)
+                .collect(),
+            GGMLType::I32 => buf
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32)
+                .collect(),
+            _ => return CpuTensorBuf::from_raw_bytes(buf, typ)?.dequantize(GGMLType::F16),
+        };
+        CpuTensorBuf::from(values).quantize(GGMLType::F16)
     }
 
     pub fn is_owned(&self) -> bool {
@@ -72,6 +186,29 @@ impl<'a> CpuTensorBuf<'a> {
         )
     }
 
+    /// the rows of the packed buffers are interleaved, they can only be consumed by the kernels
+    /// which are aware of the layout, see `pack`. the W8A8 weights are only consumed by
+    /// matmul_vec too.
+    pub fn is_packed(&self) -> bool {
+        matches!(self, CpuTensorBuf::Q8_0Packed(_) | CpuTensorBuf::W8A8(_))
+    }
+
+    /// quantize a 2-D weight of (rows, cols) into int8 with a scale per row for the W8A8
+    /// matmul, see `Int8Matrix`. the quantized weights are dequantized into f32 first.
+    pub fn quantize_w8a8(self, rows: usize, cols: usize) -> Result<Self> {
+        let weights = self.dequantize(GGMLType::F32)?;
+        Int8Matrix::quantize(weights.as_f32_ref(), rows, cols).map(Self::W8A8)
+    }
+
+    /// reorder a 2-D weight of (rows, cols) into the layout consumed by the register blocked
+    /// matmul kernels. only Q8_0 is packed for now, returns None if the buffer can not be packed.
+    pub fn pack(&self, rows: usize, cols: usize) -> Option<Self> {
+        match self {
+            CpuTensorBuf::Q8_0(buf) => PackedBufQ8_0::pack(buf, rows, cols).map(Self::Q8_0Packed),
+            _ => None,
+        }
+    }
+
     pub fn is_quantized(&self) -> bool {
         matches!(self, CpuTensorBuf::F32(_))
     }
@@ -80,9 +217,12 @@ impl<'a> CpuTensorBuf<'a> {
         match self {
             CpuTensorBuf::F32(buf) => buf.len(),
             CpuTensorBuf::F16(buf) => buf.len(),
+            CpuTensorBuf::BF16(buf) => buf.len(),
             CpuTensorBuf::Q2K(buf) => buf.len(),
             CpuTensorBuf::Q3K(buf) => buf.len(),
             CpuTensorBuf::Q8_0(buf) => buf.len(),
+            CpuTensorBuf::Q8_0Packed(buf) => buf.len(),
+            CpuTensorBuf::W8A8(buf) => buf.len(),
             CpuTensorBuf::Q8_1(buf) => buf.len(),
             CpuTensorBuf::Q8K(buf) => buf.len(),
             CpuTensorBuf::Q5_0(buf) => buf.len(),
@@ -100,13 +240,49 @@ impl<'a> CpuTensorBuf<'a> {
         self.len() == 0
     }
 
+    /// borrow the elements in [offset, offset + len) without copying, only the f32/f16/bf16
+    /// buffers can be sliced at any element, the quantized blocks can not.
+    pub fn slice(&self, offset: usize, len: usize) -> Result<CpuTensorBuf<'_>> {
+        if offset + len > self.len() {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "slice: [{}, {}) is out of the buffer of {} elements",
+                    offset,
+                    offset + len,
+                    self.len()
+                ),
+            )
+                .into());
+        }
+        match self {
+            CpuTensorBuf::F32(buf) => {
+                Ok(CpuTensorBuf::F32(Cow::Borrowed(&buf[offset..offset + len])))
+            }
+            CpuTensorBuf::F16(buf) => {
+                Ok(CpuTensorBuf::F16(Cow::Borrowed(&buf[offset..offset + len])))
+            }
+            CpuTensorBuf::BF16(buf) => Ok(CpuTensorBuf::BF16(Cow::Borrowed(
+                &buf[offset..offset + len],
+            ))),
+            _ => Err((
+                ErrorKind::NotImplemented,
+                format!("slice: the tensors of {} can not be sliced", self.dtype()),
+            )
+                .into()),
+        }
+    }
+
     pub fn dtype(&self) -> GGMLType {
         match self {
             CpuTensorBuf::F32(_) => GGMLType::F32,
             CpuTensorBuf::F16(_) => GGMLType::F16,
+            CpuTensorBuf::BF16(_) => GGMLType::BF16,
             CpuTensorBuf::Q2K(_) => GGMLType::Q2K,
             CpuTensorBuf::Q3K(_) => GGMLType::Q3K,
             CpuTensorBuf::Q8_0(_) => GGMLType::Q8_0,
+            CpuTensorBuf::Q8_0Packed(_) => GGMLType::Q8_0,
+            CpuTensorBuf::W8A8(_) => GGMLType::I8,
             CpuTensorBuf::Q8_1(_) => GGMLType::Q8_1,
             CpuTensorBuf::Q8K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q4_0(_) => GGMLType::Q4_0,
@@ -122,10 +298,14 @@ impl<'a> CpuTensorBuf<'a> {
     pub fn vec_dot_rhs_dtype(&self) -> GGMLType {
         match self {
             CpuTensorBuf::F32(_) => GGMLType::F32,
-            CpuTensorBuf::F16(_) => GGMLType::F16,
+            CpuTensorBuf::F16(_) => GGMLType::F32,
+            CpuTensorBuf::BF16(_) => GGMLType::F32,
             CpuTensorBuf::Q2K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q3K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q8_0(_) => GGMLType::Q8_0,
+            CpuTensorBuf::Q8_0Packed(_) => GGMLType::Q8_0,
+            // the activations are quantized per token in matmul_vec
+            CpuTensorBuf::W8A8(_) => GGMLType::F32,
             CpuTensorBuf::Q8_1(_) => GGMLType::Q8_1,
             CpuTensorBuf::Q8K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q5_0(_) => GGMLType::Q8_0,
@@ -155,9 +335,12 @@ impl<'a> CpuTensorBuf<'a> {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(match self {
                 CpuTensorBuf::F32(buf) => buf,
                 CpuTensorBuf::F16(buf) => dequantize_f16_buf(&buf, 0).collect(),
+                CpuTensorBuf::BF16(buf) => dequantize_bf16_buf(&buf, 0).collect(),
                 CpuTensorBuf::Q2K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q3K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q8_0(buf) => buf.dequantize(0).collect(),
+                CpuTensorBuf::Q8_0Packed(buf) => buf.dequantize(0).collect(),
+                CpuTensorBuf::W8A8(buf) => buf.dequantize().into(),
                 CpuTensorBuf::Q8_1(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q8K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q4_0(buf) => buf.dequantize(0).collect(),
@@ -168,7 +351,10 @@ impl<'a> CpuTensorBuf<'a> {
                 CpuTensorBuf::Q5K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q6K(buf) => buf.dequantize(0).collect(),
             })),
-            GGMLType::F16 => unimplemented!(),
+            GGMLType::F16 => match self {
+                CpuTensorBuf::F16(buf) => Ok(CpuTensorBuf::F16(buf)),
+                buf => buf.dequantize(GGMLType::F32)?.quantize(GGMLType::F16),
+            },
             _ => unreachable!(),
         }
     }
@@ -177,6 +363,7 @@ impl<'a> CpuTensorBuf<'a> {
         match dtype {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(self.as_f32_ref().to_vec().into())),
             GGMLType::F16 => Ok(CpuTensorBuf::F16(quantize_f32_f16(self.as_f32_ref()))),
+            GGMLType::BF16 => Ok(CpuTensorBuf::BF16(quantize_f32_bf16(self.as_f32_ref()))),
             GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::quantize(self.as_f32_ref()))),
             GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::quantize(self.as_f32_ref()))),
             GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::quantize(
@@ -209,14 +396,144 @@ impl<'a> CpuTensorBuf<'a> {
         }
     }
 
+    /// concatenate the buffers of the same dtype into an owned buffer. the quantized blocks are
+    /// copied as is, so each buffer must contain whole blocks, like the rows of a weight.
+    pub fn concat(bufs: &[&CpuTensorBuf<'a>]) -> Result<Self> {
+        let dtype = match bufs.first() {
+            Some(buf) => buf.dtype(),
+            None => return Err((ErrorKind::TensorError, "concat: no buffer").into()),
+        };
+        if bufs.iter().any(|b| b.is_packed()) {
+            return Err((
+                ErrorKind::TensorError,
+                "concat: packed buffers are not supported",
+            )
+                .into());
+        }
+        if let Some(buf) = bufs.iter().find(|b| b.dtype() != dtype) {
+            return Err((
+                ErrorKind::TensorError,
+                format!("concat: dtype mismatch {} vs {}", dtype, buf.dtype()),
+            )
+                .into());
+        }
+
+        macro_rules! concat_blocks {
+            ($variant:ident, $buf_type:ident) => {{
+                let mut blocks = vec![];
+                for buf in bufs {
+                    if let CpuTensorBuf::$variant(b) = buf {
+                        blocks.extend_from_slice(&b.blocks);
+                    }
+                }
+                CpuTensorBuf::$variant($buf_type {
+                    blocks: blocks.into(),
+                })
+            }};
+        }
+
+        let buf = match dtype {
+            GGMLType::F32 => {
+                let data = bufs.iter().flat_map(|b| b.as_f32_ref().iter().copied());
+                CpuTensorBuf::F32(data.collect::<Vec<_>>().into())
+            }
+            GGMLType::F16 => {
+                let mut data = vec![];
+                for buf in bufs {
+                    if let CpuTensorBuf::F16(b) = buf {
+                        data.extend_from_slice(b);
+                    }
+                }
+                CpuTensorBuf::F16(data.into())
+            }
+            GGMLType::BF16 => {
+                let mut data = vec![];
+                for buf in bufs {
+                    if let CpuTensorBuf::BF16(b) = buf {
+                        data.extend_from_slice(b);
+                    }
+                }
+                CpuTensorBuf::BF16(data.into())
+            }
+            GGMLType::Q2K => concat_blocks!(Q2K, QuantBufQ2K),
+            GGMLType::Q3K => concat_blocks!(Q3K, QuantBufQ3K),
+            GGMLType::Q8_0 => concat_blocks!(Q8_0, QuantBufQ8_0),
+            GGMLType::Q8_1 => concat_blocks!(Q8_1, QuantBufQ8_1),
+            GGMLType::Q8K => concat_blocks!(Q8K, QuantBufQ8K),
+            GGMLType::Q4_0 => concat_blocks!(Q4_0, QuantBufQ4_0),
+            GGMLType::Q4_1 => concat_blocks!(Q4_1, QuantBufQ4_1),
+            GGMLType::Q4K => concat_blocks!(Q4K, QuantBufQ4K),
+            GGMLType::Q5_0 => concat_blocks!(Q5_0, QuantBufQ5_0),
+            GGMLType::Q5_1 => concat_blocks!(Q5_1, QuantBufQ5_1),
+            GGMLType::Q5K => concat_blocks!(Q5K, QuantBufQ5K),
+            GGMLType::Q6K => concat_blocks!(Q6K, QuantBufQ6K),
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("concat: {} is not supported", dtype),
+                )
+                    .into());
+            }
+        };
+        Ok(buf)
+    }
+
+    /// check that vec_dot supports the lhs and the rhs, the rhs is expected to be in the
+    /// vec_dot_rhs_dtype of the lhs, or f32 to be quantized into it.
+    pub fn check_vec_dot(&self, b: &Self) -> Result<()> {
+        use CpuTensorBuf::*;
+        let rhs_dtype = self.vec_dot_rhs_dtype();
+        let supported = (b.dtype() == rhs_dtype || b.dtype() == GGMLType::F32)
+            && matches!(
+                (self, rhs_dtype),
+                (F32(_), GGMLType::F32)
+                    | (F16(_), GGMLType::F32)
+                    | (BF16(_), GGMLType::F32)
+                    | (Q2K(_), GGMLType::Q8K)
+                    | (Q3K(_), GGMLType::Q8K)
+                    | (Q8_0(_), GGMLType::Q8_0)
+                    | (Q8_0Packed(_), GGMLType::Q8_0)
+                    | (W8A8(_), GGMLType::F32)
+                    | (Q8K(_), GGMLType::Q8K)
+                    | (Q4_0(_), GGMLType::Q8_0)
+                    | (Q4_1(_), GGMLType::Q8_1)
+                    | (Q4K(_), GGMLType::Q8K)
+                    | (Q5_0(_), GGMLType::Q8_0)
+                    | (Q5_1(_), GGMLType::Q8_1)
+                    | (Q5K(_), GGMLType::Q8K)
+                    | (Q6K(_), GGMLType::Q8K)
+            );
+        if !supported {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "vec_dot of {} and {} is not supported",
+                    self.dtype(),
+                    b.dtype()
+                ),
+            )
+                .into());
+        }
+        Ok(())
+    }
+
+    /// the pair of the buffers is expected to pass check_vec_dot, after the rhs is quantized
+    /// into the vec_dot_rhs_dtype of the lhs.
     pub fn vec_dot(&self, a_offset: usize, b: &Self, b_offset: usize, len: usize) -> f32 {
         use CpuTensorBuf::*;
         match (self, b) {
             (F32(a), F32(b)) => vec_dot_f32_f32(a, a_offset, b, b_offset, len),
             (F16(a), F16(b)) => vec_dot_f16_f16(a, a_offset, b, b_offset, len),
+            (F16(a), F32(b)) => vec_dot_f16_f32(a, a_offset, b, b_offset, len),
+            (BF16(a), F32(b)) => vec_dot_bf16_f32(a, a_offset, b, b_offset, len),
             (Q2K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q3K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q8_0(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (Q8_0Packed(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (W8A8(a), F32(b)) => {
+                let (_, cols) = a.shape();
+                a.dot_row_f32(a_offset / cols, &b[b_offset..b_offset + len])
+            }
             (Q8_1(a), Q8_1(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q8K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q4_0(a), Q8_0(b)) => a.vec_dot(a_offset, b, b_offset, len),
@@ -226,7 +543,7 @@ impl<'a> CpuTensorBuf<'a> {
             (Q5_1(a), Q8_1(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q5K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q6K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
-            _ => unreachable!(),
+            _ => unreachable!("the pair is checked by check_vec_dot"),
         }
     }
 
@@ -261,6 +578,9 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::F16(buf) => {
                 self.copy_from_iter(dequantize_f16_buf(buf, src_offset), dst_offset, len)
             }
+            CpuTensorBuf::BF16(buf) => {
+                self.copy_from_iter(dequantize_bf16_buf(buf, src_offset), dst_offset, len)
+            }
             CpuTensorBuf::Q2K(buf) => {
                 self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
             }
@@ -270,6 +590,13 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q8_0(buf) => {
                 self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
             }
+            CpuTensorBuf::Q8_0Packed(buf) => {
+                self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
+            }
+            CpuTensorBuf::W8A8(buf) => {
+                let iter = (src_offset..buf.len()).map(|pos| buf.dequantize_at(pos));
+                self.copy_from_iter(iter, dst_offset, len)
+            }
             CpuTensorBuf::Q8_1(buf) => {
                 self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
             }
@@ -349,6 +676,34 @@ impl<'a> CpuTensorBuf<'a> {
         }
     }
 
+    /// read a single element as f32. the quantized blocks around it are dequantized on the fly,
+    /// it's slow and only meant for the places like printing a few elements of a weight.
+    pub fn dequantize_at(&self, pos: usize) -> f32 {
+        // QK_K is a multiple of the sizes of all the blocks
+        let start = pos - pos % QK_K;
+        let nth = pos % QK_K;
+        let v = match self {
+            CpuTensorBuf::F32(buf) => Some(buf[pos]),
+            CpuTensorBuf::F16(buf) => Some(buf[pos].to_f32()),
+            CpuTensorBuf::BF16(buf) => Some(buf[pos].to_f32()),
+            CpuTensorBuf::Q2K(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q3K(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q8_0(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q8_0Packed(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::W8A8(buf) => (pos < buf.len()).then(|| buf.dequantize_at(pos)),
+            CpuTensorBuf::Q8_1(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q8K(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q4_0(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q4_1(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q4K(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q5_0(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q5_1(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q5K(buf) => buf.dequantize(start).nth(nth),
+            CpuTensorBuf::Q6K(buf) => buf.dequantize(start).nth(nth),
+        };
+        v.unwrap_or_else(|| panic!("index {} out of the buffer of {}", pos, self.len()))
+    }
+
     /// the quantized tensor can not be iterated directly. to iterate the quantized tensor,
     /// use `dequantize` to convert it to f32/f16 tensor first.
     pub fn iter_f32(&self) -> impl Iterator<Item = f32> + '_ {
@@ -361,14 +716,63 @@ impl<'a> CpuTensorBuf<'a> {
     }
 }
 
+// the bytes of a block of the quantized types, or of an element of the other types
+fn raw_block_bytes(typ: GGMLType) -> Option<usize> {
+    let size = match typ {
+        GGMLType::F32 => size_of::<f32>(),
+        GGMLType::F16 => size_of::<f16>(),
+        GGMLType::BF16 => size_of::<bf16>(),
+        GGMLType::I8 => size_of::<i8>(),
+        GGMLType::I16 => size_of::<i16>(),
+        GGMLType::I32 => size_of::<i32>(),
+        GGMLType::Q2K => size_of::<BlockQ2K>(),
+        GGMLType::Q3K => size_of::<BlockQ3K>(),
+        GGMLType::Q8_0 => size_of::<BlockQ8_0>(),
+        GGMLType::Q8_1 => size_of::<BlockQ8_1>(),
+        GGMLType::Q8K => size_of::<BlockQ8K>(),
+        GGMLType::Q4_0 => size_of::<BlockQ4_0>(),
+        GGMLType::Q4_1 => size_of::<BlockQ4_1>(),
+        GGMLType::Q4K => size_of::<BlockQ4K>(),
+        GGMLType::Q5_0 => size_of::<BlockQ5_0>(),
+        GGMLType::Q5_1 => size_of::<BlockQ5_1>(),
+        GGMLType::Q5K => size_of::<BlockQ5K>(),
+        GGMLType::Q6K => size_of::<BlockQ6K>(),
+        _ => return None,
+    };
+    Some(size)
+}
+
+// the bytes of a malformed file would trip the asserts in `cast_bytes` and the `from_bytes` of
+// the blocks, they're rejected here as a format error instead.
+fn check_raw_bytes(buf: &[u8], typ: GGMLType) -> Result<()> {
+    let Some(block_bytes) = raw_block_bytes(typ) else {
+        return Ok(());
+    };
+    if buf.len() % block_bytes != 0 {
+        return Err(Error::new(
+            ErrorKind::FormatError,
+            format!(
+                "the {} bytes of the {} tensor are not a multiple of its {} bytes blocks",
+                buf.len(),
+                typ,
+                block_bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+
 impl Clone for CpuTensorBuf<'_> {
     fn clone(&self) -> Self {
         match self {
             CpuTensorBuf::F32(buf) => Self::F32(buf.clone()),
             CpuTensorBuf::F16(buf) => Self::F16(buf.clone()),
+            CpuTensorBuf::BF16(buf) => Self::BF16(buf.clone()),
             CpuTensorBuf::Q2K(buf) => Self::Q2K(buf.clone()),
             CpuTensorBuf::Q3K(buf) => Self::Q3K(buf.clone()),
             CpuTensorBuf::Q8_0(buf) => Self::Q8_0(buf.clone()),
+            CpuTensorBuf::Q8_0Packed(buf) => Self::Q8_0Packed(buf.clone()),
+            CpuTensorBuf::W8A8(buf) => Self::W8A8(buf.clone()),
             CpuTensorBuf::Q8_1(buf) => Self::Q8_1(buf.clone()),
             CpuTensorBuf::Q8K(buf) => Self::Q8K(buf.clone()),
             CpuTensorBuf::Q5_0(buf) => Self::Q5_0(buf.clone()),
@@ -0,0 +1,71 @@
+use std::borrow::Cow;
+
+use half::bf16;
+
+use crate::backends::cpu::buf::bytes::cast_bytes;
+
+pub fn bf16_buf_from_bytes(buf: &[u8]) -> Cow<'_, [bf16]> {
+    cast_bytes(buf)
+}
+
+pub fn dequantize_bf16_buf(buf: &[bf16], start: usize) -> impl Iterator<Item = f32> + '_ {
+    buf.iter().skip(start).map(|x| x.to_f32())
+}
+
+pub fn quantize_f32_bf16<'a>(buf: &[f32]) -> Cow<'a, [bf16]> {
+    buf.iter()
+        .map(|x| bf16::from_f32(*x))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// bf16 is the upper half of f32, the weights are widened on the fly and multiplied with the
+/// activations in f32, there's no loss on the activations.
+pub fn vec_dot_bf16_f32(
+    a: &[bf16],
+    a_offset: usize,
+    b: &[f32],
+    b_offset: usize,
+    len: usize,
+) -> f32 {
+    let a = &a[a_offset..a_offset + len];
+    let b = &b[b_offset..b_offset + len];
+    let mut sums = [0.0_f32; 8];
+    let chunks = len - len % 8;
+    for (ca, cb) in a[..chunks].chunks_exact(8).zip(b[..chunks].chunks_exact(8)) {
+        for ((sum, x), y) in sums.iter_mut().zip(ca).zip(cb) {
+            *sum += x.to_f32() * y;
+        }
+    }
+    let tail = a[chunks..]
+        .iter()
+        .zip(b[chunks..].iter())
+        .map(|(x, y)| x.to_f32() * y)
+        .sum::<f32>();
+    sums.iter().sum::<f32>() + tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vec_dot_bf16_f32() {
+        let a = (0..19).map(|i| i as f32).collect::<Vec<_>>();
+        let b = (0..19).map(|i| (i % 3) as f32).collect::<Vec<_>>();
+        let a_bf16 = quantize_f32_bf16(&a);
+        let want = a[1..]
+            .iter()
+            .zip(b[2..].iter())
+            .map(|(x, y)| x * y)
+            .sum::<f32>();
+        assert_eq!(vec_dot_bf16_f32(&a_bf16, 1, &b, 2, 17), want);
+        assert_eq!(dequantize_bf16_buf(&a_bf16, 0).collect::<Vec<_>>(), a);
+
+        let bytes = a_bf16
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(bf16_buf_from_bytes(&bytes).as_ref(), a_bf16.as_ref());
+    }
+}
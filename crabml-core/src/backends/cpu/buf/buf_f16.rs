@@ -1,19 +1,11 @@
 use std::borrow::Cow;
-use std::slice;
 
 use half::f16;
 
-pub fn f16_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [f16]> {
-    let len = buf.len();
-    assert_eq!(
-        len % std::mem::size_of::<f32>(),
-        0,
-        "Length of slice must be multiple of f32 size"
-    );
-    let new_len = len / std::mem::size_of::<f16>();
-    let ptr = buf.as_ptr() as *const f16;
-    let f16_buf = unsafe { slice::from_raw_parts(ptr, new_len) };
-    f16_buf.into()
+use crate::backends::cpu::buf::bytes::cast_bytes;
+
+pub fn f16_buf_from_bytes(buf: &[u8]) -> Cow<'_, [f16]> {
+    cast_bytes(buf)
 }
 
 // it's slow to initialize a vec![f16::ZERO; buf_size], nearly 80~200ms on preparing kv cache.
@@ -96,6 +88,182 @@ pub fn vec_dot_f16_f16_fallback(
     sum
 }
 
+/// the f16 side is widened into f32 in the kernel and the sum is kept in f32, so the f32 side
+/// like the activations is not rounded into f16.
+pub fn vec_dot_f16_f32(a: &[f16], a_offset: usize, b: &[f32], b_offset: usize, len: usize) -> f32 {
+    let a = &a[a_offset..a_offset + len];
+    let b = &b[b_offset..b_offset + len];
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        vec_dot_f16_f32_neon(a, b)
+    }
+
+    #[cfg(all(
+        target_arch = "x86_64",
+        target_feature = "avx2",
+        target_feature = "f16c"
+    ))]
+    {
+        vec_dot_f16_f32_avx2(a, b)
+    }
+
+    #[cfg(not(any(
+        target_arch = "aarch64",
+        all(
+            target_arch = "x86_64",
+            target_feature = "avx2",
+            target_feature = "f16c"
+        )
+    )))]
+    vec_dot_f16_f32_fallback(a, b)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn vec_dot_f16_f32_neon(a: &[f16], b: &[f32]) -> f32 {
+    use std::arch::aarch64;
+
+    use crate::backends::cpu::archutil::aarch64 as myaarch64;
+    let len_rounded = a.len() - a.len() % 8;
+    unsafe {
+        let mut sumv0 = aarch64::vdupq_n_f32(0.0);
+        let mut sumv1 = aarch64::vdupq_n_f32(0.0);
+        for i in (0..len_rounded).step_by(8) {
+            let av = myaarch64::vld1q_f16(a.as_ptr().add(i));
+            let av0 = myaarch64::vget_low_f16_f32(av);
+            let av1 = myaarch64::vget_high_f16_f32(av);
+            let bv0 = aarch64::vld1q_f32(b.as_ptr().add(i));
+            let bv1 = aarch64::vld1q_f32(b.as_ptr().add(i + 4));
+            sumv0 = aarch64::vfmaq_f32(sumv0, av0, bv0);
+            sumv1 = aarch64::vfmaq_f32(sumv1, av1, bv1);
+        }
+        let mut sum = aarch64::vaddvq_f32(aarch64::vaddq_f32(sumv0, sumv1));
+        for i in len_rounded..a.len() {
+            sum += a.get_unchecked(i).to_f32() * b.get_unchecked(i);
+        }
+        sum
+    }
+}
+
+#[cfg(all(
+    target_arch = "x86_64",
+    target_feature = "avx2",
+    target_feature = "f16c"
+))]
+fn vec_dot_f16_f32_avx2(a: &[f16], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    use crate::backends::cpu::archutil::x86_64::hsum_float_8;
+    let len_rounded = a.len() - a.len() % 16;
+    unsafe {
+        let mut acc0 = _mm256_setzero_ps();
+        let mut acc1 = _mm256_setzero_ps();
+        for i in (0..len_rounded).step_by(16) {
+            let av0 = _mm256_cvtph_ps(_mm_loadu_si128(a.as_ptr().add(i) as *const __m128i));
+            let av1 = _mm256_cvtph_ps(_mm_loadu_si128(a.as_ptr().add(i + 8) as *const __m128i));
+            let bv0 = _mm256_loadu_ps(b.as_ptr().add(i));
+            let bv1 = _mm256_loadu_ps(b.as_ptr().add(i + 8));
+            acc0 = _mm256_add_ps(acc0, _mm256_mul_ps(av0, bv0));
+            acc1 = _mm256_add_ps(acc1, _mm256_mul_ps(av1, bv1));
+        }
+        let mut sum = hsum_float_8(_mm256_add_ps(acc0, acc1));
+        for i in len_rounded..a.len() {
+            sum += a.get_unchecked(i).to_f32() * b.get_unchecked(i);
+        }
+        sum
+    }
+}
+
+#[allow(dead_code)]
+fn vec_dot_f16_f32_fallback(a: &[f16], b: &[f32]) -> f32 {
+    let mut sums = [0.0_f32; 8];
+    let chunks = a.len() - a.len() % 8;
+    for (ca, cb) in a[..chunks].chunks_exact(8).zip(b[..chunks].chunks_exact(8)) {
+        for ((sum, x), y) in sums.iter_mut().zip(ca).zip(cb) {
+            *sum += x.to_f32() * y;
+        }
+    }
+    let tail = a[chunks..]
+        .iter()
+        .zip(b[chunks..].iter())
+        .map(|(x, y)| x.to_f32() * y)
+        .sum::<f32>();
+    sums.iter().sum::<f32>() + tail
+}
+
+/// c += a[a_offset..a_offset+m] * b, the f16 side is widened and accumulated in f32.
+pub fn vec_fma_f16_f32(a: &[f16], b: f32, c: &mut [f32], a_offset: usize, m: usize) {
+    let a = &a[a_offset..a_offset + m];
+    let c = &mut c[..m];
+
+    #[cfg(target_arch = "aarch64")]
+    vec_fma_f16_f32_neon(a, b, c);
+
+    #[cfg(all(
+        target_arch = "x86_64",
+        target_feature = "avx2",
+        target_feature = "f16c"
+    ))]
+    vec_fma_f16_f32_avx2(a, b, c);
+
+    #[cfg(not(any(
+        target_arch = "aarch64",
+        all(
+            target_arch = "x86_64",
+            target_feature = "avx2",
+            target_feature = "f16c"
+        )
+    )))]
+    vec_fma_f16_f32_fallback(a, b, c);
+}
+
+#[cfg(target_arch = "aarch64")]
+fn vec_fma_f16_f32_neon(a: &[f16], b: f32, c: &mut [f32]) {
+    use std::arch::aarch64;
+
+    use crate::backends::cpu::archutil::aarch64 as myaarch64;
+    let m_rounded = a.len() - a.len() % 8;
+    unsafe {
+        let bv = aarch64::vdupq_n_f32(b);
+        for mi in (0..m_rounded).step_by(8) {
+            let av = myaarch64::vld1q_f16(a.as_ptr().add(mi));
+            let cv0 = aarch64::vld1q_f32(c.as_ptr().add(mi));
+            let cv1 = aarch64::vld1q_f32(c.as_ptr().add(mi + 4));
+            let cv0 = aarch64::vfmaq_f32(cv0, myaarch64::vget_low_f16_f32(av), bv);
+            let cv1 = aarch64::vfmaq_f32(cv1, myaarch64::vget_high_f16_f32(av), bv);
+            aarch64::vst1q_f32(c.as_mut_ptr().add(mi), cv0);
+            aarch64::vst1q_f32(c.as_mut_ptr().add(mi + 4), cv1);
+        }
+    }
+    vec_fma_f16_f32_fallback(&a[m_rounded..], b, &mut c[m_rounded..]);
+}
+
+#[cfg(all(
+    target_arch = "x86_64",
+    target_feature = "avx2",
+    target_feature = "f16c"
+))]
+fn vec_fma_f16_f32_avx2(a: &[f16], b: f32, c: &mut [f32]) {
+    use std::arch::x86_64::*;
+    let m_rounded = a.len() - a.len() % 8;
+    unsafe {
+        let bv = _mm256_set1_ps(b);
+        for mi in (0..m_rounded).step_by(8) {
+            let av = _mm256_cvtph_ps(_mm_loadu_si128(a.as_ptr().add(mi) as *const __m128i));
+            let cv = _mm256_loadu_ps(c.as_ptr().add(mi));
+            let cv = _mm256_add_ps(cv, _mm256_mul_ps(av, bv));
+            _mm256_storeu_ps(c.as_mut_ptr().add(mi), cv);
+        }
+    }
+    vec_fma_f16_f32_fallback(&a[m_rounded..], b, &mut c[m_rounded..]);
+}
+
+fn vec_fma_f16_f32_fallback(a: &[f16], b: f32, c: &mut [f32]) {
+    for (ci, ai) in c.iter_mut().zip(a.iter()) {
+        *ci += ai.to_f32() * b;
+    }
+}
+
 pub fn vec_dot_f16_f16_strided(
     a: &[f16],
     a_base: usize,
@@ -264,7 +432,44 @@ pub fn vec_dot_f16_f16_strided_fallback(
 mod tests {
     use half::f16;
 
+    use crate::backends::cpu::buf::buf_f16::quantize_f32_f16;
+    use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f32;
+    use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f32_fallback;
     use crate::backends::cpu::buf::buf_f16::vec_fma_f16_f16;
+    use crate::backends::cpu::buf::buf_f16::vec_fma_f16_f32;
+    use crate::backends::cpu::buf::buf_f16::vec_fma_f16_f32_fallback;
+
+    #[test]
+    fn test_vec_dot_f16_f32() {
+        let a = (0..19).map(|i| i as f32).collect::<Vec<_>>();
+        let b = (0..19).map(|i| (i % 3) as f32 + 0.001).collect::<Vec<_>>();
+        let a_f16 = quantize_f32_f16(&a);
+        let want = a[1..]
+            .iter()
+            .zip(b[2..].iter())
+            .map(|(x, y)| x * y)
+            .sum::<f32>();
+        let got = vec_dot_f16_f32(&a_f16, 1, &b, 2, 17);
+        assert!((got - want).abs() < 1e-4, "got {} want {}", got, want);
+
+        // the f32 accumulator keeps the small addends a f16 one would drop
+        let mut c = vec![2048.0_f32; 4];
+        let a = vec![f16::from_f32(1.0); 5];
+        vec_fma_f16_f32(&a, 0.25, &mut c, 1, 4);
+        assert_eq!(c, vec![2048.25; 4]);
+
+        // the SIMD lanes and the tail agree with the scalar kernel
+        let a = quantize_f32_f16(&(0..21).map(|i| i as f32 * 0.5).collect::<Vec<_>>());
+        let mut c = (0..19).map(|i| i as f32).collect::<Vec<_>>();
+        let mut want = c.clone();
+        vec_fma_f16_f32(&a, 0.5, &mut c, 2, 19);
+        vec_fma_f16_f32_fallback(&a[2..], 0.5, &mut want);
+        assert_eq!(c, want);
+        assert_eq!(
+            vec_dot_f16_f32(&a, 2, &c, 0, 19),
+            vec_dot_f16_f32_fallback(&a[2..], &c)
+        );
+    }
 
     #[test]
     fn test_vec_fma_f16_f16() {
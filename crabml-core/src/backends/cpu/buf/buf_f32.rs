@@ -1,19 +1,11 @@
 use std::borrow::Cow;
-use std::slice;
 
 use half::f16;
 
-pub fn f32_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [f32]> {
-    let len = buf.len();
-    assert_eq!(
-        len % std::mem::size_of::<f32>(),
-        0,
-        "Length of slice must be multiple of f32 size"
-    );
-    let new_len = len / std::mem::size_of::<f32>();
-    let ptr = buf.as_ptr() as *const f32;
-    let f32_buf = unsafe { slice::from_raw_parts(ptr, new_len) };
-    f32_buf.into()
+use crate::backends::cpu::buf::bytes::cast_bytes;
+
+pub fn f32_buf_from_bytes(buf: &[u8]) -> Cow<'_, [f32]> {
+    cast_bytes(buf)
 }
 
 pub fn vec_dot_f32_f32(a: &[f32], a_offset: usize, b: &[f32], b_offset: usize, len: usize) -> f32 {
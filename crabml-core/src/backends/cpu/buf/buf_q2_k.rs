@@ -3,6 +3,7 @@ use std::borrow::Cow;
 use half::f16;
 
 use super::QuantBufQ8K;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 use crate::backends::cpu::buf::util::*;
 
 /// A q2_k super block of 2-bit quantization
@@ -90,11 +91,8 @@ impl<'a> QuantBufQ2K<'a> {
             data.len() % blk_size == 0,
             "data length must be a multiple of BlockQ2K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ2K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
 
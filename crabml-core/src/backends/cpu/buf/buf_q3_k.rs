@@ -5,6 +5,7 @@ use half::f16;
 
 use crate::backends::cpu::buf::buf_q8_k::BlockQ8K;
 use crate::backends::cpu::buf::buf_q8_k::QuantBufQ8K;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 use crate::backends::cpu::buf::util::*;
 
 /// A q3_k super block of 3-bit quantization
@@ -105,11 +106,8 @@ impl<'a> QuantBufQ3K<'a> {
             data.len() % blk_size == 0,
             "data length must be a multiple of BlockQ3K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ3K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
 
@@ -4,6 +4,7 @@ use half::f16;
 
 use super::QuantBufQ8_0;
 use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
@@ -38,11 +39,8 @@ impl<'a> QuantBufQ4_0<'_> {
             0,
             "data length must be a multiple of QuantBlockQ4_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_0, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
     pub fn quantize(data: &[f32]) -> Self {
@@ -123,7 +121,15 @@ pub fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
         vec_dot_q4_0_q8_0_neon(abs, bbs)
     }
 
-    #[cfg(not(all(target_arch = "aarch64", target_feature = "neon")))]
+    #[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+    {
+        vec_dot_q4_0_q8_0_avx2(abs, bbs)
+    }
+
+    #[cfg(not(any(
+        all(target_arch = "aarch64", target_feature = "neon"),
+        all(target_arch = "x86_64", target_feature = "avx2")
+    )))]
     {
         vec_dot_q4_0_q8_0_fallback(abs, bbs)
     }
@@ -198,6 +204,33 @@ pub fn vec_dot_q4_0_q8_0_neon(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
     sumf
 }
 
+#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+pub fn vec_dot_q4_0_q8_0_avx2(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+    use std::arch::x86_64::*;
+
+    use crate::backends::cpu::archutil::x86_64::*;
+    debug_assert_eq!(abs.len(), bbs.len());
+
+    unsafe {
+        let mut acc = _mm256_setzero_ps();
+        let off = _mm256_set1_epi8(8);
+
+        for (ab, bb) in abs.iter().zip(bbs.iter()) {
+            let d = _mm256_set1_ps(f16::to_f32(ab.d) * f16::to_f32(bb.d));
+
+            // unpack the 32 nibbles into bytes in [0, 15], and shift them into [-8, 7]
+            let qx = bytes_from_nibbles_32(ab.qs.as_ptr());
+            let qx = _mm256_sub_epi8(qx, off);
+            let qy = _mm256_loadu_si256(bb.qs.as_ptr() as *const _);
+
+            let q = mul_sum_i8_pairs_float(qx, qy);
+            acc = _mm256_fmadd_ps(d, q, acc);
+        }
+
+        hsum_float_8(acc)
+    }
+}
+
 pub fn vec_dot_q4_0_q8_0_fallback(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
     let mut sumf: f32 = 0f32;
     for i in 0..bbs.len() {
@@ -257,4 +290,35 @@ mod tests {
             -24.0, -24.0, -24.0, -24.0
         ]);
     }
+
+    #[test]
+    fn test_q4_0_vec_dot() {
+        let a = (0..96)
+            .map(|i| ((i * 7) % 19) as f32 - 9.0)
+            .collect::<Vec<_>>();
+        let b = (0..96)
+            .map(|i| ((i * 5) % 13) as f32 / 4.0 - 1.5)
+            .collect::<Vec<_>>();
+        let qa = QuantBufQ4_0::quantize(&a);
+        let qb = QuantBufQ8_0::quantize(&b);
+
+        // the dot product on the quantized blocks equals the one on the dequantized values
+        let da = qa.dequantize(0).collect::<Vec<_>>();
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+        let want = da[32..]
+            .iter()
+            .zip(db[32..].iter())
+            .map(|(x, y)| x * y)
+            .sum::<f32>();
+        let got = qa.vec_dot(32, &qb, 32, 64);
+        assert!(
+            (got - want).abs() < 1e-3 * want.abs().max(1.0),
+            "{} != {}",
+            got,
+            want
+        );
+
+        let fallback = vec_dot_q4_0_q8_0_fallback(&qa.blocks, &qb.blocks);
+        assert!((vec_dot_q4_0_q8_0(&qa.blocks, &qb.blocks) - fallback).abs() < 1e-3);
+    }
 }
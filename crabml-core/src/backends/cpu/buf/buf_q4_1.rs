@@ -4,6 +4,7 @@ use half::f16;
 
 use super::QuantBufQ8_1;
 use crate::backends::cpu::buf::buf_q8_1::BlockQ8_1;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct BlockQ4_1 {
@@ -40,11 +41,8 @@ impl<'a> QuantBufQ4_1<'a> {
             0,
             "data length must be a multiple of QuantBlockQ8_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_1, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
 
@@ -225,7 +223,7 @@ pub fn vec_dot_q4_1_q8_1_avx2(abs: &[BlockQ4_1], bbs: &[BlockQ8_1]) -> f32 {
             let d0 = f16::to_f32(ab0.d);
             let d1 = f16::to_f32(bb0.d);
 
-            summs += f16::to_f32(ab0.m) + f16::to_f32(bb0.s);
+            summs += f16::to_f32(ab0.m) * f16::to_f32(bb0.s);
 
             let d0v = _mm256_set1_ps(d0);
             let d1v = _mm256_set1_ps(d1);
@@ -324,4 +322,28 @@ mod tests {
         bs.blocks[0].dequantize(&mut dequantize);
         assert_eq!(dequantize, *data);
     }
+
+    #[test]
+    fn test_q4_1_vec_dot() {
+        let a = (0..64)
+            .map(|i| ((i * 7) % 19) as f32 / 2.0 + 1.0)
+            .collect::<Vec<_>>();
+        let b = (0..64)
+            .map(|i| ((i * 5) % 13) as f32 / 4.0 - 0.5)
+            .collect::<Vec<_>>();
+        let qa = QuantBufQ4_1::quantize(&a);
+        let qb = QuantBufQ8_1::quantize(&b);
+
+        // the min of each q4_1 block is scaled by the sum of the q8_1 block
+        let da = qa.dequantize(0).collect::<Vec<_>>();
+        let db = qb.dequantize(0).collect::<Vec<_>>();
+        let want = da.iter().zip(db.iter()).map(|(x, y)| x * y).sum::<f32>();
+        let got = qa.vec_dot(0, &qb, 0, 64);
+        assert!(
+            (got - want).abs() < 1e-2 * want.abs().max(1.0),
+            "{} != {}",
+            got,
+            want
+        );
+    }
 }
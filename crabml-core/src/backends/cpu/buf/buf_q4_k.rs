@@ -6,6 +6,7 @@ use super::util::get_scale_min_k4;
 use super::util::QK_K;
 use super::QuantBufQ8K;
 use crate::backends::cpu::buf::buf_q8_k::BlockQ8K;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 use crate::backends::cpu::buf::util::make_qkx1_quants;
 use crate::backends::cpu::buf::util::nearest_i32;
 
@@ -58,11 +59,8 @@ impl<'a> QuantBufQ4K<'_> {
             0,
             "data length must be a multiple of QuantBlockQ4_K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
 
@@ -7,6 +7,7 @@ use half::f16;
 
 use super::QuantBufQ8_0;
 use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -47,11 +48,8 @@ impl<'a> QuantBufQ5_0<'_> {
             0,
             "data length must be a multiple of QuantBlockQ5_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ5_0, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
     pub fn quantize(data: &[f32]) -> Self {
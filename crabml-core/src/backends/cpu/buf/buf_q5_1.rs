@@ -5,6 +5,7 @@ use byteorder::LittleEndian;
 use half::f16;
 
 use super::QuantBufQ8_1;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct BlockQ5_1 {
@@ -46,11 +47,8 @@ impl<'a> QuantBufQ5_1<'a> {
             0,
             "data length must be a multiple of QuantBlockQ8_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ5_1, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
 
@@ -2,6 +2,7 @@ use std::borrow::Cow;
 
 use super::util::get_scale_min_k4;
 use super::util::QK_K;
+use crate::backends::cpu::buf::bytes::cast_bytes;
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -66,11 +67,8 @@ impl<'a> QuantBufQ5K<'_> {
             0,
             "data length must be a multiple of QuantBlockQ5_K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ5K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
     pub fn quantize(data: &[f32]) -> Self {
@@ -3,6 +3,8 @@ use std::simd::num::SimdFloat;
 
 use half::f16;
 
+use crate::backends::cpu::buf::bytes::cast_bytes;
+
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
 pub struct BlockQ8_0 {
@@ -34,11 +36,8 @@ impl<'a> QuantBufQ8_0<'a> {
             0,
             "data length must be a multiple of QuantBlockQ8_0 size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_0, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
 
@@ -78,6 +77,89 @@ impl<'a> QuantBufQ8_0<'a> {
     }
 }
 
+/// the Q8_0 blocks of every 4 rows interleaved by block: `[r0b0, r1b0, r2b0, r3b0, r0b1, ..]`.
+/// the packed kernel loads a block of the rhs once and dots it with the 4 rows in registers,
+/// and the blocks of the 4 rows are read in a single sequential stream.
+#[derive(Debug, Clone)]
+pub struct PackedBufQ8_0 {
+    blocks: Vec<BlockQ8_0>,
+    blocks_per_row: usize,
+}
+
+impl PackedBufQ8_0 {
+    pub const ROWS: usize = 4;
+
+    /// returns None if the rows can not be packed, the rows must be a multiple of 4.
+    pub fn pack(buf: &QuantBufQ8_0, rows: usize, cols: usize) -> Option<Self> {
+        if rows % Self::ROWS != 0 || cols % 32 != 0 || buf.len() != rows * cols {
+            return None;
+        }
+        let blocks_per_row = cols / 32;
+        let mut blocks = Vec::with_capacity(buf.blocks.len());
+        for group in buf.blocks.chunks_exact(Self::ROWS * blocks_per_row) {
+            for j in 0..blocks_per_row {
+                for r in 0..Self::ROWS {
+                    blocks.push(group[r * blocks_per_row + j].clone());
+                }
+            }
+        }
+        Some(Self {
+            blocks,
+            blocks_per_row,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len() * 32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn block(&self, row: usize, j: usize) -> &BlockQ8_0 {
+        let group_start = row / Self::ROWS * Self::ROWS * self.blocks_per_row;
+        &self.blocks[group_start + j * Self::ROWS + row % Self::ROWS]
+    }
+
+    /// dequantize in the row-major order like the unpacked buffer.
+    pub fn dequantize(&self, start: usize) -> impl Iterator<Item = f32> + '_ {
+        assert_eq!(start % 32, 0);
+
+        (start / 32..self.blocks.len()).flat_map(|bi| {
+            let blk = self.block(bi / self.blocks_per_row, bi % self.blocks_per_row);
+            let mut buf = [0.0; 32];
+            blk.dequantize(&mut buf);
+            buf.into_iter()
+        })
+    }
+
+    /// the slow path for the callers which are unaware of the packing, the blocks of the row
+    /// are strided by 4 in the packed layout, so they are dotted one by one in place.
+    pub fn vec_dot(&self, a_offset: usize, b: &QuantBufQ8_0, b_offset: usize, len: usize) -> f32 {
+        let row_len = self.blocks_per_row * 32;
+        let row = a_offset / row_len;
+        let j_start = a_offset % row_len / 32;
+        let bbs = &b.blocks()[b_offset / 32..(b_offset + len) / 32];
+        bbs.iter()
+            .enumerate()
+            .map(|(j, bb)| {
+                let ab = self.block(row, j_start + j);
+                vec_dot_q8_0_q8_0(std::slice::from_ref(ab), std::slice::from_ref(bb))
+            })
+            .sum()
+    }
+
+    /// the dot products of the 4 rows starting from `row` with the rhs vector.
+    pub fn vec_dot_x4(&self, row: usize, b: &QuantBufQ8_0, b_offset: usize) -> [f32; 4] {
+        debug_assert_eq!(row % Self::ROWS, 0);
+        let group_start = row * self.blocks_per_row;
+        let abs = &self.blocks[group_start..group_start + Self::ROWS * self.blocks_per_row];
+        let bbs = &b.blocks()[b_offset / 32..b_offset / 32 + self.blocks_per_row];
+        vec_dot_q8_0_q8_0_x4(abs, bbs)
+    }
+}
+
 pub fn quantize_f32_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
     use std::simd::f32x4;
     assert!(data.len() % 32 == 0);
@@ -248,7 +330,7 @@ pub fn vec_dot_q8_0_q8_0_avx2(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
 
         if abs.len() % 2 == 1 {
             let a = abs.last().unwrap_unchecked();
-            let b = abs.last().unwrap_unchecked();
+            let b = bbs.last().unwrap_unchecked();
 
             let d = _mm256_set1_ps(a.d.to_f32() * b.d.to_f32());
 
@@ -264,6 +346,110 @@ pub fn vec_dot_q8_0_q8_0_avx2(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
     }
 }
 
+// abs is the 4 rows interleaved by block, see `PackedBufQ8_0`
+fn vec_dot_q8_0_q8_0_x4(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> [f32; 4] {
+    debug_assert_eq!(abs.len(), bbs.len() * 4);
+
+    #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+    {
+        vec_dot_q8_0_q8_0_x4_neon(abs, bbs)
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+    {
+        vec_dot_q8_0_q8_0_x4_avx2(abs, bbs)
+    }
+
+    #[cfg(not(any(
+        all(target_arch = "aarch64", target_feature = "neon"),
+        all(target_arch = "x86_64", target_feature = "avx2")
+    )))]
+    vec_dot_q8_0_q8_0_x4_fallback(abs, bbs)
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+fn vec_dot_q8_0_q8_0_x4_neon(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> [f32; 4] {
+    use std::arch::aarch64;
+
+    unsafe {
+        let zerov = aarch64::vdupq_n_s32(0);
+        let mut sumv = [aarch64::vdupq_n_f32(0.0); 4];
+
+        for (ab4, bb) in abs.chunks_exact(4).zip(bbs) {
+            // load the rhs block once for the 4 rows
+            let bv0 = aarch64::vld1q_s8(bb.qs.as_ptr());
+            let bv1 = aarch64::vld1q_s8(bb.qs.as_ptr().add(16));
+            let bd = f16::to_f32(bb.d);
+
+            for (r, ab) in ab4.iter().enumerate() {
+                let av0 = aarch64::vld1q_s8(ab.qs.as_ptr());
+                let av1 = aarch64::vld1q_s8(ab.qs.as_ptr().add(16));
+                sumv[r] = aarch64::vmlaq_n_f32(
+                    sumv[r],
+                    aarch64::vcvtq_f32_s32(aarch64::vaddq_s32(
+                        aarch64::vdotq_s32(zerov, av0, bv0),
+                        aarch64::vdotq_s32(zerov, av1, bv1),
+                    )),
+                    f16::to_f32(ab.d) * bd,
+                );
+            }
+        }
+
+        [
+            aarch64::vaddvq_f32(sumv[0]),
+            aarch64::vaddvq_f32(sumv[1]),
+            aarch64::vaddvq_f32(sumv[2]),
+            aarch64::vaddvq_f32(sumv[3]),
+        ]
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+fn vec_dot_q8_0_q8_0_x4_avx2(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> [f32; 4] {
+    use std::arch::x86_64::*;
+
+    use crate::backends::cpu::archutil::x86_64::*;
+
+    unsafe {
+        let mut acc = [_mm256_setzero_ps(); 4];
+
+        for (ab4, bb) in abs.chunks_exact(4).zip(bbs) {
+            // load the rhs block once for the 4 rows
+            let qb = _mm256_loadu_si256(bb.qs.as_ptr() as *const __m256i);
+            let bd = bb.d.to_f32();
+
+            for (r, ab) in ab4.iter().enumerate() {
+                let d = _mm256_set1_ps(ab.d.to_f32() * bd);
+                let qa = _mm256_loadu_si256(ab.qs.as_ptr() as *const __m256i);
+                let q = mul_sum_i8_pairs_float(qa, qb);
+                acc[r] = _mm256_fmadd_ps(d, q, acc[r]);
+            }
+        }
+
+        [
+            hsum_float_8(acc[0]),
+            hsum_float_8(acc[1]),
+            hsum_float_8(acc[2]),
+            hsum_float_8(acc[3]),
+        ]
+    }
+}
+
+#[allow(unused)]
+fn vec_dot_q8_0_q8_0_x4_fallback(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> [f32; 4] {
+    let mut sumf = [0.0_f32; 4];
+    for (ab4, bb) in abs.chunks_exact(4).zip(bbs) {
+        for (r, ab) in ab4.iter().enumerate() {
+            let mut sumi: i32 = 0;
+            for j in 0..32 {
+                sumi += (ab.qs[j] as i32) * (bb.qs[j] as i32);
+            }
+            sumf[r] += sumi as f32 * ab.d.to_f32() * bb.d.to_f32();
+        }
+    }
+    sumf
+}
+
 #[allow(unused)]
 pub fn vec_dot_q8_0_q8_0_fallback(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
     let mut sumf: f32 = 0.0;
@@ -314,6 +500,23 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_vec_dot_q8_0_q8_0_odd_blocks() {
+        // the last block of an odd number of blocks is dotted apart from the pairs on avx2
+        let a = (0..96).map(|i| (i % 7) as f32 - 3.0).collect::<Vec<_>>();
+        let b = (0..96).map(|i| (i % 5) as f32 * 0.5).collect::<Vec<_>>();
+        let qa = QuantBufQ8_0::quantize(&a);
+        let qb = QuantBufQ8_0::quantize(&b);
+        let expected = vec_dot_q8_0_q8_0_fallback(&qa.blocks, &qb.blocks);
+        let result = vec_dot_q8_0_q8_0(&qa.blocks, &qb.blocks);
+        assert!(
+            (result - expected).abs() < 1e-3,
+            "{} != {}",
+            result,
+            expected
+        );
+    }
+
     #[test]
     fn test_vec_dot_q8_0_q8_0() {
         let tests = vec![
@@ -380,4 +583,43 @@ mod tests {
             assert_eq!(result, expect, "test: {}", name);
         }
     }
+
+    #[test]
+    fn test_packed_q8_0() {
+        // (8, 96) @ (96, )
+        let a = (0..8 * 96)
+            .map(|i| ((i * 7) % 23) as f32 - 11.0)
+            .collect::<Vec<_>>();
+        let b = (0..96).map(|i| (i % 5) as f32 * 0.5).collect::<Vec<_>>();
+        let qa = QuantBufQ8_0::quantize(&a);
+        let qb = QuantBufQ8_0::quantize(&b);
+        let packed = PackedBufQ8_0::pack(&qa, 8, 96).unwrap();
+        assert!(PackedBufQ8_0::pack(&qa, 6, 128).is_none());
+        assert_eq!(packed.len(), qa.len());
+
+        assert_eq!(
+            packed.dequantize(0).collect::<Vec<_>>(),
+            qa.dequantize(0).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            packed.dequantize(96).collect::<Vec<_>>(),
+            qa.dequantize(96).collect::<Vec<_>>()
+        );
+
+        for row in [0, 4] {
+            let got = packed.vec_dot_x4(row, &qb, 0);
+            for (r, v) in got.iter().enumerate() {
+                let expected = qa.vec_dot((row + r) * 96, &qb, 0, 96);
+                assert!(
+                    (v - expected).abs() < 1e-3,
+                    "row {}: {} vs {}",
+                    row + r,
+                    v,
+                    expected
+                );
+                let got = packed.vec_dot((row + r) * 96, &qb, 0, 96);
+                assert!((got - expected).abs() < 1e-3, "{} vs {}", got, expected);
+            }
+        }
+    }
 }
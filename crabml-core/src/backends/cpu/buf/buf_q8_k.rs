@@ -1,5 +1,7 @@
 use std::borrow::Cow;
 
+use crate::backends::cpu::buf::bytes::cast_bytes;
+
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct BlockQ8K {
@@ -30,11 +32,8 @@ impl<'a> QuantBufQ8K<'_> {
             0,
             "data length must be a multiple of QuantBlockQ8_K size"
         );
-        let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8K, data.len() / blk_size)
-        };
         Self {
-            blocks: blocks.into(),
+            blocks: cast_bytes(data),
         }
     }
     pub fn quantize(data: &[f32]) -> Self {
@@ -0,0 +1,111 @@
+use std::borrow::Cow;
+use std::mem::align_of;
+use std::mem::size_of;
+
+use half::bf16;
+use half::f16;
+
+use super::buf_q2_k::BlockQ2K;
+use super::buf_q3_k::BlockQ3K;
+use super::buf_q4_0::BlockQ4_0;
+use super::buf_q4_1::BlockQ4_1;
+use super::buf_q4_k::BlockQ4K;
+use super::buf_q5_0::BlockQ5_0;
+use super::buf_q5_1::BlockQ5_1;
+use super::buf_q5_k::BlockQ5K;
+use super::buf_q6_k::BlockQ6K;
+use super::buf_q8_0::BlockQ8_0;
+use super::buf_q8_1::BlockQ8_1;
+use super::buf_q8_k::BlockQ8K;
+
+/// the plain old data types which any bytes are a valid value of, they can be read right out of
+/// the raw bytes of a tensor.
+///
+/// # Safety
+///
+/// the type should be `repr(C)` or a primitive, made of the integers and the floats only,
+/// without any padding or pointer.
+pub unsafe trait Pod: Clone {}
+
+unsafe impl Pod for f32 {}
+unsafe impl Pod for f16 {}
+unsafe impl Pod for bf16 {}
+unsafe impl Pod for BlockQ2K {}
+unsafe impl Pod for BlockQ3K {}
+unsafe impl Pod for BlockQ4_0 {}
+unsafe impl Pod for BlockQ4_1 {}
+unsafe impl Pod for BlockQ4K {}
+unsafe impl Pod for BlockQ5_0 {}
+unsafe impl Pod for BlockQ5_1 {}
+unsafe impl Pod for BlockQ5K {}
+unsafe impl Pod for BlockQ6K {}
+unsafe impl Pod for BlockQ8_0 {}
+unsafe impl Pod for BlockQ8_1 {}
+unsafe impl Pod for BlockQ8K {}
+
+/// whether the bytes are aligned to be borrowed as a slice of T.
+pub fn is_aligned_for<T: Pod>(buf: &[u8]) -> bool {
+    (buf.as_ptr() as usize) % align_of::<T>() == 0
+}
+
+/// reinterpret the bytes as a slice of T. the bytes are borrowed if they're aligned for T, like
+/// the tensors in a mmaped GGUF file, whose mapping starts on a page and whose tensors start on
+/// `general.alignment`. otherwise they're copied into an owned buffer, a tensor at an unaligned
+/// offset is still loadable without reading misaligned pointers.
+pub fn cast_bytes<T: Pod>(buf: &[u8]) -> Cow<'_, [T]> {
+    let size = size_of::<T>();
+    assert_eq!(
+        buf.len() % size,
+        0,
+        "the length of the bytes must be a multiple of {} bytes",
+        size
+    );
+    let len = buf.len() / size;
+    if is_aligned_for::<T>(buf) {
+        let items = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const T, len) };
+        return Cow::Borrowed(items);
+    }
+    let mut items = Vec::<T>::with_capacity(len);
+    unsafe {
+        std::ptr::copy_nonoverlapping(buf.as_ptr(), items.as_mut_ptr() as *mut u8, buf.len());
+        items.set_len(len);
+    }
+    Cow::Owned(items)
+}
+
+/// view the items as their raw bytes, the reverse of `cast_bytes`.
+pub fn as_bytes<T: Pod>(items: &[T]) -> &[u8] {
+    let len = std::mem::size_of_val(items);
+    unsafe { std::slice::from_raw_parts(items.as_ptr() as *const u8, len) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cast_bytes() {
+        let values = [1.0_f32, -2.5, 3.25];
+        let bytes = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        // a Vec<u8> has no alignment promised, put the values at both an aligned and an
+        // unaligned offset of a f32 aligned buffer
+        let mut backing = vec![0.0_f32; 4];
+        let raw = unsafe {
+            std::slice::from_raw_parts_mut(backing.as_mut_ptr() as *mut u8, backing.len() * 4)
+        };
+        raw[..12].copy_from_slice(&bytes);
+        let aligned = cast_bytes::<f32>(&raw[..12]);
+        assert!(matches!(aligned, Cow::Borrowed(_)));
+        assert_eq!(aligned.as_ref(), &values);
+
+        raw[1..13].copy_from_slice(&bytes);
+        let unaligned = cast_bytes::<f32>(&raw[1..13]);
+        assert!(matches!(unaligned, Cow::Owned(_)));
+        assert_eq!(unaligned.as_ref(), &values);
+        assert_eq!(as_bytes(unaligned.as_ref()), &bytes[..]);
+    }
+}
@@ -1,9 +1,11 @@
 pub mod api;
 pub use api::CpuTensorBuf;
 
+pub mod buf_bf16;
 pub mod buf_f16;
 pub mod buf_f32;
 
+mod bytes;
 mod util;
 
 pub mod buf_q2_k;
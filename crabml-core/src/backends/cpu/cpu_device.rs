@@ -1,16 +1,44 @@
-use std::cell::OnceCell;
-use std::cell::RefCell;
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::OnceLock;
 
 use half::f16;
 
 use super::primitives::gelu_single;
+use super::primitives::RopeCache;
 use super::thread_pool::ThreadPool;
 use super::CpuTensor;
+use crate::backends::available_ram;
+use crate::backends::Backend;
+use crate::backends::BackendCapabilities;
+use crate::backends::BackendOptions;
+use crate::gguf::GGMLType;
+use crate::gguf::SUPPORTED_GGML_TYPES;
+use crate::tensor::RopeMode;
 use crate::tensor::TensorMetrics;
 
+/// the implementation of the large f32/f16 matmuls, the quantized matmuls always run on the
+/// internal kernels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GemmBackend {
+    Internal,
+    /// the BLAS library linked with the `blas` feature, like OpenBLAS, Accelerate or MKL. it
+    /// falls back to the internal kernels if the feature is not enabled.
+    Blas,
+}
+
+impl Default for GemmBackend {
+    fn default() -> Self {
+        if cfg!(feature = "blas") {
+            GemmBackend::Blas
+        } else {
+            GemmBackend::Internal
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CpuTensorDeviceOptions {
     /// when enabled, whenever tensor called with `with_name`, the name and the
@@ -19,7 +47,13 @@ pub struct CpuTensorDeviceOptions {
 
     pub metrics: TensorMetrics,
 
-    pub thread_num: usize,
+    pub backend: BackendOptions,
+
+    pub gemm_backend: GemmBackend,
+
+    /// the matmuls are dispatched to BLAS only if all of the m, n, k are not less than this,
+    /// the gemv on decoding and the small matmuls are faster on the internal kernels.
+    pub blas_min_dim: usize,
 }
 
 impl Default for CpuTensorDeviceOptions {
@@ -27,14 +61,21 @@ impl Default for CpuTensorDeviceOptions {
         Self {
             debug_named_tensors: false,
             metrics: TensorMetrics::default(),
-            thread_num: 1,
+            backend: BackendOptions::default(),
+            gemm_backend: GemmBackend::default(),
+            blas_min_dim: 32,
         }
     }
 }
 
 impl CpuTensorDeviceOptions {
     pub fn with_thread_num(mut self, thread_num: usize) -> Self {
-        self.thread_num = thread_num;
+        self.backend = self.backend.with_threads(thread_num);
+        self
+    }
+
+    pub fn with_backend_options(mut self, backend: BackendOptions) -> Self {
+        self.backend = backend;
         self
     }
 
@@ -47,20 +88,57 @@ impl CpuTensorDeviceOptions {
         self.metrics = metrics;
         self
     }
+
+    pub fn with_gemm_backend(mut self, gemm_backend: GemmBackend) -> Self {
+        self.gemm_backend = gemm_backend;
+        self
+    }
+
+    pub fn with_blas_min_dim(mut self, blas_min_dim: usize) -> Self {
+        self.blas_min_dim = blas_min_dim;
+        self
+    }
+}
+
+thread_local! {
+    static THREAD_NUM_LIMIT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// caps the number of threads used by the tensor operations issued from the current thread,
+/// until the guard is dropped. it allows a low priority session to leave the cores to the
+/// interactive ones which share the same device.
+pub struct ThreadNumLimitGuard {
+    prev: Option<usize>,
+}
+
+impl ThreadNumLimitGuard {
+    pub fn new(limit: usize) -> Self {
+        let prev = THREAD_NUM_LIMIT.with(|l| l.replace(Some(limit.max(1))));
+        Self { prev }
+    }
+}
+
+impl Drop for ThreadNumLimitGuard {
+    fn drop(&mut self) {
+        THREAD_NUM_LIMIT.with(|l| l.set(self.prev));
+    }
 }
 
 #[derive(Debug)]
 pub struct CpuTensorDevice<'a> {
     pub(crate) opts: CpuTensorDeviceOptions,
     pub(crate) metrics: TensorMetrics,
-    pub(crate) debug_tensors: RefCell<HashMap<String, Vec<f32>>>,
-    pub(crate) exp_cache: Rc<Vec<f16>>,
-    pub(crate) gelu_cache: OnceCell<Vec<f16>>,
+    pub(crate) debug_tensors: Mutex<HashMap<String, Vec<f32>>>,
+    pub(crate) exp_cache: Arc<Vec<f16>>,
+    pub(crate) gelu_cache: OnceLock<Vec<f16>>,
+    pub(crate) rope_caches: Mutex<Vec<Arc<RopeCache>>>,
     pub(crate) thread_pool: Mutex<ThreadPool>,
     _phantom: std::marker::PhantomData<&'a ()>,
 }
 
-pub type CpuTensorDeviceRef<'a> = Rc<CpuTensorDevice<'a>>;
+/// the device is shared between the tensors of the model weights, which might be used by many
+/// threads concurrently, so it's kept Send + Sync.
+pub type CpuTensorDeviceRef<'a> = Arc<CpuTensorDevice<'a>>;
 
 impl<'a> CpuTensorDevice<'a> {
     pub fn new() -> CpuTensorDeviceRef<'a> {
@@ -70,25 +148,38 @@ impl<'a> CpuTensorDevice<'a> {
 
     pub fn with_options(opts: CpuTensorDeviceOptions) -> CpuTensorDeviceRef<'a> {
         let metrics = opts.metrics.clone();
-        let thread_pool = Mutex::new(ThreadPool::new(opts.thread_num));
+        let thread_pool = Mutex::new(ThreadPool::new(opts.backend.threads()));
         let device = Self {
             opts,
             metrics,
             thread_pool,
-            debug_tensors: RefCell::new(HashMap::new()),
-            exp_cache: Rc::new(Self::init_exp_cache()),
-            gelu_cache: OnceCell::new(),
+            debug_tensors: Mutex::new(HashMap::new()),
+            exp_cache: Arc::new(Self::init_exp_cache()),
+            gelu_cache: OnceLock::new(),
+            rope_caches: Mutex::new(vec![]),
             _phantom: std::marker::PhantomData,
         };
-        Rc::new(device)
+        Arc::new(device)
     }
 
     pub fn metrics(&self) -> &TensorMetrics {
         &self.metrics
     }
 
+    /// the number of threads to split the work, it's capped by `ThreadNumLimitGuard` in the
+    /// current thread if there's any.
     pub fn thread_num(&self) -> usize {
-        self.opts.thread_num
+        match THREAD_NUM_LIMIT.with(|l| l.get()) {
+            Some(limit) => limit.min(self.opts.backend.threads()),
+            None => self.opts.backend.threads(),
+        }
+    }
+
+    /// whether to run the (m, k) @ (k, n) matmul on BLAS, picked per shape.
+    pub fn use_blas(&self, m: usize, n: usize, k: usize) -> bool {
+        cfg!(feature = "blas")
+            && self.opts.gemm_backend == GemmBackend::Blas
+            && m.min(n).min(k) >= self.opts.blas_min_dim
     }
 
     pub fn thread_pool(&self) -> &Mutex<ThreadPool> {
@@ -96,10 +187,10 @@ impl<'a> CpuTensorDevice<'a> {
     }
 
     pub fn dump_debug_tensor(&self, name: &str) -> Option<Vec<f32>> {
-        self.debug_tensors.borrow().get(name).cloned()
+        self.debug_tensors.lock().unwrap().get(name).cloned()
     }
 
-    pub fn exp_cache(&self) -> Rc<Vec<f16>> {
+    pub fn exp_cache(&self) -> Arc<Vec<f16>> {
         self.exp_cache.clone()
     }
 
@@ -107,6 +198,48 @@ impl<'a> CpuTensorDevice<'a> {
         self.gelu_cache.get_or_init(Self::init_gelu_cache)
     }
 
+    /// precompute the rope tables for the positions up to max_pos, the models sharing this
+    /// device share the tables if they have the same rope shape.
+    pub fn init_rope_cache(
+        &self,
+        mode: RopeMode,
+        head_dim: usize,
+        rope_dim: usize,
+        theta: f32,
+        freq_scale: f32,
+        max_pos: usize,
+    ) {
+        let mut caches = self.rope_caches.lock().unwrap();
+        let existed = caches
+            .iter()
+            .position(|c| c.matches(mode, head_dim, rope_dim, theta, freq_scale));
+        let cache = || {
+            Arc::new(RopeCache::new(
+                mode, head_dim, rope_dim, theta, freq_scale, max_pos,
+            ))
+        };
+        match existed {
+            Some(i) if caches[i].max_pos() >= max_pos => {}
+            Some(i) => caches[i] = cache(),
+            None => caches.push(cache()),
+        }
+    }
+
+    pub(crate) fn rope_cache(
+        &self,
+        mode: RopeMode,
+        head_dim: usize,
+        rope_dim: usize,
+        theta: f32,
+        freq_scale: f32,
+    ) -> Option<Arc<RopeCache>> {
+        let caches = self.rope_caches.lock().unwrap();
+        caches
+            .iter()
+            .find(|c| c.matches(mode, head_dim, rope_dim, theta, freq_scale))
+            .cloned()
+    }
+
     fn init_exp_cache() -> Vec<f16> {
         (0..65536)
             .map(|x| {
@@ -128,7 +261,26 @@ impl<'a> CpuTensorDevice<'a> {
     pub(crate) fn add_debug_tensor(&self, tensor: &CpuTensor<'a>) {
         let buf = tensor.buf().iter_f32().collect::<Vec<_>>();
         self.debug_tensors
-            .borrow_mut()
+            .lock()
+            .unwrap()
             .insert(tensor.name.clone().unwrap(), buf);
     }
 }
+
+impl Backend for CpuTensorDevice<'_> {
+    fn capabilities(&self) -> BackendCapabilities {
+        let dtypes = SUPPORTED_GGML_TYPES.to_vec();
+        let quant_kernels = dtypes
+            .iter()
+            .copied()
+            .filter(|t| !matches!(t, GGMLType::F32 | GGMLType::F16 | GGMLType::BF16))
+            .collect();
+        BackendCapabilities {
+            dtypes,
+            quant_kernels,
+            max_buffer_bytes: None,
+            shader_f16: false,
+            available_memory: available_ram(),
+        }
+    }
+}
@@ -8,11 +8,12 @@ use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
+use crate::tensor::print::format_elements;
 use crate::tensor::RopeMode;
 use crate::tensor::Tensor;
 use crate::tensor::TensorStrider;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct CpuTensor<'a> {
     buf: CpuTensorBuf<'a>,
     strider: TensorStrider,
@@ -28,11 +29,10 @@ pub struct CpuTensor<'a> {
 impl<'a> CpuTensor<'a> {
     pub fn new(buf: Vec<f32>, shape: &[usize], device: CpuTensorDeviceRef<'a>) -> Result<Self> {
         if buf.len() != shape.iter().product() {
-            return Err(Error {
-                kind: ErrorKind::TensorError,
-                message: format!("invalid shape {:?} for data of length {}", shape, buf.len()),
-                cause: None,
-            });
+            return Err(Error::new(
+                ErrorKind::TensorError,
+                format!("invalid shape {:?} for data of length {}", shape, buf.len()),
+            ));
         }
 
         let strider = TensorStrider::new(shape.to_vec());
@@ -50,6 +50,7 @@ impl<'a> CpuTensor<'a> {
         shape: &[usize],
         device: CpuTensorDeviceRef<'a>,
     ) -> Result<Self> {
+        let buf = tensor_bytes(buf, typ, shape)?;
         let buf = CpuTensorBuf::from_raw_bytes(buf, typ)?;
         let strider = TensorStrider::new(shape.to_vec());
         Ok(Self {
@@ -60,6 +61,25 @@ impl<'a> CpuTensor<'a> {
         })
     }
 
+    /// like from_bytes, but the data is loaded into f16, which also takes the integer types
+    /// without kernels, see `CpuTensorBuf::from_raw_bytes_f16`.
+    pub fn from_bytes_f16(
+        buf: &'a [u8],
+        typ: GGMLType,
+        shape: &[usize],
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Self> {
+        let buf = tensor_bytes(buf, typ, shape)?;
+        let buf = CpuTensorBuf::from_raw_bytes_f16(buf, typ)?;
+        let strider = TensorStrider::new(shape.to_vec());
+        Ok(Self {
+            buf,
+            strider,
+            device,
+            name: None,
+        })
+    }
+
     pub fn dequantize(self, dtype: GGMLType) -> Result<Self> {
         let _t = self.device.metrics.dequantize_walltime.track();
         let strider = self.strider.clone();
@@ -120,6 +140,295 @@ impl<'a> CpuTensor<'a> {
     pub(crate) fn buf_mut(&mut self) -> &mut CpuTensorBuf<'a> {
         &mut self.buf
     }
+
+    /// a view of len elements from start on the dim, which borrows the buffer of this tensor
+    /// without copying. the strides are kept, so the view is only contiguous if it's sliced on
+    /// the outermost dim. the view is read-only like the mmaped weights, `dup()` a contiguous
+    /// view to write into it.
+    pub fn slice(&self, dim: usize, start: usize, len: usize) -> Result<CpuTensor<'_>> {
+        let shape = self.shape();
+        if dim >= shape.len() || start + len > shape[dim] {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "slice: [{}, {}) is out of the dim {} of the shape {:?}",
+                    start,
+                    start + len,
+                    dim,
+                    shape
+                ),
+            )
+                .into());
+        }
+
+        let mut new_shape = shape.to_vec();
+        new_shape[dim] = len;
+        let strider = self.strider.resize(&new_shape)?;
+        // the view covers the buffer from its first element to its last one
+        let (offset, span) = if strider.is_empty() {
+            (0, 0)
+        } else {
+            let last = new_shape
+                .iter()
+                .zip(strider.strides())
+                .map(|(n, stride)| (n - 1) * stride)
+                .sum::<usize>();
+            (start * self.strider.strides()[dim], last + 1)
+        };
+        Ok(CpuTensor {
+            buf: self.buf.slice(offset, span)?,
+            strider,
+            device: self.device.clone(),
+            name: None,
+        })
+    }
+
+    /// selects the elements where the mask is non-zero into a 1-D tensor, in the row-major
+    /// order. the mask must have the same shape with this tensor.
+    pub fn masked_select(&self, mask: &CpuTensor<'a>) -> Result<Self> {
+        let buf = primitives::masked_select(&self.buf, &self.strider, &mask.buf, &mask.strider)?;
+        let len = buf.len();
+        CpuTensor::new(buf, &[len], self.device())
+    }
+
+    /// returns the indices of the non-zero elements as a (n, dims) tensor. the indices are kept
+    /// in f32, which are exact as long as they are less than 2^24.
+    pub fn nonzero(&self) -> Result<Self> {
+        let indices = primitives::nonzero(&self.buf, &self.strider)?;
+        let dims = self.strider.dims();
+        let buf = indices.iter().map(|i| *i as f32).collect::<Vec<_>>();
+        CpuTensor::new(buf, &[indices.len() / dims.max(1), dims], self.device())
+    }
+
+    /// the indices and the values of the largest elements along the dim, the dim is kept with
+    /// the size 1. the indices are kept in f32 like `nonzero`.
+    pub fn argmax(&self, dim: usize) -> Result<(Self, Self)> {
+        self.topk(1, dim)
+    }
+
+    /// the indices and the values of the k largest elements along the dim, in the descending
+    /// order of the values. the k largest are selected without sorting the whole dim.
+    pub fn topk(&self, k: usize, dim: usize) -> Result<(Self, Self)> {
+        let (indices, values) = primitives::topk(&self.buf, &self.strider, k, dim)?;
+        let mut shape = self.shape().to_vec();
+        shape[dim] = k;
+        let indices = indices.iter().map(|i| *i as f32).collect::<Vec<_>>();
+        Ok((
+            CpuTensor::new(indices, &shape, self.device())?,
+            CpuTensor::new(values, &shape, self.device())?,
+        ))
+    }
+
+    /// concatenate the 2-D tensors along the rows into an owned tensor, like fusing the Q, K
+    /// and V weights into a single matmul. the quantized blocks are copied without dequantizing.
+    pub fn concat_rows(tensors: &[&CpuTensor<'a>]) -> Result<Self> {
+        let cols = match tensors.first() {
+            Some(t) if t.strider.dims() == 2 => t.shape()[1],
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    "concat_rows: expect at least one 2-D tensor",
+                )
+                    .into());
+            }
+        };
+        for t in tensors {
+            if t.strider.dims() != 2 || t.shape()[1] != cols || !t.is_contiguous() {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!(
+                        "concat_rows: expect contiguous 2-D tensors with {} columns, but got {:?}",
+                        cols,
+                        t.shape()
+                    ),
+                )
+                    .into());
+            }
+        }
+
+        let rows = tensors.iter().map(|t| t.shape()[0]).sum::<usize>();
+        let bufs = tensors.iter().map(|t| &t.buf).collect::<Vec<_>>();
+        let buf = CpuTensorBuf::concat(&bufs)?;
+        Ok(Self {
+            buf,
+            strider: TensorStrider::new(vec![rows, cols]),
+            device: tensors[0].device(),
+            name: None,
+        })
+    }
+
+    /// concatenate the tensors along the dim into an owned tensor, the tensors must have the
+    /// same shape except on the dim. the strided views are copied in their logical order.
+    pub fn concat(tensors: &[&CpuTensor<'a>], dim: usize) -> Result<Self> {
+        let shape = match tensors.first() {
+            Some(t) if dim < t.strider.dims() => t.shape(),
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("concat: expect at least one tensor with the dim {}", dim),
+                )
+                    .into());
+            }
+        };
+        for t in tensors {
+            let matched = t.strider.dims() == shape.len()
+                && (0..shape.len()).all(|i| i == dim || t.shape()[i] == shape[i]);
+            if !matched || t.dtype() != GGMLType::F32 {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!(
+                        "concat: expect f32 tensors of the shape {:?} except on the dim {}, but \
+                         got {:?} in {}",
+                        shape,
+                        dim,
+                        t.shape(),
+                        t.dtype()
+                    ),
+                )
+                    .into());
+            }
+        }
+
+        let mut out_shape = shape.to_vec();
+        out_shape[dim] = tensors.iter().map(|t| t.shape()[dim]).sum();
+        let buf = Self::interleave(tensors, shape[..dim].iter().product());
+        CpuTensor::new(buf, &out_shape, tensors[0].device())
+    }
+
+    /// stack the tensors of the same shape along a new dim into an owned tensor, like
+    /// stacking k tensors of (n, d) on the dim 0 into (k, n, d).
+    pub fn stack(tensors: &[&CpuTensor<'a>], dim: usize) -> Result<Self> {
+        let shape = match tensors.first() {
+            Some(t) if dim <= t.strider.dims() => t.shape(),
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("stack: expect at least one tensor with the dim {}", dim),
+                )
+                    .into());
+            }
+        };
+        for t in tensors {
+            if t.shape() != shape || t.dtype() != GGMLType::F32 {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!(
+                        "stack: expect f32 tensors of the shape {:?}, but got {:?} in {}",
+                        shape,
+                        t.shape(),
+                        t.dtype()
+                    ),
+                )
+                    .into());
+            }
+        }
+
+        let mut out_shape = shape.to_vec();
+        out_shape.insert(dim, tensors.len());
+        let buf = Self::interleave(tensors, shape[..dim].iter().product());
+        CpuTensor::new(buf, &out_shape, tensors[0].device())
+    }
+
+    // split each tensor into the outer blocks in its logical order, and take the i-th block of
+    // all the tensors in turn.
+    fn interleave(tensors: &[&CpuTensor<'a>], outer: usize) -> Vec<f32> {
+        let values = tensors
+            .iter()
+            .map(|t| match t.is_contiguous() {
+                true => Cow::Borrowed(&t.buf.as_f32_ref()[..t.len()]),
+                false => {
+                    let buf = t.buf.as_f32_ref();
+                    Cow::Owned(t.strider.iter().map(|pos| buf[pos]).collect::<Vec<_>>())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut buf = Vec::with_capacity(values.iter().map(|v| v.len()).sum());
+        for i in 0..outer {
+            for v in values.iter() {
+                let block = v.len() / outer;
+                buf.extend_from_slice(&v[i * block..(i + 1) * block]);
+            }
+        }
+        buf
+    }
+
+    /// repack a 2-D weight into the layout consumed by the register blocked matmul kernels at
+    /// load time. the packed weight is only used as the lhs of matmul_vec, so only pack the
+    /// weights of the linear layers. the tensors which can not be packed are returned as is.
+    pub fn prepack(self) -> Result<Self> {
+        if self.strider.dims() != 2 || !self.is_contiguous() {
+            return Ok(self);
+        }
+        let (rows, cols) = (self.shape()[0], self.shape()[1]);
+        match self.buf.pack(rows, cols) {
+            Some(buf) => Ok(Self { buf, ..self }),
+            None => Ok(self),
+        }
+    }
+
+    /// quantize a 2-D weight into int8 with a scale per row for the W8A8 matmul, the
+    /// activations are quantized per token in matmul_vec, see `Int8Matrix`. like prepack, only
+    /// the weights of the linear layers are meant to be converted.
+    pub fn quantize_w8a8(self) -> Result<Self> {
+        if self.strider.dims() != 2 || !self.is_contiguous() {
+            return Ok(self);
+        }
+        let (rows, cols) = (self.shape()[0], self.shape()[1]);
+        let buf = self.buf.quantize_w8a8(rows, cols)?;
+        Ok(Self { buf, ..self })
+    }
+
+    // the elementwise ops update the lhs in place, the lhs is only copied into the broadcasted
+    // shape if the rhs is larger than it on some dims, like a (n, 1) column plus a (dim, ) row.
+    fn broadcast_lhs(self, rhs: &CpuTensor<'a>) -> Result<Self> {
+        let shape = TensorStrider::broadcast_shape(self.shape(), rhs.shape())?;
+        if shape == self.shape() {
+            return Ok(self);
+        }
+        let strider = self.strider.broadcast_to(&shape)?;
+        let buf = self.buf.as_f32_ref();
+        let buf = strider.iter().map(|pos| buf[pos]).collect::<Vec<_>>();
+        CpuTensor::new(buf, &shape, self.device())
+    }
+
+    /// prints all the elements without summarizing, mostly used in tests.
+    pub fn to_string_full(&self) -> String {
+        self.format(true)
+    }
+
+    fn format(&self, full: bool) -> String {
+        let name = match &self.name {
+            Some(name) => format!("name={:?}, ", name),
+            None => "".to_string(),
+        };
+        let header = format!(
+            "CpuTensor({}dtype={}, shape={:?}, strides={:?})",
+            name,
+            self.dtype(),
+            self.shape(),
+            self.strider.strides()
+        );
+        // the quantized elements are dequantized one by one, only the visible ones are read
+        let body = format_elements(
+            self.shape(),
+            |idx| self.buf.dequantize_at(self.strider.at_unchecked(idx)),
+            full,
+        );
+        format!("{}\n{}", header, body)
+    }
+}
+
+impl std::fmt::Display for CpuTensor<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(false))
+    }
+}
+
+impl std::fmt::Debug for CpuTensor<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(false))
+    }
 }
 
 impl<'a> Tensor for CpuTensor<'a> {
@@ -349,23 +658,88 @@ impl<'a> Tensor for CpuTensor<'a> {
         Ok(())
     }
 
+    fn import(&mut self, src: &[f32]) -> Result<()> {
+        if !self.is_owned() || !self.is_contiguous() {
+            return Err((
+                ErrorKind::TensorError,
+                "import: the tensor is not owned or contiguous",
+            )
+                .into());
+        }
+        if src.len() != self.strider.len() {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "import: {} values into a tensor of shape {:?}",
+                    src.len(),
+                    self.shape()
+                ),
+            )
+                .into());
+        }
+        self.buf.copy_from_iter(src.iter().cloned(), 0, src.len());
+        Ok(())
+    }
+
     // (b, m, k) @ (b, k, n) -> (b, m, n)
+    // (b, m, k) @ (k, n) -> (b, m, n)
     fn batch_matmul(&self, b: &CpuTensor<'a>) -> Result<Self> {
+        let invalid = || -> Error {
+            (
+                ErrorKind::TensorError,
+                format!(
+                    "can not batch_matmul the shapes {:?} and {:?}",
+                    self.shape(),
+                    b.shape()
+                ),
+            )
+                .into()
+        };
+        // a 2D B is shared by all the batches of A
+        let strider2 = match b.shape().len() {
+            3 => b.strider().clone(),
+            2 => b.strider().broadcast_to(&[1, b.shape()[0], b.shape()[1]])?,
+            _ => return Err(invalid()),
+        };
+        let (b_batch, k, n) = (
+            strider2.shape()[0],
+            strider2.shape()[1],
+            strider2.shape()[2],
+        );
+        let a_batch = self.shape()[0];
+        if self.shape().len() != 3 || self.shape()[2] != k || b_batch == 0 || a_batch % b_batch != 0
+        {
+            return Err(invalid());
+        }
+
         let bufa = self.buf();
         let bufb = b.buf();
         let _t = self.device.metrics.batch_matmul_walltime.track();
-        let mut c = CpuTensor::alloc(
-            &[self.shape()[0], self.shape()[1], b.shape()[2]],
-            GGMLType::F32,
-            self.device(),
-        )?;
+        let mut c = CpuTensor::alloc(&[a_batch, self.shape()[1], n], GGMLType::F32, self.device())?;
         let bufc = c.buf_mut();
         let strider1 = self.strider();
-        let strider2 = b.strider();
-        primitives::batch_matmul(&self.device(), bufa, bufb, bufc, strider1, strider2);
+        primitives::batch_matmul(&self.device(), bufa, bufb, bufc, strider1, &strider2);
         Ok(c)
     }
 
+    fn flash_attention(&self, k: &CpuTensor<'a>, v: &CpuTensor<'a>, scale: f32) -> Result<Self> {
+        // counted as the batch matmuls of the attention it fuses
+        let _t = self.device.metrics.batch_matmul_walltime.track();
+        let mut o = CpuTensor::alloc(self.shape(), GGMLType::F32, self.device())?;
+        primitives::flash_attention(
+            &self.device(),
+            self.buf().as_f32_ref(),
+            k.buf(),
+            v.buf(),
+            o.buf_mut().as_f32_mut(),
+            self.strider(),
+            k.strider(),
+            v.strider(),
+            scale,
+        )?;
+        Ok(o)
+    }
+
     // gemv
     // (m, k) @ (k, ) => (m, )
     // (m, k) @ (b, k) => (b, m, )
@@ -382,39 +756,46 @@ impl<'a> Tensor for CpuTensor<'a> {
         let strider1 = self.strider();
         let strider2 = x.strider();
         // let _t = self.device.metrics.matmul_walltime.track();
-        primitives::matmul_vec(&self.device, bufa, bufb, bufc, strider1, strider2);
+        primitives::matmul_vec(&self.device, bufa, bufb, bufc, strider1, strider2)?;
         Ok(c)
     }
 
-    fn mul_inplace(mut self, rhs: &CpuTensor<'a>) -> Result<Self> {
-        let strider1 = self.strider().clone();
+    fn mul_inplace(self, rhs: &CpuTensor<'a>) -> Result<Self> {
+        let mut lhs = self.broadcast_lhs(rhs)?;
+        let strider1 = lhs.strider().clone();
         let strider2 = rhs.strider();
-        let _t = self.device.metrics.mul_walltime.track();
-        primitives::mul_inplace(self.buf_mut(), rhs.buf(), &strider1, strider2)?;
-        Ok(self)
+        let _t = lhs.device.metrics.mul_walltime.track();
+        primitives::mul_inplace(lhs.buf_mut(), rhs.buf(), &strider1, strider2)?;
+        Ok(lhs)
     }
 
-    fn add_inplace(mut self, b: &Self) -> Result<Self> {
-        let strider1 = self.strider().clone();
+    fn add_inplace(self, b: &Self) -> Result<Self> {
+        let mut lhs = self.broadcast_lhs(b)?;
+        let strider1 = lhs.strider().clone();
         let strider2 = b.strider();
-        let _t = self.device.metrics.add_walltime.track();
-        primitives::add_inplace(self.buf_mut(), b.buf(), &strider1, strider2)?;
-        Ok(self)
+        let _t = lhs.device.metrics.add_walltime.track();
+        primitives::add_inplace(lhs.buf_mut(), b.buf(), &strider1, strider2)?;
+        Ok(lhs)
+    }
+
+    fn div_inplace(self, b: &Self) -> Result<Self> {
+        let mut lhs = self.broadcast_lhs(b)?;
+        let strider1 = lhs.strider().clone();
+        let strider2 = b.strider();
+        primitives::div_inplace(lhs.buf_mut(), b.buf(), &strider1, strider2)?;
+        Ok(lhs)
     }
 
     fn div_scalar_inplace(mut self, b: f32) -> Result<Self> {
-        let rhs = CpuTensor::new(vec![b], &[1], self.device())?;
         let strider1 = self.strider().clone();
-        let strider2 = rhs.strider();
-        primitives::div_inplace(self.buf_mut(), rhs.buf(), &strider1, strider2)?;
+        primitives::div_scalar_inplace(self.buf_mut(), &strider1, b)?;
         Ok(self)
     }
 
     fn scale_inplace(mut self, rhs: f32) -> Result<Self> {
-        let rhs = CpuTensor::new(vec![rhs], &[1], self.device())?;
         let strider1 = self.strider().clone();
-        let strider2 = rhs.strider();
-        primitives::mul_inplace(self.buf_mut(), rhs.buf(), &strider1, strider2)?;
+        let _t = self.device.metrics.mul_walltime.track();
+        primitives::scale_inplace(self.buf_mut(), &strider1, rhs)?;
         Ok(self)
     }
 
@@ -437,14 +818,146 @@ impl<'a> Tensor for CpuTensor<'a> {
         Ok(self)
     }
 
-    fn rope_inplace(mut self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn log_softmax_inplace(mut self, axis: usize) -> Result<Self> {
+        let _t = self.device.metrics.softmax_walltime.track();
+        let strider1 = self.strider().clone();
+        primitives::log_softmax_inplace(self.buf_mut(), strider1, axis)?;
+        Ok(self)
+    }
+
+    fn causal_mask_inplace(mut self) -> Result<Self> {
+        let strider1 = self.strider().clone();
+        primitives::causal_mask_inplace(self.buf_mut(), &strider1)?;
+        Ok(self)
+    }
+
+    fn tree_mask_inplace(mut self, parents: &[Option<usize>]) -> Result<Self> {
+        let strider1 = self.strider().clone();
+        primitives::tree_mask_inplace(self.buf_mut(), &strider1, parents)?;
+        Ok(self)
+    }
+
+    fn rope_positions_inplace(
+        mut self,
+        mode: RopeMode,
+        positions: &[usize],
+        rope_dims: usize,
+        theta: f32,
+        freq_scale: f32,
+    ) -> Result<Self> {
+        let _t = self.device.metrics.rope_walltime.track();
+        let strider1 = self.strider().clone();
+        let head_dim = strider1.shape()[strider1.dims() - 1];
+        let cache = self
+            .device
+            .rope_cache(mode, head_dim, rope_dims, theta, freq_scale);
+        let buf1 = self.buf_mut();
+        primitives::rope_positions_inplace(
+            buf1,
+            &strider1,
+            mode,
+            positions,
+            rope_dims,
+            theta,
+            freq_scale,
+            cache.as_deref(),
+        )?;
+        Ok(self)
+    }
+
+    fn rope_inplace(
+        mut self,
+        mode: RopeMode,
+        pos: usize,
+        rope_dims: usize,
+        theta: f32,
+        freq_scale: f32,
+    ) -> Result<Self> {
         let _t = self.device.metrics.rope_walltime.track();
         let strider1 = self.strider().clone();
+        let head_dim = strider1.shape()[strider1.dims() - 1];
+        let cache = self
+            .device
+            .rope_cache(mode, head_dim, rope_dims, theta, freq_scale);
         let buf1 = self.buf_mut();
-        primitives::rope_inplace(buf1, &strider1, mode, pos, rope_dims)?;
+        primitives::rope_inplace(
+            buf1,
+            &strider1,
+            mode,
+            pos,
+            rope_dims,
+            theta,
+            freq_scale,
+            cache.as_deref(),
+        )?;
         Ok(self)
     }
 
+    fn split_last_dim(&self, sizes: &[usize]) -> Result<Vec<Self>> {
+        let shape = self.shape();
+        let last_dim = shape[shape.len() - 1];
+        if sizes.iter().sum::<usize>() != last_dim {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "split_last_dim: sizes {:?} mismatch the shape {:?}",
+                    sizes, shape
+                ),
+            )
+                .into());
+        }
+        if !self.is_contiguous() || self.dtype() != GGMLType::F32 {
+            return Err((
+                ErrorKind::TensorError,
+                "split_last_dim: only contiguous f32 tensors are supported",
+            )
+                .into());
+        }
+
+        let src = self.buf.as_f32_ref();
+        let n_rows = self.len() / last_dim;
+        let mut offset = 0;
+        let mut outputs = Vec::with_capacity(sizes.len());
+        for &size in sizes {
+            let mut buf = Vec::with_capacity(n_rows * size);
+            for row in src.chunks_exact(last_dim) {
+                buf.extend_from_slice(&row[offset..offset + size]);
+            }
+            let mut out_shape = shape.to_vec();
+            *out_shape.last_mut().unwrap() = size;
+            outputs.push(CpuTensor::new(buf, &out_shape, self.device())?);
+            offset += size;
+        }
+        Ok(outputs)
+    }
+
+    fn init_rope_cache(
+        device: &Self::Device,
+        mode: RopeMode,
+        head_dim: usize,
+        rope_dims: usize,
+        theta: f32,
+        freq_scale: f32,
+        max_pos: usize,
+    ) -> Result<()> {
+        device.init_rope_cache(mode, head_dim, rope_dims, theta, freq_scale, max_pos);
+        Ok(())
+    }
+
+    // only the weights borrowed from the model file are prefetched, the owned and packed
+    // buffers are already in memory
+    fn prefetch(&self) -> Result<()> {
+        if self.buf.is_owned() || self.buf.is_packed() {
+            return Ok(());
+        }
+        let bytes = self.buf.as_raw_bytes()?;
+        #[cfg(unix)]
+        crate::unix_memory::prefetch(bytes)?;
+        #[cfg(windows)]
+        crate::win_memory::prefetch(bytes)?;
+        Ok(())
+    }
+
     fn rms_norm_inplace(mut self, eps: f32) -> Result<Self> {
         let _t = self.device.metrics.rms_norm_walltime.track();
         let strider1 = self.strider().clone();
@@ -454,12 +967,35 @@ impl<'a> Tensor for CpuTensor<'a> {
     }
 }
 
+// the bytes of the tensor in the shape. the data of a tensor in a GGUF file runs up to the next
+// tensor, the trailing alignment padding is cut off, and the data of a malformed file may be
+// shorter than the shape.
+fn tensor_bytes<'a>(buf: &'a [u8], typ: GGMLType, shape: &[usize]) -> Result<&'a [u8]> {
+    let n_elems: usize = shape.iter().product();
+    let len = CpuTensorBuf::raw_bytes_len(typ, n_elems)?;
+    if buf.len() < len {
+        return Err(Error::new(
+            ErrorKind::FormatError,
+            format!(
+                "the tensor of {} in shape {:?} takes {} bytes, but got {} bytes",
+                typ,
+                shape,
+                len,
+                buf.len()
+            ),
+        ));
+    }
+    Ok(&buf[..len])
+}
+
 #[cfg(test)]
 mod tests {
     use approx::assert_relative_eq;
 
     use super::*;
     use crate::backends::cpu::CpuTensorDevice;
+    use crate::backends::cpu::CpuTensorDeviceOptions;
+    use crate::gguf::GGUFFileLoader;
 
     #[test]
     fn test_tensor_view() -> Result<()> {
@@ -472,6 +1008,99 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tensor_display() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3], device.clone())?;
+        let t = t.transpose(&[1, 0])?.with_name("t".to_string());
+        assert_eq!(
+            t.to_string(),
+            "CpuTensor(name=\"t\", dtype=F32, shape=[3, 2], strides=[1, 3])\n[[1.0000, 4.0000],\n [2.0000, 5.0000],\n [3.0000, 6.0000]]"
+        );
+
+        let t = CpuTensor::new(vec![0.0; 2048], &[2, 1024], device)?;
+        assert_eq!(t.to_string().lines().count(), 3);
+        assert!(t.to_string().contains(", ..., "));
+        assert!(!t.to_string_full().contains("..."));
+
+        // the quantized weights print their dequantized corners
+        let data = (0..64 * 64).map(|i| (i % 64) as f32).collect::<Vec<_>>();
+        let w = CpuTensor {
+            buf: CpuTensorBuf::from(data).quantize(GGMLType::Q8_0)?,
+            strider: TensorStrider::new(vec![64, 64]),
+            device,
+            name: None,
+        };
+        let s = w.to_string();
+        assert!(s.starts_with("CpuTensor(dtype=Q8_0, shape=[64, 64], strides=[64, 1])\n"));
+        assert_eq!(s.lines().count(), 8);
+        let w_f32 = w.dequantize(GGMLType::F32)?.to_string();
+        assert_eq!(
+            s.lines().skip(1).collect::<Vec<_>>(),
+            w_f32.lines().skip(1).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_masked_select_and_nonzero() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3], device.clone())?;
+        let mask = CpuTensor::new(vec![0.0, 1.0, 0.0, 1.0, 1.0, 0.0], &[2, 3], device.clone())?;
+
+        let selected = t.masked_select(&mask)?;
+        assert_eq!(selected.shape(), &[3]);
+        assert_eq!(selected.to_vec(), vec![2.0, 4.0, 5.0]);
+
+        let indices = mask.nonzero()?;
+        assert_eq!(indices.shape(), &[3, 2]);
+        assert_eq!(indices.to_vec(), vec![0.0, 1.0, 1.0, 0.0, 1.0, 1.0]);
+
+        // the elements are visited in the logical order of a transposed tensor
+        let selected = t
+            .clone()
+            .transpose(&[1, 0])?
+            .masked_select(&mask.transpose(&[1, 0])?)?;
+        assert_eq!(selected.to_vec(), vec![4.0, 2.0, 5.0]);
+
+        let empty = CpuTensor::new(vec![0.0; 4], &[4], device.clone())?.nonzero()?;
+        assert_eq!(empty.shape(), &[0, 1]);
+
+        let mask = CpuTensor::new(vec![1.0; 4], &[4], device)?;
+        assert!(t.masked_select(&mask).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_argmax_and_topk() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t = CpuTensor::new(vec![1.0, 5.0, 3.0, 6.0, 2.0, 4.0], &[2, 3], device.clone())?;
+
+        let (indices, values) = t.argmax(1)?;
+        assert_eq!(indices.shape(), &[2, 1]);
+        assert_eq!(indices.to_vec(), vec![1.0, 0.0]);
+        assert_eq!(values.to_vec(), vec![5.0, 6.0]);
+
+        let (indices, values) = t.topk(2, 1)?;
+        assert_eq!(indices.shape(), &[2, 2]);
+        assert_eq!(indices.to_vec(), vec![1.0, 2.0, 0.0, 2.0]);
+        assert_eq!(values.to_vec(), vec![5.0, 3.0, 6.0, 4.0]);
+
+        // along the columns, the dim 0 is replaced by k
+        let (indices, values) = t.topk(2, 0)?;
+        assert_eq!(indices.shape(), &[2, 3]);
+        assert_eq!(indices.to_vec(), vec![1.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(values.to_vec(), vec![6.0, 5.0, 4.0, 1.0, 2.0, 3.0]);
+
+        let (indices, _) = t.clone().transpose(&[1, 0])?.argmax(0)?;
+        assert_eq!(indices.to_vec(), vec![1.0, 0.0]);
+
+        assert!(t.topk(4, 1).is_err());
+        assert!(t.topk(0, 1).is_err());
+        assert!(t.argmax(2).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_copy_from() -> Result<()> {
         // 1 2
@@ -511,6 +1140,14 @@ mod tests {
             0.999995, 0.999995, 0.999995, 0.999995, 0.999995, 0.999995
         ]);
 
+        // the op normalizes each row, the rows of 40 are not a multiple of the SIMD lanes
+        let device = CpuTensorDevice::new();
+        let v = (0..80).map(|i| (i % 13) as f32 - 6.0).collect::<Vec<_>>();
+        let t1 = CpuTensor::new(v.clone(), &[2, 40], device.clone())?;
+        let t1 = t1.rms_norm_inplace(1e-5)?;
+        let mut want = v;
+        want.chunks_mut(40).for_each(simple_rmsnorm);
+        assert_relative_eq!(&t1.to_vec()[..], &want[..], epsilon = 1e-5);
         Ok(())
     }
 
@@ -520,7 +1157,7 @@ mod tests {
         let v1 = (0..32).map(|v| v as f32).collect::<Vec<_>>();
         let t1 = CpuTensor::new(v1, &[2, 16], device.clone())?;
 
-        let r1 = t1.rope_inplace(RopeMode::Llama, 1, 2)?;
+        let r1 = t1.rope_inplace(RopeMode::Llama, 1, 2, 10000.0, 1.0)?;
         let out = r1.to_vec();
         assert_relative_eq!(
             &out[..],
@@ -535,6 +1172,43 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_concat_rows_and_split() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let wq = CpuTensor::new(vec![1.0; 64], &[2, 32], device.clone())?;
+        let wk = CpuTensor::new(vec![2.0; 32], &[1, 32], device.clone())?;
+        let wv = CpuTensor::new(vec![3.0; 32], &[1, 32], device.clone())?;
+        let x = CpuTensor::new(vec![1.0; 32], &[1, 32], device.clone())?;
+
+        let wqkv = CpuTensor::concat_rows(&[&wq, &wk, &wv])?;
+        assert_eq!(wqkv.shape(), &[4, 32]);
+        let qkv = wqkv.matmul_vec(&x)?;
+        let chunks = qkv.split_last_dim(&[2, 1, 1])?;
+        assert_eq!(chunks[0].to_vec(), wq.matmul_vec(&x)?.to_vec());
+        assert_eq!(chunks[1].to_vec(), wk.matmul_vec(&x)?.to_vec());
+        assert_eq!(chunks[2].to_vec(), vec![96.0]);
+        assert!(qkv.split_last_dim(&[2, 1]).is_err());
+
+        // the quantized blocks are concatenated as is
+        let wq_q8 = CpuTensor {
+            buf: wq.buf.quantize(GGMLType::Q8_0)?,
+            strider: wq.strider.clone(),
+            device: device.clone(),
+            name: None,
+        };
+        let wk_q8 = CpuTensor {
+            buf: wk.buf.quantize(GGMLType::Q8_0)?,
+            strider: wk.strider.clone(),
+            device: device.clone(),
+            name: None,
+        };
+        let wqk_q8 = CpuTensor::concat_rows(&[&wq_q8, &wk_q8])?;
+        assert_eq!(wqk_q8.dtype(), GGMLType::Q8_0);
+        assert_eq!(wqk_q8.shape(), &[3, 32]);
+        assert!(CpuTensor::concat_rows(&[&wq_q8, &wk]).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_matmul() -> Result<()> {
         let device = CpuTensorDevice::new();
@@ -546,6 +1220,221 @@ mod tests {
             12.0, 12.0
         ]);
 
+        // the f16 rhs is neither f32 nor the rhs dtype of the f32 lhs
+        let b16 = CpuTensor {
+            buf: CpuTensorBuf::from(vec![1.0, 2.0]).quantize(GGMLType::F16)?,
+            strider: TensorStrider::new(vec![2]),
+            device: device.clone(),
+            name: None,
+        };
+        let err = w.matmul_vec(&b16).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::TensorError);
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_quantized() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let data = (0..4 * 64)
+            .map(|i| ((i * 7) % 23) as f32 / 4.0 - 2.5)
+            .collect::<Vec<_>>();
+        let b = (0..64)
+            .map(|i| ((i * 5) % 11) as f32 / 8.0 - 0.5)
+            .collect::<Vec<_>>();
+        let b = CpuTensor::new(b, &[64], device.clone())?;
+
+        // the activation is quantized on the fly to the rhs dtype of the weights, the result
+        // is close to the one on the dequantized weights
+        for typ in [GGMLType::Q8_0, GGMLType::Q4_0, GGMLType::Q4_1] {
+            let w = CpuTensor {
+                buf: CpuTensorBuf::from(data.clone()).quantize(typ)?,
+                strider: TensorStrider::new(vec![4, 64]),
+                device: device.clone(),
+                name: None,
+            };
+            let out = w.matmul_vec(&b)?.to_vec();
+            let w_f32 = w.dequantize(GGMLType::F32)?;
+            let want = w_f32.matmul_vec(&b)?.to_vec();
+            let bound = w_f32
+                .to_vec()
+                .chunks(64)
+                .map(|row| {
+                    row.iter()
+                        .zip(b.to_vec())
+                        .map(|(x, y)| (x * y).abs())
+                        .sum::<f32>()
+                })
+                .collect::<Vec<_>>();
+            for ((o, w), bound) in out.iter().zip(want.iter()).zip(bound.iter()) {
+                assert!((o - w).abs() <= 0.02 * bound, "{:?}: {} vs {}", typ, o, w);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_matmul_threads() -> Result<()> {
+        // the 5 batches are split over the 3 threads unevenly
+        let a = (0..5 * 2 * 8)
+            .map(|i| ((i * 7) % 13) as f32 / 4.0)
+            .collect::<Vec<_>>();
+        let b = (0..5 * 8 * 3)
+            .map(|i| ((i * 5) % 11) as f32 / 8.0)
+            .collect::<Vec<_>>();
+        let run = |thread_num: usize, dtype: GGMLType, transposed: bool| -> Result<Vec<f32>> {
+            let opts = CpuTensorDeviceOptions::default().with_thread_num(thread_num);
+            let device = CpuTensorDevice::with_options(opts);
+            let ta = CpuTensor::new(a.clone(), &[5, 2, 8], device.clone())?;
+            let shape = if transposed {
+                vec![5, 3, 8]
+            } else {
+                vec![5, 8, 3]
+            };
+            let mut tb = CpuTensor {
+                buf: CpuTensorBuf::from(b.clone()).quantize(dtype)?,
+                strider: TensorStrider::new(shape),
+                device: device.clone(),
+                name: None,
+            };
+            if transposed {
+                tb = tb.transpose(&[0, 2, 1])?;
+            }
+            Ok(ta.batch_matmul(&tb)?.to_vec())
+        };
+        for dtype in [GGMLType::F32, GGMLType::F16] {
+            for transposed in [false, true] {
+                let want = run(1, dtype, transposed)?;
+                assert_eq!(want.len(), 5 * 2 * 3);
+                assert_eq!(run(3, dtype, transposed)?, want);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_matmul_broadcast() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // the reference of (bA, m, k) @ (bB, k, n), each batch of B is shared by bA / bB batches
+        let reference = |a: &[f32], b: &[f32], shape: [usize; 5]| -> Vec<f32> {
+            let [a_batch, b_batch, m, k, n] = shape;
+            let mut c = vec![0.0; a_batch * m * n];
+            for bi in 0..a_batch {
+                let bj = bi / (a_batch / b_batch);
+                for mi in 0..m {
+                    for ni in 0..n {
+                        c[bi * m * n + mi * n + ni] = (0..k)
+                            .map(|ki| a[bi * m * k + mi * k + ki] * b[bj * k * n + ki * n + ni])
+                            .sum();
+                    }
+                }
+            }
+            c
+        };
+        let a = (0..4 * 2 * 3)
+            .map(|i| ((i * 7) % 13) as f32 / 4.0)
+            .collect::<Vec<_>>();
+        let b = (0..2 * 3 * 5)
+            .map(|i| ((i * 5) % 11) as f32 / 8.0)
+            .collect::<Vec<_>>();
+
+        for dtype in [GGMLType::F32, GGMLType::F16] {
+            let tb = |shape: &[usize]| -> Result<CpuTensor> {
+                Ok(CpuTensor {
+                    buf: CpuTensorBuf::from(b.clone()).quantize(dtype)?,
+                    strider: TensorStrider::new(shape.to_vec()),
+                    device: device.clone(),
+                    name: None,
+                })
+            };
+
+            // (4, 2, 3) @ (3, 5), the 2D B is shared by all the batches
+            let ta = CpuTensor::new(a.clone(), &[4, 2, 3], device.clone())?;
+            let got = ta.batch_matmul(&tb(&[3, 5])?)?;
+            assert_eq!(got.shape(), &[4, 2, 5]);
+            assert_eq!(got.to_vec(), reference(&a, &b, [4, 1, 2, 3, 5]));
+
+            // (4, 2, 3) @ (2, 3, 5), the batches 0, 1 of A take the batch 0 of B
+            let got = ta.batch_matmul(&tb(&[2, 3, 5])?)?;
+            assert_eq!(got.to_vec(), reference(&a, &b, [4, 2, 2, 3, 5]));
+
+            // A in a transposed view gives the same result as its contiguous copy
+            let ta_t = CpuTensor::new(a.clone(), &[2, 4, 3], device.clone())?;
+            let ta_t = ta_t.transpose(&[1, 0, 2])?;
+            assert!(!ta_t.is_contiguous());
+            let want = ta_t.clone().contiguous()?;
+            let want = want.batch_matmul(&tb(&[2, 3, 5])?)?.to_vec();
+            assert_eq!(ta_t.batch_matmul(&tb(&[2, 3, 5])?)?.to_vec(), want);
+
+            // the inner dims differ, or the batches of B do not divide the batches of A
+            assert!(ta.batch_matmul(&tb(&[5, 3])?).is_err());
+            let tb3 = CpuTensor::new(vec![0.0; 3 * 3 * 5], &[3, 3, 5], device.clone())?;
+            assert!(ta.batch_matmul(&tb3).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bf16() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let data = (0..32).map(|i| i as f32 * 0.5).collect::<Vec<_>>();
+        let bytes = data
+            .iter()
+            .flat_map(|v| half::bf16::from_f32(*v).to_le_bytes())
+            .collect::<Vec<_>>();
+        // the weights stay in bf16, and are only widened in the dot products
+        let w = CpuTensor::from_bytes(&bytes, GGMLType::BF16, &[16, 2], device.clone())?;
+        assert_eq!(w.dtype(), GGMLType::BF16);
+        let b = CpuTensor::new(vec![1.0, 2.0], &[2], device.clone())?;
+        let out = w.matmul_vec(&b)?;
+        let w_f32 = CpuTensor::new(data.clone(), &[16, 2], device.clone())?;
+        assert_eq!(out.to_vec(), w_f32.matmul_vec(&b)?.to_vec());
+
+        let w = w.dequantize(GGMLType::F32)?;
+        assert_eq!(w.to_vec(), data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_bytes_alignment() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let info = gf.get_tensor_info("blk.0.attn_q.weight").unwrap();
+
+        // the tensors in the mmaped file are aligned, they're borrowed without a copy
+        let t = CpuTensor::from_bytes(info.data(), info.typ(), info.dimensions(), device.clone())?;
+        assert!(!t.is_owned());
+
+        // a payload at an unaligned offset is copied instead of read through a misaligned
+        // pointer
+        let mut buf = vec![0_u8; info.data().len() + 1];
+        buf[1..].copy_from_slice(info.data());
+        let t2 = CpuTensor::from_bytes(&buf[1..], info.typ(), info.dimensions(), device.clone())?;
+        assert!(t2.is_owned());
+        assert_eq!(t2.to_vec(), t.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_bytes_malformed() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let bytes = vec![0_u8; 34 * 2];
+
+        // the data is shorter than the shape
+        let err = CpuTensor::from_bytes(&bytes, GGMLType::Q8_0, &[96], device.clone()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+
+        // the shape does not fill the blocks
+        let err = CpuTensor::from_bytes(&bytes, GGMLType::Q8_0, &[48], device.clone()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+
+        // the bytes are not whole blocks
+        let err = CpuTensorBuf::from_raw_bytes(&bytes[1..], GGMLType::Q8_0).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+
+        // the trailing padding is cut off
+        let t = CpuTensor::from_bytes(&bytes, GGMLType::Q8_0, &[32], device.clone())?;
+        assert_eq!(t.dequantize(GGMLType::F32)?.to_vec().len(), 32);
         Ok(())
     }
 
@@ -581,6 +1470,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_gelu() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![-2.0, -1.0, 0.0, 1.0, 2.0], &[5], device.clone())?;
+        let t1 = t1.gelu_inplace()?;
+
+        // the values are looked up in a f16 table
+        assert_relative_eq!(
+            &t1.to_vec()[..],
+            &[-0.04540231, -0.15880801, 0.0, 0.841192, 1.9545977][..],
+            epsilon = 1e-2
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_contigous() -> Result<()> {
         let device = CpuTensorDevice::new();
@@ -612,9 +1516,104 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_broadcast_arithmetic() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3], device.clone())?;
+        let row = CpuTensor::new(vec![1.0, 2.0, 3.0], &[3], device.clone())?;
+        let t1 = t1.mul_inplace(&row)?;
+        assert_eq!(t1.to_vec(), vec![1.0, 4.0, 9.0, 4.0, 10.0, 18.0]);
+
+        let col = CpuTensor::new(vec![1.0, 2.0], &[2, 1], device.clone())?;
+        let t1 = t1.div_inplace(&col)?;
+        assert_eq!(t1.to_vec(), vec![1.0, 4.0, 9.0, 2.0, 5.0, 9.0]);
+
+        // the lhs grows into the broadcasted shape
+        let col = CpuTensor::new(vec![10.0, 20.0], &[2, 1], device.clone())?;
+        let t2 = col.add_inplace(&row)?;
+        assert_eq!(t2.shape(), &[2, 3]);
+        assert_eq!(t2.to_vec(), vec![11.0, 12.0, 13.0, 21.0, 22.0, 23.0]);
+
+        let bad = CpuTensor::new(vec![1.0, 2.0], &[2], device.clone())?;
+        assert!(t2.add_inplace(&bad).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_resize() -> Result<()> {
         // todo:
         Ok(())
     }
+
+    #[test]
+    fn test_concat_and_stack() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3], device.clone())?;
+        let t2 = CpuTensor::new(vec![7.0, 8.0, 9.0, 10.0], &[2, 2], device.clone())?;
+
+        let t3 = CpuTensor::concat(&[&t1, &t2], 1)?;
+        assert_eq!(t3.shape(), &[2, 5]);
+        assert_eq!(t3.to_vec(), vec![
+            1.0, 2.0, 3.0, 7.0, 8.0, 4.0, 5.0, 6.0, 9.0, 10.0
+        ]);
+        let t3 = CpuTensor::concat(&[&t1, &t1], 0)?;
+        assert_eq!(t3.shape(), &[4, 3]);
+        assert_eq!(t3.to_vec()[6..], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert!(CpuTensor::concat(&[&t1, &t2], 0).is_err());
+        assert!(CpuTensor::concat(&[&t1], 2).is_err());
+        assert!(CpuTensor::concat(&[], 0).is_err());
+
+        // the strided views are taken in their logical order
+        let t4 = t1.clone().transpose(&[1, 0])?; // (3, 2)
+        let t3 = CpuTensor::stack(&[&t4, &t4.contiguous()?], 1)?;
+        assert_eq!(t3.shape(), &[3, 2, 2]);
+        assert_eq!(t3.to_vec(), vec![
+            1.0, 4.0, 1.0, 4.0, 2.0, 5.0, 2.0, 5.0, 3.0, 6.0, 3.0, 6.0
+        ]);
+        let t3 = CpuTensor::stack(&[&t1, &t1, &t1], 0)?;
+        assert_eq!(t3.shape(), &[3, 2, 3]);
+        let t3 = CpuTensor::stack(&[&t1, &t1], 2)?;
+        assert_eq!(t3.shape(), &[2, 3, 2]);
+        assert_eq!(t3.to_vec()[..4], [1.0, 1.0, 2.0, 2.0]);
+        assert!(CpuTensor::stack(&[&t1, &t2], 0).is_err());
+        assert!(CpuTensor::stack(&[&t1], 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_and_narrow() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let buf = (0..24).map(|i| i as f32).collect::<Vec<_>>();
+        let t1 = CpuTensor::new(buf, &[2, 3, 4], device.clone())?;
+
+        // the rows on the outermost dim are still contiguous
+        let t2 = t1.slice(0, 1, 1)?;
+        assert_eq!(t2.shape(), &[1, 3, 4]);
+        assert!(t2.is_contiguous() && !t2.is_owned());
+        assert_eq!(t2.to_vec(), (12..24).map(|i| i as f32).collect::<Vec<_>>());
+
+        let t2 = t1.slice(1, 1, 2)?;
+        assert_eq!(t2.shape(), &[2, 2, 4]);
+        assert!(!t2.is_contiguous());
+        assert_eq!(t2.to_vec(), vec![
+            4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 16.0, 17.0, 18.0, 19.0, 20.0, 21.0, 22.0,
+            23.0
+        ]);
+        let t2 = t1.slice(2, 3, 1)?;
+        assert_eq!(t2.to_vec(), vec![3.0, 7.0, 11.0, 15.0, 19.0, 23.0]);
+        assert_eq!(t2.contiguous()?.shape(), &[2, 3, 1]);
+        assert_eq!(t1.slice(1, 3, 0)?.len(), 0);
+        assert!(t1.slice(1, 2, 2).is_err());
+        assert!(t1.slice(3, 0, 1).is_err());
+
+        // narrow keeps the first elements without moving the strides, like the kv cache
+        let t2 = t1.clone().narrow(1, 2)?;
+        assert_eq!(t2.shape(), &[2, 2, 4]);
+        assert_eq!(t2.strider().strides(), &[12, 4, 1]);
+        assert_eq!(t2.to_vec()[4..8], [4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(t2.to_vec()[8..12], [12.0, 13.0, 14.0, 15.0]);
+        assert!(t1.clone().narrow(1, 4).is_err());
+        assert!(t1.narrow(3, 1).is_err());
+        Ok(())
+    }
 }
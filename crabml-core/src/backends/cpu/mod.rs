@@ -9,4 +9,10 @@ pub use buf::CpuTensorBuf;
 pub use cpu_device::CpuTensorDevice;
 pub use cpu_device::CpuTensorDeviceOptions;
 pub use cpu_device::CpuTensorDeviceRef;
+pub use cpu_device::GemmBackend;
+pub use cpu_device::ThreadNumLimitGuard;
 pub use cpu_tensor::CpuTensor;
+pub use primitives::argmax_row;
+pub use primitives::log_softmax_row;
+pub use primitives::softmax_row;
+pub use primitives::topk_row;
@@ -2,28 +2,23 @@ use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::error::Result;
 use crate::tensor::TensorStrider;
 
+// the rhs of the elementwise ops is broadcasted to the shape of the lhs in the rules of numpy,
+// the lhs is updated in place so its shape never changes.
+
 pub fn add_inplace<'a>(
     buf1: &mut CpuTensorBuf<'a>,
     buf2: &CpuTensorBuf<'a>,
     strider1: &TensorStrider,
     strider2: &TensorStrider,
 ) -> Result<()> {
-    assert!(buf1.len() % buf2.len() == 0);
-    assert!(strider1.shape().last() == strider2.shape().last() || buf2.len() == 1);
-    assert!(strider1.is_contiguous());
-    assert!(strider2.is_contiguous());
-
-    if buf2.len() == 1 {
-        let ib = buf2.iter_f32().next().unwrap();
-        buf1.iter_f32_mut().for_each(|ia| {
-            *ia *= ib;
-        });
-        return Ok(());
+    if !is_tiled(strider1, strider2) || strider2.len() % 4 != 0 {
+        return binary_inplace::<_>(buf1, buf2, strider1, strider2, |ia, ib| *ia += ib);
     }
 
     let buf1 = buf1.as_f32_mut();
-    let buf2 = buf2.as_f32_ref();
-    buf1.chunks_exact_mut(4)
+    let buf2 = &buf2.as_f32_ref()[..strider2.len()];
+    buf1[..strider1.len()]
+        .chunks_exact_mut(4)
         .zip(buf2.chunks_exact(4).cycle())
         .for_each(|(ia, ib)| {
             let va = std::simd::f32x4::from_slice(ia);
@@ -50,22 +45,14 @@ pub fn mul_inplace<'a>(
     strider1: &TensorStrider,
     strider2: &TensorStrider,
 ) -> Result<()> {
-    assert!(buf1.len() % buf2.len() == 0);
-    assert!(strider1.shape().last() == strider2.shape().last() || buf2.len() == 1);
-    assert!(strider1.is_contiguous());
-    assert!(strider2.is_contiguous());
-
-    if buf2.len() == 1 {
-        let ib = buf2.iter_f32().next().unwrap();
-        buf1.iter_f32_mut().for_each(|ia| {
-            *ia *= ib;
-        });
-        return Ok(());
+    if !is_tiled(strider1, strider2) || strider2.len() % 4 != 0 {
+        return binary_inplace::<_>(buf1, buf2, strider1, strider2, |ia, ib| *ia *= ib);
     }
 
     let buf1 = buf1.as_f32_mut();
-    let buf2 = buf2.as_f32_ref();
-    buf1.chunks_exact_mut(4)
+    let buf2 = &buf2.as_f32_ref()[..strider2.len()];
+    buf1[..strider1.len()]
+        .chunks_exact_mut(4)
         .zip(buf2.chunks_exact(4).cycle())
         .for_each(|(ia, ib)| {
             let va = std::simd::f32x4::from_slice(ia);
@@ -86,6 +73,48 @@ pub fn div_inplace<'a>(
     binary_inplace::<_>(buf1, buf2, strider1, strider2, |ia, ib| *ia /= ib)
 }
 
+// the ops with a scalar rhs, like scaling the attention scores, update the lhs without
+// allocating a tensor of the scalar
+pub fn scale_inplace(
+    buf1: &mut CpuTensorBuf<'_>,
+    strider1: &TensorStrider,
+    rhs: f32,
+) -> Result<()> {
+    if !strider1.is_contiguous() {
+        return scalar_inplace(buf1, strider1, |ia| *ia *= rhs);
+    }
+
+    let buf1 = &mut buf1.as_f32_mut()[..strider1.len()];
+    let vb = std::simd::f32x4::splat(rhs);
+    let mut chunks = buf1.chunks_exact_mut(4);
+    chunks.by_ref().for_each(|ia| {
+        let va = std::simd::f32x4::from_slice(ia) * vb;
+        va.copy_to_slice(ia);
+    });
+    chunks.into_remainder().iter_mut().for_each(|ia| *ia *= rhs);
+    Ok(())
+}
+
+pub fn div_scalar_inplace(
+    buf1: &mut CpuTensorBuf<'_>,
+    strider1: &TensorStrider,
+    rhs: f32,
+) -> Result<()> {
+    scalar_inplace(buf1, strider1, |ia| *ia /= rhs)
+}
+
+#[inline]
+fn scalar_inplace<F>(buf1: &mut CpuTensorBuf<'_>, strider1: &TensorStrider, f: F) -> Result<()>
+where F: Fn(&mut f32) {
+    let buf1 = buf1.as_f32_mut();
+    if strider1.is_contiguous() {
+        buf1[..strider1.len()].iter_mut().for_each(f);
+    } else {
+        strider1.iter().for_each(|pos| f(&mut buf1[pos]));
+    }
+    Ok(())
+}
+
 #[inline]
 pub fn binary_inplace<'a, F>(
     buf1: &mut CpuTensorBuf<'a>,
@@ -97,25 +126,132 @@ pub fn binary_inplace<'a, F>(
 where
     F: Fn(&mut f32, f32),
 {
-    assert!(buf1.len() % buf2.len() == 0);
-    assert!(strider1.shape().last() == strider2.shape().last() || buf2.len() == 1);
-    assert!(strider1.is_contiguous());
-    assert!(strider2.is_contiguous());
-
-    if buf2.len() == 1 {
-        let ib = buf2.iter_f32().next().unwrap();
-        buf1.iter_f32_mut().for_each(|ia| {
-            f(ia, ib);
-        });
+    if strider2.len() == 1 {
+        let ib = buf2.as_f32_ref()[0];
+        let buf1 = buf1.as_f32_mut();
+        if strider1.is_contiguous() {
+            buf1[..strider1.len()].iter_mut().for_each(|ia| f(ia, ib));
+        } else {
+            strider1.iter().for_each(|pos| f(&mut buf1[pos], ib));
+        }
         return Ok(());
     }
 
-    // it seems that using cycle is slower
-    buf1.iter_f32_mut()
-        .zip(buf2.as_f32_ref().iter().cycle())
-        .for_each(|(ia, ib)| {
-            f(ia, *ib);
-        });
+    if is_tiled(strider1, strider2) {
+        // it seems that using cycle is slower
+        buf1.as_f32_mut()[..strider1.len()]
+            .iter_mut()
+            .zip(buf2.as_f32_ref()[..strider2.len()].iter().cycle())
+            .for_each(|(ia, ib)| {
+                f(ia, *ib);
+            });
+        return Ok(());
+    }
 
+    let strider2 = strider2.broadcast_to(strider1.shape())?;
+    let buf1 = buf1.as_f32_mut();
+    let buf2 = buf2.as_f32_ref();
+    strider1
+        .iter()
+        .zip(strider2.iter())
+        .for_each(|(pos1, pos2)| f(&mut buf1[pos1], buf2[pos2]));
     Ok(())
 }
+
+// both are contiguous and the rhs repeats over the leading dims of the lhs, like adding a bias
+// of (dim, ) to a batch of (n, dim), so the rhs can be simply cycled over the lhs
+fn is_tiled(strider1: &TensorStrider, strider2: &TensorStrider) -> bool {
+    if !strider1.is_contiguous() || !strider2.is_contiguous() {
+        return false;
+    }
+    let shape1 = strider1.shape();
+    let shape2 = strider2.shape();
+    let leading_ones = shape2.iter().take_while(|&&d| d == 1).count();
+    let shape2 = &shape2[leading_ones..];
+    shape2.len() <= shape1.len() && shape1.ends_with(shape2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(
+        a: Vec<f32>,
+        shape1: &[usize],
+        b: Vec<f32>,
+        shape2: &[usize],
+        f: impl Fn(&mut f32, f32),
+    ) -> Result<Vec<f32>> {
+        let mut buf1 = CpuTensorBuf::from(a);
+        let buf2 = CpuTensorBuf::from(b);
+        let strider1 = TensorStrider::new(shape1.to_vec());
+        let strider2 = TensorStrider::new(shape2.to_vec());
+        binary_inplace(&mut buf1, &buf2, &strider1, &strider2, f)?;
+        Ok(buf1.iter_f32().collect())
+    }
+
+    #[test]
+    fn test_binary_inplace_broadcast() -> Result<()> {
+        // a column of (2, 1) over (2, 3)
+        let out = binary(vec![1.0; 6], &[2, 3], vec![1.0, 2.0], &[2, 1], |a, b| {
+            *a += b
+        })?;
+        assert_eq!(out, vec![2.0, 2.0, 2.0, 3.0, 3.0, 3.0]);
+
+        // a row of (3, ) over (2, 3)
+        let out = binary(vec![1.0; 6], &[2, 3], vec![1.0, 2.0, 3.0], &[3], |a, b| {
+            *a *= b
+        })?;
+        assert_eq!(out, vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0]);
+
+        // (2, 1, 2) over (2, 2, 2)
+        let a = (1..=8).map(|v| v as f32).collect::<Vec<_>>();
+        let out = binary(
+            a,
+            &[2, 2, 2],
+            vec![1.0, 2.0, 4.0, 8.0],
+            &[2, 1, 2],
+            |a, b| *a /= b,
+        )?;
+        assert_eq!(out, vec![1.0, 1.0, 3.0, 2.0, 1.25, 0.75, 1.75, 1.0]);
+
+        // a scalar
+        let out = binary(vec![1.0, 2.0], &[2], vec![3.0], &[1, 1], |a, b| *a += b)?;
+        assert_eq!(out, vec![4.0, 5.0]);
+
+        assert!(binary(vec![1.0; 6], &[2, 3], vec![1.0; 2], &[2], |a, b| *a += b).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_inplace_scalar() -> Result<()> {
+        let mut buf1 = CpuTensorBuf::from(vec![1.0, 2.0, 3.0, 4.0]);
+        let buf2 = CpuTensorBuf::from(vec![2.0]);
+        let strider1 = TensorStrider::new(vec![4]);
+        let strider2 = TensorStrider::new(vec![1]);
+        add_inplace(&mut buf1, &buf2, &strider1, &strider2)?;
+        assert_eq!(buf1.iter_f32().collect::<Vec<_>>(), vec![
+            3.0, 4.0, 5.0, 6.0
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scalar_inplace() -> Result<()> {
+        // the tail which does not fill a simd lane
+        let mut buf1 = CpuTensorBuf::from((1..=6).map(|v| v as f32).collect::<Vec<_>>());
+        let strider1 = TensorStrider::new(vec![2, 3]);
+        scale_inplace(&mut buf1, &strider1, 2.0)?;
+        assert_eq!(buf1.iter_f32().collect::<Vec<_>>(), vec![
+            2.0, 4.0, 6.0, 8.0, 10.0, 12.0
+        ]);
+
+        // a transposed view updates the same elements
+        let strider1 = strider1.transpose(&[1, 0])?;
+        div_scalar_inplace(&mut buf1, &strider1, 4.0)?;
+        assert_eq!(buf1.iter_f32().collect::<Vec<_>>(), vec![
+            0.5, 1.0, 1.5, 2.0, 2.5, 3.0
+        ]);
+        Ok(())
+    }
+}
@@ -1,19 +1,24 @@
+use std::borrow::Cow;
+
 use half::f16;
 
-use crate::backends::cpu::buf::buf_f16::quantize_f32_f16;
-use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f16;
-use crate::backends::cpu::buf::buf_f16::vec_fma_f16_f16;
+use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f32;
+use crate::backends::cpu::buf::buf_f16::vec_fma_f16_f32;
 use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::backends::cpu::CpuTensorDeviceRef;
 use crate::gguf::GGMLType;
 use crate::tensor::TensorStrider;
 
-/// A (b, m, n) @ B (b, k, n) -> C (b, m, n)
+/// A (bA, m, k) @ B (bB, k, n) -> C (bA, m, n)
 ///
-/// A is expected to be contiguous, B is allowed to be strided, but B should
-/// be contiguous on the K dimension or N dimension.
+/// both A and B are allowed to be strided, like the transposed views, but a f16 B should be
+/// contiguous on the K dimension or N dimension. a f16 B like the f16 KV cache is converted
+/// inside the kernels and accumulated in f32, A is not rounded into f16. bA is a multiple of bB,
+/// each batch of B is shared by bA / bB consecutive batches of A like the kv heads on Grouped
+/// Query Attention.
+/// the batches are split over the threads of the device like the rows of matmul_vec.
 pub fn batch_matmul<'a>(
-    _device: &CpuTensorDeviceRef<'a>,
+    device: &CpuTensorDeviceRef<'a>,
     bufa: &CpuTensorBuf<'a>,
     bufb: &CpuTensorBuf<'a>,
     bufc: &mut CpuTensorBuf<'a>,
@@ -22,46 +27,103 @@ pub fn batch_matmul<'a>(
 ) {
     assert!(strider1.dims() == 3);
     assert!(strider2.dims() == 3);
-    assert!(strider1.is_contiguous());
-    assert!(strider2.strides()[1] == 1 || strider2.strides()[2] == 1);
+    assert!(strider1.shape()[0] % strider2.shape()[0] == 0);
+    assert!(strider1.shape()[2] == strider2.shape()[1]);
+    assert!(
+        matches!(bufb, CpuTensorBuf::F32(_))
+            || strider2.strides()[1] == 1
+            || strider2.strides()[2] == 1
+    );
     assert!(bufa.dtype() == GGMLType::F32 || bufa.dtype() == GGMLType::F16);
     assert!(bufb.dtype() == GGMLType::F32 || bufb.dtype() == GGMLType::F16);
 
-    match bufb {
-        CpuTensorBuf::F32(bufb) => batch_matmul_naive_f32(
-            bufa.as_f32_ref(),
-            bufb,
-            bufc.as_f32_mut(),
-            strider1,
-            strider2,
-        ),
+    #[cfg(feature = "blas")]
+    {
+        let (m, k, n) = (
+            strider1.shape()[1],
+            strider1.shape()[2],
+            strider2.shape()[2],
+        );
+        if device.use_blas(m, n, k)
+            && super::blas::batch_matmul_blas(
+                &dense_f32(bufa.as_f32_ref(), strider1),
+                bufb,
+                bufc.as_f32_mut(),
+                strider1,
+                strider2,
+            )
+        {
+            return;
+        }
+    }
+
+    // the batches, which are the heads on attention, are split over the threads
+    let (a_batch, m, n) = (
+        strider1.shape()[0],
+        strider1.shape()[1],
+        strider2.shape()[2],
+    );
+    let dense_a = match bufb {
+        CpuTensorBuf::F16(_) => dense_f32(bufa.as_f32_ref(), strider1),
+        _ => Cow::Borrowed(&[][..]),
+    };
+    let kernel = |b_start: usize, bufc: &mut [f32]| match bufb {
+        CpuTensorBuf::F32(bufb) => {
+            batch_matmul_naive_f32(bufa.as_f32_ref(), bufb, bufc, strider1, strider2, b_start)
+        }
         CpuTensorBuf::F16(bufb) => {
-            let bufa = quantize_f32_f16(bufa.as_f32_ref());
-            batch_matmul_simd_f16(&bufa, bufb, bufc.as_f32_mut(), strider1, strider2)
+            batch_matmul_f32_f16(&dense_a, bufb, bufc, strider1, strider2, b_start)
         }
         _ => unreachable!(),
+    };
+
+    let bufc = bufc.as_f32_mut();
+    let thread_num = device.thread_num().min(a_batch);
+    if thread_num <= 1 || m * n == 0 {
+        kernel(0, bufc);
+        return;
     }
+    let work_batches = a_batch.div_ceil(thread_num);
+    device.thread_pool().lock().unwrap().scoped(|s| {
+        bufc.chunks_mut(work_batches * m * n)
+            .enumerate()
+            .for_each(|(work_idx, work_buf)| {
+                let kernel = &kernel;
+                s.spawn(move || kernel(work_idx * work_batches, work_buf));
+            });
+    });
+}
+
+// gather a strided A into the row-major order, the contiguous A is borrowed as is
+fn dense_f32<'b>(bufa: &'b [f32], strider1: &TensorStrider) -> Cow<'b, [f32]> {
+    if strider1.is_contiguous() {
+        return Cow::Borrowed(bufa);
+    }
+    Cow::Owned(strider1.iter().map(|i| bufa[i]).collect())
 }
 
 // TODO: use vec_dot and vec_fma to optimize this function
+// bufc holds the batches from b_start of C, the batches of a thread
 fn batch_matmul_naive_f32(
     bufa: &[f32],     // b x m x k
     bufb: &[f32],     // b x k x n
     bufc: &mut [f32], // b x m x n
     stride1: &TensorStrider,
     stride2: &TensorStrider,
+    b_start: usize,
 ) {
     let (a_batch, b_batch) = (stride1.shape()[0], stride2.shape()[0]);
     assert!(a_batch >= b_batch);
     let (m, k, n) = (stride1.shape()[1], stride1.shape()[2], stride2.shape()[2]);
-    for bi in 0..a_batch {
+    let batch_broadcast = a_batch / b_batch;
+    for bi in b_start..b_start + bufc.len() / (m * n).max(1) {
         for mi in 0..m {
             for ni in 0..n {
                 for ki in 0..k {
-                    bufc[bi * (m * n) + mi * n + ni] += bufa[bi * stride1.strides()[0]
+                    bufc[(bi - b_start) * (m * n) + mi * n + ni] += bufa[bi * stride1.strides()[0]
                         + mi * stride1.strides()[1]
                         + ki * stride1.strides()[2]]
-                        * bufb[(bi % b_batch) * stride2.strides()[0]
+                        * bufb[(bi / batch_broadcast) * stride2.strides()[0]
                             + ki * stride2.strides()[1]
                             + ni * stride2.strides()[2]];
                 }
@@ -70,12 +132,13 @@ fn batch_matmul_naive_f32(
     }
 }
 
-fn batch_matmul_simd_f16(
-    bufa: &[f16],     // bA x m x k
+fn batch_matmul_f32_f16(
+    bufa: &[f32],     // bA x m x k, contiguous
     bufb: &[f16],     // bB x k x n, bA is multiple of bB
     bufc: &mut [f32], // bA x m x n
     stride1: &TensorStrider,
     stride2: &TensorStrider,
+    b_start: usize,
 ) {
     let (a_batch, b_batch) = (stride1.shape()[0], stride2.shape()[0]);
     assert!(a_batch >= b_batch);
@@ -85,46 +148,45 @@ fn batch_matmul_simd_f16(
         stride2.strides()[1],
         stride2.strides()[2],
     );
+    if m * n == 0 {
+        return;
+    }
 
     // On Grouped Query Attention, the batch size of A is always a multiple of the batch size of B.
     // batch demension of A / batch_broadcast = batch dimension of B.
     let batch_broadcast = a_batch / b_batch;
+    let batches = bufc.chunks_exact_mut(m * n).zip(b_start..);
 
     // matrix A is always row-wise contiguous, matrix B should be contiguous on the K
     // dimension or N dimension.
-    // if matrix B is contiguous on the k dimension, then we use vec_dot_f16_f16
-    // if matrix B is contiguous on the n dimension, then we use vec_fma_f16_f16
+    // if matrix B is contiguous on the k dimension, then we use vec_dot_f16_f32
+    // if matrix B is contiguous on the n dimension, then we use vec_fma_f16_f32
     if stride_bk == 1 {
-        bufc.iter_mut().enumerate().for_each(|(i, bufcp)| {
-            let ni = i % n;
-            let mi = (i - ni) / n % m;
-            let bi_a = (i - ni - mi * n) / (m * n);
-            let offset_a = bi_a * (m * k) + mi * k;
-            let offset_b = (bi_a / batch_broadcast) * stride_bb + ni * stride_bn;
-            *bufcp = vec_dot_f16_f16(bufa, offset_a, &bufb[offset_b..offset_b + k], 0, k);
-        });
+        for (bufc, bi_a) in batches {
+            bufc.iter_mut().enumerate().for_each(|(i, bufcp)| {
+                let (mi, ni) = (i / n, i % n);
+                let offset_a = bi_a * (m * k) + mi * k;
+                let offset_b = (bi_a / batch_broadcast) * stride_bb + ni * stride_bn;
+                *bufcp = vec_dot_f16_f32(bufb, offset_b, bufa, offset_a, k);
+            });
+        }
     } else if stride_bn == 1 {
-        let mut tmpc = vec![f16::ZERO; a_batch * m * n]; // TODO: avoid allocation
-        for bi_a in 0..a_batch {
+        for (bufc, bi_a) in batches {
+            bufc.fill(0.0);
             for mi in 0..m {
                 for ki in 0..k {
                     let offset_a = bi_a * (m * k) + mi * k + ki;
                     let offset_b = (bi_a / batch_broadcast) * stride_bb + ki * stride_bk;
-                    let offset_c = bi_a * (m * n) + mi * n;
-                    vec_fma_f16_f16(
-                        &bufb[offset_b..offset_b + n],
+                    vec_fma_f16_f32(
+                        bufb,
                         bufa[offset_a],
-                        &mut tmpc[offset_c..offset_c + n],
-                        0,
+                        &mut bufc[mi * n..(mi + 1) * n],
+                        offset_b,
                         n,
                     );
                 }
             }
         }
-
-        bufc.iter_mut().zip(tmpc.iter()).for_each(|(c, tmp)| {
-            *c = tmp.to_f32();
-        });
     } else {
         unreachable!()
     }
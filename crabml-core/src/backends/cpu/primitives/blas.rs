@@ -0,0 +1,156 @@
+use std::borrow::Cow;
+
+use cblas_sys::cblas_sgemm;
+use cblas_sys::CBLAS_LAYOUT;
+use cblas_sys::CBLAS_TRANSPOSE;
+
+use crate::backends::cpu::buf::buf_bf16::dequantize_bf16_buf;
+use crate::backends::cpu::buf::buf_f16::dequantize_f16_buf;
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::tensor::TensorStrider;
+
+/// C (m, n) = A (m, k) @ B, B is (k, n) with the row stride ldb, or (n, k) if transposed.
+#[allow(clippy::too_many_arguments)]
+fn sgemm(
+    a: &[f32],
+    lda: usize,
+    b: &[f32],
+    ldb: usize,
+    trans_b: bool,
+    c: &mut [f32],
+    m: usize,
+    n: usize,
+    k: usize,
+) {
+    let trans_b = if trans_b {
+        CBLAS_TRANSPOSE::CblasTrans
+    } else {
+        CBLAS_TRANSPOSE::CblasNoTrans
+    };
+    unsafe {
+        cblas_sgemm(
+            CBLAS_LAYOUT::CblasRowMajor,
+            CBLAS_TRANSPOSE::CblasNoTrans,
+            trans_b,
+            m as i32,
+            n as i32,
+            k as i32,
+            1.0,
+            a.as_ptr(),
+            lda as i32,
+            b.as_ptr(),
+            ldb as i32,
+            0.0,
+            c.as_mut_ptr(),
+            n as i32,
+        );
+    }
+}
+
+// the f16 and bf16 weights are converted to f32 on each call, it costs O(m * k), which is cheap
+// compared with the O(m * n * k) matmul on the large shapes.
+fn dense_f32<'b>(buf: &'b CpuTensorBuf<'_>) -> Option<Cow<'b, [f32]>> {
+    match buf {
+        CpuTensorBuf::F32(buf) => Some(Cow::Borrowed(buf)),
+        CpuTensorBuf::F16(buf) => Some(Cow::Owned(dequantize_f16_buf(buf, 0).collect())),
+        CpuTensorBuf::BF16(buf) => Some(Cow::Owned(dequantize_bf16_buf(buf, 0).collect())),
+        _ => None,
+    }
+}
+
+/// (m, k) @ (n, k) -> (n, m), returns false if the weight is quantized, which is left to the
+/// internal kernels.
+pub fn matmul_vec_blas(
+    bufa: &CpuTensorBuf,
+    bufb: &CpuTensorBuf,
+    bufc: &mut CpuTensorBuf,
+    m: usize,
+    k: usize,
+) -> bool {
+    let (bufa, bufb) = match (dense_f32(bufa), bufb) {
+        (Some(a), CpuTensorBuf::F32(b)) => (a, b),
+        _ => return false,
+    };
+    let n = bufb.len() / k;
+    // C^T = B @ A^T
+    sgemm(bufb, k, &bufa, k, true, bufc.as_f32_mut(), n, m, k);
+    true
+}
+
+/// (b, m, k) @ (b, k, n) -> (b, m, n), the B is allowed to be strided like the internal
+/// batch_matmul. returns false if B is not f32, or not contiguous on the K or N dimension.
+pub fn batch_matmul_blas(
+    bufa: &[f32],
+    bufb: &CpuTensorBuf,
+    bufc: &mut [f32],
+    strider1: &TensorStrider,
+    strider2: &TensorStrider,
+) -> bool {
+    let bufb = match bufb {
+        CpuTensorBuf::F32(b) => b,
+        _ => return false,
+    };
+    let (a_batch, b_batch) = (strider1.shape()[0], strider2.shape()[0]);
+    let (m, k, n) = (
+        strider1.shape()[1],
+        strider1.shape()[2],
+        strider2.shape()[2],
+    );
+    let (stride_bb, stride_bk, stride_bn) = (
+        strider2.strides()[0],
+        strider2.strides()[1],
+        strider2.strides()[2],
+    );
+    if stride_bk != 1 && stride_bn != 1 {
+        return false;
+    }
+    let batch_broadcast = a_batch / b_batch;
+
+    for bi_a in 0..a_batch {
+        let a = &bufa[bi_a * m * k..(bi_a + 1) * m * k];
+        let b = &bufb[(bi_a / batch_broadcast) * stride_bb..];
+        let c = &mut bufc[bi_a * m * n..(bi_a + 1) * m * n];
+        if stride_bn == 1 {
+            sgemm(a, k, b, stride_bk, false, c, m, n, k);
+        } else {
+            sgemm(a, k, b, stride_bn, true, c, m, n, k);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matmul_vec_blas() {
+        // (3, 2) @ (2, 2) -> (2, 3)
+        let a = CpuTensorBuf::from(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let b = CpuTensorBuf::from(vec![1.0, 1.0, 0.0, 2.0]);
+        let mut c = CpuTensorBuf::from(vec![0.0; 6]);
+        assert!(matmul_vec_blas(&a, &b, &mut c, 3, 2));
+        assert_eq!(c.as_f32_ref(), &[3.0, 7.0, 11.0, 4.0, 8.0, 12.0]);
+    }
+
+    #[test]
+    fn test_batch_matmul_blas() {
+        // (1, 2, 2) @ (1, 2, 3) -> (1, 2, 3), B is contiguous on the n dimension
+        let a = vec![1.0, 2.0, 3.0, 4.0];
+        let b = CpuTensorBuf::from(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let mut c = vec![0.0; 6];
+        let strider1 = TensorStrider::new(vec![1, 2, 2]);
+        let strider2 = TensorStrider::new(vec![1, 2, 3]);
+        assert!(batch_matmul_blas(&a, &b, &mut c, &strider1, &strider2));
+        assert_eq!(c, vec![9.0, 12.0, 15.0, 19.0, 26.0, 33.0]);
+
+        // the same B stored in (1, 3, 2) and transposed
+        let b = CpuTensorBuf::from(vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        let strider2 = TensorStrider::new(vec![1, 3, 2])
+            .transpose(&[0, 2, 1])
+            .unwrap();
+        let mut c = vec![0.0; 6];
+        assert!(batch_matmul_blas(&a, &b, &mut c, &strider1, &strider2));
+        assert_eq!(c, vec![9.0, 12.0, 15.0, 19.0, 26.0, 33.0]);
+    }
+}
@@ -0,0 +1,150 @@
+use std::borrow::Cow;
+
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
+/// the attention scores in (n_head, n_batch, seq) of a batch of tokens, the batch is the last
+/// n_batch tokens of the seq, so the query i is at the position seq - n_batch + i. the scores
+/// of the keys after the query are set to -inf, which turn into zero after softmax.
+pub fn causal_mask_inplace(buf: &mut CpuTensorBuf<'_>, strider: &TensorStrider) -> Result<()> {
+    if strider.dims() != 3 || !strider.is_contiguous() {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "causal mask expects a contiguous (n_head, n_batch, seq) tensor, but got {:?}",
+                strider.shape()
+            ),
+        )
+            .into());
+    }
+    let (n_batch, seq) = (strider.shape()[1], strider.shape()[2]);
+    if n_batch > seq {
+        return Err((
+            ErrorKind::TensorError,
+            format!("causal mask: n_batch {} > seq {}", n_batch, seq),
+        )
+            .into());
+    }
+
+    let buf = match buf {
+        CpuTensorBuf::F32(Cow::Owned(buf)) => buf,
+        _ => {
+            return Err((
+                ErrorKind::TensorError,
+                format!("causal mask only supports owned f32, got {}", buf.dtype()),
+            )
+                .into());
+        }
+    };
+    for (row_idx, row) in buf.chunks_exact_mut(seq).enumerate() {
+        let query_pos = seq - n_batch + row_idx % n_batch;
+        row[query_pos + 1..].fill(f32::NEG_INFINITY);
+    }
+    Ok(())
+}
+
+/// like causal_mask_inplace, but the batch is a tree of tokens after the cached prefix, the
+/// parents[i] is the index of the parent of the token i in the batch, which must be before it.
+/// the token i only attends to the prefix, its ancestors and itself, so the branches of the
+/// tree do not see each other.
+pub fn tree_mask_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    parents: &[Option<usize>],
+) -> Result<()> {
+    if strider.dims() != 3 || !strider.is_contiguous() {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "tree mask expects a contiguous (n_head, n_batch, seq) tensor, but got {:?}",
+                strider.shape()
+            ),
+        )
+            .into());
+    }
+    let (n_batch, seq) = (strider.shape()[1], strider.shape()[2]);
+    if n_batch > seq || parents.len() != n_batch {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "tree mask: {} parents for n_batch {} and seq {}",
+                parents.len(),
+                n_batch,
+                seq
+            ),
+        )
+            .into());
+    }
+    if let Some(i) = (0..n_batch).find(|i| parents[*i].is_some_and(|p| p >= *i)) {
+        return Err((
+            ErrorKind::TensorError,
+            format!("tree mask: the parent of the token {} is not before it", i),
+        )
+            .into());
+    }
+
+    let buf = match buf {
+        CpuTensorBuf::F32(Cow::Owned(buf)) => buf,
+        _ => {
+            return Err((
+                ErrorKind::TensorError,
+                format!("tree mask only supports owned f32, got {}", buf.dtype()),
+            )
+                .into());
+        }
+    };
+    // (query, key) => visible, the ancestors are collected by following the parents
+    let mut visible = vec![false; n_batch * n_batch];
+    for i in 0..n_batch {
+        let mut node = Some(i);
+        while let Some(j) = node {
+            visible[i * n_batch + j] = true;
+            node = parents[j];
+        }
+    }
+    let prefix_len = seq - n_batch;
+    for (row_idx, row) in buf.chunks_exact_mut(seq).enumerate() {
+        let i = row_idx % n_batch;
+        for (j, score) in row[prefix_len..].iter_mut().enumerate() {
+            if !visible[i * n_batch + j] {
+                *score = f32::NEG_INFINITY;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_causal_mask() -> Result<()> {
+        // (n_head: 1, n_batch: 2, seq: 3), the batch is at the positions 1 and 2
+        let mut buf = CpuTensorBuf::from(vec![1.0; 6]);
+        causal_mask_inplace(&mut buf, &TensorStrider::new(vec![1, 2, 3]))?;
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(buf.as_f32_ref(), &[1.0, 1.0, inf, 1.0, 1.0, 1.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_mask() -> Result<()> {
+        // a prefix of 1 token, and a tree of 3 tokens: 0 -> 1, 0 -> 2
+        let mut buf = CpuTensorBuf::from(vec![1.0; 12]);
+        let strider = TensorStrider::new(vec![1, 3, 4]);
+        tree_mask_inplace(&mut buf, &strider, &[None, Some(0), Some(0)])?;
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(buf.as_f32_ref(), &[
+            1.0, 1.0, inf, inf, //
+            1.0, 1.0, 1.0, inf, //
+            1.0, 1.0, inf, 1.0,
+        ]);
+
+        let mut buf = CpuTensorBuf::from(vec![1.0; 12]);
+        assert!(tree_mask_inplace(&mut buf, &strider, &[None, Some(2), Some(0)]).is_err());
+        Ok(())
+    }
+}
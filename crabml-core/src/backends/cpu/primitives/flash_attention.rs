@@ -0,0 +1,242 @@
+use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f32;
+use crate::backends::cpu::buf::buf_f16::vec_fma_f16_f32;
+use crate::backends::cpu::buf::buf_f32::vec_dot_f32_f32;
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::CpuTensorDeviceRef;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
+// the keys are scored in blocks, the output of a query is rescaled once per block instead of
+// once per key
+const BLOCK_LEN: usize = 64;
+
+/// the fused attention of Q (n_batch, n_heads, head_dim) over K and V (n_kv_heads, seq,
+/// head_dim) into O (n_batch, n_heads, head_dim). the queries are the last n_batch tokens of
+/// the seq, each attends to the keys up to itself. Q is contiguous, the rows of K and V must be
+/// contiguous like the KV cache in f32 or f16, and n_heads is a multiple of n_kv_heads like on
+/// Grouped Query Attention.
+///
+/// the scores of a query are never materialized over the whole seq: the keys are scored in
+/// blocks, and the output and the sum of the softmax are rescaled whenever the running max
+/// grows, like FlashAttention. the rows of O are split over the threads of the device.
+#[allow(clippy::too_many_arguments)]
+pub fn flash_attention<'a>(
+    device: &CpuTensorDeviceRef<'a>,
+    bufq: &[f32],
+    bufk: &CpuTensorBuf<'a>,
+    bufv: &CpuTensorBuf<'a>,
+    bufo: &mut [f32],
+    strider_q: &TensorStrider,
+    strider_k: &TensorStrider,
+    strider_v: &TensorStrider,
+    scale: f32,
+) -> Result<()> {
+    let (n_batch, n_heads, head_dim) = match strider_q.shape() {
+        &[n_batch, n_heads, head_dim] => (n_batch, n_heads, head_dim),
+        _ => return Err(invalid(strider_q, strider_k, strider_v)),
+    };
+    let (n_kv_heads, seq) = match strider_k.shape() {
+        &[n_kv_heads, seq, dim] if dim == head_dim => (n_kv_heads, seq),
+        _ => return Err(invalid(strider_q, strider_k, strider_v)),
+    };
+    if !strider_q.is_contiguous()
+        || strider_v.shape() != strider_k.shape()
+        || strider_k.strides()[2] != 1
+        || strider_v.strides()[2] != 1
+        || n_kv_heads == 0
+        || n_heads % n_kv_heads != 0
+        || seq < n_batch
+        || bufo.len() != n_batch * n_heads * head_dim
+    {
+        return Err(invalid(strider_q, strider_k, strider_v));
+    }
+    if !matches!(bufk, CpuTensorBuf::F32(_) | CpuTensorBuf::F16(_))
+        || !matches!(bufv, CpuTensorBuf::F32(_) | CpuTensorBuf::F16(_))
+    {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "flash_attention: only f32 and f16 keys and values are supported, but got {} and {}",
+                bufk.dtype(),
+                bufv.dtype()
+            ),
+        )
+            .into());
+    }
+
+    if bufo.is_empty() {
+        return Ok(());
+    }
+
+    let n_past = seq - n_batch;
+    let heads_per_kv = n_heads / n_kv_heads;
+    let dot = |offset: usize, q: &[f32]| match bufk {
+        CpuTensorBuf::F32(k) => vec_dot_f32_f32(k, offset, q, 0, head_dim),
+        CpuTensorBuf::F16(k) => vec_dot_f16_f32(k, offset, q, 0, head_dim),
+        _ => unreachable!(),
+    };
+    let fma = |offset: usize, p: f32, out: &mut [f32]| match bufv {
+        CpuTensorBuf::F32(v) => out
+            .iter_mut()
+            .zip(&v[offset..offset + head_dim])
+            .for_each(|(o, v)| *o += p * v),
+        CpuTensorBuf::F16(v) => vec_fma_f16_f32(v, p, out, offset, head_dim),
+        _ => unreachable!(),
+    };
+
+    // row is the index of (query, head) in O, the rows from row_start are on a thread
+    let kernel = |row_start: usize, bufo: &mut [f32]| {
+        let mut scores = [0.0_f32; BLOCK_LEN];
+        for (out, row) in bufo.chunks_exact_mut(head_dim).zip(row_start..) {
+            let (qi, h) = (row / n_heads, row % n_heads);
+            let q = &bufq[row * head_dim..(row + 1) * head_dim];
+            let k_head = (h / heads_per_kv) * strider_k.strides()[0];
+            let v_head = (h / heads_per_kv) * strider_v.strides()[0];
+            let n_keys = n_past + qi + 1;
+
+            out.fill(0.0);
+            let mut max = f32::NEG_INFINITY;
+            let mut sum = 0.0_f32;
+            for block_start in (0..n_keys).step_by(BLOCK_LEN) {
+                let block_len = BLOCK_LEN.min(n_keys - block_start);
+                let scores = &mut scores[..block_len];
+                for (j, score) in scores.iter_mut().enumerate() {
+                    let ki = k_head + (block_start + j) * strider_k.strides()[1];
+                    *score = dot(ki, q) * scale;
+                }
+
+                let block_max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                if block_max > max {
+                    // the output and the sum were weighted by the old max
+                    let rescale = (max - block_max).exp();
+                    out.iter_mut().for_each(|o| *o *= rescale);
+                    sum *= rescale;
+                    max = block_max;
+                }
+                for (j, score) in scores.iter().enumerate() {
+                    let p = (score - max).exp();
+                    sum += p;
+                    let vi = v_head + (block_start + j) * strider_v.strides()[1];
+                    fma(vi, p, out);
+                }
+            }
+            out.iter_mut().for_each(|o| *o /= sum);
+        }
+    };
+
+    let n_rows = n_batch * n_heads;
+    let thread_num = device.thread_num().min(n_rows);
+    if thread_num <= 1 {
+        kernel(0, bufo);
+        return Ok(());
+    }
+    let work_rows = n_rows.div_ceil(thread_num);
+    device.thread_pool().lock().unwrap().scoped(|s| {
+        bufo.chunks_mut(work_rows * head_dim)
+            .enumerate()
+            .for_each(|(work_idx, work_buf)| {
+                let kernel = &kernel;
+                s.spawn(move || kernel(work_idx * work_rows, work_buf));
+            });
+    });
+    Ok(())
+}
+
+fn invalid(
+    strider_q: &TensorStrider,
+    strider_k: &TensorStrider,
+    strider_v: &TensorStrider,
+) -> crate::error::Error {
+    (
+        ErrorKind::TensorError,
+        format!(
+            "flash_attention: can not attend the queries {:?} over the keys {:?} and the values {:?}",
+            strider_q.shape(),
+            strider_k.shape(),
+            strider_v.shape()
+        ),
+    )
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::backends::cpu::CpuTensorDevice;
+    use crate::backends::cpu::CpuTensorDeviceOptions;
+    use crate::gguf::GGMLType;
+
+    // softmax(q @ k^T * scale) @ v over the materialized scores of each query
+    fn naive_attention(
+        q: &[f32],
+        k: &[f32],
+        v: &[f32],
+        (n_batch, n_heads, n_kv_heads, seq, head_dim): (usize, usize, usize, usize, usize),
+    ) -> Vec<f32> {
+        let mut out = vec![0.0; n_batch * n_heads * head_dim];
+        for (row, out) in out.chunks_exact_mut(head_dim).enumerate() {
+            let (qi, h) = (row / n_heads, row % n_heads);
+            let q = &q[row * head_dim..(row + 1) * head_dim];
+            let kv = h / (n_heads / n_kv_heads);
+            let n_keys = seq - n_batch + qi + 1;
+            let scores = (0..n_keys)
+                .map(|j| {
+                    let k = &k[(kv * seq + j) * head_dim..][..head_dim];
+                    q.iter().zip(k).map(|(a, b)| a * b).sum::<f32>() / (head_dim as f32).sqrt()
+                })
+                .collect::<Vec<_>>();
+            let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let sum = scores.iter().map(|s| (s - max).exp()).sum::<f32>();
+            for (j, score) in scores.iter().enumerate() {
+                let v = &v[(kv * seq + j) * head_dim..][..head_dim];
+                let p = (score - max).exp() / sum;
+                out.iter_mut().zip(v).for_each(|(o, v)| *o += p * v);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_flash_attention() -> Result<()> {
+        // the keys span over 3 blocks, and 4 heads share 2 kv heads
+        let dims = (3, 4, 2, 150, 8);
+        let (n_batch, n_heads, n_kv_heads, seq, head_dim) = dims;
+        let values = |n: usize, seed: usize| {
+            (0..n)
+                .map(|i| ((i * 7919 + seed * 104729) % 1000) as f32 / 250.0 - 2.0)
+                .collect::<Vec<_>>()
+        };
+        let q = values(n_batch * n_heads * head_dim, 1);
+        let k = values(n_kv_heads * seq * head_dim, 2);
+        let v = values(n_kv_heads * seq * head_dim, 3);
+        let expected = naive_attention(&q, &k, &v, dims);
+
+        let strider_q = TensorStrider::new(vec![n_batch, n_heads, head_dim]);
+        let strider_kv = TensorStrider::new(vec![n_kv_heads, seq, head_dim]);
+        for (thread_num, dtype, epsilon) in [
+            (1, GGMLType::F32, 1e-4),
+            (3, GGMLType::F32, 1e-4),
+            (3, GGMLType::F16, 1e-2),
+        ] {
+            let opts = CpuTensorDeviceOptions::default().with_thread_num(thread_num);
+            let device = CpuTensorDevice::with_options(opts);
+            let mut out = vec![0.0; n_batch * n_heads * head_dim];
+            flash_attention(
+                &device,
+                &q,
+                &CpuTensorBuf::from(k.clone()).quantize(dtype)?,
+                &CpuTensorBuf::from(v.clone()).quantize(dtype)?,
+                &mut out,
+                &strider_q,
+                &strider_kv,
+                &strider_kv,
+                1.0 / (head_dim as f32).sqrt(),
+            )?;
+            assert_relative_eq!(&out[..], &expected[..], epsilon = epsilon);
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
+use crate::backends::cpu::buf::buf_q8_0::PackedBufQ8_0;
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::buf::QuantBufQ8_0;
 use crate::backends::cpu::CpuTensorDeviceRef;
+use crate::error::Result;
 use crate::tensor::metrics::TimeMetric;
 use crate::tensor::TensorStrider;
+use crate::w8a8::quantize_activations;
 
 /// only dense GEMV is supported
 /// (m, k) @ k -> (m, )
@@ -13,13 +17,23 @@ pub fn matmul_vec<'a>(
     bufc: &mut CpuTensorBuf<'a>,
     strider1: &TensorStrider,
     strider2: &TensorStrider,
-) {
+) -> Result<()> {
     assert!(strider1.is_contiguous());
     assert!(strider2.is_contiguous());
     assert!(strider1.shape().last() == strider2.shape().last());
 
     let (m, k) = (strider1.shape()[0], strider1.shape()[1]);
-    gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k);
+
+    // the long prompts on prefilling benefit from the tuned GEMM of BLAS
+    #[cfg(feature = "blas")]
+    if device.use_blas(m, bufb.len() / k, k) {
+        let _t = device.metrics.matmul_walltime.track();
+        if super::blas::matmul_vec_blas(bufa, bufb, bufc, m, k) {
+            return Ok(());
+        }
+    }
+
+    gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -30,14 +44,24 @@ fn gemv_dense_2d_2d(
     bufc: &mut CpuTensorBuf, // (b, m)
     m: usize,
     k: usize,
-) {
+) -> Result<()> {
     let metrics = device.metrics.clone();
     let bufc = bufc.as_f32_mut();
 
+    bufa.check_vec_dot(bufb)?;
     let bufb = &{
         let _t = metrics.matmul_quantize_walltime.track();
-        bufb.quantize(bufa.vec_dot_rhs_dtype()).unwrap()
+        bufb.quantize(bufa.vec_dot_rhs_dtype())?
+    };
+    // the W8A8 weights take the activations in int8, each token is quantized once here
+    let qx = match bufa {
+        CpuTensorBuf::W8A8(_) => {
+            let _t = metrics.matmul_quantize_walltime.track();
+            Some(quantize_activations(bufb.as_f32_ref(), k))
+        }
+        _ => None,
     };
+    let qx = qx.as_deref();
     let thread_num = device.thread_num();
 
     // each thread handles 1/thread_num of the elements in the C matrix. thread_num is allowed
@@ -66,6 +90,18 @@ fn gemv_dense_2d_2d(
                                 let elem_idx = work_idx * work_len + chunk_idx * chunk_len;
                                 let mi = elem_idx % m;
                                 let bi = (elem_idx - mi) / m;
+                                if let (CpuTensorBuf::Q8_0Packed(a), CpuTensorBuf::Q8_0(b)) =
+                                    (bufa, bufb)
+                                {
+                                    gemv_packed_chunk(a, b, chunk_buf, mi, bi, k);
+                                    return;
+                                }
+                                if let (CpuTensorBuf::W8A8(a), Some(qx)) = (bufa, qx) {
+                                    for (i, cval) in chunk_buf.iter_mut().enumerate() {
+                                        *cval = a.dot_row(mi + i, &qx[bi]);
+                                    }
+                                    return;
+                                }
                                 for (i, cval) in chunk_buf.iter_mut().enumerate() {
                                     *cval = bufa.vec_dot((mi + i) * k, bufb, bi * k, k);
                                 }
@@ -75,4 +111,30 @@ fn gemv_dense_2d_2d(
                 });
         });
     }
+    Ok(())
+}
+
+// the rows of the packed weight are interleaved by 4, each 4 aligned rows are computed at once
+// to reuse the loaded rhs blocks, the unaligned rows at the edges of the chunk fall back to the
+// plain vec_dot.
+fn gemv_packed_chunk(
+    bufa: &PackedBufQ8_0,
+    bufb: &QuantBufQ8_0,
+    chunk_buf: &mut [f32],
+    mi: usize,
+    bi: usize,
+    k: usize,
+) {
+    let mut i = 0;
+    while i < chunk_buf.len() {
+        let row = mi + i;
+        if row % PackedBufQ8_0::ROWS == 0 && i + PackedBufQ8_0::ROWS <= chunk_buf.len() {
+            let vals = bufa.vec_dot_x4(row, bufb, bi * k);
+            chunk_buf[i..i + PackedBufQ8_0::ROWS].copy_from_slice(&vals);
+            i += PackedBufQ8_0::ROWS;
+        } else {
+            chunk_buf[i] = bufa.vec_dot(row * k, bufb, bi * k, k);
+            i += 1;
+        }
+    }
 }
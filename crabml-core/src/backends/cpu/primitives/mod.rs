@@ -1,24 +1,45 @@
 mod arithmetic;
 mod batch_matmul;
+#[cfg(feature = "blas")]
+mod blas;
+mod causal_mask;
 mod concatenate;
 mod contiguous;
+mod flash_attention;
 mod gelu;
 mod matmul_vec;
 mod rms_norm;
 mod rope;
+mod select;
 mod silu;
 mod softmax;
+mod topk;
 
 pub use arithmetic::add_inplace;
 pub use arithmetic::div_inplace;
+pub use arithmetic::div_scalar_inplace;
 pub use arithmetic::mul_inplace;
+pub use arithmetic::scale_inplace;
 pub use batch_matmul::batch_matmul;
+pub use causal_mask::causal_mask_inplace;
+pub use causal_mask::tree_mask_inplace;
 pub use concatenate::concatenate_inplace;
 pub use contiguous::contiguous;
+pub use flash_attention::flash_attention;
 pub use gelu::gelu_inplace;
 pub use gelu::gelu_single;
 pub use matmul_vec::matmul_vec;
 pub use rms_norm::rms_norm_inplace;
 pub use rope::rope_inplace;
+pub use rope::rope_positions_inplace;
+pub use rope::RopeCache;
+pub use select::masked_select;
+pub use select::nonzero;
 pub use silu::silu_inplace;
+pub use softmax::log_softmax_inplace;
+pub use softmax::log_softmax_row;
 pub use softmax::softmax_inplace;
+pub use softmax::softmax_row;
+pub use topk::argmax_row;
+pub use topk::topk;
+pub use topk::topk_row;
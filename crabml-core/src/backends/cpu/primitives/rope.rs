@@ -5,6 +5,113 @@ use crate::error::Result;
 use crate::tensor::RopeMode;
 use crate::tensor::TensorStrider;
 
+/// the precomputed cos/sin tables of rope for the positions in [0, max_pos). it's built once
+/// on creating the runner and shared by all the layers, instead of computing the trigonometry
+/// per head per token.
+#[derive(Debug)]
+pub struct RopeCache {
+    mode: RopeMode,
+    head_dim: usize,
+    rope_dim: usize,
+    theta: f32,
+    freq_scale: f32,
+    max_pos: usize,
+    cos: Vec<f32>, // (max_pos, rope_dim / 2)
+    sin: Vec<f32>, // (max_pos, rope_dim / 2)
+}
+
+impl RopeCache {
+    pub fn new(
+        mode: RopeMode,
+        head_dim: usize,
+        rope_dim: usize,
+        theta: f32,
+        freq_scale: f32,
+        max_pos: usize,
+    ) -> Self {
+        let half = rope_dim / 2;
+        let mut cos = Vec::with_capacity(max_pos * half);
+        let mut sin = Vec::with_capacity(max_pos * half);
+        for pos in 0..max_pos {
+            rope_freqs(mode, pos, rope_dim, theta, freq_scale, &mut cos, &mut sin);
+        }
+        Self {
+            mode,
+            head_dim,
+            rope_dim,
+            theta,
+            freq_scale,
+            max_pos,
+            cos,
+            sin,
+        }
+    }
+
+    pub fn matches(
+        &self,
+        mode: RopeMode,
+        head_dim: usize,
+        rope_dim: usize,
+        theta: f32,
+        freq_scale: f32,
+    ) -> bool {
+        self.mode == mode
+            && self.head_dim == head_dim
+            && self.rope_dim == rope_dim
+            && self.theta == theta
+            && self.freq_scale == freq_scale
+    }
+
+    pub fn max_pos(&self) -> usize {
+        self.max_pos
+    }
+
+    fn row(&self, pos: usize) -> Option<(&[f32], &[f32])> {
+        if pos >= self.max_pos {
+            return None;
+        }
+        let half = self.rope_dim / 2;
+        let range = pos * half..(pos + 1) * half;
+        Some((&self.cos[range.clone()], &self.sin[range]))
+    }
+}
+
+// push the cos/sin of the rotation angles of each dimension pair at the position. the
+// frequencies are spread over the rotated dimensions like llama.cpp, so a partial rotation
+// like phi2 or NeoX keeps the same frequencies as a full rotation of rope_dim. the position
+// is scaled by freq_scale, which is below 1 on the models with a linearly extended context.
+fn rope_freqs(
+    mode: RopeMode,
+    pos: usize,
+    rope_dim: usize,
+    base: f32,
+    freq_scale: f32,
+    cos: &mut Vec<f32>,
+    sin: &mut Vec<f32>,
+) {
+    let pos = pos as f32 * freq_scale;
+    match mode {
+        RopeMode::Llama => {
+            let theta_scale = base.powf(-2.0 / rope_dim as f32);
+            let mut theta: f32 = pos;
+            for _ in 0..rope_dim / 2 {
+                cos.push(theta.cos());
+                sin.push(theta.sin());
+                theta *= theta_scale;
+            }
+        }
+        RopeMode::Neox => {
+            for i in 0..rope_dim / 2 {
+                let freq_exponents = 2.0 * i as f32 / rope_dim as f32;
+                let timescale = base.powf(freq_exponents);
+                let theta = pos / timescale;
+                cos.push(theta.cos());
+                sin.push(theta.sin());
+            }
+        }
+    }
+}
+
 // only support f32 yet
 // TODO: support f16
 pub fn rope_inplace(
@@ -13,9 +120,59 @@ pub fn rope_inplace(
     mode: RopeMode,
     pos: usize,
     rope_dim: usize,
+    theta: f32,
+    freq_scale: f32,
+    cache: Option<&RopeCache>,
+) -> Result<()> {
+    rope_rows_inplace(
+        buf1,
+        strider1,
+        mode,
+        |bi| pos + bi,
+        rope_dim,
+        theta,
+        freq_scale,
+        cache,
+    )
+}
+
+/// like rope_inplace, but the rows of the batch are at the given positions, which are not
+/// consecutive on evaluating a tree of tokens.
+pub fn rope_positions_inplace(
+    buf1: &mut CpuTensorBuf<'_>,
+    strider1: &TensorStrider,
+    mode: RopeMode,
+    positions: &[usize],
+    rope_dim: usize,
+    theta: f32,
+    freq_scale: f32,
+    cache: Option<&RopeCache>,
+) -> Result<()> {
+    rope_rows_inplace(
+        buf1,
+        strider1,
+        mode,
+        |bi| positions[bi],
+        rope_dim,
+        theta,
+        freq_scale,
+        cache,
+    )
+}
+
+fn rope_rows_inplace(
+    buf1: &mut CpuTensorBuf<'_>,
+    strider1: &TensorStrider,
+    mode: RopeMode,
+    position_of: impl Fn(usize) -> usize,
+    rope_dim: usize,
+    theta: f32,
+    freq_scale: f32,
+    cache: Option<&RopeCache>,
 ) -> Result<()> {
     assert!(strider1.is_contiguous());
     assert!(strider1.dims() == 2 || strider1.dims() == 3);
+    assert!(rope_dim % 2 == 0);
 
     let buf = match buf1 {
         CpuTensorBuf::F32(Cow::Owned(buf)) => buf,
@@ -32,49 +189,170 @@ pub fn rope_inplace(
         )
     };
 
+    // fallback to compute the angles of the position once for all the heads if the cache
+    // does not cover it
+    assert!(rope_dim <= head_dim);
+    let cache = cache.filter(|c| c.matches(mode, head_dim, rope_dim, theta, freq_scale));
+    let mut cos_buf = Vec::with_capacity(rope_dim / 2);
+    let mut sin_buf = Vec::with_capacity(rope_dim / 2);
+
     for bi in 0..n_batch {
-        let seq_pos = pos + bi;
+        let seq_pos = position_of(bi);
         let buf_row = &mut buf[bi * bi_stride..(bi + 1) * bi_stride];
+        let (cos, sin) = match cache.and_then(|c| c.row(seq_pos)) {
+            Some(row) => row,
+            None => {
+                cos_buf.clear();
+                sin_buf.clear();
+                rope_freqs(
+                    mode,
+                    seq_pos,
+                    rope_dim,
+                    theta,
+                    freq_scale,
+                    &mut cos_buf,
+                    &mut sin_buf,
+                );
+                (&cos_buf[..], &sin_buf[..])
+            }
+        };
         match mode {
-            RopeMode::Llama => rope_llama(buf_row, seq_pos, head_dim, rope_dim),
-            RopeMode::Neox => rope_neox(buf_row, seq_pos, head_dim, rope_dim),
+            RopeMode::Llama => rope_llama(buf_row, head_dim, cos, sin),
+            RopeMode::Neox => rope_neox(buf_row, head_dim, cos, sin),
         }
     }
 
     Ok(())
 }
 
-fn rope_llama(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize) {
-    let theta_scale = 10000_f32.powf(-2.0 / head_dim as f32);
+fn rope_llama(buf: &mut [f32], head_dim: usize, cos: &[f32], sin: &[f32]) {
     buf.chunks_exact_mut(head_dim).for_each(|chunk| {
-        let mut theta: f32 = pos as f32;
-        for i in (0..rope_dim).step_by(2) {
-            let cos_theta = theta.cos();
-            let sin_theta = theta.sin();
-            theta *= theta_scale;
+        for (i, (cos_theta, sin_theta)) in cos.iter().zip(sin.iter()).enumerate() {
             unsafe {
-                let qp0 = *chunk.get_unchecked(i);
-                let qp1 = *chunk.get_unchecked(i + 1);
-                *chunk.get_unchecked_mut(i) = qp0 * cos_theta - qp1 * sin_theta;
-                *chunk.get_unchecked_mut(i + 1) = qp0 * sin_theta + qp1 * cos_theta;
+                let qp0 = *chunk.get_unchecked(2 * i);
+                let qp1 = *chunk.get_unchecked(2 * i + 1);
+                *chunk.get_unchecked_mut(2 * i) = qp0 * cos_theta - qp1 * sin_theta;
+                *chunk.get_unchecked_mut(2 * i + 1) = qp0 * sin_theta + qp1 * cos_theta;
             }
         }
     });
 }
 
-fn rope_neox(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize) {
+// pairs the dimension i with i + rope_dim / 2, the dimensions after rope_dim are kept
+fn rope_neox(buf: &mut [f32], head_dim: usize, cos: &[f32], sin: &[f32]) {
+    let half = cos.len();
     buf.chunks_exact_mut(head_dim).for_each(|chunk| {
-        for i in 0..rope_dim / 2 {
-            let freq_exponents = 2.0 * i as f32 / head_dim as f32;
-            let timescale = 10000_f32.powf(freq_exponents);
-            let theta = pos as f32 / timescale;
-            let cos_theta = theta.cos();
-            let sin_theta = theta.sin();
-
+        for (i, (cos_theta, sin_theta)) in cos.iter().zip(sin.iter()).enumerate() {
             let qp0 = chunk[i];
-            let qp1 = chunk[i + head_dim / 2];
+            let qp1 = chunk[i + half];
             chunk[i] = qp0 * cos_theta - qp1 * sin_theta;
-            chunk[i + head_dim / 2] = qp0 * sin_theta + qp1 * cos_theta;
+            chunk[i + half] = qp0 * sin_theta + qp1 * cos_theta;
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rope_with_cache() -> Result<()> {
+        // (n_batch, n_head, head_dim)
+        let strider = TensorStrider::new(vec![3, 2, 8]);
+        let data = (0..48).map(|i| i as f32 * 0.1).collect::<Vec<_>>();
+
+        for mode in [RopeMode::Llama, RopeMode::Neox] {
+            let cache = RopeCache::new(mode, 8, 6, 10000.0, 1.0, 4);
+            assert_eq!(cache.max_pos(), 4);
+
+            let mut expected = CpuTensorBuf::from(data.clone());
+            rope_inplace(&mut expected, &strider, mode, 2, 6, 10000.0, 1.0, None)?;
+
+            // the positions 2 and 3 are covered by the cache, but the position 4 falls back
+            let mut got = CpuTensorBuf::from(data.clone());
+            rope_inplace(&mut got, &strider, mode, 2, 6, 10000.0, 1.0, Some(&cache))?;
+            assert_eq!(got.as_f32_ref(), expected.as_f32_ref());
+
+            // the cache for another rope_dim is ignored
+            let other = RopeCache::new(mode, 8, 8, 10000.0, 1.0, 4);
+            let mut got = CpuTensorBuf::from(data.clone());
+            rope_inplace(&mut got, &strider, mode, 2, 6, 10000.0, 1.0, Some(&other))?;
+            assert_eq!(got.as_f32_ref(), expected.as_f32_ref());
+
+            let mut got = CpuTensorBuf::from(data.clone());
+            let positions = [2, 3, 4];
+            rope_positions_inplace(
+                &mut got,
+                &strider,
+                mode,
+                &positions,
+                6,
+                10000.0,
+                1.0,
+                Some(&cache),
+            )?;
+            assert_eq!(got.as_f32_ref(), expected.as_f32_ref());
+
+            // the cache for another theta is ignored
+            let other = RopeCache::new(mode, 8, 6, 1e6, 1.0, 4);
+            let mut got = CpuTensorBuf::from(data.clone());
+            rope_inplace(&mut got, &strider, mode, 2, 6, 10000.0, 1.0, Some(&other))?;
+            assert_eq!(got.as_f32_ref(), expected.as_f32_ref());
+
+            // the cache for another frequency scale is ignored
+            let other = RopeCache::new(mode, 8, 6, 10000.0, 0.25, 4);
+            let mut got = CpuTensorBuf::from(data.clone());
+            rope_inplace(&mut got, &strider, mode, 2, 6, 10000.0, 1.0, Some(&other))?;
+            assert_eq!(got.as_f32_ref(), expected.as_f32_ref());
+
+            // a position scaled by 0.5 rotates like the half position
+            let mut scaled = CpuTensorBuf::from(data[..16].to_vec());
+            let row = TensorStrider::new(vec![1, 2, 8]);
+            rope_inplace(&mut scaled, &row, mode, 2, 6, 10000.0, 0.5, None)?;
+            let mut half = CpuTensorBuf::from(data[..16].to_vec());
+            rope_inplace(&mut half, &row, mode, 1, 6, 10000.0, 1.0, None)?;
+            assert_eq!(scaled.as_f32_ref(), half.as_f32_ref());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rope_theta_and_partial_neox() -> Result<()> {
+        // (n_head, head_dim), rotate the first 4 of the 6 dimensions of each head
+        let strider = TensorStrider::new(vec![2, 6]);
+        let data = (0..12).map(|i| i as f32 * 0.1 + 0.1).collect::<Vec<_>>();
+        let (pos, theta) = (3, 1e6_f32);
+
+        let mut got = CpuTensorBuf::from(data.clone());
+        rope_inplace(&mut got, &strider, RopeMode::Neox, pos, 4, theta, 1.0, None)?;
+        let got = got.as_f32_ref();
+
+        for head in data.chunks(6).zip(got.chunks(6)) {
+            let (x, y) = head;
+            for i in 0..2 {
+                let angle = pos as f32 / theta.powf(2.0 * i as f32 / 4.0);
+                let (x0, x1) = (x[i], x[i + 2]);
+                let want0 = x0 * angle.cos() - x1 * angle.sin();
+                let want1 = x0 * angle.sin() + x1 * angle.cos();
+                assert!((y[i] - want0).abs() < 1e-5, "{} != {}", y[i], want0);
+                assert!((y[i + 2] - want1).abs() < 1e-5, "{} != {}", y[i + 2], want1);
+            }
+            assert_eq!(&y[4..], &x[4..]);
+        }
+
+        let cache = RopeCache::new(RopeMode::Neox, 6, 4, theta, 1.0, 8);
+        let mut cached = CpuTensorBuf::from(data.clone());
+        rope_inplace(
+            &mut cached,
+            &strider,
+            RopeMode::Neox,
+            pos,
+            4,
+            theta,
+            1.0,
+            Some(&cache),
+        )?;
+        assert_eq!(cached.as_f32_ref(), got);
+        Ok(())
+    }
+}
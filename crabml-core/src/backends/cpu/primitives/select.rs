@@ -0,0 +1,68 @@
+use crate::backends::cpu::CpuTensorBuf;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
+/// iterate the elements in the row-major order of the shape, no matter how the tensor is strided.
+fn iter_elements<'b>(
+    buf: &'b CpuTensorBuf,
+    strider: &'b TensorStrider,
+) -> Result<Box<dyn Iterator<Item = f32> + 'b>> {
+    match buf {
+        CpuTensorBuf::F32(buf) => Ok(Box::new(strider.iter().map(move |pos| buf[pos]))),
+        CpuTensorBuf::F16(buf) => Ok(Box::new(strider.iter().map(move |pos| buf[pos].to_f32()))),
+        CpuTensorBuf::BF16(buf) => Ok(Box::new(strider.iter().map(move |pos| buf[pos].to_f32()))),
+        _ => Err((
+            ErrorKind::TensorError,
+            format!("only f32/f16/bf16 is supported, but got {}", buf.dtype()),
+        )
+            .into()),
+    }
+}
+
+/// collects the elements of `buf` where the `mask` is non-zero.
+pub fn masked_select(
+    buf: &CpuTensorBuf,
+    strider: &TensorStrider,
+    mask: &CpuTensorBuf,
+    mask_strider: &TensorStrider,
+) -> Result<Vec<f32>> {
+    if strider.shape() != mask_strider.shape() {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "mask shape {:?} mismatches the tensor shape {:?}",
+                mask_strider.shape(),
+                strider.shape()
+            ),
+        )
+            .into());
+    }
+
+    let selected = iter_elements(buf, strider)?
+        .zip(iter_elements(mask, mask_strider)?)
+        .filter(|(_, m)| *m != 0.0)
+        .map(|(v, _)| v)
+        .collect();
+    Ok(selected)
+}
+
+/// returns the indices of the non-zero elements, flattened from a (n, dims) matrix.
+pub fn nonzero(buf: &CpuTensorBuf, strider: &TensorStrider) -> Result<Vec<usize>> {
+    let shape = strider.shape();
+    let mut out = vec![];
+    for (i, v) in iter_elements(buf, strider)?.enumerate() {
+        if v == 0.0 {
+            continue;
+        }
+        // unravel the flat index into the index on each dimension
+        let start = out.len();
+        let mut rem = i;
+        for dim in shape.iter().rev() {
+            out.push(rem % dim);
+            rem /= dim;
+        }
+        out[start..].reverse();
+    }
+    Ok(out)
+}
@@ -13,46 +13,128 @@ pub fn softmax_inplace<'a>(
     strider: TensorStrider,
     axis: usize,
 ) -> Result<()> {
-    assert!(strider.dims() == 2 || strider.dims() == 3);
     assert!(strider.is_contiguous());
     assert!(buf.dtype() == GGMLType::F32);
 
-    if axis != strider.dims() - 1 {
+    // the exponent is always <= 0 after subtracting the max, which is accurate enough in the
+    // f16 lookup table.
+    for_each_row(buf.as_f32_mut(), strider.shape(), axis, |row| {
+        softmax_row_with(row, |v| exp_f32_cached(v, &device.exp_cache))
+    })
+}
+
+pub fn log_softmax_inplace(
+    buf: &mut CpuTensorBuf,
+    strider: TensorStrider,
+    axis: usize,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(buf.dtype() == GGMLType::F32);
+
+    for_each_row(buf.as_f32_mut(), strider.shape(), axis, log_softmax_row)
+}
+
+/// softmax over a single row. the max is subtracted before exp, so large logits do not overflow.
+pub fn softmax_row(row: &mut [f32]) {
+    softmax_row_with(row, f32::exp)
+}
+
+/// log(softmax(x)) over a single row, computed as `x - max - log(sum(exp(x - max)))`, which does
+/// not underflow to -inf like taking the log of the softmax.
+pub fn log_softmax_row(row: &mut [f32]) {
+    let max = row.iter().fold(f32::NEG_INFINITY, |m, v| v.max(m));
+    let sum = row.iter().map(|v| (v - max).exp()).sum::<f32>();
+    let log_sum = sum.ln();
+    row.iter_mut().for_each(|v| *v = *v - max - log_sum);
+}
+
+fn softmax_row_with(row: &mut [f32], exp: impl Fn(f32) -> f32) {
+    let max = row.iter().fold(f32::NEG_INFINITY, |m, v| v.max(m));
+    let sum = row.iter_mut().fold(0.0, |mut acc, val| {
+        *val = exp(*val - max);
+        acc += *val;
+        acc
+    });
+    row.iter_mut().for_each(|val| *val /= sum);
+}
+
+/// apply `f` on each row along the axis of a contiguous buffer. the tensor is viewed as
+/// (outer, axis_len, inner), the rows are gathered into a temporary buffer if inner > 1.
+fn for_each_row(
+    buf: &mut [f32],
+    shape: &[usize],
+    axis: usize,
+    mut f: impl FnMut(&mut [f32]),
+) -> Result<()> {
+    if axis >= shape.len() {
         return Err((
             ErrorKind::TensorError,
-            format!(
-                "only axis={} is supported on a {} dimensions tensor",
-                strider.dims() - 1,
-                strider.dims()
-            ),
+            format!("axis {} out of range for shape {:?}", axis, shape),
         )
             .into());
     }
 
-    let (depths, rows, cols) = match strider.dims() {
-        2 => (1, strider.shape()[0], strider.shape()[1]),
-        3 => (strider.shape()[0], strider.shape()[1], strider.shape()[2]),
-        _ => unreachable!(),
-    };
-    let (stride_0, stride_1, _) = (rows * cols, cols, 1);
-
-    let buf = buf.as_f32_mut();
-
-    for depth in 0..depths {
-        for row in 0..rows {
-            let buf_offset = depth * stride_0 + row * stride_1;
-            let buf_row = &mut buf[buf_offset..buf_offset + cols];
-            let max = buf_row.iter().fold(0.0, |m, val| val.max(m));
-            let sum = buf_row.iter_mut().fold(0.0, |mut acc, val| {
-                *val = exp_f32_cached(*val - max, &device.exp_cache);
-                acc += *val;
-                acc
-            });
-            buf_row.iter_mut().for_each(|val| {
-                *val /= sum;
-            });
-        }
+    let axis_len = shape[axis];
+    let inner = shape[axis + 1..].iter().product::<usize>();
+    let outer = shape[..axis].iter().product::<usize>();
+
+    if inner == 1 {
+        buf.chunks_exact_mut(axis_len).for_each(f);
+        return Ok(());
     }
 
+    let mut row = vec![0.0; axis_len];
+    for o in 0..outer {
+        for i in 0..inner {
+            let offset = o * axis_len * inner + i;
+            for (k, v) in row.iter_mut().enumerate() {
+                *v = buf[offset + k * inner];
+            }
+            f(&mut row);
+            for (k, v) in row.iter().enumerate() {
+                buf[offset + k * inner] = *v;
+            }
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_softmax_large_logits() {
+        let mut row = vec![1000.0, 1001.0, 1002.0];
+        softmax_row(&mut row);
+        assert_relative_eq!(
+            &row[..],
+            &[0.09003057, 0.24472847, 0.66524096][..],
+            epsilon = 1e-6
+        );
+
+        let mut row = vec![-1000.0, -1001.0, -1002.0];
+        log_softmax_row(&mut row);
+        assert_relative_eq!(
+            &row[..],
+            &[-0.40760595, -1.40760595, -2.40760595][..],
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn test_for_each_row_on_axis() -> Result<()> {
+        // [[1, 2], [3, 4]], sum on the axis 0 should be [4, 6]
+        let mut buf = vec![1.0, 2.0, 3.0, 4.0];
+        for_each_row(&mut buf, &[2, 2], 0, |row| {
+            let sum = row.iter().sum::<f32>();
+            row.iter_mut().for_each(|v| *v = sum);
+        })?;
+        assert_eq!(buf, vec![4.0, 6.0, 4.0, 6.0]);
+
+        assert!(for_each_row(&mut buf, &[2, 2], 2, |_| {}).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::cmp::Ordering;
+
+use crate::backends::cpu::CpuTensorBuf;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::TensorStrider;
+
+// orders the values with the NaNs below all the others, even below -inf, so a NaN is never
+// taken as the largest. the other values are compared in `f32::total_cmp`.
+fn cmp_nan_last(a: f32, b: f32) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => a.total_cmp(&b),
+    }
+}
+
+/// the index of the largest value in the row, the first one is taken on ties. the NaNs are
+/// ranked below all the values, the same order as `topk_row`. a row of NaNs gives its first
+/// index.
+pub fn argmax_row(row: &[f32]) -> Option<usize> {
+    if row.is_empty() {
+        return None;
+    }
+    let mut best = 0;
+    for (i, v) in row.iter().enumerate().skip(1) {
+        if cmp_nan_last(*v, row[best]).is_gt() {
+            best = i;
+        }
+    }
+    Some(best)
+}
+
+/// the indices of the k largest values in the row, in the descending order of the values. the
+/// k largest are partitioned out in O(n) before sorting them, which is much cheaper than sorting
+/// the whole row of a 32k vocab for a small k. the lower index comes first on ties, and the NaNs
+/// come last.
+pub fn topk_row(row: &[f32], k: usize) -> Vec<usize> {
+    let k = k.min(row.len());
+    if k == 0 {
+        return vec![];
+    }
+    let desc = |a: &usize, b: &usize| cmp_nan_last(row[*b], row[*a]).then(a.cmp(b));
+    let mut indices = (0..row.len()).collect::<Vec<_>>();
+    indices.select_nth_unstable_by(k - 1, desc);
+    indices.truncate(k);
+    indices.sort_unstable_by(desc);
+    indices
+}
+
+/// the k largest values along the dim and their indices on the dim, both laid out in the
+/// row-major order of the shape whose dim is replaced by k.
+pub fn topk(
+    buf: &CpuTensorBuf,
+    strider: &TensorStrider,
+    k: usize,
+    dim: usize,
+) -> Result<(Vec<usize>, Vec<f32>)> {
+    let shape = strider.shape();
+    if dim >= shape.len() || k == 0 || k > shape[dim] {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "topk: can not take the top {} on the dim {} of the shape {:?}",
+                k, dim, shape
+            ),
+        )
+            .into());
+    }
+
+    let mut out_shape = shape.to_vec();
+    out_shape[dim] = k;
+    let out_strider = TensorStrider::new(out_shape);
+    let n_rows = strider.len() / shape[dim];
+    let mut indices = vec![0; n_rows * k];
+    let mut values = vec![0.0; n_rows * k];
+
+    let mut row = vec![0.0; shape[dim]];
+    let mut pos = vec![0; shape.len()];
+    for r in 0..n_rows {
+        // unravel the row number into the position of its first element, the dim is kept 0
+        let mut rem = r;
+        for (d, n) in shape.iter().enumerate().rev() {
+            if d == dim {
+                pos[d] = 0;
+                continue;
+            }
+            pos[d] = rem % n;
+            rem /= n;
+        }
+
+        for (i, v) in row.iter_mut().enumerate() {
+            pos[dim] = i;
+            *v = buf.dequantize_at(strider.at_unchecked(&pos));
+        }
+        let top = if k == 1 {
+            argmax_row(&row).into_iter().collect()
+        } else {
+            topk_row(&row, k)
+        };
+        for (i, idx) in top.into_iter().enumerate() {
+            pos[dim] = i;
+            let out = out_strider.at_unchecked(&pos);
+            indices[out] = idx;
+            values[out] = row[idx];
+        }
+    }
+    Ok((indices, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topk_row() {
+        let row = [0.5, -1.0, 3.0, f32::NEG_INFINITY, 3.0, 2.0];
+        assert_eq!(argmax_row(&row), Some(2));
+        assert_eq!(argmax_row(&[]), None);
+
+        assert_eq!(topk_row(&row, 3), vec![2, 4, 5]);
+        assert_eq!(topk_row(&row, 1), vec![2]);
+        assert_eq!(topk_row(&row, 10), vec![2, 4, 5, 0, 1, 3]);
+        assert!(topk_row(&row, 0).is_empty());
+
+        // the NaNs are never the largest
+        let row = [f32::NAN, -1.0, f32::NEG_INFINITY, -f32::NAN, 2.0];
+        assert_eq!(argmax_row(&row), Some(4));
+        assert_eq!(topk_row(&row, 3), vec![4, 1, 2]);
+        assert_eq!(argmax_row(&[f32::NAN, f32::NAN]), Some(0));
+    }
+}
@@ -1,4 +1,11 @@
+mod capabilities;
 pub mod cpu;
+mod options;
+#[cfg(feature = "wgpu")]
 pub mod wgpu;
 
+pub use capabilities::available_ram;
+pub use capabilities::Backend;
+pub use capabilities::BackendCapabilities;
 pub use cpu::CpuTensor;
+pub use options::BackendOptions;
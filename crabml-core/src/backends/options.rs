@@ -0,0 +1,45 @@
+/// the options shared by the backends, so the callers do not have to know which device the
+/// model is placed on to configure it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendOptions {
+    threads: usize,
+}
+
+impl Default for BackendOptions {
+    fn default() -> Self {
+        Self { threads: 1 }
+    }
+}
+
+impl BackendOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the threads the CPU ops split the matmul rows and the attention heads over, 0 takes all
+    /// the cores.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = if threads == 0 {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            threads
+        };
+        self
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_options_threads() {
+        assert_eq!(BackendOptions::new().threads(), 1);
+        assert_eq!(BackendOptions::new().with_threads(3).threads(), 3);
+        assert!(BackendOptions::new().with_threads(0).threads() >= 1);
+    }
+}
@@ -26,7 +26,9 @@ pub struct BatchMatmulMeta {
     pub k: u32,
     pub n: u32,
     pub strides_b: [u32; 3],
-    pub _padding_1: u32,
+    /// the number of the batches of A sharing a batch of B, like the query heads of a KV head
+    /// on GQA
+    pub broadcast_b: u32,
 }
 
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -37,7 +39,9 @@ pub struct RopeMeta {
     pub pos: u32,
     pub n_heads: u32,
     pub n_rope_dims: u32,
-    pub _padding: [u32; 7],
+    pub theta: f32,
+    pub freq_scale: f32,
+    pub _padding: [u32; 5],
 }
 
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
@@ -1,4 +1,5 @@
 mod meta;
+mod vram;
 mod wgpu_device;
 mod wgpu_tensor;
 
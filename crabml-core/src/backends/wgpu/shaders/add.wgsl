@@ -1,5 +1,6 @@
 struct Meta {
     N: u32, // elments count
+    M: u32, // elments count of the rhs, which is cycled over the lhs
 }
 
 @group(0) @binding(0)
@@ -22,5 +23,5 @@ fn main(
         return;
     }
 
-    buf0[idx] += buf1[idx % arrayLength(&buf1)];
+    buf0[idx] += buf1[idx % bufM.M];
 }
\ No newline at end of file
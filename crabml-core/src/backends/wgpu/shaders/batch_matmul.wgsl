@@ -1,10 +1,11 @@
-// (b, m, k) * (b, k, n) = (b, m, n)
+// (b, m, k) * (b / broadcast_b, k, n) = (b, m, n)
 struct Meta {
     B: u32,
     M: u32,
     K: u32,
     N: u32,
     strides_b: vec3<u32>,
+    broadcast_b: u32,
 };
 
 @group(0) @binding(0)
@@ -28,6 +29,11 @@ fn main(
     let ni = gidx % bufm.N;
     let mi = ((gidx - ni) / bufm.N) % bufm.M;
     let bi = (gidx - ni - mi * bufm.N) / (bufm.M * bufm.N);
+    if (bi >= bufm.B) {
+        return;
+    }
+    // the consecutive batches of A share a batch of B, like the query heads of a KV head
+    let bi_b = bi / bufm.broadcast_b;
 
     var sum = 0.0f;
     for (var ki = 0u; ki < bufm.K; ki = ki + 1u) {
@@ -36,7 +42,7 @@ fn main(
             mi * bufm.K +
             ki
         ];
-        let b = bufb[bufm.strides_b.x * bi + ki * bufm.strides_b.y + ni * bufm.strides_b.z];
+        let b = bufb[bufm.strides_b.x * bi_b + ki * bufm.strides_b.y + ni * bufm.strides_b.z];
         sum += a * b;
     }
 
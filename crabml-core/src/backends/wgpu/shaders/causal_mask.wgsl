@@ -0,0 +1,32 @@
+struct Meta {
+    N: u32, // elements count
+    M: u32, // n_batch
+    S: u32, // seq
+}
+
+@group(0) @binding(0)
+var<storage, read_write> input: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> bufM: Meta;
+
+@compute
+@workgroup_size(32, 1, 1)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+) {
+    // the workgroups are dispatched in rows of at most 65535 on the large batches
+    let idx = 32u * (workgroup_id.y * num_workgroups.x + workgroup_id.x) + local_id.x;
+    if idx >= bufM.N {
+        return;
+    }
+
+    // the query i of the batch is at the position S - M + i
+    let si = idx % bufM.S;
+    let mi = (idx / bufM.S) % bufM.M;
+    if si > bufM.S - bufM.M + mi {
+        input[idx] = -3.40282347e+38;
+    }
+}
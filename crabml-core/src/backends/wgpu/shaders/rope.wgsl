@@ -4,7 +4,9 @@ struct Meta {
     pos: u32,
     nHeads: u32,
     nRopeDims: u32,
-    _padding: vec3<u32>,
+    theta: f32,
+    freqScale: f32,
+    _padding: vec2<u32>,
 };
 
 @group(0) @binding(0)
@@ -28,8 +30,8 @@ fn main(
 
     for (var h = 0u; h < bufM.nHeads; h++) {
         for (var i = 0u; i < bufM.nRopeDims / 2u; i++) {
-            let thetaScale = pow(10000.0, -2.0 * f32(i) / f32(nHeadDims));
-            let theta = f32(bufM.pos) * thetaScale;
+            let thetaScale = pow(bufM.theta, -2.0 * f32(i) / f32(bufM.nRopeDims));
+            let theta = f32(bufM.pos) * bufM.freqScale * thetaScale;
 
             let cosTheta = cos(theta);
             let sinTheta = sin(theta);
@@ -19,8 +19,9 @@ fn main(
         return;
     }
 
-    var max = 0.0f;
-    for (var ni = 0u; ni < input_m.N; ni = ni + 1u) {
+    // start from the first element, the rows may be all negative like the masked scores
+    var max = input[mi * input_m.N];
+    for (var ni = 1u; ni < input_m.N; ni = ni + 1u) {
         let idx = mi * input_m.N + ni;
         if (input[idx] > max) {
             max = input[idx];
@@ -0,0 +1,86 @@
+//! the free VRAM of the GPUs, which wgpu does not report. it's read from the driver of each
+//! vendor where it's exposed, and the integrated GPUs share the RAM.
+
+use std::process::Command;
+
+use crate::backends::available_ram;
+
+const VENDOR_NVIDIA: u32 = 0x10de;
+
+/// the free memory of the GPU in bytes, None if it can not be detected. the discrete GPUs are
+/// read from the sysfs of amdgpu and i915 on linux, or from nvidia-smi on NVIDIA.
+pub fn available_vram(info: &wgpu::AdapterInfo) -> Option<usize> {
+    match info.device_type {
+        wgpu::DeviceType::IntegratedGpu => available_ram(),
+        wgpu::DeviceType::DiscreteGpu if info.vendor == VENDOR_NVIDIA => {
+            nvidia_free_vram(info.device)
+        }
+        wgpu::DeviceType::DiscreteGpu => sysfs_free_vram(info.vendor, info.device),
+        _ => None,
+    }
+}
+
+// the cards under /sys/class/drm with the PCI ids, like card0/device/{vendor,device}
+fn sysfs_free_vram(vendor: u32, device: u32) -> Option<usize> {
+    let read_id = |path: std::path::PathBuf| {
+        let s = std::fs::read_to_string(path).ok()?;
+        u32::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok()
+    };
+    let read_bytes = |path: std::path::PathBuf| {
+        let s = std::fs::read_to_string(path).ok()?;
+        s.trim().parse::<usize>().ok()
+    };
+    for entry in std::fs::read_dir("/sys/class/drm").ok()?.flatten() {
+        let dir = entry.path().join("device");
+        if read_id(dir.join("vendor")) != Some(vendor)
+            || read_id(dir.join("device")) != Some(device)
+        {
+            continue;
+        }
+        let total = read_bytes(dir.join("mem_info_vram_total"))?;
+        let used = read_bytes(dir.join("mem_info_vram_used"))?;
+        return Some(total.saturating_sub(used));
+    }
+    None
+}
+
+fn nvidia_free_vram(device: u32) -> Option<usize> {
+    let output = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=pci.device_id,memory.free",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_nvidia_smi(&String::from_utf8_lossy(&output.stdout), device)
+}
+
+// the lines like "0x220410DE, 23805", the PCI id is the device id over the vendor id, and the
+// free memory is in MiB
+fn parse_nvidia_smi(output: &str, device: u32) -> Option<usize> {
+    output.lines().find_map(|line| {
+        let (pci_id, free_mib) = line.split_once(',')?;
+        let pci_id = u32::from_str_radix(pci_id.trim().trim_start_matches("0x"), 16).ok()?;
+        if pci_id >> 16 != device {
+            return None;
+        }
+        Some(free_mib.trim().parse::<usize>().ok()? << 20)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nvidia_smi() {
+        let output = "0x220410DE, 23805\n0x268410DE, 7000\n";
+        assert_eq!(parse_nvidia_smi(output, 0x2684), Some(7000 << 20));
+        assert_eq!(parse_nvidia_smi(output, 0x2204), Some(23805 << 20));
+        assert_eq!(parse_nvidia_smi(output, 0x1234), None);
+        assert_eq!(parse_nvidia_smi("[N/A], [N/A]", 0x2204), None);
+    }
+}
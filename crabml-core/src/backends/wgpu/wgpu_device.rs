@@ -1,15 +1,36 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
 
 use wgpu::util::DeviceExt;
 
+use crate::backends::wgpu::vram::available_vram;
+use crate::backends::Backend;
+use crate::backends::BackendCapabilities;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
 use crate::tensor::Tensor;
+
 pub struct WgpuTensorDeviceOptions {
     pub staging_buf_bytes: usize,
 
     pub debug_named_tensor: bool,
+
+    /// persist the compiled pipelines into this directory, so the next startup does not pay
+    /// the shader compilation again. only takes effect on the drivers which support the
+    /// pipeline cache, like Vulkan.
+    pub pipeline_cache_dir: Option<PathBuf>,
+
+    /// run on the GPU at this index of `WgpuTensorDevice::adapter_names`, like one replica of
+    /// the model per GPU. the default adapter is picked if not given.
+    pub adapter_index: Option<usize>,
 }
 
 impl Default for WgpuTensorDeviceOptions {
@@ -23,6 +44,8 @@ impl WgpuTensorDeviceOptions {
         Self {
             staging_buf_bytes: 1024 * 4,
             debug_named_tensor: false,
+            pipeline_cache_dir: None,
+            adapter_index: None,
         }
     }
 
@@ -35,24 +58,121 @@ impl WgpuTensorDeviceOptions {
         self.debug_named_tensor = v;
         self
     }
+
+    pub fn with_pipeline_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.pipeline_cache_dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_adapter_index(mut self, index: usize) -> Self {
+        self.adapter_index = Some(index);
+        self
+    }
+}
+
+const MODULE_SOURCES: &[(&str, &str)] = &[
+    ("add_inplace", include_str!("shaders/add.wgsl")),
+    ("mul_inplace", include_str!("shaders/mul.wgsl")),
+    ("div_inplace", include_str!("shaders/div.wgsl")),
+    ("rms_norm_inplace", include_str!("shaders/rms_norm.wgsl")),
+    ("sgemv", include_str!("shaders/sgemv.wgsl")),
+    ("rope_inplace", include_str!("shaders/rope.wgsl")),
+    ("softmax_inplace", include_str!("shaders/softmax.wgsl")),
+    (
+        "log_softmax_inplace",
+        include_str!("shaders/log_softmax.wgsl"),
+    ),
+    (
+        "causal_mask_inplace",
+        include_str!("shaders/causal_mask.wgsl"),
+    ),
+    ("silu_inplace", include_str!("shaders/silu.wgsl")),
+    ("batch_matmul", include_str!("shaders/batch_matmul.wgsl")),
+    (
+        "concatenate_inplace",
+        include_str!("shaders/concatenate.wgsl"),
+    ),
+    ("contiguous", include_str!("shaders/contiguous.wgsl")),
+];
+
+/// the compiled pipelines persisted on the disk. the file is keyed by the driver and the hash
+/// of the kernel sources, so it's not reused after upgrading the driver or the kernels.
+struct PipelineCache {
+    cache: wgpu::PipelineCache,
+    path: PathBuf,
+}
+
+impl PipelineCache {
+    fn open(device: &wgpu::Device, adapter_info: &wgpu::AdapterInfo, dir: PathBuf) -> Option<Self> {
+        let driver_key = wgpu::util::pipeline_cache_key(adapter_info)?;
+        let mut hasher = DefaultHasher::new();
+        MODULE_SOURCES.hash(&mut hasher);
+        adapter_info.driver_info.hash(&mut hasher);
+        let path = dir.join(format!("{}_{:016x}.bin", driver_key, hasher.finish()));
+
+        let data = std::fs::read(&path).ok();
+        // SAFETY: the data was written by PipelineCache::get_data() on the same driver, the
+        // cache falls back to an empty one if the data is corrupted.
+        let cache = unsafe {
+            device.create_pipeline_cache(&wgpu::PipelineCacheDescriptor {
+                label: Some("crabml pipeline cache"),
+                data: data.as_deref(),
+                fallback: true,
+            })
+        };
+        Some(Self { cache, path })
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = match self.cache.get_data() {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        let io_error = |err: std::io::Error| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to save the pipeline cache {}", self.path.display()),
+            )
+            .with_cause(err)
+        };
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        // write into a temporary file and rename, so a crash never leaves a truncated cache
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data).map_err(io_error)?;
+        std::fs::rename(&tmp_path, &self.path).map_err(io_error)
+    }
 }
 
 pub struct WgpuTensorDevice {
     pub(crate) opts: WgpuTensorDeviceOptions,
+    adapter_info: wgpu::AdapterInfo,
     pub(crate) inner: wgpu::Device,
     pub(crate) queue: wgpu::Queue,
     pub(crate) staging_buf: wgpu::Buffer,
     pub(crate) modules: HashMap<&'static str, wgpu::ShaderModule>,
+    pipelines: Mutex<HashMap<&'static str, Arc<wgpu::ComputePipeline>>>,
+    pipeline_cache: Option<PipelineCache>,
 
     /// used for test only
-    pub debug_tensors: RefCell<HashMap<String, Vec<f32>>>,
+    pub debug_tensors: Mutex<HashMap<String, Vec<f32>>>,
 }
 
-pub type WgpuTensorDeviceRef = Rc<WgpuTensorDevice>;
+pub type WgpuTensorDeviceRef = Arc<WgpuTensorDevice>;
 
 impl WgpuTensorDevice {
-    pub fn new(opts: WgpuTensorDeviceOptions) -> WgpuTensorDeviceRef {
-        let (device, queue) = pollster::block_on(Self::init_wgpu());
+    /// fails if there's no GPU at the adapter index, or no GPU at all.
+    pub fn new(opts: WgpuTensorDeviceOptions) -> Result<WgpuTensorDeviceRef> {
+        let use_pipeline_cache = opts.pipeline_cache_dir.is_some();
+        let (adapter_info, device, queue) =
+            pollster::block_on(Self::init_wgpu(use_pipeline_cache, opts.adapter_index))?;
+        let pipeline_cache = match &opts.pipeline_cache_dir {
+            Some(dir) if device.features().contains(wgpu::Features::PIPELINE_CACHE) => {
+                PipelineCache::open(&device, &adapter_info, dir.clone())
+            }
+            _ => None,
+        };
         let staging_buf = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("staging buffer"),
             size: opts.staging_buf_bytes as u64,
@@ -62,34 +182,21 @@ impl WgpuTensorDevice {
         let mut d = Self {
             inner: device,
             opts,
+            adapter_info,
             queue,
             staging_buf,
             modules: HashMap::new(),
-            debug_tensors: RefCell::new(HashMap::new()),
+            pipelines: Mutex::new(HashMap::new()),
+            pipeline_cache,
+            debug_tensors: Mutex::new(HashMap::new()),
         };
         d.load_modules();
-        Rc::new(d)
+        Ok(Arc::new(d))
     }
 
     pub(crate) fn load_modules(&mut self) {
-        let module_sources = vec![
-            ("add_inplace", include_str!("shaders/add.wgsl")),
-            ("mul_inplace", include_str!("shaders/mul.wgsl")),
-            ("div_inplace", include_str!("shaders/div.wgsl")),
-            ("rms_norm_inplace", include_str!("shaders/rms_norm.wgsl")),
-            ("sgemv", include_str!("shaders/sgemv.wgsl")),
-            ("rope_inplace", include_str!("shaders/rope.wgsl")),
-            ("softmax_inplace", include_str!("shaders/softmax.wgsl")),
-            ("silu_inplace", include_str!("shaders/silu.wgsl")),
-            ("batch_matmul", include_str!("shaders/batch_matmul.wgsl")),
-            (
-                "concatenate_inplace",
-                include_str!("shaders/concatenate.wgsl"),
-            ),
-            ("contiguous", include_str!("shaders/contiguous.wgsl")),
-        ];
         let mut modules = HashMap::new();
-        for (module_name, module_source) in module_sources {
+        for (module_name, module_source) in MODULE_SOURCES.iter().copied() {
             let module = self
                 .inner
                 .create_shader_module(wgpu::ShaderModuleDescriptor {
@@ -110,31 +217,103 @@ impl WgpuTensorDevice {
             })
     }
 
-    pub(crate) fn pipeline_for(&self, key: &'static str) -> wgpu::ComputePipeline {
-        let module = self.modules.get(key).unwrap();
-        self.inner
-            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                label: None,
-                layout: None,
-                module,
-                entry_point: "main",
-            })
+    // the pipelines are compiled once on the first use, and loaded from the pipeline cache if
+    // it's enabled
+    pub(crate) fn pipeline_for(&self, key: &'static str) -> Arc<wgpu::ComputePipeline> {
+        let mut pipelines = self.pipelines.lock().unwrap();
+        let pipeline = pipelines.entry(key).or_insert_with(|| {
+            let module = self.modules.get(key).unwrap();
+            let pipeline = self
+                .inner
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some(key),
+                    layout: None,
+                    module,
+                    entry_point: "main",
+                    compilation_options: Default::default(),
+                    cache: self.pipeline_cache.as_ref().map(|c| &c.cache),
+                });
+            Arc::new(pipeline)
+        });
+        pipeline.clone()
+    }
+
+    /// write the compiled pipelines into the cache dir, it's also saved on dropping the device.
+    pub fn save_pipeline_cache(&self) -> Result<()> {
+        match &self.pipeline_cache {
+            Some(cache) => cache.save(),
+            None => Ok(()),
+        }
     }
 
-    async fn init_wgpu() -> (wgpu::Device, wgpu::Queue) {
+    /// the names of the GPUs, the index of a GPU in them is its `adapter_index`.
+    pub fn adapter_names() -> Vec<String> {
+        wgpu::Instance::default()
+            .enumerate_adapters(wgpu::Backends::PRIMARY)
+            .iter()
+            .map(|adapter| adapter.get_info().name)
+            .collect()
+    }
+
+    /// the free VRAM of the GPU at the adapter index or the default one in bytes, before
+    /// creating a device on it. None if it can not be detected, or there's no such GPU.
+    pub fn detect_vram(adapter_index: Option<usize>) -> Option<usize> {
+        let adapter = pollster::block_on(Self::request_adapter(adapter_index)).ok()?;
+        available_vram(&adapter.get_info())
+    }
+
+    async fn request_adapter(adapter_index: Option<usize>) -> Result<wgpu::Adapter> {
         let instance = wgpu::Instance::default();
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions::default())
-            .await
-            .unwrap();
+        let adapter = match adapter_index {
+            Some(index) => {
+                let mut adapters = instance.enumerate_adapters(wgpu::Backends::PRIMARY);
+                let n_adapters = adapters.len();
+                if index >= n_adapters {
+                    return Err(Error::new(
+                        ErrorKind::BadInput,
+                        format!(
+                            "no GPU at the adapter index {}, found {} GPUs",
+                            index, n_adapters
+                        ),
+                    ));
+                }
+                adapters.swap_remove(index)
+            }
+            None => instance
+                .request_adapter(&wgpu::RequestAdapterOptions::default())
+                .await
+                .ok_or_else(|| Error::new(ErrorKind::Unexpected, "no GPU is found"))?,
+        };
+        Ok(adapter)
+    }
+
+    async fn init_wgpu(
+        pipeline_cache: bool,
+        adapter_index: Option<usize>,
+    ) -> Result<(wgpu::AdapterInfo, wgpu::Device, wgpu::Queue)> {
+        let adapter = Self::request_adapter(adapter_index).await?;
 
         // `request_device` instantiates the feature specific connection to the GPU, defining some parameters,
         //  `features` being the available features.
+        let mut required_features = wgpu::Features::empty();
+        if pipeline_cache && adapter.features().contains(wgpu::Features::PIPELINE_CACHE) {
+            required_features |= wgpu::Features::PIPELINE_CACHE;
+        }
+        if adapter.features().contains(wgpu::Features::SHADER_F16) {
+            required_features |= wgpu::Features::SHADER_F16;
+        }
+        let descriptor = wgpu::DeviceDescriptor {
+            required_features,
+            ..Default::default()
+        };
         let (device, queue) = adapter
-            .request_device(&wgpu::DeviceDescriptor::default(), None)
+            .request_device(&descriptor, None)
             .await
-            .unwrap();
-        (device, queue)
+            .map_err(|err| {
+                Error::new(ErrorKind::Unexpected, "failed to request the GPU device")
+                    .with_cause(err)
+            })?;
+        Ok((adapter.get_info(), device, queue))
     }
 
     pub fn encode_pipeline_commnad(
@@ -163,13 +342,91 @@ impl WgpuTensorDevice {
         encoder
     }
 
+    /// the GPU memory reserved by the allocator of wgpu in bytes, None if the backend does not
+    /// report it, like GL and WebGPU.
+    pub fn allocated_bytes(&self) -> Option<usize> {
+        self.inner
+            .generate_allocator_report()
+            .map(|report| report.total_reserved_bytes as usize)
+    }
+
     pub fn record_debug_tensor(&self, name: String, tensor: &impl Tensor) {
         let mut dst = vec![0.0; tensor.strider().len()];
         tensor.export(&mut dst).unwrap();
-        self.debug_tensors.borrow_mut().insert(name, dst);
+        self.debug_tensors.lock().unwrap().insert(name, dst);
     }
 
     pub fn dump_debug_tensor(&self, name: &str) -> Option<Vec<f32>> {
-        self.debug_tensors.borrow().get(name).cloned()
+        self.debug_tensors.lock().unwrap().get(name).cloned()
+    }
+}
+
+impl Backend for WgpuTensorDevice {
+    fn capabilities(&self) -> BackendCapabilities {
+        // a tensor is bound as a single storage buffer in the shaders
+        let limits = self.inner.limits();
+        let max_buffer_bytes = limits
+            .max_buffer_size
+            .min(limits.max_storage_buffer_binding_size as u64);
+        BackendCapabilities {
+            // the quantized weights are not supported on wgpu yet
+            dtypes: vec![GGMLType::F32],
+            quant_kernels: vec![],
+            max_buffer_bytes: Some(max_buffer_bytes as usize),
+            shader_f16: self.inner.features().contains(wgpu::Features::SHADER_F16),
+            available_memory: available_vram(&self.adapter_info),
+        }
+    }
+}
+
+impl Drop for WgpuTensorDevice {
+    fn drop(&mut self) {
+        // it's fine to compile the pipelines again on the next startup
+        let _ = self.save_pipeline_cache();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline_cache_round_trip() -> Result<()> {
+        let dir = std::env::temp_dir().join("crabml-test-pipeline-cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = || {
+            WgpuTensorDevice::new(
+                WgpuTensorDeviceOptions::new().with_pipeline_cache_dir(dir.clone()),
+            )
+        };
+        let keys = ["add_inplace", "rms_norm_inplace", "softmax_inplace"];
+
+        // write the pipelines compiled on the first startup
+        let device = open()?;
+        let path = match &device.pipeline_cache {
+            Some(cache) => cache.path.clone(),
+            // the driver has no pipeline cache, like Metal or DX12
+            None => return Ok(()),
+        };
+        for key in keys {
+            device.pipeline_for(key);
+        }
+        device.save_pipeline_cache()?;
+        let saved = std::fs::read(&path)?;
+        assert!(!saved.is_empty());
+        drop(device);
+
+        // reopen, the cache is loaded from the file instead of falling back to an empty one
+        let device = open()?;
+        let cache = device.pipeline_cache.as_ref().unwrap();
+        assert_eq!(cache.path, path);
+        assert_eq!(cache.cache.get_data(), Some(saved.clone()));
+
+        // hit, the pipelines are all found in the cache, so nothing is added to it
+        for key in keys {
+            device.pipeline_for(key);
+        }
+        assert_eq!(cache.cache.get_data(), Some(saved));
+        Ok(())
     }
 }
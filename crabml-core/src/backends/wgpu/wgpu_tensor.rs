@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use wgpu::util::DeviceExt;
 
@@ -16,9 +16,12 @@ use crate::tensor::RopeMode;
 use crate::tensor::Tensor;
 use crate::tensor::TensorStrider;
 
+// vulkan limits each dimension of a dispatch to 65535 workgroups
+const MAX_WORKGROUPS_PER_DIM: u32 = 65535;
+
 #[derive(Clone)]
 pub struct WgpuTensor {
-    buf: Rc<wgpu::Buffer>,
+    buf: Arc<wgpu::Buffer>,
     dtype: GGMLType,
     capacity: usize, // max element count
     strider: TensorStrider,
@@ -40,7 +43,7 @@ impl WgpuTensor {
             return Err((ErrorKind::TensorError, "new: buffer size mismatch").into());
         };
         Ok(Self {
-            buf: Rc::new(buf),
+            buf: Arc::new(buf),
             capacity: src.len(),
             dtype: GGMLType::F32,
             strider,
@@ -64,7 +67,7 @@ impl WgpuTensor {
             });
         let strider = TensorStrider::new(shape.to_vec());
         Ok(Self {
-            buf: Rc::new(buf),
+            buf: Arc::new(buf),
             dtype,
             capacity: strider.len(),
             strider,
@@ -80,6 +83,84 @@ impl WgpuTensor {
     pub fn shape(&self) -> &[usize] {
         self.strider.shape()
     }
+
+    // run a shader which normalizes each row on the last axis, like softmax
+    // the shaders of the elementwise ops cycle the rhs over the lhs, which only broadcasts the
+    // rhs whose shape is the trailing dims of the lhs, like a (dim, ) row over a (n, dim) batch
+    fn elementwise_inplace(self, pipeline: &'static str, rhs: &Self) -> Result<Self> {
+        assert!(self.is_contiguous());
+        assert!(rhs.is_contiguous());
+        let (lhs_shape, rhs_shape) = (self.strider.shape(), rhs.strider.shape());
+        let leading_ones = rhs_shape.iter().take_while(|dim| **dim == 1).count();
+        if !lhs_shape.ends_with(&rhs_shape[leading_ones..]) {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "{} on wgpu only broadcasts the trailing dims, can not broadcast {:?} over {:?}",
+                    pipeline, rhs_shape, lhs_shape
+                ),
+            )
+                .into());
+        }
+
+        let n_elms = self.strider.len();
+        let meta = [n_elms as u32, rhs.strider.len() as u32];
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::cast_slice(&meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: rhs.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder =
+            self.device
+                .encode_pipeline_commnad(pipeline, entries, (n_elms as u32 / 32 + 1, 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
+    fn softmax_rows(self, pipeline: &'static str, axis: usize) -> Result<Self> {
+        assert!(axis == self.strider.dims() - 1);
+        assert!(self.is_contiguous());
+        assert!(self.shape().len() == 3 || self.shape().len() == 2);
+
+        let (m, n) = if self.strider.dims() == 3 {
+            (
+                (self.shape()[0] * self.shape()[1]) as u32,
+                self.shape()[2] as u32,
+            )
+        } else {
+            (self.shape()[0] as u32, self.shape()[1] as u32)
+        };
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[m, n]));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder =
+            self.device
+                .encode_pipeline_commnad(pipeline, entries, (m * n / 16 + 1, 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
 }
 
 impl Tensor for WgpuTensor {
@@ -100,7 +181,7 @@ impl Tensor for WgpuTensor {
         });
         let strider = TensorStrider::new(shape.to_vec());
         Ok(Self {
-            buf: Rc::new(buf),
+            buf: Arc::new(buf),
             dtype: GGMLType::F32,
             capacity: n_elms,
             strider,
@@ -334,12 +415,85 @@ impl Tensor for WgpuTensor {
             drop(data);
             self.device.staging_buf.unmap();
         } else {
-            panic!("failed to run compute on gpu!")
+            return Err((
+                ErrorKind::TensorError,
+                "failed to read back from the gpu, the device may be lost",
+            )
+                .into());
         }
 
         Ok(())
     }
 
+    fn import(&mut self, src: &[f32]) -> Result<()> {
+        if self.dtype != GGMLType::F32 || !self.is_contiguous() {
+            return Err((
+                ErrorKind::TensorError,
+                "import: only support contiguous f32 tensors yet",
+            )
+                .into());
+        }
+        if src.len() != self.strider.len() {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "import: {} values into a tensor of shape {:?}",
+                    src.len(),
+                    self.shape()
+                ),
+            )
+                .into());
+        }
+        self.device
+            .queue
+            .write_buffer(&self.buf, 0, bytemuck::cast_slice(src));
+        Ok(())
+    }
+
+    fn split_last_dim(&self, sizes: &[usize]) -> Result<Vec<Self>> {
+        assert!(self.is_contiguous());
+        let shape = self.shape();
+        let last_dim = shape[shape.len() - 1];
+        if sizes.iter().sum::<usize>() != last_dim {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "split_last_dim: sizes {:?} mismatch the shape {:?}",
+                    sizes, shape
+                ),
+            )
+                .into());
+        }
+
+        let f32_size = std::mem::size_of::<f32>();
+        let n_rows = self.strider.len() / last_dim;
+        let mut encoder = self
+            .device
+            .inner
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let mut offset = 0;
+        let mut outputs = Vec::with_capacity(sizes.len());
+        for &size in sizes {
+            let mut out_shape = shape.to_vec();
+            *out_shape.last_mut().unwrap() = size;
+            let out = Self::alloc(&out_shape, self.dtype, self.device.clone())?;
+            // copy the chunk of each row, the rows are small in the activations
+            for row in 0..n_rows {
+                encoder.copy_buffer_to_buffer(
+                    &self.buf,
+                    ((row * last_dim + offset) * f32_size) as u64,
+                    &out.buf,
+                    (row * size * f32_size) as u64,
+                    (size * f32_size) as u64,
+                );
+            }
+            outputs.push(out);
+            offset += size;
+        }
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(outputs)
+    }
+
     fn dup(&self) -> Result<Self> {
         let new_tensor = Self::alloc(self.strider.shape(), self.dtype, self.device.clone())?;
 
@@ -352,7 +506,29 @@ impl Tensor for WgpuTensor {
         Ok(new_tensor)
     }
 
-    fn rope_inplace(self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn rope_positions_inplace(
+        self,
+        _mode: RopeMode,
+        _positions: &[usize],
+        _rope_dims: usize,
+        _theta: f32,
+        _freq_scale: f32,
+    ) -> Result<Self> {
+        Err((
+            ErrorKind::NotImplemented,
+            "rope_positions is not implemented on wgpu yet",
+        )
+            .into())
+    }
+
+    fn rope_inplace(
+        self,
+        mode: RopeMode,
+        pos: usize,
+        rope_dims: usize,
+        theta: f32,
+        freq_scale: f32,
+    ) -> Result<Self> {
         assert!(self.shape().len() == 3 || self.shape().len() == 2);
         assert!(self.is_contiguous());
         assert!(mode == RopeMode::Llama, "TODO: only support Llama mode yet");
@@ -372,7 +548,9 @@ impl Tensor for WgpuTensor {
             pos: pos as u32,
             n_heads: n_head as u32,
             n_rope_dims: rope_dims as u32,
-            _padding: [0; 7],
+            theta,
+            freq_scale,
+            _padding: [0; 5],
         };
 
         let meta_buf = self
@@ -398,6 +576,19 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn init_rope_cache(
+        _device: &Self::Device,
+        _mode: RopeMode,
+        _head_dim: usize,
+        _rope_dims: usize,
+        _theta: f32,
+        _freq_scale: f32,
+        _max_pos: usize,
+    ) -> Result<()> {
+        // the angles are computed in the shader
+        Ok(())
+    }
+
     fn rms_norm_inplace(self, eps: f32) -> Result<Self> {
         assert!(self.strider.dims() == 2 || self.strider.dims() == 1);
         let (n_batch, n_dims) = if self.strider.dims() == 2 {
@@ -432,21 +623,22 @@ impl Tensor for WgpuTensor {
     }
 
     fn softmax_inplace(self, axis: usize) -> Result<Self> {
-        assert!(axis == self.strider.dims() - 1);
+        self.softmax_rows("softmax_inplace", axis)
+    }
+
+    fn log_softmax_inplace(self, axis: usize) -> Result<Self> {
+        self.softmax_rows("log_softmax_inplace", axis)
+    }
+
+    fn causal_mask_inplace(self) -> Result<Self> {
         assert!(self.is_contiguous());
-        assert!(self.shape().len() == 3 || self.shape().len() == 2);
+        assert!(self.shape().len() == 3);
 
-        let (m, n) = if self.strider.dims() == 3 {
-            (
-                (self.shape()[0] * self.shape()[1]) as u32,
-                self.shape()[2] as u32,
-            )
-        } else {
-            (self.shape()[0] as u32, self.shape()[1] as u32)
-        };
+        let n_elms = self.strider.len() as u32;
+        let (m, s) = (self.shape()[1] as u32, self.shape()[2] as u32);
         let meta_buf = self
             .device
-            .make_storage_buffer("meta", bytemuck::cast_slice(&[m, n]));
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_elms, m, s]));
         let entries = &[
             wgpu::BindGroupEntry {
                 binding: 0,
@@ -457,13 +649,27 @@ impl Tensor for WgpuTensor {
                 resource: meta_buf.as_entire_binding(),
             },
         ];
-        let encoder =
-            self.device
-                .encode_pipeline_commnad("softmax_inplace", entries, (m * n / 16 + 1, 1, 1));
+        // the workgroups overflowing the limit of a dimension wrap into the rows of y
+        let n_groups = n_elms.div_ceil(32);
+        let n_groups_x = n_groups.min(MAX_WORKGROUPS_PER_DIM);
+        let n_groups_y = n_groups.div_ceil(n_groups_x.max(1));
+        let encoder = self.device.encode_pipeline_commnad(
+            "causal_mask_inplace",
+            entries,
+            (n_groups_x, n_groups_y, 1),
+        );
         self.device.queue.submit(Some(encoder.finish()));
         Ok(self)
     }
 
+    fn tree_mask_inplace(self, _parents: &[Option<usize>]) -> Result<Self> {
+        Err((
+            ErrorKind::NotImplemented,
+            "tree_mask is not implemented on wgpu yet",
+        )
+            .into())
+    }
+
     fn silu_inplace(self) -> Result<Self> {
         assert!(self.is_contiguous());
 
@@ -499,63 +705,15 @@ impl Tensor for WgpuTensor {
     }
 
     fn mul_inplace(self, rhs: &Self) -> Result<Self> {
-        assert!(self.is_contiguous());
-        assert!(rhs.is_contiguous());
-        let n_elms = self.strider.len();
-        let meta_buf = self
-            .device
-            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_elms as u32]));
-        let entries = &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: self.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: rhs.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: meta_buf.as_entire_binding(),
-            },
-        ];
-        let encoder = self.device.encode_pipeline_commnad(
-            "mul_inplace",
-            entries,
-            (n_elms as u32 / 32 + 1, 1, 1),
-        );
-        self.device.queue.submit(Some(encoder.finish()));
-        Ok(self)
+        self.elementwise_inplace("mul_inplace", rhs)
     }
 
     fn add_inplace(self, rhs: &Self) -> Result<Self> {
-        assert!(self.is_contiguous());
-        assert!(rhs.is_contiguous());
-        let n_elms = self.strider.len();
-        let meta_buf = self
-            .device
-            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_elms as u32]));
-        let entries = &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: self.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: rhs.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: meta_buf.as_entire_binding(),
-            },
-        ];
-        let encoder = self.device.encode_pipeline_commnad(
-            "add_inplace",
-            entries,
-            (n_elms as u32 / 32 + 1, 1, 1),
-        );
-        self.device.queue.submit(Some(encoder.finish()));
-        Ok(self)
+        self.elementwise_inplace("add_inplace", rhs)
+    }
+
+    fn div_inplace(self, rhs: &Self) -> Result<Self> {
+        self.elementwise_inplace("div_inplace", rhs)
     }
 
     fn scale_inplace(self, rhs: f32) -> Result<Self> {
@@ -568,7 +726,7 @@ impl Tensor for WgpuTensor {
         // TODO: make uniform buffer for meta
         let meta_buf = self
             .device
-            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_elms as u32]));
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_elms as u32, 1]));
         let entries = &[
             wgpu::BindGroupEntry {
                 binding: 0,
@@ -597,7 +755,7 @@ impl Tensor for WgpuTensor {
         let n_elms = self.strider.len();
         let meta_buf = self
             .device
-            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_elms as u32]));
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[n_elms as u32, 1]));
         let rhs_buf = self
             .device
             .make_storage_buffer("rhs", bytemuck::cast_slice(&[rhs]));
@@ -662,7 +820,7 @@ impl Tensor for WgpuTensor {
                 resource: output.buf.as_entire_binding(),
             },
         ];
-        assert!(meta.m / 32 < 65535); // vulkan limit each dimension to 65535
+        assert!(meta.m / 32 < MAX_WORKGROUPS_PER_DIM);
         let encoder =
             self.device
                 .encode_pipeline_commnad("sgemv", entries, (meta.b, meta.m / 32, 1));
@@ -672,23 +830,42 @@ impl Tensor for WgpuTensor {
     }
 
     /// (b, m, k) @ (b, k, n) => (b, m, n)
-    /// the A matrix is dense and the B matrix is allowed to be strided
+    /// the A matrix is dense and the B matrix is allowed to be strided. the batches of B may be
+    /// fewer than A's, each batch of B is shared by the consecutive batches of A like the KV
+    /// heads on GQA.
     fn batch_matmul(&self, y: &Self) -> Result<Self> {
+        if y.shape().len() != 3 {
+            return Err((
+                ErrorKind::NotImplemented,
+                "batch_matmul with a 2D rhs is not implemented on wgpu yet",
+            )
+                .into());
+        }
         assert!(self.shape().len() == 3);
-        assert!(y.shape().len() == 3);
-        assert!(self.shape()[0] == y.shape()[0]);
         assert!(self.shape()[2] == y.shape()[1]);
         assert!(self.is_contiguous());
+        let (a_batch, b_batch) = (self.shape()[0], y.shape()[0]);
+        if b_batch == 0 || a_batch % b_batch != 0 {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "can not batch_matmul the shapes {:?} and {:?}",
+                    self.shape(),
+                    y.shape()
+                ),
+            )
+                .into());
+        }
 
         // (b, m, k) @ (b, k, n) => (b, m, n)
         let output = Self::alloc(
-            &[y.shape()[0], self.shape()[1], y.shape()[2]],
+            &[a_batch, self.shape()[1], y.shape()[2]],
             GGMLType::F32,
             self.device.clone(),
         )?;
 
         let meta = BatchMatmulMeta {
-            b: y.shape()[0] as u32,
+            b: a_batch as u32,
             m: self.shape()[1] as u32,
             k: self.shape()[2] as u32,
             n: y.shape()[2] as u32,
@@ -697,7 +874,7 @@ impl Tensor for WgpuTensor {
                 y.strider.strides()[1] as u32,
                 y.strider.strides()[2] as u32,
             ],
-            ..Default::default()
+            broadcast_b: (a_batch / b_batch) as u32,
         };
         let meta_bytes = bytemuck::bytes_of(&meta);
 
@@ -794,9 +971,16 @@ mod tests {
 
     #[thread_local]
     static DEVICE: LazyLock<WgpuTensorDeviceRef> = LazyLock::new(|| {
-        WgpuTensorDevice::new(WgpuTensorDeviceOptions::new().with_debug_named_tensor(true))
+        WgpuTensorDevice::new(WgpuTensorDeviceOptions::new().with_debug_named_tensor(true)).unwrap()
     });
 
+    #[test]
+    fn test_wgpu_device_adapter_index() {
+        let opts = WgpuTensorDeviceOptions::new().with_adapter_index(usize::MAX);
+        let err = WgpuTensorDevice::new(opts).unwrap_err();
+        assert_eq!(err.kind, crate::error::ErrorKind::BadInput);
+    }
+
     #[test]
     fn test_wgpu_tensor_new_and_export() -> Result<()> {
         // let device = WgpuTensorDevice::new(WgpuTensorDeviceOptions::new());
@@ -843,6 +1027,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_tensor_broadcast() -> Result<()> {
+        // the (2, ) row and the (1, 2) row are cycled over the rows
+        let t1 = WgpuTensor::new(&[6.0; 6], &[3, 2], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&[2.0, 3.0], &[2], DEVICE.clone())?;
+        let t1 = t1.div_inplace(&t2)?;
+        let t3 = WgpuTensor::new(&[1.0, 2.0], &[1, 2], DEVICE.clone())?;
+        let t1 = t1.mul_inplace(&t3)?;
+        let mut dst = vec![0.0; 6];
+        t1.export(&mut dst)?;
+        assert_eq!(dst, vec![3.0, 4.0, 3.0, 4.0, 3.0, 4.0]);
+
+        // the (3, 1) column and the larger rhs are not cycled right, they're rejected
+        let column = WgpuTensor::new(&[2.0; 3], &[3, 1], DEVICE.clone())?;
+        let larger = WgpuTensor::new(&[2.0; 12], &[2, 3, 2], DEVICE.clone())?;
+        for rhs in [&column, &larger] {
+            let t1 = WgpuTensor::new(&[6.0; 6], &[3, 2], DEVICE.clone())?;
+            assert!(t1.mul_inplace(rhs).is_err());
+            let t1 = WgpuTensor::new(&[6.0; 6], &[3, 2], DEVICE.clone())?;
+            assert!(t1.div_inplace(rhs).is_err());
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_tensor_div_scalar() -> Result<()> {
         let t1 = WgpuTensor::new(&[6.0; 1024], &[512, 2], DEVICE.clone())?;
@@ -964,11 +1172,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_batch_matmul_gqa() -> Result<()> {
+        // 4 query heads on 2 KV heads, the heads 0, 1 share the KV head 0 and 2, 3 the KV head 1
+        let v1 = (0..8).map(|i| i as f32).collect::<Vec<_>>();
+        let t1 = WgpuTensor::new(&v1, &[4, 1, 2], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&[1.0, 1.0, 1.0, -1.0], &[2, 2, 1], DEVICE.clone())?;
+        let t3 = t1.batch_matmul(&t2)?;
+        assert_eq!(t3.shape(), &[4, 1, 1]);
+        let mut dst1 = vec![0.0; 4];
+        t3.export(&mut dst1)?;
+        assert_eq!(dst1, vec![1.0, 5.0, -1.0, -1.0]);
+
+        let t2 = WgpuTensor::new(&[1.0; 6], &[3, 2, 1], DEVICE.clone())?;
+        assert!(t1.batch_matmul(&t2).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_rope() -> Result<()> {
         let v1 = (0..32).map(|i| i as f32).collect::<Vec<_>>();
         let t1 = WgpuTensor::new(&v1, &[2, 16], DEVICE.clone())?;
-        let t1 = t1.rope_inplace(RopeMode::Llama, 1, 2)?;
+        let t1 = t1.rope_inplace(RopeMode::Llama, 1, 2, 10000.0, 1.0)?;
 
         let mut dst1 = vec![0.0; 32];
         t1.export(&mut dst1)?;
@@ -986,6 +1211,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_causal_mask_large() -> Result<()> {
+        // (n_heads, n_batch, seq) takes more than 65535 workgroups of 32 elements
+        let (n_heads, m, seq) = (2, 1000, 1100);
+        let t1 = WgpuTensor::new(
+            &vec![1.0; n_heads * m * seq],
+            &[n_heads, m, seq],
+            DEVICE.clone(),
+        )?;
+        let t1 = t1.causal_mask_inplace()?;
+
+        let mut dst1 = vec![0.0; n_heads * m * seq];
+        t1.export(&mut dst1)?;
+        for (row, vals) in dst1.chunks(seq).enumerate() {
+            // the query mi is at the position seq - m + mi
+            let visible = seq - m + row % m + 1;
+            assert!(vals[..visible].iter().all(|v| *v == 1.0), "row {}", row);
+            assert!(vals[visible..].iter().all(|v| *v < -1e38), "row {}", row);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_concatenate() -> Result<()> {
         // TODO: fix this test later
@@ -1070,6 +1317,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_log_softmax() -> Result<()> {
+        // the second row is all far below zero
+        let v1 = vec![1.0, 2.0, 3.0, -1001.0, -1002.0, -1003.0];
+        let t1 = WgpuTensor::new(&v1, &[2, 3], DEVICE.clone())?;
+        let t1 = t1.log_softmax_inplace(1)?;
+
+        let mut dst1 = vec![0.0; 6];
+        t1.export(&mut dst1)?;
+
+        assert_relative_eq!(
+            &dst1[..],
+            &[
+                -2.407606,
+                -1.4076059,
+                -0.40760595,
+                -0.40760595,
+                -1.4076059,
+                -2.407606
+            ][..],
+            epsilon = 1e-4
+        );
+
+        let t2 = WgpuTensor::new(&v1, &[2, 3], DEVICE.clone())?.softmax_inplace(1)?;
+        let mut dst2 = vec![0.0; 6];
+        t2.export(&mut dst2)?;
+        assert_relative_eq!(dst2[3], 0.66524094, epsilon = 1e-5);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_silu() -> Result<()> {
         let v1 = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
@@ -0,0 +1,230 @@
+use std::simd::i32x16;
+use std::simd::i8x16;
+use std::simd::num::SimdInt;
+
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// an embedding vector quantized into int8 with a scale per vector, the value is restored as
+/// `values[i] as f32 * scale`. it takes 1/4 of the memory of the f32 vector, which matters
+/// when storing millions of vectors for the similarity search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Int8Embedding {
+    pub scale: f32,
+    pub values: Vec<i8>,
+}
+
+impl Int8Embedding {
+    /// symmetric quantization, the element with the max absolute value is mapped to ±127.
+    pub fn quantize(embedding: &[f32]) -> Self {
+        let amax = embedding.iter().fold(0.0_f32, |m, v| m.max(v.abs()));
+        let scale = amax / 127.0;
+        let inv_scale = if scale == 0.0 { 0.0 } else { 1.0 / scale };
+        let values = embedding
+            .iter()
+            .map(|v| (v * inv_scale).round().clamp(-127.0, 127.0) as i8)
+            .collect();
+        Self { scale, values }
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.values.iter().map(|v| *v as f32 * self.scale).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// the dot product of the two vectors, approximates the dot product of the f32 vectors.
+    pub fn dot(&self, other: &Self) -> Result<f32> {
+        self.check_len(other)?;
+        let dot = vec_dot_i8_i8(&self.values, &other.values);
+        Ok(dot as f32 * self.scale * other.scale)
+    }
+
+    /// the scales are cancelled out in the cosine similarity, it's computed on the integers.
+    pub fn cosine_similarity(&self, other: &Self) -> Result<f32> {
+        self.check_len(other)?;
+        let dot = vec_dot_i8_i8(&self.values, &other.values) as f32;
+        let norm_a = (vec_dot_i8_i8(&self.values, &self.values) as f32).sqrt();
+        let norm_b = (vec_dot_i8_i8(&other.values, &other.values) as f32).sqrt();
+        if norm_a == 0.0 || norm_b == 0.0 {
+            return Ok(0.0);
+        }
+        Ok(dot / (norm_a * norm_b))
+    }
+
+    /// serialize into the little endian scale followed by the values.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.values.len());
+        buf.extend_from_slice(&self.scale.to_le_bytes());
+        buf.extend(self.values.iter().map(|v| *v as u8));
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 4 {
+            return Err((
+                ErrorKind::FormatError,
+                format!("int8 embedding needs at least 4 bytes, got {}", buf.len()),
+            )
+                .into());
+        }
+        let scale = f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let values = buf[4..].iter().map(|v| *v as i8).collect();
+        Ok(Self { scale, values })
+    }
+
+    fn check_len(&self, other: &Self) -> Result<()> {
+        if self.len() != other.len() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "embedding length mismatch: {} vs {}",
+                    self.len(),
+                    other.len()
+                ),
+            )
+                .into());
+        }
+        Ok(())
+    }
+}
+
+/// the dot product of two int8 vectors, accumulated in i32. a product is at most 127 * 127, so
+/// it does not overflow until the vectors are longer than 130k elements. the values are
+/// expected within ±127 like the symmetric quantization gives, -128 is not negated correctly by
+/// the VNNI path.
+pub fn vec_dot_i8_i8(a: &[i8], b: &[i8]) -> i32 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(all(
+        target_arch = "x86_64",
+        target_feature = "avx512vnni",
+        target_feature = "avx512vl"
+    ))]
+    {
+        vec_dot_i8_i8_vnni(a, b)
+    }
+
+    #[cfg(not(all(
+        target_arch = "x86_64",
+        target_feature = "avx512vnni",
+        target_feature = "avx512vl"
+    )))]
+    vec_dot_i8_i8_fallback(a, b)
+}
+
+// vpdpbusd multiplies the unsigned bytes of a by the signed bytes of b and sums each 4 of the
+// products into an i32 lane, the sign of a is moved onto b to take the signed a
+#[cfg(all(
+    target_arch = "x86_64",
+    target_feature = "avx512vnni",
+    target_feature = "avx512vl"
+))]
+fn vec_dot_i8_i8_vnni(a: &[i8], b: &[i8]) -> i32 {
+    use std::arch::x86_64::*;
+
+    let (a_chunks, a_rest) = a.as_chunks::<32>();
+    let (b_chunks, b_rest) = b.as_chunks::<32>();
+    let sum = unsafe {
+        let mut acc = _mm256_setzero_si256();
+        for (ac, bc) in a_chunks.iter().zip(b_chunks.iter()) {
+            let va = _mm256_loadu_si256(ac.as_ptr() as *const __m256i);
+            let vb = _mm256_loadu_si256(bc.as_ptr() as *const __m256i);
+            let ua = _mm256_sign_epi8(va, va);
+            let sb = _mm256_sign_epi8(vb, va);
+            acc = _mm256_dpbusd_epi32(acc, ua, sb);
+        }
+        let s = _mm_add_epi32(
+            _mm256_castsi256_si128(acc),
+            _mm256_extracti128_si256(acc, 1),
+        );
+        let s = _mm_add_epi32(s, _mm_shuffle_epi32(s, 0b01_00_11_10));
+        let s = _mm_add_epi32(s, _mm_shuffle_epi32(s, 0b10_11_00_01));
+        _mm_cvtsi128_si32(s)
+    };
+    let rest = a_rest
+        .iter()
+        .zip(b_rest.iter())
+        .map(|(a, b)| *a as i32 * *b as i32)
+        .sum::<i32>();
+    sum + rest
+}
+
+#[allow(dead_code)]
+fn vec_dot_i8_i8_fallback(a: &[i8], b: &[i8]) -> i32 {
+    let (a_chunks, a_rest) = a.as_chunks::<16>();
+    let (b_chunks, b_rest) = b.as_chunks::<16>();
+
+    let mut acc = i32x16::splat(0);
+    for (ac, bc) in a_chunks.iter().zip(b_chunks.iter()) {
+        let va: i32x16 = i8x16::from_array(*ac).cast();
+        let vb: i32x16 = i8x16::from_array(*bc).cast();
+        acc += va * vb;
+    }
+    let rest = a_rest
+        .iter()
+        .zip(b_rest.iter())
+        .map(|(a, b)| *a as i32 * *b as i32)
+        .sum::<i32>();
+    acc.reduce_sum() + rest
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_vec_dot_i8_i8() {
+        let a = (0..37).map(|i| (i - 18) as i8).collect::<Vec<_>>();
+        let b = (0..37).map(|i| (i % 5) as i8 * 30).collect::<Vec<_>>();
+        let expected = a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| *a as i32 * *b as i32)
+            .sum::<i32>();
+        assert_eq!(vec_dot_i8_i8(&a, &b), expected);
+        assert_eq!(vec_dot_i8_i8(&[127; 64], &[-127; 64]), -127 * 127 * 64);
+
+        // the SIMD path of the target agrees with the portable one over the chunks and the rest
+        let a = (0..101)
+            .map(|i| (i * 37 % 255 - 127) as i8)
+            .collect::<Vec<_>>();
+        let b = (0..101)
+            .map(|i| (i * 91 % 255 - 127) as i8)
+            .collect::<Vec<_>>();
+        assert_eq!(vec_dot_i8_i8(&a, &b), vec_dot_i8_i8_fallback(&a, &b));
+    }
+
+    #[test]
+    fn test_int8_embedding() -> Result<()> {
+        let a = (0..64).map(|i| (i as f32 * 0.1).sin()).collect::<Vec<_>>();
+        let b = (0..64).map(|i| (i as f32 * 0.1).cos()).collect::<Vec<_>>();
+        let qa = Int8Embedding::quantize(&a);
+        let qb = Int8Embedding::quantize(&b);
+        assert_eq!(qa.len(), 64);
+
+        for (v, dv) in a.iter().zip(qa.dequantize().iter()) {
+            assert_relative_eq!(v, dv, epsilon = qa.scale);
+        }
+
+        let dot = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>();
+        assert_relative_eq!(qa.dot(&qb)?, dot, epsilon = 0.05);
+        assert_relative_eq!(qa.cosine_similarity(&qa)?, 1.0, epsilon = 1e-6);
+
+        let zero = Int8Embedding::quantize(&[0.0; 64]);
+        assert_eq!(zero.cosine_similarity(&qa)?, 0.0);
+        assert!(qa.dot(&Int8Embedding::quantize(&[1.0])).is_err());
+
+        assert_eq!(Int8Embedding::from_bytes(&qa.to_bytes())?, qa);
+        assert!(Int8Embedding::from_bytes(&[0, 1]).is_err());
+        Ok(())
+    }
+}
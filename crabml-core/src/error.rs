@@ -1,3 +1,5 @@
+use std::backtrace::Backtrace;
+use std::backtrace::BacktraceStatus;
 use std::sync::Arc;
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -26,47 +28,156 @@ pub enum ErrorKind {
     /// raised on chat template is not found
     ChatTemplateNotFound,
 
+    /// raised when the tokens of a session exceed its context limit
+    ContextOverflow,
+
     /// unimplemented yet
     NotImplemented,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Error {
     pub kind: ErrorKind,
     pub message: String,
-    pub cause: Option<Arc<dyn std::error::Error>>,
+    pub cause: Option<Arc<dyn std::error::Error + Send + Sync>>,
+
+    /// the op which failed, like `matmul` or `load_tensor`.
+    pub op: Option<String>,
+
+    /// the names and the shapes of the tensors involved, like the tensor in the GGUF file which
+    /// failed to load.
+    pub tensors: Vec<(String, Vec<usize>)>,
+
+    /// captured on creating the error if RUST_BACKTRACE or RUST_LIB_BACKTRACE is set. it's
+    /// only printed by `{:#}` and `{:?}`, like the error returned from main, the plain `{}`
+    /// ends up in the responses to the clients.
+    pub backtrace: Option<Arc<Backtrace>>,
 }
 
 impl Error {
     pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        let backtrace = Backtrace::capture();
+        let backtrace = match backtrace.status() {
+            BacktraceStatus::Captured => Some(Arc::new(backtrace)),
+            _ => None,
+        };
         Error {
             kind,
             message: message.into(),
             cause: None,
+            op: None,
+            tensors: vec![],
+            backtrace,
+        }
+    }
+
+    pub fn with_cause(mut self, cause: impl std::error::Error + Send + Sync + 'static) -> Self {
+        self.cause = Some(Arc::new(cause));
+        self
+    }
+
+    /// the op is kept if it's already set, the innermost op is the one which failed.
+    pub fn with_op(mut self, op: impl Into<String>) -> Self {
+        if self.op.is_none() {
+            self.op = Some(op.into());
         }
+        self
+    }
+
+    pub fn with_tensor(mut self, name: impl Into<String>, shape: &[usize]) -> Self {
+        self.tensors.push((name.into(), shape.to_vec()));
+        self
     }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{:?}: {}", self.kind, self.message)?;
+        if let Some(op) = self.op.as_ref() {
+            write!(f, "\nop: {}", op)?;
+        }
+        for (name, shape) in self.tensors.iter() {
+            write!(f, "\ntensor: {} {:?}", name, shape)?;
+        }
         if let Some(cause) = self.cause.as_ref() {
             write!(f, "\ncaused by: {}", cause)?;
         }
+        if let Some(backtrace) = self.backtrace.as_ref().filter(|_| f.alternate()) {
+            write!(f, "\nbacktrace:\n{}", backtrace)?;
+        }
         Ok(())
     }
 }
 
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:#}", self)
+    }
+}
+
 impl<S: Into<String>> From<(ErrorKind, S)> for Error {
     fn from((kind, message): (ErrorKind, S)) -> Self {
-        Self {
-            kind,
-            message: message.into(),
-            cause: None,
-        }
+        Self::new(kind, message)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::new(ErrorKind::IOError, err.to_string()).with_cause(err)
+    }
+}
+
+/// attaches the context to the error of a result.
+pub trait ResultExt<T> {
+    fn with_op(self, op: &str) -> Result<T>;
+
+    fn with_tensor(self, name: &str, shape: &[usize]) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn with_op(self, op: &str) -> Result<T> {
+        self.map_err(|err| err.with_op(op))
+    }
+
+    fn with_tensor(self, name: &str, shape: &[usize]) -> Result<T> {
+        self.map_err(|err| err.with_tensor(name, shape))
     }
 }
 
 impl std::error::Error for Error {}
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_context() {
+        let result: Result<()> = Err(Error::new(ErrorKind::TensorError, "bad shape"));
+        let mut err = result
+            .with_op("matmul")
+            .with_tensor("blk.0.attn_q.weight", &[64, 32])
+            .with_op("forward")
+            .unwrap_err();
+        assert_eq!(err.op.as_deref(), Some("matmul"));
+        err.backtrace = Some(Arc::new(Backtrace::force_capture()));
+        let text = err.to_string();
+        // the backtrace is left to {:#} and {:?}, {} is sent to the clients
+        assert!(!text.contains("backtrace"), "{}", text);
+        assert!(format!("{:#}", err).contains("\nbacktrace:\n"));
+        assert!(format!("{:?}", err).contains("\nbacktrace:\n"));
+        assert!(
+            text.starts_with(
+                "TensorError: bad shape\nop: matmul\ntensor: blk.0.attn_q.weight [64, 32]"
+            ),
+            "{}",
+            text
+        );
+
+        let io_err = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let err = Error::from(io_err);
+        assert_eq!(err.kind, ErrorKind::IOError);
+        assert!(err.cause.is_some());
+    }
+}
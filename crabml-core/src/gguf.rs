@@ -2,17 +2,18 @@ use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::File;
 use std::mem;
-use std::sync::Arc;
 
 use int_enum::IntEnum;
 use memmap2::Mmap;
 
+use crate::backends::cpu::CpuTensorBuf;
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
+use crate::source::TensorDataSource;
 
 const GGUF_MAGIC: u32 = 0x46554747;
-const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+pub const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
 
 // General
 pub const KEY_GENERAL_ARCHITECTURE: &str = "general.architecture";
@@ -34,6 +35,8 @@ pub const KEY_BLOCK_COUNT: &str = "{arch}.block_count";
 pub const KEY_FEED_FORWARD_LENGTH: &str = "{arch}.feed_forward_length";
 pub const KEY_USE_PARALLEL_RESIDUAL: &str = "{arch}.use_parallel_residual";
 pub const KEY_TENSOR_DATA_LAYOUT: &str = "{arch}.tensor_data_layout";
+pub const KEY_EXPERT_COUNT: &str = "{arch}.expert_count";
+pub const KEY_EXPERT_USED_COUNT: &str = "{arch}.expert_used_count";
 
 // Attention
 pub const KEY_ATTENTION_HEAD_COUNT: &str = "{arch}.attention.head_count";
@@ -50,6 +53,7 @@ pub const KEY_ROPE_SCALE_LINEAR: &str = "{arch}.rope.scale_linear";
 
 // Tokenization
 pub const KEY_TOKENIZER_MODEL: &str = "tokenizer.ggml.model";
+pub const KEY_TOKENIZER_PRE: &str = "tokenizer.ggml.pre";
 pub const KEY_TOKENIZER_LIST: &str = "tokenizer.ggml.tokens";
 pub const KEY_TOKENIZER_TOKEN_TYPE: &str = "tokenizer.ggml.token_type";
 pub const KEY_TOKENIZER_SCORES: &str = "tokenizer.ggml.scores";
@@ -100,10 +104,13 @@ pub enum GGMLType {
     Q5K = 13,
     Q6K = 14,
     Q8K = 15,
-    I8 = 16,
-    I16 = 17,
-    I32 = 18,
-    COUNT = 19,
+    // the ids in between are the IQ quantizations, which are not supported
+    I8 = 24,
+    I16 = 25,
+    I32 = 26,
+    // I64 (27), F64 (28) and IQ1_M (29) are not supported
+    BF16 = 30,
+    COUNT = 31,
 }
 
 impl Display for GGMLType {
@@ -127,18 +134,110 @@ impl Display for GGMLType {
             GGMLType::I16 => write!(f, "I16"),
             GGMLType::I32 => write!(f, "I32"),
             GGMLType::COUNT => write!(f, "COUNT"),
+            GGMLType::BF16 => write!(f, "BF16"),
         }
     }
 }
 
+impl GGMLType {
+    /// the number of the elements quantized together in a block, 1 on the float and the integer
+    /// types. the rows of a quantized tensor are made of whole blocks.
+    pub fn block_size(&self) -> usize {
+        match self {
+            GGMLType::Q4_0
+            | GGMLType::Q4_1
+            | GGMLType::Q5_0
+            | GGMLType::Q5_1
+            | GGMLType::Q8_0
+            | GGMLType::Q8_1 => 32,
+            GGMLType::Q2K
+            | GGMLType::Q3K
+            | GGMLType::Q4K
+            | GGMLType::Q5K
+            | GGMLType::Q6K
+            | GGMLType::Q8K => 256,
+            _ => 1,
+        }
+    }
+}
+
+/// the types which have kernels, the tensors of the other types can be parsed, but not loaded.
+pub const SUPPORTED_GGML_TYPES: &[GGMLType] = &[
+    GGMLType::F32,
+    GGMLType::F16,
+    GGMLType::BF16,
+    GGMLType::Q4_0,
+    GGMLType::Q4_1,
+    GGMLType::Q5_0,
+    GGMLType::Q5_1,
+    GGMLType::Q8_0,
+    GGMLType::Q8_1,
+    GGMLType::Q2K,
+    GGMLType::Q3K,
+    GGMLType::Q4K,
+    GGMLType::Q5K,
+    GGMLType::Q6K,
+    GGMLType::Q8K,
+];
+
+// the names of the types in ggml which are not in GGMLType, like the IQ quantizations
+fn ggml_type_name(id: u32) -> String {
+    let name = match id {
+        16 => "IQ2_XXS",
+        17 => "IQ2_XS",
+        18 => "IQ3_XXS",
+        19 => "IQ1_S",
+        20 => "IQ4_NL",
+        21 => "IQ3_S",
+        22 => "IQ2_S",
+        23 => "IQ4_XS",
+        27 => "I64",
+        28 => "F64",
+        29 => "IQ1_M",
+        _ => return format!("type {}", id),
+    };
+    name.to_string()
+}
+
+/// a single error for all the tensors in the unsupported types, the tensors are grouped by
+/// their types in (name, type).
+pub fn unsupported_types_error(tensors: &[(String, String)]) -> Error {
+    let mut groups: Vec<(&str, Vec<&str>)> = vec![];
+    for (name, typ) in tensors {
+        match groups.iter_mut().find(|(t, _)| t == typ) {
+            Some((_, names)) => names.push(name),
+            None => groups.push((typ, vec![name])),
+        }
+    }
+    let groups = groups
+        .iter()
+        .map(|(typ, names)| format!("{} ({})", typ, names.join(", ")))
+        .collect::<Vec<_>>();
+    let supported = SUPPORTED_GGML_TYPES
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>();
+    Error::new(
+        ErrorKind::NotImplemented,
+        format!(
+            "{} tensors are in the types not supported yet: {}. the supported types are {}",
+            tensors.len(),
+            groups.join("; "),
+            supported.join(", ")
+        ),
+    )
+}
+
 impl TryFrom<u32> for GGMLType {
     type Error = Error;
 
     fn try_from(v: u32) -> std::result::Result<Self, Self::Error> {
-        Self::from_int(v).map_err(|err| Error {
-            kind: ErrorKind::FormatError,
-            message: format!("failed to decode the ggml type for {}", v),
-            cause: Some(Arc::new(err)),
+        Self::from_int(v).map_err(|err| {
+            Error::new(
+                ErrorKind::FormatError,
+                format!("failed to decode the ggml type for {}", v),
+            )
+            .with_cause(err)
         })
     }
 }
@@ -181,10 +280,12 @@ impl TryFrom<u32> for GGUFMetadataValueType {
     type Error = Error;
 
     fn try_from(v: u32) -> std::result::Result<Self, Self::Error> {
-        Self::from_int(v).map_err(|err| Error {
-            kind: ErrorKind::FormatError,
-            message: format!("failed to decode the value type for {}", v),
-            cause: Some(Arc::new(err)),
+        Self::from_int(v).map_err(|err| {
+            Error::new(
+                ErrorKind::FormatError,
+                format!("failed to decode the value type for {}", v),
+            )
+            .with_cause(err)
         })
     }
 }
@@ -258,15 +359,14 @@ impl<'a> GGUFBufReader<'a> {
 
     pub fn read(&mut self, n: usize) -> Result<&'a [u8]> {
         if n > self.cursor.len() {
-            return Err(Error {
-                kind: ErrorKind::FormatError,
-                message: format!(
+            return Err(Error::new(
+                ErrorKind::FormatError,
+                format!(
                     "failed to read {} bytes from the buffer, only {} bytes left",
                     n,
                     self.cursor.len()
                 ),
-                cause: None,
-            });
+            ));
         }
         let v = &self.cursor[0..n];
         self.cursor = &self.cursor[n..];
@@ -385,11 +485,8 @@ impl<'a, 'b> GGUFMetadataReader<'a, 'b> {
     pub fn read_string(&mut self) -> Result<&'a str> {
         let len = self.read_len()?;
         let buf = self.buf.read(len)?;
-        let s = std::str::from_utf8(buf).map_err(|e| Error {
-            kind: ErrorKind::FormatError,
-            message: "Invalid UTF-8 string".to_string(),
-            cause: Some(Arc::new(e)),
-        });
+        let s = std::str::from_utf8(buf)
+            .map_err(|e| Error::new(ErrorKind::FormatError, "Invalid UTF-8 string").with_cause(e));
         s
     }
 
@@ -523,21 +620,22 @@ impl<'a> GGUFHeader<'a> {
         let mut r = GGUFMetadataReader::new(buf, GGUFVersion::V2);
         let magic = r.read_u32()?;
         if magic != GGUF_MAGIC {
-            return Err(Error {
-                kind: ErrorKind::FormatError,
-                message: format!("Invalid magic number: {}", magic),
-                cause: None,
-            });
+            return Err(Error::new(
+                ErrorKind::FormatError,
+                format!("Invalid magic number: {}", magic),
+            ));
         }
 
         let version = r.read_u32()?;
-        let version = GGUFVersion::from_int(version).map_err(|err| Error {
-            kind: ErrorKind::FormatError,
-            message: format!(
-                "Unsupported version number: {}, only 1, 2 is supported yet",
-                version
-            ),
-            cause: Some(Arc::new(err)),
+        let version = GGUFVersion::from_int(version).map_err(|err| {
+            Error::new(
+                ErrorKind::FormatError,
+                format!(
+                    "Unsupported version number: {}, only 1, 2 is supported yet",
+                    version
+                ),
+            )
+            .with_cause(err)
         })?;
         r.version = version;
 
@@ -557,11 +655,10 @@ impl<'a> GGUFHeader<'a> {
         let architecture = match metadata.get_string(KEY_GENERAL_ARCHITECTURE) {
             Some(s) => s.to_string(),
             _ => {
-                return Err(Error {
-                    kind: ErrorKind::FormatError,
-                    message: "Missing string metadata general.architecture".to_string(),
-                    cause: None,
-                });
+                return Err(Error::new(
+                    ErrorKind::FormatError,
+                    "Missing string metadata general.architecture",
+                ));
             }
         };
 
@@ -634,19 +731,28 @@ struct GGUFOnDiskTensorInfo {
 }
 
 impl GGUFOnDiskTensorInfo {
-    pub fn decode(buf: &mut GGUFBufReader, version: GGUFVersion) -> Result<Self> {
+    /// returns the name and the type id of the tensor in Err if its type is unknown, the
+    /// decoding goes on over it to report all the tensors of the unknown types at once.
+    pub fn decode(
+        buf: &mut GGUFBufReader,
+        version: GGUFVersion,
+    ) -> Result<std::result::Result<Self, (String, u32)>> {
         let mut r = GGUFMetadataReader::new(buf, version);
         let name = r.read_string()?.to_string();
         let n_dimensions = r.read_u32()? as usize;
         let dimensions = r.read_len_array(n_dimensions)?;
-        let typ = GGMLType::try_from(r.read_u32()?)?;
+        let typ_id = r.read_u32()?;
         let offset = r.read_u64()?;
-        Ok(Self {
+        let typ = match GGMLType::try_from(typ_id) {
+            Ok(typ) => typ,
+            Err(_) => return Ok(Err((name, typ_id))),
+        };
+        Ok(Ok(Self {
             name,
             dimensions,
             typ,
             offset,
-        })
+        }))
     }
 }
 
@@ -713,30 +819,51 @@ pub struct GGUFFile<'a> {
 
 impl<'a> GGUFFile<'a> {
     fn decode(buf: &mut GGUFBufReader<'a>) -> Result<Self> {
+        let (header, on_disk_tensor_infos) = Self::decode_header_and_infos(buf)?;
+        let tensor_data = buf.cursor();
+
+        // convert the on-disk tensor infos to in-memory
+        let tensor_infos = Self::convert_tensor_infos(&on_disk_tensor_infos, tensor_data)?;
+
+        Ok(Self {
+            header,
+            tensor_infos,
+            _tensor_data: tensor_data,
+        })
+    }
+
+    /// decode the header and the on disk tensor infos, leaves the cursor of buf at the
+    /// beginning of the tensor data.
+    fn decode_header_and_infos(
+        buf: &mut GGUFBufReader<'a>,
+    ) -> Result<(GGUFHeader<'a>, Vec<GGUFOnDiskTensorInfo>)> {
         let header = GGUFHeader::decode(buf)?;
 
         // load on disk tensor infos
         let mut on_disk_tensor_infos = Vec::with_capacity(header.tensor_count);
+        let mut unknown_tensors = vec![];
         for _ in 0..header.tensor_count {
-            let tensor_info = GGUFOnDiskTensorInfo::decode(buf, header.version)?;
-            on_disk_tensor_infos.push(tensor_info);
+            match GGUFOnDiskTensorInfo::decode(buf, header.version)? {
+                Ok(tensor_info) => on_disk_tensor_infos.push(tensor_info),
+                Err((name, typ_id)) => unknown_tensors.push((name, ggml_type_name(typ_id))),
+            }
+        }
+        if !unknown_tensors.is_empty() {
+            return Err(unsupported_types_error(&unknown_tensors));
         }
 
         // find the tensor_data position
         let position = buf.read_bytes();
         let alignment = header.alignment() as usize;
-        let next_position = position - (position % alignment) + alignment;
+        if alignment == 0 {
+            return Err(Error::new(
+                ErrorKind::FormatError,
+                "general.alignment should not be 0",
+            ));
+        }
+        let next_position = position.next_multiple_of(alignment);
         let _ = buf.read(next_position - position)?;
-        let tensor_data = buf.cursor();
-
-        // convert the on-disk tensor infos to in-memory
-        let tensor_infos = Self::convert_tensor_infos(&on_disk_tensor_infos, tensor_data)?;
-
-        Ok(Self {
-            header,
-            tensor_infos,
-            _tensor_data: tensor_data,
-        })
+        Ok((header, on_disk_tensor_infos))
     }
 
     fn convert_tensor_infos(
@@ -750,7 +877,24 @@ impl<'a> GGUFFile<'a> {
             } else {
                 tensor_infos[i + 1].offset as usize
             };
-            let data = &tensor_data[tensor_info.offset as usize..next_offset];
+            // a truncated file or a broken offset would panic on slicing the mmap
+            let offset = tensor_info.offset as usize;
+            if offset > next_offset || next_offset > tensor_data.len() {
+                return Err(Error::new(
+                    ErrorKind::FormatError,
+                    format!(
+                        "the data of tensor {} at {}..{} is out of the {} bytes of tensor data, \
+                         the file may be truncated",
+                        tensor_info.name,
+                        offset,
+                        next_offset,
+                        tensor_data.len()
+                    ),
+                )
+                .with_op("load_tensor")
+                .with_tensor(&tensor_info.name, &tensor_info.dimensions));
+            }
+            let data = &tensor_data[offset..next_offset];
 
             let item = GGUFTensorInfo::new(
                 tensor_info.name.clone(),
@@ -775,6 +919,10 @@ impl<'a> GGUFFile<'a> {
         self.header.version
     }
 
+    pub fn alignment(&self) -> u64 {
+        self.header.alignment()
+    }
+
     pub fn metadata(&self) -> &GGUFMetadata {
         &self.header.metadata
     }
@@ -789,52 +937,253 @@ impl<'a> GGUFFile<'a> {
             .find(|ti| ti.name() == name)
             .cloned()
     }
+
+    /// read a byte of each page of the tensor data, so the pages of the mmap are faulted in up
+    /// front instead of on the first forward. returns the bytes of the tensor data.
+    pub fn touch_tensor_data(&self) -> usize {
+        let mut checksum = 0u8;
+        let mut n_bytes = 0;
+        for info in &self.tensor_infos {
+            let data = info.data();
+            for b in data.iter().step_by(TOUCH_PAGE_SIZE) {
+                checksum = checksum.wrapping_add(*b);
+            }
+            n_bytes += data.len();
+        }
+        std::hint::black_box(checksum);
+        n_bytes
+    }
 }
 
+// the smallest page size of the platforms, touching more often than the actual page size
+// costs little
+const TOUCH_PAGE_SIZE: usize = 4096;
+
 pub struct GGUFFileLoader {
-    mmap: memmap2::Mmap,
+    buf: GGUFFileBuf,
+    prefetch_error: Option<Error>,
+}
+
+enum GGUFFileBuf {
+    Mmap(memmap2::Mmap),
+    #[cfg(windows)]
+    LargePages(crate::win_memory::LargePageBuf),
+}
+
+impl GGUFFileBuf {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            GGUFFileBuf::Mmap(mmap) => &mmap[..],
+            #[cfg(windows)]
+            GGUFFileBuf::LargePages(buf) => &buf[..],
+        }
+    }
 }
 
 impl GGUFFileLoader {
     pub fn new(path: &str, mlock: bool) -> Result<Self> {
-        let file = File::open(path).map_err(|err| Error {
-            kind: ErrorKind::IOError,
-            message: format!("failed to open the file: {}", path),
-            cause: Some(Arc::new(err)),
+        let file = File::open(path).map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to open the file: {}", path),
+            )
+            .with_cause(err)
         })?;
 
         let mmap = unsafe {
-            Mmap::map(&file).map_err(|err| Error {
-                kind: ErrorKind::IOError,
-                message: format!("failed to mmap file: {}", path),
-                cause: Some(Arc::new(err)),
+            Mmap::map(&file).map_err(|err| {
+                Error::new(ErrorKind::IOError, format!("failed to mmap file: {}", path))
+                    .with_cause(err)
             })?
         };
-        mmap.advise(memmap2::Advice::WillNeed)
-            .map_err(|err| Error {
-                kind: ErrorKind::IOError,
-                message: format!("failed to advise the mmap: {}", path),
-                cause: Some(Arc::new(err)),
-            })?;
-        if mlock {
-            mmap.lock().map_err(|err| Error {
-                kind: ErrorKind::IOError,
-                message: format!("failed to advise the mmap: {}", path),
-                cause: Some(Arc::new(err)),
-            })?;
+        // the prefetch is only a hint to read the pages in ahead, the pages are still faulted in
+        // on the first access if it fails, so it never fails the loading. the error is kept for
+        // the caller to warn about it.
+        let mut prefetch_error = None;
+        #[cfg(unix)]
+        {
+            if let Err(err) = mmap.advise(memmap2::Advice::WillNeed) {
+                prefetch_error = Some(
+                    Error::new(
+                        ErrorKind::IOError,
+                        format!("failed to advise the mmap: {}", path),
+                    )
+                    .with_cause(err),
+                );
+            }
+            if mlock {
+                mmap.lock().map_err(|err| {
+                    Error::new(
+                        ErrorKind::IOError,
+                        format!("failed to advise the mmap: {}", path),
+                    )
+                    .with_cause(err)
+                })?;
+            }
+        }
+        #[cfg(windows)]
+        {
+            if let Err(err) = crate::win_memory::prefetch(&mmap) {
+                prefetch_error = Some(err);
+            }
+            if mlock {
+                crate::win_memory::lock(&mmap)?;
+            }
+        }
+        Ok(Self {
+            buf: GGUFFileBuf::Mmap(mmap),
+            prefetch_error,
+        })
+    }
+
+    /// read the whole file into a buffer allocated in large pages on Windows, it requires the
+    /// "Lock pages in memory" privilege. on the other platforms it's the same as mmap the file,
+    /// linux can back the mapping with transparent huge pages.
+    pub fn new_with_large_pages(path: &str) -> Result<Self> {
+        #[cfg(windows)]
+        {
+            let buf = crate::win_memory::LargePageBuf::read_file(path)?;
+            Ok(Self {
+                buf: GGUFFileBuf::LargePages(buf),
+                prefetch_error: None,
+            })
+        }
+        #[cfg(not(windows))]
+        {
+            Self::new(path, false)
         }
-        Ok(Self { mmap })
+    }
+
+    /// the error of reading in the pages of the file ahead on loading. the loading goes on
+    /// without the prefetch, it's up to the caller to warn about it.
+    pub fn prefetch_error(&self) -> Option<&Error> {
+        self.prefetch_error.as_ref()
     }
 
     pub fn open(&self) -> Result<GGUFFile<'_>> {
-        let buf = &mut GGUFBufReader::new(&self.mmap[..]);
+        let buf = &mut GGUFBufReader::new(self.buf.as_bytes());
         GGUFFile::decode(buf)
     }
 }
 
+/// loads a GGUF file from a TensorDataSource lazily: only the header and the tensor infos are
+/// fetched on open, the tensor data is fetched on demand with `read_tensor_data`.
+pub struct GGUFLazyFileLoader<S: TensorDataSource> {
+    source: S,
+    header_buf: Vec<u8>,
+}
+
+impl<S: TensorDataSource> GGUFLazyFileLoader<S> {
+    pub fn new(source: S) -> Result<Self> {
+        let total = source.size()?;
+
+        // we do not know how large the header is before decoding it, start with a small prefix
+        // and double it until the header and the tensor infos can be decoded.
+        let mut prefix_len = (1024 * 1024).min(total);
+        loop {
+            let header_buf = source.read_range(0, prefix_len as usize)?;
+            let decoded = GGUFFile::decode_header_and_infos(&mut GGUFBufReader::new(&header_buf))
+                .map(|(_, _)| ());
+            match decoded {
+                Ok(()) => return Ok(Self { source, header_buf }),
+                Err(err) if prefix_len >= total || err.kind != ErrorKind::FormatError => {
+                    return Err(err);
+                }
+                Err(_) => prefix_len = (prefix_len * 2).min(total),
+            }
+        }
+    }
+
+    pub fn open(&self) -> Result<GGUFLazyFile<'_>> {
+        let buf = &mut GGUFBufReader::new(&self.header_buf);
+        let (header, on_disk_tensor_infos) = GGUFFile::decode_header_and_infos(buf)?;
+        Ok(GGUFLazyFile {
+            header,
+            tensor_infos: on_disk_tensor_infos,
+            tensor_data_offset: buf.read_bytes() as u64,
+            source: &self.source,
+        })
+    }
+}
+
+pub struct GGUFLazyFile<'a> {
+    header: GGUFHeader<'a>,
+    tensor_infos: Vec<GGUFOnDiskTensorInfo>,
+    tensor_data_offset: u64,
+    source: &'a dyn TensorDataSource,
+}
+
+impl<'a> GGUFLazyFile<'a> {
+    pub fn architecture(&self) -> &str {
+        self.header.architecture()
+    }
+
+    pub fn version(&self) -> GGUFVersion {
+        self.header.version
+    }
+
+    pub fn metadata(&self) -> &GGUFMetadata {
+        &self.header.metadata
+    }
+
+    pub fn tensor_names(&self) -> Vec<&str> {
+        self.tensor_infos.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    /// returns the type and the dimensions of the tensor.
+    pub fn tensor_type_and_dims(&self, name: &str) -> Option<(GGMLType, &[usize])> {
+        self.tensor_infos
+            .iter()
+            .find(|t| t.name == name)
+            .map(|t| (t.typ, t.dimensions.as_slice()))
+    }
+
+    /// fetch the data of a tensor from the source. the data is owned, it's up to the caller
+    /// to cache it. the length is computed from the type and the shape of the tensor, the
+    /// alignment padding after it is not fetched.
+    pub fn read_tensor_data(&self, name: &str) -> Result<Vec<u8>> {
+        let info = self
+            .tensor_infos
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::TensorNotFound,
+                    format!(
+                        "failed to find tensor {} in {}",
+                        name,
+                        self.source.describe()
+                    ),
+                )
+            })?;
+        let n_elems = info.dimensions.iter().product::<usize>();
+        let len = CpuTensorBuf::raw_bytes_len(info.typ, n_elems)?;
+        let start = self.tensor_data_offset.checked_add(info.offset);
+        let size = self.source.size()?;
+        let remaining = start.and_then(|start| size.checked_sub(start));
+        match (start, remaining) {
+            (Some(start), Some(remaining)) if remaining >= len as u64 => {
+                self.source.read_range(start, len)
+            }
+            _ => Err(Error::new(
+                ErrorKind::FormatError,
+                format!(
+                    "the data of tensor {} at offset {} takes {} bytes, beyond the {} bytes of {}",
+                    name,
+                    info.offset,
+                    len,
+                    size,
+                    self.source.describe()
+                ),
+            )),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::source::LocalFileSource;
 
     #[test]
     fn test_load_tensors() -> Result<()> {
@@ -847,6 +1196,12 @@ mod tests {
         assert_eq!(gf.tensor_infos[0].data().len() % 32, 0);
         assert_eq!(gf.tensor_infos[0].typ().to_string(), "F32");
         assert_eq!(gf.tensor_infos[0].dimensions(), vec![64, 512]);
+        let n_bytes = gf
+            .tensor_infos
+            .iter()
+            .map(|t| t.data().len())
+            .sum::<usize>();
+        assert_eq!(gf.touch_tensor_data(), n_bytes);
 
         let typs = gf
             .tensor_infos
@@ -913,6 +1268,146 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_decode_aligned_header() -> Result<()> {
+        fn put_str(buf: &mut Vec<u8>, s: &str) {
+            buf.extend((s.len() as u64).to_le_bytes());
+            buf.extend(s.as_bytes());
+        }
+
+        // pad the name of the tensor to end the header on every offset around the alignment,
+        // the header which ends right on it has no padding before the tensor data
+        for name_len in 1..=32 {
+            let mut buf = vec![];
+            buf.extend(GGUF_MAGIC.to_le_bytes());
+            buf.extend(3u32.to_le_bytes());
+            buf.extend(1u64.to_le_bytes()); // tensor count
+            buf.extend(1u64.to_le_bytes()); // metadata count
+            put_str(&mut buf, KEY_GENERAL_ARCHITECTURE);
+            buf.extend((GGUFMetadataValueType::String as u32).to_le_bytes());
+            put_str(&mut buf, "llama");
+            put_str(&mut buf, &"w".repeat(name_len));
+            buf.extend(1u32.to_le_bytes()); // n_dimensions
+            buf.extend(4u64.to_le_bytes());
+            buf.extend((GGMLType::F32 as u32).to_le_bytes());
+            buf.extend(0u64.to_le_bytes()); // offset
+            let header_len = buf.len();
+            buf.resize(header_len.next_multiple_of(32), 0);
+            let data = [1.0f32, 2.0, 3.0, 4.0]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>();
+            buf.extend(&data);
+
+            let gf = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+            assert_eq!(
+                gf.tensor_infos()[0].data(),
+                &data,
+                "header_len {}",
+                header_len
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_load_tensors() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let loader = GGUFFileLoader::new(path, false)?;
+        let gf = loader.open()?;
+
+        let lazy_loader = GGUFLazyFileLoader::new(LocalFileSource::new(path)?)?;
+        let lazy_gf = lazy_loader.open()?;
+        assert_eq!(lazy_gf.architecture(), "llama");
+        assert_eq!(lazy_gf.tensor_names().len(), 48);
+
+        for info in gf.tensor_infos() {
+            let data = lazy_gf.read_tensor_data(info.name())?;
+            let n_elems = info.dimensions().iter().product::<usize>();
+            assert_eq!(
+                data.len(),
+                CpuTensorBuf::raw_bytes_len(info.typ(), n_elems)?
+            );
+            assert_eq!(
+                data,
+                info.data()[..data.len()],
+                "tensor {} mismatch",
+                info.name()
+            );
+            let (typ, dims) = lazy_gf.tensor_type_and_dims(info.name()).unwrap();
+            assert_eq!(typ, info.typ());
+            assert_eq!(dims, info.dimensions());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_types_error() {
+        let tensors = [
+            ("blk.0.attn_q.weight".to_string(), ggml_type_name(23)),
+            ("blk.0.attn_k.weight".to_string(), ggml_type_name(22)),
+            ("blk.1.attn_q.weight".to_string(), ggml_type_name(23)),
+        ];
+        let err = unsupported_types_error(&tensors);
+        assert_eq!(err.kind, ErrorKind::NotImplemented);
+        assert!(
+            err.message.starts_with(
+                "3 tensors are in the types not supported yet: \
+                 IQ4_XS (blk.0.attn_q.weight, blk.1.attn_q.weight); IQ2_S (blk.0.attn_k.weight). \
+                 the supported types are F32, F16, BF16, Q4_0"
+            ),
+            "{}",
+            err.message
+        );
+    }
+
+    #[test]
+    fn test_decode_iq_tensor() {
+        fn put_str(buf: &mut Vec<u8>, s: &str) {
+            buf.extend((s.len() as u64).to_le_bytes());
+            buf.extend(s.as_bytes());
+        }
+
+        // the ids 16 to 18 are the IQ quantizations in ggml, not the integer types
+        let mut buf = vec![];
+        buf.extend(GGUF_MAGIC.to_le_bytes());
+        buf.extend(3u32.to_le_bytes());
+        buf.extend(1u64.to_le_bytes()); // tensor count
+        buf.extend(1u64.to_le_bytes()); // metadata count
+        put_str(&mut buf, KEY_GENERAL_ARCHITECTURE);
+        buf.extend((GGUFMetadataValueType::String as u32).to_le_bytes());
+        put_str(&mut buf, "llama");
+        put_str(&mut buf, "blk.0.attn_q.weight");
+        buf.extend(1u32.to_le_bytes()); // n_dimensions
+        buf.extend(256u64.to_le_bytes());
+        buf.extend(16u32.to_le_bytes()); // IQ2_XXS
+        buf.extend(0u64.to_le_bytes()); // offset
+        buf.resize(buf.len().next_multiple_of(32) + 66, 0);
+
+        let err = GGUFFile::decode(&mut GGUFBufReader::new(&buf))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::NotImplemented);
+        assert!(
+            err.message.contains("IQ2_XXS (blk.0.attn_q.weight)"),
+            "{}",
+            err.message
+        );
+        assert_eq!(GGMLType::try_from(24).unwrap(), GGMLType::I8);
+        assert_eq!(GGMLType::try_from(26).unwrap(), GGMLType::I32);
+    }
+
+    #[test]
+    fn test_load_truncated_file() -> Result<()> {
+        let buf = std::fs::read("../testdata/tinyllamas-stories-260k-f32.gguf").unwrap();
+        let truncated = &buf[..buf.len() / 2];
+        let mut r = GGUFBufReader::new(truncated);
+        let err = GGUFFile::decode(&mut r).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        assert!(err.message.contains("truncated"), "{}", err.message);
+        Ok(())
+    }
+
     #[test]
     fn test_load_metadata() -> Result<()> {
         let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use half::bf16;
+use half::f16;
+
+use crate::backends::cpu::CpuTensorBuf;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::gguf::GGUFFile;
+use crate::gguf::GGUFMetadataArray;
+use crate::gguf::GGUFMetadataValue;
+use crate::gguf::GGUFMetadataValueType;
+use crate::gguf::GGUFTensorInfo;
+use crate::gguf::GGUF_DEFAULT_ALIGNMENT;
+use crate::gguf::KEY_GENERAL_ALIGNMENT;
+use crate::gguf::KEY_GENERAL_ARCHITECTURE;
+
+const GGUF_MAGIC: &[u8] = b"GGUF";
+const GGUF_WRITE_VERSION: u32 = 3;
+const GGUF_MAX_TENSOR_NAME_LEN: usize = 64;
+
+/// edits the metadata and the tensor names of a GGUF file, like fixing the chat template or the
+/// context length of a converted model, and writes it into a new file. the tensor data is
+/// copied as is, without decoding or re-encoding. it can also build a file from scratch, like
+/// the tiny models in the tests.
+pub struct GGUFEditor<'a> {
+    metadata: BTreeMap<String, GGUFMetadataValue<'a>>,
+    tensor_infos: Vec<GGUFTensorInfo<'a>>,
+    alignment: u64,
+}
+
+impl<'a> GGUFEditor<'a> {
+    pub fn new(gf: &'a GGUFFile<'_>) -> Self {
+        let metadata = gf
+            .metadata()
+            .as_hashmap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Self {
+            metadata,
+            tensor_infos: gf.tensor_infos().to_vec(),
+            alignment: gf.alignment(),
+        }
+    }
+
+    /// a file without any metadata or tensor, in the default alignment.
+    pub fn empty() -> Self {
+        Self {
+            metadata: BTreeMap::new(),
+            tensor_infos: vec![],
+            alignment: GGUF_DEFAULT_ALIGNMENT,
+        }
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, GGUFMetadataValue<'a>> {
+        &self.metadata
+    }
+
+    /// add or replace a metadata key, returns the replaced value.
+    pub fn set(
+        &mut self,
+        key: &str,
+        value: GGUFMetadataValue<'a>,
+    ) -> Result<Option<GGUFMetadataValue<'a>>> {
+        if key == KEY_GENERAL_ALIGNMENT {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the alignment can not be changed without moving the tensor data",
+            ));
+        }
+        if key == KEY_GENERAL_ARCHITECTURE && value.typ() != GGUFMetadataValueType::String {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the architecture must be a string",
+            ));
+        }
+        Ok(self.metadata.insert(key.to_string(), value))
+    }
+
+    /// remove a metadata key, returns the removed value.
+    pub fn remove(&mut self, key: &str) -> Result<GGUFMetadataValue<'a>> {
+        if key == KEY_GENERAL_ARCHITECTURE || key == KEY_GENERAL_ALIGNMENT {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("the metadata {} is required", key),
+            ));
+        }
+        self.metadata.remove(key).ok_or_else(|| {
+            Error::new(
+                ErrorKind::BadInput,
+                format!("the metadata {} does not exist", key),
+            )
+        })
+    }
+
+    pub fn rename_tensor(&mut self, from: &str, to: &str) -> Result<()> {
+        if to.len() > GGUF_MAX_TENSOR_NAME_LEN {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "the tensor name {} is longer than {} bytes",
+                    to, GGUF_MAX_TENSOR_NAME_LEN
+                ),
+            ));
+        }
+        if self.tensor_infos.iter().any(|t| t.name() == to) {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("the tensor {} already exists", to),
+            ));
+        }
+        let info = self
+            .tensor_infos
+            .iter_mut()
+            .find(|t| t.name() == from)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::TensorNotFound,
+                    format!("failed to find tensor {}", from),
+                )
+            })?;
+        *info = GGUFTensorInfo::new(
+            to.to_string(),
+            info.dimensions().to_vec(),
+            info.typ(),
+            info.data(),
+        );
+        Ok(())
+    }
+
+    /// append a tensor, the dimensions are in the GGUF order, which is the reverse of the
+    /// numpy shape. the data is in the encoding of the type.
+    pub fn add_tensor(
+        &mut self,
+        name: &str,
+        dimensions: &[usize],
+        typ: GGMLType,
+        data: &'a [u8],
+    ) -> Result<()> {
+        if self.tensor_infos.iter().any(|t| t.name() == name) {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("the tensor {} already exists", name),
+            ));
+        }
+        self.tensor_infos.push(GGUFTensorInfo::new(
+            name.to_string(),
+            dimensions.to_vec(),
+            typ,
+            data,
+        ));
+        Ok(())
+    }
+
+    /// replace the data of a tensor in another encoding, like the f32 weights converted into
+    /// f16 by `convert_f32_data`. the dimensions are kept.
+    pub fn set_tensor_data(&mut self, name: &str, typ: GGMLType, data: &'a [u8]) -> Result<()> {
+        let info = self
+            .tensor_infos
+            .iter_mut()
+            .find(|t| t.name() == name)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::TensorNotFound,
+                    format!("failed to find tensor {}", name),
+                )
+            })?;
+        *info = GGUFTensorInfo::new(name.to_string(), info.dimensions().to_vec(), typ, data);
+        Ok(())
+    }
+
+    /// write the file in GGUF v3. the data of each tensor is padded to the alignment. the
+    /// tensors of an opened file already keep the padding after them, so their offsets are the
+    /// same as the original file.
+    pub fn write(&self, w: &mut impl Write) -> Result<()> {
+        let mut header = vec![];
+        header.extend_from_slice(GGUF_MAGIC);
+        header.extend_from_slice(&GGUF_WRITE_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.tensor_infos.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(self.metadata.len() as u64).to_le_bytes());
+        for (key, value) in self.metadata.iter() {
+            write_string(&mut header, key);
+            write_value(&mut header, value);
+        }
+
+        let mut offset = 0_u64;
+        for info in self.tensor_infos.iter() {
+            write_string(&mut header, info.name());
+            header.extend_from_slice(&(info.dimensions().len() as u32).to_le_bytes());
+            for dim in info.dimensions() {
+                header.extend_from_slice(&(*dim as u64).to_le_bytes());
+            }
+            header.extend_from_slice(&(info.typ() as u32).to_le_bytes());
+            header.extend_from_slice(&offset.to_le_bytes());
+            offset = (offset + info.data().len() as u64).next_multiple_of(self.alignment);
+        }
+
+        let alignment = self.alignment as usize;
+        header.resize(header.len().next_multiple_of(alignment), 0);
+
+        w.write_all(&header).map_err(io_error)?;
+        for info in self.tensor_infos.iter() {
+            let len = info.data().len();
+            let padding = len.next_multiple_of(alignment) - len;
+            w.write_all(info.data()).map_err(io_error)?;
+            w.write_all(&vec![0; padding]).map_err(io_error)?;
+        }
+        w.flush().map_err(io_error)
+    }
+
+    /// write into a temporary file next to the path and rename it, so the path is never left
+    /// half written. the path can be the file being edited.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("gguf.tmp");
+        let file = File::create(&tmp_path).map_err(io_error)?;
+        self.write(&mut BufWriter::new(file))?;
+        std::fs::rename(&tmp_path, path).map_err(io_error)
+    }
+}
+
+/// encode the first n_elements of the f32 tensor data into f16, bf16 or a quantized type, the
+/// padding after the elements in an opened file is dropped. the elements of a quantized type
+/// must be made of whole blocks.
+pub fn convert_f32_data(data: &[u8], n_elements: usize, typ: GGMLType) -> Result<Vec<u8>> {
+    if data.len() < n_elements * 4 {
+        return Err(Error::new(
+            ErrorKind::FormatError,
+            format!(
+                "expect {} f32 elements, but got {} bytes",
+                n_elements,
+                data.len()
+            ),
+        ));
+    }
+    let values = data[..n_elements * 4]
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    match typ {
+        GGMLType::F16 => Ok(values
+            .flat_map(|v| f16::from_f32(v).to_le_bytes())
+            .collect()),
+        GGMLType::BF16 => Ok(values
+            .flat_map(|v| bf16::from_f32(v).to_le_bytes())
+            .collect()),
+        typ if typ.block_size() > 1 && CpuTensorBuf::is_supported_type(typ) => {
+            if n_elements % typ.block_size() != 0 {
+                return Err(Error::new(
+                    ErrorKind::FormatError,
+                    format!(
+                        "can not quantize {} elements into the blocks of {} elements of {}",
+                        n_elements,
+                        typ.block_size(),
+                        typ
+                    ),
+                ));
+            }
+            let buf = CpuTensorBuf::from(values.collect::<Vec<_>>()).quantize(typ)?;
+            Ok(buf.as_raw_bytes()?.to_vec())
+        }
+        _ => Err(Error::new(
+            ErrorKind::NotImplemented,
+            format!("can not convert the f32 tensors into {}", typ),
+        )),
+    }
+}
+
+fn io_error(err: std::io::Error) -> Error {
+    Error::new(ErrorKind::IOError, "failed to write the GGUF file").with_cause(err)
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(buf: &mut Vec<u8>, value: &GGUFMetadataValue) {
+    buf.extend_from_slice(&(value.typ() as u32).to_le_bytes());
+    match value {
+        GGUFMetadataValue::U8(v) => buf.push(*v),
+        GGUFMetadataValue::I8(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::U16(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::I16(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::U32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::I32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::U64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::I64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::F32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::F64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::Bool(v) => buf.push(*v),
+        GGUFMetadataValue::String(v) => write_string(buf, v),
+        GGUFMetadataValue::Array(arr) => write_array(buf, arr),
+    }
+}
+
+macro_rules! write_primitive_array {
+    ($buf:expr, $typ:ident, $arr:expr) => {{
+        $buf.extend_from_slice(&(GGUFMetadataValueType::$typ as u32).to_le_bytes());
+        $buf.extend_from_slice(&($arr.len() as u64).to_le_bytes());
+        for v in $arr.iter() {
+            $buf.extend_from_slice(&v.to_le_bytes());
+        }
+    }};
+}
+
+fn write_array(buf: &mut Vec<u8>, arr: &GGUFMetadataArray) {
+    match arr {
+        GGUFMetadataArray::U8Array(arr) => write_primitive_array!(buf, U8, arr),
+        GGUFMetadataArray::I8Array(arr) => write_primitive_array!(buf, I8, arr),
+        GGUFMetadataArray::U16Array(arr) => write_primitive_array!(buf, U16, arr),
+        GGUFMetadataArray::I16Array(arr) => write_primitive_array!(buf, I16, arr),
+        GGUFMetadataArray::U32Array(arr) => write_primitive_array!(buf, U32, arr),
+        GGUFMetadataArray::I32Array(arr) => write_primitive_array!(buf, I32, arr),
+        GGUFMetadataArray::U64Array(arr) => write_primitive_array!(buf, U64, arr),
+        GGUFMetadataArray::I64Array(arr) => write_primitive_array!(buf, I64, arr),
+        GGUFMetadataArray::F32Array(arr) => write_primitive_array!(buf, F32, arr),
+        GGUFMetadataArray::F64Array(arr) => write_primitive_array!(buf, F64, arr),
+        GGUFMetadataArray::BoolArray(arr) => write_primitive_array!(buf, Bool, arr),
+        GGUFMetadataArray::StringArray(arr) => {
+            buf.extend_from_slice(&(GGUFMetadataValueType::String as u32).to_le_bytes());
+            buf.extend_from_slice(&(arr.len() as u64).to_le_bytes());
+            for s in arr.iter() {
+                write_string(buf, s);
+            }
+        }
+        GGUFMetadataArray::NestedArray(arr) => {
+            buf.extend_from_slice(&(GGUFMetadataValueType::Array as u32).to_le_bytes());
+            buf.extend_from_slice(&(arr.len() as u64).to_le_bytes());
+            for nested in arr.iter() {
+                write_array(buf, nested);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFFileLoader;
+
+    #[test]
+    fn test_gguf_edit() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = loader.open()?;
+
+        let mut editor = GGUFEditor::new(&gf);
+        editor.set("general.name", GGUFMetadataValue::String("edited"))?;
+        editor.set("llama.context_length", GGUFMetadataValue::U32(1024))?;
+        editor.remove("llama.tensor_data_layout")?;
+        editor.rename_tensor("output.weight", "output.renamed")?;
+        assert!(editor.remove(KEY_GENERAL_ARCHITECTURE).is_err());
+        assert!(
+            editor
+                .rename_tensor("output_norm.weight", "token_embd.weight")
+                .is_err()
+        );
+
+        let path = std::env::temp_dir().join("crabml-test-gguf-edit.gguf");
+        editor.write_to_file(&path)?;
+        let edited_loader = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let edited = edited_loader.open()?;
+        assert_eq!(edited.metadata().get_string("general.name"), Some("edited"));
+        assert_eq!(
+            edited.metadata().get_u32("llama.context_length"),
+            Some(1024)
+        );
+        assert!(
+            edited
+                .metadata()
+                .get_string("llama.tensor_data_layout")
+                .is_none()
+        );
+        assert_eq!(
+            edited.metadata().get_string_array("tokenizer.ggml.tokens"),
+            gf.metadata().get_string_array("tokenizer.ggml.tokens")
+        );
+
+        // the tensor data is the same
+        assert_eq!(edited.tensor_infos().len(), gf.tensor_infos().len());
+        for (a, b) in edited.tensor_infos().iter().zip(gf.tensor_infos()) {
+            assert_eq!(a.data(), b.data());
+            assert_eq!(a.dimensions(), b.dimensions());
+        }
+        assert!(edited.get_tensor_info("output.renamed").is_some());
+        assert!(edited.get_tensor_info("output.weight").is_none());
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_f32_data_quantized() -> Result<()> {
+        let values = (0..512)
+            .map(|i| (i as f32 - 256.0) / 64.0)
+            .collect::<Vec<_>>();
+        let data = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        // the quantized bytes load back into the values within the error of the type
+        for (typ, tolerance) in [(GGMLType::Q8_0, 0.05), (GGMLType::Q6K, 0.1)] {
+            let bytes = convert_f32_data(&data, values.len(), typ)?;
+            let buf = CpuTensorBuf::from_raw_bytes(&bytes, typ)?.dequantize(GGMLType::F32)?;
+            assert_eq!(buf.len(), values.len());
+            for (got, want) in buf.as_f32_ref().iter().zip(values.iter()) {
+                assert!(
+                    (got - want).abs() < tolerance,
+                    "{}: {} vs {}",
+                    typ,
+                    got,
+                    want
+                );
+            }
+        }
+
+        // not whole blocks
+        let err = convert_f32_data(&data, 288, GGMLType::Q6K).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        Ok(())
+    }
+}
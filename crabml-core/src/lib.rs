@@ -1,6 +1,10 @@
 #![feature(portable_simd)]
 #![feature(slice_as_chunks)]
 #![cfg_attr(target_arch = "aarch64", feature(stdarch_neon_dotprod))]
+#![cfg_attr(
+    all(target_arch = "x86_64", target_feature = "avx512vnni"),
+    feature(stdarch_x86_avx512)
+)]
 #![feature(thread_local)]
 #![feature(lazy_cell)]
 #![feature(iter_array_chunks)]
@@ -8,9 +12,24 @@
 #![allow(clippy::map_entry)]
 #![allow(clippy::comparison_chain)]
 
+// links the BLAS library picked by the features
+#[cfg(any(feature = "openblas", feature = "accelerate", feature = "intel-mkl"))]
+extern crate blas_src;
+
 #[allow(unreachable_patterns)]
 pub mod backends;
+pub mod embedding;
 pub mod error;
 pub mod gguf;
+#[cfg(feature = "gguf-edit")]
+pub mod gguf_edit;
+pub mod loss;
+pub mod progress;
+pub mod source;
 pub mod tensor;
 pub mod tokenizer;
+#[cfg(unix)]
+mod unix_memory;
+pub mod w8a8;
+#[cfg(windows)]
+mod win_memory;
@@ -0,0 +1,125 @@
+use crate::backends::cpu::log_softmax_row;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// the cross entropy of the target token under the logits, which is the negative log
+/// likelihood of the token.
+pub fn cross_entropy(logits: &[f32], target: usize) -> Result<f32> {
+    if target >= logits.len() {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "target token {} out of the vocab size {}",
+                target,
+                logits.len()
+            ),
+        )
+            .into());
+    }
+    let mut log_probs = logits.to_vec();
+    log_softmax_row(&mut log_probs);
+    Ok(-log_probs[target])
+}
+
+/// KL(P || Q) between the distributions of two logits over the same vocab, P is taken as the
+/// reference (like the f16 model) and Q as the approximation (like the quantized model).
+pub fn kl_divergence(p_logits: &[f32], q_logits: &[f32]) -> Result<f32> {
+    if p_logits.len() != q_logits.len() {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "vocab size mismatch: {} vs {}",
+                p_logits.len(),
+                q_logits.len()
+            ),
+        )
+            .into());
+    }
+    let mut log_p = p_logits.to_vec();
+    let mut log_q = q_logits.to_vec();
+    log_softmax_row(&mut log_p);
+    log_softmax_row(&mut log_q);
+    let kl = log_p
+        .iter()
+        .zip(log_q.iter())
+        .map(|(lp, lq)| lp.exp() * (lp - lq))
+        .sum::<f32>();
+    // the rounding errors might make it slightly negative
+    Ok(kl.max(0.0))
+}
+
+/// accumulates the token level losses of a stream, like the perplexity over a text.
+#[derive(Debug, Clone, Default)]
+pub struct LossAccumulator {
+    count: usize,
+    sum: f64,
+    max: f32,
+}
+
+impl LossAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, loss: f32) {
+        self.count += 1;
+        self.sum += loss as f64;
+        self.max = self.max.max(loss);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn mean(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        (self.sum / self.count as f64) as f32
+    }
+
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    /// exp of the mean cross entropy, only makes sense when the pushed losses are cross entropies.
+    pub fn perplexity(&self) -> f32 {
+        self.mean().exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_cross_entropy() -> Result<()> {
+        let logits = vec![1.0, 2.0, 3.0];
+        assert_relative_eq!(cross_entropy(&logits, 2)?, 0.40760595, epsilon = 1e-5);
+        assert!(cross_entropy(&logits, 3).is_err());
+
+        let mut acc = LossAccumulator::new();
+        acc.push(cross_entropy(&[0.0, 0.0], 0)?);
+        acc.push(cross_entropy(&[0.0, 0.0], 1)?);
+        assert_eq!(acc.count(), 2);
+        assert_relative_eq!(acc.perplexity(), 2.0, epsilon = 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kl_divergence() -> Result<()> {
+        let p = vec![1.0, 2.0, 3.0];
+        assert_relative_eq!(kl_divergence(&p, &p)?, 0.0, epsilon = 1e-6);
+        // shifting the logits does not change the distribution
+        let q = vec![101.0, 102.0, 103.0];
+        assert_relative_eq!(kl_divergence(&p, &q)?, 0.0, epsilon = 1e-6);
+
+        // KL([0.5, 0.5] || [0.2, 0.8]) = 0.5 * ln(0.5 / 0.2) + 0.5 * ln(0.5 / 0.8)
+        let q = vec![0.2_f32.ln(), 0.8_f32.ln()];
+        assert_relative_eq!(kl_divergence(&[0.0, 0.0], &q)?, 0.22314355, epsilon = 1e-5);
+        assert!(kl_divergence(&p, &q).is_err());
+        Ok(())
+    }
+}
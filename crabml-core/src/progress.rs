@@ -0,0 +1,43 @@
+use std::fmt::Display;
+use std::sync::Arc;
+
+/// the long running stages which can report their progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressStage {
+    /// loading the weights from the model file, counted in tensors.
+    Load,
+
+    /// quantizing or converting the weights, counted in tensors.
+    Quantize,
+
+    /// feeding the prompt into the model, counted in tokens.
+    Prefill,
+}
+
+impl Display for ProgressStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProgressStage::Load => write!(f, "load"),
+            ProgressStage::Quantize => write!(f, "quantize"),
+            ProgressStage::Prefill => write!(f, "prefill"),
+        }
+    }
+}
+
+/// receives the progress of the long running stages, so GUIs and CLIs can show a progress bar
+/// instead of freezing for tens of seconds. `completed` goes from 0 to `total`, the reporter is
+/// always called with `completed == total` at the end of a stage. the reporters may be called on
+/// other threads than the one which set them, like the threads of a server.
+pub trait ProgressReporter {
+    fn report(&self, stage: ProgressStage, completed: usize, total: usize);
+}
+
+pub type ProgressReporterRef = Arc<dyn ProgressReporter + Send + Sync>;
+
+impl<F> ProgressReporter for F
+where F: Fn(ProgressStage, usize, usize)
+{
+    fn report(&self, stage: ProgressStage, completed: usize, total: usize) {
+        self(stage, completed, total)
+    }
+}
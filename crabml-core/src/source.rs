@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::OnceLock;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// A source of the raw bytes of a model file, which can be read by byte ranges. it allows the
+/// GGUF tensors to be fetched lazily from places other than the local disk, like an object
+/// storage or a HTTP server which supports Range requests, so a serverless deployment does not
+/// have to download the whole model up front.
+pub trait TensorDataSource {
+    /// the total size of the file in bytes.
+    fn size(&self) -> Result<u64>;
+
+    /// read exactly `len` bytes starting from `offset`.
+    fn read_range(&self, offset: u64, len: usize) -> Result<Vec<u8>>;
+
+    /// a human readable description of the source, used in error messages.
+    fn describe(&self) -> String;
+}
+
+/// reads the byte ranges from a file on the local disk with positional reads, without
+/// mapping the whole file into memory.
+pub struct LocalFileSource {
+    path: String,
+    file: File,
+    size: u64,
+}
+
+impl LocalFileSource {
+    pub fn new(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to open the file: {}", path),
+            )
+            .with_cause(err)
+        })?;
+        let size = file
+            .metadata()
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::IOError,
+                    format!("failed to stat the file: {}", path),
+                )
+                .with_cause(err)
+            })?
+            .len();
+        Ok(Self {
+            path: path.to_string(),
+            file,
+            size,
+        })
+    }
+
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.file.read_exact_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            let n = self.file.seek_read(buf, offset)?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            buf = &mut buf[n..];
+            offset += n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl TensorDataSource for LocalFileSource {
+    fn size(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn read_range(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.read_exact_at(&mut buf, offset).map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!(
+                    "failed to read {} bytes at offset {} from {}",
+                    len, offset, self.path
+                ),
+            )
+            .with_cause(err)
+        })?;
+        Ok(buf)
+    }
+
+    fn describe(&self) -> String {
+        self.path.clone()
+    }
+}
+
+/// adapts any range fetching function into a TensorDataSource. it's the extension point for
+/// the storages which need a full featured client, like S3 (with a GetObject request carrying
+/// a `Range` header) or a HTTPS endpoint, without pulling these clients into crabml itself.
+pub struct RangeFnSource<F>
+where F: Fn(u64, usize) -> Result<Vec<u8>>
+{
+    name: String,
+    size: u64,
+    fetch: F,
+}
+
+impl<F> RangeFnSource<F>
+where F: Fn(u64, usize) -> Result<Vec<u8>>
+{
+    pub fn new(name: impl Into<String>, size: u64, fetch: F) -> Self {
+        Self {
+            name: name.into(),
+            size,
+            fetch,
+        }
+    }
+}
+
+impl<F> TensorDataSource for RangeFnSource<F>
+where F: Fn(u64, usize) -> Result<Vec<u8>>
+{
+    fn size(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn read_range(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let buf = (self.fetch)(offset, len)?;
+        if buf.len() != len {
+            return Err(Error::new(
+                ErrorKind::IOError,
+                format!(
+                    "short read from {}: want {} bytes at offset {}, got {}",
+                    self.name,
+                    len,
+                    offset,
+                    buf.len()
+                ),
+            ));
+        }
+        Ok(buf)
+    }
+
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// fetches the byte ranges over plain HTTP/1.1 with `Range` requests. the server must respond
+/// with `206 Partial Content`. https is not handled here, please wrap a https client with
+/// `RangeFnSource` instead. a S3 compatible storage serving on http (like a minio inside the
+/// cluster) can be read with a presigned url directly.
+pub struct HttpRangeSource {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    size: OnceLock<u64>,
+}
+
+impl HttpRangeSource {
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            Error::new(
+                ErrorKind::BadInput,
+                format!("only http:// urls are supported, but got {}", url),
+            )
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse::<u16>().map_err(|_| {
+                    Error::new(ErrorKind::BadInput, format!("invalid port in url {}", url))
+                })?;
+                (host, port)
+            }
+            None => (authority, 80),
+        };
+        Ok(Self {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            size: OnceLock::new(),
+        })
+    }
+
+    /// send a GET request with the range [start, end] (both inclusive), returns the response
+    /// headers and the body.
+    fn get_range(&self, start: u64, end: u64) -> Result<(HashMap<String, String>, Vec<u8>)> {
+        let io_err = |err: std::io::Error| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to request {}", self.url),
+            )
+            .with_cause(err)
+        };
+
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).map_err(io_err)?;
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={}-{}\r\nConnection: close\r\n\r\n",
+            self.path, self.host, start, end
+        );
+        stream.write_all(req.as_bytes()).map_err(io_err)?;
+        let mut resp = vec![];
+        stream.read_to_end(&mut resp).map_err(io_err)?;
+
+        let header_end = resp
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::IOError,
+                    format!("malformed http response from {}", self.url),
+                )
+            })?;
+        let header_text = String::from_utf8_lossy(&resp[..header_end]).to_string();
+        let mut lines = header_text.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|l| l.split_whitespace().nth(1))
+            .unwrap_or("");
+        if status != "206" {
+            return Err(Error::new(
+                ErrorKind::IOError,
+                format!(
+                    "expect 206 Partial Content from {}, but got status {:?}",
+                    self.url, status
+                ),
+            ));
+        }
+        let headers = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+            .collect::<HashMap<_, _>>();
+        if headers.get("transfer-encoding").map(|v| v.as_str()) == Some("chunked") {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!("chunked response from {} is not supported", self.url),
+            ));
+        }
+
+        Ok((headers, resp[header_end + 4..].to_vec()))
+    }
+}
+
+impl TensorDataSource for HttpRangeSource {
+    fn size(&self) -> Result<u64> {
+        if let Some(size) = self.size.get() {
+            return Ok(*size);
+        }
+
+        // Content-Range: bytes 0-0/12345
+        let (headers, _) = self.get_range(0, 0)?;
+        let size = headers
+            .get("content-range")
+            .and_then(|v| v.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse::<u64>().ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::IOError,
+                    format!("missing the total size in Content-Range from {}", self.url),
+                )
+            })?;
+        Ok(*self.size.get_or_init(|| size))
+    }
+
+    fn read_range(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let (_, body) = self.get_range(offset, offset + len as u64 - 1)?;
+        if body.len() != len {
+            return Err(Error::new(
+                ErrorKind::IOError,
+                format!(
+                    "short read from {}: want {} bytes at offset {}, got {}",
+                    self.url,
+                    len,
+                    offset,
+                    body.len()
+                ),
+            ));
+        }
+        Ok(body)
+    }
+
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_file_source() -> Result<()> {
+        let src = LocalFileSource::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        assert!(src.size()? > 4);
+        let magic = src.read_range(0, 4)?;
+        assert_eq!(magic, b"GGUF");
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_range_source_parse_url() -> Result<()> {
+        let src = HttpRangeSource::new("http://localhost:9000/models/a.gguf?X-Amz-Expires=60")?;
+        assert_eq!(src.host, "localhost");
+        assert_eq!(src.port, 9000);
+        assert_eq!(src.path, "/models/a.gguf?X-Amz-Expires=60");
+
+        let src = HttpRangeSource::new("http://example.com")?;
+        assert_eq!(src.port, 80);
+        assert_eq!(src.path, "/");
+
+        assert!(HttpRangeSource::new("https://example.com/a.gguf").is_err());
+        Ok(())
+    }
+}
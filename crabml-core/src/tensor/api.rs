@@ -1,4 +1,5 @@
 use super::strider::TensorStrider;
+use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
 
@@ -20,6 +21,24 @@ pub trait Tensor: Sized + Clone {
     /// place where we use this function.
     fn resize(self, axis: usize, n: usize) -> Result<Self>;
 
+    /// keep the first len elements on the dim, like resize but the tensor can only shrink
+    /// within its current shape. used on attending to the first tokens of the kv cache.
+    fn narrow(self, dim: usize, len: usize) -> Result<Self> {
+        if dim >= self.shape().len() || len > self.shape()[dim] {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "narrow: can not keep {} elements on the dim {} of the shape {:?}",
+                    len,
+                    dim,
+                    self.shape()
+                ),
+            )
+                .into());
+        }
+        self.resize(dim, len)
+    }
+
     fn dtype(&self) -> GGMLType;
 
     fn with_strider(self, strider: TensorStrider) -> Result<Self>;
@@ -44,28 +63,108 @@ pub trait Tensor: Sized + Clone {
 
     fn export(&self, buf: &mut [f32]) -> Result<()>;
 
+    /// the reverse of export, fill an owned contiguous tensor with the f32 values. used on
+    /// moving the KV cache across the devices.
+    fn import(&mut self, buf: &[f32]) -> Result<()>;
+
+    /// split the last dimension into the chunks of the sizes, each chunk is copied into a new
+    /// contiguous tensor. used on splitting the output of the fused QKV projection.
+    fn split_last_dim(&self, sizes: &[usize]) -> Result<Vec<Self>>;
+
     /// duplicate the tensor and the underlying storage
     fn dup(&self) -> Result<Self>;
 
-    fn rope_inplace(self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self>;
+    /// rotate the first rope_dims of each head at the position, in the frequencies of the base
+    /// theta, like 10000 on llama2 and 1000000 on CodeLlama. the position is multiplied by
+    /// freq_scale, which is 1 unless the model extends its context linearly. the rest of the
+    /// head is kept.
+    fn rope_inplace(
+        self,
+        mode: RopeMode,
+        pos: usize,
+        rope_dims: usize,
+        theta: f32,
+        freq_scale: f32,
+    ) -> Result<Self>;
+
+    /// like rope_inplace, but each row of the batch takes its own position, the rows on the
+    /// different branches of a token tree may share the same position.
+    fn rope_positions_inplace(
+        self,
+        mode: RopeMode,
+        positions: &[usize],
+        rope_dims: usize,
+        theta: f32,
+        freq_scale: f32,
+    ) -> Result<Self>;
+
+    /// precompute the sin/cos tables of rope for the positions in [0, max_pos), which are
+    /// shared by all the layers. the backends without a rope cache just ignore it.
+    fn init_rope_cache(
+        device: &Self::Device,
+        mode: RopeMode,
+        head_dim: usize,
+        rope_dims: usize,
+        theta: f32,
+        freq_scale: f32,
+        max_pos: usize,
+    ) -> Result<()>;
 
     fn rms_norm_inplace(self, eps: f32) -> Result<Self>;
 
+    /// softmax over the axis, the max is subtracted before exp to avoid overflow.
     fn softmax_inplace(self, axis: usize) -> Result<Self>;
 
+    fn log_softmax_inplace(self, axis: usize) -> Result<Self>;
+
+    /// mask the attention scores in (n_head, n_batch, seq) of a batch of tokens which are the
+    /// last n_batch of the seq, the scores of the keys after each query are set to -inf. only
+    /// needed on forwarding more than one token at once.
+    fn causal_mask_inplace(self) -> Result<Self>;
+
+    /// like causal_mask_inplace, but the batch is a tree of tokens, each token only attends to
+    /// the tokens before the batch, its ancestors and itself. parents[i] is the index of the
+    /// parent of the token i in the batch.
+    fn tree_mask_inplace(self, parents: &[Option<usize>]) -> Result<Self>;
+
     fn silu_inplace(self) -> Result<Self>;
 
     fn gelu_inplace(self) -> Result<Self>;
 
+    /// the elementwise ops broadcast the shapes like numpy, like multiplying a (n, dim) batch
+    /// with a (dim, ) weight, or a (n, dim) batch with a (n, 1) column.
     fn mul_inplace(self, rhs: &Self) -> Result<Self>;
 
     fn add_inplace(self, rhs: &Self) -> Result<Self>;
 
+    fn div_inplace(self, rhs: &Self) -> Result<Self>;
+
     fn div_scalar_inplace(self, rhs: f32) -> Result<Self>;
 
     fn scale_inplace(self, rhs: f32) -> Result<Self>;
 
     fn matmul_vec(&self, y: &Self) -> Result<Self>;
 
+    /// (bA, m, k) @ (bB, k, n) -> (bA, m, n), each batch of y is shared by bA / bB batches of
+    /// self, like the kv heads on GQA. a (k, n) y is shared by all the batches.
     fn batch_matmul(&self, y: &Self) -> Result<Self>;
+
+    /// the fused attention of the queries in (n_batch, n_heads, head_dim) over the keys and the
+    /// values in (n_kv_heads, seq, head_dim), returns (n_batch, n_heads, head_dim). the queries
+    /// are the last n_batch tokens of the seq and attend causally, the scores are multiplied by
+    /// scale before softmax. the (n_heads, n_batch, seq) scores are never materialized. the
+    /// backends without the fused kernel return NotImplemented.
+    fn flash_attention(&self, _k: &Self, _v: &Self, _scale: f32) -> Result<Self> {
+        Err((
+            ErrorKind::NotImplemented,
+            "flash_attention is not implemented on this backend",
+        )
+            .into())
+    }
+
+    /// hint the backend to bring the data of the tensor in ahead of its use, like reading in
+    /// the pages of the mmaped weights. the backends which keep the weights in memory ignore it.
+    fn prefetch(&self) -> Result<()> {
+        Ok(())
+    }
 }
@@ -1,5 +1,7 @@
 mod api;
 pub mod metrics;
+pub mod ops;
+mod print;
 mod strider;
 
 pub use api::RopeMode;
@@ -0,0 +1,119 @@
+//! the math of the models over any `Tensor`, shared by the architectures. the ops consume the
+//! tensor and update it in place on the backends, like the `*_inplace` methods they wrap.
+
+use crate::error::Result;
+use crate::tensor::Tensor;
+
+/// softmax over the dim, the max is subtracted before exp to avoid overflow.
+pub fn softmax<T: Tensor>(x: T, dim: usize) -> Result<T> {
+    x.softmax_inplace(dim)
+}
+
+/// normalize each row of the last dim by its root mean square, x / sqrt(mean(x^2) + eps). the
+/// weight of the norm is multiplied afterwards.
+pub fn rms_norm<T: Tensor>(x: T, eps: f32) -> Result<T> {
+    x.rms_norm_inplace(eps)
+}
+
+/// x * sigmoid(x), the activation of the gated FFN of llama.
+pub fn silu<T: Tensor>(x: T) -> Result<T> {
+    x.silu_inplace()
+}
+
+/// the tanh approximation of GeLU, the activation of the gated FFN of Gemma.
+pub fn gelu<T: Tensor>(x: T) -> Result<T> {
+    x.gelu_inplace()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::backends::cpu::CpuTensor;
+    use crate::backends::cpu::CpuTensorDevice;
+
+    #[test]
+    fn test_softmax() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let x = CpuTensor::new(vec![1.0, 2.0, 3.0, -1.0, 0.0, 1000.0], &[2, 3], device)?;
+
+        // over the rows, the large logit does not overflow
+        let y = softmax(x.clone(), 1)?;
+        assert_relative_eq!(
+            &y.to_vec()[..],
+            &[0.09003057, 0.24472848, 0.66524094, 0.0, 0.0, 1.0][..],
+            epsilon = 1e-5
+        );
+
+        // over the columns
+        let y = softmax(x, 0)?;
+        assert_relative_eq!(
+            &y.to_vec()[..],
+            &[0.8807971, 0.8807971, 0.0, 0.11920292, 0.11920292, 1.0][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rms_norm() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let x = CpuTensor::new(
+            vec![1.0, 2.0, 3.0, 4.0, -3.0, 0.0, 0.0, 4.0],
+            &[2, 4],
+            device,
+        )?;
+        let y = rms_norm(x, 1e-5)?;
+        assert_relative_eq!(
+            &y.to_vec()[..],
+            &[
+                0.36514813, 0.73029626, 1.0954444, 1.4605925, -1.1999990, 0.0, 0.0, 1.5999987
+            ][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_silu() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let x = CpuTensor::new(vec![-6.0, -1.0, 0.0, 0.5, 1.0, 6.0], &[6], device)?;
+        let y = silu(x)?;
+        assert_relative_eq!(
+            &y.to_vec()[..],
+            &[
+                -0.01483574,
+                -0.26894143,
+                0.0,
+                0.31122968,
+                0.7310586,
+                5.9851646
+            ][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_gelu() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let x = CpuTensor::new(vec![-3.0, -1.0, 0.0, 0.5, 1.0, 3.0], &[6], device)?;
+        let y = gelu(x)?;
+
+        // the values are looked up in a f16 table
+        assert_relative_eq!(
+            &y.to_vec()[..],
+            &[
+                -0.00363739,
+                -0.15880801,
+                0.0,
+                0.34571401,
+                0.841192,
+                2.99636261
+            ][..],
+            epsilon = 1e-2
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt::Write;
+
+/// the tensors with more elements than this are summarized on printing.
+const SUMMARIZE_THRESHOLD: usize = 1000;
+
+/// the number of elements printed at the beginning and the end of each dimension on summarizing.
+const EDGE_ITEMS: usize = 3;
+
+const PRECISION: usize = 4;
+
+/// the elements are printed in the scientific notation if any of them is out of this range, the
+/// small weights would be all zeros on the fixed precision otherwise.
+const SCI_MODE_RANGE: (f32, f32) = (1e-4, 1e8);
+
+/// prints the elements of a tensor in a NumPy like style. `get` takes the index of an element
+/// and returns its value. when `full` is false, large tensors are summarized with ellipsis, only
+/// the corner elements are printed.
+pub(crate) fn format_elements(
+    shape: &[usize],
+    get: impl Fn(&[usize]) -> f32,
+    full: bool,
+) -> String {
+    let summarize = !full && shape.iter().product::<usize>() > SUMMARIZE_THRESHOLD;
+    if shape.is_empty() {
+        let v = get(&[]);
+        return format_value(v, use_sci_mode(&[v]));
+    }
+
+    // collect the visible elements first, to align them in the same width.
+    let mut values = vec![];
+    visit(shape, summarize, &mut vec![], &mut |idx| {
+        values.push(get(idx));
+    });
+    let sci_mode = use_sci_mode(&values);
+    let texts = values
+        .iter()
+        .map(|v| format_value(*v, sci_mode))
+        .collect::<Vec<_>>();
+    let width = texts.iter().map(|s| s.len()).max().unwrap_or(0);
+
+    let mut out = String::new();
+    let mut texts = texts.into_iter();
+    write_dim(&mut out, shape, summarize, 0, width, &mut texts);
+    out
+}
+
+fn use_sci_mode(values: &[f32]) -> bool {
+    let (min, max) = SCI_MODE_RANGE;
+    values
+        .iter()
+        .map(|v| v.abs())
+        .filter(|v| v.is_finite() && *v != 0.0)
+        .any(|v| v < min || v >= max)
+}
+
+fn format_value(v: f32, sci_mode: bool) -> String {
+    if sci_mode {
+        format!("{:.*e}", PRECISION, v)
+    } else {
+        format!("{:.*}", PRECISION, v)
+    }
+}
+
+fn visible_indices(n: usize, summarize: bool) -> Vec<Option<usize>> {
+    if summarize && n > 2 * EDGE_ITEMS {
+        (0..EDGE_ITEMS)
+            .map(Some)
+            .chain(std::iter::once(None))
+            .chain((n - EDGE_ITEMS..n).map(Some))
+            .collect()
+    } else {
+        (0..n).map(Some).collect()
+    }
+}
+
+fn visit(shape: &[usize], summarize: bool, idx: &mut Vec<usize>, f: &mut impl FnMut(&[usize])) {
+    let dim = idx.len();
+    if dim == shape.len() {
+        f(idx);
+        return;
+    }
+    for i in visible_indices(shape[dim], summarize).into_iter().flatten() {
+        idx.push(i);
+        visit(shape, summarize, idx, f);
+        idx.pop();
+    }
+}
+
+fn write_dim(
+    out: &mut String,
+    shape: &[usize],
+    summarize: bool,
+    dim: usize,
+    width: usize,
+    texts: &mut impl Iterator<Item = String>,
+) {
+    out.push('[');
+    let indices = visible_indices(shape[dim], summarize);
+    let last_dim = dim == shape.len() - 1;
+    for (n, i) in indices.iter().enumerate() {
+        if n > 0 {
+            if last_dim {
+                out.push_str(", ");
+            } else {
+                // separate the higher dimensions with blank lines, like numpy does
+                out.push(',');
+                out.push_str(&"\n".repeat(shape.len() - dim - 1));
+                out.push_str(&" ".repeat(dim + 1));
+            }
+        }
+        match (i, last_dim) {
+            (None, _) => out.push_str("..."),
+            (Some(_), true) => {
+                let text = texts.next().unwrap();
+                write!(out, "{:>width$}", text, width = width).unwrap();
+            }
+            (Some(_), false) => write_dim(out, shape, summarize, dim + 1, width, texts),
+        }
+    }
+    out.push(']');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_elements() {
+        let get = |shape: &[usize]| {
+            let shape = shape.to_vec();
+            move |idx: &[usize]| -> f32 {
+                let mut pos = 0;
+                for (d, i) in idx.iter().enumerate() {
+                    pos = pos * shape[d] + i;
+                }
+                pos as f32
+            }
+        };
+
+        assert_eq!(format_elements(&[], |_| 1.5, false), "1.5000");
+        assert_eq!(
+            format_elements(&[2, 3], get(&[2, 3]), false),
+            "[[0.0000, 1.0000, 2.0000],\n [3.0000, 4.0000, 5.0000]]"
+        );
+        assert_eq!(
+            format_elements(&[2, 1, 2], get(&[2, 1, 2]), false),
+            "[[[0.0000, 1.0000]],\n\n [[2.0000, 3.0000]]]"
+        );
+        assert_eq!(
+            format_elements(&[2000], get(&[2000]), false),
+            "[   0.0000,    1.0000,    2.0000, ..., 1997.0000, 1998.0000, 1999.0000]"
+        );
+        assert_eq!(
+            format_elements(&[8], get(&[8]), false),
+            "[0.0000, 1.0000, 2.0000, 3.0000, 4.0000, 5.0000, 6.0000, 7.0000]"
+        );
+
+        let s = format_elements(&[40, 40], get(&[40, 40]), false);
+        assert_eq!(s.lines().count(), 7);
+        assert!(s.contains(" ...,\n"));
+        let s = format_elements(&[40, 40], get(&[40, 40]), true);
+        assert_eq!(s.lines().count(), 40);
+
+        // the small values are not rounded into zeros
+        assert_eq!(
+            format_elements(&[3], |idx| [0.0, 2.5e-5, -1.0][idx[0]], false),
+            "[ 0.0000e0, 2.5000e-5, -1.0000e0]"
+        );
+    }
+}
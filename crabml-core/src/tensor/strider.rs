@@ -1,3 +1,4 @@
+use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
 
@@ -189,6 +190,61 @@ impl TensorStrider {
         Ok(strider)
     }
 
+    /// view the tensor in a larger shape like numpy: the missing leading dims are padded, and
+    /// the dims of size 1 are expanded. both take a stride of 0 to read the same elements again.
+    pub fn broadcast_to(&self, shape: &[usize]) -> Result<Self> {
+        let invalid = || -> Error {
+            (
+                ErrorKind::TensorError,
+                format!(
+                    "can not broadcast a tensor of shape {:?} to {:?}",
+                    self.shape, shape
+                ),
+            )
+                .into()
+        };
+        if shape.len() < self.shape.len() {
+            return Err(invalid());
+        }
+
+        let pad = shape.len() - self.shape.len();
+        let mut strides = vec![0; shape.len()];
+        for (i, (&dim, &stride)) in self.shape.iter().zip(self.strides.iter()).enumerate() {
+            if dim == shape[pad + i] {
+                strides[pad + i] = stride;
+            } else if dim != 1 {
+                return Err(invalid());
+            }
+        }
+
+        Ok(Self {
+            shape: shape.to_vec(),
+            strides,
+        })
+    }
+
+    /// the shape of an elementwise op on the two shapes in the broadcasting rules of numpy:
+    /// the shapes are aligned from the last dim, and a dim is either equal or 1 on one side.
+    pub fn broadcast_shape(a: &[usize], b: &[usize]) -> Result<Vec<usize>> {
+        let dims = a.len().max(b.len());
+        let dim_at = |shape: &[usize], i: usize| match (i + shape.len()).checked_sub(dims) {
+            Some(j) => shape[j],
+            None => 1,
+        };
+        (0..dims)
+            .map(|i| match (dim_at(a, i), dim_at(b, i)) {
+                (da, db) if da == db => Ok(da),
+                (1, db) => Ok(db),
+                (da, 1) => Ok(da),
+                _ => Err((
+                    ErrorKind::TensorError,
+                    format!("can not broadcast the shapes {:?} and {:?}", a, b),
+                )
+                    .into()),
+            })
+            .collect()
+    }
+
     pub fn is_contiguous(&self) -> bool {
         self.is_contiguous_on_axis(0)
     }
@@ -249,6 +305,22 @@ impl TensorStrider {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_strider_broadcast() -> Result<()> {
+        assert_eq!(TensorStrider::broadcast_shape(&[2, 1, 4], &[3, 1])?, vec![
+            2, 3, 4
+        ]);
+        assert_eq!(TensorStrider::broadcast_shape(&[4], &[1])?, vec![4]);
+        assert!(TensorStrider::broadcast_shape(&[2, 3], &[2]).is_err());
+
+        let s = TensorStrider::new(vec![3, 1]).broadcast_to(&[2, 3, 4])?;
+        assert_eq!(s.strides(), &[0, 1, 0]);
+        assert_eq!(s.iter().take(6).collect::<Vec<_>>(), vec![0, 0, 0, 0, 1, 1]);
+        assert!(TensorStrider::new(vec![3]).broadcast_to(&[3, 2]).is_err());
+        assert!(TensorStrider::new(vec![2, 3]).broadcast_to(&[3]).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_strider_reshape() -> Result<()> {
         let s = TensorStrider::new(vec![3, 4]);
@@ -1,25 +1,48 @@
+#[cfg(feature = "gpt2-tokenizer")]
 mod tokenizer_gpt2;
 mod tokenizer_llama;
+mod trie;
 
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
 
+#[cfg(feature = "gpt2-tokenizer")]
 use tokenizer_gpt2::Gpt2Tokenizer;
 use tokenizer_llama::LlamaTokenizer;
+pub use trie::TokenTrie;
+use unicode_normalization::is_nfc_quick;
+use unicode_normalization::IsNormalized;
+use unicode_normalization::UnicodeNormalization;
 
+use crate::error::Error;
+use crate::error::ErrorKind;
 use crate::error::Result;
 
 pub type TokenID = usize;
 
+// how many tokens encode_append looks back for a word boundary before re-encoding everything
+const ENCODE_APPEND_LOOKBACK: usize = 16;
+
+/// the vocab of the tokenizer is shared between the clones, but each clone has its own
+/// decoding state, so every session should take a clone of the tokenizer from the model.
 pub struct Tokenizer {
-    tokens: Rc<Vec<String>>,
+    tokens: Arc<Vec<String>>,
+    token_types: Arc<Vec<TokenType>>,
+    bos_token: TokenID,
     eos_token: TokenID,
-    inner: TokenizerInner,
-    utf8_buf: RefCell<Utf8Buf>,
+    pad_token: Option<TokenID>,
+    unk_token: Option<TokenID>,
+    options: TokenizerOptions,
+    inner: Arc<TokenizerInner>,
+    trie: Arc<OnceLock<TokenTrie>>,
+    utf8_buf: Mutex<Utf8Buf>,
 }
 
 enum TokenizerInner {
     Llama(LlamaTokenizer),
+    #[cfg(feature = "gpt2-tokenizer")]
     GPT2(Gpt2Tokenizer),
 }
 
@@ -29,6 +52,114 @@ pub enum TokenizerKind {
     GPT2,
 }
 
+/// the types of the tokens, the values are the same as `tokenizer.ggml.token_type` in GGUF.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TokenType {
+    Undefined = 0,
+    Normal = 1,
+    Unknown = 2,
+    Control = 3,
+    UserDefined = 4,
+    Unused = 5,
+    Byte = 6,
+}
+
+impl TokenType {
+    pub fn from_gguf(v: i32) -> Self {
+        match v {
+            1 => Self::Normal,
+            2 => Self::Unknown,
+            3 => Self::Control,
+            4 => Self::UserDefined,
+            5 => Self::Unused,
+            6 => Self::Byte,
+            _ => Self::Undefined,
+        }
+    }
+}
+
+/// the options on pre-processing the text, they're usually read from the GGUF metadata like
+/// `tokenizer.ggml.add_bos_token`, and should be the same as llama.cpp, or the prompts are
+/// tokenized differently and the outputs of the model change noticeably.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TokenizerOptions {
+    /// prepend the BOS token to the prompt.
+    pub add_bos: bool,
+
+    /// append the EOS token to the prompt.
+    pub add_eos: bool,
+
+    /// prepend a space (the dummy prefix) to the text, it's on by default for the sentencepiece
+    /// tokenizers and off for the BPE ones.
+    pub add_space_prefix: bool,
+
+    /// normalize the text into the unicode NFC form before encoding.
+    pub normalize_nfc: bool,
+
+    /// how the BPE tokenizers split the text into the words before merging, it's ignored by
+    /// the sentencepiece tokenizers.
+    pub pre_tokenizer: PreTokenizer,
+}
+
+/// the pre-tokenizers of `tokenizer.ggml.pre`, the BPE merges never cross the words they split
+/// the text into.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum PreTokenizer {
+    /// merge the text between the special tokens as a whole.
+    #[default]
+    Whole,
+
+    /// the regex of Qwen2, which splits the words with their leading space, the runs of the
+    /// whitespaces and the punctuations, and every digit on its own.
+    Qwen2,
+}
+
+impl PreTokenizer {
+    pub fn from_gguf(pre: &str) -> Self {
+        match pre {
+            "qwen2" => Self::Qwen2,
+            _ => Self::Whole,
+        }
+    }
+}
+
+impl TokenizerOptions {
+    pub fn new(kind: TokenizerKind) -> Self {
+        Self {
+            add_bos: true,
+            add_eos: false,
+            add_space_prefix: kind == TokenizerKind::Llama,
+            normalize_nfc: false,
+            pre_tokenizer: PreTokenizer::Whole,
+        }
+    }
+
+    pub fn with_add_bos(mut self, add_bos: bool) -> Self {
+        self.add_bos = add_bos;
+        self
+    }
+
+    pub fn with_add_eos(mut self, add_eos: bool) -> Self {
+        self.add_eos = add_eos;
+        self
+    }
+
+    pub fn with_add_space_prefix(mut self, add_space_prefix: bool) -> Self {
+        self.add_space_prefix = add_space_prefix;
+        self
+    }
+
+    pub fn with_normalize_nfc(mut self, normalize_nfc: bool) -> Self {
+        self.normalize_nfc = normalize_nfc;
+        self
+    }
+
+    pub fn with_pre_tokenizer(mut self, pre_tokenizer: PreTokenizer) -> Self {
+        self.pre_tokenizer = pre_tokenizer;
+        self
+    }
+}
+
 impl Tokenizer {
     /// if TokenizerKind is Llama, we need to provide scores, if GPT2, we need to provide merges.
     pub fn new_llama(
@@ -37,48 +168,89 @@ impl Tokenizer {
         bos_token: TokenID,
         eos_token: TokenID,
     ) -> Self {
-        let tokens = Rc::new(tokens);
-        let decode_buf = RefCell::new(Utf8Buf::new());
-        let inner = TokenizerInner::Llama(LlamaTokenizer::new(
+        let tokens = Arc::new(tokens);
+        let decode_buf = Mutex::new(Utf8Buf::new());
+        let inner = Arc::new(TokenizerInner::Llama(LlamaTokenizer::new(
             tokens.clone(),
             scores,
             bos_token,
             eos_token,
-        ));
+        )));
 
         Self {
             tokens,
+            token_types: Arc::new(vec![]),
+            bos_token,
             eos_token,
+            pad_token: None,
+            unk_token: None,
+            options: TokenizerOptions::new(TokenizerKind::Llama),
             utf8_buf: decode_buf,
             inner,
+            trie: Arc::new(OnceLock::new()),
         }
     }
 
+    /// the byte level BPE tokenizer of gpt2, only built with the `gpt2-tokenizer` feature.
+    #[cfg(feature = "gpt2-tokenizer")]
     pub fn new_gpt2(
         tokens: Vec<String>,
         merges: Vec<String>,
         bos_token: TokenID,
         eos_token: TokenID,
     ) -> Self {
-        let tokens = Rc::new(tokens);
-        let decode_buf = RefCell::new(Utf8Buf::new());
-        let inner = TokenizerInner::GPT2(Gpt2Tokenizer::new(
+        let tokens = Arc::new(tokens);
+        let decode_buf = Mutex::new(Utf8Buf::new());
+        let inner = Arc::new(TokenizerInner::GPT2(Gpt2Tokenizer::new(
             tokens.clone(),
             &merges,
             bos_token,
             eos_token,
-        ));
+        )));
         Self {
             tokens,
+            token_types: Arc::new(vec![]),
+            bos_token,
             eos_token,
+            pad_token: None,
+            unk_token: None,
+            options: TokenizerOptions::new(TokenizerKind::GPT2),
             utf8_buf: decode_buf,
             inner,
+            trie: Arc::new(OnceLock::new()),
         }
     }
 
+    pub fn with_options(mut self, options: TokenizerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &TokenizerOptions {
+        &self.options
+    }
+
+    /// the types of the tokens from `tokenizer.ggml.token_type`, the types are guessed from
+    /// the special tokens and the pieces if it's not provided.
+    pub fn with_token_types(mut self, token_types: Vec<TokenType>) -> Self {
+        self.token_types = Arc::new(token_types);
+        self
+    }
+
+    pub fn with_pad_token(mut self, pad_token: Option<TokenID>) -> Self {
+        self.pad_token = pad_token;
+        self
+    }
+
+    pub fn with_unk_token(mut self, unk_token: Option<TokenID>) -> Self {
+        self.unk_token = unk_token;
+        self
+    }
+
     pub fn kind(&self) -> TokenizerKind {
-        match &self.inner {
+        match self.inner.as_ref() {
             TokenizerInner::Llama(_) => TokenizerKind::Llama,
+            #[cfg(feature = "gpt2-tokenizer")]
             TokenizerInner::GPT2(_) => TokenizerKind::GPT2,
         }
     }
@@ -87,29 +259,275 @@ impl Tokenizer {
         &self.tokens
     }
 
+    pub fn vocab_size(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn bos_token(&self) -> TokenID {
+        self.bos_token
+    }
+
     pub fn eos_token(&self) -> TokenID {
         self.eos_token
     }
 
+    pub fn pad_token(&self) -> Option<TokenID> {
+        self.pad_token
+    }
+
+    pub fn unk_token(&self) -> Option<TokenID> {
+        self.unk_token
+    }
+
+    /// the piece of the token as it's stored in the vocab, like `▁hello` or `<0x0A>`, use
+    /// token_bytes() to get the text of it. None if the token is out of the vocab.
+    pub fn token_to_piece(&self, token_id: TokenID) -> Option<&str> {
+        self.tokens.get(token_id).map(String::as_str)
+    }
+
+    pub fn piece_to_token(&self, piece: &str) -> Option<TokenID> {
+        match self.inner.as_ref() {
+            TokenizerInner::Llama(inner) => inner.token_id(piece),
+            #[cfg(feature = "gpt2-tokenizer")]
+            TokenizerInner::GPT2(inner) => inner.token_id(piece),
+        }
+    }
+
+    /// the token standing for the raw byte, it's `<0xXX>` on the sentencepiece tokenizers,
+    /// and the byte level unicode character on the BPE ones.
+    pub fn byte_to_token(&self, byte: u8) -> Option<TokenID> {
+        match self.inner.as_ref() {
+            TokenizerInner::Llama(inner) => inner.byte_token(byte),
+            #[cfg(feature = "gpt2-tokenizer")]
+            TokenizerInner::GPT2(inner) => inner.byte_token(byte),
+        }
+    }
+
+    pub fn token_to_byte(&self, token_id: TokenID) -> Option<u8> {
+        match self.inner.as_ref() {
+            TokenizerInner::Llama(inner) => inner.token_byte(token_id),
+            #[cfg(feature = "gpt2-tokenizer")]
+            TokenizerInner::GPT2(inner) => inner.token_byte(token_id),
+        }
+    }
+
+    pub fn token_type(&self, token_id: TokenID) -> TokenType {
+        if let Some(token_type) = self.token_types.get(token_id) {
+            return *token_type;
+        }
+        if Some(token_id) == self.unk_token {
+            TokenType::Unknown
+        } else if [Some(self.bos_token), Some(self.eos_token), self.pad_token]
+            .contains(&Some(token_id))
+        {
+            TokenType::Control
+        } else if self.kind() == TokenizerKind::Llama && self.token_to_byte(token_id).is_some() {
+            TokenType::Byte
+        } else {
+            TokenType::Normal
+        }
+    }
+
+    /// the control and unknown tokens, they're not part of the text.
+    pub fn is_special(&self, token_id: TokenID) -> bool {
+        matches!(
+            self.token_type(token_id),
+            TokenType::Control | TokenType::Unknown
+        )
+    }
+
     pub fn token(&self, token_id: TokenID) -> String {
         self.tokens[token_id].clone()
     }
 
+    /// the raw bytes of the token, the byte tokens like `<0x0A>` are converted into the byte.
+    /// unlike decode, it does not buffer the incomplete utf-8 characters.
+    pub fn token_bytes(&self, token_id: TokenID) -> Vec<u8> {
+        match self.inner.as_ref() {
+            TokenizerInner::Llama(inner) => inner.decode(token_id),
+            #[cfg(feature = "gpt2-tokenizer")]
+            TokenizerInner::GPT2(inner) => inner.decode(token_id),
+        }
+    }
+
+    /// the prefix trie over the bytes of the tokens, it's built on the first call and shared
+    /// between the clones.
+    pub fn trie(&self) -> &TokenTrie {
+        self.trie.get_or_init(|| {
+            let pieces = (0..self.tokens.len())
+                .map(|id| self.token_bytes(id))
+                .collect::<Vec<_>>();
+            TokenTrie::new(&pieces)
+        })
+    }
+
     /// TODO: make it consume an Interator<Item=Result<TokenID>>
     pub fn decode(&self, token: TokenID) -> Result<String> {
-        let bytes = match &self.inner {
-            TokenizerInner::Llama(inner) => inner.decode(token),
-            TokenizerInner::GPT2(inner) => inner.decode(token),
-        };
-        Ok(self.utf8_buf.borrow_mut().step(&bytes))
+        let mut text = String::new();
+        self.decode_append(token, &mut text)?;
+        Ok(text)
+    }
+
+    /// decode the token and append its text to the end of text, nothing is appended until the
+    /// character split over the tokens is complete. it allocates nothing once text has the
+    /// capacity, so the streaming callers can decode all the tokens into a reused buffer and
+    /// take the pieces as the slices of it.
+    pub fn decode_append(&self, token: TokenID, text: &mut String) -> Result<()> {
+        let mut utf8_buf = self.utf8_buf.lock().unwrap();
+        utf8_buf.token_bytes.clear();
+        match self.inner.as_ref() {
+            TokenizerInner::Llama(inner) => inner.decode_to(token, &mut utf8_buf.token_bytes),
+            #[cfg(feature = "gpt2-tokenizer")]
+            TokenizerInner::GPT2(inner) => inner.decode_to(token, &mut utf8_buf.token_bytes),
+        }
+        utf8_buf.step(text);
+        Ok(())
     }
 
     // encode the string text (input) into an upper-bound preallocated tokens[] array
     // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
     pub fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<TokenID>> {
-        match &self.inner {
-            TokenizerInner::Llama(inner) => Ok(inner.encode(text, bos, eos, true)),
-            TokenizerInner::GPT2(inner) => Ok(inner.encode(text, bos, eos, true)),
+        Ok(self.encode_text(text, bos, eos, self.options.add_space_prefix))
+    }
+
+    /// like encode, but the text is taken as plain text: the pieces of the special tokens in
+    /// it, like `</s>` or `<|im_end|>`, are encoded as their bytes instead of the special tokens.
+    /// only the BOS and the EOS asked for are special.
+    pub fn encode_raw(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<TokenID>> {
+        let mut tokens = Vec::with_capacity(text.len() + 2);
+        if bos {
+            tokens.push(self.bos_token);
+        }
+        for token in self.encode(text, false, false)? {
+            if !self.is_special(token) {
+                tokens.push(token);
+                continue;
+            }
+            for byte in self.tokens[token].bytes() {
+                let byte_token = self.byte_to_token(byte).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::BadInput,
+                        format!(
+                            "no byte token of {:#04x} to encode {} as text",
+                            byte, self.tokens[token]
+                        ),
+                    )
+                })?;
+                tokens.push(byte_token);
+            }
+        }
+        if eos {
+            tokens.push(self.eos_token);
+        }
+        Ok(tokens)
+    }
+
+    fn encode_text(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+        add_prefix_space: bool,
+    ) -> Vec<TokenID> {
+        let text = self.normalize(text);
+        match self.inner.as_ref() {
+            TokenizerInner::Llama(inner) => inner.encode(&text, bos, eos, add_prefix_space),
+            #[cfg(feature = "gpt2-tokenizer")]
+            TokenizerInner::GPT2(inner) => inner.encode(
+                &text,
+                bos,
+                eos,
+                add_prefix_space,
+                self.options.pre_tokenizer,
+            ),
+        }
+    }
+
+    fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !self.options.normalize_nfc || is_nfc_quick(text.chars()) == IsNormalized::Yes {
+            return Cow::Borrowed(text);
+        }
+        Cow::Owned(text.nfc().collect())
+    }
+
+    /// encode the text appended after the already encoded prev_tokens, returns the tokens of
+    /// the whole text. the merges may cross the boundary of the appended text, so the tokens
+    /// from the last word boundary of prev_tokens are re-encoded together with the new text,
+    /// instead of re-encoding the whole history on every turn of a conversation. the tokens
+    /// before the boundary are kept as is, so their KV cache can be reused.
+    pub fn encode_append(&self, prev_tokens: &[TokenID], new_text: &str) -> Result<Vec<TokenID>> {
+        if new_text.is_empty() {
+            return Ok(prev_tokens.to_vec());
+        }
+
+        // the BOS/EOS tokens are never merged, the text after the last of them is re-encodable
+        let start = prev_tokens
+            .iter()
+            .rposition(|t| *t == self.bos_token || *t == self.eos_token)
+            .map(|pos| pos + 1)
+            .unwrap_or(0);
+        if start == prev_tokens.len() {
+            // the text right after the BOS takes the dummy prefix like encode() does
+            let add_prefix_space = self.options.add_space_prefix
+                && (start == 0 || prev_tokens[start - 1] == self.bos_token);
+            return Ok(self.encode_after(prev_tokens, start, new_text, add_prefix_space));
+        }
+
+        let lookback = prev_tokens
+            .len()
+            .saturating_sub(ENCODE_APPEND_LOOKBACK)
+            .max(start + 1);
+        let cut = (lookback..prev_tokens.len())
+            .rev()
+            .find(|i| self.is_word_boundary(prev_tokens[*i - 1], prev_tokens[*i]))
+            .unwrap_or(start);
+
+        // the dummy prefix of the first token is kept in its bytes, so the region is re-encoded
+        // without adding the prefix again.
+        let mut text = prev_tokens[cut..]
+            .iter()
+            .flat_map(|t| self.token_bytes(*t))
+            .collect::<Vec<_>>();
+        text.extend_from_slice(new_text.as_bytes());
+        let text = String::from_utf8_lossy(&text);
+        Ok(self.encode_after(prev_tokens, cut, &text, false))
+    }
+
+    // a word starts with a space, unless the previous token ends with a space, the spaces may
+    // be merged together like "▁▁".
+    fn is_word_boundary(&self, prev: TokenID, token: TokenID) -> bool {
+        self.token_bytes(token).first() == Some(&b' ')
+            && self.token_bytes(prev).last() != Some(&b' ')
+    }
+
+    fn encode_after(
+        &self,
+        prev_tokens: &[TokenID],
+        cut: usize,
+        text: &str,
+        add_prefix_space: bool,
+    ) -> Vec<TokenID> {
+        let new_tokens = self.encode_text(text, false, false, add_prefix_space);
+        let mut tokens = prev_tokens[..cut].to_vec();
+        tokens.extend(new_tokens);
+        tokens
+    }
+}
+
+impl Clone for Tokenizer {
+    /// the clone shares the vocab, but starts with an empty decoding state.
+    fn clone(&self) -> Self {
+        Self {
+            tokens: self.tokens.clone(),
+            token_types: self.token_types.clone(),
+            bos_token: self.bos_token,
+            eos_token: self.eos_token,
+            pad_token: self.pad_token,
+            unk_token: self.unk_token,
+            options: self.options,
+            inner: self.inner.clone(),
+            trie: self.trie.clone(),
+            utf8_buf: Mutex::new(Utf8Buf::new()),
         }
     }
 }
@@ -118,12 +536,15 @@ impl Tokenizer {
 /// until we have a valid utf-8 string, then return it.
 struct Utf8Buf {
     buf: Vec<u8>,
+    // the bytes of the token being decoded, reused over the tokens
+    token_bytes: Vec<u8>,
 }
 
 impl Utf8Buf {
     pub fn new() -> Self {
         Self {
             buf: Vec::with_capacity(128),
+            token_bytes: Vec::with_capacity(128),
         }
     }
 
@@ -131,22 +552,13 @@ impl Utf8Buf {
         std::str::from_utf8(&self.buf).is_ok()
     }
 
-    pub fn step(&mut self, bytes: &[u8]) -> String {
-        let is_utf8 = std::str::from_utf8(bytes).is_ok();
-        if is_utf8 {
-            self.buf.extend(bytes);
-            let s = String::from_utf8_lossy(&self.buf).to_string();
+    // take the bytes of the token, and append the buffered text to out once it's valid
+    pub fn step(&mut self, out: &mut String) {
+        let is_utf8 = std::str::from_utf8(&self.token_bytes).is_ok();
+        self.buf.extend_from_slice(&self.token_bytes);
+        if is_utf8 || self.is_valid() || self.buf.len() >= 4 {
+            out.push_str(&String::from_utf8_lossy(&self.buf));
             self.buf.clear();
-            return s;
         }
-
-        self.buf.extend(bytes);
-        if self.is_valid() || self.buf.len() >= 4 {
-            let s = String::from_utf8_lossy(&self.buf).to_string();
-            self.buf.clear();
-            return s;
-        }
-
-        "".to_string()
     }
 }
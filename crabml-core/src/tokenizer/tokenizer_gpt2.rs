@@ -1,13 +1,22 @@
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::OnceLock;
 
 use regex::Regex;
 
+use super::PreTokenizer;
 use super::TokenID;
 
+// the pre-tokenizer regex of Qwen2 without its `\s+(?!\S)` alternative, the regex crate has no
+// lookahead, it's emulated by `split_qwen2` instead
+const QWEN2_PATTERN: &str = concat!(
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+",
+);
+
 pub struct Gpt2Tokenizer {
-    tokens: Rc<Vec<String>>,
-    token_ids: Rc<HashMap<String, TokenID>>,
+    tokens: Arc<Vec<String>>,
+    token_ids: Arc<HashMap<String, TokenID>>,
     bpe_ranks: HashMap<(TokenID, TokenID), usize>,
     byte_encodes: HashMap<u8, char>,
     byte_decodes: HashMap<char, u8>,
@@ -17,12 +26,12 @@ pub struct Gpt2Tokenizer {
 
 impl Gpt2Tokenizer {
     pub fn new(
-        tokens: Rc<Vec<String>>,
+        tokens: Arc<Vec<String>>,
         merges: &[String],
         bos_token: TokenID,
         eos_token: TokenID,
     ) -> Self {
-        let token_ids: Rc<HashMap<String, TokenID>> = Rc::new(
+        let token_ids: Arc<HashMap<String, TokenID>> = Arc::new(
             tokens
                 .iter()
                 .enumerate()
@@ -57,23 +66,53 @@ impl Gpt2Tokenizer {
     }
 
     pub fn decode(&self, token_id: TokenID) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.decode_to(token_id, &mut bytes);
+        bytes
+    }
+
+    /// append the bytes of the token to buf, without allocating on the way.
+    pub fn decode_to(&self, token_id: TokenID, buf: &mut Vec<u8>) {
         let token = &self.tokens[token_id];
         if token.len() > 1 {
-            token.as_bytes().to_vec()
+            buf.extend_from_slice(token.as_bytes());
         } else if token.len() == 1 {
             let ch = token.chars().next().unwrap();
             match self.byte_decodes.get(&ch) {
-                Some(b) => vec![*b],
-                None => vec![ch as u8],
+                Some(b) => buf.push(*b),
+                None => buf.push(ch as u8),
             }
-        } else {
-            vec![]
+        }
+    }
+
+    pub fn token_id(&self, piece: &str) -> Option<TokenID> {
+        self.token_ids.get(piece).copied()
+    }
+
+    /// every byte is mapped to a unicode character, which is a token in the vocab.
+    pub fn byte_token(&self, byte: u8) -> Option<TokenID> {
+        let ch = self.byte_encodes.get(&byte)?;
+        self.token_id(&ch.to_string())
+    }
+
+    pub fn token_byte(&self, token: TokenID) -> Option<u8> {
+        let mut chars = self.tokens[token].chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => self.byte_decodes.get(&ch).copied(),
+            _ => None,
         }
     }
 
     // encode the string text (input) into an upper-bound preallocated tokens[] array
     // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
-    pub fn encode(&self, text: &str, bos: bool, eos: bool, add_prefix_space: bool) -> Vec<TokenID> {
+    pub fn encode(
+        &self,
+        text: &str,
+        bos: bool,
+        eos: bool,
+        add_prefix_space: bool,
+        pre_tokenizer: PreTokenizer,
+    ) -> Vec<TokenID> {
         let text = if add_prefix_space {
             format!(" {}", text)
         } else {
@@ -89,14 +128,22 @@ impl Gpt2Tokenizer {
                 if special_tokens.contains(&s.as_str()) {
                     return vec![*self.token_ids.get(s).unwrap()];
                 }
-                let mut toks = vec![];
-                for b in s.bytes() {
-                    let ch = self.byte_encodes.get(&b).unwrap().to_string();
-                    let token_id = self.token_ids.get(&ch).unwrap();
-                    toks.push(*token_id);
-                }
-                toks = self.bpe_merge(toks);
-                toks
+                let words = match pre_tokenizer {
+                    PreTokenizer::Whole => vec![s.as_str()],
+                    PreTokenizer::Qwen2 => split_qwen2(s),
+                };
+                words
+                    .into_iter()
+                    .flat_map(|word| {
+                        let mut toks = vec![];
+                        for b in word.bytes() {
+                            let ch = self.byte_encodes.get(&b).unwrap().to_string();
+                            let token_id = self.token_ids.get(&ch).unwrap();
+                            toks.push(*token_id);
+                        }
+                        self.bpe_merge(toks)
+                    })
+                    .collect::<Vec<_>>()
             })
             .collect::<Vec<_>>();
 
@@ -159,6 +206,29 @@ fn build_byte_encode_map() -> HashMap<u8, char> {
     map
 }
 
+/// split the text into the words of the Qwen2 pre-tokenizer. the `\s+(?!\S)` of its regex
+/// leaves the last whitespace of a run to the word after it, like the space of " world" in
+/// "hello  world", so the run is shortened by one and the matching goes on from there.
+fn split_qwen2(text: &str) -> Vec<&str> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let re = PATTERN.get_or_init(|| Regex::new(QWEN2_PATTERN).unwrap());
+
+    let mut words = vec![];
+    let mut pos = 0;
+    while let Some(m) = re.find_at(text, pos) {
+        let word = m.as_str();
+        let mut end = m.end();
+        let is_spaces = word.chars().all(char::is_whitespace) && !word.ends_with(['\r', '\n']);
+        let last = word.char_indices().last().map_or(0, |(i, _)| i);
+        if is_spaces && last > 0 && end < text.len() {
+            end = m.start() + last;
+        }
+        words.push(&text[m.start()..end]);
+        pos = end;
+    }
+    words
+}
+
 fn split_text_by_keyword(text: &str, keywords: &[&str]) -> Vec<String> {
     // Escape the keywords and join them into a regular expression pattern
     let escaped_keywords: Vec<String> = keywords.iter().map(|&k| regex::escape(k)).collect();
@@ -195,7 +265,7 @@ mod tests {
             GGUFFileLoader::new("/Users/yazhou/llm/qwen1_5-0_5b-chat-q8_0.gguf", false)?;
         let gf = gf_loader.open()?;
 
-        let tokens = Rc::new(
+        let tokens = Arc::new(
             gf.metadata()
                 .get_string_array("tokenizer.ggml.tokens")
                 .unwrap()
@@ -224,7 +294,7 @@ mod tests {
         ];
 
         for tt in tests {
-            let outputs = tk.encode(tt.0, false, false, false);
+            let outputs = tk.encode(tt.0, false, false, false, PreTokenizer::Whole);
             let tokens_in_string = outputs
                 .iter()
                 .map(|t| tokens[*t].clone())
@@ -236,6 +306,18 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_split_qwen2() {
+        assert_eq!(split_qwen2("Hello  world\n\n  123!"), vec![
+            "Hello", " ", " world", "\n\n", " ", " ", "1", "2", "3", "!"
+        ]);
+        assert_eq!(split_qwen2("I'M fine, thanks.  "), vec![
+            "I", "'M", " fine", ",", " thanks", ".", "  "
+        ]);
+        assert_eq!(split_qwen2("a\t\tb"), vec!["a", "\t", "\tb"]);
+        assert!(split_qwen2("").is_empty());
+    }
+
     #[test]
     fn test_split_words() {
         let output: Vec<String> = split_text_by_keyword(
@@ -1,10 +1,10 @@
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use super::TokenID;
 
 pub struct LlamaTokenizer {
-    tokens: Rc<Vec<String>>,
+    tokens: Arc<Vec<String>>,
     token_ids: HashMap<String, TokenID>,
     token_scores: HashMap<TokenID, f32>,
     token_buf_len: usize,
@@ -14,7 +14,7 @@ pub struct LlamaTokenizer {
 
 impl LlamaTokenizer {
     pub fn new(
-        tokens: Rc<Vec<String>>,
+        tokens: Arc<Vec<String>>,
         scores: Vec<f32>,
         bos_token: TokenID,
         eos_token: TokenID,
@@ -36,27 +36,49 @@ impl LlamaTokenizer {
     }
 
     pub fn decode(&self, token: TokenID) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.decode_to(token, &mut bytes);
+        bytes
+    }
+
+    /// append the bytes of the token to buf, without allocating on the way.
+    pub fn decode_to(&self, token: TokenID, buf: &mut Vec<u8>) {
         // get the token string from the tokens table
-        let piece: &[u8] = self.tokens[token].as_bytes();
+        let piece = &self.tokens[token];
 
         // some tokens designate raw bytes, and look like e.g. '<0x01>', we need parse this and
         // convert and return the actual byte.
         // this is a bit of a hack, the byte itself might not be a valid utf8 character, we need append
         // it to the decode_buf until we have a valid utf8 string, then return that. before that, we
         // return an empty string.
-        let is_byte = piece.starts_with(b"<0x") && piece[piece.len() - 1] == b'>';
-        if is_byte {
-            let s = String::from_utf8_lossy(&piece[1..piece.len() - 1]);
-            let byte = u8::from_str_radix(s.trim_start_matches("0x"), 16).unwrap();
-            vec![byte]
-        } else if piece.starts_with("▁".as_bytes()) {
-            let s = self.tokens[token].replace('▁', " ");
-            s.as_bytes().to_vec()
+        if let Some(byte) = self.token_byte(token) {
+            buf.push(byte);
+        } else if piece.starts_with('▁') {
+            for (i, part) in piece.split('▁').enumerate() {
+                if i > 0 {
+                    buf.push(b' ');
+                }
+                buf.extend_from_slice(part.as_bytes());
+            }
         } else {
-            piece.to_vec()
+            buf.extend_from_slice(piece.as_bytes());
         }
     }
 
+    pub fn token_id(&self, piece: &str) -> Option<TokenID> {
+        self.token_ids.get(piece).copied()
+    }
+
+    /// the byte fallback token of the byte, like `<0x0A>`.
+    pub fn byte_token(&self, byte: u8) -> Option<TokenID> {
+        self.token_id(&format!("<0x{:02X}>", byte))
+    }
+
+    pub fn token_byte(&self, token: TokenID) -> Option<u8> {
+        let hex = self.tokens[token].strip_prefix("<0x")?.strip_suffix('>')?;
+        u8::from_str_radix(hex, 16).ok()
+    }
+
     // encode the string text (input) into an upper-bound preallocated tokens[] array
     // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
     pub fn encode(&self, text: &str, bos: bool, eos: bool, add_prefix_space: bool) -> Vec<TokenID> {
@@ -89,11 +111,11 @@ impl LlamaTokenizer {
                 // we found this codepoint in vocab, add it as a token
                 tokens.push(*tok);
             } else {
-                // byte_fallback encoding: just encode each byte as a token
-                // +3 is here because the first 3 vocab elements are <unk>, <s>, </s>
-                // so the individual bytes only start at index 3
+                // byte_fallback encoding: just encode each byte as a token. the byte tokens
+                // usually start at index 3 after <unk>, <s>, </s>, but it's looked up in the
+                // vocab in case they do not
                 for byte in token_buf.bytes() {
-                    tokens.push(byte as usize + 3);
+                    tokens.push(self.byte_token(byte).unwrap_or(byte as usize + 3));
                 }
             }
         }
@@ -105,7 +127,8 @@ impl LlamaTokenizer {
             let mut best_token: Option<usize> = None;
             let mut i = 0;
 
-            while i < (tokens.len() - 1) {
+            // the tokens are empty on encoding an empty text without the BOS
+            while i + 1 < tokens.len() {
                 token_buf.clear();
                 token_buf.push_str(&self.tokens[tokens[i]]);
                 token_buf.push_str(&self.tokens[tokens[i + 1]]);
@@ -138,12 +161,12 @@ impl LlamaTokenizer {
 
 #[cfg(test)]
 mod tests {
+    use super::super::TokenType;
     use super::super::Tokenizer;
     use crate::error::Result;
     use crate::gguf::GGUFFileLoader;
 
-    #[test]
-    fn test_gguf_tokenizer() -> Result<()> {
+    fn load_tokenizer() -> Result<Tokenizer> {
         let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
         let gf = gf_loader.open()?;
 
@@ -159,10 +182,12 @@ mod tests {
             .get_f32_array("tokenizer.ggml.scores")
             .unwrap()
             .to_vec();
-        for (i, tok) in tokens.iter().enumerate().take(100) {
-            println!("{} {}", i, tok);
-        }
-        let tk = Tokenizer::new_llama(tokens, token_scores, 1, 2);
+        Ok(Tokenizer::new_llama(tokens, token_scores, 1, 2))
+    }
+
+    #[test]
+    fn test_gguf_tokenizer() -> Result<()> {
+        let tk = load_tokenizer()?;
 
         let tests = vec![
             (10842, "▁Captain"),
@@ -204,4 +229,117 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_encode_byte_fallback() -> Result<()> {
+        let tk = load_tokenizer()?;
+        assert_eq!(tk.encode("", false, false)?, Vec::<usize>::new());
+
+        // the crab is not in the vocab, it falls back to its utf-8 bytes
+        let tokens = tk.encode("a 🦀!", false, false)?;
+        let byte_tokens = "🦀"
+            .bytes()
+            .map(|b| tk.byte_to_token(b).unwrap())
+            .collect::<Vec<_>>();
+        assert!(tokens.windows(4).any(|w| w == byte_tokens));
+        let decoded = tokens
+            .iter()
+            .map(|t| tk.decode(*t))
+            .collect::<Result<String>>()?;
+        assert_eq!(decoded.trim_start(), "a 🦀!");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_raw() -> Result<()> {
+        let tk = load_tokenizer()?;
+        let text = "the end</s><s> of it";
+        let tokens = tk.encode_raw(text, true, false)?;
+        assert_eq!(tokens[0], tk.bos_token());
+        // the pieces of the special tokens in the text are plain text
+        assert!(tokens[1..].iter().all(|t| !tk.is_special(*t)));
+        let decoded = tokens[1..]
+            .iter()
+            .map(|t| tk.decode(*t))
+            .collect::<Result<String>>()?;
+        assert_eq!(decoded.trim_start(), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_append() -> Result<()> {
+        let tk = load_tokenizer()?;
+        let tokens = tk.encode("a 🦀!", false, false)?;
+        let mut text = String::with_capacity(64);
+        let mut pieces = vec![];
+        for token in tokens.iter() {
+            let start = text.len();
+            tk.decode_append(*token, &mut text)?;
+            pieces.push(text[start..].to_string());
+        }
+        assert_eq!(text.trim_start(), "a 🦀!");
+        // the crab is appended at once on its last byte token
+        assert!(pieces.contains(&"🦀".to_string()));
+        assert_eq!(pieces.concat(), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_append() -> Result<()> {
+        let tk = load_tokenizer()?;
+        let tests = vec![
+            ("", "Captain America"),
+            ("Captain America", ": hello, world"),
+            ("i don't eat be", "af."),
+            ("tik", "tok"),
+            ("hello,", " world"),
+        ];
+        for (prev, new) in tests {
+            let prev_tokens = tk.encode(prev, true, false)?;
+            let got = tk.encode_append(&prev_tokens, new)?;
+            let expected = tk.encode(&format!("{}{}", prev, new), true, false)?;
+            assert_eq!(got, expected, "failed to append {:?} to {:?}", new, prev);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenizer_options() -> Result<()> {
+        let tk = load_tokenizer()?;
+        let options = *tk.options();
+        assert!(options.add_bos && options.add_space_prefix);
+
+        // "e" followed by a combining acute accent is composed into "é"
+        let tk = tk.with_options(options.with_normalize_nfc(true));
+        assert_eq!(
+            tk.encode("caf\u{65}\u{301}", true, false)?,
+            tk.encode("caf\u{e9}", true, false)?
+        );
+
+        let tk = tk.with_options(options.with_add_space_prefix(false));
+        let tokens = tk.encode("hello", false, false)?;
+        assert!(!tk.token(tokens[0]).starts_with('▁'));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vocab_api() -> Result<()> {
+        let tk = load_tokenizer()?;
+        assert_eq!(tk.vocab_size(), 32000);
+        assert_eq!(tk.piece_to_token("▁Captain"), Some(10842));
+        assert_eq!(tk.token_to_piece(10842), Some("▁Captain"));
+        assert_eq!(tk.token_to_piece(32000), None);
+        assert_eq!(tk.piece_to_token("not-a-piece"), None);
+
+        assert_eq!(tk.byte_to_token(b'\n'), Some(13));
+        assert_eq!(tk.token_to_piece(13), Some("<0x0A>"));
+        assert_eq!(tk.token_to_byte(13), Some(b'\n'));
+        assert_eq!(tk.token_to_byte(10842), None);
+
+        assert_eq!(tk.token_type(1), TokenType::Control);
+        assert_eq!(tk.token_type(13), TokenType::Byte);
+        assert_eq!(tk.token_type(10842), TokenType::Normal);
+        assert!(tk.is_special(2));
+        Ok(())
+    }
 }
@@ -0,0 +1,143 @@
+use super::TokenID;
+
+#[derive(Debug, Default, Clone)]
+struct TrieNode {
+    // sorted by the byte
+    children: Vec<(u8, u32)>,
+    // the tokens whose bytes end at this node, different tokens may have the same bytes, like
+    // the byte token <0x20> and the piece "▁"
+    tokens: Vec<TokenID>,
+}
+
+/// a prefix trie over the bytes of the token pieces. the token healing and the grammar
+/// constraints walk the trie, instead of scanning the whole vocabulary linearly.
+#[derive(Debug, Clone)]
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl TokenTrie {
+    /// the empty pieces (like some special tokens) are not indexed.
+    pub fn new(pieces: &[Vec<u8>]) -> Self {
+        let mut trie = Self {
+            nodes: vec![TrieNode::default()],
+        };
+        for (id, piece) in pieces.iter().enumerate() {
+            if piece.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for b in piece {
+                node = match trie.child(node, *b) {
+                    Some(child) => child,
+                    None => trie.add_child(node, *b),
+                };
+            }
+            trie.nodes[node as usize].tokens.push(id);
+        }
+        trie
+    }
+
+    fn child(&self, node: u32, byte: u8) -> Option<u32> {
+        let children = &self.nodes[node as usize].children;
+        children
+            .binary_search_by_key(&byte, |(b, _)| *b)
+            .ok()
+            .map(|i| children[i].1)
+    }
+
+    fn add_child(&mut self, node: u32, byte: u8) -> u32 {
+        let child = self.nodes.len() as u32;
+        self.nodes.push(TrieNode::default());
+        let children = &mut self.nodes[node as usize].children;
+        let pos = children.partition_point(|(b, _)| *b < byte);
+        children.insert(pos, (byte, child));
+        child
+    }
+
+    fn find(&self, bytes: &[u8]) -> Option<u32> {
+        bytes.iter().try_fold(0, |node, b| self.child(node, *b))
+    }
+
+    /// the tokens whose bytes are exactly the given bytes.
+    pub fn get(&self, bytes: &[u8]) -> &[TokenID] {
+        match self.find(bytes) {
+            Some(node) => &self.nodes[node as usize].tokens,
+            None => &[],
+        }
+    }
+
+    /// the tokens which start with the prefix, including the ones equal to it. used by token
+    /// healing to find the candidates to replace the last token of the prompt.
+    pub fn tokens_with_prefix(&self, prefix: &[u8]) -> Vec<TokenID> {
+        let mut tokens = vec![];
+        if let Some(node) = self.find(prefix) {
+            let mut stack = vec![node];
+            while let Some(node) = stack.pop() {
+                let node = &self.nodes[node as usize];
+                tokens.extend_from_slice(&node.tokens);
+                stack.extend(node.children.iter().map(|(_, child)| *child));
+            }
+        }
+        tokens.sort_unstable();
+        tokens
+    }
+
+    /// walk all the tokens along with a state machine over the bytes, the subtrees are pruned
+    /// once the state machine rejects a byte, so the tokens sharing a rejected prefix are
+    /// skipped at once. `visit` is called with every accepted token and the state after it.
+    pub fn walk<S: Copy>(
+        &self,
+        init: S,
+        step: impl Fn(S, u8) -> Option<S>,
+        mut visit: impl FnMut(TokenID, S),
+    ) {
+        let mut stack = vec![(0_u32, init)];
+        while let Some((node, state)) = stack.pop() {
+            let node = &self.nodes[node as usize];
+            for id in node.tokens.iter() {
+                visit(*id, state);
+            }
+            for (b, child) in node.children.iter() {
+                if let Some(next) = step(state, *b) {
+                    stack.push((*child, next));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie() -> TokenTrie {
+        let pieces = ["", "a", "ab", "abc", "b", "ab", "bc"];
+        TokenTrie::new(&pieces.map(|p| p.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn test_token_trie() {
+        let trie = trie();
+        assert_eq!(trie.get(b"ab"), &[2, 5]);
+        assert!(trie.get(b"").is_empty());
+        assert!(trie.get(b"x").is_empty());
+
+        assert_eq!(trie.tokens_with_prefix(b"ab"), vec![2, 3, 5]);
+        assert_eq!(trie.tokens_with_prefix(b"c"), Vec::<TokenID>::new());
+    }
+
+    #[test]
+    fn test_token_trie_walk() {
+        let trie = trie();
+        // only the bytes 'a' and 'b' are accepted, count the bytes on the way
+        let mut visited = vec![];
+        trie.walk(
+            0,
+            |n, b| (b == b'a' || b == b'b').then_some(n + 1),
+            |id, n| visited.push((id, n)),
+        );
+        visited.sort();
+        assert_eq!(visited, vec![(1, 1), (2, 2), (4, 1), (5, 2)]);
+    }
+}
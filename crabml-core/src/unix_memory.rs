@@ -0,0 +1,83 @@
+//! unix specific helpers for the mapped model files. memmap2 advises the whole mapping on
+//! loading, while the weights are prefetched tensor by tensor ahead of their use.
+
+use std::ffi::c_long;
+use std::ffi::c_void;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+// the same on linux, macOS and the BSDs
+const MADV_WILLNEED: i32 = 3;
+
+// the value of _SC_PAGESIZE differs between the libcs
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SC_PAGESIZE: i32 = 30;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const SC_PAGESIZE: i32 = 29;
+#[cfg(target_os = "freebsd")]
+const SC_PAGESIZE: i32 = 47;
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+const SC_PAGESIZE: i32 = 28;
+
+extern "C" {
+    fn madvise(addr: *mut c_void, len: usize, advice: i32) -> i32;
+    fn sysconf(name: i32) -> c_long;
+}
+
+/// the size of the pages of the platform, like 4KB on x86 and 16KB on Apple Silicon.
+pub fn page_size() -> Result<usize> {
+    let ret = unsafe { sysconf(SC_PAGESIZE) };
+    if ret <= 0 || (ret as usize).count_ones() != 1 {
+        return Err(
+            Error::new(ErrorKind::IOError, "failed to get the page size")
+                .with_cause(std::io::Error::last_os_error()),
+        );
+    }
+    Ok(ret as usize)
+}
+
+/// ask the kernel to read the pages of the buffer in the background, like `madvise(WILLNEED)`.
+/// madvise takes a page aligned address, the range is widened to the start of the first page of
+/// the buffer. a mapping starts on a page boundary, so the range never leaves the mapping of the
+/// buffer.
+pub fn prefetch(buf: &[u8]) -> Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let (start, end) = page_range(buf.as_ptr() as usize, buf.len(), page_size()?);
+    let ret = unsafe { madvise(start as *mut c_void, end - start, MADV_WILLNEED) };
+    if ret != 0 {
+        return Err(
+            Error::new(ErrorKind::IOError, "failed to prefetch the mapped file")
+                .with_cause(std::io::Error::last_os_error()),
+        );
+    }
+    Ok(())
+}
+
+// the range of the bytes from the start of the page of addr to the end of the bytes
+fn page_range(addr: usize, len: usize, page_size: usize) -> (usize, usize) {
+    (addr & !(page_size - 1), addr + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_range() -> Result<()> {
+        let page_size = page_size()?;
+        assert!(page_size >= 4096);
+
+        assert_eq!(page_range(4096 + 100, 10, 4096), (4096, 4096 + 110));
+        assert_eq!(page_range(8192, 4096, 4096), (8192, 12288));
+        assert_eq!(page_range(16384 + 4096, 1, 16384), (16384, 16384 + 4097));
+
+        let buf = vec![1u8; page_size * 3];
+        prefetch(&buf[page_size + 1..page_size * 2 + 1])?;
+        prefetch(&buf[..0])?;
+        Ok(())
+    }
+}
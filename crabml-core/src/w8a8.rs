@@ -0,0 +1,163 @@
+// an experimental fully int8 path (W8A8): the weights are quantized into int8 with a scale per
+// output channel, the activations with a scale per token, and the dot products are accumulated
+// in i32. unlike q8_0 there's a single scale per row instead of one per block of 32, so the inner
+// loop is a plain int8 dot product which maps onto the VNNI / dot-product instructions, see
+// `vec_dot_i8_i8`. the weights of the linear layers are converted on loading with
+// `CpuLlama2ModelLoader::with_w8a8`, and consumed by `matmul_vec` as `CpuTensorBuf::W8A8`.
+
+use crate::embedding::vec_dot_i8_i8;
+use crate::embedding::Int8Embedding;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// a (rows, cols) weight matrix in int8 with a scale per row, the value is restored as
+/// `values[r * cols + c] as f32 * scales[r]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Int8Matrix {
+    rows: usize,
+    cols: usize,
+    scales: Vec<f32>,
+    values: Vec<i8>,
+}
+
+impl Int8Matrix {
+    /// symmetric quantization of each row, the element with the max absolute value of a row is
+    /// mapped to ±127.
+    pub fn quantize(weights: &[f32], rows: usize, cols: usize) -> Result<Self> {
+        if weights.len() != rows * cols {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "invalid shape ({}, {}) for weights of length {}",
+                    rows,
+                    cols,
+                    weights.len()
+                ),
+            )
+                .into());
+        }
+        let mut scales = Vec::with_capacity(rows);
+        let mut values = Vec::with_capacity(rows * cols);
+        for row in weights.chunks(cols.max(1)).take(rows) {
+            let q = Int8Embedding::quantize(row);
+            scales.push(q.scale);
+            values.extend(q.values);
+        }
+        Ok(Self {
+            rows,
+            cols,
+            scales,
+            values,
+        })
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// the element at pos of the row-major matrix.
+    pub fn dequantize_at(&self, pos: usize) -> f32 {
+        self.values[pos] as f32 * self.scales[pos / self.cols.max(1)]
+    }
+
+    /// the dot product of a row and a token of the activations quantized by
+    /// `quantize_activations`.
+    pub fn dot_row(&self, row: usize, x: &Int8Embedding) -> f32 {
+        let w = &self.values[row * self.cols..(row + 1) * self.cols];
+        vec_dot_i8_i8(w, &x.values) as f32 * self.scales[row] * x.scale
+    }
+
+    /// the dot product of a row and the f32 activations, without quantizing them. it's the
+    /// slow path for the callers which take a single dot product.
+    pub fn dot_row_f32(&self, row: usize, x: &[f32]) -> f32 {
+        let w = &self.values[row * self.cols..(row + 1) * self.cols];
+        let dot = w.iter().zip(x).map(|(w, x)| *w as f32 * x).sum::<f32>();
+        dot * self.scales[row]
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.values
+            .chunks(self.cols.max(1))
+            .zip(self.scales.iter())
+            .flat_map(|(row, scale)| row.iter().map(move |v| *v as f32 * scale))
+            .collect()
+    }
+
+    /// x: (n_batch, cols) => (n_batch, rows), each token of the batch is quantized with its own
+    /// scale before the int8 dot products.
+    pub fn matmul(&self, x: &[f32], n_batch: usize) -> Result<Vec<f32>> {
+        if x.len() != n_batch * self.cols {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "w8a8 matmul: invalid input of length {} for ({}, {})",
+                    x.len(),
+                    n_batch,
+                    self.cols
+                ),
+            )
+                .into());
+        }
+        let mut out = Vec::with_capacity(n_batch * self.rows);
+        for qx in quantize_activations(x, self.cols) {
+            out.extend((0..self.rows).map(|r| self.dot_row(r, &qx)));
+        }
+        Ok(out)
+    }
+}
+
+/// quantize the activations of (n_batch, cols) with a scale per token, each token is multiplied
+/// by the rows of an `Int8Matrix` with `dot_row`.
+pub fn quantize_activations(x: &[f32], cols: usize) -> Vec<Int8Embedding> {
+    x.chunks(cols.max(1)).map(Int8Embedding::quantize).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_w8a8_matmul() -> Result<()> {
+        let (rows, cols, n_batch) = (8, 96, 3);
+        // the rows take different ranges, which the per channel scales keep apart
+        let w = (0..rows * cols)
+            .map(|i| ((i * 7) % 31) as f32 / 31.0 - 0.5)
+            .enumerate()
+            .map(|(i, v)| v * (1 + i / cols) as f32)
+            .collect::<Vec<_>>();
+        let x = (0..n_batch * cols)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let m = Int8Matrix::quantize(&w, rows, cols)?;
+        assert_eq!(m.shape(), (rows, cols));
+        for (v, dv) in w.iter().zip(m.dequantize().iter()) {
+            assert_relative_eq!(v, dv, epsilon = 0.05);
+        }
+
+        let out = m.matmul(&x, n_batch)?;
+        assert_eq!(out.len(), n_batch * rows);
+        for b in 0..n_batch {
+            for r in 0..rows {
+                let xb = &x[b * cols..(b + 1) * cols];
+                let wr = &w[r * cols..(r + 1) * cols];
+                let want = wr.iter().zip(xb.iter()).map(|(a, b)| a * b).sum::<f32>();
+                let bound = 0.02 * wr.iter().zip(xb).map(|(a, b)| (a * b).abs()).sum::<f32>();
+                assert!((out[b * rows + r] - want).abs() <= bound.max(1e-3));
+            }
+        }
+
+        assert!(m.matmul(&x[1..], n_batch).is_err());
+        assert!(Int8Matrix::quantize(&w, rows + 1, cols).is_err());
+        Ok(())
+    }
+}
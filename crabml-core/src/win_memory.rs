@@ -0,0 +1,241 @@
+//! windows specific helpers for loading the model files. memmap2 maps the file with
+//! CreateFileMapping + MapViewOfFile, but the pages are faulted in 4KB by 4KB on the first
+//! touch, which makes loading several times slower than linux with `madvise(WILLNEED)`.
+
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::Read;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+type Handle = *mut c_void;
+type Bool = i32;
+
+#[repr(C)]
+struct Win32MemoryRangeEntry {
+    virtual_address: *mut c_void,
+    number_of_bytes: usize,
+}
+
+#[repr(C)]
+struct Luid {
+    low_part: u32,
+    high_part: i32,
+}
+
+#[repr(C)]
+struct TokenPrivileges {
+    privilege_count: u32,
+    luid: Luid,
+    attributes: u32,
+}
+
+const MEM_COMMIT: u32 = 0x1000;
+const MEM_RESERVE: u32 = 0x2000;
+const MEM_RELEASE: u32 = 0x8000;
+const MEM_LARGE_PAGES: u32 = 0x2000_0000;
+const PAGE_READWRITE: u32 = 0x04;
+const TOKEN_ADJUST_PRIVILEGES: u32 = 0x0020;
+const TOKEN_QUERY: u32 = 0x0008;
+const SE_PRIVILEGE_ENABLED: u32 = 0x0002;
+const ERROR_NOT_ALL_ASSIGNED: u32 = 1300;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentProcess() -> Handle;
+    fn PrefetchVirtualMemory(
+        process: Handle,
+        number_of_entries: usize,
+        entries: *const Win32MemoryRangeEntry,
+        flags: u32,
+    ) -> Bool;
+    fn VirtualLock(address: *mut c_void, size: usize) -> Bool;
+    fn VirtualAlloc(
+        address: *mut c_void,
+        size: usize,
+        alloc_type: u32,
+        protect: u32,
+    ) -> *mut c_void;
+    fn VirtualFree(address: *mut c_void, size: usize, free_type: u32) -> Bool;
+    fn GetLargePageMinimum() -> usize;
+    fn CloseHandle(handle: Handle) -> Bool;
+    fn GetLastError() -> u32;
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn OpenProcessToken(process: Handle, access: u32, token: *mut Handle) -> Bool;
+    fn LookupPrivilegeValueW(system_name: *const u16, name: *const u16, luid: *mut Luid) -> Bool;
+    fn AdjustTokenPrivileges(
+        token: Handle,
+        disable_all: Bool,
+        new_state: *const TokenPrivileges,
+        buffer_length: u32,
+        previous_state: *mut TokenPrivileges,
+        return_length: *mut u32,
+    ) -> Bool;
+}
+
+fn last_os_error(message: String) -> Error {
+    Error::new(ErrorKind::IOError, message).with_cause(std::io::Error::last_os_error())
+}
+
+/// ask the kernel to read the mapped pages in with large sequential IOs, it's the windows
+/// equivalent of `madvise(WILLNEED)`.
+pub fn prefetch(buf: &[u8]) -> Result<()> {
+    let entry = Win32MemoryRangeEntry {
+        virtual_address: buf.as_ptr() as *mut c_void,
+        number_of_bytes: buf.len(),
+    };
+    let ok = unsafe { PrefetchVirtualMemory(GetCurrentProcess(), 1, &entry, 0) };
+    if ok == 0 {
+        return Err(last_os_error(
+            "failed to prefetch the mapped file".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// lock the pages in the working set, it's the windows equivalent of `mlock`.
+pub fn lock(buf: &[u8]) -> Result<()> {
+    let ok = unsafe { VirtualLock(buf.as_ptr() as *mut c_void, buf.len()) };
+    if ok == 0 {
+        return Err(last_os_error("failed to lock the mapped file".to_string()));
+    }
+    Ok(())
+}
+
+/// a buffer allocated in large pages (usually 2MB), which reduces the TLB misses on the
+/// weights. large pages can not back a file mapping, so the file is read into the buffer
+/// instead. the process needs the `SeLockMemoryPrivilege` ("Lock pages in memory") granted.
+pub struct LargePageBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the buffer is never mutated after the file is read into it.
+unsafe impl Send for LargePageBuf {}
+unsafe impl Sync for LargePageBuf {}
+
+impl LargePageBuf {
+    pub fn read_file(path: &str) -> Result<Self> {
+        let mut file = File::open(path).map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to open the file: {}", path),
+            )
+            .with_cause(err)
+        })?;
+        let len = file
+            .metadata()
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::IOError,
+                    format!("failed to stat the file: {}", path),
+                )
+                .with_cause(err)
+            })?
+            .len() as usize;
+
+        enable_lock_memory_privilege()?;
+        let page_size = unsafe { GetLargePageMinimum() };
+        if page_size == 0 {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                "large pages are not supported on this system",
+            ));
+        }
+        let alloc_len = len.div_ceil(page_size) * page_size;
+        let ptr = unsafe {
+            VirtualAlloc(
+                std::ptr::null_mut(),
+                alloc_len,
+                MEM_RESERVE | MEM_COMMIT | MEM_LARGE_PAGES,
+                PAGE_READWRITE,
+            )
+        } as *mut u8;
+        if ptr.is_null() {
+            return Err(last_os_error(format!(
+                "failed to allocate {} bytes in large pages for {}",
+                alloc_len, path
+            )));
+        }
+
+        let buf = Self { ptr, len };
+        let dst = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+        file.read_exact(dst).map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to read the file: {}", path),
+            )
+            .with_cause(err)
+        })?;
+        Ok(buf)
+    }
+}
+
+impl std::ops::Deref for LargePageBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for LargePageBuf {
+    fn drop(&mut self) {
+        unsafe {
+            VirtualFree(self.ptr as *mut c_void, 0, MEM_RELEASE);
+        }
+    }
+}
+
+fn enable_lock_memory_privilege() -> Result<()> {
+    let name = "SeLockMemoryPrivilege"
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+    unsafe {
+        let mut token: Handle = std::ptr::null_mut();
+        if OpenProcessToken(
+            GetCurrentProcess(),
+            TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY,
+            &mut token,
+        ) == 0
+        {
+            return Err(last_os_error(
+                "failed to open the process token".to_string(),
+            ));
+        }
+
+        let mut privileges = TokenPrivileges {
+            privilege_count: 1,
+            luid: Luid {
+                low_part: 0,
+                high_part: 0,
+            },
+            attributes: SE_PRIVILEGE_ENABLED,
+        };
+        let ok = LookupPrivilegeValueW(std::ptr::null(), name.as_ptr(), &mut privileges.luid) != 0
+            && AdjustTokenPrivileges(
+                token,
+                0,
+                &privileges,
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ) != 0;
+        // AdjustTokenPrivileges succeeds even if the privilege is not granted to the user
+        let not_assigned = GetLastError() == ERROR_NOT_ALL_ASSIGNED;
+        CloseHandle(token);
+        if !ok || not_assigned {
+            return Err(Error::new(
+                ErrorKind::IOError,
+                "failed to enable SeLockMemoryPrivilege, please grant \"Lock pages in memory\" to the user",
+            ));
+        }
+    }
+    Ok(())
+}
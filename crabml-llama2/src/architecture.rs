@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFMetadata;
+use crabml::gguf::KEY_GENERAL_ARCHITECTURE;
+use crabml::tensor::RopeMode;
+
+use crate::llama2::Activation;
+use crate::model::ModelArchitecture;
+
+/// what an architecture changes in the forward of the llama decoder, the runner forwards all
+/// the architectures in the same layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForwardSpec {
+    /// how the dims of a head are paired on the rotary embedding.
+    pub rope_mode: RopeMode,
+
+    /// the activation of the gate projection in the FFN.
+    pub activation: Activation,
+
+    /// scale the token embedding with sqrt(embedding_dim), like Gemma.
+    pub scale_embedding: bool,
+}
+
+/// a model architecture, registered in the `ArchitectureRegistry` under the
+/// `general.architecture` of its GGUF files. it tells the loader how to read the model, and
+/// the runner how to forward it.
+pub trait Architecture: Send + Sync {
+    /// the `general.architecture` of the files, the hyperparameters are keyed under it too,
+    /// like `llama.block_count`.
+    fn name(&self) -> &'static str;
+
+    /// the family of the model, which picks the chat template of the models which have none.
+    fn family(&self) -> ModelArchitecture;
+
+    fn forward_spec(&self) -> ForwardSpec;
+
+    /// whether to prepend the BOS token to the prompts if the file does not say it, None keeps
+    /// the default of the tokenizer.
+    fn add_bos(&self) -> Option<bool> {
+        None
+    }
+}
+
+pub type ArchitectureRef = Arc<dyn Architecture>;
+
+pub struct LlamaArchitecture;
+
+impl Architecture for LlamaArchitecture {
+    fn name(&self) -> &'static str {
+        "llama"
+    }
+
+    fn family(&self) -> ModelArchitecture {
+        ModelArchitecture::Llama
+    }
+
+    fn forward_spec(&self) -> ForwardSpec {
+        ForwardSpec {
+            rope_mode: RopeMode::Llama,
+            activation: Activation::SiLU,
+            scale_embedding: false,
+        }
+    }
+}
+
+/// Gemma adds a 1.0 to the weights of the rmsnorms too, it's done on converting the files
+/// into GGUF, so the weights are taken as is. Gemma2 is not covered, it soft-caps the attention
+/// and the final logits, and takes a sliding window and the post-norms in the layers.
+pub struct GemmaArchitecture;
+
+impl Architecture for GemmaArchitecture {
+    fn name(&self) -> &'static str {
+        "gemma"
+    }
+
+    fn family(&self) -> ModelArchitecture {
+        ModelArchitecture::Gemma
+    }
+
+    fn forward_spec(&self) -> ForwardSpec {
+        ForwardSpec {
+            rope_mode: RopeMode::Neox,
+            activation: Activation::GeLU,
+            scale_embedding: true,
+        }
+    }
+}
+
+/// Qwen2 is llama with the biases on the qkv projections, which are loaded if the file has
+/// them, and the neox rope. it prepends no BOS token, like llama.cpp.
+pub struct Qwen2Architecture;
+
+impl Architecture for Qwen2Architecture {
+    fn name(&self) -> &'static str {
+        "qwen2"
+    }
+
+    fn family(&self) -> ModelArchitecture {
+        ModelArchitecture::Qwen2
+    }
+
+    fn forward_spec(&self) -> ForwardSpec {
+        ForwardSpec {
+            rope_mode: RopeMode::Neox,
+            activation: Activation::SiLU,
+            scale_embedding: false,
+        }
+    }
+
+    fn add_bos(&self) -> Option<bool> {
+        Some(false)
+    }
+}
+
+/// the architectures the models can be loaded in, keyed by their `general.architecture`.
+/// llama, gemma and qwen2 are registered by default, the others can be added without
+/// touching the loader, like a llama-shaped architecture under another name.
+#[derive(Clone)]
+pub struct ArchitectureRegistry {
+    architectures: Vec<ArchitectureRef>,
+}
+
+impl Default for ArchitectureRegistry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl ArchitectureRegistry {
+    pub fn empty() -> Self {
+        Self {
+            architectures: vec![],
+        }
+    }
+
+    pub fn builtin() -> Self {
+        Self::empty()
+            .with_architecture(Arc::new(LlamaArchitecture))
+            .with_architecture(Arc::new(GemmaArchitecture))
+            .with_architecture(Arc::new(Qwen2Architecture))
+    }
+
+    /// register the architecture, it replaces the one registered under the same name.
+    pub fn register(&mut self, architecture: ArchitectureRef) {
+        self.architectures
+            .retain(|a| a.name() != architecture.name());
+        self.architectures.push(architecture);
+    }
+
+    pub fn with_architecture(mut self, architecture: ArchitectureRef) -> Self {
+        self.register(architecture);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<ArchitectureRef> {
+        self.architectures
+            .iter()
+            .find(|a| a.name() == name)
+            .cloned()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.architectures.iter().map(|a| a.name()).collect()
+    }
+
+    /// the architecture of a model by the `general.architecture` of its metadata.
+    pub fn resolve(&self, metadata: &GGUFMetadata) -> Result<ArchitectureRef> {
+        let name = metadata
+            .get_string(KEY_GENERAL_ARCHITECTURE)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::ModelError,
+                    format!("missing {}", KEY_GENERAL_ARCHITECTURE),
+                )
+            })?;
+        self.get(name).ok_or_else(|| {
+            Error::new(
+                ErrorKind::ModelError,
+                format!(
+                    "unsupported architecture {}, expect one of {}",
+                    name,
+                    self.names().join(", ")
+                ),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a llama-shaped architecture under another name
+    struct TinyArchitecture;
+
+    impl Architecture for TinyArchitecture {
+        fn name(&self) -> &'static str {
+            "tiny"
+        }
+
+        fn family(&self) -> ModelArchitecture {
+            ModelArchitecture::Llama
+        }
+
+        fn forward_spec(&self) -> ForwardSpec {
+            LlamaArchitecture.forward_spec()
+        }
+    }
+
+    #[test]
+    fn test_architecture_registry() {
+        let registry = ArchitectureRegistry::default();
+        assert_eq!(registry.names(), vec!["llama", "gemma", "qwen2"]);
+        let gemma = registry.get("gemma").unwrap();
+        assert_eq!(gemma.family(), ModelArchitecture::Gemma);
+        assert!(gemma.forward_spec().scale_embedding);
+        assert_eq!(registry.get("qwen2").unwrap().add_bos(), Some(false));
+        assert!(registry.get("tiny").is_none());
+
+        let registry = registry.with_architecture(Arc::new(TinyArchitecture));
+        let tiny = registry.get("tiny").unwrap();
+        assert_eq!(tiny.forward_spec().rope_mode, RopeMode::Llama);
+
+        // the same name replaces the registered one
+        let registry = registry.with_architecture(Arc::new(TinyArchitecture));
+        assert_eq!(registry.names(), vec!["llama", "gemma", "qwen2", "tiny"]);
+    }
+}
@@ -0,0 +1,142 @@
+/// which attention weights to record. the full maps take n_layers * n_heads * seq^2 floats, so
+/// they're subsampled by the layers and the heads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttentionMapOptions {
+    layer_stride: usize,
+    head_stride: usize,
+}
+
+impl Default for AttentionMapOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AttentionMapOptions {
+    /// record all the heads of all the layers.
+    pub fn new() -> Self {
+        Self {
+            layer_stride: 1,
+            head_stride: 1,
+        }
+    }
+
+    /// only record the layers 0, n, 2n, ...
+    pub fn with_layer_stride(mut self, stride: usize) -> Self {
+        self.layer_stride = stride.max(1);
+        self
+    }
+
+    /// only record the heads 0, n, 2n, ... of the recorded layers.
+    pub fn with_head_stride(mut self, stride: usize) -> Self {
+        self.head_stride = stride.max(1);
+        self
+    }
+}
+
+/// the attention weights of the token at pos on a head of a layer, over all the tokens in the
+/// KV cache. the weights of the masked tokens are zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttentionRow {
+    pub layer: usize,
+    pub head: usize,
+    pub pos: usize,
+    pub weights: Vec<f32>,
+}
+
+/// the attention weights recorded on the forwards of a runner, in the order of the layers of
+/// each forward. it's the input of the attention visualization tools.
+#[derive(Debug, Clone, Default)]
+pub struct AttentionMaps {
+    options: AttentionMapOptions,
+    tokens: Vec<usize>,
+    rows: Vec<AttentionRow>,
+}
+
+impl AttentionMaps {
+    pub fn new(options: AttentionMapOptions) -> Self {
+        Self {
+            options,
+            tokens: vec![],
+            rows: vec![],
+        }
+    }
+
+    pub fn options(&self) -> &AttentionMapOptions {
+        &self.options
+    }
+
+    /// the forwarded tokens, the token at pos is tokens[pos] if the maps are recorded from an
+    /// empty KV cache.
+    pub fn tokens(&self) -> &[usize] {
+        &self.tokens
+    }
+
+    pub fn rows(&self) -> &[AttentionRow] {
+        &self.rows
+    }
+
+    /// the rows of a head of a layer, in the order of the positions.
+    pub fn head_rows(&self, layer: usize, head: usize) -> impl Iterator<Item = &AttentionRow> {
+        self.rows
+            .iter()
+            .filter(move |row| row.layer == layer && row.head == head)
+    }
+
+    /// drop the recorded rows, like the ones of the prompt.
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.rows.clear();
+    }
+
+    pub(crate) fn records_layer(&self, layer: usize) -> bool {
+        layer % self.options.layer_stride == 0
+    }
+
+    pub(crate) fn record_tokens(&mut self, tokens: &[usize]) {
+        self.tokens.extend_from_slice(tokens);
+    }
+
+    // attn is the softmax-ed scores in (n_heads, n_batch, seq), the batch is the last n_batch
+    // tokens of the seq
+    pub(crate) fn record(&mut self, layer: usize, attn: &[f32], n_heads: usize, n_batch: usize) {
+        let seq = attn.len() / (n_heads * n_batch);
+        for head in (0..n_heads).step_by(self.options.head_stride) {
+            for i in 0..n_batch {
+                let offset = (head * n_batch + i) * seq;
+                self.rows.push(AttentionRow {
+                    layer,
+                    head,
+                    pos: seq - n_batch + i,
+                    weights: attn[offset..offset + seq].to_vec(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attention_maps_record() {
+        let options = AttentionMapOptions::new()
+            .with_layer_stride(2)
+            .with_head_stride(2);
+        let mut maps = AttentionMaps::new(options);
+        assert!(maps.records_layer(0));
+        assert!(!maps.records_layer(1));
+
+        // 3 heads, a batch of 2 tokens after a cached token
+        let attn = (0..18).map(|i| i as f32).collect::<Vec<_>>();
+        maps.record(2, &attn, 3, 2);
+        assert_eq!(maps.rows().len(), 4);
+        let rows = maps.head_rows(2, 2).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].pos, 1);
+        assert_eq!(rows[0].weights, vec![12.0, 13.0, 14.0]);
+        assert_eq!(rows[1].pos, 2);
+        assert_eq!(maps.head_rows(2, 1).count(), 0);
+    }
+}
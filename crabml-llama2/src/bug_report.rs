@@ -0,0 +1,244 @@
+use std::any::Any;
+
+use crabml::error::Error;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFile;
+use crabml::tensor::Tensor;
+
+/// what the runner captures for the failure reports, see `Llama2Runner::set_failure_reports`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FailureReportOptions {
+    dump_len: usize,
+}
+
+impl FailureReportOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// keep the first values of the inputs of each op in the report, none by default.
+    pub fn with_dump_len(mut self, dump_len: usize) -> Self {
+        self.dump_len = dump_len;
+        self
+    }
+}
+
+/// an input of the op which failed, as it was on entering the op.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorSummary {
+    pub name: String,
+    pub shape: Vec<usize>,
+
+    /// None on the tensors only named by the error.
+    pub dtype: Option<GGMLType>,
+
+    /// the FNV-1a hash over the bits of the values in f32, None if the tensor failed to export.
+    pub checksum: Option<u64>,
+
+    /// the first values of the tensor, empty unless `with_dump_len` is set.
+    pub values: Vec<f32>,
+}
+
+/// where and on what a forward failed, captured to make the issues filed by the users
+/// actionable. it's serialized by the callers, like the `--bug-report` of the cli.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailureReport {
+    pub error: String,
+
+    /// the op which failed, the innermost one of the error if it has one, like `matmul`,
+    /// otherwise the step of the forward the runner was on, like `attention`.
+    pub op: String,
+
+    /// the layer the op was on, None on the embedding and the output head.
+    pub layer: Option<usize>,
+
+    pub pos: usize,
+    pub tokens: Vec<usize>,
+
+    /// the tensors the op was given, and the tensors named by the error.
+    pub inputs: Vec<TensorSummary>,
+
+    /// the type of the tensors the runner is on, like `CpuTensor`.
+    pub backend: String,
+
+    /// whether the op panicked instead of returning the error.
+    pub panicked: bool,
+}
+
+// keeps the step of the forward the runner is on with its inputs, updated on entering each
+// step while the failure reports are enabled
+#[derive(Debug, Clone)]
+pub(crate) struct OpTracer {
+    options: FailureReportOptions,
+    op: &'static str,
+    layer: Option<usize>,
+    inputs: Vec<TensorSummary>,
+}
+
+impl OpTracer {
+    pub fn new(options: FailureReportOptions) -> Self {
+        Self {
+            options,
+            op: "forward",
+            layer: None,
+            inputs: vec![],
+        }
+    }
+
+    pub fn enter<T: Tensor>(
+        &mut self,
+        op: &'static str,
+        layer: Option<usize>,
+        inputs: &[(&str, &T)],
+    ) {
+        self.op = op;
+        self.layer = layer;
+        self.inputs.clear();
+        for (name, tensor) in inputs {
+            let summary = summarize(name, *tensor, self.options.dump_len);
+            self.inputs.push(summary);
+        }
+    }
+
+    pub fn report<T: Tensor>(
+        &self,
+        err: &Error,
+        tokens: &[usize],
+        pos: usize,
+        panicked: bool,
+    ) -> FailureReport {
+        let mut inputs = self.inputs.clone();
+        // the shapes named by the error, like the weight of a failed matmul
+        for (name, shape) in &err.tensors {
+            inputs.push(TensorSummary {
+                name: name.clone(),
+                shape: shape.clone(),
+                dtype: None,
+                checksum: None,
+                values: vec![],
+            });
+        }
+        FailureReport {
+            error: err.to_string(),
+            op: err.op.clone().unwrap_or_else(|| self.op.to_string()),
+            layer: self.layer,
+            pos,
+            tokens: tokens.to_vec(),
+            inputs,
+            backend: backend_name::<T>(),
+            panicked,
+        }
+    }
+}
+
+/// the message of a panic caught with catch_unwind, the panics carry a &str or a String.
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// a hash identifying a model file, over its metadata, the names, the shapes and the types of
+/// its tensors, and the head and the tail of each tensor's data. it's cheap to take on a
+/// model of many GBs, unlike hashing the whole file.
+pub fn model_hash(gf: &GGUFFile) -> String {
+    let mut hash = FNV_OFFSET;
+    let metadata = gf.metadata().as_hashmap();
+    let mut keys = metadata.keys().collect::<Vec<_>>();
+    keys.sort();
+    for key in keys {
+        hash = fnv1a(hash, key.as_bytes());
+        hash = fnv1a(hash, format!("{:?}", metadata[key]).as_bytes());
+    }
+    for info in gf.tensor_infos() {
+        hash = fnv1a(hash, info.name().as_bytes());
+        hash = fnv1a(
+            hash,
+            format!("{:?}{}", info.dimensions(), info.typ()).as_bytes(),
+        );
+        let data = info.data();
+        let n = data.len().min(64);
+        hash = fnv1a(hash, &data[..n]);
+        hash = fnv1a(hash, &data[data.len() - n..]);
+    }
+    format!("{:016x}", hash)
+}
+
+fn summarize<T: Tensor>(name: &str, tensor: &T, dump_len: usize) -> TensorSummary {
+    let mut buf = vec![0.0; tensor.shape().iter().product()];
+    let exported = tensor.export(&mut buf).is_ok();
+    let checksum = exported.then(|| {
+        buf.iter().fold(FNV_OFFSET, |hash, v| {
+            fnv1a(hash, &v.to_bits().to_le_bytes())
+        })
+    });
+    buf.truncate(if exported { dump_len } else { 0 });
+    TensorSummary {
+        name: name.to_string(),
+        shape: tensor.shape().to_vec(),
+        dtype: Some(tensor.dtype()),
+        checksum,
+        values: buf,
+    }
+}
+
+// the name of the tensor type without its module path, like CpuTensor
+fn backend_name<T: Tensor>() -> String {
+    let name = std::any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name).to_string()
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensor;
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::error::ErrorKind;
+    use crabml::error::Result;
+    use crabml::error::ResultExt;
+
+    use super::*;
+
+    #[test]
+    fn test_op_tracer_report() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let x = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3], device.clone())?;
+        let mut tracer = OpTracer::new(FailureReportOptions::new().with_dump_len(4));
+        tracer.enter("ffn", Some(1), &[("x", &x)]);
+
+        let err = Err::<(), _>(Error::new(ErrorKind::TensorError, "bad shape"))
+            .with_op("matmul")
+            .with_tensor("blk.1.ffn_up.weight", &[64, 32])
+            .unwrap_err();
+        let report = tracer.report::<CpuTensor>(&err, &[7], 3, false);
+        assert_eq!(report.op, "matmul");
+        assert_eq!(report.layer, Some(1));
+        assert_eq!(report.backend, "CpuTensor");
+        assert_eq!(report.inputs.len(), 2);
+        assert_eq!(report.inputs[0].shape, vec![2, 3]);
+        assert_eq!(report.inputs[0].values, vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(report.inputs[1].name, "blk.1.ffn_up.weight");
+        assert_eq!(report.inputs[1].checksum, None);
+
+        // the same values hash the same, the others do not
+        let same = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[3, 2], device.clone())?;
+        let other = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 7.0], &[2, 3], device)?;
+        tracer.enter("ffn", Some(1), &[("same", &same), ("other", &other)]);
+        let checksums = tracer.inputs.iter().map(|t| t.checksum).collect::<Vec<_>>();
+        assert_eq!(checksums[0], report.inputs[0].checksum);
+        assert_ne!(checksums[1], report.inputs[0].checksum);
+        Ok(())
+    }
+}
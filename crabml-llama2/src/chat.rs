@@ -1,5 +1,8 @@
+use crabml::error::Error;
+use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::tensor::Tensor;
+use crabml::tokenizer::Tokenizer;
 
 use crate::llama2::Llama2Runner;
 use crate::model::ModelArchitecture;
@@ -18,9 +21,7 @@ impl<'a, T: Tensor> Llama2Chat<'a, T> {
         prompt: impl Into<String>,
         system_prompt: Option<String>,
     ) -> Result<Self> {
-        let model_name = &runner.conf().model_name;
-        let model_arch = runner.conf().architecture;
-        let chat_template = ChatTemplate::heuristic_guess(model_name, model_arch, "")?;
+        let chat_template = ChatTemplate::for_runner(runner)?;
         Ok(Self {
             inner: runner,
             prompt: prompt.into(),
@@ -46,6 +47,22 @@ impl<'a, T: Tensor> Llama2Chat<'a, T> {
         Ok(chat_iter)
     }
 
+    /// put a recorded turn with its reply into the KV cache without generating, like on
+    /// loading a saved conversation.
+    pub fn restore(&mut self, reply: &str) -> Result<()> {
+        let templated_prompt =
+            self.chat_template
+                .apply(&self.prompt, self.system_prompt.as_deref(), true);
+        let bos = self.inner.kv_cache_len() == 0;
+        self.inner.prefill(&templated_prompt, bos, false)?;
+        if !reply.is_empty() {
+            self.inner.prefill(reply, false, false)?;
+        }
+        self.inner
+            .prefill(self.chat_template.stop_mark(), false, false)?;
+        Ok(())
+    }
+
     /// the reply might ended with <eos>, but not <end_of_turn>, so we need to append the <end_of_turn>
     pub fn finish(&mut self) -> Result<()> {
         if !self.stats.has_stop_mark {
@@ -184,15 +201,103 @@ impl MarkMatcher {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+}
+
+/// a message of a conversation. the system prompt can only be the first message, and the
+/// user and the assistant take turns after it like on `Llama2Chat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+
+    /// split the system prompt off the turns of a conversation, and check the user and the
+    /// assistant take turns after it starting with the user. a system message which is not
+    /// the first is rejected.
+    pub fn split_turns(messages: &[ChatMessage]) -> Result<(Option<&str>, &[ChatMessage])> {
+        let (system_prompt, turns) = match messages.split_first() {
+            Some((first, rest)) if first.role == ChatRole::System => {
+                (Some(first.content.as_str()), rest)
+            }
+            _ => (None, messages),
+        };
+        if system_prompt.is_some() && turns.is_empty() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the system prompt is applied with the first user message, but got none",
+            ));
+        }
+        let offset = messages.len() - turns.len();
+        for (i, message) in turns.iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                ChatRole::User
+            } else {
+                ChatRole::Assistant
+            };
+            if message.role != expected {
+                return Err(Error::new(
+                    ErrorKind::BadInput,
+                    format!(
+                        "the message {} is from {:?}, but {:?} is expected on its turn",
+                        offset + i,
+                        message.role,
+                        expected
+                    ),
+                ));
+            }
+        }
+        Ok((system_prompt, turns))
+    }
+}
+
+/// the prompt tokens of a conversation and the room left in the context, see
+/// `ChatTemplate::token_budget`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatTokenBudget {
+    /// the tokens of each message. the system prompt is templated into the first user
+    /// message, so it's counted there and the system message itself takes 0.
+    pub message_tokens: Vec<usize>,
+    pub prompt_tokens: usize,
+    pub context_limit: usize,
+    /// the tokens left for generating after the prompt, 0 if the prompt does not fit.
+    pub remaining: usize,
+}
+
+impl ChatTokenBudget {
+    pub fn fits(&self) -> bool {
+        self.prompt_tokens <= self.context_limit
+    }
+}
+
 /// buildin the commonly used chat templates inside the code.
 /// TODO: support customized template, it might need some template engine.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ChatTemplate {
     Llama2,
     Gemma,
+    ChatML,
 }
 
 impl ChatTemplate {
+    /// the template of the model of the runner, which is applied by `Llama2Chat`.
+    pub fn for_runner<T: Tensor>(runner: &Llama2Runner<T>) -> Result<Self> {
+        let conf = runner.conf();
+        Self::heuristic_guess(&conf.model_name, conf.architecture, "")
+    }
+
     /// GGUF may contains a metadata called tokenizer.chat_template (maybe in a jinja format),
     /// we'd not take the chat_template directly but use a heuristic to guess the common ones.
     fn heuristic_guess(
@@ -202,6 +307,8 @@ impl ChatTemplate {
     ) -> Result<Self> {
         if model_name.contains("gemma") || model_arch == ModelArchitecture::Gemma {
             Ok(ChatTemplate::Gemma)
+        } else if model_arch == ModelArchitecture::Qwen2 {
+            Ok(ChatTemplate::ChatML)
         } else if model_name.contains("llama2") {
             Ok(ChatTemplate::Llama2)
         } else {
@@ -214,6 +321,7 @@ impl ChatTemplate {
         match self {
             ChatTemplate::Llama2 => "[/INST]",
             ChatTemplate::Gemma => "<end_of_turn>",
+            ChatTemplate::ChatML => "<|im_end|>",
         }
     }
 
@@ -249,8 +357,73 @@ impl ChatTemplate {
                     system_prompt, prompt, assistant_prefix
                 )
             }
+            ChatTemplate::ChatML => {
+                let system_prompt = system_prompt
+                    .map(|s| format!("<|im_start|>system\n{}<|im_end|>\n", s))
+                    .unwrap_or("".to_string());
+                let assistant_prefix = match append_assistant_prefix {
+                    true => "<|im_start|>assistant\n",
+                    false => "",
+                };
+                format!(
+                    "{}<|im_start|>user\n{}<|im_end|>\n{}",
+                    system_prompt, prompt, assistant_prefix
+                )
+            }
         }
     }
+
+    /// count the prompt tokens of the messages as they're put into an empty KV cache by
+    /// `Llama2Chat`: each user message is templated and tokenized on its own, and each reply
+    /// is followed by the stop mark like on `restore`. the counts are exact as long as the
+    /// history is restored from the text, a generated reply may be tokenized differently
+    /// from the sampled tokens. if the last message is from the user, the remaining tokens
+    /// are the room for its reply.
+    pub fn token_budget(
+        &self,
+        tokenizer: &Tokenizer,
+        messages: &[ChatMessage],
+        context_limit: usize,
+    ) -> Result<ChatTokenBudget> {
+        let (system_prompt, turns) = ChatMessage::split_turns(messages)?;
+
+        let options = *tokenizer.options();
+        let mut message_tokens = Vec::with_capacity(messages.len());
+        if system_prompt.is_some() {
+            message_tokens.push(0);
+        }
+        for (i, message) in turns.iter().enumerate() {
+            let n_tokens = match message.role {
+                ChatRole::User => {
+                    let bos = i == 0;
+                    let system_prompt = if bos { system_prompt } else { None };
+                    let prompt = self.apply(&message.content, system_prompt, true);
+                    let tokens = tokenizer.encode(
+                        &prompt,
+                        bos && options.add_bos,
+                        bos && options.add_eos,
+                    )?;
+                    tokens.len()
+                }
+                _ => {
+                    let reply = match message.content.is_empty() {
+                        true => 0,
+                        false => tokenizer.encode(&message.content, false, false)?.len(),
+                    };
+                    reply + tokenizer.encode(self.stop_mark(), false, false)?.len()
+                }
+            };
+            message_tokens.push(n_tokens);
+        }
+
+        let prompt_tokens = message_tokens.iter().sum::<usize>();
+        Ok(ChatTokenBudget {
+            message_tokens,
+            prompt_tokens,
+            context_limit,
+            remaining: context_limit.saturating_sub(prompt_tokens),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -258,6 +431,9 @@ mod tests {
     use crabml::error::Result;
     use crabml::gguf::GGUFFileLoader;
 
+    use crate::chat::ChatMessage;
+    use crate::chat::ChatRole;
+    use crate::chat::ChatTemplate;
     use crate::chat::Llama2Chat;
     use crate::llama2::Llama2Runner;
     use crate::model::CpuLlama2ModelLoader;
@@ -278,4 +454,76 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_token_budget() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+
+        let template = ChatTemplate::for_runner(&runner)?;
+        let messages = vec![
+            ChatMessage::new(ChatRole::System, "be brief"),
+            ChatMessage::new(ChatRole::User, "tell me a story"),
+            ChatMessage::new(ChatRole::Assistant, "once upon a time"),
+            ChatMessage::new(ChatRole::User, "and then?"),
+        ];
+        let budget = template.token_budget(runner.tokenizer(), &messages, 200)?;
+        assert_eq!(budget.message_tokens.len(), 4);
+        assert_eq!(budget.message_tokens[0], 0);
+        assert_eq!(
+            budget.prompt_tokens,
+            budget.message_tokens.iter().sum::<usize>()
+        );
+        assert_eq!(budget.remaining, 200 - budget.prompt_tokens);
+        assert!(budget.fits());
+
+        // the counts are the tokens put into the KV cache on restoring the history
+        let mut chat = Llama2Chat::new(&mut runner, "tell me a story", Some("be brief".into()))?;
+        chat.restore("once upon a time")?;
+        let n_history = budget.message_tokens[..3].iter().sum::<usize>();
+        assert_eq!(runner.kv_cache_len(), n_history);
+
+        let budget = template.token_budget(runner.tokenizer(), &messages, n_history)?;
+        assert_eq!(budget.remaining, 0);
+        assert!(!budget.fits());
+
+        // the user and the assistant take turns after the system prompt
+        let tokenizer = runner.tokenizer();
+        assert!(
+            template
+                .token_budget(tokenizer, &messages[..1], 200)
+                .is_err()
+        );
+        assert!(
+            template
+                .token_budget(tokenizer, &messages[2..], 200)
+                .is_err()
+        );
+        let twice = [messages[1].clone(), messages[3].clone()];
+        assert!(template.token_budget(tokenizer, &twice, 200).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_turns() -> Result<()> {
+        let messages = vec![
+            ChatMessage::new(ChatRole::System, "be brief"),
+            ChatMessage::new(ChatRole::User, "tell me a story"),
+            ChatMessage::new(ChatRole::Assistant, "once upon a time"),
+            ChatMessage::new(ChatRole::User, "and then?"),
+        ];
+        let (system_prompt, turns) = ChatMessage::split_turns(&messages)?;
+        assert_eq!(system_prompt, Some("be brief"));
+        assert_eq!(turns, &messages[1..]);
+
+        // two user messages in a row, or a system message in the middle
+        let twice = [messages[1].clone(), messages[3].clone()];
+        assert!(ChatMessage::split_turns(&twice).is_err());
+        let misplaced = [messages[1].clone(), messages[0].clone()];
+        assert!(ChatMessage::split_turns(&misplaced).is_err());
+        assert!(ChatMessage::split_turns(&messages[2..]).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,57 @@
+// the logits of the models in an ensemble are not comparable, each model has its own offset and
+// scale, so they are mixed as the log-probabilities. it makes a product of experts: the fused
+// distribution is proportional to the product of p_i ^ weight_i of the models.
+
+/// replace the logits with their log-probabilities scaled by the weight.
+pub fn scale_log_softmax(logits: &mut [f32], weight: f32) {
+    let lse = log_sum_exp(logits);
+    logits.iter_mut().for_each(|x| *x = (*x - lse) * weight);
+}
+
+/// add the log-probabilities of the logits of another model scaled by the weight.
+pub fn add_scaled_log_softmax(fused: &mut [f32], logits: &[f32], weight: f32) {
+    let lse = log_sum_exp(logits);
+    fused
+        .iter_mut()
+        .zip(logits.iter())
+        .for_each(|(f, x)| *f += (x - lse) * weight);
+}
+
+fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return max;
+    }
+    max + logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_fuse_logits() {
+        let a = vec![1.0, 2.0, 3.0];
+        // the same distribution as a with another offset
+        let b = vec![11.0, 12.0, 13.0];
+        let c = vec![3.0, 2.0, 1.0];
+
+        let mut fused = a.clone();
+        scale_log_softmax(&mut fused, 0.5);
+        add_scaled_log_softmax(&mut fused, &b, 0.5);
+        let mut want = a.clone();
+        scale_log_softmax(&mut want, 1.0);
+        for (f, w) in fused.iter().zip(want.iter()) {
+            assert_relative_eq!(*f, *w, epsilon = 1e-5);
+        }
+
+        // the opposite models cancel out into a uniform distribution
+        let mut fused = a.clone();
+        scale_log_softmax(&mut fused, 1.0);
+        add_scaled_log_softmax(&mut fused, &c, 1.0);
+        assert_relative_eq!(fused[0], fused[1], epsilon = 1e-5);
+        assert_relative_eq!(fused[1], fused[2], epsilon = 1e-5);
+    }
+}
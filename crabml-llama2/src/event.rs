@@ -0,0 +1,177 @@
+use std::fmt::Display;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// the ID of a request, it's accepted from the client (like the X-Request-Id header) or
+/// generated, so a slow call can be correlated with what happened inside. the events of the
+/// request follow the RequestStarted event carrying it, instead of each carrying it, and the
+/// frontends prefix the log lines of the request with it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// generate a process-wide unique ID, made of the start time of the process and a counter.
+    pub fn generate() -> Self {
+        static PROCESS_TAG: OnceLock<u64> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let tag = PROCESS_TAG.get_or_init(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0)
+        });
+        let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self(format!("req-{:x}-{}", tag, seq))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for RequestId {
+    fn from(id: String) -> Self {
+        Self(id)
+    }
+}
+
+impl From<&str> for RequestId {
+    fn from(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// why the generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// the model generated the end of sentence token.
+    Eos,
+
+    /// the generation reached the max steps.
+    MaxSteps,
+
+    /// the generation reached the end of the context window before the max steps.
+    ContextLength,
+
+    /// the generation was stuck in a cycle of tokens, and aborted by the loop watchdog.
+    Loop,
+
+    /// the generated text hit a stop string of the stopping criteria.
+    StopString,
+
+    /// a custom stopping criteria stopped the generation.
+    Criteria,
+}
+
+impl StopReason {
+    /// the finish_reason in the style of the OpenAI API, the end of the context window is told
+    /// apart from the max steps as "length:context".
+    pub fn finish_reason(&self) -> &'static str {
+        match self {
+            StopReason::Eos => "stop",
+            StopReason::MaxSteps => "length",
+            StopReason::ContextLength => "length:context",
+            StopReason::Loop => "loop",
+            StopReason::StopString | StopReason::Criteria => "stop",
+        }
+    }
+}
+
+/// the typed events emitted during the lifecycle of a generation. the frontends can subscribe
+/// these events to build the per token timing or logprob coloring, instead of parsing the
+/// generated text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GenerationEvent {
+    /// the runner starts serving the request, the events after it until the next
+    /// RequestStarted belong to this request. only emitted if the runner has a request ID.
+    RequestStarted { request_id: RequestId },
+
+    /// the prompt has been fed into the model.
+    PromptProcessed { n_tokens: usize, t_ms: f64 },
+
+    /// a new token is sampled. `logprob` is the log probability of the token in the raw logits
+    /// (before applying the temperature), `t_ms` is the wall time spent on this token.
+    TokenGenerated {
+        id: usize,
+        text: String,
+        logprob: f32,
+        t_ms: f64,
+    },
+
+    /// the generation is stopped.
+    StopHit { reason: StopReason },
+
+    /// the generation failed, no more events will be emitted for this generation.
+    Error { message: String },
+
+    /// the offloaded forward failed on the other device (like the GPU), the session moves back
+    /// to the device of the runner and goes on.
+    BackendFallback { message: String },
+
+    /// a draft/verify step of the speculative decoding is done, `n_accepted` of the
+    /// `n_drafted` tokens proposed by the draft model are accepted by the target model. the
+    /// TokenGenerated events of the accepted tokens follow.
+    SpeculativeStep {
+        n_drafted: usize,
+        n_accepted: usize,
+        draft_t_ms: f64,
+        verify_t_ms: f64,
+    },
+}
+
+pub type GenerationEventSender = Sender<GenerationEvent>;
+
+/// a generated token yielded by `TokenStream::next_token`, the texts are borrowed from the text
+/// buffer of the runner, which is reused over the tokens instead of allocating a string per
+/// token. they're valid until the next token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenEvent<'a> {
+    pub id: usize,
+
+    /// the text of the token, it's empty if the token ends in the middle of a character.
+    pub piece: &'a str,
+
+    /// the text generated so far, ending with the piece.
+    pub text: &'a str,
+}
+
+/// the log probability of the token `id` over the logits, computed with the log-sum-exp trick.
+pub fn logprob(logits: &[f32], id: usize) -> f32 {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    let sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>();
+    logits[id] - max - sum.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id() {
+        let a = RequestId::generate();
+        let b = RequestId::generate();
+        assert_ne!(a, b);
+        assert!(a.as_str().starts_with("req-"));
+        assert_eq!(RequestId::from("abc").to_string(), "abc");
+    }
+
+    #[test]
+    fn test_logprob() {
+        let logits = vec![1.0, 2.0, 3.0];
+        let probs = (0..3)
+            .map(|i| logprob(&logits, i).exp())
+            .collect::<Vec<_>>();
+        approx::assert_relative_eq!(probs.iter().sum::<f32>(), 1.0, epsilon = 1e-5);
+        approx::assert_relative_eq!(probs[2], 0.66524096, epsilon = 1e-5);
+    }
+}
@@ -0,0 +1,336 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFMetadataArray;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf_edit::GGUFEditor;
+
+use crate::model::ModelArchitecture;
+use crate::sampler::SplitMix64;
+
+// the pieces after the byte tokens, the longer pieces get the higher scores to be merged first
+const FIXTURE_PIECES: &[&str] = &[
+    "▁", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r",
+    "s", "t", "u", "v", "w", "x", "y", "z", ".", ",", "▁a", "▁c", "▁t", "he", "in", "ed", "▁ca",
+    "▁the", "▁cat", "ing",
+];
+
+/// the shape of a tiny llama model with random weights. the model is useless on generating
+/// texts, but it runs through all the code paths of a llama model in milliseconds, so the tests
+/// and the CI do not need to download a real model.
+#[derive(Debug, Clone, Copy)]
+pub struct FixtureModelOptions {
+    n_layers: usize,
+    embedding_dim: usize,
+    hidden_dim: usize,
+    n_heads: usize,
+    n_kv_heads: usize,
+    context_length: usize,
+    architecture: ModelArchitecture,
+    n_experts: usize,
+    n_experts_used: usize,
+    seed: u64,
+}
+
+impl Default for FixtureModelOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FixtureModelOptions {
+    /// 2 layers of 32 dims in 4 heads, and the grouped query attention on 2 KV heads.
+    pub fn new() -> Self {
+        Self {
+            n_layers: 2,
+            embedding_dim: 32,
+            hidden_dim: 64,
+            n_heads: 4,
+            n_kv_heads: 2,
+            context_length: 128,
+            architecture: ModelArchitecture::Llama,
+            n_experts: 0,
+            n_experts_used: 0,
+            seed: 0,
+        }
+    }
+
+    pub fn with_n_layers(mut self, n_layers: usize) -> Self {
+        self.n_layers = n_layers;
+        self
+    }
+
+    /// the embedding dim should be divisible by the number of the heads.
+    pub fn with_dims(mut self, embedding_dim: usize, hidden_dim: usize) -> Self {
+        self.embedding_dim = embedding_dim;
+        self.hidden_dim = hidden_dim;
+        self
+    }
+
+    pub fn with_heads(mut self, n_heads: usize, n_kv_heads: usize) -> Self {
+        self.n_heads = n_heads;
+        self.n_kv_heads = n_kv_heads;
+        self
+    }
+
+    pub fn with_context_length(mut self, context_length: usize) -> Self {
+        self.context_length = context_length;
+        self
+    }
+
+    /// the keys are written under the prefix of the architecture, Qwen2 takes the biases on the
+    /// qkv projections and Gemma ties the output head to the token embedding. the other
+    /// differences, like the norms of Gemma, are not covered.
+    pub fn with_architecture(mut self, architecture: ModelArchitecture) -> Self {
+        self.architecture = architecture;
+        self
+    }
+
+    /// a mixture-of-experts FFN like Mixtral, with the experts stacked into the
+    /// `ffn_*_exps` tensors. 0 experts keeps the FFN dense.
+    pub fn with_experts(mut self, n_experts: usize, n_experts_used: usize) -> Self {
+        self.n_experts = n_experts;
+        self.n_experts_used = n_experts_used;
+        self
+    }
+
+    /// the same seed always generates the same file.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// generate a tiny llama model in f32 into the GGUF file, or of the other architecture of the
+/// options. the vocab has the <unk>, <s>, </s>,
+/// the 256 byte tokens and a few pieces of english.
+pub fn write_fixture_model(path: impl AsRef<Path>, options: &FixtureModelOptions) -> Result<()> {
+    let dim = options.embedding_dim;
+    let kv_dim = dim / options.n_heads * options.n_kv_heads;
+    let hidden_dim = options.hidden_dim;
+
+    let mut vocab = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+    vocab.extend((0..=255).map(|b| format!("<0x{:02X}>", b)));
+    vocab.extend(FIXTURE_PIECES.iter().map(|s| s.to_string()));
+    let vocab_size = vocab.len();
+    let scores = vocab
+        .iter()
+        .enumerate()
+        .map(|(i, piece)| match i {
+            0..=258 => 0.0,
+            _ => piece.chars().count() as f32,
+        })
+        .collect::<Vec<_>>();
+    let token_types = (0..vocab_size)
+        .map(|i| match i {
+            0 => 2,
+            1 | 2 => 3,
+            3..=258 => 6,
+            _ => 1,
+        })
+        .collect::<Vec<i32>>();
+
+    // (name, dimensions in the GGUF order), the norms are all ones
+    let mut tensors = vec![("token_embd.weight".to_string(), vec![dim, vocab_size])];
+    for layer in 0..options.n_layers {
+        for (name, dims) in [
+            ("attn_norm", vec![dim]),
+            ("attn_q", vec![dim, dim]),
+            ("attn_k", vec![dim, kv_dim]),
+            ("attn_v", vec![dim, kv_dim]),
+            ("attn_output", vec![dim, dim]),
+            ("ffn_norm", vec![dim]),
+        ] {
+            tensors.push((format!("blk.{}.{}.weight", layer, name), dims));
+        }
+        if options.n_experts == 0 {
+            for (name, dims) in [
+                ("ffn_gate", vec![dim, hidden_dim]),
+                ("ffn_up", vec![dim, hidden_dim]),
+                ("ffn_down", vec![hidden_dim, dim]),
+            ] {
+                tensors.push((format!("blk.{}.{}.weight", layer, name), dims));
+            }
+        } else {
+            let n_experts = options.n_experts;
+            for (name, dims) in [
+                ("ffn_gate_inp", vec![dim, n_experts]),
+                ("ffn_gate_exps", vec![dim, hidden_dim, n_experts]),
+                ("ffn_up_exps", vec![dim, hidden_dim, n_experts]),
+                ("ffn_down_exps", vec![hidden_dim, dim, n_experts]),
+            ] {
+                tensors.push((format!("blk.{}.{}.weight", layer, name), dims));
+            }
+        }
+        if options.architecture == ModelArchitecture::Qwen2 {
+            for (name, dims) in [
+                ("attn_q", vec![dim]),
+                ("attn_k", vec![kv_dim]),
+                ("attn_v", vec![kv_dim]),
+            ] {
+                tensors.push((format!("blk.{}.{}.bias", layer, name), dims));
+            }
+        }
+    }
+    tensors.push(("output_norm.weight".to_string(), vec![dim]));
+    if options.architecture != ModelArchitecture::Gemma {
+        tensors.push(("output.weight".to_string(), vec![dim, vocab_size]));
+    }
+
+    let mut rng = SplitMix64(options.seed);
+    let tensor_data = tensors
+        .iter()
+        .map(|(name, dims)| {
+            let n_elems = dims.iter().product::<usize>();
+            let scale = 1.0 / (dims[0] as f32).sqrt();
+            (0..n_elems)
+                .flat_map(|_| {
+                    let v = if name.ends_with("norm.weight") {
+                        1.0
+                    } else {
+                        (rng.next_f32() * 2.0 - 1.0) * scale
+                    };
+                    v.to_le_bytes()
+                })
+                .collect::<Vec<u8>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut editor = GGUFEditor::empty();
+    let vocab_refs = vocab.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let u32_value = |v: usize| GGUFMetadataValue::U32(v as u32);
+    let arch = match options.architecture {
+        ModelArchitecture::Llama => "llama",
+        ModelArchitecture::Gemma => "gemma",
+        ModelArchitecture::Qwen2 => "qwen2",
+    };
+    for (key, value) in [
+        ("context_length", u32_value(options.context_length)),
+        ("embedding_length", u32_value(dim)),
+        ("block_count", u32_value(options.n_layers)),
+        ("feed_forward_length", u32_value(hidden_dim)),
+        ("rope.dimension_count", u32_value(dim / options.n_heads)),
+        ("attention.head_count", u32_value(options.n_heads)),
+        ("attention.head_count_kv", u32_value(options.n_kv_heads)),
+        (
+            "attention.layer_norm_rms_epsilon",
+            GGUFMetadataValue::F32(1e-5),
+        ),
+    ] {
+        editor.set(&format!("{}.{}", arch, key), value)?;
+    }
+    if options.n_experts > 0 {
+        editor.set(
+            &format!("{}.expert_count", arch),
+            u32_value(options.n_experts),
+        )?;
+        editor.set(
+            &format!("{}.expert_used_count", arch),
+            u32_value(options.n_experts_used),
+        )?;
+    }
+    for (key, value) in [
+        ("general.architecture", GGUFMetadataValue::String(arch)),
+        ("general.name", GGUFMetadataValue::String("crabml-fixture")),
+        ("tokenizer.ggml.model", GGUFMetadataValue::String("llama")),
+        (
+            "tokenizer.ggml.tokens",
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(vocab_refs)),
+        ),
+        (
+            "tokenizer.ggml.scores",
+            GGUFMetadataValue::Array(GGUFMetadataArray::F32Array(&scores)),
+        ),
+        (
+            "tokenizer.ggml.token_type",
+            GGUFMetadataValue::Array(GGUFMetadataArray::I32Array(&token_types)),
+        ),
+        ("tokenizer.ggml.unknown_token_id", u32_value(0)),
+        ("tokenizer.ggml.bos_token_id", u32_value(1)),
+        ("tokenizer.ggml.eos_token_id", u32_value(2)),
+    ] {
+        editor.set(key, value)?;
+    }
+    for ((name, dims), data) in tensors.iter().zip(tensor_data.iter()) {
+        editor.add_tensor(name, dims, GGMLType::F32, data)?;
+    }
+    editor.write_to_file(path)
+}
+
+/// a directory of its own under the temp dir for the fixture files of a test, so the tests
+/// running in parallel or in another process never write the same file. it's removed with the
+/// files on dropping, even if the test fails.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let dir_name = format!("crabml-test-{}-{}-{}", name, std::process::id(), id);
+        let path = std::env::temp_dir().join(dir_name);
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    /// the path of a file in the directory.
+    pub fn join(&self, file_name: &str) -> PathBuf {
+        self.path.join(file_name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // the files may still be mapped by the loaders on some platforms, it's left behind then
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::llama2::Llama2Runner;
+    use crate::model::CpuLlama2ModelLoader;
+
+    #[test]
+    fn test_fixture_model() -> Result<()> {
+        let dir = TempDir::new("fixture")?;
+        let path = dir.join("fixture.gguf");
+        let path2 = dir.join("fixture-2.gguf");
+        write_fixture_model(&path, &FixtureModelOptions::new())?;
+        write_fixture_model(&path2, &FixtureModelOptions::new())?;
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            std::fs::read(&path2).unwrap()
+        );
+
+        let gl = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let model = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        assert_eq!(model.conf.n_layers, 2);
+        assert_eq!(model.conf.n_kv_heads, 2);
+        assert_eq!(model.conf.vocab_size, 3 + 256 + FIXTURE_PIECES.len());
+
+        // the generation is deterministic on the greedy sampling
+        let mut outputs = vec![];
+        for _ in 0..2 {
+            let mut runner = Llama2Runner::new(&model, 128, false)?;
+            let output = runner
+                .prefill_and_generate("the cat played", 8)?
+                .collect::<Result<Vec<String>>>()?
+                .join("");
+            outputs.push(output);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::sync::Arc;
+
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tokenizer::TokenID;
+use crabml::tokenizer::Tokenizer;
+
+const DEAD_STATE: u32 = u32::MAX;
+
+/// a deterministic automaton over the bytes of the output, it's the compiled form of a grammar
+/// or a JSON schema.
+#[derive(Debug, Clone)]
+pub struct ByteDfa {
+    transitions: Vec<[u32; 256]>,
+    accepting: Vec<bool>,
+    start: u32,
+}
+
+impl ByteDfa {
+    /// a DFA with n_states states without any transition, the state 0 is the start state.
+    pub fn new(n_states: usize) -> Self {
+        Self {
+            transitions: vec![[DEAD_STATE; 256]; n_states],
+            accepting: vec![false; n_states],
+            start: 0,
+        }
+    }
+
+    /// accepts exactly one of the strings, like the values of an enum in the JSON schema.
+    pub fn from_choices(choices: &[&str]) -> Self {
+        let mut dfa = Self::new(1);
+        for choice in choices {
+            let mut state = dfa.start;
+            for b in choice.bytes() {
+                state = match dfa.next_state(state, b) {
+                    Some(next) => next,
+                    None => {
+                        let next = dfa.add_state();
+                        dfa.add_transition(state, b..=b, next);
+                        next
+                    }
+                };
+            }
+            dfa.set_accepting(state, true);
+        }
+        dfa
+    }
+
+    pub fn add_state(&mut self) -> u32 {
+        self.transitions.push([DEAD_STATE; 256]);
+        self.accepting.push(false);
+        (self.transitions.len() - 1) as u32
+    }
+
+    pub fn add_transition(&mut self, from: u32, bytes: std::ops::RangeInclusive<u8>, to: u32) {
+        for b in bytes {
+            self.transitions[from as usize][b as usize] = to;
+        }
+    }
+
+    pub fn set_accepting(&mut self, state: u32, accepting: bool) {
+        self.accepting[state as usize] = accepting;
+    }
+
+    pub fn n_states(&self) -> usize {
+        self.transitions.len()
+    }
+
+    pub fn next_state(&self, state: u32, byte: u8) -> Option<u32> {
+        match self.transitions[state as usize][byte as usize] {
+            DEAD_STATE => None,
+            next => Some(next),
+        }
+    }
+
+    /// walk the bytes from the state, returns None if any byte is rejected.
+    pub fn walk(&self, state: u32, bytes: &[u8]) -> Option<u32> {
+        bytes
+            .iter()
+            .try_fold(state, |state, b| self.next_state(state, *b))
+    }
+
+    pub fn is_accepting(&self, state: u32) -> bool {
+        self.accepting[state as usize]
+    }
+}
+
+/// the token level automaton of a grammar: the allowed tokens and the next states of each
+/// state of the byte DFA. it's compiled once on loading, instead of re-scanning the whole
+/// vocabulary against the grammar on every decoding step, which dominates the latency of the
+/// constrained decoding on the large vocabularies. it's shared by the sessions with an Arc.
+#[derive(Debug)]
+pub struct CompiledGrammar {
+    dfa: ByteDfa,
+    // (state) => [(token, next_state)], sorted by the token id
+    token_transitions: Vec<Vec<(TokenID, u32)>>,
+    eos_token: TokenID,
+}
+
+impl CompiledGrammar {
+    /// intersect the token trie with the DFA on every state, the tokens sharing a rejected
+    /// prefix are pruned together. the decoding step only takes O(allowed tokens).
+    pub fn compile(dfa: ByteDfa, tokenizer: &Tokenizer) -> Result<Arc<Self>> {
+        if dfa.n_states() == 0 {
+            return Err((ErrorKind::BadInput, "the grammar has no state").into());
+        }
+
+        let eos_token = tokenizer.eos_token();
+        let trie = tokenizer.trie();
+        let token_transitions = (0..dfa.n_states() as u32)
+            .map(|state| {
+                let mut transitions = vec![];
+                trie.walk(
+                    state,
+                    |state, b| dfa.next_state(state, b),
+                    |id, next| {
+                        if id != eos_token {
+                            transitions.push((id, next));
+                        }
+                    },
+                );
+                // the trie is walked in depth first order, advance() binary searches the ids
+                transitions.sort_unstable();
+                transitions
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Arc::new(Self {
+            dfa,
+            token_transitions,
+            eos_token,
+        }))
+    }
+
+    pub fn start(&self) -> GrammarState {
+        GrammarState {
+            state: self.dfa.start,
+        }
+    }
+
+    /// the tokens allowed on the state, the EOS is allowed once the output is accepted.
+    pub fn allowed_tokens(&self, state: &GrammarState) -> impl Iterator<Item = TokenID> + '_ {
+        let eos = self.dfa.is_accepting(state.state).then_some(self.eos_token);
+        self.token_transitions[state.state as usize]
+            .iter()
+            .map(|(id, _)| *id)
+            .chain(eos)
+    }
+
+    /// set the logits of the disallowed tokens to -inf, so they're never sampled.
+    pub fn mask_logits(&self, state: &GrammarState, logits: &mut [f32]) {
+        let mut allowed = vec![false; logits.len()];
+        for id in self.allowed_tokens(state) {
+            allowed[id] = true;
+        }
+        for (logit, allowed) in logits.iter_mut().zip(allowed) {
+            if !allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// move to the next state on the sampled token.
+    pub fn advance(&self, state: &mut GrammarState, token: TokenID) -> Result<()> {
+        if token == self.eos_token && self.dfa.is_accepting(state.state) {
+            return Ok(());
+        }
+        let transitions = &self.token_transitions[state.state as usize];
+        match transitions.binary_search_by_key(&token, |(id, _)| *id) {
+            Ok(i) => {
+                state.state = transitions[i].1;
+                Ok(())
+            }
+            Err(_) => Err((
+                ErrorKind::BadInput,
+                format!("token {} is not allowed by the grammar", token),
+            )
+                .into()),
+        }
+    }
+
+    pub fn is_accepting(&self, state: &GrammarState) -> bool {
+        self.dfa.is_accepting(state.state)
+    }
+}
+
+/// the position of a session in the grammar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrammarState {
+    state: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = [
+            "<unk>", "<s>", "</s>", "y", "ye", "yes", "n", "no", "s", "x",
+        ];
+        Tokenizer::new_gpt2(vocab.iter().map(|s| s.to_string()).collect(), vec![], 1, 2)
+    }
+
+    #[test]
+    fn test_byte_dfa() {
+        let dfa = ByteDfa::from_choices(&["yes", "no"]);
+        let end = dfa.walk(0, b"yes").unwrap();
+        assert!(dfa.is_accepting(end));
+        assert!(!dfa.is_accepting(dfa.walk(0, b"ye").unwrap()));
+        assert!(dfa.walk(0, b"yex").is_none());
+    }
+
+    #[test]
+    fn test_compiled_grammar() -> Result<()> {
+        let tokenizer = tokenizer();
+        let grammar = CompiledGrammar::compile(ByteDfa::from_choices(&["yes", "no"]), &tokenizer)?;
+
+        let mut state = grammar.start();
+        let allowed = grammar.allowed_tokens(&state).collect::<Vec<_>>();
+        assert_eq!(allowed, vec![3, 4, 5, 6, 7]);
+
+        let mut logits = vec![1.0; 10];
+        grammar.mask_logits(&state, &mut logits);
+        assert_eq!(logits[9], f32::NEG_INFINITY);
+        assert_eq!(logits[5], 1.0);
+
+        grammar.advance(&mut state, 4)?; // "ye"
+        assert_eq!(grammar.allowed_tokens(&state).collect::<Vec<_>>(), vec![8]);
+        assert!(grammar.advance(&mut state, 9).is_err());
+        grammar.advance(&mut state, 8)?; // "s"
+        assert!(grammar.is_accepting(&state));
+        assert_eq!(grammar.allowed_tokens(&state).collect::<Vec<_>>(), vec![2]);
+        Ok(())
+    }
+}
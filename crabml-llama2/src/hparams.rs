@@ -0,0 +1,373 @@
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFMetadata;
+use crabml::gguf::KEY_ATTENTION_HEAD_COUNT;
+use crabml::gguf::KEY_ATTENTION_HEAD_COUNT_KV;
+use crabml::gguf::KEY_ATTENTION_LAYERNORM_RMS_EPS;
+use crabml::gguf::KEY_BLOCK_COUNT;
+use crabml::gguf::KEY_CONTEXT_LENGTH;
+use crabml::gguf::KEY_EMBEDDING_LENGTH;
+use crabml::gguf::KEY_EXPERT_COUNT;
+use crabml::gguf::KEY_EXPERT_USED_COUNT;
+use crabml::gguf::KEY_FEED_FORWARD_LENGTH;
+use crabml::gguf::KEY_GENERAL_NAME;
+use crabml::gguf::KEY_ROPE_DIMENSION_COUNT;
+use crabml::gguf::KEY_ROPE_FREQ_BASE;
+use crabml::gguf::KEY_ROPE_SCALE_LINEAR;
+use crabml::gguf::KEY_TOKENIZER_LIST;
+
+use crate::architecture::ArchitectureRegistry;
+use crate::architecture::ForwardSpec;
+use crate::model::Llama2Config;
+use crate::model::ModelArchitecture;
+
+/// the rotary position embedding of a model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RopeConfig {
+    /// the number of the dims rotated in each head, all of them if not given
+    pub dim: Option<usize>,
+    /// the base of the rope frequencies, 10000 on llama2 and 1000000 on CodeLlama
+    pub freq_base: f32,
+    /// the scale of the positions, the inverse of `{arch}.rope.scale_linear` on the models
+    /// with a linearly extended context, 1 on the others
+    pub freq_scale: f32,
+}
+
+/// the hyperparameters of a model, read from the GGUF metadata under the prefix of its
+/// architecture, like `llama.block_count`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelHParams {
+    pub architecture: ModelArchitecture,
+    pub forward: ForwardSpec,
+    pub model_name: String,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub embedding_dim: usize,
+    pub ffn_dim: usize,
+    pub vocab_size: usize,
+    pub context_length: usize,
+    pub rms_norm_eps: f32,
+    pub rope: RopeConfig,
+    /// the experts of the mixture-of-experts FFN like Mixtral, and the experts each token is
+    /// routed to. both are 0 on the dense models
+    pub n_experts: usize,
+    pub n_experts_used: usize,
+}
+
+impl ModelHParams {
+    /// read the hyperparameters and check the values against each other. every missing or
+    /// invalid key is reported in a single error, instead of failing on the first one.
+    pub fn from_gguf(metadata: &GGUFMetadata) -> Result<Self> {
+        Self::from_gguf_with_registry(metadata, &ArchitectureRegistry::default())
+    }
+
+    /// like from_gguf, on the architectures of the registry.
+    pub fn from_gguf_with_registry(
+        metadata: &GGUFMetadata,
+        registry: &ArchitectureRegistry,
+    ) -> Result<Self> {
+        let arch = registry.resolve(metadata)?;
+        let prefix = arch.name();
+
+        let mut r = HParamsReader {
+            metadata,
+            prefix,
+            problems: vec![],
+        };
+        let n_layers = r.required_u32(KEY_BLOCK_COUNT);
+        let n_heads = r.required_u32(KEY_ATTENTION_HEAD_COUNT);
+        // the models without the grouped query attention may leave it out
+        let n_kv_heads = r.optional_u32(KEY_ATTENTION_HEAD_COUNT_KV).or(n_heads);
+        let embedding_dim = r.required_u32(KEY_EMBEDDING_LENGTH);
+        let ffn_dim = r.required_u32(KEY_FEED_FORWARD_LENGTH);
+        let context_length = r.required_u32(KEY_CONTEXT_LENGTH);
+        let rms_norm_eps = r.required_f32(KEY_ATTENTION_LAYERNORM_RMS_EPS);
+        let rope_dim = r.optional_u32(KEY_ROPE_DIMENSION_COUNT);
+        let rope_freq_base = r.optional_f32(KEY_ROPE_FREQ_BASE).unwrap_or(10000.0);
+        let rope_scale_linear = r.optional_f32(KEY_ROPE_SCALE_LINEAR).unwrap_or(1.0);
+        let n_experts = r.optional_u32(KEY_EXPERT_COUNT);
+        let n_experts_used = match n_experts {
+            Some(_) => r.required_u32(KEY_EXPERT_USED_COUNT),
+            None => None,
+        };
+        let vocab_size = match metadata.get_string_array(KEY_TOKENIZER_LIST) {
+            Some(tokens) if !tokens.is_empty() => Some(tokens.len()),
+            Some(_) => r.problem(format!("{} is empty", KEY_TOKENIZER_LIST)),
+            None => r.problem(format!("missing {}", KEY_TOKENIZER_LIST)),
+        };
+        let model_name = metadata
+            .get_string(KEY_GENERAL_NAME)
+            .unwrap_or("")
+            .to_string();
+
+        // the checks between the values, skipped on the values already reported
+        if let (Some(n_heads), Some(n_kv_heads)) = (n_heads, n_kv_heads) {
+            if n_kv_heads > n_heads || n_heads % n_kv_heads != 0 {
+                r.report(format!(
+                    "{}.attention.head_count {} is not a multiple of \
+                     {}.attention.head_count_kv {}",
+                    prefix, n_heads, prefix, n_kv_heads
+                ));
+            }
+        }
+        if let (Some(embedding_dim), Some(n_heads)) = (embedding_dim, n_heads) {
+            if embedding_dim % n_heads != 0 {
+                r.report(format!(
+                    "{}.embedding_length {} is not divisible by {}.attention.head_count {}",
+                    prefix, embedding_dim, prefix, n_heads
+                ));
+            } else if let Some(rope_dim) = rope_dim {
+                let head_size = embedding_dim / n_heads;
+                if rope_dim > head_size || rope_dim % 2 != 0 {
+                    r.report(format!(
+                        "{}.rope.dimension_count {} should be even and within the head size {}",
+                        prefix, rope_dim, head_size
+                    ));
+                }
+            }
+        }
+        if n_experts == Some(0) {
+            r.report(format!("{}.expert_count 0 is not positive", prefix));
+        }
+        if n_experts_used == Some(0) {
+            r.report(format!("{}.expert_used_count 0 is not positive", prefix));
+        }
+        if let (Some(n_experts), Some(n_experts_used)) = (n_experts, n_experts_used) {
+            if n_experts_used > n_experts {
+                r.report(format!(
+                    "{}.expert_used_count {} is more than {}.expert_count {}",
+                    prefix, n_experts_used, prefix, n_experts
+                ));
+            }
+        }
+        if rope_freq_base <= 0.0 || !rope_freq_base.is_finite() {
+            r.report(format!(
+                "{}.rope.freq_base {} is not positive",
+                prefix, rope_freq_base
+            ));
+        }
+        if rope_scale_linear <= 0.0 || !rope_scale_linear.is_finite() {
+            r.report(format!(
+                "{}.rope.scale_linear {} is not positive",
+                prefix, rope_scale_linear
+            ));
+        }
+
+        if !r.problems.is_empty() {
+            return Err(Error::new(
+                ErrorKind::ModelError,
+                format!(
+                    "invalid hyperparameters of the {} model: {}",
+                    prefix,
+                    r.problems.join("; ")
+                ),
+            ));
+        }
+        // all the values are present once there's no problem
+        Ok(Self {
+            architecture: arch.family(),
+            forward: arch.forward_spec(),
+            model_name,
+            n_layers: n_layers.unwrap(),
+            n_heads: n_heads.unwrap(),
+            n_kv_heads: n_kv_heads.unwrap(),
+            embedding_dim: embedding_dim.unwrap(),
+            ffn_dim: ffn_dim.unwrap(),
+            vocab_size: vocab_size.unwrap(),
+            context_length: context_length.unwrap(),
+            rms_norm_eps: rms_norm_eps.unwrap(),
+            rope: RopeConfig {
+                dim: rope_dim,
+                freq_base: rope_freq_base,
+                freq_scale: 1.0 / rope_scale_linear,
+            },
+            n_experts: n_experts.unwrap_or(0),
+            n_experts_used: n_experts_used.unwrap_or(0),
+        })
+    }
+}
+
+impl From<ModelHParams> for Llama2Config {
+    fn from(hp: ModelHParams) -> Self {
+        Self {
+            architecture: hp.architecture,
+            forward: hp.forward,
+            model_name: hp.model_name,
+            embedding_dim: hp.embedding_dim,
+            hidden_dim: hp.ffn_dim,
+            n_layers: hp.n_layers,
+            n_heads: hp.n_heads,
+            n_kv_heads: hp.n_kv_heads,
+            vocab_size: hp.vocab_size,
+            seq_len: hp.context_length,
+            rms_norm_eps: hp.rms_norm_eps,
+            rope_dim: hp.rope.dim,
+            rope_theta: hp.rope.freq_base,
+            rope_freq_scale: hp.rope.freq_scale,
+            n_experts: hp.n_experts,
+            n_experts_used: hp.n_experts_used,
+        }
+    }
+}
+
+// reads the keys of `crabml::gguf` under the prefix of the architecture, the values which
+// are missing, in a wrong type or out of range are collected into the problems and read as None.
+struct HParamsReader<'m, 'a> {
+    metadata: &'m GGUFMetadata<'a>,
+    prefix: &'static str,
+    problems: Vec<String>,
+}
+
+impl HParamsReader<'_, '_> {
+    fn report(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    // report the problem of the value, which is read as None
+    fn problem<T>(&mut self, problem: String) -> Option<T> {
+        self.report(problem);
+        None
+    }
+
+    fn key(&self, key: &str) -> String {
+        key.replace("{arch}", self.prefix)
+    }
+
+    fn has_key(&self, key: &str) -> bool {
+        self.metadata.as_hashmap().contains_key(&self.key(key))
+    }
+
+    fn read<T>(&mut self, key: &str, get: impl Fn(&GGUFMetadata, &str) -> Option<T>) -> Option<T> {
+        let key = self.key(key);
+        match get(self.metadata, &key) {
+            Some(v) => Some(v),
+            None if self.metadata.as_hashmap().contains_key(&key) => {
+                self.problem(format!("{} has an unexpected type", key))
+            }
+            None => None,
+        }
+    }
+
+    fn optional_u32(&mut self, key: &str) -> Option<usize> {
+        match self.read(key, |m, k| m.get_u32(k))? {
+            0 => self.problem(format!("{} is 0", self.key(key))),
+            v => Some(v as usize),
+        }
+    }
+
+    fn required_u32(&mut self, key: &str) -> Option<usize> {
+        if !self.has_key(key) {
+            return self.problem(format!("missing {}", self.key(key)));
+        }
+        self.optional_u32(key)
+    }
+
+    fn optional_f32(&mut self, key: &str) -> Option<f32> {
+        self.read(key, |m, k| m.get_f32(k))
+    }
+
+    fn required_f32(&mut self, key: &str) -> Option<f32> {
+        if !self.has_key(key) {
+            return self.problem(format!("missing {}", self.key(key)));
+        }
+        match self.optional_f32(key)? {
+            v if v > 0.0 && v.is_finite() => Some(v),
+            v => self.problem(format!("{} {} is not positive", self.key(key), v)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "gguf-edit"))]
+mod tests {
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf_edit::GGUFEditor;
+
+    use super::*;
+    use crate::fixture::write_fixture_model;
+    use crate::fixture::FixtureModelOptions;
+    use crate::fixture::TempDir;
+
+    #[test]
+    fn test_model_hparams() -> Result<()> {
+        let dir = TempDir::new("hparams")?;
+        let path = dir.join("hparams.gguf");
+        let broken_path = dir.join("hparams-broken.gguf");
+        let scaled_path = dir.join("hparams-scaled.gguf");
+        let no_experts_path = dir.join("hparams-no-experts.gguf");
+        write_fixture_model(&path, &FixtureModelOptions::new())?;
+
+        let gl = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let hp = ModelHParams::from_gguf(gf.metadata())?;
+        assert_eq!(hp.architecture, ModelArchitecture::Llama);
+        assert_eq!((hp.n_layers, hp.n_heads, hp.n_kv_heads), (2, 4, 2));
+        assert_eq!(
+            (hp.embedding_dim, hp.ffn_dim, hp.context_length),
+            (32, 64, 128)
+        );
+        assert_eq!(hp.rope, RopeConfig {
+            dim: Some(8),
+            freq_base: 10000.0,
+            freq_scale: 1.0,
+        });
+        assert_eq!((hp.n_experts, hp.n_experts_used), (0, 0));
+
+        // the positions are scaled by the inverse of the linear rope scale
+        let mut editor = GGUFEditor::new(&gf);
+        editor.set("llama.rope.scale_linear", GGUFMetadataValue::F32(4.0))?;
+        editor.write_to_file(&scaled_path)?;
+        let scaled_gl = GGUFFileLoader::new(scaled_path.to_str().unwrap(), false)?;
+        let scaled_gf = scaled_gl.open()?;
+        let hp = ModelHParams::from_gguf(scaled_gf.metadata())?;
+        assert_eq!(hp.rope.freq_scale, 0.25);
+
+        // a missing key, a key in the wrong type and the values not fitting each other are all
+        // reported at once
+        let mut editor = GGUFEditor::new(&gf);
+        editor.remove("llama.block_count")?;
+        editor.set(
+            "llama.attention.layer_norm_rms_epsilon",
+            GGUFMetadataValue::String("1e-5"),
+        )?;
+        editor.set("llama.attention.head_count_kv", GGUFMetadataValue::U32(3))?;
+        editor.set("llama.rope.dimension_count", GGUFMetadataValue::U32(16))?;
+        editor.set("llama.expert_count", GGUFMetadataValue::U32(8))?;
+        editor.set("llama.expert_used_count", GGUFMetadataValue::U32(9))?;
+        editor.set("llama.rope.scale_linear", GGUFMetadataValue::F32(-2.0))?;
+        editor.write_to_file(&broken_path)?;
+
+        let broken_gl = GGUFFileLoader::new(broken_path.to_str().unwrap(), false)?;
+        let broken_gf = broken_gl.open()?;
+        let err = ModelHParams::from_gguf(broken_gf.metadata()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::ModelError);
+        for problem in [
+            "missing llama.block_count",
+            "llama.attention.layer_norm_rms_epsilon has an unexpected type",
+            "llama.attention.head_count 4 is not a multiple of llama.attention.head_count_kv 3",
+            "llama.rope.dimension_count 16 should be even and within the head size 8",
+            "llama.expert_used_count 9 is more than llama.expert_count 8",
+            "llama.rope.scale_linear -2 is not positive",
+        ] {
+            assert!(err.message.contains(problem), "{}", err.message);
+        }
+
+        // a MoE model routes to at least one of its experts
+        let mut editor = GGUFEditor::new(&gf);
+        editor.set("llama.expert_count", GGUFMetadataValue::U32(0))?;
+        editor.set("llama.expert_used_count", GGUFMetadataValue::U32(0))?;
+        editor.write_to_file(&no_experts_path)?;
+        let no_experts_gl = GGUFFileLoader::new(no_experts_path.to_str().unwrap(), false)?;
+        let no_experts_gf = no_experts_gl.open()?;
+        let err = ModelHParams::from_gguf(no_experts_gf.metadata()).unwrap_err();
+        for problem in [
+            "llama.expert_count 0 is not positive",
+            "llama.expert_used_count 0 is not positive",
+        ] {
+            assert!(err.message.contains(problem), "{}", err.message);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use crabml::error::Result;
+use crabml::tokenizer::TokenID;
+use crabml::tokenizer::Tokenizer;
+
+/// the pieces of the FIM special tokens of each model family: (prefix, suffix, middle, eot).
+const FIM_PIECES: [(&str, &str, &str, &str); 3] = [
+    // Qwen-coder
+    (
+        "<|fim_prefix|>",
+        "<|fim_suffix|>",
+        "<|fim_middle|>",
+        "<|endoftext|>",
+    ),
+    // StarCoder
+    (
+        "<fim_prefix>",
+        "<fim_suffix>",
+        "<fim_middle>",
+        "<|endoftext|>",
+    ),
+    // CodeLlama
+    ("▁<PRE>", "▁<SUF>", "▁<MID>", "▁<EOT>"),
+];
+
+/// the special tokens of the fill-in-the-middle prompt of the code models, the model generates
+/// the middle part between the prefix and the suffix, until the EOT token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FimTokens {
+    pub prefix: TokenID,
+    pub suffix: TokenID,
+    pub middle: TokenID,
+    pub eot: Option<TokenID>,
+}
+
+impl FimTokens {
+    /// find the FIM tokens in the vocab, returns None if the model is not trained on FIM.
+    pub fn detect(tokenizer: &Tokenizer) -> Option<Self> {
+        FIM_PIECES.iter().find_map(|(prefix, suffix, middle, eot)| {
+            Some(Self {
+                prefix: tokenizer.piece_to_token(prefix)?,
+                suffix: tokenizer.piece_to_token(suffix)?,
+                middle: tokenizer.piece_to_token(middle)?,
+                eot: tokenizer.piece_to_token(eot),
+            })
+        })
+    }
+
+    /// build the prompt in the PSM (prefix, suffix, middle) order:
+    /// `[BOS] <PRE> prefix <SUF> suffix <MID>`. the suffix is encoded without the dummy prefix
+    /// space, it follows the cursor directly.
+    pub fn build_prompt(
+        &self,
+        tokenizer: &Tokenizer,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<Vec<TokenID>> {
+        let options = *tokenizer.options();
+        let suffix_tokenizer = tokenizer
+            .clone()
+            .with_options(options.with_add_space_prefix(false));
+
+        let mut tokens = vec![];
+        if options.add_bos {
+            tokens.push(tokenizer.bos_token());
+        }
+        tokens.push(self.prefix);
+        tokens.extend(tokenizer.encode(prefix, false, false)?);
+        tokens.push(self.suffix);
+        tokens.extend(suffix_tokenizer.encode(suffix, false, false)?);
+        tokens.push(self.middle);
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fim_prompt() -> Result<()> {
+        let vocab = [
+            "<unk>",
+            "<s>",
+            "<|endoftext|>",
+            "<fim_prefix>",
+            "<fim_suffix>",
+            "<fim_middle>",
+            "a",
+            "b",
+            "c",
+        ];
+        let tokenizer =
+            Tokenizer::new_gpt2(vocab.iter().map(|s| s.to_string()).collect(), vec![], 1, 2);
+        let fim = FimTokens::detect(&tokenizer).unwrap();
+        assert_eq!(fim, FimTokens {
+            prefix: 3,
+            suffix: 4,
+            middle: 5,
+            eot: Some(2),
+        });
+        let tokens = fim.build_prompt(&tokenizer, "ab", "c")?;
+        assert_eq!(tokens, vec![1, 3, 6, 7, 4, 8, 5]);
+        Ok(())
+    }
+}
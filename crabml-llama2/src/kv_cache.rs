@@ -0,0 +1,235 @@
+use std::ops::Range;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::tensor::Tensor;
+
+/// the keys and the values of the tokens of a session. the buffers of each layer are
+/// allocated in (n_kv_heads, capacity, head_dim) once on the first append, appending a token
+/// writes into its slot without reallocating, and the views over the tokens in the cache are
+/// strided tensors in (n_kv_heads, len, head_dim). a cache which is never appended to, like the
+/// one of a runner whose forwards are offloaded to the GPU, takes no memory. neither do the
+/// skipped layers, whose keys and values are cached on another device.
+pub struct KvCache<T: Tensor> {
+    // the views are taken out of the options on attending, and put back after. they're empty
+    // until the first append, and None on the skipped layers
+    keys: Vec<Option<T>>,
+    values: Vec<Option<T>>,
+    skipped: Vec<bool>,
+    n_layers: usize,
+    n_kv_heads: usize,
+    capacity: usize,
+    head_dim: usize,
+    dtype: GGMLType,
+    device: T::Device,
+}
+
+impl<T: Tensor> KvCache<T> {
+    pub fn new(
+        n_layers: usize,
+        n_kv_heads: usize,
+        capacity: usize,
+        head_dim: usize,
+        dtype: GGMLType,
+        device: T::Device,
+    ) -> Self {
+        Self {
+            keys: vec![],
+            values: vec![],
+            skipped: vec![false; n_layers],
+            n_layers,
+            n_kv_heads,
+            capacity,
+            head_dim,
+            dtype,
+            device,
+        }
+    }
+
+    fn alloc(&self) -> Result<Vec<Option<T>>> {
+        let shape = [self.n_kv_heads, self.capacity, self.head_dim];
+        (0..self.n_layers)
+            .map(|l| {
+                if self.skipped[l] {
+                    return Ok(None);
+                }
+                let t = T::alloc(&shape, self.dtype, self.device.clone())?;
+                Ok(Some(t.resize(1, 0)?))
+            })
+            .collect()
+    }
+
+    /// never allocate the buffers of the layers, like the ones forwarded on another device
+    /// which keeps their keys and values. it's called before the first append.
+    pub fn skip_layers(&mut self, layers: Range<usize>) {
+        self.skipped[layers].fill(true);
+    }
+
+    /// whether the buffers are allocated, they're allocated on the first append.
+    pub fn is_allocated(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// the number of the tokens in the cache, 0 if all the layers are skipped.
+    pub fn len(&self) -> usize {
+        let first_layer = self
+            .keys
+            .iter()
+            .zip(self.skipped.iter())
+            .find(|(_, s)| !**s);
+        match first_layer {
+            Some((keys, _)) => keys.as_ref().unwrap().shape()[1],
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the max number of the tokens, the size of the preallocated buffers.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn n_layers(&self) -> usize {
+        self.n_layers
+    }
+
+    pub fn dtype(&self) -> GGMLType {
+        self.dtype
+    }
+
+    /// the keys of the layer in (n_kv_heads, len, head_dim), the cache must be allocated.
+    pub fn keys(&self, layer: usize) -> &T {
+        self.keys[layer].as_ref().unwrap()
+    }
+
+    /// the values of the layer in (n_kv_heads, len, head_dim), the cache must be allocated.
+    pub fn values(&self, layer: usize) -> &T {
+        self.values[layer].as_ref().unwrap()
+    }
+
+    /// append the keys and the values of a batch in (n_kv_heads, n_batch, head_dim) to the
+    /// layer, the layers are appended one by one on forwarding the batch.
+    pub fn append(&mut self, layer: usize, k: &T, v: &T) -> Result<()> {
+        if !self.is_allocated() {
+            self.keys = self.alloc()?;
+            self.values = self.alloc()?;
+        }
+        let n_cached = self.keys(layer).shape()[1];
+        if n_cached + k.shape()[1] > self.capacity {
+            return Err(Error::new(
+                ErrorKind::ContextOverflow,
+                format!(
+                    "the KV cache of {} tokens can not hold {} more",
+                    self.capacity,
+                    k.shape()[1]
+                ),
+            ));
+        }
+        self.keys[layer].as_mut().unwrap().concatenate(k, 1)?;
+        self.values[layer].as_mut().unwrap().concatenate(v, 1)?;
+        Ok(())
+    }
+
+    /// keep the first len tokens, the memory is kept for reuse.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "can not truncate the KV cache of {} tokens to {}",
+                    self.len(),
+                    len
+                ),
+            ));
+        }
+        let skipped = self.skipped.iter().chain(self.skipped.iter());
+        for (cache, _) in self
+            .keys
+            .iter_mut()
+            .chain(self.values.iter_mut())
+            .zip(skipped)
+            .filter(|(_, s)| !**s)
+        {
+            let t = cache.take().unwrap();
+            cache.replace(t.narrow(1, len)?);
+        }
+        Ok(())
+    }
+
+    /// take the keys and the values of the layer out to attend, the ops like transpose take
+    /// the tensors by value. they should be put back with `put` in their original strides.
+    pub fn take(&mut self, layer: usize) -> (T, T) {
+        (
+            self.keys[layer].take().unwrap(),
+            self.values[layer].take().unwrap(),
+        )
+    }
+
+    pub fn put(&mut self, layer: usize, k: T, v: T) {
+        self.keys[layer].replace(k);
+        self.values[layer].replace(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensor;
+    use crabml::backends::cpu::CpuTensorDevice;
+
+    use super::*;
+
+    #[test]
+    fn test_kv_cache() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let mut cache = KvCache::<CpuTensor>::new(2, 2, 4, 3, GGMLType::F32, device.clone());
+        assert!(cache.is_empty());
+        assert!(!cache.is_allocated());
+        assert_eq!(cache.capacity(), 4);
+        cache.truncate(0)?;
+
+        // (n_kv_heads, n_batch, head_dim)
+        let k = CpuTensor::new(
+            (0..12).map(|v| v as f32).collect(),
+            &[2, 2, 3],
+            device.clone(),
+        )?;
+        let v = CpuTensor::new(vec![1.0; 12], &[2, 2, 3], device.clone())?;
+        for layer in 0..2 {
+            cache.append(layer, &k, &v)?;
+        }
+        assert!(cache.is_allocated());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.keys(1).shape(), &[2, 2, 3]);
+        let mut buf = vec![0.0; 12];
+        cache.keys(0).clone().contiguous()?.export(&mut buf)?;
+        assert_eq!(buf, (0..12).map(|v| v as f32).collect::<Vec<_>>());
+
+        // the views are strided over the preallocated buffers
+        assert_eq!(cache.keys(0).strider().strides(), &[12, 3, 1]);
+        let (k0, v0) = cache.take(0);
+        cache.put(0, k0, v0);
+
+        cache.truncate(1)?;
+        assert_eq!(cache.len(), 1);
+        assert!(cache.truncate(2).is_err());
+        for layer in 0..2 {
+            cache.append(layer, &k, &v)?;
+        }
+        let err = cache.append(0, &k, &v).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::ContextOverflow);
+
+        // the skipped layers take no memory, the length is the one of the first kept layer
+        let mut cache = KvCache::<CpuTensor>::new(3, 2, 4, 3, GGMLType::F32, device.clone());
+        cache.skip_layers(0..2);
+        cache.append(2, &k, &v)?;
+        assert_eq!(cache.len(), 2);
+        cache.truncate(1)?;
+        assert_eq!(cache.len(), 1);
+        Ok(())
+    }
+}
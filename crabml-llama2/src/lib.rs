@@ -1,10 +1,34 @@
+pub mod architecture;
+pub mod attention_map;
+pub mod bug_report;
 pub mod chat;
+pub mod ensemble;
+pub mod event;
+#[cfg(feature = "gguf-edit")]
+pub mod fixture;
+pub mod grammar;
+pub mod hparams;
+pub mod infill;
+pub mod kv_cache;
 pub mod llama2;
+pub mod llama_cpp_session;
+pub mod loop_watchdog;
 pub mod model;
+pub mod moe;
+pub mod pipeline;
+pub mod placement;
 pub mod sampler;
+pub mod sparse_ffn;
+pub mod speculative;
+pub mod stopping;
+pub mod ttft;
 
 pub use chat::Llama2Chat;
+pub use event::GenerationEvent;
+pub use event::RequestId;
+pub use hparams::ModelHParams;
 pub use model::CpuLlama2Model;
 pub use model::Llama2Model;
+#[cfg(feature = "wgpu")]
 pub use model::WgpuLlama2Model;
 pub use sampler::Llama2Sampler;
@@ -1,20 +1,70 @@
-use std::rc::Rc;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 use std::vec;
 
+use crabml::backends::cpu::ThreadNumLimitGuard;
+use crabml::embedding::Int8Embedding;
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
+use crabml::progress::ProgressReporterRef;
+use crabml::progress::ProgressStage;
+use crabml::tensor::ops::gelu;
+use crabml::tensor::ops::rms_norm;
+use crabml::tensor::ops::silu;
+use crabml::tensor::ops::softmax;
 use crabml::tensor::RopeMode;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
 use crabml::tokenizer::Tokenizer;
 
+use crate::attention_map::AttentionMapOptions;
+use crate::attention_map::AttentionMaps;
+use crate::bug_report::panic_message;
+use crate::bug_report::FailureReport;
+use crate::bug_report::FailureReportOptions;
+use crate::bug_report::OpTracer;
+use crate::ensemble::add_scaled_log_softmax;
+use crate::ensemble::scale_log_softmax;
+use crate::event::logprob;
+use crate::event::GenerationEvent;
+use crate::event::GenerationEventSender;
+use crate::event::RequestId;
+use crate::event::StopReason;
+use crate::event::TokenEvent;
+use crate::grammar::CompiledGrammar;
+use crate::grammar::GrammarState;
+use crate::infill::FimTokens;
+use crate::kv_cache::KvCache;
+use crate::llama_cpp_session::LlamaCppSession;
+use crate::loop_watchdog::LoopAction;
+use crate::loop_watchdog::LoopWatchdog;
 use crate::model::Llama2Config;
 use crate::model::Llama2Model;
 use crate::model::Llama2Weights;
-use crate::model::ModelArchitecture;
+use crate::moe::predict_next_experts;
+use crate::moe::route_tokens;
+use crate::pipeline::Detokenizer;
+use crate::pipeline::PipelinedOutput;
+use crate::pipeline::SampledToken;
+use crate::pipeline::PIPELINE_DEPTH;
 use crate::sampler::Llama2Sampler;
+use crate::sampler::Llama2SamplerRef;
+use crate::sampler::SamplerState;
+use crate::sparse_ffn::SparseFfn;
+use crate::sparse_ffn::SparseFfnOptions;
+use crate::stopping::StoppingContext;
+use crate::stopping::StoppingCriteria;
+use crate::stopping::StoppingCriteriaList;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Activation {
@@ -22,15 +72,355 @@ pub enum Activation {
     GeLU,
 }
 
+/// what to do when the prompt does not fit into the context limit of the session.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ContextOverflowPolicy {
+    /// fail the prefill with a `ContextOverflow` error.
+    #[default]
+    Error,
+
+    /// drop the oldest tokens of the prompt (but keep the BOS) to fit into the context limit.
+    TruncatePrompt,
+}
+
+/// what the decode loop does on sampling the EOS token.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum EosPolicy {
+    /// stop the generation, the EOS is not yielded.
+    #[default]
+    Stop,
+
+    /// yield the EOS like the other tokens and go on, like on sampling a base model past the
+    /// end of its documents. the stop tokens still stop the generation.
+    Continue,
+}
+
+/// parses "stop" or "continue".
+impl std::str::FromStr for EosPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stop" => Ok(EosPolicy::Stop),
+            "continue" => Ok(EosPolicy::Continue),
+            _ => Err(Error::new(
+                ErrorKind::BadInput,
+                format!("unknown EOS policy {}, expect stop or continue", s),
+            )),
+        }
+    }
+}
+
+/// the kernels of the attention over the KV cache. the best one varies with the context length
+/// and the hardware, `crabml bench --attention all` compares them on a model.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum AttentionKernel {
+    /// the scores of all the queries of the batch in a single (n_heads, n_batch, seq) matrix.
+    #[default]
+    Naive,
+
+    /// the queries of the batch in chunks of rows, which bounds the score matrix to
+    /// (n_heads, chunk_len, seq) on prefilling the long prompts. the tree forwards and the
+    /// layers recording the attention maps run on the naive kernel.
+    Chunked(usize),
+
+    /// the fused attention which never materializes the score matrix, each query keeps the
+    /// running max and sum of its softmax over the blocks of the keys, like FlashAttention.
+    /// only the CPU has the fused kernel for now. the tree forwards and the layers recording
+    /// the attention maps run on the naive kernel.
+    Flash,
+}
+
+impl std::fmt::Display for AttentionKernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttentionKernel::Naive => write!(f, "naive"),
+            AttentionKernel::Chunked(chunk_len) => write!(f, "chunked:{}", chunk_len),
+            AttentionKernel::Flash => write!(f, "flash"),
+        }
+    }
+}
+
+/// parses "naive", "chunked" (in chunks of 64 rows), "chunked:N" or "flash".
+impl std::str::FromStr for AttentionKernel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "naive" => Ok(AttentionKernel::Naive),
+            None if s == "chunked" => Ok(AttentionKernel::Chunked(64)),
+            None if s == "flash" => Ok(AttentionKernel::Flash),
+            Some(("chunked", chunk_len)) => match chunk_len.parse::<usize>() {
+                Ok(chunk_len) if chunk_len > 0 => Ok(AttentionKernel::Chunked(chunk_len)),
+                _ => Err(Error::new(
+                    ErrorKind::BadInput,
+                    format!("invalid chunk length of the attention kernel: {}", s),
+                )),
+            },
+            _ => Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "unknown attention kernel {}, expect naive, chunked[:N] or flash",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// lowers the priority of a background session (like a batch summarization), so the
+/// interactive sessions in the same process keep a low latency.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Niceness {
+    /// cap the number of threads used by the CPU tensor operations of this session.
+    pub max_threads: Option<usize>,
+
+    /// pause between the layers to give the cores to the other sessions. a zero duration just
+    /// yields the current thread.
+    pub layer_pause: Option<Duration>,
+}
+
+impl Niceness {
+    /// runs on a single thread, and yields between every layer.
+    pub fn background() -> Self {
+        Self {
+            max_threads: Some(1),
+            layer_pause: Some(Duration::ZERO),
+        }
+    }
+}
+
+/// the timing of the decode loop, passed to the throttle hook after each generated token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeTiming {
+    /// the position of the generated token.
+    pub pos: usize,
+
+    /// the number of the tokens generated so far, including the one sampled on prefill.
+    pub n_generated: usize,
+
+    /// the walltime of forwarding and sampling the last token.
+    pub token_elapsed: Duration,
+
+    /// the walltime since the decode loop started.
+    pub elapsed: Duration,
+}
+
+/// the timing of the last prefill, see `Llama2Runner::prefill_timing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PrefillTiming {
+    /// the number of the prompt tokens forwarded.
+    pub n_tokens: usize,
+
+    /// the walltime of encoding the prompt, zero if the runner is given the tokens.
+    pub tokenize: Duration,
+
+    /// the walltime of forwarding the prompt and sampling the first token.
+    pub compute: Duration,
+}
+
+/// what the decode loop does before the next token, decided by the throttle hook.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Throttle {
+    /// sleep before the next token, a zero duration just yields the current thread.
+    pub pause: Option<Duration>,
+
+    /// cap the number of threads of the next tokens, along with the cap of the niceness.
+    pub max_threads: Option<usize>,
+}
+
+/// decides how to throttle the decode loop, like on the battery or thermal state of the
+/// device, so the laptop and mobile apps do not peg all the cores while generating. the hook
+/// is shared across the threads, like with the monitor of the battery.
+pub trait ThrottleHook {
+    fn throttle(&self, timing: &DecodeTiming) -> Throttle;
+}
+
+pub type ThrottleHookRef = Arc<dyn ThrottleHook + Send + Sync>;
+
+impl<F> ThrottleHook for F
+where F: Fn(&DecodeTiming) -> Throttle
+{
+    fn throttle(&self, timing: &DecodeTiming) -> Throttle {
+        self(timing)
+    }
+}
+
+/// the keys and values in the KV cache of a runner in f32, in (layer) => (n_kv_heads, len,
+/// head_dim). it's used to move a session across the devices.
+#[derive(Debug, Clone)]
+pub struct KvCacheSnapshot {
+    pub len: usize,
+    pub keys: Vec<Vec<f32>>,
+    pub values: Vec<Vec<f32>>,
+}
+
+/// runs the prefill of a runner on another device, like the GPU which is fast on the large
+/// matmuls of a long prompt, while the latency sensitive decoding stays on the CPU. the KV
+/// cache is moved back once after the prefill.
+pub trait PrefillOffload {
+    /// forward the tokens from the position 0, returns the KV cache and the logits of the
+    /// last token.
+    fn offload_prefill(&mut self, tokens: &[usize]) -> Result<(KvCacheSnapshot, Vec<f32>)>;
+}
+
+/// runs all the forwards of a runner on another device like the GPU. once it fails, like the
+/// device is lost, out of memory or an op is not supported yet, the runner falls back to its
+/// own device by forwarding the tokens in the KV cache again, and the generation goes on.
+pub trait ForwardOffload {
+    /// forward the tokens at pos, returns the logits of the last token.
+    fn offload_forward(&mut self, tokens: &[usize], pos: usize) -> Result<Vec<f32>>;
+
+    /// keep the first len tokens in the KV cache.
+    fn offload_truncate(&mut self, len: usize) -> Result<()>;
+
+    /// forward a tree of tokens after the cached ones, like `Llama2Runner::forward_tree`.
+    fn offload_forward_tree(
+        &mut self,
+        _tokens: &[usize],
+        _parents: &[Option<usize>],
+    ) -> Result<Vec<f32>> {
+        Err(Error::new(
+            ErrorKind::NotImplemented,
+            "the offload does not forward the trees",
+        ))
+    }
+
+    /// embed the text on the empty KV cache, like `Llama2Runner::embed`.
+    fn offload_embed(&mut self, _text: &str) -> Result<Vec<f32>> {
+        Err(Error::new(
+            ErrorKind::NotImplemented,
+            "the offload does not embed",
+        ))
+    }
+
+    /// copy the KV cache out in f32.
+    fn offload_export_kv_cache(&self) -> Result<KvCacheSnapshot> {
+        Err(Error::new(
+            ErrorKind::NotImplemented,
+            "the offload does not export the KV cache",
+        ))
+    }
+
+    /// replace the KV cache with the snapshot.
+    fn offload_import_kv_cache(&mut self, _snapshot: &KvCacheSnapshot) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::NotImplemented,
+            "the offload does not import the KV cache",
+        ))
+    }
+}
+
+/// forwards a range of the layers of a runner on another device, like the layers which fit into
+/// the VRAM while the rest of the model stays on the CPU. the hidden states are moved between
+/// the devices over the host at the boundaries of the range, and the offload keeps the KV cache
+/// of its layers.
+pub trait LayerOffload {
+    /// forward the hidden states in (n_batch, embed_dim) of the tokens at pos through the
+    /// layers, returns the hidden states after the last one.
+    fn offload_layers(&mut self, x: &[f32], pos: usize) -> Result<Vec<f32>>;
+
+    /// keep the first len tokens in the KV cache.
+    fn offload_truncate(&mut self, len: usize) -> Result<()>;
+}
+
+// the model of a runner, to build the scratch runners which share its weights
+struct RunnerModel<'r, T: Tensor>(&'r Llama2Runner<T>);
+
+impl<'r, T: Tensor> Llama2Model for RunnerModel<'r, T> {
+    type T = T;
+
+    fn conf(&self) -> Llama2Config {
+        self.0.conf.clone()
+    }
+
+    fn device(&self) -> T::Device {
+        self.0.device.clone()
+    }
+
+    fn weights(&self) -> Arc<Llama2Weights<T>> {
+        self.0.weights.clone()
+    }
+
+    fn tokenizer(&self) -> Tokenizer {
+        self.0.tokenizer.clone()
+    }
+
+    fn sampler(&self) -> Llama2SamplerRef {
+        Llama2Sampler::new(self.0.conf.vocab_size, 0.0, 0.0)
+    }
+
+    fn metrics(&self) -> &TensorMetrics {
+        &self.0.metrics
+    }
+}
+
+/// a runner holds the mutable states of a session, like the KV cache and the sampler, while the
+/// weights are shared with the model. create one runner per thread to serve concurrently.
 pub struct Llama2Runner<T: Tensor> {
     conf: Llama2Config,
-    weights: Rc<Llama2Weights<T>>,
-    tokenizer: Rc<Tokenizer>,
-    sampler: Rc<Llama2Sampler>,
+    weights: Arc<Llama2Weights<T>>,
+    tokenizer: Tokenizer,
+    sampler: Llama2SamplerRef,
     device: T::Device,
-    logits: Vec<f32>,            // output logits (vocab_size, )
-    key_cache: Vec<Option<T>>,   // (layer, n_kv_head, seq_len, kv_dim)
-    value_cache: Vec<Option<T>>, // (layer, n_kv_head, seq_len, kv_dim)
+    logits: Vec<f32>, // output logits (vocab_size, )
+    kv_cache: KvCache<T>,
+    context_limit: usize,
+    context_overflow_policy: ContextOverflowPolicy,
+    eos_policy: EosPolicy,
+    niceness: Niceness,
+    throttle_hook: Option<ThrottleHookRef>,
+    throttle: Throttle,
+    prefill_chunk_size: usize,
+    attention_kernel: AttentionKernel,
+    sparse_ffn: Option<SparseFfn>,
+    // the tokens the output head is restricted to, and their rows of the output weight
+    output_vocab: Option<(Vec<usize>, T)>,
+    progress_reporter: Option<ProgressReporterRef>,
+    event_sender: Option<GenerationEventSender>,
+    request_id: Option<RequestId>,
+    request_started: bool,
+    grammar: Option<(Arc<CompiledGrammar>, GrammarState)>,
+    // the tokens the next sample is restricted to after the last prompt token is healed, and
+    // the text of the healed token which the first piece of the generation starts with
+    heal_candidates: Option<Vec<usize>>,
+    healed_text: String,
+    stop_tokens: Vec<usize>,
+    // the stop tokens replaced for the current generation only, like the EOT of an infill, they
+    // are restored once it stops or on the next prefill
+    saved_stop_tokens: Option<Vec<usize>>,
+    prefill_offload: Option<Box<dyn PrefillOffload + Send>>,
+    attention_maps: Option<AttentionMaps>,
+    forward_offload: Option<Box<dyn ForwardOffload + Send>>,
+    offloaded_tokens: Vec<usize>,
+    // the first tokens of the offloaded session which are in the KV cache of this runner too,
+    // like the imported ones, they're not forwarded again on falling back
+    offloaded_prefix: usize,
+    fallback_cause: Option<Error>,
+    // the ranges of the layers forwarded on the other devices, and the number of the tokens in
+    // the KV caches split over the devices
+    layer_offloads: Vec<(Range<usize>, Box<dyn LayerOffload + Send>)>,
+    split_len: usize,
+    stop_reason: Option<StopReason>,
+    loop_watchdog: Option<LoopWatchdog>,
+    // the tokens of the current generation in the window of the loop watchdog
+    recent_tokens: Vec<usize>,
+    stopping_criteria: StoppingCriteriaList,
+    // the text of the current generation, the tokens are decoded by appending to it and the
+    // pieces are borrowed from it. the raw logits of the last sampled token are kept for the
+    // stopping criteria
+    generated_text: String,
+    raw_logits: Vec<f32>,
+    // the runners of the other models whose logits are fused with this one, and their weights
+    ensemble: Vec<(Llama2Runner<T>, f32)>,
+    ensemble_weight: f32,
+    // the step of the forward the runner is on, and the report of the last failed forward
+    op_tracer: Option<OpTracer>,
+    failure_report: Option<FailureReport>,
+    // the time spent on encoding the prompt about to be prefilled, and the timing of the last
+    // prefill
+    tokenize_elapsed: Duration,
+    prefill_timing: Option<PrefillTiming>,
     pub metrics: TensorMetrics,
 }
 
@@ -53,445 +443,3450 @@ impl<'a, T: Tensor> Llama2Runner<T> {
         let sampler = model.sampler();
         let metrics = model.metrics().clone();
         let logits = vec![0.0; conf.vocab_size];
-        let key_cache = (0..conf.n_layers)
-            .map(|_| {
-                T::alloc(
-                    &[conf.n_kv_heads, seq_len, conf.head_size()],
-                    kv_cache_dtype,
-                    device.clone(),
-                )
-                .map(|t| t.resize(1, 0).unwrap())
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        let value_cache = (0..conf.n_layers)
-            .map(|_| {
-                T::alloc(
-                    &[conf.n_kv_heads, seq_len, conf.head_size()],
-                    kv_cache_dtype,
-                    device.clone(),
-                )
-                .map(|t| t.resize(1, 0).unwrap())
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
+
+        // the rope tables are shared by all the layers, and by the runners on the same device
+        let rope_mode = conf.forward.rope_mode;
+        let rope_dim = conf.rope_dim.unwrap_or(conf.head_size());
+        T::init_rope_cache(
+            &device,
+            rope_mode,
+            conf.head_size(),
+            rope_dim,
+            conf.rope_theta,
+            conf.rope_freq_scale,
+            seq_len,
+        )?;
+
+        // the KV cache is allocated on the first forward, it takes no memory while the forwards
+        // are offloaded
+        let kv_cache = KvCache::new(
+            conf.n_layers,
+            conf.n_kv_heads,
+            seq_len,
+            conf.head_size(),
+            kv_cache_dtype,
+            device.clone(),
+        );
         Ok(Self {
             conf: conf.clone(),
             logits,
             sampler,
-            key_cache,
-            value_cache,
+            kv_cache,
             weights,
             tokenizer,
             device,
             metrics,
+            context_limit: seq_len,
+            context_overflow_policy: ContextOverflowPolicy::default(),
+            eos_policy: EosPolicy::default(),
+            niceness: Niceness::default(),
+            throttle_hook: None,
+            throttle: Throttle::default(),
+            prefill_chunk_size: 512,
+            attention_kernel: AttentionKernel::default(),
+            sparse_ffn: None,
+            output_vocab: None,
+            progress_reporter: None,
+            event_sender: None,
+            request_id: None,
+            request_started: false,
+            grammar: None,
+            heal_candidates: None,
+            healed_text: String::new(),
+            stop_tokens: vec![],
+            saved_stop_tokens: None,
+            prefill_offload: None,
+            attention_maps: None,
+            forward_offload: None,
+            offloaded_tokens: vec![],
+            offloaded_prefix: 0,
+            fallback_cause: None,
+            layer_offloads: vec![],
+            split_len: 0,
+            stop_reason: None,
+            loop_watchdog: None,
+            recent_tokens: vec![],
+            stopping_criteria: StoppingCriteriaList::new(),
+            generated_text: String::new(),
+            raw_logits: vec![],
+            ensemble: vec![],
+            ensemble_weight: 1.0,
+            op_tracer: None,
+            failure_report: None,
+            tokenize_elapsed: Duration::ZERO,
+            prefill_timing: None,
         })
     }
 
-    pub fn conf(&self) -> &Llama2Config {
-        &self.conf
+    pub fn with_attention_kernel(mut self, kernel: AttentionKernel) -> Self {
+        self.attention_kernel = kernel;
+        self
     }
 
-    pub fn kv_cache_len(&self) -> usize {
-        self.key_cache[0].as_ref().unwrap().shape()[1]
+    pub fn set_attention_kernel(&mut self, kernel: AttentionKernel) {
+        self.attention_kernel = kernel;
     }
 
-    // prefill the model with the prompt, return the next position and the first generated token
-    pub fn prefill(
-        &mut self,
-        prompt: &str,
-        bos: bool,
-        _batched: bool,
-    ) -> Result<(usize, usize, usize)> {
-        let prompt_tokens = self.tokenizer.encode(prompt, bos, false)?;
-        if prompt_tokens.is_empty() {
-            return Err(Error {
-                kind: ErrorKind::BadInput,
-                message: "something is wrong, expected at least 1 prompt token".to_string(),
-                cause: None,
-            });
-        }
+    pub fn attention_kernel(&self) -> AttentionKernel {
+        self.attention_kernel
+    }
 
-        let base_pos = self.kv_cache_len();
-        // this is expected to be eos, make it as the prewarm
-        let sampler = self.sampler.clone();
-        let mut logits: &mut [f32] = &mut [];
-        for (pos, token) in prompt_tokens.iter().enumerate() {
-            logits = self.forward(&[*token], base_pos + pos)?;
-        }
-        let token = sampler.sample(logits)?;
-        let last_token = *prompt_tokens.last().unwrap();
+    /// skip the rows of the down projections whose activations are about zero.
+    pub fn with_sparse_ffn(mut self, options: SparseFfnOptions) -> Self {
+        self.sparse_ffn = Some(SparseFfn::new(options));
+        self
+    }
 
-        // take the length of kv cache as the next position
-        let next_pos = self.kv_cache_len();
-        assert_eq!(next_pos, base_pos + prompt_tokens.len());
-        Ok((next_pos, last_token, token))
+    /// the fraction of the FFN activations kept by the sparse FFN so far, it helps on picking
+    /// the threshold.
+    pub fn sparse_ffn_density(&self) -> Option<f32> {
+        self.sparse_ffn.as_ref().map(|s| s.density())
     }
 
-    pub fn generate(
-        &'a mut self,
-        pos: usize,
-        token: usize,
-        steps: Option<usize>,
-    ) -> impl Iterator<Item = Result<String>> + '_ {
-        // the first token has already been generated in the prefill phase.
-        let max_seq = self.conf.seq_len - pos - 1;
-        let max_steps = match steps {
-            Some(steps) => max_seq.min(steps - 1),
-            None => max_seq,
-        };
+    /// report the progress of prefill in tokens.
+    pub fn with_progress_reporter(mut self, reporter: ProgressReporterRef) -> Self {
+        self.progress_reporter = Some(reporter);
+        self
+    }
 
-        let sampler = self.sampler.clone();
-        let first_token = self.tokenizer.decode(token);
-        let tokens_iter = (pos..pos + max_steps).scan(token, move |current_token, pos| {
-            let logits = self.forward(&[*current_token], pos).unwrap();
-            let new_token = sampler.sample(logits).unwrap();
-            if new_token == self.tokenizer.eos_token() {
-                return None;
-            }
-            let r = self.tokenizer.decode(new_token).unwrap();
-            *current_token = new_token;
-            Some(Ok(r))
-        });
-        std::iter::once(first_token).chain(tokens_iter)
+    /// limit the number of tokens this session can hold in its KV cache, it can not exceed the
+    /// seq_len passed on creating the runner. the prefill fails with `ContextOverflow` (or
+    /// truncates the prompt, depends on the policy) when the limit is exceeded, and the
+    /// generation stops at the limit.
+    pub fn with_context_limit(mut self, max_tokens: usize) -> Self {
+        self.context_limit = max_tokens.min(self.context_limit);
+        self
     }
 
-    // simplify the test cases
-    pub fn prefill_and_generate(
-        &'a mut self,
-        prompt: &str,
-        steps: usize,
-    ) -> Result<impl Iterator<Item = Result<String>> + '_> {
-        let (pos, _prev_token, token) = self.prefill(prompt, true, false)?;
-        Ok(self.generate(pos, token, Some(steps)))
+    /// limit the memory of the KV cache of this session in bytes, it's converted into a
+    /// context limit in tokens.
+    pub fn with_kv_cache_budget(self, max_bytes: usize) -> Self {
+        let max_tokens = max_bytes / self.kv_cache_bytes_per_token();
+        self.with_context_limit(max_tokens)
     }
 
-    pub fn forward(&mut self, tokens: &[usize], pos: usize) -> Result<&mut [f32]> {
-        let _t = self.metrics.forward_walltime.track();
+    pub fn with_context_overflow_policy(mut self, policy: ContextOverflowPolicy) -> Self {
+        self.context_overflow_policy = policy;
+        self
+    }
 
-        let x = match self.conf.architecture {
-            ModelArchitecture::Llama => self.forward_llama(tokens, pos)?,
-            ModelArchitecture::Gemma => self.forward_gemma(tokens, pos)?,
-        };
+    /// whether the following generations stop on the EOS token, they stop by default.
+    pub fn set_eos_policy(&mut self, policy: EosPolicy) {
+        self.eos_policy = policy;
+    }
 
-        let mut x_final = T::alloc(
-            &[self.conf.embedding_dim],
-            GGMLType::F32,
-            self.device.clone(),
-        )?;
-        x_final.copy_rows_from(&x, &[tokens.len() - 1])?;
+    pub fn with_eos_policy(mut self, policy: EosPolicy) -> Self {
+        self.set_eos_policy(policy);
+        self
+    }
 
-        // classifier into logits
-        // TODO: it'd be make sense to reuse the same buffer for the logits
-        let output_weight = self
-            .weights
-            .output_weight
-            .as_ref()
-            .unwrap_or_else(|| &self.weights.token_embed);
-        let logits = output_weight.matmul_vec(&x_final)?; // (batch_size, vocab_size),
-        logits.export(&mut self.logits)?;
-        Ok(&mut self.logits)
+    pub fn with_niceness(mut self, niceness: Niceness) -> Self {
+        self.niceness = niceness;
+        self
     }
 
-    fn forward_llama(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
-        let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
-        let n_batch = tokens.len();
+    /// call the hook after each generated token to pause the decode loop or cap its threads.
+    pub fn with_throttle_hook(mut self, hook: ThrottleHookRef) -> Self {
+        self.throttle_hook = Some(hook);
+        self
+    }
 
-        // copy the token embedding into x
-        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
-        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+    /// the max number of tokens forwarded at once on the batched prefill, 512 by default. it
+    /// bounds the memory of the attention scores and the activations on the long prompts.
+    pub fn with_prefill_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.prefill_chunk_size = chunk_size.max(1);
+        self
+    }
 
-        // forward all the layers
-        for l in 0..self.conf.n_layers {
-            let x_attn_orig = x.dup()?;
+    /// run the prefill of the new prompts on another runner, like the one on the GPU. only the
+    /// prompts starting at the position 0 are offloaded, since the other runner does not have
+    /// the tokens in this KV cache, like the previous turns of a chat.
+    pub fn with_prefill_offload(mut self, offload: Box<dyn PrefillOffload + Send>) -> Self {
+        self.prefill_offload = Some(offload);
+        self
+    }
 
-            // attention rnsnorm
-            x = {
-                x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
-                x = x.mul_inplace(&self.weights.rms_att_weight[l])?;
-                x = x.with_name(format!("attn_rmsnorm:{}:{}", l, pos));
-                x
-            };
+    /// run all the forwards on another runner, like the one on the GPU, and fall back to this
+    /// runner once it fails. the tokens are tracked to be forwarded again on falling back.
+    pub fn with_forward_offload(mut self, offload: Box<dyn ForwardOffload + Send>) -> Self {
+        self.forward_offload = Some(offload);
+        self.offloaded_tokens.clear();
+        self.offloaded_prefix = 0;
+        self
+    }
 
-            // matmul qkv for every head
-            let (q, k, v) = {
-                // wq: (embed_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, embed_dim, )
-                // wk: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
-                // wv: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
-                let q = self.weights.wq[l].matmul_vec(&x)?;
-                let k = self.weights.wk[l].matmul_vec(&x)?;
-                let v = self.weights.wv[l].matmul_vec(&x)?;
-                (q, k, v)
-            };
+    /// forward the layers in the range on another runner, like the one on the GPU holding the
+    /// weights of these layers only, while the embedding, the other layers and the output head
+    /// stay on this runner. the ranges of the offloads must not overlap. the KV cache of the
+    /// offloaded layers is not allocated on this runner.
+    pub fn with_layer_offload(
+        mut self,
+        layers: Range<usize>,
+        offload: Box<dyn LayerOffload + Send>,
+    ) -> Self {
+        self.kv_cache.skip_layers(layers.clone());
+        self.layer_offloads.push((layers, offload));
+        self
+    }
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_batch, n_heads, head_dim])?;
-                let k = k.reshape(&[n_batch, n_kv_heads, head_dim])?;
+    /// whether the forwards are still offloaded, it's false after falling back.
+    pub fn is_offloaded(&self) -> bool {
+        self.forward_offload.is_some()
+    }
 
-                let q = q.rope_inplace(RopeMode::Llama, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Llama, pos, rope_dim)?;
-                (q, k)
-            };
+    /// the error of the offloaded forward which made this runner fall back to its own device.
+    pub fn fallback_cause(&self) -> Option<&Error> {
+        self.fallback_cause.as_ref()
+    }
 
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
-            x = x.with_name(format!("attn_out:{}:{}", l, pos));
+    /// record the attention weights of the following forwards, it's slow and takes a lot of
+    /// memory on the long contexts, only for the interpretability tools.
+    pub fn with_attention_maps(mut self, options: AttentionMapOptions) -> Self {
+        self.set_attention_maps(Some(options));
+        self
+    }
 
-            // residual connection back into x
-            x = x.add_inplace(&x_attn_orig)?;
+    /// start recording the attention maps from scratch, or stop recording with None.
+    pub fn set_attention_maps(&mut self, options: Option<AttentionMapOptions>) {
+        self.attention_maps = options.map(AttentionMaps::new);
+    }
 
-            // ffn
-            x = self.forward_ffn(x, l, pos, Activation::SiLU)?;
-            x = x.with_name(format!("ffn_out:{}:{}", l, pos));
+    pub fn attention_maps(&self) -> Option<&AttentionMaps> {
+        self.attention_maps.as_ref()
+    }
+
+    pub fn attention_maps_mut(&mut self) -> Option<&mut AttentionMaps> {
+        self.attention_maps.as_mut()
+    }
+
+    /// capture a report of the op, the layer and the inputs of the forwards which fail or
+    /// panic, pass None to turn it off. the inputs of each op are hashed on the way, which
+    /// slows the forward down, it's meant to reproduce a failure. a panic is turned into an
+    /// `Unexpected` error, the KV cache is left in an undefined state after it. the failures
+    /// of the offloaded forwards are kept in `fallback_cause` instead.
+    pub fn set_failure_reports(&mut self, options: Option<FailureReportOptions>) {
+        self.op_tracer = options.map(OpTracer::new);
+    }
+
+    pub fn with_failure_reports(mut self, options: FailureReportOptions) -> Self {
+        self.set_failure_reports(Some(options));
+        self
+    }
+
+    /// the report of the last failed forward, if the failure reports are enabled.
+    pub fn failure_report(&self) -> Option<&FailureReport> {
+        self.failure_report.as_ref()
+    }
+
+    /// the timing of the last prefill which succeeded.
+    pub fn prefill_timing(&self) -> Option<PrefillTiming> {
+        self.prefill_timing
+    }
+
+    /// prefill n tokens on a scratch runner which shares the weights, the session of this
+    /// runner is kept. the weights are in memory after the first prefill, so the first prefill
+    /// minus this one is the time it spent on faulting in the weights. None if the prefills are
+    /// offloaded, the offloaded weights are uploaded on loading.
+    pub fn time_warm_prefill(
+        &self,
+        n_tokens: usize,
+        batched: bool,
+    ) -> Result<Option<PrefillTiming>> {
+        if self.prefill_offload.is_some()
+            || self.forward_offload.is_some()
+            || !self.layer_offloads.is_empty()
+        {
+            return Ok(None);
         }
+        let n_tokens = n_tokens.max(1);
+        let use_f16_kv_cache = self.kv_cache.dtype() == GGMLType::F16;
+        let mut scratch = Llama2Runner::new(RunnerModel(self), n_tokens, use_f16_kv_cache)?;
+        scratch.prefill_chunk_size = self.prefill_chunk_size;
+        scratch.attention_kernel = self.attention_kernel;
+        let tokens = vec![self.tokenizer.bos_token(); n_tokens];
+        scratch.prefill_tokens(tokens, false, batched)?;
+        Ok(scratch.prefill_timing)
+    }
 
-        // final rmsnorm
-        x = {
-            x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
-            x = x.mul_inplace(&self.weights.rms_final_weight)?;
-            x.with_name(format!("final_rmsnorm:{}", pos))
+    pub fn context_limit(&self) -> usize {
+        self.context_limit
+    }
+
+    /// restrict the output head to the tokens, like the ones a grammar permits, the logits of
+    /// the other tokens are -inf. only the rows of the tokens are projected, which saves most of
+    /// the output matmul on a small vocab. None restores the full vocab.
+    pub fn set_output_vocab(&mut self, tokens: Option<&[usize]>) -> Result<()> {
+        let tokens = match tokens {
+            Some(tokens) => tokens,
+            None => {
+                self.output_vocab = None;
+                return Ok(());
+            }
         };
+        if tokens.is_empty() || tokens.iter().any(|t| *t >= self.conf.vocab_size) {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "the output vocab should be a non-empty subset of the {} tokens",
+                    self.conf.vocab_size
+                ),
+            ));
+        }
+        let output_weight = self
+            .weights
+            .output_weight
+            .as_ref()
+            .unwrap_or(&self.weights.token_embed);
+        let mut rows = T::alloc(
+            &[tokens.len(), self.conf.embedding_dim],
+            GGMLType::F32,
+            self.device.clone(),
+        )?;
+        rows.copy_rows_from(output_weight, tokens)?;
+        self.output_vocab = Some((tokens.to_vec(), rows));
+        Ok(())
+    }
 
-        Ok(x)
+    pub fn output_vocab(&self) -> Option<&[usize]> {
+        self.output_vocab
+            .as_ref()
+            .map(|(tokens, _)| tokens.as_slice())
     }
 
-    // The differences between GEMMA and LLAMA are:
-    // 1. the way the ROPE is calculated.
-    // 2. it uses GELU instead of SiLU.
-    // 3. it scales the input embedding with sqrt(embed_dim).
-    // 4. it adds a 1.0 to every weights on rmsnorm (rms_att_weight, rms_ffn_weight,
-    //    rms_final_weight), this have been processed during GGUF format convert, so we
-    //    don't need to do it here.
-    fn forward_gemma(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
-        let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
-        let n_batch = tokens.len();
+    /// the bytes of the keys and values of a single token in all the layers.
+    pub fn kv_cache_bytes_per_token(&self) -> usize {
+        let dtype_bytes = match self.kv_cache.dtype() {
+            GGMLType::F16 => 2,
+            _ => 4,
+        };
+        2 * self.conf.n_layers * self.conf.n_kv_heads * self.conf.head_size() * dtype_bytes
+    }
 
-        // copy the token embedding into x
-        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
-        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+    /// emit the generation lifecycle events into the channel.
+    pub fn with_event_sender(mut self, sender: GenerationEventSender) -> Self {
+        self.event_sender = Some(sender);
+        self
+    }
+
+    /// tag the following generations with the request ID, a RequestStarted event is emitted
+    /// on the next prefill. a runner serves one request at a time, set it on each request.
+    pub fn set_request_id(&mut self, request_id: Option<RequestId>) {
+        self.request_id = request_id;
+        self.request_started = false;
+    }
 
-        // GEMMA only: scale the embedding with sqrt(embed_dim)
-        x = x.scale_inplace((embed_dim as f32).sqrt())?;
-        x = x.with_name("scaled_embed".to_string());
+    pub fn with_request_id(mut self, request_id: RequestId) -> Self {
+        self.set_request_id(Some(request_id));
+        self
+    }
 
-        // forward all the layers
-        for l in 0..self.conf.n_layers {
-            let x_attn_orig = x.dup()?;
+    pub fn request_id(&self) -> Option<&RequestId> {
+        self.request_id.as_ref()
+    }
 
-            // attention rnsnorm
-            x = {
-                x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
-                x = x.mul_inplace(&self.weights.rms_att_weight[l])?;
-                x = x.with_name(format!("attn_rmsnorm:{}:{}", l, pos));
-                x
-            };
+    /// constrain the following generated tokens with the grammar, the grammar starts from its
+    /// start state. pass None to generate freely.
+    pub fn set_grammar(&mut self, grammar: Option<Arc<CompiledGrammar>>) {
+        self.grammar = grammar.map(|g| {
+            let state = g.start();
+            (g, state)
+        });
+    }
 
-            // matmul qkv for every head
-            let (q, k, v) = {
-                // wq: (embed_dim, embed_dim) @ x (embed_dim, ) => (embed_dim, )
-                // wk: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
-                // wv: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
-                let q = self.weights.wq[l].matmul_vec(&x)?;
-                let k = self.weights.wk[l].matmul_vec(&x)?;
-                let v = self.weights.wv[l].matmul_vec(&x)?;
-                (q, k, v)
-            };
+    /// stop the generation on these tokens besides the EOS, like the EOT token of the FIM
+    /// prompts. the stop tokens are not yielded.
+    pub fn set_stop_tokens(&mut self, stop_tokens: Vec<usize>) {
+        self.stop_tokens = stop_tokens;
+        self.saved_stop_tokens = None;
+    }
+
+    // replace the stop tokens for the next generation only
+    fn override_stop_tokens(&mut self, stop_tokens: Vec<usize>) {
+        let prev = std::mem::replace(&mut self.stop_tokens, stop_tokens);
+        self.saved_stop_tokens.get_or_insert(prev);
+    }
+
+    fn restore_stop_tokens(&mut self) {
+        if let Some(stop_tokens) = self.saved_stop_tokens.take() {
+            self.stop_tokens = stop_tokens;
+        }
+    }
+
+    /// check the following generations against the criteria besides the built-in checks, like
+    /// the stop strings or a custom predicate of a request. pass an empty list to turn it off.
+    pub fn set_stopping_criteria(&mut self, criteria: StoppingCriteriaList) {
+        self.stopping_criteria = criteria;
+    }
+
+    pub fn with_stopping_criteria(mut self, criteria: StoppingCriteriaList) -> Self {
+        self.set_stopping_criteria(criteria);
+        self
+    }
+
+    /// watch the following generations for the cycles of tokens, pass None to turn it off.
+    pub fn set_loop_watchdog(&mut self, watchdog: Option<LoopWatchdog>) {
+        self.loop_watchdog = watchdog;
+    }
+
+    pub fn with_loop_watchdog(mut self, watchdog: LoopWatchdog) -> Self {
+        self.set_loop_watchdog(Some(watchdog));
+        self
+    }
+
+    /// replace the sampler of the model, like a seeded one to reproduce a session.
+    pub fn set_sampler(&mut self, sampler: Llama2SamplerRef) {
+        self.sampler = sampler;
+    }
+
+    pub fn sampler(&self) -> &Llama2SamplerRef {
+        &self.sampler
+    }
+
+    /// run another model over the same tokenizer in lockstep, like a domain fine-tune of the
+    /// base model, and sample from the logits fused by the weights. the member forwards the
+    /// same tokens on each step and is truncated with this runner, so its KV cache should hold
+    /// the same tokens as this one when it's added.
+    pub fn add_ensemble_member(&mut self, member: Llama2Runner<T>, weight: f32) -> Result<()> {
+        if member.tokenizer.vocab() != self.tokenizer.vocab() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the ensemble member should share the same tokenizer",
+            ));
+        }
+        if member.kv_cache_len() != self.kv_cache_len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "the ensemble member has {} tokens in the KV cache, but the runner has {}",
+                    member.kv_cache_len(),
+                    self.kv_cache_len()
+                ),
+            ));
+        }
+        self.ensemble.push((member, weight));
+        Ok(())
+    }
+
+    /// the weight of this runner's own logits on fusing with the ensemble, 1.0 by default.
+    pub fn set_ensemble_weight(&mut self, weight: f32) {
+        self.ensemble_weight = weight;
+    }
+
+    pub(crate) fn emit_event(&self, event: GenerationEvent) {
+        if let Some(sender) = &self.event_sender {
+            // the receiver may have hung up, it's not a reason to stop the generation
+            let _ = sender.send(event);
+        }
+    }
+
+    pub(crate) fn stop(&mut self, reason: StopReason) {
+        self.stop_reason = Some(reason);
+        self.restore_stop_tokens();
+        self.emit_event(GenerationEvent::StopHit { reason });
+    }
+
+    /// why the last generation stopped, it's None if the generation is still going or failed.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    pub fn conf(&self) -> &Llama2Config {
+        &self.conf
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// the free slots of the KV cache, which is the context limit minus the tokens of the
+    /// prompt and the generated ones.
+    pub fn remaining_context(&self) -> usize {
+        self.context_limit.saturating_sub(self.kv_cache_len())
+    }
+
+    pub fn kv_cache_len(&self) -> usize {
+        if self.forward_offload.is_some() {
+            return self.offloaded_tokens.len();
+        }
+        if !self.layer_offloads.is_empty() {
+            return self.split_len;
+        }
+        self.kv_cache.len()
+    }
+
+    /// drop all the tokens in the KV cache, the next forward starts from position 0. the
+    /// memory of the cache is kept for reuse.
+    pub fn reset(&mut self) -> Result<()> {
+        self.truncate(0)
+    }
+
+    /// copy the KV cache out in f32, it can be imported into a runner of the same model on
+    /// another device.
+    pub fn export_kv_cache(&self) -> Result<KvCacheSnapshot> {
+        if let Some(offload) = self.forward_offload.as_ref() {
+            return offload.offload_export_kv_cache();
+        }
+        self.ensure_not_split("export_kv_cache")?;
+        let n_layers = self.kv_cache.n_layers();
+        if !self.kv_cache.is_allocated() {
+            return Ok(KvCacheSnapshot {
+                len: 0,
+                keys: vec![vec![]; n_layers],
+                values: vec![vec![]; n_layers],
+            });
+        }
+        let export = |cache: &T| {
+            let mut buf = vec![0.0; cache.shape().iter().product()];
+            cache.clone().contiguous()?.export(&mut buf)?;
+            Ok(buf)
+        };
+        Ok(KvCacheSnapshot {
+            len: self.kv_cache_len(),
+            keys: (0..n_layers)
+                .map(|l| export(self.kv_cache.keys(l)))
+                .collect::<Result<_>>()?,
+            values: (0..n_layers)
+                .map(|l| export(self.kv_cache.values(l)))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// replace the KV cache with the snapshot, the next forward starts from its length. while
+    /// the forwards are offloaded, the snapshot is imported into both the offload and this
+    /// runner, which continues from it on falling back.
+    pub fn import_kv_cache(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        self.ensure_no_ensemble("import_kv_cache")?;
+        self.ensure_not_split("import_kv_cache")?;
+        let shape = [self.conf.n_kv_heads, snapshot.len, self.conf.head_size()];
+        let layer_len = shape.iter().product::<usize>();
+        let valid = snapshot.keys.len() == self.conf.n_layers
+            && snapshot.values.len() == self.conf.n_layers
+            && snapshot
+                .keys
+                .iter()
+                .chain(snapshot.values.iter())
+                .all(|buf| buf.len() == layer_len);
+        if !valid {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "the KV cache snapshot of {} tokens does not match the model",
+                    snapshot.len
+                ),
+            ));
+        }
+        if snapshot.len > self.context_limit {
+            return Err(Error::new(
+                ErrorKind::ContextOverflow,
+                format!(
+                    "the KV cache snapshot has {} tokens, exceeds the context limit {}",
+                    snapshot.len, self.context_limit
+                ),
+            ));
+        }
+
+        self.truncate(0)?;
+        // the KV cache of this runner is left behind by the offload
+        self.kv_cache.truncate(0)?;
+        for (l, (keys, values)) in snapshot.keys.iter().zip(snapshot.values.iter()).enumerate() {
+            let mut k = T::alloc(&shape, GGMLType::F32, self.device.clone())?;
+            k.import(keys)?;
+            let mut v = T::alloc(&shape, GGMLType::F32, self.device.clone())?;
+            v.import(values)?;
+            self.kv_cache.append(l, &k, &v)?;
+        }
+        if let Some(offload) = self.forward_offload.as_mut() {
+            // the imported tokens are unknown, they're in the KV cache of this runner already
+            self.offloaded_tokens = vec![0; snapshot.len];
+            self.offloaded_prefix = snapshot.len;
+            match offload.offload_import_kv_cache(snapshot) {
+                Ok(()) => {}
+                Err(err) if is_backend_error(&err) => self.fall_back(err, snapshot.len)?,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// replace the KV cache with a prompt cache saved by llama.cpp on the same model. if the
+    /// session has no logits, the last token is forwarded again to take them.
+    pub fn import_llama_cpp_session(&mut self, session: &LlamaCppSession) -> Result<()> {
+        if session.n_layers() != self.conf.n_layers {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "the llama.cpp session has {} layers, but the model has {}",
+                    session.n_layers(),
+                    self.conf.n_layers
+                ),
+            ));
+        }
+        let snapshot = session.to_kv_cache_snapshot(self.conf.n_kv_heads)?;
+        self.import_kv_cache(&snapshot)?;
+        match (session.logits(), session.tokens().last()) {
+            (Some(logits), _) if logits.len() == self.logits.len() => {
+                self.logits.copy_from_slice(logits);
+            }
+            (_, Some(&last_token)) => {
+                let pos = snapshot.len - 1;
+                self.truncate(pos)?;
+                self.forward(&[last_token], pos)?;
+            }
+            (_, None) => {}
+        }
+        Ok(())
+    }
+
+    /// keep the first len tokens in the KV cache and drop the rest, the next forward starts from
+    /// position len. it's used to reuse the cached prefix of a prompt which shares the head
+    /// with the previous one.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len > self.kv_cache_len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "can not truncate the KV cache of {} tokens to {}",
+                    self.kv_cache_len(),
+                    len
+                ),
+            ));
+        }
+        for (member, _) in self.ensemble.iter_mut() {
+            member.truncate(len)?;
+        }
+        if let Some(offload) = self.forward_offload.as_mut() {
+            match offload.offload_truncate(len) {
+                Ok(()) => {
+                    self.offloaded_tokens.truncate(len);
+                    self.offloaded_prefix = self.offloaded_prefix.min(len);
+                    return Ok(());
+                }
+                Err(err) => self.fall_back(err, len)?,
+            }
+        }
+        if !self.layer_offloads.is_empty() {
+            for (_, offload) in self.layer_offloads.iter_mut() {
+                offload.offload_truncate(len)?;
+            }
+            self.split_len = len;
+            // the cache of the layers on this runner is not allocated if all the layers are
+            // offloaded
+            if !self.kv_cache.is_allocated() {
+                return Ok(());
+            }
+        }
+        self.kv_cache.truncate(len)
+    }
+
+    // prefill the model with the prompt, return the next position and the first generated token.
+    // on batched, the prompt is forwarded in chunks of prefill_chunk_size tokens, otherwise one
+    // token at a time.
+    pub fn prefill(
+        &mut self,
+        prompt: &str,
+        bos: bool,
+        batched: bool,
+    ) -> Result<(usize, usize, usize)> {
+        // the BOS/EOS are only added at the beginning of the text, if the tokenizer asks for them
+        let options = *self.tokenizer.options();
+        let tokenize_started_at = Instant::now();
+        let prompt_tokens =
+            self.tokenizer
+                .encode(prompt, bos && options.add_bos, bos && options.add_eos)?;
+        self.tokenize_elapsed = tokenize_started_at.elapsed();
+        let keep_head = bos && options.add_bos;
+        self.prefill_tokens(prompt_tokens, keep_head, batched)
+    }
+
+    /// prefill the prompt as plain text for the completions on the base models: no BOS or EOS
+    /// is added, and the pieces of the special tokens in it are encoded as text, see
+    /// `Tokenizer::encode_raw`.
+    pub fn prefill_raw(&mut self, prompt: &str, batched: bool) -> Result<(usize, usize, usize)> {
+        let tokenize_started_at = Instant::now();
+        let prompt_tokens = self.tokenizer.encode_raw(prompt, false, false)?;
+        self.tokenize_elapsed = tokenize_started_at.elapsed();
+        self.prefill_tokens(prompt_tokens, false, batched)
+    }
+
+    /// like prefill, but the last token of the prompt is healed: it's taken back, and the first
+    /// generated token is sampled from the tokens starting with its bytes, so a prompt ending in
+    /// the middle of a word (like "https:") is not stuck on the token boundary of the prompt.
+    /// the text of the taken back token is trimmed from the first generated piece.
+    pub fn prefill_healed(
+        &mut self,
+        prompt: &str,
+        bos: bool,
+        batched: bool,
+    ) -> Result<(usize, usize, usize)> {
+        let options = *self.tokenizer.options();
+        let tokenize_started_at = Instant::now();
+        let mut prompt_tokens =
+            self.tokenizer
+                .encode(prompt, bos && options.add_bos, bos && options.add_eos)?;
+        self.tokenize_elapsed = tokenize_started_at.elapsed();
+        let keep_head = bos && options.add_bos;
+
+        // the head and the special tokens are kept, at least one token is left to prefill
+        let last = *prompt_tokens.last().unwrap_or(&0);
+        if prompt_tokens.len() > usize::from(keep_head) + 1 && !self.tokenizer.is_special(last) {
+            let bytes = self.tokenizer.token_bytes(last);
+            if let Ok(text) = String::from_utf8(bytes) {
+                if !text.is_empty() {
+                    prompt_tokens.pop();
+                    self.heal_candidates =
+                        Some(self.tokenizer.trie().tokens_with_prefix(text.as_bytes()));
+                    self.healed_text = text;
+                }
+            }
+        }
+
+        let result = self.prefill_tokens(prompt_tokens, keep_head, batched);
+        self.heal_candidates = None;
+        if result.is_err() {
+            self.healed_text.clear();
+        }
+        result
+    }
+
+    /// prefill the fill-in-the-middle prompt of the code models, the generation after it is
+    /// the code between the prefix and the suffix, it stops on the EOT token. the EOT replaces
+    /// the stop tokens of the runner until the generation stops.
+    pub fn prefill_infill(
+        &mut self,
+        prefix: &str,
+        suffix: &str,
+        batched: bool,
+    ) -> Result<(usize, usize, usize)> {
+        let fim = FimTokens::detect(&self.tokenizer).ok_or_else(|| {
+            Error::new(
+                ErrorKind::BadInput,
+                "the model does not support fill-in-the-middle, no FIM tokens in the vocab",
+            )
+        })?;
+        let tokenize_started_at = Instant::now();
+        let prompt_tokens = fim.build_prompt(&self.tokenizer, prefix, suffix)?;
+        self.tokenize_elapsed = tokenize_started_at.elapsed();
+        let keep_head = self.tokenizer.options().add_bos;
+        let prefilled = self.prefill_tokens(prompt_tokens, keep_head, batched)?;
+        self.override_stop_tokens(fim.eot.into_iter().collect());
+        Ok(prefilled)
+    }
+
+    /// generate the code between the prefix and the suffix.
+    pub fn complete_infill(
+        &'a mut self,
+        prefix: &str,
+        suffix: &str,
+        steps: Option<usize>,
+    ) -> Result<impl Iterator<Item = Result<String>> + '_> {
+        let (pos, _prev_token, token) = self.prefill_infill(prefix, suffix, false)?;
+        Ok(self.generate(pos, token, steps))
+    }
+
+    /// prefill the tokens after the KV cache. if keep_head, the first token (like the BOS) is
+    /// kept when the prompt is truncated on overflowing the context.
+    pub fn prefill_tokens(
+        &mut self,
+        mut prompt_tokens: Vec<usize>,
+        keep_head: bool,
+        batched: bool,
+    ) -> Result<(usize, usize, usize)> {
+        let tokenize_elapsed = std::mem::take(&mut self.tokenize_elapsed);
+        // an infill generation which was not run to the end does not leave its stop tokens
+        self.restore_stop_tokens();
+        if let (Some(request_id), false) = (&self.request_id, self.request_started) {
+            self.emit_event(GenerationEvent::RequestStarted {
+                request_id: request_id.clone(),
+            });
+            self.request_started = true;
+        }
+
+        if prompt_tokens.is_empty() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "something is wrong, expected at least 1 prompt token",
+            ));
+        }
+
+        let base_pos = self.kv_cache_len();
+        let available = self.context_limit.saturating_sub(base_pos);
+        if prompt_tokens.len() > available {
+            let keep_head = usize::from(keep_head);
+            if self.context_overflow_policy == ContextOverflowPolicy::Error
+                || available <= keep_head
+            {
+                return Err(Error::new(
+                    ErrorKind::ContextOverflow,
+                    format!(
+                        "the prompt has {} tokens, but only {} tokens left in the context (limit {})",
+                        prompt_tokens.len(),
+                        available,
+                        self.context_limit
+                    ),
+                ));
+            }
+            let drop_start = keep_head;
+            let drop_end = prompt_tokens.len() - (available - keep_head);
+            prompt_tokens.drain(drop_start..drop_end);
+        }
+        let prefill_started_at = Instant::now();
+        let chunk_size = if batched { self.prefill_chunk_size } else { 1 };
+        // the ensemble members can not take the KV cache of the offloaded prefill
+        if base_pos == 0 && self.prefill_offload.is_some() && self.ensemble.is_empty() {
+            if let Err(err) = self.prefill_offloaded(&prompt_tokens) {
+                self.emit_event(GenerationEvent::Error {
+                    message: err.to_string(),
+                });
+                return Err(err);
+            }
+            if let Some(reporter) = &self.progress_reporter {
+                reporter.report(
+                    ProgressStage::Prefill,
+                    prompt_tokens.len(),
+                    prompt_tokens.len(),
+                );
+            }
+        }
+        while self.kv_cache_len() < base_pos + prompt_tokens.len() {
+            let left = match self.prefill_step(&prompt_tokens, base_pos, chunk_size) {
+                Ok(left) => left,
+                Err(err) => {
+                    self.emit_event(GenerationEvent::Error {
+                        message: err.to_string(),
+                    });
+                    return Err(err);
+                }
+            };
+            if let Some(reporter) = &self.progress_reporter {
+                let done = prompt_tokens.len() - left;
+                reporter.report(ProgressStage::Prefill, done, prompt_tokens.len());
+            }
+        }
+        self.emit_event(GenerationEvent::PromptProcessed {
+            n_tokens: prompt_tokens.len(),
+            t_ms: prefill_started_at.elapsed().as_secs_f64() * 1000.0,
+        });
+
+        let sample_started_at = Instant::now();
+        let token = self.sample_and_emit(sample_started_at)?;
+        let last_token = *prompt_tokens.last().unwrap();
+        self.prefill_timing = Some(PrefillTiming {
+            n_tokens: prompt_tokens.len(),
+            tokenize: tokenize_elapsed,
+            compute: prefill_started_at.elapsed(),
+        });
+
+        // take the length of kv cache as the next position
+        let next_pos = self.kv_cache_len();
+        assert_eq!(next_pos, base_pos + prompt_tokens.len());
+        Ok((next_pos, last_token, token))
+    }
+
+    // prefill the prompt on the offload runner, and take its KV cache and logits
+    fn prefill_offloaded(&mut self, prompt_tokens: &[usize]) -> Result<()> {
+        let offload = self.prefill_offload.as_mut().unwrap();
+        let (snapshot, logits) = offload.offload_prefill(prompt_tokens)?;
+        if logits.len() != self.logits.len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the offloaded prefill returns the logits of another vocab",
+            ));
+        }
+        self.import_kv_cache(&snapshot)?;
+        self.logits.copy_from_slice(&logits);
+        Ok(())
+    }
+
+    /// forward the next chunk of at most chunk_size prompt tokens which are not in the KV cache
+    /// yet, the prompt starts at base_pos. returns the number of the tokens left. the
+    /// scheduler serving many sequences can interleave the decode steps of the other sequences
+    /// between the chunks, instead of blocking them until a long prompt is done.
+    pub fn prefill_step(
+        &mut self,
+        prompt_tokens: &[usize],
+        base_pos: usize,
+        chunk_size: usize,
+    ) -> Result<usize> {
+        let done = self.kv_cache_len().saturating_sub(base_pos);
+        if done >= prompt_tokens.len() {
+            return Ok(0);
+        }
+        let end = (done + chunk_size.max(1)).min(prompt_tokens.len());
+        self.forward(&prompt_tokens[done..end], base_pos + done)?;
+        Ok(prompt_tokens.len() - end)
+    }
+
+    pub fn prefill_chunk_size(&self) -> usize {
+        self.prefill_chunk_size
+    }
+
+    /// yield the texts of the generated tokens, starting with the token sampled on the prefill
+    /// at pos. each step forwards the last token and samples the next one, until the eos or a
+    /// stop token, the steps, the context limit or an error. the reason is kept in
+    /// `stop_reason()` once the iteration ends.
+    pub fn generate(
+        &'a mut self,
+        pos: usize,
+        token: usize,
+        steps: Option<usize>,
+    ) -> impl Iterator<Item = Result<String>> + '_ {
+        let mut stream = self.generate_stream(pos, token, steps);
+        std::iter::from_fn(move || {
+            let event = stream.next_token()?;
+            Some(event.map(|event| event.piece.to_string()))
+        })
+    }
+
+    /// like generate, but the tokens are decoded into a text buffer of the runner which is
+    /// reused over the generations, and each token borrows its piece from the buffer instead
+    /// of allocating a string.
+    pub fn generate_stream(
+        &mut self,
+        pos: usize,
+        token: usize,
+        steps: Option<usize>,
+    ) -> TokenStream<'_, T> {
+        // the first token has already been generated in the prefill phase, each of the
+        // following tokens takes a slot in the KV cache to forward the token before it. the
+        // steps are capped by the remaining context instead of overrunning the KV cache.
+        let max_seq = self.context_limit.saturating_sub(pos + 1);
+        let steps = steps.unwrap_or(usize::MAX);
+        let (max_steps, capped_reason) = if steps.saturating_sub(1) > max_seq {
+            (max_seq, StopReason::ContextLength)
+        } else {
+            (steps.saturating_sub(1), StopReason::MaxSteps)
+        };
+
+        self.generated_text.clear();
+        let first_token = self
+            .tokenizer
+            .decode_append(token, &mut self.generated_text);
+        let healed_text = std::mem::take(&mut self.healed_text);
+        if self.generated_text.starts_with(&healed_text) {
+            self.generated_text.drain(..healed_text.len());
+        }
+        let criteria_reason = match &first_token {
+            Ok(_) if steps > 0 => self.check_stopping_criteria(token, 0, 1),
+            _ => None,
+        };
+
+        // the stop token sampled on prefill is not yielded
+        let stopped = self.stop_tokens.contains(&token) || criteria_reason == Some(StopReason::Eos);
+        let max_steps = if stopped || criteria_reason.is_some() {
+            0
+        } else {
+            max_steps
+        };
+
+        self.stop_reason = None;
+        self.recent_tokens.clear();
+        self.recent_tokens.push(token);
+        if stopped {
+            self.stop(StopReason::Eos);
+        } else if let Some(reason) = criteria_reason {
+            self.stop(reason);
+        } else if max_steps == 0 {
+            self.stop(capped_reason);
+        }
+        let first = (!stopped && steps > 0).then(|| first_token.map(|_| (token, 0)));
+        TokenStream {
+            runner: self,
+            first,
+            current: Some(token),
+            pos,
+            start_pos: pos,
+            end_pos: pos + max_steps,
+            capped_reason,
+            decode_started_at: Instant::now(),
+        }
+    }
+
+    /// like generate_stream, but the tokens are detokenized, matched against the stop strings
+    /// and emitted as the events on a thread of their own, so the decode loop never waits on
+    /// the text. `on_token` is called on that thread with each token. the decode loop runs
+    /// ahead by up to `PIPELINE_DEPTH` tokens, the ones it sampled after a stop string are
+    /// taken back: they're dropped from the KV cache, and the sampler, the grammar and the
+    /// loop watchdog are set back to the token which completed the string. an error of
+    /// `on_token` stops the generation on its token the same way, with `StopReason::Criteria`,
+    /// and it's returned.
+    ///
+    /// the stopping criteria of the runner are checked inline with the decode loop, it fails
+    /// with any of them set, pass the stop strings here instead.
+    pub fn generate_pipelined(
+        &mut self,
+        pos: usize,
+        token: usize,
+        steps: Option<usize>,
+        stop_strings: &[String],
+        on_token: impl FnMut(&TokenEvent) -> Result<()> + Send,
+    ) -> Result<PipelinedOutput> {
+        let healed_text = std::mem::take(&mut self.healed_text);
+        if !self.stopping_criteria.is_empty() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the pipelined generation does not check the stopping criteria, pass the stop \
+                 strings instead",
+            ));
+        }
+        let max_seq = self.context_limit.saturating_sub(pos + 1);
+        let steps = steps.unwrap_or(usize::MAX);
+        let (max_steps, capped_reason) = if steps.saturating_sub(1) > max_seq {
+            (max_seq, StopReason::ContextLength)
+        } else {
+            (steps.saturating_sub(1), StopReason::MaxSteps)
+        };
+
+        self.stop_reason = None;
+        self.recent_tokens.clear();
+        self.recent_tokens.push(token);
+        // the stop token sampled on prefill is not yielded
+        if self.stop_tokens.contains(&token) {
+            self.stop(StopReason::Eos);
+            return Ok(PipelinedOutput::default());
+        }
+        if steps == 0 {
+            self.stop(capped_reason);
+            return Ok(PipelinedOutput::default());
+        }
+
+        let stop_hit = AtomicBool::new(false);
+        let mut snapshots = VecDeque::with_capacity(PIPELINE_DEPTH + 2);
+        let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let detokenizer = Detokenizer::new(
+            self.tokenizer.clone(),
+            stop_strings,
+            self.event_sender.clone(),
+            on_token,
+        )
+        .with_healed_text(healed_text);
+        let (decoded, output) = std::thread::scope(|s| {
+            let stop_hit = &stop_hit;
+            let consumer = s.spawn(move || detokenizer.run(receiver, stop_hit));
+            let decoded = self.decode_ahead(
+                pos,
+                token,
+                pos + max_steps,
+                &sender,
+                stop_hit,
+                &mut snapshots,
+            );
+            // hang up, so the detokenizer ends after the tokens in flight
+            drop(sender);
+            let output = consumer
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            (decoded, output)
+        });
+
+        let (output, detokenized) = output;
+        let reason = match decoded {
+            Ok(reason) => reason,
+            Err(err) => {
+                self.emit_event(GenerationEvent::Error {
+                    message: err.to_string(),
+                });
+                return Err(err);
+            }
+        };
+        if output.stop_string_hit || detokenized.is_err() {
+            // the tokens before the last one are forwarded on the sequential generation, the
+            // ones sampled after it are taken back
+            let n_tokens = output.tokens.len();
+            self.truncate(pos + n_tokens - 1)?;
+            if let Some(snapshot) = snapshots.iter().find(|s| s.n_tokens == n_tokens) {
+                self.restore_sampling(snapshot.clone());
+            }
+        }
+        if let Err(err) = detokenized {
+            self.stop(StopReason::Criteria);
+            return Err(err);
+        }
+        if output.stop_string_hit {
+            self.stop(StopReason::StopString);
+        } else {
+            self.stop(reason.unwrap_or(capped_reason));
+        }
+        Ok(output)
+    }
+
+    // the state the sampling has moved on to after the tokens
+    fn sampling_snapshot(&self, n_tokens: usize) -> SamplingSnapshot {
+        SamplingSnapshot {
+            n_tokens,
+            sampler: self.sampler.state(),
+            grammar: self.grammar.as_ref().map(|(_, state)| *state),
+            recent_tokens: self.recent_tokens.clone(),
+        }
+    }
+
+    fn restore_sampling(&mut self, snapshot: SamplingSnapshot) {
+        // the seeded RNG is replayed to the coins, an unseeded one has nothing to restore
+        if snapshot.sampler.seed.is_some() && snapshot.sampler != self.sampler.state() {
+            let vocab_size = self.conf.vocab_size;
+            self.sampler = Llama2Sampler::from_state(vocab_size, &snapshot.sampler);
+        }
+        if let (Some((_, state)), Some(snapshot)) = (self.grammar.as_mut(), snapshot.grammar) {
+            *state = snapshot;
+        }
+        self.recent_tokens = snapshot.recent_tokens;
+    }
+
+    // forward and sample the tokens until end_pos, handing each one to the detokenizer without
+    // waiting for its text. it returns on the EOS or a loop, the reason is None on reaching
+    // end_pos or once the detokenizer hits a stop string or hangs up.
+    fn decode_ahead(
+        &mut self,
+        start_pos: usize,
+        token: usize,
+        end_pos: usize,
+        sender: &SyncSender<SampledToken>,
+        stop_hit: &AtomicBool,
+        snapshots: &mut VecDeque<SamplingSnapshot>,
+    ) -> Result<Option<StopReason>> {
+        let first = SampledToken {
+            id: token,
+            logprob: None,
+            t_ms: 0.0,
+        };
+        snapshots.push_back(self.sampling_snapshot(1));
+        if sender.send(first).is_err() {
+            return Ok(None);
+        }
+
+        let decode_started_at = Instant::now();
+        let mut current_token = token;
+        for pos in start_pos..end_pos {
+            if stop_hit.load(Ordering::Acquire) {
+                return Ok(None);
+            }
+            let started_at = Instant::now();
+            self.forward(&[current_token], pos)?;
+            self.penalize_loop();
+            let new_token = self.sample_next()?;
+            if self.is_stop_token(new_token) {
+                return Ok(Some(StopReason::Eos));
+            }
+            if self.watch_loop(new_token) {
+                return Ok(Some(StopReason::Loop));
+            }
+
+            let sampled = SampledToken {
+                id: new_token,
+                logprob: self
+                    .event_sender
+                    .is_some()
+                    .then(|| logprob(&self.raw_logits, new_token)),
+                t_ms: started_at.elapsed().as_secs_f64() * 1000.0,
+            };
+            // the detokenizer is behind by at most the tokens in the channel, the older
+            // snapshots are never restored
+            if snapshots.len() == PIPELINE_DEPTH + 2 {
+                snapshots.pop_front();
+            }
+            snapshots.push_back(self.sampling_snapshot(pos - start_pos + 2));
+            if sender.send(sampled).is_err() {
+                return Ok(None);
+            }
+            if pos + 1 < end_pos {
+                self.throttle_decode(&DecodeTiming {
+                    pos,
+                    n_generated: pos - start_pos + 2,
+                    token_elapsed: started_at.elapsed(),
+                    elapsed: decode_started_at.elapsed(),
+                });
+            }
+            current_token = new_token;
+        }
+        Ok(None)
+    }
+
+    // check the piece from piece_start of the generated text against the stopping criteria,
+    // it's skipped without any criteria
+    fn check_stopping_criteria(
+        &mut self,
+        token: usize,
+        piece_start: usize,
+        n_generated: usize,
+    ) -> Option<StopReason> {
+        if self.stopping_criteria.is_empty() {
+            return None;
+        }
+        let ctx = StoppingContext {
+            token,
+            piece: &self.generated_text[piece_start..],
+            text: &self.generated_text,
+            n_generated,
+            logits: &self.raw_logits,
+        };
+        self.stopping_criteria.should_stop(&ctx)
+    }
+
+    // the EOS stops the generation unless the EOS policy is to continue
+    pub(crate) fn is_stop_token(&self, token: usize) -> bool {
+        let is_eos = token == self.tokenizer.eos_token() && self.eos_policy == EosPolicy::Stop;
+        is_eos || self.stop_tokens.contains(&token)
+    }
+
+    // record the generated token, returns true if the generation is stuck in a cycle and
+    // should be aborted
+    fn watch_loop(&mut self, token: usize) -> bool {
+        let watchdog = match self.loop_watchdog {
+            Some(watchdog) => watchdog,
+            None => return false,
+        };
+        self.recent_tokens.push(token);
+        let window = watchdog.window();
+        if self.recent_tokens.len() > window * 2 {
+            self.recent_tokens
+                .drain(..self.recent_tokens.len() - window);
+        }
+        watchdog.action() == LoopAction::Abort && watchdog.detect(&self.recent_tokens).is_some()
+    }
+
+    // push the sampler out of the cycle by penalizing the token which would continue it
+    fn penalize_loop(&mut self) {
+        let watchdog = match self.loop_watchdog {
+            Some(watchdog) => watchdog,
+            None => return,
+        };
+        if let LoopAction::Penalize(penalty) = watchdog.action() {
+            if let Some(next) = watchdog.next_in_cycle(&self.recent_tokens) {
+                self.logits[next] -= penalty;
+            }
+        }
+    }
+
+    // sample the next token from the logits of the last forward, and emit the TokenGenerated
+    // event if there's a subscriber.
+    fn sample_and_emit(&mut self, started_at: Instant) -> Result<usize> {
+        let token = self.sample_next()?;
+        if self.event_sender.is_none() {
+            return Ok(token);
+        }
+        let text = self.tokenizer.decode(token)?;
+        self.emit_event(GenerationEvent::TokenGenerated {
+            id: token,
+            text,
+            logprob: logprob(&self.raw_logits, token),
+            t_ms: started_at.elapsed().as_secs_f64() * 1000.0,
+        });
+        Ok(token)
+    }
+
+    // sample the next token from the logits of the last forward, the raw logits are kept in
+    // raw_logits if there's a subscriber or any stopping criteria
+    fn sample_next(&mut self) -> Result<usize> {
+        let sampler = self.sampler.clone();
+        if let Some((grammar, state)) = &self.grammar {
+            grammar.mask_logits(state, &mut self.logits);
+        }
+        if let Some(candidates) = self.heal_candidates.take() {
+            for (token, logit) in self.logits.iter_mut().enumerate() {
+                if candidates.binary_search(&token).is_err() {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+        if self.event_sender.is_none() && self.stopping_criteria.is_empty() {
+            let token = sampler.sample(&mut self.logits)?;
+            self.advance_grammar(token)?;
+            return Ok(token);
+        }
+
+        // the sampler modifies the logits in place, keep the raw ones for the logprob and the
+        // stopping criteria
+        self.raw_logits.clear();
+        self.raw_logits.extend_from_slice(&self.logits);
+        let token = sampler.sample(&mut self.logits)?;
+        self.advance_grammar(token)?;
+        Ok(token)
+    }
+
+    fn advance_grammar(&mut self, token: usize) -> Result<()> {
+        match &mut self.grammar {
+            Some((grammar, state)) => grammar.advance(state, token),
+            None => Ok(()),
+        }
+    }
+
+    // simplify the test cases
+    pub fn prefill_and_generate(
+        &'a mut self,
+        prompt: &str,
+        steps: usize,
+    ) -> Result<impl Iterator<Item = Result<String>> + '_> {
+        let (pos, _prev_token, token) = self.prefill(prompt, true, false)?;
+        Ok(self.generate(pos, token, Some(steps)))
+    }
+
+    pub fn forward(&mut self, tokens: &[usize], pos: usize) -> Result<&mut [f32]> {
+        self.forward_logits(tokens, pos, None)?;
+        if !self.ensemble.is_empty() {
+            self.fuse_ensemble(tokens, pos)?;
+        }
+        Ok(&mut self.logits)
+    }
+
+    // forward the tokens on the members of the ensemble, and fuse their logits into this one
+    fn fuse_ensemble(&mut self, tokens: &[usize], pos: usize) -> Result<()> {
+        scale_log_softmax(&mut self.logits, self.ensemble_weight);
+        for (member, weight) in self.ensemble.iter_mut() {
+            let logits = member.forward(tokens, pos)?;
+            add_scaled_log_softmax(&mut self.logits, logits, *weight);
+        }
+        Ok(())
+    }
+
+    /// forward the tokens at the given positions instead of the ones after pos, the positions
+    /// only take effect on the rotary embedding, the tokens are still appended to the KV cache
+    /// and attend to all the tokens before them. it allows the callers which shift the cache or
+    /// reuse a prefix to keep the original positions, like forwarding the tokens at 100, 101
+    /// after a cache of 10 tokens. returns the logits of the last token. cpu only.
+    pub fn forward_at_positions(
+        &mut self,
+        tokens: &[usize],
+        positions: &[usize],
+    ) -> Result<&mut [f32]> {
+        self.ensure_not_offloaded("forward_at_positions")?;
+        self.ensure_no_ensemble("forward_at_positions")?;
+        if tokens.is_empty() || tokens.len() != positions.len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "{} tokens are given {} positions",
+                    tokens.len(),
+                    positions.len()
+                ),
+            ));
+        }
+        self.forward_logits(tokens, positions[0], Some(positions))?;
+        Ok(&mut self.logits)
+    }
+
+    fn forward_logits(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        positions: Option<&[usize]>,
+    ) -> Result<()> {
+        if self.forward_offload.is_some() {
+            match self.forward_offloaded(tokens, pos) {
+                Ok(()) => return Ok(()),
+                Err(err) if is_backend_error(&err) => self.fall_back(err, pos)?,
+                Err(err) => return Err(err),
+            }
+        }
+        if self.op_tracer.is_none() {
+            return self.forward_logits_on_device(tokens, pos, positions);
+        }
+
+        // the backends panic on some failures like an out of bounds index, the panic is
+        // reported like an error
+        let forwarded = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            self.forward_logits_on_device(tokens, pos, positions)
+        }));
+        let (result, panicked) = match forwarded {
+            Ok(result) => (result, false),
+            Err(panic) => {
+                let message = format!("the forward panicked: {}", panic_message(&*panic));
+                (Err(Error::new(ErrorKind::Unexpected, message)), true)
+            }
+        };
+        if let (Err(err), Some(tracer)) = (&result, &self.op_tracer) {
+            self.failure_report = Some(tracer.report::<T>(err, tokens, pos, panicked));
+        }
+        result
+    }
+
+    fn forward_logits_on_device(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        positions: Option<&[usize]>,
+    ) -> Result<()> {
+        let _t = self.metrics.forward_walltime.track();
+        let _thread_limit = self.max_threads().map(ThreadNumLimitGuard::new);
+
+        let x = self.forward_hidden(tokens, pos, positions, None)?;
+
+        let mut x_final = T::alloc(
+            &[self.conf.embedding_dim],
+            GGMLType::F32,
+            self.device.clone(),
+        )?;
+        x_final.copy_rows_from(&x, &[tokens.len() - 1])?;
+        self.trace_op("output", None, &[("x", &x_final)]);
+
+        // only the rows of the output vocab are projected, the others are -inf
+        if let Some((tokens, rows)) = &self.output_vocab {
+            let logits = rows.matmul_vec(&x_final)?; // (n_tokens, )
+            let mut buf = vec![0.0; tokens.len()];
+            logits.export(&mut buf)?;
+            self.logits.fill(f32::NEG_INFINITY);
+            for (token, logit) in tokens.iter().zip(buf) {
+                self.logits[*token] = logit;
+            }
+            return Ok(());
+        }
+
+        // classifier into logits
+        // TODO: it'd be make sense to reuse the same buffer for the logits
+        let output_weight = self
+            .weights
+            .output_weight
+            .as_ref()
+            .unwrap_or_else(|| &self.weights.token_embed);
+        let logits = output_weight.matmul_vec(&x_final)?; // (batch_size, vocab_size),
+        logits.export(&mut self.logits)?;
+        Ok(())
+    }
+
+    fn forward_offloaded(&mut self, tokens: &[usize], pos: usize) -> Result<()> {
+        let offload = self.forward_offload.as_mut().unwrap();
+        // the GPU backends panic on some failures like losing the device, the offload is
+        // dropped after the failure anyway
+        let forwarded =
+            std::panic::catch_unwind(AssertUnwindSafe(|| offload.offload_forward(tokens, pos)));
+        let logits = forwarded.unwrap_or_else(|panic| {
+            Err(Error::new(
+                ErrorKind::Unexpected,
+                format!("the offloaded forward panicked: {}", panic_message(&*panic)),
+            ))
+        })?;
+        if logits.len() != self.logits.len() {
+            return Err(Error::new(
+                ErrorKind::Unexpected,
+                "the offloaded forward returns the logits of another vocab",
+            ));
+        }
+        self.offloaded_tokens.truncate(pos);
+        self.offloaded_tokens.extend_from_slice(tokens);
+        self.offloaded_prefix = self.offloaded_prefix.min(pos);
+        self.logits.copy_from_slice(&logits);
+        if let Some((tokens, _)) = &self.output_vocab {
+            let mut masked = vec![f32::NEG_INFINITY; self.logits.len()];
+            tokens.iter().for_each(|t| masked[*t] = self.logits[*t]);
+            self.logits = masked;
+        }
+        Ok(())
+    }
+
+    // drop the failed offload, and forward the tokens before pos again on this runner, except
+    // the prefix which is in its KV cache already
+    fn fall_back(&mut self, cause: Error, pos: usize) -> Result<()> {
+        self.emit_event(GenerationEvent::BackendFallback {
+            message: cause.to_string(),
+        });
+        self.forward_offload = None;
+        self.fallback_cause = Some(cause);
+
+        let tokens = std::mem::take(&mut self.offloaded_tokens);
+        let end = pos.min(tokens.len());
+        let prefix = std::mem::take(&mut self.offloaded_prefix).min(end);
+        self.truncate(prefix)?;
+        for chunk in tokens[prefix..end].chunks(self.prefill_chunk_size) {
+            let pos = self.kv_cache_len();
+            self.forward(chunk, pos)?;
+        }
+        Ok(())
+    }
+
+    // the ops which touch the KV cache directly do not run on the offload
+    fn ensure_not_offloaded(&self, op: &str) -> Result<()> {
+        if self.forward_offload.is_some() {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!("{} is not supported while the forwards are offloaded", op),
+            ));
+        }
+        self.ensure_not_split(op)
+    }
+
+    // the KV cache of the offloaded layers is on the other devices
+    fn ensure_not_split(&self, op: &str) -> Result<()> {
+        if !self.layer_offloads.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!(
+                    "{} is not supported while the layers are split over the devices",
+                    op
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // the members of the ensemble only follow the forwards and the truncations
+    fn ensure_no_ensemble(&self, op: &str) -> Result<()> {
+        if !self.ensemble.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!("{} is not supported on an ensemble", op),
+            ));
+        }
+        Ok(())
+    }
+
+    // the hidden states of the tokens after the final norm, in (n_batch, embed_dim). the tokens
+    // are a sequence from pos, or a tree whose roots follow pos - 1 if the parents are given.
+    // the positions override the ones of the rotary embedding if given.
+    fn forward_hidden(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        positions: Option<&[usize]>,
+        parents: Option<&[Option<usize>]>,
+    ) -> Result<T> {
+        if let Some(maps) = &mut self.attention_maps {
+            maps.record_tokens(tokens);
+        }
+        self.forward_decoder(tokens, pos, positions, parents)
+    }
+
+    /// evaluate a tree of candidate tokens after the KV cache in a single batched forward,
+    /// like the drafts of the speculative decoding, the beams, or the plans of an agent which
+    /// share the prompt. parents[i] is the index of the parent of the token i, which must be
+    /// before it, or None if the token follows the cached tokens directly. each token only
+    /// attends to the cached tokens and its ancestors, and is placed at the position of its
+    /// depth. returns the logits of all the tokens in (n_tokens, vocab_size). all the tokens
+    /// are left in the KV cache, call commit_tree_path to keep one branch of them. cpu only.
+    pub fn forward_tree(
+        &mut self,
+        tokens: &[usize],
+        parents: &[Option<usize>],
+    ) -> Result<Vec<f32>> {
+        self.ensure_no_ensemble("forward_tree")?;
+        if let Some(offload) = self.forward_offload.as_mut() {
+            let pos = self.offloaded_tokens.len();
+            match offload.offload_forward_tree(tokens, parents) {
+                Ok(logits) => {
+                    // the branches are not forwarded again on falling back, commit_tree_path
+                    // keeps the head path of them or forwards the path again
+                    self.offloaded_tokens.extend_from_slice(tokens);
+                    return Ok(logits);
+                }
+                Err(err) if is_backend_error(&err) => self.fall_back(err, pos)?,
+                Err(err) => return Err(err),
+            }
+        }
+        let _t = self.metrics.forward_walltime.track();
+        let _thread_limit = self.niceness.max_threads.map(ThreadNumLimitGuard::new);
+        if tokens.is_empty() || tokens.len() != parents.len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "a tree of {} tokens has {} parents",
+                    tokens.len(),
+                    parents.len()
+                ),
+            ));
+        }
+        let pos = self.kv_cache_len();
+        if pos + tokens.len() > self.context_limit {
+            return Err(Error::new(
+                ErrorKind::ContextOverflow,
+                format!(
+                    "the tree has {} tokens, but only {} tokens left in the context",
+                    tokens.len(),
+                    self.context_limit - pos
+                ),
+            ));
+        }
+
+        let positions = tree_positions(pos, parents);
+        let x = self.forward_hidden(tokens, pos, Some(&positions), Some(parents))?;
+        let output_weight = self
+            .weights
+            .output_weight
+            .as_ref()
+            .unwrap_or_else(|| &self.weights.token_embed);
+        let logits = output_weight.matmul_vec(&x)?; // (n_tokens, vocab_size)
+        let mut buf = vec![0.0; tokens.len() * self.conf.vocab_size];
+        logits.export(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// keep the tokens on the path of the tree evaluated by forward_tree in the KV cache, and
+    /// drop the other branches. base_pos is the length of the KV cache before forward_tree, the
+    /// path is the indices of the tokens from a root down to a node. if the path is not the
+    /// head of the tree like [0, 1, 2], its tokens are forwarded again to be placed after the
+    /// prefix, which also leaves the logits of the last token for sampling.
+    pub fn commit_tree_path(
+        &mut self,
+        base_pos: usize,
+        tokens: &[usize],
+        parents: &[Option<usize>],
+        path: &[usize],
+    ) -> Result<()> {
+        let is_path = path.iter().enumerate().all(|(i, node)| {
+            *node < parents.len() && parents[*node] == i.checked_sub(1).map(|i| path[i])
+        });
+        if !is_path || base_pos + tokens.len() != self.kv_cache_len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("{:?} is not a path of the last evaluated tree", path),
+            ));
+        }
+
+        if path.iter().enumerate().all(|(i, node)| i == *node) {
+            return self.truncate(base_pos + path.len());
+        }
+        self.truncate(base_pos)?;
+        if !path.is_empty() {
+            let path_tokens = path.iter().map(|i| tokens[*i]).collect::<Vec<_>>();
+            self.forward(&path_tokens, base_pos)?;
+        }
+        Ok(())
+    }
+
+    /// embed the text into a vector by mean pooling the hidden states of all the tokens
+    /// after the final norm. it runs on the KV cache of the runner, so the runner must have
+    /// no session in progress, the KV cache is cleared after the embedding.
+    pub fn embed(&mut self, text: &str) -> Result<Vec<f32>> {
+        if let Some(offload) = self.forward_offload.as_mut() {
+            let pos = self.offloaded_tokens.len();
+            match offload.offload_embed(text) {
+                Ok(embedding) => return Ok(embedding),
+                Err(err) if is_backend_error(&err) => self.fall_back(err, pos)?,
+                Err(err) => return Err(err),
+            }
+        }
+        if self.kv_cache_len() > 0 {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "embed needs an empty KV cache, but {} tokens of a session are in it, reset \
+                     the runner or embed on another runner",
+                    self.kv_cache_len()
+                ),
+            ));
+        }
+        let tokens = self.tokenizer.encode(text, true, false)?;
+        if tokens.len() > self.context_limit {
+            return Err(Error::new(
+                ErrorKind::ContextOverflow,
+                format!(
+                    "the text has {} tokens, exceeds the context limit {}",
+                    tokens.len(),
+                    self.context_limit
+                ),
+            ));
+        }
+
+        let embed_dim = self.conf.embedding_dim;
+        let mut embedding = vec![0.0; embed_dim];
+        let mut hidden = vec![0.0; embed_dim];
+        for (pos, token) in tokens.iter().enumerate() {
+            let x = self.forward_hidden(&[*token], pos, None, None)?;
+            x.export(&mut hidden)?;
+            embedding
+                .iter_mut()
+                .zip(hidden.iter())
+                .for_each(|(e, h)| *e += h);
+        }
+        self.reset()?;
+
+        embedding.iter_mut().for_each(|e| *e /= tokens.len() as f32);
+        Ok(embedding)
+    }
+
+    /// like `embed`, but quantize the embedding into int8 with a scale, for storage.
+    pub fn embed_int8(&mut self, text: &str) -> Result<Int8Embedding> {
+        let embedding = self.embed(text)?;
+        Ok(Int8Embedding::quantize(&embedding))
+    }
+
+    // the cap of the threads from the niceness and the throttle hook, the lower one wins
+    fn max_threads(&self) -> Option<usize> {
+        match (self.niceness.max_threads, self.throttle.max_threads) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // ask the throttle hook what to do before the next token
+    fn throttle_decode(&mut self, timing: &DecodeTiming) {
+        if let Some(hook) = &self.throttle_hook {
+            self.throttle = hook.throttle(timing);
+            match self.throttle.pause {
+                Some(pause) if pause.is_zero() => std::thread::yield_now(),
+                Some(pause) => std::thread::sleep(pause),
+                None => {}
+            }
+        }
+    }
+
+    // record the step the forward enters with its inputs for the failure report, it's skipped
+    // unless the failure reports are enabled
+    fn trace_op(&mut self, op: &'static str, layer: Option<usize>, inputs: &[(&str, &T)]) {
+        if let Some(tracer) = &mut self.op_tracer {
+            tracer.enter(op, layer, inputs);
+        }
+    }
+
+    fn pause_between_layers(&self, layer: usize) {
+        match self.niceness.layer_pause {
+            Some(_) if layer == 0 => {}
+            Some(pause) if pause.is_zero() => std::thread::yield_now(),
+            Some(pause) => std::thread::sleep(pause),
+            None => {}
+        }
+    }
+
+    // the llama decoder, with the changes of the architecture in its forward spec, like the
+    // neox rope, the GeLU and the scaled embedding of Gemma.
+    fn forward_decoder(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        positions: Option<&[usize]>,
+        parents: Option<&[Option<usize>]>,
+    ) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_batch = tokens.len();
+
+        // copy the token embedding into x
+        self.trace_op("embedding", None, &[]);
+        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+        if self.conf.forward.scale_embedding {
+            x = x.scale_inplace((embed_dim as f32).sqrt())?;
+            x = x.with_name("scaled_embed".to_string());
+        }
+
+        x = self.forward_layers(x, pos, positions, parents)?;
+        if !self.layer_offloads.is_empty() {
+            self.split_len = pos + n_batch;
+        }
+
+        // final rmsnorm
+        self.trace_op("final_rmsnorm", None, &[("x", &x)]);
+        x = {
+            x = rms_norm(x, self.conf.rms_norm_eps)?;
+            x = x.mul_inplace(&self.weights.rms_final_weight)?;
+            x.with_name(format!("final_rmsnorm:{}", pos))
+        };
+
+        Ok(x)
+    }
+
+    // forward the hidden states in (n_batch, embed_dim) through all the layers, the offloaded
+    // ranges of them are forwarded on their devices
+    fn forward_layers(
+        &mut self,
+        mut x: T,
+        pos: usize,
+        positions: Option<&[usize]>,
+        parents: Option<&[Option<usize>]>,
+    ) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_heads = self.conf.n_heads;
+        let n_kv_heads = self.conf.n_kv_heads;
+        let head_dim = self.conf.head_size();
+        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
+        let rope_theta = self.conf.rope_theta;
+        let rope_freq_scale = self.conf.rope_freq_scale;
+        let spec = self.conf.forward;
+        let rope_mode = spec.rope_mode;
+        let n_batch = x.shape()[0];
+
+        for l in 0..self.conf.n_layers {
+            if let Some(i) = self.layer_offloads.iter().position(|(r, _)| r.contains(&l)) {
+                if self.layer_offloads[i].0.start == l {
+                    x = self.forward_offloaded_layers(i, x, pos, positions, parents)?;
+                }
+                continue;
+            }
+            self.pause_between_layers(l);
+            let x_attn_orig = x.dup()?;
+
+            // attention rnsnorm
+            self.trace_op("attn_rmsnorm", Some(l), &[("x", &x)]);
+            x = {
+                x = rms_norm(x, self.conf.rms_norm_eps)?;
+                x = x.mul_inplace(&self.weights.rms_att_weight[l])?;
+                x = x.with_name(format!("attn_rmsnorm:{}:{}", l, pos));
+                x
+            };
+
+            // matmul qkv for every head
+            self.trace_op("qkv", Some(l), &[("x", &x)]);
+            let (q, k, v) = self.forward_qkv(&x, l)?;
+
+            // ROPE
+            self.trace_op("rope", Some(l), &[("q", &q), ("k", &k)]);
+            let (q, k) = {
+                let q = q.reshape(&[n_batch, n_heads, head_dim])?;
+                let k = k.reshape(&[n_batch, n_kv_heads, head_dim])?;
+
+                let (theta, scale) = (rope_theta, rope_freq_scale);
+                let q = rope(q, rope_mode, pos, positions, rope_dim, theta, scale)?;
+                let k = rope(k, rope_mode, pos, positions, rope_dim, theta, scale)?;
+                (q, k)
+            };
+
+            self.trace_op("attention", Some(l), &[("q", &q), ("k", &k), ("v", &v)]);
+            x = self.forward_multi_query_attention(
+                q, k, v, l, parents, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
+            )?;
+            x = x.with_name(format!("attn_out:{}:{}", l, pos));
+
+            // residual connection back into x
+            x = x.add_inplace(&x_attn_orig)?;
+
+            // ffn
+            self.trace_op("ffn", Some(l), &[("x", &x)]);
+            x = self.forward_ffn(x, l, pos, spec.activation)?;
+            x = x.with_name(format!("ffn_out:{}:{}", l, pos));
+        }
+        Ok(x)
+    }
+
+    // move the hidden states to the device of the offload and back after its layers, the
+    // offloads only forward the sequences of tokens
+    fn forward_offloaded_layers(
+        &mut self,
+        i: usize,
+        x: T,
+        pos: usize,
+        positions: Option<&[usize]>,
+        parents: Option<&[Option<usize>]>,
+    ) -> Result<T> {
+        let device = self.device.clone();
+        let (layers, offload) = &mut self.layer_offloads[i];
+        if positions.is_some() || parents.is_some() {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!(
+                    "the layers {}-{} are offloaded, which only forward the tokens in sequence",
+                    layers.start,
+                    layers.end - 1
+                ),
+            ));
+        }
+        let mut hidden = vec![0.0; x.shape().iter().product()];
+        x.export(&mut hidden)?;
+        let hidden = offload.offload_layers(&hidden, pos)?;
+        let mut x = T::alloc(x.shape(), GGMLType::F32, device)?;
+        x.import(&hidden)?;
+        Ok(x.with_name(format!("offload_out:{}:{}", layers.end - 1, pos)))
+    }
+
+    fn forward_qkv(&self, x: &T, l: usize) -> Result<(T, T, T)> {
+        // wq: (embed_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, embed_dim, )
+        // wk: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+        // wv: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+        let (q, k, v) = match self.weights.wqkv.get(l) {
+            Some(wqkv) => {
+                // wqkv: (q_dim + 2 * kv_dim, embed_dim) @ x => (n_batch, q_dim + 2 * kv_dim)
+                let q_dim = self.conf.n_heads * self.conf.head_size();
+                let kv_dim = self.conf.kv_dim();
+                let qkv = wqkv.matmul_vec(x)?;
+                let mut chunks = qkv.split_last_dim(&[q_dim, kv_dim, kv_dim])?.into_iter();
+                let q = chunks.next().unwrap();
+                let k = chunks.next().unwrap();
+                let v = chunks.next().unwrap();
+                (q, k, v)
+            }
+            None => {
+                let q = self.weights.wq[l].matmul_vec(x)?;
+                let k = self.weights.wk[l].matmul_vec(x)?;
+                let v = self.weights.wv[l].matmul_vec(x)?;
+                (q, k, v)
+            }
+        };
+
+        // the biases are broadcasted over the batch, like Qwen2
+        if self.weights.bq.is_empty() {
+            return Ok((q, k, v));
+        }
+        let q = q.add_inplace(&self.weights.bq[l])?;
+        let k = k.add_inplace(&self.weights.bk[l])?;
+        let v = v.add_inplace(&self.weights.bv[l])?;
+        Ok((q, k, v))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn forward_multi_query_attention(
+        &mut self,
+        q: T,
+        k: T,
+        v: T,
+        l: usize,
+        parents: Option<&[Option<usize>]>,
+        n_kv_heads: usize,
+        n_heads: usize,
+        embed_dim: usize,
+        head_dim: usize,
+        n_batch: usize,
+    ) -> Result<T> {
+        // save to kv cache in layout of (n_kv_heads, n_batch, head_dim)
+        {
+            let k = k
+                .reshape(&[n_batch, n_kv_heads, head_dim])?
+                .transpose(&[1, 0, 2])?;
+            let v = v
+                .reshape(&[n_batch, n_kv_heads, head_dim])?
+                .transpose(&[1, 0, 2])?;
+
+            self.kv_cache.append(l, &k, &v)?;
+        };
+
+        let records_maps = self
+            .attention_maps
+            .as_ref()
+            .is_some_and(|m| m.records_layer(l));
+        let chunk_len = match self.attention_kernel {
+            AttentionKernel::Chunked(chunk_len) if parents.is_none() && !records_maps => {
+                chunk_len.max(1)
+            }
+            _ => n_batch,
+        };
+        if chunk_len < n_batch {
+            let x_with_attn =
+                self.forward_chunked_attention(q, l, n_heads, head_dim, n_batch, chunk_len)?;
+            return self.weights.wo[l].matmul_vec(&x_with_attn);
+        }
+        if self.attention_kernel == AttentionKernel::Flash && parents.is_none() && !records_maps {
+            let x_with_attn = self.forward_flash_attention(q, l, n_heads, head_dim, n_batch)?;
+            return self.weights.wo[l].matmul_vec(&x_with_attn);
+        }
+
+        // multi query attention
+        let x = {
+            // - q: [n_batch, n_head, head_size]
+            // - q = q.transpose(1, 0, 2).contiguous => [n_head, n_batch, head_size]
+            let q = q
+                .reshape(&[n_batch, n_heads, head_dim])?
+                .transpose(&[1, 0, 2])?
+                .contiguous()?
+                .div_scalar_inplace((head_dim as f32).sqrt())?;
+
+            // get attention scores:
+            // - key_cache: [n_kv_head, seq, head_size].transpose(0, 2, 1) => [n_kv_head, head_size, seq]
+            // - attn_scores = batch_matmul(q, key_cache) => [n_head, n_batch, seq]
+            // - attn_scores = softmax(attn_score, axis=2) => [n_head, n_batch, seq]
+            let (k_cache, v_cache) = self.kv_cache.take(l);
+            let k_cache_strider_orig = k_cache.strider().clone();
+            let k_cache = k_cache.transpose(&[0, 2, 1])?; // (n_kv_heads, head_size, seq)
+            // (n_head, 1, head_size) @ (n_kv_heads, head_size, seq)
+            let attn = q.batch_matmul(&k_cache)?; // (n_head, n_batch, seq)
+            // the tokens in the batch should not attend to the tokens after them, or to the
+            // other branches of the tree
+            let attn = match parents {
+                Some(parents) => attn.tree_mask_inplace(parents)?,
+                None if n_batch > 1 => attn.causal_mask_inplace()?,
+                None => attn,
+            };
+            let attn = softmax(attn, 2)?;
+            if let Some(maps) = self.attention_maps.as_mut().filter(|m| m.records_layer(l)) {
+                let mut buf = vec![0.0; attn.shape().iter().product()];
+                attn.export(&mut buf)?;
+                maps.record(l, &buf, n_heads, n_batch);
+            }
+            let k_cache = k_cache.with_strider(k_cache_strider_orig)?;
+
+            // - val_cache: [n_kv_head, seq, head_size]
+            // - out = batch_matmul(atten_scores, val_cache) => [n_head, n_batch, head_size]
+            // - out = out.transpose(1, 0, 2).contiguous => [n_batch, n_head, head_size]
+            // - out = out.reshape(n_batch, embed_dim)
+            let v_cache_strider_orig = v_cache.strider().clone();
+            // (n_head, n_batch, seq) @ (n_kv_heads, seq, head_dim) => (n_head, n_batch, head_dim)
+            let x_with_attn = attn.batch_matmul(&v_cache)?; // (n_heads, n_batch, head_dim)
+            let x_with_attn = if n_batch == 1 {
+                // TODO: this specialase might be able to unify with the general case
+                x_with_attn.reshape(&[n_batch, embed_dim])?
+            } else {
+                x_with_attn
+                    .transpose(&[1, 0, 2])? // (n_batch, n_heads, head_dim)
+                    .contiguous()?
+                    .reshape(&[n_batch, embed_dim])?
+            };
+            let v_cache = v_cache.with_strider(v_cache_strider_orig)?;
+            self.kv_cache.put(l, k_cache, v_cache);
+
+            // final matmul to get the output of the attention
+            self.weights.wo[l].matmul_vec(&x_with_attn)?
+        };
+        Ok(x)
+    }
+
+    // the attention of the queries in chunks of rows, each chunk attends to the KV cache up to
+    // its last token, so the causal mask of a chunk is the same as the one of a batch. returns
+    // the output of the heads in (n_batch, embed_dim) before the wo projection.
+    fn forward_chunked_attention(
+        &mut self,
+        q: T,
+        l: usize,
+        n_heads: usize,
+        head_dim: usize,
+        n_batch: usize,
+        chunk_len: usize,
+    ) -> Result<T> {
+        let embed_dim = n_heads * head_dim;
+        let q = q.reshape(&[n_batch, embed_dim])?;
+        let (mut k_cache, mut v_cache) = self.kv_cache.take(l);
+        let k_cache_strider_orig = k_cache.strider().clone();
+        let v_cache_strider_orig = v_cache.strider().clone();
+        let n_past = k_cache.shape()[1] - n_batch;
+
+        let mut x =
+            T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?.resize(0, 0)?;
+        for start in (0..n_batch).step_by(chunk_len) {
+            let end = (start + chunk_len).min(n_batch);
+            let n_chunk = end - start;
+            let mut q_chunk = T::alloc(&[n_chunk, embed_dim], GGMLType::F32, self.device.clone())?;
+            q_chunk.copy_rows_from(&q, &(start..end).collect::<Vec<_>>())?;
+            let q_chunk = q_chunk
+                .reshape(&[n_chunk, n_heads, head_dim])?
+                .transpose(&[1, 0, 2])?
+                .contiguous()?
+                .div_scalar_inplace((head_dim as f32).sqrt())?;
+
+            // the chunk is the last n_chunk tokens of the cache up to its end
+            let k = k_cache.narrow(1, n_past + end)?.transpose(&[0, 2, 1])?;
+            let attn = q_chunk.batch_matmul(&k)?; // (n_head, n_chunk, n_past + end)
+            k_cache = k.with_strider(k_cache_strider_orig.clone())?;
+            let attn = if n_chunk > 1 {
+                attn.causal_mask_inplace()?
+            } else {
+                attn
+            };
+            let attn = softmax(attn, 2)?;
+
+            let v = v_cache.narrow(1, n_past + end)?;
+            let x_chunk = attn.batch_matmul(&v)?; // (n_heads, n_chunk, head_dim)
+            v_cache = v.with_strider(v_cache_strider_orig.clone())?;
+            let x_chunk = x_chunk
+                .transpose(&[1, 0, 2])?
+                .contiguous()?
+                .reshape(&[n_chunk, embed_dim])?;
+            x.concatenate(&x_chunk, 0)?;
+        }
+        self.kv_cache.put(l, k_cache, v_cache);
+        Ok(x)
+    }
+
+    // the fused attention of the queries over the KV cache, it takes the queries and returns
+    // the output of the heads in (n_batch, embed_dim) before the wo projection, no transpose is
+    // needed in between.
+    fn forward_flash_attention(
+        &mut self,
+        q: T,
+        l: usize,
+        n_heads: usize,
+        head_dim: usize,
+        n_batch: usize,
+    ) -> Result<T> {
+        let q = q.reshape(&[n_batch, n_heads, head_dim])?;
+        let (k_cache, v_cache) = self.kv_cache.take(l);
+        let x = q.flash_attention(&k_cache, &v_cache, 1.0 / (head_dim as f32).sqrt());
+        self.kv_cache.put(l, k_cache, v_cache);
+        x?.reshape(&[n_batch, n_heads * head_dim])
+    }
+
+    fn forward_ffn(&self, mut x: T, l: usize, _pos: usize, activation: Activation) -> Result<T> {
+        // save for redidual connection
+        let x_orig_ffn = x.dup()?; // (n_batch, embed_dim)
+
+        // ffn rmsnorm
+        x = {
+            x = rms_norm(x, 1e-5)?;
+            x = x.mul_inplace(&self.weights.rms_ffn_weight[l])?;
+            x
+        };
+
+        if self.weights.is_moe() {
+            x = self.forward_moe(&x, l, activation)?;
+            return x.add_inplace(&x_orig_ffn);
+        }
+
+        // Now for FFN in PyTorch we have: self.down_proj(F.silu(self.gate_proj(x)) * self.up_proj(x))
+        // first calculate self.w1(x) and self.w3(x)
+        // w1: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
+        // w3: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
+        let mut h1 = self.weights.ffn_gate_weight[l].matmul_vec(&x)?;
+        let h2 = self.weights.ffn_up_weight[l].matmul_vec(&x)?;
+
+        // F.silu; silu(x)=x*σ(x),where σ(x) is the logistic sigmoid
+        h1 = match activation {
+            Activation::SiLU => silu(h1)?,
+            Activation::GeLU => gelu(h1)?,
+        };
+
+        // elementwise multiply with w3(x)
+        h1 = h1.mul_inplace(&h2)?;
+
+        // final matmul to get the output of the ffn, the rows of the inactive neurons are
+        // skipped on the sparse ffn
+        let w_down = &self.weights.ffn_down_weight[l];
+        let x_sparse = match &self.sparse_ffn {
+            Some(sparse_ffn) => {
+                let down_rows = &self.weights.sparse_down_rows;
+                sparse_ffn.forward_down(w_down, &h1, down_rows, l, &self.device)?
+            }
+            None => None,
+        };
+        x = match x_sparse {
+            Some(x) => x,
+            None => w_down.matmul_vec(&h1)?, // (n_batch, embed_dim)
+        };
+
+        // residual connection
+        x = x.add_inplace(&x_orig_ffn)?;
+        Ok(x)
+    }
+
+    // the FFN of a mixture-of-experts layer like Mixtral: each token is routed to the top
+    // experts on the router logits, and the outputs of the experts are summed in their gate
+    // weights. each expert forwards the rows of the tokens routed to it in a batch.
+    fn forward_moe(&self, x: &T, l: usize, activation: Activation) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_experts = self.conf.n_experts;
+        let n_used = self.conf.n_experts_used;
+        let n_batch = x.shape()[0];
+
+        // (n_experts, embed_dim) @ x (n_batch, embed_dim) => (n_batch, n_experts)
+        let router_logits = self.weights.ffn_gate_inp[l].matmul_vec(x)?;
+        let mut logits = vec![0.0; n_batch * n_experts];
+        router_logits.export(&mut logits)?;
+        let routes = route_tokens(&logits, n_experts, n_used)?;
+
+        // the weights of the experts the next token likely takes on this layer are read in
+        // while the current experts run, instead of stalling the next token on the page faults
+        for e in predict_next_experts(&logits, n_experts, n_used, n_used) {
+            self.weights.ffn_gate_exps[l][e].prefetch()?;
+            self.weights.ffn_up_exps[l][e].prefetch()?;
+            self.weights.ffn_down_exps[l][e].prefetch()?;
+        }
+
+        let mut out = vec![0.0; n_batch * embed_dim];
+        let mut buf = vec![];
+        for (e, routed) in routes.iter().enumerate() {
+            if routed.is_empty() {
+                continue;
+            }
+            let rows = routed.iter().map(|(row, _)| *row).collect::<Vec<_>>();
+            let mut x_expert =
+                T::alloc(&[rows.len(), embed_dim], GGMLType::F32, self.device.clone())?;
+            x_expert.copy_rows_from(x, &rows)?;
+
+            let mut h1 = self.weights.ffn_gate_exps[l][e].matmul_vec(&x_expert)?;
+            let h2 = self.weights.ffn_up_exps[l][e].matmul_vec(&x_expert)?;
+            h1 = match activation {
+                Activation::SiLU => silu(h1)?,
+                Activation::GeLU => gelu(h1)?,
+            };
+            h1 = h1.mul_inplace(&h2)?;
+            let y = self.weights.ffn_down_exps[l][e].matmul_vec(&h1)?;
+
+            // scatter the outputs back to the rows of their tokens in the gate weights
+            buf.resize(rows.len() * embed_dim, 0.0);
+            y.export(&mut buf)?;
+            for ((row, weight), y_row) in routed.iter().zip(buf.chunks(embed_dim)) {
+                let out_row = &mut out[row * embed_dim..(row + 1) * embed_dim];
+                for (o, v) in out_row.iter_mut().zip(y_row) {
+                    *o += weight * v;
+                }
+            }
+        }
+
+        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+        x.import(&out)?;
+        Ok(x)
+    }
+}
+
+/// the tokens of a generation, see `Llama2Runner::generate_stream`. it lends each token out of
+/// the text buffer of the runner until the next call, like a lending iterator.
+pub struct TokenStream<'r, T: Tensor> {
+    runner: &'r mut Llama2Runner<T>,
+    // the token sampled on the prefill with the start of its piece, yielded on the first call
+    first: Option<Result<(usize, usize)>>,
+    // the token to forward on the next step, None after the end or an error
+    current: Option<usize>,
+    pos: usize,
+    start_pos: usize,
+    end_pos: usize,
+    capped_reason: StopReason,
+    decode_started_at: Instant,
+}
+
+impl<T: Tensor> TokenStream<'_, T> {
+    /// the next generated token, or None once the generation ends.
+    pub fn next_token(&mut self) -> Option<Result<TokenEvent<'_>>> {
+        let next = match self.first.take() {
+            Some(first) => first,
+            None => self.step()?,
+        };
+        let (id, piece_start) = match next {
+            Ok(next) => next,
+            Err(err) => return Some(Err(err)),
+        };
+        let text = &self.runner.generated_text;
+        Some(Ok(TokenEvent {
+            id,
+            piece: &text[piece_start..],
+            text,
+        }))
+    }
+
+    // forward the current token and sample the next one, returns the sampled token with the
+    // start of its piece in the generated text
+    fn step(&mut self) -> Option<Result<(usize, usize)>> {
+        let current_token = self.current.take()?;
+        if self.pos >= self.end_pos {
+            return None;
+        }
+        let pos = self.pos;
+        self.pos += 1;
+
+        let runner = &mut *self.runner;
+        let started_at = Instant::now();
+        let forwarded = runner.forward(&[current_token], pos).map(|_| ());
+        let sampled = forwarded.and_then(|_| {
+            runner.penalize_loop();
+            runner.sample_and_emit(started_at)
+        });
+        let new_token = match sampled {
+            Ok(new_token) => new_token,
+            Err(err) => {
+                runner.emit_event(GenerationEvent::Error {
+                    message: err.to_string(),
+                });
+                return Some(Err(err));
+            }
+        };
+        if runner.is_stop_token(new_token) {
+            runner.stop(StopReason::Eos);
+            return None;
+        }
+        if runner.watch_loop(new_token) {
+            runner.stop(StopReason::Loop);
+            return None;
+        }
+        let piece_start = runner.generated_text.len();
+        if let Err(err) = runner
+            .tokenizer
+            .decode_append(new_token, &mut runner.generated_text)
+        {
+            return Some(Err(err));
+        }
+        let n_generated = pos - self.start_pos + 2;
+        if let Some(reason) = runner.check_stopping_criteria(new_token, piece_start, n_generated) {
+            runner.stop(reason);
+            // the token which hit the criteria ends the iteration after it's yielded
+            return (reason != StopReason::Eos).then_some(Ok((new_token, piece_start)));
+        }
+        if pos + 1 == self.end_pos {
+            runner.stop(self.capped_reason);
+        } else {
+            runner.throttle_decode(&DecodeTiming {
+                pos,
+                n_generated,
+                token_elapsed: started_at.elapsed(),
+                elapsed: self.decode_started_at.elapsed(),
+            });
+        }
+        self.current = Some(new_token);
+        Some(Ok((new_token, piece_start)))
+    }
+}
+
+impl<T: Tensor> ForwardOffload for Llama2Runner<T> {
+    fn offload_forward(&mut self, tokens: &[usize], pos: usize) -> Result<Vec<f32>> {
+        Ok(self.forward(tokens, pos)?.to_vec())
+    }
+
+    fn offload_truncate(&mut self, len: usize) -> Result<()> {
+        self.truncate(len)
+    }
+
+    fn offload_forward_tree(
+        &mut self,
+        tokens: &[usize],
+        parents: &[Option<usize>],
+    ) -> Result<Vec<f32>> {
+        self.forward_tree(tokens, parents)
+    }
+
+    fn offload_embed(&mut self, text: &str) -> Result<Vec<f32>> {
+        self.embed(text)
+    }
+
+    fn offload_export_kv_cache(&self) -> Result<KvCacheSnapshot> {
+        self.export_kv_cache()
+    }
+
+    fn offload_import_kv_cache(&mut self, snapshot: &KvCacheSnapshot) -> Result<()> {
+        self.import_kv_cache(snapshot)
+    }
+}
+
+// the errors of the backend, not the ones of the request like a bad input
+// the state the sampling has moved on to after the first n_tokens tokens of a pipelined
+// generation, the tokens sampled ahead of a stop are taken back to it
+#[derive(Debug, Clone)]
+struct SamplingSnapshot {
+    n_tokens: usize,
+    sampler: SamplerState,
+    grammar: Option<GrammarState>,
+    recent_tokens: Vec<usize>,
+}
+
+fn is_backend_error(err: &Error) -> bool {
+    matches!(
+        err.kind,
+        ErrorKind::Unexpected
+            | ErrorKind::IOError
+            | ErrorKind::TensorError
+            | ErrorKind::NotImplemented
+    )
+}
+
+impl<T: Tensor> LayerOffload for Llama2Runner<T> {
+    fn offload_layers(&mut self, x: &[f32], pos: usize) -> Result<Vec<f32>> {
+        let _t = self.metrics.forward_walltime.track();
+        let n_batch = x.len() / self.conf.embedding_dim;
+        let shape = [n_batch, self.conf.embedding_dim];
+        let mut hidden = T::alloc(&shape, GGMLType::F32, self.device.clone())?;
+        hidden.import(x)?;
+        let hidden = self.forward_layers(hidden, pos, None, None)?;
+        let mut buf = vec![0.0; x.len()];
+        hidden.export(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn offload_truncate(&mut self, len: usize) -> Result<()> {
+        self.truncate(len)
+    }
+}
+
+impl<T: Tensor> PrefillOffload for Llama2Runner<T> {
+    fn offload_prefill(&mut self, tokens: &[usize]) -> Result<(KvCacheSnapshot, Vec<f32>)> {
+        self.reset()?;
+        while self.prefill_step(tokens, 0, self.prefill_chunk_size)? > 0 {}
+        let snapshot = self.export_kv_cache()?;
+        self.reset()?;
+        Ok((snapshot, self.logits.clone()))
+    }
+}
+
+// the positions of the tokens of a tree are the depths of them after pos
+fn tree_positions(pos: usize, parents: &[Option<usize>]) -> Vec<usize> {
+    let mut positions: Vec<usize> = Vec::with_capacity(parents.len());
+    for parent in parents {
+        let position = match parent {
+            Some(p) => positions.get(*p).map_or(pos, |parent_pos| parent_pos + 1),
+            None => pos,
+        };
+        positions.push(position);
+    }
+    positions
+}
+
+// rope the rows of the batch at pos, pos + 1, ..., or at the given positions
+fn rope<T: Tensor>(
+    t: T,
+    mode: RopeMode,
+    pos: usize,
+    positions: Option<&[usize]>,
+    rope_dim: usize,
+    theta: f32,
+    freq_scale: f32,
+) -> Result<T> {
+    match positions {
+        Some(positions) => t.rope_positions_inplace(mode, positions, rope_dim, theta, freq_scale),
+        None => t.rope_inplace(mode, pos, rope_dim, theta, freq_scale),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use approx::assert_relative_eq;
+    #[cfg(feature = "wgpu")]
+    use crabml::backends::cpu::CpuTensorDeviceOptions;
+    #[cfg(feature = "wgpu")]
+    use crabml::backends::wgpu::WgpuTensor;
+    #[cfg(feature = "wgpu")]
+    use crabml::backends::wgpu::WgpuTensorDevice;
+    #[cfg(feature = "wgpu")]
+    use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::grammar::ByteDfa;
+    use crate::model::CpuLlama2ModelLoader;
+    use crate::stopping::MaxTokens;
+    use crate::stopping::StopStrings;
+    #[cfg(feature = "wgpu")]
+    use crate::WgpuLlama2Model;
+
+    #[test]
+    fn test_generate_f32() -> Result<()> {
+        let gl: GGUFFileLoader =
+            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner.prefill_and_generate("Lily is a cat", 31)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_on_another_thread() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cat";
+
+        let expected = Llama2Runner::new(&lm, 200, false)?
+            .prefill_and_generate(prompt, 16)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+
+        // the runner is moved to the thread serving it along with its offload, like a replica
+        // of the server
+        let offload = Llama2Runner::new(&lm, 200, false)?;
+        let mut runner =
+            Llama2Runner::new(&lm, 200, false)?.with_forward_offload(Box::new(offload));
+        let output = std::thread::scope(|s| {
+            s.spawn(move || -> Result<String> {
+                let output = runner
+                    .prefill_and_generate(prompt, 16)?
+                    .collect::<Result<Vec<String>>>()?;
+                Ok(output.join(""))
+            })
+            .join()
+            .unwrap()
+        })?;
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_layer_offload() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        // all the layers are forwarded on another runner, and the hidden states move over
+        let n_layers = lm.conf.n_layers;
+        let offload = Llama2Runner::new(&lm, 200, false)?;
+        let mut runner =
+            Llama2Runner::new(&lm, 200, false)?.with_layer_offload(0..n_layers, Box::new(offload));
+        let output = runner
+            .prefill_and_generate("Lily is a cat", 16)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output,
+            " who likes to play with yarn. She has many colors of yarn"
+        );
+        let mut plain = Llama2Runner::new(&lm, 200, false)?;
+        plain
+            .prefill_and_generate("Lily is a cat", 16)?
+            .for_each(drop);
+        assert_eq!(runner.kv_cache_len(), plain.kv_cache_len());
+        assert!(!runner.kv_cache.is_allocated());
+
+        runner.truncate(6)?;
+        assert_eq!(runner.kv_cache_len(), 6);
+        assert!(runner.export_kv_cache().is_err());
+        assert!(runner.forward_tree(&[1, 2], &[None, Some(0)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_fused_qkv() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlama2ModelLoader::new().with_fused_qkv(true).load(&gf)?;
+        assert_eq!(lm.weights.wqkv.len(), lm.conf.n_layers);
+        assert_eq!(lm.weights.wqkv[0].shape(), &[
+            lm.conf.embedding_dim + 2 * lm.conf.kv_dim(),
+            lm.conf.embedding_dim
+        ]);
+        assert!(lm.weights.wq.is_empty() && lm.weights.wk.is_empty() && lm.weights.wv.is_empty());
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner.prefill_and_generate("Lily is a cat", 31)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_events() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_event_sender(tx);
+        let output = runner.prefill_and_generate("Lily is a cat", 5)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert!(matches!(
+            events[0],
+            GenerationEvent::PromptProcessed { n_tokens, .. } if n_tokens > 1
+        ));
+        let texts = events
+            .iter()
+            .filter_map(|e| match e {
+                GenerationEvent::TokenGenerated { text, logprob, .. } => {
+                    assert!(*logprob <= 0.0);
+                    Some(text.clone())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(texts.len(), 5);
+        assert_eq!(texts.join(""), s);
+        assert_eq!(
+            events.last(),
+            Some(&GenerationEvent::StopHit {
+                reason: StopReason::MaxSteps
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_events_with_request_id() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut runner = Llama2Runner::new(&lm, 200, false)?
+            .with_event_sender(tx)
+            .with_request_id(RequestId::from("req-1"));
+        runner.prefill("Lily is a cat", true, false)?;
+        // the request is started only once, even if it's prefilled in many rounds
+        runner.prefill(" who", false, false)?;
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        let started = events
+            .iter()
+            .filter(|e| matches!(e, GenerationEvent::RequestStarted { .. }))
+            .collect::<Vec<_>>();
+        assert_eq!(started, vec![&GenerationEvent::RequestStarted {
+            request_id: RequestId::from("req-1")
+        }]);
+        assert_eq!(events[0], *started[0]);
+        assert_eq!(runner.request_id(), Some(&RequestId::from("req-1")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_concurrently() -> Result<()> {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        assert_send_sync(&lm);
+
+        let outputs = std::thread::scope(|s| {
+            let handles = (0..2)
+                .map(|_| {
+                    s.spawn(|| -> Result<String> {
+                        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+                        let output = runner.prefill_and_generate("Lily is a cat", 31)?;
+                        Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        for s in outputs {
+            assert_eq!(
+                s,
+                " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_limit() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        let prompt = "Lily is a cat who likes to play with yarn";
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_context_limit(4);
+        let err = runner.prefill(prompt, true, false).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::ContextOverflow);
+        assert_eq!(runner.kv_cache_len(), 0);
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?
+            .with_context_limit(4)
+            .with_context_overflow_policy(ContextOverflowPolicy::TruncatePrompt);
+        let (pos, _, token) = runner.prefill(prompt, true, false)?;
+        assert_eq!(pos, 4);
+        let output = runner
+            .generate(pos, token, Some(10))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(output.len(), 1);
+        assert_eq!(runner.stop_reason(), Some(StopReason::ContextLength));
+
+        // the max steps are capped by the remaining context
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_context_limit(20);
+        let (pos, _, token) = runner.prefill(prompt, true, false)?;
+        assert_eq!(runner.remaining_context(), 20 - pos);
+        let output = runner
+            .generate(pos, token, Some(100))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(output.len(), 20 - pos);
+        assert_eq!(runner.kv_cache_len(), 20 - 1);
+        assert_eq!(runner.stop_reason(), Some(StopReason::ContextLength));
+        assert_eq!(StopReason::ContextLength.finish_reason(), "length:context");
+
+        runner.reset()?;
+        let (pos, _, token) = runner.prefill(prompt, true, false)?;
+        let output = runner
+            .generate(pos, token, Some(3))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(output.len(), 3);
+        assert_eq!(runner.stop_reason(), Some(StopReason::MaxSteps));
+
+        let runner = Llama2Runner::new(&lm, 200, true)?;
+        let bytes_per_token = runner.kv_cache_bytes_per_token();
+        let runner = runner.with_kv_cache_budget(bytes_per_token * 16);
+        assert_eq!(runner.context_limit(), 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_niceness() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().with_thread_num(2).load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_niceness(Niceness::background());
+        let output = runner.prefill_and_generate("Lily is a cat", 31)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+
+        assert_eq!(lm.device.thread_num(), 2);
+        {
+            let _guard = ThreadNumLimitGuard::new(1);
+            assert_eq!(lm.device.thread_num(), 1);
+        }
+        assert_eq!(lm.device.thread_num(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_throttle_hook() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().with_thread_num(2).load(&gf)?;
+
+        let timings = Arc::new(Mutex::new(vec![]));
+        let hook_timings = timings.clone();
+        let hook = move |timing: &DecodeTiming| {
+            hook_timings.lock().unwrap().push(*timing);
+            Throttle {
+                pause: Some(Duration::ZERO),
+                max_threads: Some(1),
+            }
+        };
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_throttle_hook(Arc::new(hook));
+        let output = runner.prefill_and_generate("Lily is a cat", 16)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(
+            s,
+            " who likes to play with yarn. She has many colors of yarn"
+        );
+
+        // called between the tokens, but not after the last one
+        let timings = timings.lock().unwrap();
+        assert_eq!(timings.len(), 14);
+        assert_eq!(timings[0].n_generated, 2);
+        assert_eq!(timings[13].n_generated, 15);
+        assert_eq!(runner.max_threads(), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_warm_prefill() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().with_thread_num(2).load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill("Lily is a cat", true, true)?;
+        let timing = runner.prefill_timing().unwrap();
+        let warm = runner.time_warm_prefill(timing.n_tokens, true)?.unwrap();
+        assert_eq!(warm.n_tokens, timing.n_tokens);
+
+        // the session goes on as if the warm prefill never ran
+        assert_eq!(runner.kv_cache_len(), pos);
+        let s = runner
+            .generate(pos, token, Some(8))
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        let mut plain = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = plain.prefill("Lily is a cat", true, true)?;
+        let expected = plain
+            .generate(pos, token, Some(8))
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(s, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_attention_maps() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let conf = lm.conf.clone();
+
+        let options = AttentionMapOptions::new().with_layer_stride(2);
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_attention_maps(options);
+        let output = runner.prefill_and_generate("Lily is a cat", 8)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert!(" who likes to play with yarn. She has many colors".starts_with(&s));
+
+        let maps = runner.attention_maps().unwrap();
+        let n_layers = conf.n_layers.div_ceil(2);
+        assert_eq!(maps.tokens().len(), runner.kv_cache_len());
+        assert_eq!(
+            maps.rows().len(),
+            maps.tokens().len() * n_layers * conf.n_heads
+        );
+        for row in maps.rows() {
+            assert_eq!(row.layer % 2, 0);
+            assert_eq!(row.weights.len(), row.pos + 1);
+            assert_relative_eq!(row.weights.iter().sum::<f32>(), 1.0, epsilon = 1e-4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_embed() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let cat = runner.embed("Lily is a cat")?;
+        assert_eq!(cat.len(), lm.conf.embedding_dim);
+        assert_eq!(runner.kv_cache_len(), 0);
+
+        let cat_q = runner.embed_int8("Lily is a cat")?;
+        let kitty_q = runner.embed_int8("Lily is a kitty")?;
+        let car_q = runner.embed_int8("The red car drove fast on the road")?;
+        assert_eq!(cat_q, Int8Embedding::quantize(&cat));
+        assert!(cat_q.cosine_similarity(&kitty_q)? > cat_q.cosine_similarity(&car_q)?);
+
+        // the session in progress is kept
+        let (pos, _, _) = runner.prefill("Lily is", true, false)?;
+        assert!(runner.embed("Lily is a cat").is_err());
+        assert_eq!(runner.kv_cache_len(), pos);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_offload_session_ops() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let offloaded = || -> Result<_> {
+            let offload = Llama2Runner::new(&lm, 200, false)?;
+            Ok(Llama2Runner::new(&lm, 200, false)?.with_forward_offload(Box::new(offload)))
+        };
+        let (tokens, parents) = ([365, 338], [None, Some(0)]);
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let embedding = runner.embed("Lily is a cat")?;
+        runner.prefill("Lily is a cat", true, false)?;
+        let snapshot = runner.export_kv_cache()?;
+        let tree_logits = runner.forward_tree(&tokens, &parents)?;
+
+        // the session runs on the offload, the KV cache of the runner is never allocated
+        let mut runner = offloaded()?;
+        assert_eq!(runner.embed("Lily is a cat")?, embedding);
+        runner.prefill("Lily is a cat", true, false)?;
+        assert!(!runner.kv_cache.is_allocated());
+        let offloaded_snapshot = runner.export_kv_cache()?;
+        assert_eq!(offloaded_snapshot.len, snapshot.len);
+        assert_eq!(offloaded_snapshot.keys, snapshot.keys);
+        assert_eq!(runner.forward_tree(&tokens, &parents)?, tree_logits);
+        assert!(runner.is_offloaded());
+
+        let mut runner = offloaded()?;
+        runner.import_kv_cache(&snapshot)?;
+        assert_eq!(runner.kv_cache_len(), snapshot.len);
+        assert_eq!(runner.export_kv_cache()?.values, snapshot.values);
+        assert_eq!(runner.forward_tree(&tokens, &parents)?, tree_logits);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let first = runner
+            .prefill_and_generate("Lily is a cat", 8)?
+            .collect::<Result<Vec<String>>>()?;
+        assert!(runner.kv_cache_len() > 0);
+
+        runner.reset()?;
+        assert_eq!(runner.kv_cache_len(), 0);
+        let second = runner
+            .prefill_and_generate("Lily is a cat", 8)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(first, second);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_q8_0() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        assert_eq!(lm.conf.rope_dim, Some(48));
+        assert_eq!(lm.conf.head_size(), 48);
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner.prefill_and_generate("Lily is a cute cat, ", 11)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, "3 years old. She likes to play with her");
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefill_batched() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cat who likes to play";
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill(prompt, true, false)?;
+        let expected_logits = runner.logits.clone();
+        let expected = runner
+            .generate(pos, token, Some(10))
+            .collect::<Result<Vec<_>>>()?;
+
+        // the prompt is forwarded in the chunks of 3 tokens
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_prefill_chunk_size(3);
+        let (batched_pos, _, batched_token) = runner.prefill(prompt, true, true)?;
+        assert_eq!(batched_pos, pos);
+        assert_eq!(runner.prefill_timing().unwrap().n_tokens, pos);
+        assert_eq!(batched_token, token);
+        for (a, b) in runner.logits.iter().zip(expected_logits.iter()) {
+            assert_relative_eq!(a, b, epsilon = 1e-3);
+        }
+        let output = runner
+            .generate(batched_pos, batched_token, Some(10))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefill_with_chunked_attention() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cat who likes to play";
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill(prompt, true, true)?;
+        let expected_logits = runner.logits.clone();
+        let expected = runner
+            .generate(pos, token, Some(10))
+            .collect::<Result<Vec<_>>>()?;
+
+        // the queries of the prompt attend in the chunks of 3 rows
+        let mut runner =
+            Llama2Runner::new(&lm, 200, false)?.with_attention_kernel(AttentionKernel::Chunked(3));
+        let (chunked_pos, _, chunked_token) = runner.prefill(prompt, true, true)?;
+        assert_eq!(chunked_pos, pos);
+        assert_eq!(chunked_token, token);
+        for (a, b) in runner.logits.iter().zip(expected_logits.iter()) {
+            assert_relative_eq!(a, b, epsilon = 1e-3);
+        }
+        let output = runner
+            .generate(chunked_pos, chunked_token, Some(10))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_flash_attention() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cat who likes to play";
+
+        // the prompt is prefilled in a batch and the tokens are decoded one by one on the
+        // fused kernel, over the f32 and the f16 KV cache
+        for use_f16_kv_cache in [false, true] {
+            let mut runner = Llama2Runner::new(&lm, 200, use_f16_kv_cache)?;
+            let (pos, _, token) = runner.prefill(prompt, true, true)?;
+            let expected_logits = runner.logits.clone();
+            let expected = runner
+                .generate(pos, token, Some(10))
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut runner = Llama2Runner::new(&lm, 200, use_f16_kv_cache)?
+                .with_attention_kernel(AttentionKernel::Flash);
+            let (flash_pos, _, flash_token) = runner.prefill(prompt, true, true)?;
+            assert_eq!(flash_pos, pos);
+            assert_eq!(flash_token, token);
+            for (a, b) in runner.logits.iter().zip(expected_logits.iter()) {
+                assert_relative_eq!(a, b, epsilon = 1e-2);
+            }
+            let output = runner
+                .generate(flash_pos, flash_token, Some(10))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(output, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_sparse_ffn() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cat who likes to play";
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        runner.prefill(prompt, true, true)?;
+        let expected_logits = runner.logits.clone();
+
+        // keeping all the activations is the same as the dense ffn
+        let options = SparseFfnOptions::new(0.0).with_max_density(1.0);
+        assert!(!lm.weights.sparse_down_rows.is_built(0));
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_sparse_ffn(options);
+        runner.prefill(prompt, true, true)?;
+        for (a, b) in runner.logits.iter().zip(expected_logits.iter()) {
+            assert_relative_eq!(a, b, epsilon = 1e-3);
+        }
+        // the transposed rows are kept in the weights for the other runners
+        assert!(lm.weights.sparse_down_rows.is_built(0));
+
+        let options = SparseFfnOptions::new(0.05).with_max_density(1.0);
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_sparse_ffn(options);
+        let output = runner
+            .prefill_and_generate(prompt, 10)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(output.len(), 10);
+        let density = runner.sparse_ffn_density().unwrap();
+        assert!(density > 0.0 && density < 1.0, "density: {}", density);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefill_healed() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a ca";
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let prompt_tokens = runner.tokenizer.encode(prompt, true, false)?;
+        let healed = runner.tokenizer.token_bytes(*prompt_tokens.last().unwrap());
+        let (pos, _prev_token, token) = runner.prefill_healed(prompt, true, false)?;
+        assert_eq!(pos, prompt_tokens.len() - 1);
+        // the first token takes the place of the healed one, its text is trimmed
+        let piece = runner.tokenizer.decode(token)?;
+        assert!(piece.as_bytes().starts_with(&healed), "piece: {:?}", piece);
+        let output = runner
+            .generate(pos, token, Some(3))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(output[0], piece[healed.len()..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_with_output_vocab() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let expected_logits = runner.forward(&[1, 365], 0)?.to_vec();
+
+        let vocab = [13, 365, 1576, 2501, 29889];
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        runner.set_output_vocab(Some(&vocab))?;
+        assert_eq!(runner.output_vocab(), Some(&vocab[..]));
+        let logits = runner.forward(&[1, 365], 0)?;
+        for (token, logit) in logits.iter().enumerate() {
+            if vocab.contains(&token) {
+                assert_relative_eq!(*logit, expected_logits[token], epsilon = 1e-3);
+            } else {
+                assert_eq!(*logit, f32::NEG_INFINITY);
+            }
+        }
+
+        runner.set_output_vocab(None)?;
+        assert_eq!(runner.forward(&[1576], 2)?.len(), expected_logits.len());
+        assert!(runner.set_output_vocab(Some(&[])).is_err());
+        assert!(runner.set_output_vocab(Some(&[32000])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_at_positions() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let tokens = runner.tokenizer().encode("Lily is a cat", true, false)?;
+
+        let expected = runner.forward(&tokens, 0)?.to_vec();
+        runner.reset()?;
+        let positions = (0..tokens.len()).collect::<Vec<_>>();
+        let logits = runner.forward_at_positions(&tokens, &positions)?.to_vec();
+        for (a, b) in logits.iter().zip(&expected) {
+            assert_relative_eq!(a, b, epsilon = 1e-4);
+        }
+
+        // the last token is placed after a gap, which is not the position of the cache length
+        runner.reset()?;
+        let mut positions = positions;
+        *positions.last_mut().unwrap() += 10;
+        let logits = runner.forward_at_positions(&tokens, &positions)?.to_vec();
+        assert_eq!(runner.kv_cache_len(), tokens.len());
+        assert!(
+            logits
+                .iter()
+                .zip(&expected)
+                .any(|(a, b)| (a - b).abs() > 1e-3)
+        );
+
+        assert!(
+            runner
+                .forward_at_positions(&tokens, &positions[1..])
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_tree() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cat";
+        let vocab_size = lm.conf().vocab_size;
+
+        // two branches "who likes" and "that", which share the prompt
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let branch_a = runner.tokenizer().encode("who likes", false, false)?;
+        let branch_b = runner.tokenizer().encode("that", false, false)?;
+        let tokens = [branch_a[0], branch_a[1], branch_b[0]];
+        let parents = [None, Some(0), None];
+        let mut expected = vec![];
+        for branch in [&tokens[..2], &tokens[2..]] {
+            runner.reset()?;
+            runner.prefill(prompt, true, false)?;
+            let pos = runner.kv_cache_len();
+            expected.push(runner.forward(branch, pos)?.to_vec());
+        }
+        runner.reset()?;
+
+        runner.prefill(prompt, true, false)?;
+        let base_pos = runner.kv_cache_len();
+        let logits = runner.forward_tree(&tokens, &parents)?;
+        assert_eq!(logits.len(), 3 * vocab_size);
+        for (a, b) in logits[vocab_size..2 * vocab_size].iter().zip(&expected[0]) {
+            assert_relative_eq!(a, b, epsilon = 1e-3);
+        }
+        for (a, b) in logits[2 * vocab_size..].iter().zip(&expected[1]) {
+            assert_relative_eq!(a, b, epsilon = 1e-3);
+        }
+
+        // keep the second branch, which is re-forwarded after the prompt
+        assert!(
+            runner
+                .commit_tree_path(base_pos, &tokens, &parents, &[1])
+                .is_err()
+        );
+        runner.commit_tree_path(base_pos, &tokens, &parents, &[2])?;
+        assert_eq!(runner.kv_cache_len(), base_pos + 1);
+        for (a, b) in runner.logits.iter().zip(&expected[1]) {
+            assert_relative_eq!(a, b, epsilon = 1e-3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_grammar() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let dfa = ByteDfa::from_choices(&[" very happy", " sad"]);
+        let grammar = CompiledGrammar::compile(dfa, runner.tokenizer())?;
+        runner.set_grammar(Some(grammar));
+        let output = runner.prefill_and_generate("Lily is a cat. She is", 10)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert!(s == " very happy" || s == " sad", "got {:?}", s);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_stop_tokens() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        // the stories model is not trained on FIM
+        assert!(runner.complete_infill("Lily is", "cat.", Some(8)).is_err());
+
+        let stop_token = runner.tokenizer().piece_to_token("▁old").unwrap();
+        runner.set_stop_tokens(vec![stop_token]);
+        let output = runner.prefill_and_generate("Lily is a cute cat, ", 11)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, "3 years");
+
+        // the stop tokens of an infill are restored once its generation stops
+        let old = stop_token;
+        let years = runner.tokenizer().piece_to_token("▁years").unwrap();
+        runner.reset()?;
+        let (pos, _prev_token, token) = runner.prefill("Lily is a cute cat, ", true, false)?;
+        runner.override_stop_tokens(vec![years]);
+        let output = runner.generate(pos, token, Some(11));
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, "3");
+        assert_eq!(runner.stop_tokens, vec![old]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_eos_policy() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let eos = lm.tokenizer.eos_token();
+
+        // only the EOS can be sampled
+        for (policy, n_tokens, reason) in [
+            (EosPolicy::Stop, 1, StopReason::Eos),
+            (EosPolicy::Continue, 5, StopReason::MaxSteps),
+        ] {
+            let mut runner = Llama2Runner::new(&lm, 200, false)?.with_eos_policy(policy);
+            runner.set_output_vocab(Some(&[eos]))?;
+            let (pos, _, token) = runner.prefill("Lily is a cat", true, false)?;
+            let output = runner
+                .generate(pos, token, Some(5))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(output.len(), n_tokens);
+            assert_eq!(runner.stop_reason(), Some(reason));
+        }
+        assert!("emit".parse::<EosPolicy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_stopping_criteria() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        let prompt = "Lily is a cute cat, ";
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_heads, head_dim])?;
-                let k = k.reshape(&[n_kv_heads, head_dim])?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let full = runner
+            .prefill_and_generate(prompt, 12)?
+            .collect::<Result<Vec<String>>>()?;
+        let generate = |criteria: StoppingCriteriaList| -> Result<Vec<String>> {
+            let mut runner = Llama2Runner::new(&lm, 200, false)?.with_stopping_criteria(criteria);
+            let output = runner
+                .prefill_and_generate(prompt, 12)?
+                .collect::<Result<Vec<String>>>()?;
+            assert_eq!(output[..], full[..output.len()]);
+            Ok(output)
+        };
 
-                let q = q.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                (q, k)
-            };
+        // the token hitting the max tokens or a predicate is yielded
+        let output = generate(StoppingCriteriaList::new().with(MaxTokens(3)))?;
+        assert_eq!(output.len(), 3);
+        let vocab_size = lm.conf.vocab_size;
+        let predicate = move |ctx: &StoppingContext| {
+            assert_eq!(ctx.logits.len(), vocab_size);
+            (ctx.n_generated == 2).then_some(StopReason::Criteria)
+        };
+        let output = generate(StoppingCriteriaList::new().with(predicate))?;
+        assert_eq!(output.len(), 2);
 
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
+        // the stop string spans over two tokens
+        let stop = full[3..5].concat();
+        let output = generate(StoppingCriteriaList::new().with(StopStrings(vec![stop.clone()])))?;
+        assert!(output.len() <= 5 && output.concat().contains(&stop));
 
-            // residual connection back into x
-            x = x.add_inplace(&x_attn_orig)?;
+        // the token stopped with Eos is not yielded, even if it's the first token
+        let eos = |ctx: &StoppingContext| (ctx.n_generated == 1).then_some(StopReason::Eos);
+        let output = generate(StoppingCriteriaList::new().with(eos))?;
+        assert!(output.is_empty());
+        Ok(())
+    }
 
-            // ffn
-            x = self.forward_ffn(x, l, pos, Activation::GeLU)?;
-            x = x.with_name(format!("ffn_out:{}:{}", l, pos));
-        }
+    #[test]
+    fn test_generate_stream() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        let prompt = "Lily is a cute cat, ";
 
-        // final rmsnorm
-        x = {
-            x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
-            x = x.mul_inplace(&self.weights.rms_final_weight)?;
-            x.with_name(format!("final_rmsnorm:{}", pos))
-        };
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let want = runner
+            .prefill_and_generate(prompt, 10)?
+            .collect::<Result<Vec<String>>>()?;
 
-        Ok(x)
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _prev_token, token) = runner.prefill(prompt, true, false)?;
+        let mut stream = runner.generate_stream(pos, token, Some(10));
+        let mut got = vec![];
+        while let Some(event) = stream.next_token() {
+            let event = event?;
+            // the pieces are the slices at the end of the text
+            assert!(event.text.ends_with(event.piece));
+            assert_eq!(event.text, [got.concat(), event.piece.to_string()].concat());
+            got.push(event.piece.to_string());
+        }
+        assert_eq!(got, want);
+        assert_eq!(runner.stop_reason(), Some(StopReason::MaxSteps));
+        Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn forward_multi_query_attention(
-        &mut self,
-        q: T,
-        k: T,
-        v: T,
-        l: usize,
-        _pos: usize,
-        n_kv_heads: usize,
-        n_heads: usize,
-        embed_dim: usize,
-        head_dim: usize,
-        n_batch: usize,
-    ) -> Result<T> {
-        // save to kv cache in layout of (n_kv_heads, n_batch, head_dim)
-        {
-            let k = k
-                .reshape(&[n_batch, n_kv_heads, head_dim])?
-                .transpose(&[1, 0, 2])?;
-            let v = v
-                .reshape(&[n_batch, n_kv_heads, head_dim])?
-                .transpose(&[1, 0, 2])?;
-
-            if let Some(k_cache) = self.key_cache[l].as_mut() {
-                k_cache.concatenate(&k, 1)?;
-            };
-            if let Some(v_cache) = self.value_cache[l].as_mut() {
-                v_cache.concatenate(&v, 1)?;
-            };
-        };
+    #[test]
+    fn test_generate_pipelined() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        let prompt = "Lily is a cute cat, ";
 
-        // multi query attention
-        let x = {
-            // - q: [n_batch, n_head, head_size]
-            // - q = q.transpose(1, 0, 2).contiguous => [n_head, n_batch, head_size]
-            let q = q
-                .reshape(&[n_batch, n_heads, head_dim])?
-                .transpose(&[1, 0, 2])?
-                .contiguous()?
-                .div_scalar_inplace((head_dim as f32).sqrt())?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let want = runner
+            .prefill_and_generate(prompt, 10)?
+            .collect::<Result<Vec<String>>>()?;
 
-            // get attention scores:
-            // - key_cache: [n_kv_head, seq, head_size].transpose(0, 2, 1) => [n_kv_head, head_size, seq]
-            // - attn_scores = batch_matmul(q, key_cache) => [n_head, n_batch, seq]
-            // - attn_scores = softmax(attn_score, axis=2) => [n_head, n_batch, seq]
-            let k_cache = self.key_cache[l].take().unwrap();
-            let k_cache_strider_orig = k_cache.strider().clone();
-            let k_cache = k_cache.transpose(&[0, 2, 1])?; // (n_kv_heads, head_size, seq)
-            // (n_head, 1, head_size) @ (n_kv_heads, head_size, seq)
-            let attn = q.batch_matmul(&k_cache)?; // (n_head, n_batch, seq)
-            let attn = attn.softmax_inplace(2)?;
-            self.key_cache[l].replace(k_cache.with_strider(k_cache_strider_orig)?);
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _prev_token, token) = runner.prefill(prompt, true, false)?;
+        let mut got = vec![];
+        let output = runner.generate_pipelined(pos, token, Some(10), &[], |event| {
+            got.push(event.piece.to_string());
+            Ok(())
+        })?;
+        assert_eq!(got, want);
+        assert_eq!(output.text, want.concat());
+        assert_eq!(runner.stop_reason(), Some(StopReason::MaxSteps));
+        assert_eq!(runner.kv_cache_len(), pos + 9);
 
-            // - val_cache: [n_kv_head, seq, head_size]
-            // - out = batch_matmul(atten_scores, val_cache) => [n_head, n_batch, head_size]
-            // - out = out.transpose(1, 0, 2).contiguous => [n_batch, n_head, head_size]
-            // - out = out.reshape(n_batch, embed_dim)
-            let v_cache = self.value_cache[l].take().unwrap();
-            let v_cache_strider_orig = v_cache.strider().clone();
-            // (n_head, n_batch, seq) @ (n_kv_heads, seq, head_dim) => (n_head, n_batch, head_dim)
-            let x_with_attn = attn.batch_matmul(&v_cache)?; // (n_heads, n_batch, head_dim)
-            let x_with_attn = if n_batch == 1 {
-                // TODO: this specialase might be able to unify with the general case
-                x_with_attn.reshape(&[n_batch, embed_dim])?
-            } else {
-                x_with_attn
-                    .transpose(&[1, 0, 2])? // (n_batch, n_heads, head_dim)
-                    .contiguous()?
-                    .reshape(&[n_batch, embed_dim])?
-            };
-            self.value_cache[l].replace(v_cache.with_strider(v_cache_strider_orig)?);
+        // the tokens the decode loop ran ahead past the stop string are taken back
+        let stop = want[3..5].concat();
+        runner.reset()?;
+        let (pos, _prev_token, token) = runner.prefill(prompt, true, false)?;
+        let output =
+            runner.generate_pipelined(pos, token, Some(10), &[stop.clone()], |_| Ok(()))?;
+        assert!(output.stop_string_hit && output.text.contains(&stop));
+        assert!(output.tokens.len() <= 5);
+        assert_eq!(output.text, want[..output.tokens.len()].concat());
+        assert_eq!(runner.stop_reason(), Some(StopReason::StopString));
+        assert_eq!(runner.kv_cache_len(), pos + output.tokens.len() - 1);
 
-            // final matmul to get the output of the attention
-            self.weights.wo[l].matmul_vec(&x_with_attn)?
-        };
-        Ok(x)
+        // the stopping criteria are checked inline with the decode loop only
+        let mut runner = Llama2Runner::new(&lm, 200, false)?
+            .with_stopping_criteria(StoppingCriteriaList::new().with(MaxTokens(3)));
+        let (pos, _prev_token, token) = runner.prefill(prompt, true, false)?;
+        let err = runner
+            .generate_pipelined(pos, token, Some(10), &[], |_| Ok(()))
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
     }
 
-    fn forward_ffn(&self, mut x: T, l: usize, _pos: usize, activation: Activation) -> Result<T> {
-        // save for redidual connection
-        let x_orig_ffn = x.dup()?; // (n_batch, embed_dim)
-
-        // ffn rmsnorm
-        x = {
-            x = x.rms_norm_inplace(1e-5)?;
-            x = x.mul_inplace(&self.weights.rms_ffn_weight[l])?;
-            x
-        };
+    #[test]
+    fn test_generate_pipelined_takes_back_sampling() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cute cat, ";
+        let seeded = || Llama2Sampler::new_with_topk(lm.conf.vocab_size, 0.8, 0, 0.9, Some(42));
 
-        // Now for FFN in PyTorch we have: self.down_proj(F.silu(self.gate_proj(x)) * self.up_proj(x))
-        // first calculate self.w1(x) and self.w3(x)
-        // w1: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
-        // w3: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
-        let mut h1 = self.weights.ffn_gate_weight[l].matmul_vec(&x)?;
-        let h2 = self.weights.ffn_up_weight[l].matmul_vec(&x)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        runner.set_sampler(seeded());
+        let want = runner
+            .prefill_and_generate(prompt, 10)?
+            .collect::<Result<Vec<String>>>()?;
 
-        // F.silu; silu(x)=x*σ(x),where σ(x) is the logistic sigmoid
-        h1 = match activation {
-            Activation::SiLU => h1.silu_inplace()?,
-            Activation::GeLU => h1.gelu_inplace()?,
-        };
+        // the sampler flips a coin on each token, the coins of the tokens sampled ahead of the
+        // stop string are taken back
+        let stop = want[3..5].concat();
+        runner.reset()?;
+        runner.set_sampler(seeded());
+        let (pos, _prev_token, token) = runner.prefill(prompt, true, false)?;
+        let output =
+            runner.generate_pipelined(pos, token, Some(10), &[stop.clone()], |_| Ok(()))?;
+        assert!(output.stop_string_hit);
+        assert_eq!(output.text, want[..output.tokens.len()].concat());
+        assert_eq!(runner.sampler().state().n_coins, output.tokens.len() as u64);
 
-        // elementwise multiply with w3(x)
-        h1 = h1.mul_inplace(&h2)?;
+        // a failure of on_token stops the generation on its token
+        runner.reset()?;
+        runner.set_sampler(seeded());
+        let (pos, _prev_token, token) = runner.prefill(prompt, true, false)?;
+        let mut n_calls = 0;
+        let err = runner
+            .generate_pipelined(pos, token, Some(10), &[], |_| {
+                n_calls += 1;
+                match n_calls {
+                    3 => Err(Error::new(ErrorKind::IOError, "the client is gone")),
+                    _ => Ok(()),
+                }
+            })
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::IOError);
+        assert_eq!(runner.stop_reason(), Some(StopReason::Criteria));
+        assert_eq!(runner.kv_cache_len(), pos + 2);
+        assert_eq!(runner.sampler().state().n_coins, 3);
+        Ok(())
+    }
 
-        // final matmul to get the output of the ffn
-        x = self.weights.ffn_down_weight[l].matmul_vec(&h1)?; // (n_batch, embed_dim)
+    #[test]
+    fn test_forward_failure_report() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?
+            .with_failure_reports(FailureReportOptions::new().with_dump_len(4));
+        runner.forward(&[1, 450], 0)?;
+        assert!(runner.failure_report().is_none());
 
-        // residual connection
-        x = x.add_inplace(&x_orig_ffn)?;
-        Ok(x)
+        // the token out of the vocab fails or panics on copying its embedding
+        let bad_token = lm.conf.vocab_size + 10;
+        assert!(runner.forward(&[bad_token], 2).is_err());
+        let report = runner.failure_report().unwrap();
+        assert_eq!(report.layer, None);
+        assert_eq!((report.pos, &report.tokens[..]), (2, &[bad_token][..]));
+        assert_eq!(report.backend, "CpuTensor");
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use approx::assert_relative_eq;
-    use crabml::backends::cpu::CpuTensorDeviceOptions;
-    use crabml::backends::wgpu::WgpuTensorDevice;
-    use crabml::backends::wgpu::WgpuTensorDeviceOptions;
-    use crabml::gguf::GGUFFileLoader;
+    #[test]
+    fn test_generate_with_loop_watchdog() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        let prompt = "the cat sat. the cat sat. the cat sat. the cat sat. the cat sat.";
 
-    use super::*;
-    use crate::model::CpuLlama2ModelLoader;
-    use crate::WgpuLlama2Model;
+        // the output vocab of a single token makes the greedy generation repeat it forever
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let the = runner.tokenizer().piece_to_token("▁the").unwrap();
+        runner.set_output_vocab(Some(&[the]))?;
+        let full = runner
+            .prefill_and_generate(prompt, 60)?
+            .collect::<Result<Vec<String>>>()?;
+        assert!(full.len() > 16, "{}", full.len());
+
+        // the watchdog only cuts the greedy generation short, it never changes the tokens
+        let watchdog = LoopWatchdog::new().with_min_span(8).with_min_repeats(2);
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_loop_watchdog(watchdog);
+        runner.set_output_vocab(Some(&[the]))?;
+        let output = runner
+            .prefill_and_generate(prompt, 60)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(runner.stop_reason(), Some(StopReason::Loop));
+        assert!(output.len() < full.len(), "{}", output.len());
+        assert_eq!(output[..], full[..output.len()]);
+        assert_eq!(StopReason::Loop.finish_reason(), "loop");
+        Ok(())
+    }
 
     #[test]
-    fn test_generate_f32() -> Result<()> {
-        let gl: GGUFFileLoader =
-            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+    fn test_generate_with_ensemble() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
         let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
 
-        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
-
+        // fusing a model with itself keeps its distribution
         let mut runner = Llama2Runner::new(&lm, 200, false)?;
-        let output = runner.prefill_and_generate("Lily is a cat", 31)?;
-        let s = output.collect::<Result<Vec<String>>>()?.join("");
-
+        runner.set_ensemble_weight(0.5);
+        runner.add_ensemble_member(Llama2Runner::new(&lm, 200, false)?, 0.5)?;
+        let output = runner
+            .prefill_and_generate("Lily is a cat", 16)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
         assert_eq!(
-            s,
-            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+            output,
+            " who likes to play with yarn. She has many colors of yarn"
         );
+
+        // the KV caches of the members are kept in sync
+        let member = &runner.ensemble[0].0;
+        assert_eq!(member.kv_cache_len(), runner.kv_cache_len());
+        runner.truncate(3)?;
+        assert_eq!(runner.ensemble[0].0.kv_cache_len(), 3);
+
+        // a member with other tokens in the KV cache can not join
+        let mut other = Llama2Runner::new(&lm, 200, false)?;
+        other.prefill("Lily", true, false)?;
+        assert!(runner.add_ensemble_member(other, 0.5).is_err());
         Ok(())
     }
 
     #[test]
-    fn test_generate_q8_0() -> Result<()> {
+    fn test_generate_q8_0_prepacked() -> Result<()> {
         let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
         let gf = gl.open()?;
 
-        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
-        assert_eq!(lm.conf.rope_dim, Some(48));
-        assert_eq!(lm.conf.head_size(), 48);
+        let lm = CpuLlama2ModelLoader::new()
+            .with_prepacking(true)
+            .load(&gf)?;
+        assert!(lm.weights.wq[0].buf().is_packed());
+        assert!(!lm.weights.token_embed.buf().is_packed());
 
         let mut runner = Llama2Runner::new(&lm, 200, false)?;
         let output = runner.prefill_and_generate("Lily is a cute cat, ", 11)?;
@@ -500,6 +3895,39 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_forward_w8a8() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let lm_w8a8 = CpuLlama2ModelLoader::new().with_w8a8(true).load(&gf)?;
+        assert_eq!(lm_w8a8.weights.wq[0].buf().dtype(), GGMLType::I8);
+        assert_eq!(lm_w8a8.weights.rms_att_weight[0].dtype(), GGMLType::F32);
+
+        // the logits of the int8 matmuls stay close to the f32 ones
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        let tokens = runner
+            .tokenizer()
+            .encode("Lily is a cute cat", true, false)?;
+        let want = runner.forward(&tokens, 0)?.to_vec();
+        let got = Llama2Runner::new(&lm_w8a8, 64, false)?
+            .forward(&tokens, 0)?
+            .to_vec();
+        let argmax = |logits: &[f32]| {
+            (0..logits.len())
+                .max_by(|a, b| logits[*a].total_cmp(&logits[*b]))
+                .unwrap()
+        };
+        assert_eq!(argmax(&got), argmax(&want));
+        let max_logit = want.iter().fold(0.0_f32, |m, v| m.max(v.abs()));
+        let max_err = want
+            .iter()
+            .zip(got.iter())
+            .fold(0.0_f32, |m, (a, b)| m.max((a - b).abs()));
+        assert!(max_err < 0.1 * max_logit, "{} vs {}", max_err, max_logit);
+        Ok(())
+    }
+
     #[test]
     fn test_generate_q4_0() -> Result<()> {
         let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q4_0.gguf", false)?;
@@ -549,6 +3977,135 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "wgpu")]
+    fn test_generate_with_prefill_offload() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2ModelLoader::new().load(&gf)?;
+        let conf = &model_cpu.conf;
+
+        // the staging buffer should hold the keys of a layer on moving the KV cache
+        let kv_bytes = conf.n_kv_heads * 200 * conf.head_size() * 4;
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new()
+                .with_staging_buf_bytes(kv_bytes.max(conf.vocab_size * 4)),
+        )?;
+        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
+
+        // prefill on the GPU, decode on the CPU
+        let runner_wgpu = Llama2Runner::new(&model_wgpu, 200, false)?;
+        let mut runner =
+            Llama2Runner::new(&model_cpu, 200, false)?.with_prefill_offload(Box::new(runner_wgpu));
+        let output = runner
+            .prefill_and_generate("Lily is a cat", 16)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output,
+            " who likes to play with yarn. She has many colors of yarn"
+        );
+
+        // the snapshot of the KV cache is the same after moving back and forth
+        let snapshot = runner.export_kv_cache()?;
+        runner.import_kv_cache(&snapshot)?;
+        assert_eq!(runner.export_kv_cache()?.keys, snapshot.keys);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "wgpu")]
+    fn test_generate_with_layers_on_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2ModelLoader::new().load(&gf)?;
+        let conf = &model_cpu.conf;
+
+        // the staging buffer should hold the hidden states of the prompt
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(200 * conf.embedding_dim * 4),
+        )?;
+        let model_wgpu = WgpuLlama2Model::from_cpu_layers(&model_cpu, device_wgpu, 0..3)?;
+        assert_eq!(model_wgpu.conf.n_layers, 3);
+        assert!(model_wgpu.weights.output_weight.is_none());
+
+        // the first layers on the GPU, the rest on the CPU
+        let runner_wgpu = Llama2Runner::new(&model_wgpu, 200, false)?;
+        let mut runner = Llama2Runner::new(&model_cpu, 200, false)?
+            .with_layer_offload(0..3, Box::new(runner_wgpu));
+        let output = runner
+            .prefill_and_generate("Lily is a cat", 16)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output,
+            " who likes to play with yarn. She has many colors of yarn"
+        );
+        Ok(())
+    }
+
+    // the offload on the GPU which fails on the n-th forward
+    #[cfg(feature = "wgpu")]
+    struct FailingOffload {
+        runner: Llama2Runner<WgpuTensor>,
+        forwards_left: usize,
+    }
+
+    #[cfg(feature = "wgpu")]
+    impl ForwardOffload for FailingOffload {
+        fn offload_forward(&mut self, tokens: &[usize], pos: usize) -> Result<Vec<f32>> {
+            if self.forwards_left == 0 {
+                return Err(Error::new(ErrorKind::TensorError, "device lost"));
+            }
+            self.forwards_left -= 1;
+            self.runner.offload_forward(tokens, pos)
+        }
+
+        fn offload_truncate(&mut self, len: usize) -> Result<()> {
+            self.runner.offload_truncate(len)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "wgpu")]
+    fn test_generate_with_forward_offload_fallback() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2ModelLoader::new().load(&gf)?;
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        )?;
+        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
+
+        let offload = FailingOffload {
+            runner: Llama2Runner::new(&model_wgpu, 200, false)?,
+            forwards_left: 8,
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut runner = Llama2Runner::new(&model_cpu, 200, false)?
+            .with_event_sender(tx)
+            .with_forward_offload(Box::new(offload));
+        let output = runner
+            .prefill_and_generate("Lily is a cat", 16)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(
+            output,
+            " who likes to play with yarn. She has many colors of yarn"
+        );
+
+        // the session moves to the CPU on the 9th forward
+        assert!(!runner.is_offloaded());
+        assert_eq!(runner.fallback_cause().unwrap().message, "device lost");
+        let n_fallbacks = rx
+            .try_iter()
+            .filter(|e| matches!(e, GenerationEvent::BackendFallback { .. }))
+            .count();
+        assert_eq!(n_fallbacks, 1);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "wgpu")]
     fn test_generate_f32_gpu() -> Result<()> {
         let gl: GGUFFileLoader =
             GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
@@ -563,7 +4120,7 @@ mod tests {
             WgpuTensorDeviceOptions::new()
                 .with_staging_buf_bytes(model_cpu.conf.vocab_size * 4)
                 .with_debug_named_tensor(true),
-        );
+        )?;
         let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu.clone())?;
 
         let mut runner_cpu = Llama2Runner::new(&model_cpu, 200, false)?;
@@ -603,4 +4160,37 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[cfg(all(feature = "wgpu", feature = "gguf-edit"))]
+    fn test_forward_gqa_gpu() -> Result<()> {
+        use crate::fixture::write_fixture_model;
+        use crate::fixture::FixtureModelOptions;
+        use crate::fixture::TempDir;
+
+        // the fixture has 4 query heads on 2 KV heads
+        let dir = TempDir::new("gqa-gpu")?;
+        let path = dir.join("gqa-gpu.gguf");
+        write_fixture_model(&path, &FixtureModelOptions::new())?;
+        let gl = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let model_cpu = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        assert!(model_cpu.conf.n_kv_heads < model_cpu.conf.n_heads);
+
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        )?;
+        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
+        let mut runner_cpu = Llama2Runner::new(&model_cpu, 64, false)?;
+        let mut runner_wgpu = Llama2Runner::new(&model_wgpu, 64, false)?;
+
+        let (pos, _, _) = runner_cpu.prefill("the cat played", true, true)?;
+        runner_wgpu.prefill("the cat played", true, true)?;
+        let logits_cpu = runner_cpu.forward(&[1], pos)?.to_vec();
+        let logits_wgpu = runner_wgpu.forward(&[1], pos)?.to_vec();
+        assert_relative_eq!(&logits_cpu[..], &logits_wgpu[..], epsilon = 1e-3);
+        Ok(())
+    }
 }
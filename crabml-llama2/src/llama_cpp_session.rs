@@ -0,0 +1,331 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use half::f16;
+
+use crate::llama2::KvCacheSnapshot;
+
+// "ggsn" in the session files of llama.cpp
+const LLAMA_SESSION_MAGIC: u32 = 0x6767736e;
+
+/// a prompt cache saved by llama.cpp, like `llama-cli --prompt-cache` or
+/// `llama_state_save_file()`. only the session versions 8 and 9 of a single sequence with
+/// the f32 or f16 KV cache are supported.
+#[derive(Debug, Clone)]
+pub struct LlamaCppSession {
+    tokens: Vec<usize>,
+    logits: Option<Vec<f32>>,
+    // (layer) => (n_cells, n_embd_gqa)
+    keys: Vec<Vec<f32>>,
+    values: Vec<Vec<f32>>,
+}
+
+impl LlamaCppSession {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to open the session file {}", path.display()),
+            )
+            .with_cause(err)
+        })?;
+        Self::read(&mut BufReader::new(file))
+    }
+
+    pub fn read(r: &mut impl Read) -> Result<Self> {
+        let mut r = SessionReader { r };
+        let magic = r.read_u32()?;
+        if magic != LLAMA_SESSION_MAGIC {
+            return Err(Error::new(
+                ErrorKind::FormatError,
+                format!("invalid magic {:#x} of the llama.cpp session file", magic),
+            ));
+        }
+        let version = r.read_u32()?;
+        if version != 8 && version != 9 {
+            return Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!("the llama.cpp session version {} is not supported", version),
+            ));
+        }
+
+        let n_tokens = r.read_u32()? as usize;
+        let tokens = (0..n_tokens)
+            .map(|_| r.read_i32().map(|id| id as usize))
+            .collect::<Result<Vec<_>>>()?;
+
+        // the version 9 dropped the state of the RNG
+        if version == 8 {
+            let rng_size = r.read_u64()? as usize;
+            r.read_bytes(rng_size)?;
+        }
+
+        // the positions in the batch of the tokens with the logits
+        let n_outputs = r.read_u32()? as usize;
+        let output_pos = (0..n_outputs)
+            .map(|_| r.read_i32())
+            .collect::<Result<Vec<_>>>()?;
+        let logits_size = r.read_u64()? as usize;
+        let all_logits = r.read_f32s(logits_size)?;
+        let embeddings_size = r.read_u64()? as usize;
+        r.read_bytes(embeddings_size * 4)?;
+
+        // take the logits of the last token
+        let logits = output_pos
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, pos)| **pos)
+            .filter(|_| logits_size > 0 && logits_size % n_outputs == 0)
+            .map(|(i, _)| {
+                let n_vocab = logits_size / n_outputs;
+                all_logits[i * n_vocab..(i + 1) * n_vocab].to_vec()
+            });
+
+        let n_cells = r.read_u32()? as usize;
+        for i in 0..n_cells {
+            let pos = r.read_i32()?;
+            if pos != i as i32 {
+                return Err(Error::new(
+                    ErrorKind::NotImplemented,
+                    format!(
+                        "the cell {} is at position {}, only a single sequence is supported",
+                        i, pos
+                    ),
+                ));
+            }
+            let n_seq_id = r.read_u32()? as usize;
+            r.read_bytes(n_seq_id * 4)?;
+        }
+        if n_cells != tokens.len() {
+            return Err(Error::new(
+                ErrorKind::FormatError,
+                format!(
+                    "the session has {} tokens but {} cells in the KV cache",
+                    tokens.len(),
+                    n_cells
+                ),
+            ));
+        }
+
+        let v_trans = r.read_u32()? != 0;
+        let n_layers = r.read_u32()? as usize;
+        let keys = (0..n_layers)
+            .map(|_| {
+                let typ = r.read_i32()?;
+                let row_size = r.read_u64()? as usize;
+                r.read_kv_rows(typ, row_size, n_cells)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let values = (0..n_layers)
+            .map(|_| {
+                let typ = r.read_i32()?;
+                if !v_trans {
+                    let row_size = r.read_u64()? as usize;
+                    return r.read_kv_rows(typ, row_size, n_cells);
+                }
+                // the transposed values are in (n_embd_gqa, n_cells)
+                let elem_size = r.read_u32()? as usize;
+                let n_embd = r.read_u32()? as usize;
+                let transposed = r.read_kv_rows(typ, elem_size * n_cells, n_embd)?;
+                let mut buf = vec![0.0; transposed.len()];
+                for (j, row) in transposed.chunks(n_cells.max(1)).enumerate() {
+                    for (i, v) in row.iter().enumerate() {
+                        buf[i * n_embd + j] = *v;
+                    }
+                }
+                Ok(buf)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            tokens,
+            logits,
+            keys,
+            values,
+        })
+    }
+
+    /// the tokens in the KV cache.
+    pub fn tokens(&self) -> &[usize] {
+        &self.tokens
+    }
+
+    /// the logits of the last token, if llama.cpp saved them.
+    pub fn logits(&self) -> Option<&[f32]> {
+        self.logits.as_deref()
+    }
+
+    pub fn n_layers(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// convert the KV cache into the layout of crabml, which is split by the heads.
+    pub fn to_kv_cache_snapshot(&self, n_kv_heads: usize) -> Result<KvCacheSnapshot> {
+        let len = self.tokens.len();
+        let to_heads = |buf: &Vec<f32>| {
+            if len == 0 {
+                return Ok(vec![]);
+            }
+            let n_embd = buf.len() / len;
+            if n_embd % n_kv_heads != 0 {
+                return Err(Error::new(
+                    ErrorKind::BadInput,
+                    format!(
+                        "the KV cache row of {} can not be split into {} heads",
+                        n_embd, n_kv_heads
+                    ),
+                ));
+            }
+            let head_dim = n_embd / n_kv_heads;
+            let mut out = Vec::with_capacity(buf.len());
+            for head in 0..n_kv_heads {
+                for row in buf.chunks(n_embd) {
+                    out.extend_from_slice(&row[head * head_dim..(head + 1) * head_dim]);
+                }
+            }
+            Ok(out)
+        };
+        Ok(KvCacheSnapshot {
+            len,
+            keys: self.keys.iter().map(to_heads).collect::<Result<Vec<_>>>()?,
+            values: self
+                .values
+                .iter()
+                .map(to_heads)
+                .collect::<Result<Vec<_>>>()?,
+        })
+    }
+}
+
+struct SessionReader<'a, R: Read> {
+    r: &'a mut R,
+}
+
+impl<'a, R: Read> SessionReader<'a, R> {
+    fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; n];
+        self.r.read_exact(&mut buf).map_err(|err| {
+            Error::new(
+                ErrorKind::FormatError,
+                "the llama.cpp session file is truncated",
+            )
+            .with_cause(err)
+        })?;
+        Ok(buf)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let buf = self.read_bytes(N)?;
+        Ok(buf.try_into().unwrap())
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_f32s(&mut self, n: usize) -> Result<Vec<f32>> {
+        let buf = self.read_bytes(n * 4)?;
+        Ok(buf
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+
+    // read n_rows of row_size bytes, and convert them into f32
+    fn read_kv_rows(&mut self, typ: i32, row_size: usize, n_rows: usize) -> Result<Vec<f32>> {
+        let typ = GGMLType::try_from(typ as u32)?;
+        let buf = self.read_bytes(row_size * n_rows)?;
+        match typ {
+            GGMLType::F32 => Ok(buf
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect()),
+            GGMLType::F16 => Ok(buf
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32())
+                .collect()),
+            _ => Err(Error::new(
+                ErrorKind::NotImplemented,
+                format!("the KV cache of {} in the llama.cpp session", typ),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    #[test]
+    fn test_read_llama_cpp_session() -> Result<()> {
+        // 2 tokens, 1 layer, 2 kv heads of head_dim 1, the values are transposed
+        let mut buf = vec![];
+        push_u32(&mut buf, LLAMA_SESSION_MAGIC);
+        push_u32(&mut buf, 9);
+        push_u32(&mut buf, 2);
+        buf.extend_from_slice(&[1, 0, 0, 0, 5, 0, 0, 0]);
+        // the logits of the last token of a vocab of 3
+        push_u32(&mut buf, 1);
+        push_u32(&mut buf, 1);
+        buf.extend_from_slice(&3_u64.to_le_bytes());
+        for v in [0.5_f32, 1.5, 2.5] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&0_u64.to_le_bytes());
+        // the cells at the positions 0 and 1 of the sequence 0
+        push_u32(&mut buf, 2);
+        for pos in 0..2 {
+            push_u32(&mut buf, pos);
+            push_u32(&mut buf, 1);
+            push_u32(&mut buf, 0);
+        }
+        push_u32(&mut buf, 1);
+        push_u32(&mut buf, 1);
+        // the keys in f32: (cell, embd) = [[1, 2], [3, 4]]
+        push_u32(&mut buf, GGMLType::F32 as u32);
+        buf.extend_from_slice(&8_u64.to_le_bytes());
+        for v in [1.0_f32, 2.0, 3.0, 4.0] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        // the values in f16: (embd, cell) = [[5, 7], [6, 8]]
+        push_u32(&mut buf, GGMLType::F16 as u32);
+        push_u32(&mut buf, 2);
+        push_u32(&mut buf, 2);
+        for v in [5.0_f32, 7.0, 6.0, 8.0] {
+            buf.extend_from_slice(&f16::from_f32(v).to_le_bytes());
+        }
+
+        let session = LlamaCppSession::read(&mut buf.as_slice())?;
+        assert_eq!(session.tokens(), &[1, 5]);
+        assert_eq!(session.logits(), Some(&[0.5, 1.5, 2.5][..]));
+        assert_eq!(session.n_layers(), 1);
+
+        let snapshot = session.to_kv_cache_snapshot(2)?;
+        assert_eq!(snapshot.len, 2);
+        assert_eq!(snapshot.keys, vec![vec![1.0, 3.0, 2.0, 4.0]]);
+        assert_eq!(snapshot.values, vec![vec![5.0, 7.0, 6.0, 8.0]]);
+
+        buf[0] = 0;
+        assert!(LlamaCppSession::read(&mut buf.as_slice()).is_err());
+        Ok(())
+    }
+}
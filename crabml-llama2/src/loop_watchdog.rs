@@ -0,0 +1,116 @@
+/// what to do when the generation is stuck in a cycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopAction {
+    /// stop the generation with the `Loop` stop reason.
+    Abort,
+
+    /// subtract the penalty from the logit of the token which would continue the cycle, so the
+    /// sampler is pushed out of it. the generation goes on.
+    Penalize(f32),
+}
+
+/// detects the generations stuck in the exact cycles, like a small model repeating the same
+/// sentence forever. a cycle is the last tokens repeating with a period, it's only taken as a
+/// loop after it repeated at least `min_repeats` times and covered at least `min_span` tokens,
+/// so the short legit repetitions like a few newlines are not hit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopWatchdog {
+    max_period: usize,
+    min_repeats: usize,
+    min_span: usize,
+    action: LoopAction,
+}
+
+impl Default for LoopWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoopWatchdog {
+    /// abort on the cycles of at most 64 tokens which repeated 4 times over 32 tokens.
+    pub fn new() -> Self {
+        Self {
+            max_period: 64,
+            min_repeats: 4,
+            min_span: 32,
+            action: LoopAction::Abort,
+        }
+    }
+
+    pub fn with_max_period(mut self, max_period: usize) -> Self {
+        self.max_period = max_period.max(1);
+        self
+    }
+
+    pub fn with_min_repeats(mut self, min_repeats: usize) -> Self {
+        self.min_repeats = min_repeats.max(2);
+        self
+    }
+
+    pub fn with_min_span(mut self, min_span: usize) -> Self {
+        self.min_span = min_span;
+        self
+    }
+
+    pub fn with_action(mut self, action: LoopAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn action(&self) -> LoopAction {
+        self.action
+    }
+
+    /// the number of the last tokens needed to detect the cycles.
+    pub fn window(&self) -> usize {
+        (self.max_period * self.min_repeats).max(self.min_span)
+    }
+
+    /// returns the shortest period of the cycle at the end of the tokens, if there's one.
+    pub fn detect(&self, tokens: &[usize]) -> Option<usize> {
+        (1..=self.max_period).find(|&period| {
+            let span = (period * self.min_repeats).max(self.min_span);
+            span <= tokens.len() && {
+                let tail = &tokens[tokens.len() - span..];
+                tail.iter().zip(&tail[period..]).all(|(a, b)| a == b)
+            }
+        })
+    }
+
+    /// the token which continues the cycle at the end of the tokens.
+    pub fn next_in_cycle(&self, tokens: &[usize]) -> Option<usize> {
+        self.detect(tokens)
+            .map(|period| tokens[tokens.len() - period])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_loop() {
+        let watchdog = LoopWatchdog::new().with_min_span(8).with_min_repeats(3);
+
+        // a cycle of [1, 2, 3] after a prefix
+        let mut tokens = vec![9, 8, 7];
+        tokens.extend([1, 2, 3].repeat(3));
+        assert_eq!(watchdog.detect(&tokens), Some(3));
+        assert_eq!(watchdog.next_in_cycle(&tokens), Some(1));
+
+        // not enough repeats
+        assert_eq!(watchdog.detect(&tokens[..tokens.len() - 2]), None);
+
+        // the period of 2 is a cycle of period 4 as well, the shortest one wins
+        let tokens = [5, 6].repeat(8);
+        assert_eq!(watchdog.detect(&tokens), Some(2));
+
+        // a short run of the same token is shorter than the min span
+        let tokens = vec![1, 2, 0, 0, 0, 0];
+        assert_eq!(watchdog.detect(&tokens), None);
+
+        let tokens = (0..100).collect::<Vec<_>>();
+        assert_eq!(watchdog.detect(&tokens), None);
+    }
+}
@@ -1,4 +1,8 @@
-use std::rc::Rc;
+#[cfg(feature = "wgpu")]
+use std::ops::Range;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::vec;
 
 use crabml::backends::cpu::CpuTensor;
@@ -6,29 +10,49 @@ use crabml::backends::cpu::CpuTensorBuf;
 use crabml::backends::cpu::CpuTensorDevice;
 use crabml::backends::cpu::CpuTensorDeviceOptions;
 use crabml::backends::cpu::CpuTensorDeviceRef;
+use crabml::backends::cpu::GemmBackend;
+#[cfg(feature = "wgpu")]
 use crabml::backends::wgpu::WgpuTensor;
+#[cfg(feature = "wgpu")]
 use crabml::backends::wgpu::WgpuTensorDeviceRef;
+use crabml::backends::BackendOptions;
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
+use crabml::error::ResultExt;
+use crabml::gguf::unsupported_types_error;
 use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFTensorInfo;
+use crabml::gguf::KEY_TOKENIZER_PRE;
+use crabml::progress::ProgressReporterRef;
+use crabml::progress::ProgressStage;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
+use crabml::tokenizer::PreTokenizer;
+use crabml::tokenizer::TokenType;
 use crabml::tokenizer::Tokenizer;
 
+use crate::architecture::ArchitectureRegistry;
+use crate::architecture::ForwardSpec;
+use crate::hparams::ModelHParams;
 use crate::sampler::Llama2SamplerRef;
+use crate::sparse_ffn::SparseDownRows;
 use crate::Llama2Sampler;
 
+/// the family of a model, see `Architecture` for how each is loaded and forwarded.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ModelArchitecture {
     Llama,
     Gemma,
+    Qwen2,
 }
 
 #[derive(Debug, Clone)]
 pub struct Llama2Config {
     pub architecture: ModelArchitecture,
+    /// the rope, the activation and the like of the architecture in the forward
+    pub forward: ForwardSpec,
     pub model_name: String,
     pub embedding_dim: usize, // the dim of embedding
     pub hidden_dim: usize,
@@ -39,6 +63,15 @@ pub struct Llama2Config {
     pub seq_len: usize,
     pub rms_norm_eps: f32,
     pub rope_dim: Option<usize>,
+    /// the base of the rope frequencies, 10000 on llama2 and 1000000 on CodeLlama
+    pub rope_theta: f32,
+    /// the positions are multiplied by it in the rope, below 1 on the models with a linearly
+    /// extended context
+    pub rope_freq_scale: f32,
+    /// the FFN is a mixture of n_experts experts like Mixtral, each token is routed to the
+    /// n_experts_used of them. 0 on the dense models
+    pub n_experts: usize,
+    pub n_experts_used: usize,
 }
 
 impl Llama2Config {
@@ -58,18 +91,46 @@ pub struct Llama2Weights<T: Tensor> {
     pub rms_att_weight: Vec<T>, // (layer, dim) rmsnorm weights
     pub rms_ffn_weight: Vec<T>, // (layer, dim)
     // weights for matmuls
+    // empty if the QKV projection is fused into wqkv
     pub wq: Vec<T>, // (layer, embedding_dim, embedding_dim)
     pub wk: Vec<T>, // (layer, kv_dim, embedding_dim)
     pub wv: Vec<T>, // (layer, kv_dim, embedding_dim)
+    // (optional) wq, wk, wv concatenated along the rows, empty if the QKV projection is not fused
+    pub wqkv: Vec<T>, // (layer, embedding_dim + 2 * kv_dim, embedding_dim)
+    // (optional) the biases of the q, k, v projections like Qwen2, empty if the model has none
+    pub bq: Vec<T>, // (layer, embedding_dim)
+    pub bk: Vec<T>, // (layer, kv_dim)
+    pub bv: Vec<T>, // (layer, kv_dim)
     pub wo: Vec<T>, // (layer, embedding_dim, embedding_dim)
     // weights for ffn
     pub ffn_gate_weight: Vec<T>, // (layer, hidden_dim, embedding_dim)
     pub ffn_down_weight: Vec<T>, // (layer, embedding_dim, hidden_dim)
     pub ffn_up_weight: Vec<T>,   // (layer, hidden_dim, embedding_dim)
+    // (optional) the router and the experts of the mixture-of-experts FFN like Mixtral, empty
+    // on the dense models. the ffn weights above are empty on the MoE models instead
+    pub ffn_gate_inp: Vec<T>,       // (layer, n_experts, embedding_dim)
+    pub ffn_gate_exps: Vec<Vec<T>>, // (layer, expert, hidden_dim, embedding_dim)
+    pub ffn_down_exps: Vec<Vec<T>>, // (layer, expert, embedding_dim, hidden_dim)
+    pub ffn_up_exps: Vec<Vec<T>>,   // (layer, expert, hidden_dim, embedding_dim)
     // final rmsnorm
     pub rms_final_weight: T, // (dim, )
     // (optional) classifier weights for the logits, on the last layer
     pub output_weight: Option<T>, // (vocab_size, dim)
+    // the down projections transposed for the sparse FFN, shared by the runners
+    pub sparse_down_rows: SparseDownRows,
+}
+
+impl<T: Tensor> Llama2Weights<T> {
+    /// the output head reuses the token embedding, like Gemma or the files which store a copy
+    /// of the embedding as the output weight.
+    pub fn tied_embeddings(&self) -> bool {
+        self.output_weight.is_none()
+    }
+
+    /// the FFN of each layer is a mixture of experts, like Mixtral.
+    pub fn is_moe(&self) -> bool {
+        !self.ffn_gate_inp.is_empty()
+    }
 }
 
 pub trait Llama2Model {
@@ -79,21 +140,29 @@ pub trait Llama2Model {
 
     fn device(&self) -> <Self::T as Tensor>::Device;
 
-    fn weights(&self) -> Rc<Llama2Weights<Self::T>>;
+    fn weights(&self) -> Arc<Llama2Weights<Self::T>>;
 
-    fn tokenizer(&self) -> Rc<Tokenizer>;
+    /// a clone of the tokenizer with its own decoding state, the vocab is shared.
+    fn tokenizer(&self) -> Tokenizer;
 
+    /// a new sampler for each session.
     fn sampler(&self) -> Llama2SamplerRef;
 
     fn metrics(&self) -> &TensorMetrics;
 }
 
+/// the loaded model only contains the immutable weights, and it's Send + Sync, so one model
+/// can be shared by many threads, each thread runs its own `Llama2Runner` which holds the
+/// mutable states of the session like the KV cache and the sampler.
 pub struct CpuLlama2Model<'a> {
     pub conf: Llama2Config,
-    pub weights: Rc<Llama2Weights<CpuTensor<'a>>>,
-    pub tokenizer: Rc<Tokenizer>,
+    pub weights: Arc<Llama2Weights<CpuTensor<'a>>>,
+    pub tokenizer: Tokenizer,
     pub device: CpuTensorDeviceRef<'a>,
-    pub sampler: Llama2SamplerRef,
+    pub temperature: f32,
+    pub topk: usize,
+    pub probability: f32,
+    pub seed: Option<u64>,
     pub metrics: TensorMetrics,
 }
 
@@ -108,16 +177,22 @@ impl<'a> Llama2Model for &CpuLlama2Model<'a> {
         self.device.clone()
     }
 
-    fn weights(&self) -> Rc<Llama2Weights<CpuTensor<'a>>> {
+    fn weights(&self) -> Arc<Llama2Weights<CpuTensor<'a>>> {
         self.weights.clone()
     }
 
-    fn tokenizer(&self) -> Rc<Tokenizer> {
+    fn tokenizer(&self) -> Tokenizer {
         self.tokenizer.clone()
     }
 
     fn sampler(&self) -> Llama2SamplerRef {
-        self.sampler.clone()
+        Llama2Sampler::new_with_topk(
+            self.conf.vocab_size,
+            self.temperature,
+            self.topk,
+            self.probability,
+            self.seed,
+        )
     }
 
     fn metrics(&self) -> &TensorMetrics {
@@ -128,9 +203,26 @@ impl<'a> Llama2Model for &CpuLlama2Model<'a> {
 pub struct CpuLlama2ModelLoader {
     temprature: f32,
 
+    topk: usize,
+
     probability: f32,
 
+    seed: Option<u64>,
+
     device_options: CpuTensorDeviceOptions,
+
+    progress_reporter: Option<ProgressReporterRef>,
+    // the tensors loaded so far, to report the progress of loading
+    loaded_tensors: AtomicUsize,
+
+    fused_qkv: bool,
+
+    prepacking: bool,
+    w8a8: bool,
+    normalize_nfc: bool,
+    dequantize_unsupported: bool,
+    add_bos: Option<bool>,
+    architectures: ArchitectureRegistry,
 }
 
 impl Default for CpuLlama2ModelLoader {
@@ -144,8 +236,19 @@ impl CpuLlama2ModelLoader {
         // this default value is suiteable for running tests
         Self {
             temprature: 0.0,
+            topk: 0,
             probability: 0.0,
+            seed: None,
             device_options: CpuTensorDeviceOptions::default(),
+            progress_reporter: None,
+            loaded_tensors: AtomicUsize::new(0),
+            fused_qkv: false,
+            prepacking: false,
+            w8a8: false,
+            normalize_nfc: false,
+            dequantize_unsupported: false,
+            add_bos: None,
+            architectures: ArchitectureRegistry::default(),
         }
     }
 
@@ -154,13 +257,35 @@ impl CpuLlama2ModelLoader {
         self
     }
 
+    /// only sample from the topk most likely tokens, 0 keeps all the tokens.
+    pub fn with_topk(mut self, topk: usize) -> Self {
+        self.topk = topk;
+        self
+    }
+
     pub fn with_probability(mut self, probability: f32) -> Self {
         self.probability = probability;
         self
     }
 
+    /// seed the RNG of the samplers, so the runners sample the same tokens on the same inputs.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
     pub fn with_thread_num(mut self, thread_num: usize) -> Self {
-        self.device_options.thread_num = thread_num;
+        self.device_options = self.device_options.with_thread_num(thread_num);
+        self
+    }
+
+    pub fn with_backend_options(mut self, backend: BackendOptions) -> Self {
+        self.device_options = self.device_options.with_backend_options(backend);
+        self
+    }
+
+    pub fn with_gemm_backend(mut self, gemm_backend: GemmBackend) -> Self {
+        self.device_options.gemm_backend = gemm_backend;
         self
     }
 
@@ -169,24 +294,111 @@ impl CpuLlama2ModelLoader {
         self
     }
 
+    pub fn with_progress_reporter(mut self, reporter: ProgressReporterRef) -> Self {
+        self.progress_reporter = Some(reporter);
+        self
+    }
+
+    /// concatenate the wq, wk, wv weights of each layer on loading, so the attention block
+    /// projects the QKV in a single matmul instead of three smaller ones. the concatenation
+    /// copies the QKV weights out of the mmaped file into the heap, like the prepacking, and
+    /// the prepacking or the W8A8 quantization copies the fused weights once more on loading.
+    /// the separated weights are dropped after the concatenation, so they are not kept twice.
+    pub fn with_fused_qkv(mut self, fused_qkv: bool) -> Self {
+        self.fused_qkv = fused_qkv;
+        self
+    }
+
+    /// repack the weights of the linear layers into the interleaved layout consumed by the
+    /// SIMD matmul kernels on loading. it takes a few seconds more on loading and copies the
+    /// weights out of the mmaped file, but the matmul gets faster. only Q8_0 weights are packed
+    /// for now.
+    pub fn with_prepacking(mut self, prepacking: bool) -> Self {
+        self.prepacking = prepacking;
+        self
+    }
+
+    /// experimental: quantize the weights of the linear layers into int8 with a scale per row on
+    /// loading, and the activations into int8 with a scale per token in the matmul, the dot
+    /// products are accumulated in i32 with VNNI where the target has it. it copies the weights
+    /// out of the mmaped file and loses some precision over the weights in the file. the W8A8
+    /// weights are not packed.
+    pub fn with_w8a8(mut self, w8a8: bool) -> Self {
+        self.w8a8 = w8a8;
+        self
+    }
+
+    /// normalize the prompts into the unicode NFC form before tokenizing.
+    pub fn with_normalize_nfc(mut self, normalize_nfc: bool) -> Self {
+        self.normalize_nfc = normalize_nfc;
+        self
+    }
+
+    /// load the tensors in the types which have no kernels, like the integer types, as f16
+    /// instead of failing. it copies them out of the mmaped file. the types which can not be
+    /// decoded, like the IQ quantizations, are still an error.
+    pub fn with_dequantize_unsupported(mut self, dequantize_unsupported: bool) -> Self {
+        self.dequantize_unsupported = dequantize_unsupported;
+        self
+    }
+
+    /// whether to prepend the BOS token to the prompts, overriding the
+    /// `tokenizer.ggml.add_bos_token` of the metadata.
+    pub fn with_add_bos(mut self, add_bos: bool) -> Self {
+        self.add_bos = Some(add_bos);
+        self
+    }
+
+    /// the architectures the models are loaded in, llama, gemma and qwen2 by default.
+    pub fn with_architecture_registry(mut self, architectures: ArchitectureRegistry) -> Self {
+        self.architectures = architectures;
+        self
+    }
+
+    fn report_progress(&self, stage: ProgressStage, completed: usize, total: usize) {
+        if let Some(reporter) = &self.progress_reporter {
+            reporter.report(stage, completed, total);
+        }
+    }
+
+    // count a tensor loaded from the file, and report the progress of loading
+    fn count_loaded_tensor(&self, gf: &GGUFFile) {
+        let total = gf.tensor_infos().len();
+        let loaded = self.loaded_tensors.fetch_add(1, Ordering::Relaxed) + 1;
+        // the last report with completed == total is left to the end of load_weights
+        if loaded < total {
+            self.report_progress(ProgressStage::Load, loaded, total);
+        }
+    }
+
     pub fn load<'a>(self, gf: &'a GGUFFile<'a>) -> Result<CpuLlama2Model<'a>> {
         let device = CpuTensorDevice::with_options(self.device_options.clone());
         let metrics = device.metrics().clone();
         let conf = self.load_config(gf)?;
         let weights = self.load_weights(gf, conf.n_layers, device.clone())?;
+        let n_routed = weights
+            .ffn_gate_inp
+            .first()
+            .map_or(0, |router| router.shape()[0]);
+        if n_routed != conf.n_experts {
+            return Err(Error::new(
+                ErrorKind::ModelError,
+                format!(
+                    "the ffn routes to {} experts, but the expert_count of the metadata is {}",
+                    n_routed, conf.n_experts
+                ),
+            ));
+        }
         let tokenizer = self.load_tokenizer(gf)?;
-        let sampler = Llama2Sampler::new(
-            conf.vocab_size,
-            self.temprature,
-            self.probability,
-            device.exp_cache(),
-        );
         Ok(CpuLlama2Model {
             conf,
-            weights: Rc::new(weights),
+            weights: Arc::new(weights),
             device,
-            tokenizer: Rc::new(tokenizer),
-            sampler,
+            tokenizer,
+            temperature: self.temprature,
+            topk: self.topk,
+            probability: self.probability,
+            seed: self.seed,
             metrics,
         })
     }
@@ -197,6 +409,18 @@ impl CpuLlama2ModelLoader {
         n_layers: usize,
         device: CpuTensorDeviceRef<'a>,
     ) -> Result<Llama2Weights<CpuTensor<'a>>> {
+        // report all the tensors which can not be loaded at once, instead of the first one
+        let unsupported = gf
+            .tensor_infos()
+            .iter()
+            .filter(|info| !CpuTensorBuf::is_supported_type(info.typ()))
+            .map(|info| (info.name().to_string(), info.typ().to_string()))
+            .collect::<Vec<_>>();
+        if !unsupported.is_empty() && !self.dequantize_unsupported {
+            return Err(unsupported_types_error(&unsupported));
+        }
+
+        self.loaded_tensors.store(0, Ordering::Relaxed);
         // [64 (dim), 512 (vocab_size)]
         let token_embed = self.load_tensor(gf, "token_embd.weight", device.clone())?;
         let mut wq = vec![];
@@ -206,8 +430,15 @@ impl CpuLlama2ModelLoader {
         let mut ffn_gate_weight = vec![];
         let mut ffn_down_weight = vec![];
         let mut ffn_up_weight = vec![];
+        let mut ffn_gate_inp = vec![];
+        let mut ffn_gate_exps = vec![];
+        let mut ffn_down_exps = vec![];
+        let mut ffn_up_exps = vec![];
         let mut rms_att_weight = vec![];
         let mut rms_ffn_weight = vec![];
+        let mut bq = vec![];
+        let mut bk = vec![];
+        let mut bv = vec![];
         for layer in 0..n_layers {
             wq.push(self.load_tensor(
                 gf,
@@ -229,22 +460,36 @@ impl CpuLlama2ModelLoader {
                 &format!("blk.{}.attn_output.weight", layer),
                 device.clone(),
             )?);
-            // (hidden_dim:172, embedding_dim:64)
-            ffn_gate_weight.push(self.load_tensor(
-                gf,
-                &format!("blk.{}.ffn_gate.weight", layer),
-                device.clone(),
-            )?);
-            ffn_down_weight.push(self.load_tensor(
-                gf,
-                &format!("blk.{}.ffn_down.weight", layer),
-                device.clone(),
-            )?);
-            ffn_up_weight.push(self.load_tensor(
-                gf,
-                &format!("blk.{}.ffn_up.weight", layer),
-                device.clone(),
-            )?);
+            // the MoE layers have a router and the experts in place of the ffn weights
+            let router_name = format!("blk.{}.ffn_gate_inp.weight", layer);
+            if let Some(router) = self.load_tensor_optional(gf, &router_name, device.clone())? {
+                let n_experts = router.shape()[0];
+                ffn_gate_inp.push(router);
+                for (experts, name) in [
+                    (&mut ffn_gate_exps, "ffn_gate"),
+                    (&mut ffn_down_exps, "ffn_down"),
+                    (&mut ffn_up_exps, "ffn_up"),
+                ] {
+                    experts.push(self.load_experts(gf, layer, name, n_experts, device.clone())?);
+                }
+            } else {
+                // (hidden_dim:172, embedding_dim:64)
+                ffn_gate_weight.push(self.load_tensor(
+                    gf,
+                    &format!("blk.{}.ffn_gate.weight", layer),
+                    device.clone(),
+                )?);
+                ffn_down_weight.push(self.load_tensor(
+                    gf,
+                    &format!("blk.{}.ffn_down.weight", layer),
+                    device.clone(),
+                )?);
+                ffn_up_weight.push(self.load_tensor(
+                    gf,
+                    &format!("blk.{}.ffn_up.weight", layer),
+                    device.clone(),
+                )?);
+            }
             rms_att_weight.push(
                 self.load_tensor(
                     gf,
@@ -261,27 +506,124 @@ impl CpuLlama2ModelLoader {
                 )?
                 .dequantize(GGMLType::F32)?,
             );
+            for (biases, name) in [
+                (&mut bq, "attn_q"),
+                (&mut bk, "attn_k"),
+                (&mut bv, "attn_v"),
+            ] {
+                let name = format!("blk.{}.{}.bias", layer, name);
+                if let Some(bias) = self.load_tensor_optional(gf, &name, device.clone())? {
+                    biases.push(bias.dequantize(GGMLType::F32)?);
+                }
+            }
         }
         let rms_final_weight = self
             .load_tensor(gf, "output_norm.weight", device.clone())?
             .dequantize(GGMLType::F32)?;
+        if !ffn_gate_inp.is_empty() && ffn_gate_inp.len() != n_layers {
+            return Err(Error::new(
+                ErrorKind::ModelError,
+                format!(
+                    "ffn_gate_inp.weight is only found on {} of the {} layers",
+                    ffn_gate_inp.len(),
+                    n_layers
+                ),
+            ));
+        }
+        for (biases, name) in [(&bq, "attn_q"), (&bk, "attn_k"), (&bv, "attn_v")] {
+            if !biases.is_empty() && biases.len() != n_layers {
+                return Err(Error::new(
+                    ErrorKind::ModelError,
+                    format!(
+                        "{}.bias is only found on {} of the {} layers",
+                        name,
+                        biases.len(),
+                        n_layers
+                    ),
+                ));
+            }
+        }
+
+        // in Gemma, the output weight is None. some files of the tied models store a copy of
+        // the token embedding as the output weight, the copy is dropped to use the embedding
+        let mut output_weight = match (
+            gf.get_tensor_info("output.weight"),
+            gf.get_tensor_info("token_embd.weight"),
+        ) {
+            (Some(output), Some(embed)) if is_same_tensor(&output, &embed) => None,
+            _ => self.load_tensor_optional(gf, "output.weight", device)?,
+        };
 
-        // in Gemma, the output weight is None
-        let output_weight = self.load_tensor_optional(gf, "output.weight", device)?;
+        // the separated weights are not kept beside the fused ones, so they are neither packed
+        // nor uploaded to the GPU
+        let mut wqkv = vec![];
+        if self.fused_qkv {
+            wqkv = (0..n_layers)
+                .map(|l| CpuTensor::concat_rows(&[&wq[l], &wk[l], &wv[l]]))
+                .collect::<Result<Vec<_>>>()?;
+            wq.clear();
+            wk.clear();
+            wv.clear();
+        }
+
+        // the token embedding is looked up by rows, and it's also the output weight in Gemma,
+        // it's kept unpacked.
+        if self.w8a8 || self.prepacking {
+            let convert = |w: CpuTensor<'a>| {
+                if self.w8a8 {
+                    w.quantize_w8a8()
+                } else {
+                    w.prepack()
+                }
+            };
+            for weights in [
+                &mut wq,
+                &mut wk,
+                &mut wv,
+                &mut wqkv,
+                &mut wo,
+                &mut ffn_gate_weight,
+                &mut ffn_down_weight,
+                &mut ffn_up_weight,
+                &mut ffn_gate_inp,
+            ]
+            .into_iter()
+            .chain(ffn_gate_exps.iter_mut())
+            .chain(ffn_down_exps.iter_mut())
+            .chain(ffn_up_exps.iter_mut())
+            {
+                *weights = std::mem::take(weights)
+                    .into_iter()
+                    .map(convert)
+                    .collect::<Result<Vec<_>>>()?;
+            }
+            output_weight = output_weight.map(convert).transpose()?;
+        }
+        let total_tensors = gf.tensor_infos().len();
+        self.report_progress(ProgressStage::Load, total_tensors, total_tensors);
 
         Ok(Llama2Weights {
             token_embed,
             wq,
             wk,
             wv,
+            wqkv,
+            bq,
+            bk,
+            bv,
             wo,
             ffn_gate_weight,
             ffn_down_weight,
             ffn_up_weight,
+            ffn_gate_inp,
+            ffn_gate_exps,
+            ffn_down_exps,
+            ffn_up_exps,
             rms_att_weight,
             rms_ffn_weight,
             rms_final_weight,
             output_weight,
+            sparse_down_rows: SparseDownRows::new(n_layers),
         })
     }
 
@@ -298,10 +640,75 @@ impl CpuLlama2ModelLoader {
 
         // the dimensions stored in GGUF seems in a reverse order of numpy's shape
         let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
-        let tensor = CpuTensor::from_bytes(info.data(), info.typ(), &dims, device.clone())?;
+        let tensor = self.tensor_from_bytes(info.data(), info.typ(), &dims, name, device)?;
+        self.count_loaded_tensor(gf);
         Ok(Some(tensor))
     }
 
+    fn tensor_from_bytes<'a>(
+        &self,
+        data: &'a [u8],
+        typ: GGMLType,
+        dims: &[usize],
+        name: &str,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<CpuTensor<'a>> {
+        if !CpuTensorBuf::is_supported_type(typ) && self.dequantize_unsupported {
+            return CpuTensor::from_bytes_f16(data, typ, dims, device)
+                .with_op("load_tensor")
+                .with_tensor(name, dims);
+        }
+        CpuTensor::from_bytes(data, typ, dims, device)
+            .with_op("load_tensor")
+            .with_tensor(name, dims)
+    }
+
+    // the experts of a ffn weight in a MoE layer, stacked in a single tensor like
+    // blk.0.ffn_gate_exps.weight, or in a tensor of each expert like blk.0.ffn_gate.0.weight
+    // in the older files of Mixtral. the stacked tensor is sliced without copying.
+    fn load_experts<'a>(
+        &self,
+        gf: &'a GGUFFile<'a>,
+        layer: usize,
+        name: &str,
+        n_experts: usize,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Vec<CpuTensor<'a>>> {
+        let stacked_name = format!("blk.{}.{}_exps.weight", layer, name);
+        let info = match gf.get_tensor_info(&stacked_name) {
+            Some(info) => info.clone(),
+            None => {
+                return (0..n_experts)
+                    .map(|e| {
+                        let name = format!("blk.{}.{}.{}.weight", layer, name, e);
+                        self.load_tensor(gf, &name, device.clone())
+                    })
+                    .collect();
+            }
+        };
+
+        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+        if dims.len() != 3 || dims[0] != n_experts {
+            return Err(Error::new(
+                ErrorKind::ModelError,
+                format!(
+                    "{} is in the shape {:?}, expect {} experts like the router",
+                    stacked_name, dims, n_experts
+                ),
+            ));
+        }
+        let expert_bytes = info.data().len() / n_experts;
+        let experts = info
+            .data()
+            .chunks(expert_bytes)
+            .map(|data| {
+                self.tensor_from_bytes(data, info.typ(), &dims[1..], &stacked_name, device.clone())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.count_loaded_tensor(gf);
+        Ok(experts)
+    }
+
     pub(crate) fn load_tensor<'a>(
         &self,
         gf: &'a GGUFFile<'a>,
@@ -309,16 +716,15 @@ impl CpuLlama2ModelLoader {
         device: CpuTensorDeviceRef<'a>,
     ) -> Result<CpuTensor<'a>> {
         match self.load_tensor_optional(gf, name, device)? {
-            None => Err(Error {
-                kind: ErrorKind::TensorNotFound,
-                message: format!("failed to find tensor {}", name),
-                cause: None,
-            }),
+            None => Err(Error::new(
+                ErrorKind::TensorNotFound,
+                format!("failed to find tensor {}", name),
+            )),
             Some(v) => Ok(v),
         }
     }
 
-    fn load_tokenizer(&self, gf: &GGUFFile) -> Result<Tokenizer> {
+    pub fn load_tokenizer(&self, gf: &GGUFFile) -> Result<Tokenizer> {
         let vocab = gf
             .metadata()
             .get_string_array("tokenizer.ggml.tokens")
@@ -330,16 +736,18 @@ impl CpuLlama2ModelLoader {
             .metadata()
             .get_u32("tokenizer.ggml.eos_token_id")
             .unwrap() as usize;
+        // Qwen2 may have no BOS token, it takes the EOS token which is never prepended
         let bos_token = gf
             .metadata()
             .get_u32("tokenizer.ggml.bos_token_id")
-            .unwrap() as usize;
+            .map(|v| v as usize)
+            .unwrap_or(eos_token);
         let tokenizer_kind = gf
             .metadata()
             .get_string("tokenizer.ggml.model")
             .unwrap()
             .to_string();
-        match tokenizer_kind.as_str() {
+        let tokenizer = match tokenizer_kind.as_str() {
             "llama" => {
                 // it seems that .to_vec() will raise an memory issue but it's ok with
                 // iter().cloned().collect(), strange.
@@ -351,13 +759,9 @@ impl CpuLlama2ModelLoader {
                     .iter()
                     .cloned()
                     .collect::<Vec<_>>();
-                Ok(Tokenizer::new_llama(
-                    vocab,
-                    vocab_scores,
-                    bos_token,
-                    eos_token,
-                ))
+                Tokenizer::new_llama(vocab, vocab_scores, bos_token, eos_token)
             }
+            #[cfg(feature = "gpt2-tokenizer")]
             "gpt2" => {
                 let merges = gf
                     .metadata()
@@ -366,99 +770,78 @@ impl CpuLlama2ModelLoader {
                     .iter()
                     .map(|s| s.to_string())
                     .collect::<Vec<_>>();
-                Ok(Tokenizer::new_gpt2(vocab, merges, bos_token, eos_token))
+                Tokenizer::new_gpt2(vocab, merges, bos_token, eos_token)
             }
-            other => Err(Error::new(
-                ErrorKind::IOError,
-                format!("unsupported tokenizer {}", other),
-            )),
-        }
-    }
-
-    fn load_config(&self, gf: &GGUFFile) -> Result<Llama2Config> {
-        // let rope_dims = gf.metadata().get_u32("llama.rope.dimension_count").unwrap();
-        let (architecture, prefix) = match gf.metadata().get_string("general.architecture").unwrap()
-        {
-            "llama" => (ModelArchitecture::Llama, "llama"),
-            "gemma" => (ModelArchitecture::Gemma, "gemma"),
-            arch => {
-                return Err(Error {
-                    kind: ErrorKind::ModelError,
-                    message: format!("unsupported architecture {}", arch),
-                    cause: None,
-                });
+            other => {
+                return Err(Error::new(
+                    ErrorKind::IOError,
+                    format!("unsupported tokenizer {}", other),
+                ));
             }
         };
-        let model_name = gf
-            .metadata()
-            .get_string("general.name")
-            .unwrap()
-            .to_string();
 
-        let n_heads = gf
+        let token_types = gf
             .metadata()
-            .get_u32(&format!("{}.attention.head_count", prefix))
-            .unwrap() as usize;
-        let n_layers = gf
-            .metadata()
-            .get_u32(&format!("{}.block_count", prefix))
-            .unwrap() as usize;
-        let hidden_dim = gf
-            .metadata()
-            .get_u32(&format!("{}.feed_forward_length", prefix))
-            .unwrap() as usize;
-        let n_kv_heads = gf
-            .metadata()
-            .get_u32(&format!("{}.attention.head_count_kv", prefix))
-            .unwrap() as usize;
-        let seq_len = gf
-            .metadata()
-            .get_u32(&format!("{}.context_length", prefix))
-            .unwrap() as usize;
-        let vocab_size = gf
-            .metadata()
-            .get_string_array("tokenizer.ggml.tokens")
-            .unwrap()
-            .len();
-        let embedding_dim = gf
-            .metadata()
-            .get_u32(&format!("{}.embedding_length", prefix))
-            .unwrap() as usize;
-        let rms_norm_eps = gf
-            .metadata()
-            .get_f32(&format!("{}.attention.layer_norm_rms_epsilon", prefix))
-            .unwrap();
-        let n_rot = gf
-            .metadata()
-            .get_u32(&format!("{}.rope.dimension_count", prefix))
-            .map(|v| v as usize);
-
-        Ok(Llama2Config {
-            architecture,
-            model_name,
-            n_heads,
-            n_kv_heads,
-            n_layers,
-            embedding_dim,
-            hidden_dim,
-            seq_len,
-            vocab_size,
-            rms_norm_eps,
-            rope_dim: n_rot,
-        })
+            .get_i32_array("tokenizer.ggml.token_type")
+            .map(|types| types.iter().map(|v| TokenType::from_gguf(*v)).collect())
+            .unwrap_or_default();
+        let get_token_id = |key: &str| gf.metadata().get_u32(key).map(|v| v as usize);
+        let tokenizer = tokenizer
+            .with_token_types(token_types)
+            .with_pad_token(get_token_id("tokenizer.ggml.padding_token_id"))
+            .with_unk_token(get_token_id("tokenizer.ggml.unknown_token_id"));
+
+        // the flags missing in the metadata take the defaults of the tokenizer kind, and of the
+        // architecture like Qwen2, which prepends no BOS token like llama.cpp
+        let get_flag = |key: &str| gf.metadata().get_bool(key).map(|v| v != 0);
+        let mut options = tokenizer.options().with_normalize_nfc(self.normalize_nfc);
+        let arch_add_bos = self
+            .architectures
+            .resolve(gf.metadata())
+            .ok()
+            .and_then(|arch| arch.add_bos());
+        if let Some(add_bos) = arch_add_bos {
+            options = options.with_add_bos(add_bos);
+        }
+        if let Some(pre) = gf.metadata().get_string(KEY_TOKENIZER_PRE) {
+            options = options.with_pre_tokenizer(PreTokenizer::from_gguf(pre));
+        }
+        if let Some(add_bos) = get_flag("tokenizer.ggml.add_bos_token") {
+            options = options.with_add_bos(add_bos);
+        }
+        if let Some(add_eos) = get_flag("tokenizer.ggml.add_eos_token") {
+            options = options.with_add_eos(add_eos);
+        }
+        if let Some(add_space_prefix) = get_flag("tokenizer.ggml.add_space_prefix") {
+            options = options.with_add_space_prefix(add_space_prefix);
+        }
+        if let Some(add_bos) = self.add_bos {
+            options = options.with_add_bos(add_bos);
+        }
+        Ok(tokenizer.with_options(options))
+    }
+
+    fn load_config(&self, gf: &GGUFFile) -> Result<Llama2Config> {
+        ModelHParams::from_gguf_with_registry(gf.metadata(), &self.architectures)
+            .map(Llama2Config::from)
     }
 }
 
+#[cfg(feature = "wgpu")]
 #[derive(Clone)]
 pub struct WgpuLlama2Model {
     pub conf: Llama2Config,
-    pub weights: Rc<Llama2Weights<WgpuTensor>>,
-    pub tokenizer: Rc<Tokenizer>,
+    pub weights: Arc<Llama2Weights<WgpuTensor>>,
+    pub tokenizer: Tokenizer,
     pub device: WgpuTensorDeviceRef,
-    pub sampler: Llama2SamplerRef,
+    pub temperature: f32,
+    pub topk: usize,
+    pub probability: f32,
+    pub seed: Option<u64>,
     pub metrics: TensorMetrics,
 }
 
+#[cfg(feature = "wgpu")]
 impl Llama2Model for &WgpuLlama2Model {
     type T = WgpuTensor;
 
@@ -466,7 +849,7 @@ impl Llama2Model for &WgpuLlama2Model {
         self.conf.clone()
     }
 
-    fn weights(&self) -> Rc<Llama2Weights<WgpuTensor>> {
+    fn weights(&self) -> Arc<Llama2Weights<WgpuTensor>> {
         self.weights.clone()
     }
 
@@ -474,12 +857,18 @@ impl Llama2Model for &WgpuLlama2Model {
         self.device.clone()
     }
 
-    fn tokenizer(&self) -> Rc<Tokenizer> {
+    fn tokenizer(&self) -> Tokenizer {
         self.tokenizer.clone()
     }
 
     fn sampler(&self) -> Llama2SamplerRef {
-        self.sampler.clone()
+        Llama2Sampler::new_with_topk(
+            self.conf.vocab_size,
+            self.temperature,
+            self.topk,
+            self.probability,
+            self.seed,
+        )
     }
 
     fn metrics(&self) -> &TensorMetrics {
@@ -487,14 +876,43 @@ impl Llama2Model for &WgpuLlama2Model {
     }
 }
 
+#[cfg(feature = "wgpu")]
 impl WgpuLlama2Model {
     pub fn from_cpu(cpu_model: &CpuLlama2Model, device: WgpuTensorDeviceRef) -> Result<Self> {
-        let weights = Self::convert_cpu_weights(&cpu_model.weights, device.clone())?;
+        let layers = 0..cpu_model.conf.n_layers;
+        Self::convert_cpu_model(cpu_model, device, layers, true)
+    }
+
+    /// upload the weights of the layers in the range only, as a model of these layers. it
+    /// forwards them for a runner which splits the layers over the devices with
+    /// `Llama2Runner::with_layer_offload`, the token embedding and the output head stay on the
+    /// cpu.
+    pub fn from_cpu_layers(
+        cpu_model: &CpuLlama2Model,
+        device: WgpuTensorDeviceRef,
+        layers: Range<usize>,
+    ) -> Result<Self> {
+        Self::convert_cpu_model(cpu_model, device, layers, false)
+    }
+
+    fn convert_cpu_model(
+        cpu_model: &CpuLlama2Model,
+        device: WgpuTensorDeviceRef,
+        layers: Range<usize>,
+        with_head: bool,
+    ) -> Result<Self> {
+        let mut conf = cpu_model.conf.clone();
+        conf.n_layers = layers.len();
+        let weights =
+            Self::convert_cpu_weights(&cpu_model.weights, device.clone(), layers, with_head)?;
         Ok(Self {
-            conf: cpu_model.conf.clone(),
-            weights: Rc::new(weights),
+            conf,
+            weights: Arc::new(weights),
             tokenizer: cpu_model.tokenizer.clone(),
-            sampler: cpu_model.sampler.clone(),
+            temperature: cpu_model.temperature,
+            topk: cpu_model.topk,
+            probability: cpu_model.probability,
+            seed: cpu_model.seed,
             metrics: cpu_model.metrics.clone(),
             device,
         })
@@ -503,50 +921,83 @@ impl WgpuLlama2Model {
     fn convert_cpu_weights(
         weights: &Llama2Weights<CpuTensor>,
         device: WgpuTensorDeviceRef,
+        layers: Range<usize>,
+        with_head: bool,
     ) -> Result<Llama2Weights<WgpuTensor>> {
-        let token_embedding_table = Self::convert_cpu_tensor(&weights.token_embed, device.clone())?;
-        let wq = weights
-            .wq
+        // a model without the head never reads the token embedding, the final norm stands in
+        // for it to not take the VRAM
+        let token_embedding_table = if with_head {
+            Self::convert_cpu_tensor(&weights.token_embed, device.clone())?
+        } else {
+            Self::convert_cpu_tensor(&weights.rms_final_weight, device.clone())?
+        };
+        let wq = layer_range(&weights.wq, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let wk = weights
-            .wk
+        let wk = layer_range(&weights.wk, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let wv = weights
-            .wv
+        let wv = layer_range(&weights.wv, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let wo = weights
-            .wo
+        let wqkv = layer_range(&weights.wqkv, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let w1 = weights
-            .ffn_gate_weight
+        let bq = layer_range(&weights.bq, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let w2 = weights
-            .ffn_down_weight
+        let bk = layer_range(&weights.bk, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let w3 = weights
-            .ffn_up_weight
+        let bv = layer_range(&weights.bv, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let rms_att_weight = weights
-            .rms_att_weight
+        let wo = layer_range(&weights.wo, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let rms_ffn_weight = weights
-            .rms_ffn_weight
+        let w1 = layer_range(&weights.ffn_gate_weight, &layers)
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let w2 = layer_range(&weights.ffn_down_weight, &layers)
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let w3 = layer_range(&weights.ffn_up_weight, &layers)
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let ffn_gate_inp = layer_range(&weights.ffn_gate_inp, &layers)
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let convert_experts = |experts: &[Vec<CpuTensor>]| {
+            experts
+                .iter()
+                .map(|layer| {
+                    layer
+                        .iter()
+                        .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+                        .collect::<Result<Vec<_>>>()
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let ffn_gate_exps = convert_experts(layer_range(&weights.ffn_gate_exps, &layers))?;
+        let ffn_down_exps = convert_experts(layer_range(&weights.ffn_down_exps, &layers))?;
+        let ffn_up_exps = convert_experts(layer_range(&weights.ffn_up_exps, &layers))?;
+        let rms_att_weight = layer_range(&weights.rms_att_weight, &layers)
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let rms_ffn_weight = layer_range(&weights.rms_ffn_weight, &layers)
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
@@ -554,20 +1005,30 @@ impl WgpuLlama2Model {
         let wcls = weights
             .output_weight
             .as_ref()
+            .filter(|_| with_head)
             .map(|output_weight| Self::convert_cpu_tensor(output_weight, device.clone()).unwrap());
         let weights = Llama2Weights {
             token_embed: token_embedding_table,
             wq,
             wk,
             wv,
+            wqkv,
+            bq,
+            bk,
+            bv,
             wo,
             ffn_gate_weight: w1,
             ffn_down_weight: w2,
             ffn_up_weight: w3,
+            ffn_gate_inp,
+            ffn_gate_exps,
+            ffn_down_exps,
+            ffn_up_exps,
             rms_att_weight,
             rms_ffn_weight,
             rms_final_weight,
             output_weight: wcls,
+            sparse_down_rows: SparseDownRows::new(rms_att_weight.len()),
         };
         Ok(weights)
     }
@@ -577,11 +1038,10 @@ impl WgpuLlama2Model {
         let buf = match buf {
             CpuTensorBuf::F32(buf) => buf,
             _ => {
-                return Err(Error {
-                    kind: ErrorKind::TensorError,
-                    message: format!("unsupported tensor type on gpu {:?}", buf),
-                    cause: None,
-                });
+                return Err(Error::new(
+                    ErrorKind::TensorError,
+                    format!("unsupported tensor type on gpu {:?}", buf),
+                ));
             }
         };
 
@@ -590,14 +1050,30 @@ impl WgpuLlama2Model {
     }
 }
 
+// the weights of the layers in the range, the optional per-layer weights are empty
+#[cfg(feature = "wgpu")]
+fn layer_range<'a, E>(weights: &'a [E], layers: &Range<usize>) -> &'a [E] {
+    weights.get(layers.clone()).unwrap_or(&[])
+}
+
+// the tensors of the same type and dimensions with the same data, the data of a tensor info is
+// padded to the alignment, and the padding is zeros
+fn is_same_tensor(a: &GGUFTensorInfo, b: &GGUFTensorInfo) -> bool {
+    let len = a.data().len().min(b.data().len());
+    a.typ() == b.typ() && a.dimensions() == b.dimensions() && a.data()[..len] == b.data()[..len]
+}
+
 #[cfg(test)]
 mod tests {
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf_edit::GGUFEditor;
     use crabml::tensor::Tensor;
 
+    use crate::llama2::Llama2Runner;
     use crate::model::CpuLlama2ModelLoader;
+    use crate::model::ModelArchitecture;
 
     #[test]
     fn test_load_q8_0() -> Result<()> {
@@ -613,4 +1089,256 @@ mod tests {
         assert_eq!(lm.weights.token_embed.dtype(), GGMLType::Q8_0);
         Ok(())
     }
+
+    #[test]
+    fn test_load_unsupported_types() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        // two of the norm weights are stored in I32, which has no kernels
+        let data = (1..=64)
+            .flat_map(|v: i32| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut editor = GGUFEditor::new(&gf);
+        for name in ["blk.0.ffn_norm.weight", "blk.1.ffn_norm.weight"] {
+            editor.rename_tensor(name, &format!("{}.orig", name))?;
+            editor.add_tensor(name, &[64], GGMLType::I32, &data)?;
+        }
+        let path = std::env::temp_dir().join("crabml-test-unsupported-types.gguf");
+        editor.write_to_file(&path)?;
+
+        let gl = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let err = CpuLlama2ModelLoader::new().load(&gf).err().unwrap();
+        assert!(
+            err.message
+                .contains("I32 (blk.0.ffn_norm.weight, blk.1.ffn_norm.weight)"),
+            "{}",
+            err.message
+        );
+
+        let lm = CpuLlama2ModelLoader::new()
+            .with_dequantize_unsupported(true)
+            .load(&gf)?;
+        let mut buf = vec![0.0; 64];
+        lm.weights.rms_ffn_weight[1].export(&mut buf)?;
+        assert_eq!(buf, (1..=64).map(|v| v as f32).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_tied_embeddings() -> Result<()> {
+        // the output weight of the llama2.c models is a copy of the token embedding
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        assert!(lm.weights.tied_embeddings());
+        let mut runner = Llama2Runner::new(&lm, 100, false)?;
+        let output = runner.prefill_and_generate("Lily", 5)?;
+        assert_eq!(output.collect::<Result<Vec<_>>>()?.len(), 5);
+
+        // an output weight which differs from the embedding is loaded
+        let embed = gf.get_tensor_info("token_embd.weight").unwrap();
+        let mut data = embed.data().to_vec();
+        data[0] ^= 1;
+        let mut editor = GGUFEditor::new(&gf);
+        editor.rename_tensor("output.weight", "output.copy")?;
+        editor.add_tensor("output.weight", embed.dimensions(), embed.typ(), &data)?;
+        let path = std::env::temp_dir().join("crabml-test-untied-embeddings.gguf");
+        editor.write_to_file(&path)?;
+
+        let gl_untied = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf_untied = gl_untied.open()?;
+        let lm_untied = CpuLlama2ModelLoader::new().load(&gf_untied)?;
+        assert!(!lm_untied.weights.tied_embeddings());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_progress() -> Result<()> {
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        use crabml::progress::ProgressStage;
+
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reports_clone = reports.clone();
+        CpuLlama2ModelLoader::new()
+            .with_progress_reporter(Arc::new(
+                move |stage: ProgressStage, completed: usize, total: usize| {
+                    assert_eq!(stage, ProgressStage::Load);
+                    assert_eq!(total, 48);
+                    reports_clone.lock().unwrap().push(completed);
+                },
+            ))
+            .load(&gf)?;
+
+        // each loaded tensor is reported once, the copy of the embedding as the output weight
+        // is not loaded but still counted at the end
+        let reports = reports.lock().unwrap().clone();
+        assert_eq!(reports, (1..=48).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[cfg(feature = "gguf-edit")]
+    #[test]
+    fn test_load_qwen2() -> Result<()> {
+        use crate::fixture::write_fixture_model;
+        use crate::fixture::FixtureModelOptions;
+        use crate::fixture::TempDir;
+
+        let dir = TempDir::new("qwen2")?;
+        let path = dir.join("qwen2.gguf");
+        let no_bias_path = dir.join("qwen2-no-bias.gguf");
+        let options = FixtureModelOptions::new().with_architecture(ModelArchitecture::Qwen2);
+        write_fixture_model(&path, &options)?;
+
+        let gl = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        assert_eq!(lm.conf.architecture, ModelArchitecture::Qwen2);
+        assert_eq!(lm.weights.bq.len(), lm.conf.n_layers);
+        assert_eq!(lm.weights.bk[0].shape(), &[16]);
+        // no BOS token is prepended unless the file asks for it
+        assert!(!lm.tokenizer.options().add_bos);
+        let bos_lm = CpuLlama2ModelLoader::new().with_add_bos(true).load(&gf)?;
+        assert!(bos_lm.tokenizer.options().add_bos);
+
+        // the biases are added on both the separate and the fused qkv projections
+        let tokens = [260, 280, 290];
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        let logits = runner.forward(&tokens, 0)?.to_vec();
+        let fused_lm = CpuLlama2ModelLoader::new().with_fused_qkv(true).load(&gf)?;
+        let mut runner = Llama2Runner::new(&fused_lm, 64, false)?;
+        let fused_logits = runner.forward(&tokens, 0)?.to_vec();
+        for (a, b) in logits.iter().zip(fused_logits.iter()) {
+            assert!((a - b).abs() < 1e-4, "{} vs {}", a, b);
+        }
+
+        // and they change the logits
+        let zeros = vec![0_u8; 32 * 4];
+        let mut editor = GGUFEditor::new(&gf);
+        for layer in 0..lm.conf.n_layers {
+            for name in ["attn_q", "attn_k", "attn_v"] {
+                let name = format!("blk.{}.{}.bias", layer, name);
+                let n_bytes = gf.get_tensor_info(&name).unwrap().dimensions()[0] * 4;
+                editor.set_tensor_data(&name, GGMLType::F32, &zeros[..n_bytes])?;
+            }
+        }
+        editor.write_to_file(&no_bias_path)?;
+        let gl = GGUFFileLoader::new(no_bias_path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        assert_ne!(runner.forward(&tokens, 0)?.to_vec(), logits);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "gguf-edit")]
+    fn test_load_gemma() -> Result<()> {
+        use crate::fixture::write_fixture_model;
+        use crate::fixture::FixtureModelOptions;
+        use crate::fixture::TempDir;
+
+        let dir = TempDir::new("gemma")?;
+        let path = dir.join("gemma.gguf");
+        let options = FixtureModelOptions::new().with_architecture(ModelArchitecture::Gemma);
+        write_fixture_model(&path, &options)?;
+
+        // the output head is tied to the token embedding
+        let gl = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        assert_eq!(lm.conf.architecture, ModelArchitecture::Gemma);
+        assert!(lm.weights.output_weight.is_none());
+        let tokens = [260, 280, 290];
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        let logits = runner.forward(&tokens, 0)?.to_vec();
+        assert_eq!(logits.len(), lm.conf.vocab_size);
+        assert!(logits.iter().all(|v| v.is_finite()));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "gguf-edit")]
+    fn test_load_mixtral() -> Result<()> {
+        use crabml::gguf::GGUFMetadataValue;
+
+        use crate::fixture::write_fixture_model;
+        use crate::fixture::FixtureModelOptions;
+        use crate::fixture::TempDir;
+
+        let dir = TempDir::new("mixtral")?;
+        let path = dir.join("mixtral.gguf");
+        let dense_path = dir.join("mixtral-dense.gguf");
+        let split_path = dir.join("mixtral-split.gguf");
+        write_fixture_model(&path, &FixtureModelOptions::new().with_experts(4, 2))?;
+
+        // the stacked experts are sliced into a tensor each
+        let gl = GGUFFileLoader::new(path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        assert_eq!((lm.conf.n_experts, lm.conf.n_experts_used), (4, 2));
+        assert!(lm.weights.is_moe());
+        assert!(lm.weights.ffn_gate_weight.is_empty());
+        assert_eq!(lm.weights.ffn_gate_inp[0].shape(), &[4, 32]);
+        assert_eq!(lm.weights.ffn_up_exps[1].len(), 4);
+        assert_eq!(lm.weights.ffn_down_exps[1][3].shape(), &[32, 64]);
+
+        // the tokens of a batch are routed on their own, like one at a time
+        let tokens = [260, 280, 290];
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        let logits = runner.forward(&tokens, 0)?.to_vec();
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        runner.forward(&tokens[..1], 0)?;
+        runner.forward(&tokens[1..2], 1)?;
+        let sequential_logits = runner.forward(&tokens[2..], 2)?.to_vec();
+        for (a, b) in logits.iter().zip(sequential_logits.iter()) {
+            assert!((a - b).abs() < 1e-4, "{} vs {}", a, b);
+        }
+
+        // the experts which are all the same FFN sum up to the dense FFN, whatever the router
+        // picks. the experts are in a tensor each like the older files of Mixtral.
+        write_fixture_model(&dense_path, &FixtureModelOptions::new())?;
+        let gl = GGUFFileLoader::new(dense_path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        let dense_logits = runner.forward(&tokens, 0)?.to_vec();
+
+        let router = (0..32 * 3)
+            .flat_map(|i| ((i % 7) as f32 - 3.0).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut editor = GGUFEditor::new(&gf);
+        for layer in 0..lm.conf.n_layers {
+            for name in ["ffn_gate", "ffn_up", "ffn_down"] {
+                let dense_name = format!("blk.{}.{}.weight", layer, name);
+                let info = gf.get_tensor_info(&dense_name).unwrap();
+                editor.rename_tensor(&dense_name, &format!("blk.{}.{}.0.weight", layer, name))?;
+                for e in 1..3 {
+                    let name = format!("blk.{}.{}.{}.weight", layer, name, e);
+                    editor.add_tensor(&name, info.dimensions(), GGMLType::F32, info.data())?;
+                }
+            }
+            let name = format!("blk.{}.ffn_gate_inp.weight", layer);
+            editor.add_tensor(&name, &[32, 3], GGMLType::F32, &router)?;
+        }
+        editor.set("llama.expert_count", GGUFMetadataValue::U32(3))?;
+        editor.set("llama.expert_used_count", GGUFMetadataValue::U32(2))?;
+        editor.write_to_file(&split_path)?;
+
+        let gl = GGUFFileLoader::new(split_path.to_str().unwrap(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new().load(&gf)?;
+        assert!(lm.weights.is_moe());
+        let mut runner = Llama2Runner::new(&lm, 64, false)?;
+        let moe_logits = runner.forward(&tokens, 0)?.to_vec();
+        for (a, b) in dense_logits.iter().zip(moe_logits.iter()) {
+            assert!((a - b).abs() < 1e-4, "{} vs {}", a, b);
+        }
+        Ok(())
+    }
 }
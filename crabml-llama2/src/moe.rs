@@ -0,0 +1,99 @@
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+
+/// the tokens routed to each expert of a mixture-of-experts layer with their gate weights,
+/// indexed by the expert. each token goes to the n_used experts of the largest router logits,
+/// and their gate weights are the softmax over these logits, like Mixtral. a layer without
+/// experts or routing to none of them is an error, the tokens would get no FFN at all.
+pub(crate) fn route_tokens(
+    logits: &[f32],
+    n_experts: usize,
+    n_used: usize,
+) -> Result<Vec<Vec<(usize, f32)>>> {
+    if n_used == 0 || n_used > n_experts {
+        return Err(Error::new(
+            ErrorKind::ModelError,
+            format!(
+                "can not route the tokens to {} of {} experts",
+                n_used, n_experts
+            ),
+        ));
+    }
+    let mut routes = vec![vec![]; n_experts];
+    for (row, row_logits) in logits.chunks(n_experts).enumerate() {
+        let mut experts = (0..n_experts).collect::<Vec<_>>();
+        experts.sort_by(|a, b| row_logits[*b].total_cmp(&row_logits[*a]));
+        experts.truncate(n_used);
+
+        let max = row_logits[experts[0]];
+        let exps = experts
+            .iter()
+            .map(|e| (row_logits[*e] - max).exp())
+            .collect::<Vec<_>>();
+        let sum = exps.iter().sum::<f32>();
+        for (e, v) in experts.iter().zip(exps) {
+            routes[*e].push((row, v / sum));
+        }
+    }
+    Ok(routes)
+}
+
+/// the experts which the next token is likely routed to besides the current ones, the ones
+/// ranked right after the used experts on the router logits of the last token. the consecutive
+/// tokens tend to be routed to the similar experts, so their weights are prefetched while the
+/// current experts run.
+pub(crate) fn predict_next_experts(
+    logits: &[f32],
+    n_experts: usize,
+    n_used: usize,
+    n_predict: usize,
+) -> Vec<usize> {
+    let Some(last) = logits.chunks(n_experts).last() else {
+        return vec![];
+    };
+    let mut experts = (0..n_experts).collect::<Vec<_>>();
+    experts.sort_by(|a, b| last[*b].total_cmp(&last[*a]));
+    experts.into_iter().skip(n_used).take(n_predict).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_route_tokens() {
+        let logits = [
+            0.1, 2.0, 1.0, -1.0, // token 0 goes to the experts 1 and 2
+            3.0, 0.0, 0.0, 3.0, // token 1 goes to the experts 0 and 3 equally
+        ];
+        let routes = route_tokens(&logits, 4, 2).unwrap();
+        assert_eq!(routes.len(), 4);
+        assert_eq!(routes[0], vec![(1, 0.5)]);
+        assert_eq!(routes[3], vec![(1, 0.5)]);
+        assert_eq!(routes[1].len(), 1);
+        assert_relative_eq!(routes[1][0].1, 1.0 / (1.0 + (-1.0f32).exp()));
+        assert_relative_eq!(routes[1][0].1 + routes[2][0].1, 1.0);
+
+        // a single expert takes the whole weight
+        let routes = route_tokens(&logits, 4, 1).unwrap();
+        assert_eq!(routes[1], vec![(0, 1.0)]);
+        assert_eq!(routes[0], vec![(1, 1.0)]);
+
+        assert!(route_tokens(&logits, 4, 0).is_err());
+        assert!(route_tokens(&[], 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_predict_next_experts() {
+        let logits = [
+            0.1, 2.0, 1.0, -1.0, // the first token is not the last one
+            3.0, 0.5, 1.0, 2.0, // the last token takes the experts 0 and 3, then 2 and 1
+        ];
+        assert_eq!(predict_next_experts(&logits, 4, 2, 1), vec![2]);
+        assert_eq!(predict_next_experts(&logits, 4, 2, 4), vec![2, 1]);
+        assert!(predict_next_experts(&[], 4, 2, 2).is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+
+use crabml::error::Result;
+use crabml::tokenizer::Tokenizer;
+
+use crate::event::GenerationEvent;
+use crate::event::GenerationEventSender;
+use crate::event::TokenEvent;
+use crate::stopping::StopStrings;
+use crate::stopping::StoppingContext;
+use crate::stopping::StoppingCriteria;
+
+/// the decode loop runs ahead of the detokenizer by at most these tokens, it blocks once the
+/// detokenizer falls behind by more.
+pub const PIPELINE_DEPTH: usize = 16;
+
+/// a token handed from the decode loop to the detokenizer thread. the TokenGenerated event is
+/// emitted only with the logprob, which is computed when there's a subscriber.
+pub(crate) struct SampledToken {
+    pub id: usize,
+    pub logprob: Option<f32>,
+    pub t_ms: f64,
+}
+
+/// the result of `Llama2Runner::generate_pipelined`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PipelinedOutput {
+    /// the generated text, ending with the token which completed a stop string if any.
+    pub text: String,
+
+    /// the tokens of the text, the ones the decode loop sampled after a stop string are not
+    /// included.
+    pub tokens: Vec<usize>,
+
+    /// whether the generation stopped on a stop string.
+    pub stop_string_hit: bool,
+}
+
+// decodes the tokens into the text, matches the stop strings and emits the events on a
+// thread of its own, so the decode loop never waits on the text
+pub(crate) struct Detokenizer<F> {
+    tokenizer: Tokenizer,
+    stop_strings: StopStrings,
+    event_sender: Option<GenerationEventSender>,
+    on_token: F,
+    // the text of the healed prompt token, trimmed from the first piece
+    healed_text: String,
+}
+
+impl<F: FnMut(&TokenEvent) -> Result<()>> Detokenizer<F> {
+    pub fn new(
+        tokenizer: Tokenizer,
+        stop_strings: &[String],
+        event_sender: Option<GenerationEventSender>,
+        on_token: F,
+    ) -> Self {
+        Self {
+            tokenizer,
+            stop_strings: StopStrings(stop_strings.to_vec()),
+            event_sender,
+            on_token,
+            healed_text: String::new(),
+        }
+    }
+
+    pub fn with_healed_text(mut self, healed_text: String) -> Self {
+        self.healed_text = healed_text;
+        self
+    }
+
+    // consume the tokens until the decode loop hangs up, a stop string is hit or on_token
+    // fails. `stop_hit` is raised on the stop string and the failure, and the receiver is
+    // dropped on returning, so the decode loop stops on the next step either way. the output
+    // is returned on the failure too, ending with the token it failed on.
+    pub fn run(
+        mut self,
+        tokens: Receiver<SampledToken>,
+        stop_hit: &AtomicBool,
+    ) -> (PipelinedOutput, Result<()>) {
+        let mut output = PipelinedOutput::default();
+        let result = self.run_tokens(tokens, stop_hit, &mut output);
+        if result.is_err() {
+            stop_hit.store(true, Ordering::Release);
+        }
+        (output, result)
+    }
+
+    fn run_tokens(
+        &mut self,
+        tokens: Receiver<SampledToken>,
+        stop_hit: &AtomicBool,
+        output: &mut PipelinedOutput,
+    ) -> Result<()> {
+        for sampled in tokens {
+            let piece_start = output.text.len();
+            output.tokens.push(sampled.id);
+            self.tokenizer.decode_append(sampled.id, &mut output.text)?;
+            if output.tokens.len() == 1 && output.text.starts_with(&self.healed_text) {
+                output.text.drain(..self.healed_text.len());
+            }
+            let piece = &output.text[piece_start..];
+
+            if let (Some(sender), Some(logprob)) = (&self.event_sender, sampled.logprob) {
+                // the receiver may have hung up, it's not a reason to stop the generation
+                let _ = sender.send(GenerationEvent::TokenGenerated {
+                    id: sampled.id,
+                    text: piece.to_string(),
+                    logprob,
+                    t_ms: sampled.t_ms,
+                });
+            }
+            (self.on_token)(&TokenEvent {
+                id: sampled.id,
+                piece,
+                text: &output.text,
+            })?;
+
+            let ctx = StoppingContext {
+                token: sampled.id,
+                piece,
+                text: &output.text,
+                n_generated: output.tokens.len(),
+                logits: &[],
+            };
+            if self.stop_strings.should_stop(&ctx).is_some() {
+                stop_hit.store(true, Ordering::Release);
+                output.stop_string_hit = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crabml::error::Error;
+    use crabml::error::ErrorKind;
+
+    use super::*;
+
+    #[test]
+    fn test_detokenizer_stop_string() -> Result<()> {
+        let tokens = ["<unk>", "<s>", "</s>", "hello", "▁wor", "ld", "!"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>();
+        let scores = vec![0.0; tokens.len()];
+        let tokenizer = Tokenizer::new_llama(tokens, scores, 1, 2);
+
+        let (tx, rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+        for id in [3, 4, 5, 6] {
+            tx.send(SampledToken {
+                id,
+                logprob: None,
+                t_ms: 0.0,
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        let mut pieces = vec![];
+        let detokenizer = Detokenizer::new(tokenizer, &["world".to_string()], None, |event| {
+            pieces.push(event.piece.to_string());
+            Ok(())
+        });
+        let stop_hit = AtomicBool::new(false);
+        let (output, result) = detokenizer.run(rx, &stop_hit);
+        result?;
+        // the token completing the string ends the text, the ones after it are dropped
+        assert_eq!(output.text, "hello world");
+        assert_eq!(output.tokens, vec![3, 4, 5]);
+        assert!(output.stop_string_hit);
+        assert!(stop_hit.load(Ordering::Acquire));
+        assert_eq!(pieces, vec!["hello", " wor", "ld"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detokenizer_on_token_error() {
+        let tokens = ["<unk>", "<s>", "</s>", "hello", "▁wor", "ld", "!"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>();
+        let scores = vec![0.0; tokens.len()];
+        let tokenizer = Tokenizer::new_llama(tokens, scores, 1, 2);
+
+        let (tx, rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+        for id in [3, 4, 5, 6] {
+            tx.send(SampledToken {
+                id,
+                logprob: None,
+                t_ms: 0.0,
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        // the output ends with the token which on_token failed on
+        let detokenizer = Detokenizer::new(tokenizer, &[], None, |event| match event.id {
+            5 => Err(Error::new(ErrorKind::IOError, "the client is gone")),
+            _ => Ok(()),
+        });
+        let stop_hit = AtomicBool::new(false);
+        let (output, result) = detokenizer.run(rx, &stop_hit);
+        assert!(result.is_err());
+        assert_eq!(output.tokens, vec![3, 4, 5]);
+        assert!(!output.stop_string_hit);
+        assert!(stop_hit.load(Ordering::Acquire));
+    }
+}
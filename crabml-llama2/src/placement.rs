@@ -0,0 +1,439 @@
+use std::fmt::Display;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFile;
+
+use crate::model::Llama2Config;
+
+/// the devices which the parts of a model can be placed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlacementDevice {
+    Cpu,
+    Wgpu,
+}
+
+impl Display for PlacementDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlacementDevice::Cpu => write!(f, "cpu"),
+            PlacementDevice::Wgpu => write!(f, "wgpu"),
+        }
+    }
+}
+
+impl FromStr for PlacementDevice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cpu" => Ok(PlacementDevice::Cpu),
+            "wgpu" => Ok(PlacementDevice::Wgpu),
+            _ => Err(Error::new(
+                ErrorKind::BadInput,
+                format!("unknown device {} in the placement", s),
+            )),
+        }
+    }
+}
+
+/// the bytes of the weights of each part of a model, and of its KV cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryEstimate {
+    /// the weights of each layer
+    pub layer_bytes: Vec<usize>,
+    /// the KV cache of a layer over the whole context
+    pub kv_cache_bytes_per_layer: usize,
+    /// the output head with the final norm, it's the token embedding if they are tied
+    pub output_bytes: usize,
+    pub embed_bytes: usize,
+}
+
+impl MemoryEstimate {
+    /// estimate the memory of a model in the GGUF file on the context of seq_len tokens.
+    pub fn from_gguf(
+        gf: &GGUFFile,
+        conf: &Llama2Config,
+        seq_len: usize,
+        use_f16_kv_cache: bool,
+    ) -> Self {
+        let tensor_bytes = |name: &str| gf.get_tensor_info(name).map(|t| t.data().len());
+        let mut layer_bytes = vec![0; conf.n_layers];
+        for info in gf.tensor_infos() {
+            let layer = info
+                .name()
+                .strip_prefix("blk.")
+                .and_then(|s| s.split('.').next())
+                .and_then(|s| s.parse::<usize>().ok());
+            if let Some(bytes) = layer.and_then(|l| layer_bytes.get_mut(l)) {
+                *bytes += info.data().len();
+            }
+        }
+        let embed_bytes = tensor_bytes("token_embd.weight").unwrap_or(0);
+        let output_bytes = tensor_bytes("output.weight").unwrap_or(embed_bytes)
+            + tensor_bytes("output_norm.weight").unwrap_or(0);
+        let dtype_bytes = if use_f16_kv_cache { 2 } else { 4 };
+        let kv_cache_bytes_per_layer =
+            2 * conf.n_kv_heads * seq_len * conf.head_size() * dtype_bytes;
+        Self {
+            layer_bytes,
+            kv_cache_bytes_per_layer,
+            output_bytes,
+            embed_bytes,
+        }
+    }
+
+    /// the bytes of the layer with its KV cache.
+    pub fn layer_total_bytes(&self, layer: usize) -> usize {
+        self.layer_bytes[layer] + self.kv_cache_bytes_per_layer
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        (0..self.layer_bytes.len())
+            .map(|l| self.layer_total_bytes(l))
+            .sum::<usize>()
+            + self.output_bytes
+            + self.embed_bytes
+    }
+}
+
+/// places each layer, the output head and the token embedding of a model on a device, with the
+/// memory budgets of the devices. it's loaded from a config file like:
+///
+/// ```text
+/// # layers 0-15 on the gpu, the rest on the cpu
+/// layers 0-15 wgpu
+/// layers 16-31 cpu
+/// output wgpu
+/// embed cpu
+/// budget wgpu 6G
+/// ```
+///
+/// the parts which are not mentioned stay on the cpu.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerPlacement {
+    layers: Vec<PlacementDevice>,
+    output: PlacementDevice,
+    embed: PlacementDevice,
+    budgets: Vec<(PlacementDevice, usize)>,
+}
+
+impl LayerPlacement {
+    /// all the parts on a single device.
+    pub fn new(n_layers: usize, device: PlacementDevice) -> Self {
+        Self {
+            layers: vec![device; n_layers],
+            output: device,
+            embed: device,
+            budgets: vec![],
+        }
+    }
+
+    pub fn load(path: &str, n_layers: usize) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("failed to read the placement {}", path),
+            )
+            .with_cause(err)
+        })?;
+        Self::parse(&text, n_layers)
+    }
+
+    pub fn parse(text: &str, n_layers: usize) -> Result<Self> {
+        let mut placement = Self::new(n_layers, PlacementDevice::Cpu);
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bad_line = |message: String| {
+                Error::new(
+                    ErrorKind::BadInput,
+                    format!("line {} of the placement: {}", i + 1, message),
+                )
+            };
+            let parse_device = |s: &str| {
+                s.parse::<PlacementDevice>()
+                    .map_err(|err| bad_line(err.message))
+            };
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words[..] {
+                ["layers", range, device] => {
+                    let (start, end) = parse_layer_range(range)
+                        .ok_or_else(|| bad_line(format!("invalid layer range {}", range)))?;
+                    if end >= n_layers {
+                        return Err(bad_line(format!(
+                            "layer {} is out of the {} layers of the model",
+                            end, n_layers
+                        )));
+                    }
+                    placement.layers[start..=end].fill(parse_device(device)?);
+                }
+                ["output", device] => placement.output = parse_device(device)?,
+                ["embed", device] => placement.embed = parse_device(device)?,
+                ["budget", device, size] => {
+                    let size = parse_size(size)
+                        .ok_or_else(|| bad_line(format!("invalid size {}", size)))?;
+                    placement = placement.with_budget(parse_device(device)?, size);
+                }
+                _ => return Err(bad_line(format!("unknown directive: {}", line))),
+            }
+        }
+        Ok(placement)
+    }
+
+    /// place as many layers as possible on the device within the budget from the first layer,
+    /// the output head and the token embedding follow once all the layers fit. the rest stays
+    /// on the cpu.
+    pub fn plan(estimate: &MemoryEstimate, device: PlacementDevice, budget: usize) -> Self {
+        let n_layers = estimate.layer_bytes.len();
+        let mut placement = Self::new(n_layers, PlacementDevice::Cpu).with_budget(device, budget);
+        let mut used = 0;
+        for layer in 0..n_layers {
+            used += estimate.layer_total_bytes(layer);
+            if used > budget {
+                return placement;
+            }
+            placement.layers[layer] = device;
+        }
+        used += estimate.output_bytes;
+        if used <= budget {
+            placement.output = device;
+        }
+        used += estimate.embed_bytes;
+        if used <= budget {
+            placement.embed = device;
+        }
+        placement
+    }
+
+    pub fn with_budget(mut self, device: PlacementDevice, bytes: usize) -> Self {
+        self.budgets.retain(|(d, _)| *d != device);
+        self.budgets.push((device, bytes));
+        self
+    }
+
+    pub fn layer_device(&self, layer: usize) -> PlacementDevice {
+        self.layers[layer]
+    }
+
+    pub fn n_layers_on(&self, device: PlacementDevice) -> usize {
+        self.layers.iter().filter(|d| **d == device).count()
+    }
+
+    /// the ranges of the consecutive layers placed on the device.
+    pub fn layer_ranges_on(&self, device: PlacementDevice) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for (l, _) in self
+            .layers
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| **d == device)
+        {
+            match ranges.last_mut() {
+                Some(range) if range.end == l => range.end = l + 1,
+                _ => ranges.push(l..l + 1),
+            }
+        }
+        ranges
+    }
+
+    pub fn output_device(&self) -> PlacementDevice {
+        self.output
+    }
+
+    pub fn embed_device(&self) -> PlacementDevice {
+        self.embed
+    }
+
+    /// the device of all the parts, None if the model is split over the devices.
+    pub fn single_device(&self) -> Option<PlacementDevice> {
+        let device = self.embed;
+        let single = self.output == device && self.layers.iter().all(|d| *d == device);
+        single.then_some(device)
+    }
+
+    /// the bytes taken on each device.
+    pub fn usage(&self, estimate: &MemoryEstimate) -> Vec<(PlacementDevice, usize)> {
+        let mut usage: Vec<(PlacementDevice, usize)> = vec![];
+        let parts = self
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(l, d)| (*d, estimate.layer_total_bytes(l)))
+            .chain([
+                (self.output, estimate.output_bytes),
+                (self.embed, estimate.embed_bytes),
+            ]);
+        for (device, bytes) in parts {
+            match usage.iter_mut().find(|(d, _)| *d == device) {
+                Some((_, total)) => *total += bytes,
+                None => usage.push((device, bytes)),
+            }
+        }
+        usage
+    }
+
+    /// check the parts placed on each device fit into its budget, returns the usage.
+    pub fn validate(&self, estimate: &MemoryEstimate) -> Result<Vec<(PlacementDevice, usize)>> {
+        if estimate.layer_bytes.len() != self.layers.len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "the placement has {} layers, but the model has {}",
+                    self.layers.len(),
+                    estimate.layer_bytes.len()
+                ),
+            ));
+        }
+        let usage = self.usage(estimate);
+        for (device, budget) in self.budgets.iter() {
+            let used = usage
+                .iter()
+                .find(|(d, _)| d == device)
+                .map(|(_, bytes)| *bytes)
+                .unwrap_or(0);
+            if used > *budget {
+                return Err(Error::new(
+                    ErrorKind::BadInput,
+                    format!(
+                        "the parts placed on {} take {} bytes, exceeds its budget of {} bytes",
+                        device, used, budget
+                    ),
+                ));
+            }
+        }
+        Ok(usage)
+    }
+}
+
+/// writes the placement in the format of the config file, so a printed plan can be saved and
+/// tuned by hand.
+impl Display for LayerPlacement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut start = 0;
+        while start < self.layers.len() {
+            let device = self.layers[start];
+            let len = self.layers[start..]
+                .iter()
+                .take_while(|d| **d == device)
+                .count();
+            writeln!(f, "layers {}-{} {}", start, start + len - 1, device)?;
+            start += len;
+        }
+        writeln!(f, "output {}", self.output)?;
+        write!(f, "embed {}", self.embed)?;
+        for (device, bytes) in self.budgets.iter() {
+            write!(f, "\nbudget {} {}", device, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// a layer range like "0-15", or a single layer like "3"
+fn parse_layer_range(s: &str) -> Option<(usize, usize)> {
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let layer = s.parse().ok()?;
+            (layer, layer)
+        }
+    };
+    (start <= end).then_some((start, end))
+}
+
+/// a size in bytes with an optional K/M/G suffix in 1024, like "6G" or "512MiB".
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_placement() -> Result<()> {
+        let text = "
+            # the first half on the gpu
+            layers 0-1 wgpu
+            layers 3 wgpu
+            output wgpu
+            budget wgpu 1K
+        ";
+        let placement = LayerPlacement::parse(text, 4)?;
+        assert_eq!(placement.layer_device(1), PlacementDevice::Wgpu);
+        assert_eq!(placement.layer_device(2), PlacementDevice::Cpu);
+        assert_eq!(placement.embed_device(), PlacementDevice::Cpu);
+        assert_eq!(placement.single_device(), None);
+        assert_eq!(placement.layer_ranges_on(PlacementDevice::Wgpu), vec![
+            0..2,
+            3..4
+        ]);
+        assert_eq!(placement.layer_ranges_on(PlacementDevice::Cpu), vec![2..3]);
+
+        let estimate = MemoryEstimate {
+            layer_bytes: vec![100; 4],
+            kv_cache_bytes_per_layer: 50,
+            output_bytes: 300,
+            embed_bytes: 300,
+        };
+        assert_eq!(estimate.total_bytes(), 1200);
+        let usage = placement.validate(&estimate)?;
+        assert_eq!(usage, vec![
+            (PlacementDevice::Wgpu, 750),
+            (PlacementDevice::Cpu, 450)
+        ]);
+        let placement = placement.with_budget(PlacementDevice::Wgpu, 700);
+        assert!(placement.validate(&estimate).is_err());
+
+        assert!(LayerPlacement::parse("layers 2-5 wgpu", 4).is_err());
+        assert!(LayerPlacement::parse("layers 0-1 tpu", 4).is_err());
+        assert!(LayerPlacement::parse("budget wgpu 1T", 4).is_err());
+        assert_eq!(parse_size("6GiB"), Some(6 << 30));
+        assert_eq!(
+            LayerPlacement::parse("layers 0-3 wgpu\noutput wgpu\nembed wgpu", 4)?.single_device(),
+            Some(PlacementDevice::Wgpu)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_placement() -> Result<()> {
+        let estimate = MemoryEstimate {
+            layer_bytes: vec![100; 4],
+            kv_cache_bytes_per_layer: 50,
+            output_bytes: 300,
+            embed_bytes: 300,
+        };
+        let plan = LayerPlacement::plan(&estimate, PlacementDevice::Wgpu, 500);
+        assert_eq!(plan.n_layers_on(PlacementDevice::Wgpu), 3);
+        assert_eq!(plan.output_device(), PlacementDevice::Cpu);
+        assert!(plan.validate(&estimate).is_ok());
+
+        // the printed plan can be loaded back
+        assert_eq!(
+            plan.to_string(),
+            "layers 0-2 wgpu\nlayers 3-3 cpu\noutput cpu\nembed cpu\nbudget wgpu 500"
+        );
+        assert_eq!(LayerPlacement::parse(&plan.to_string(), 4)?, plan);
+
+        let plan = LayerPlacement::plan(&estimate, PlacementDevice::Wgpu, 1200);
+        assert_eq!(plan.single_device(), Some(PlacementDevice::Wgpu));
+        Ok(())
+    }
+}
@@ -1,37 +1,149 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
 
-use crabml::backends::cpu::buf::buf_f32::exp_f32_cached;
+use crabml::backends::cpu::argmax_row;
+use crabml::backends::cpu::softmax_row;
+use crabml::backends::cpu::topk_row;
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
-use half::f16;
 use rand::Rng;
 
 pub struct Llama2Sampler {
-    prob_index: RefCell<Vec<(f32, usize)>>,
+    prob_index: Mutex<Vec<(f32, usize)>>,
     temperature: f32,
+    topk: usize,
     topp: f32,
-    exp_cache: Rc<Vec<f16>>,
+    seed: Option<u64>,
+    rng: Mutex<Option<SplitMix64>>,
+    n_coins: AtomicU64,
 }
 
-pub type Llama2SamplerRef = Rc<Llama2Sampler>;
+/// the state of a sampler, to save it along with a session and continue the session with the
+/// same sampling after it's restored. the frontends serialize it, like into the transcripts of
+/// the cli.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerState {
+    pub temperature: f32,
+    pub topk: usize,
+    pub topp: f32,
+
+    /// the seed of the RNG, None if the coins are flipped from the thread RNG, which can not be
+    /// restored.
+    pub seed: Option<u64>,
+
+    /// the coins flipped from the seeded RNG so far, the RNG is put at the same point on
+    /// restoring by flipping them again.
+    pub n_coins: u64,
+}
+
+/// a tiny RNG which never changes its sequence across the versions and the platforms, unlike
+/// the ones of rand, so the seeded sessions sample the same tokens after an upgrade.
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// uniform in [0, 1).
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// the sampler holds the mutable states of a session. it's Send + Sync, so the runner holding
+/// it can be moved to another thread, like a replica of the server.
+pub type Llama2SamplerRef = Arc<Llama2Sampler>;
 
 impl Llama2Sampler {
-    pub fn new(
+    pub fn new(vocab_size: usize, temperature: f32, topp: f32) -> Llama2SamplerRef {
+        Self::new_with_topk(vocab_size, temperature, 0, topp, None)
+    }
+
+    /// a sampler which flips the coins from a seeded RNG, so a session samples the same tokens
+    /// on the same inputs.
+    pub fn new_seeded(
+        vocab_size: usize,
+        temperature: f32,
+        topp: f32,
+        seed: u64,
+    ) -> Llama2SamplerRef {
+        Self::new_with_topk(vocab_size, temperature, 0, topp, Some(seed))
+    }
+
+    /// a sampler which only samples from the topk most likely tokens, then from the smallest
+    /// set of them which exceeds topp. a topk of 0 keeps all the tokens, a topp of 0 or 1
+    /// disables the nucleus sampling. the coins are flipped from a RNG seeded with the seed if
+    /// given.
+    pub fn new_with_topk(
         vocab_size: usize,
         temperature: f32,
+        topk: usize,
         topp: f32,
-        exp_cache: Rc<Vec<f16>>,
+        seed: Option<u64>,
     ) -> Llama2SamplerRef {
-        Rc::new(Self {
-            prob_index: RefCell::new(vec![(0.0, 0); vocab_size]),
+        Arc::new(Self {
+            prob_index: Mutex::new(vec![(0.0, 0); vocab_size]),
             temperature,
+            topk,
             topp,
-            exp_cache,
+            seed,
+            rng: Mutex::new(seed.map(SplitMix64)),
+            n_coins: AtomicU64::new(0),
         })
     }
 
+    /// a sampler which continues from the state taken by `state`, it samples the same tokens
+    /// as the sampler it's taken from on the same inputs.
+    pub fn from_state(vocab_size: usize, state: &SamplerState) -> Llama2SamplerRef {
+        let sampler = Self::new_with_topk(
+            vocab_size,
+            state.temperature,
+            state.topk,
+            state.topp,
+            state.seed,
+        );
+        if state.seed.is_some() {
+            for _ in 0..state.n_coins {
+                sampler.flip_coin();
+            }
+        }
+        sampler
+    }
+
+    pub fn state(&self) -> SamplerState {
+        SamplerState {
+            temperature: self.temperature,
+            topk: self.topk,
+            topp: self.topp,
+            seed: self.seed,
+            n_coins: self.n_coins.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn topk(&self) -> usize {
+        self.topk
+    }
+
+    pub fn topp(&self) -> f32 {
+        self.topp
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
     pub fn sample(&self, logits: &mut [f32]) -> Result<usize> {
         if self.temperature == 0.0 {
             return Self::sample_argmax(logits);
@@ -43,21 +155,35 @@ impl Llama2Sampler {
             *logit /= self.temperature;
         }
         // apply softmax to the logits to get the probabilities for next token
-        softmax(logits, self.exp_cache.as_ref());
+        softmax_row(logits);
 
         // flip a (float) coin (this is our source of entropy for sampling)
-        let mut rng = rand::thread_rng();
-        let coin: f32 = rng.gen_range(0.0..1.0);
+        let coin = self.flip_coin();
 
         // we sample from this distribution to get the next token
+        let topk_enabled = self.topk > 0 && self.topk < logits.len();
+        if topk_enabled {
+            return Self::sample_topk(logits, self.topk, self.topp, coin);
+        }
         if self.topp <= 0_f32 || self.topp >= 1.0_f32 {
             // simply sample from the predicted probability distribution
-            Self::sample_multi(logits, coin);
+            return Ok(Self::sample_multi(logits, coin));
         }
 
         Self::sample_topp(logits, self.topp, &self.prob_index, coin)
     }
 
+    // a random number in [0, 1), the coins of the seeded RNG are counted to restore it
+    fn flip_coin(&self) -> f32 {
+        match self.rng.lock().unwrap().as_mut() {
+            Some(rng) => {
+                self.n_coins.fetch_add(1, Ordering::Relaxed);
+                rng.next_f32()
+            }
+            None => rand::thread_rng().gen_range(0.0..1.0),
+        }
+    }
+
     pub fn sample_multi(probs: &[f32], coin: f32) -> usize {
         // sample index from probabilities (they must sum to 1!)
         // coin is a random number in [0, 1), usually from random_f32()
@@ -74,14 +200,14 @@ impl Llama2Sampler {
     pub fn sample_topp(
         probs: &[f32],
         topp: f32,
-        prob_index: &RefCell<Vec<(f32, usize)>>,
+        prob_index: &Mutex<Vec<(f32, usize)>>,
         coin: f32,
     ) -> Result<usize> {
         // top-p sampling (or "nucleus sampling") samples from the smallest set of
         // tokens that exceed probability topp. This way we never sample tokens that
         // have very low probabilities and are less likely to go "off the rails".
         // coin is a random number in [0, 1), usually from random_f32()
-        let mut prob_index = prob_index.borrow_mut();
+        let mut prob_index = prob_index.lock().unwrap();
 
         let cutoff = (1.0_f32 - topp) / (probs.len() - 1) as f32;
         let mut n0 = 0;
@@ -91,7 +217,8 @@ impl Llama2Sampler {
                 n0 += 1;
             }
         }
-        prob_index[..n0].sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        // the most likely tokens first
+        prob_index[..n0].sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
 
         // truncate the list where cumulative probability exceeds topp
         let mut cumulative_prob = 0_f32;
@@ -116,28 +243,133 @@ impl Llama2Sampler {
         Ok(prob_index[last_idx].1) // in case of rounding errors
     }
 
+    /// top-k sampling keeps the topk most likely tokens, then the nucleus of them which exceeds
+    /// topp if it's set. the kept probabilities are renormalized before sampling. the NaNs are
+    /// never kept.
+    pub fn sample_topk(probs: &[f32], topk: usize, topp: f32, coin: f32) -> Result<usize> {
+        let prob_index = topk_row(probs, topk.max(1))
+            .into_iter()
+            .map(|i| (probs[i], i))
+            .filter(|(prob, _)| !prob.is_nan())
+            .collect::<Vec<_>>();
+        if prob_index.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Unexpected,
+                "failed to sample from logits",
+            ));
+        }
+        let topk = prob_index.len();
+
+        // truncate the top k where the renormalized cumulative probability exceeds topp
+        let total = prob_index[..topk].iter().map(|p| p.0).sum::<f32>();
+        let mut last_idx = topk - 1;
+        if topp > 0_f32 && topp < 1.0_f32 {
+            let mut cumulative_prob = 0_f32;
+            for (i, prob) in prob_index[..topk].iter().enumerate() {
+                cumulative_prob += prob.0 / total;
+                if cumulative_prob > topp {
+                    last_idx = i;
+                    break;
+                }
+            }
+        }
+
+        let r = coin * prob_index[..=last_idx].iter().map(|p| p.0).sum::<f32>();
+        let mut cdf = 0_f32;
+        for prob in prob_index[..=last_idx].iter() {
+            cdf += prob.0;
+            if cdf > r {
+                return Ok(prob.1);
+            }
+        }
+        Ok(prob_index[last_idx].1) // in case of rounding errors
+    }
+
+    /// the most likely token, the first one is taken on ties. the NaNs are skipped, it fails if
+    /// all the logits are NaN.
     pub fn sample_argmax(probs: &[f32]) -> Result<usize> {
-        probs
-            .iter()
-            .enumerate()
-            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
-            .map(|(i, _)| i)
-            .ok_or_else(|| Error {
-                kind: ErrorKind::Unexpected,
-                message: "failed to sample from logits".to_string(),
-                cause: None,
-            })
+        match argmax_row(probs) {
+            Some(i) if !probs[i].is_nan() => Ok(i),
+            _ => Err(Error::new(
+                ErrorKind::Unexpected,
+                "failed to sample from logits",
+            )),
+        }
     }
 }
 
-pub fn softmax(a: &mut [f32], exp_cache: &[f16]) {
-    let max = a.iter().fold(f32::NAN, |a, b| a.max(*b));
-    let mut sum = 0.0;
-    for a in a.iter_mut() {
-        *a = exp_f32_cached(*a - max, exp_cache);
-        sum += *a;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_topk_topp() -> Result<()> {
+        let logits = [0.5_f32, 0.3, 0.15, 0.05].map(f32::ln);
+
+        // only the 2 most likely tokens are sampled
+        let sampler = Llama2Sampler::new_with_topk(4, 1.0, 2, 0.0, Some(42));
+        for _ in 0..200 {
+            assert!(sampler.sample(&mut logits.clone())? < 2);
+        }
+        let sampler = Llama2Sampler::new_with_topk(4, 1.0, 1, 0.0, Some(42));
+        assert_eq!(sampler.sample(&mut logits.clone())?, 0);
+
+        // the NaNs are never sampled, the first token is taken on ties
+        let nan_logits = [f32::NAN, 0.5, 0.5, f32::NAN];
+        assert_eq!(Llama2Sampler::sample_argmax(&nan_logits)?, 1);
+        assert_eq!(Llama2Sampler::sample_topk(&nan_logits, 4, 0.0, 0.9)?, 2);
+        assert!(Llama2Sampler::sample_argmax(&[f32::NAN; 4]).is_err());
+        assert!(Llama2Sampler::sample_topk(&[f32::NAN; 4], 2, 0.0, 0.5).is_err());
+
+        // the nucleus of 0.6 is the 2 most likely tokens
+        let sampler = Llama2Sampler::new_seeded(4, 1.0, 0.6, 42);
+        let mut sampled = [0; 4];
+        for _ in 0..200 {
+            sampled[sampler.sample(&mut logits.clone())?] += 1;
+        }
+        assert_eq!(sampled[2] + sampled[3], 0);
+        assert!(sampled[0] > sampled[1] && sampled[1] > 0);
+
+        // the same seed samples the same tokens
+        let a = Llama2Sampler::new_with_topk(4, 1.0, 3, 0.9, Some(7));
+        let b = Llama2Sampler::new_with_topk(4, 1.0, 3, 0.9, Some(7));
+        for _ in 0..20 {
+            assert_eq!(
+                a.sample(&mut logits.clone())?,
+                b.sample(&mut logits.clone())?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_splitmix64() {
+        // the sequence of the reference implementation, the seeded sessions depend on it
+        let mut rng = SplitMix64(0);
+        assert_eq!(rng.next_u64(), 0xe220a8397b1dcdaf);
+        assert_eq!(rng.next_u64(), 0x6e789e6aa1b965f4);
+        let mut rng = SplitMix64(42);
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&rng.next_f32())));
     }
-    for a in a.iter_mut() {
-        *a /= sum;
+
+    #[test]
+    fn test_sampler_state() -> Result<()> {
+        let logits = [0.4_f32, 0.3, 0.2, 0.1].map(f32::ln);
+        let sampler = Llama2Sampler::new_with_topk(4, 0.8, 3, 0.95, Some(11));
+        for _ in 0..5 {
+            sampler.sample(&mut logits.clone())?;
+        }
+        let state = sampler.state();
+        assert_eq!(state.n_coins, 5);
+        assert_eq!((state.topk, state.seed), (3, Some(11)));
+
+        // the restored sampler goes on with the same coins
+        let restored = Llama2Sampler::from_state(4, &state);
+        assert_eq!(restored.state(), state);
+        for _ in 0..20 {
+            let expected = sampler.sample(&mut logits.clone())?;
+            assert_eq!(restored.sample(&mut logits.clone())?, expected);
+        }
+        Ok(())
     }
 }
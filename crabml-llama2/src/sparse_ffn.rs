@@ -0,0 +1,161 @@
+use std::cell::Cell;
+use std::sync::OnceLock;
+
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::tensor::Tensor;
+
+// the number of the one-hot inputs forwarded at once on transposing the down projection
+const TRANSPOSE_CHUNK: usize = 64;
+
+/// the options of the sparsity-aware FFN: the activations before the down projection which are
+/// about zero are skipped with their rows of the down projection, like PowerInfer. it's a large
+/// win on the ReLU-family FFNs, where most of the activations are exactly zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SparseFfnOptions {
+    threshold: f32,
+    max_density: f32,
+}
+
+impl SparseFfnOptions {
+    /// skip the activations whose absolute value is at most the threshold, a threshold of 0
+    /// only skips the exact zeros. a larger one trades some accuracy for the speed on the
+    /// SiLU/GeLU models.
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            max_density: 0.3,
+        }
+    }
+
+    /// run the dense down projection if more than this fraction of the activations is kept,
+    /// where the skipping does not pay off. 0.3 by default.
+    pub fn with_max_density(mut self, max_density: f32) -> Self {
+        self.max_density = max_density;
+        self
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+}
+
+/// the down projections transposed into (hidden_dim, embed_dim) rows of f32, they're built on
+/// the first sparse forward of each layer. it's kept in the weights and shared by all the
+/// runners of the model, it takes 4 bytes per weight of the down projections once any runner
+/// takes the sparse FFN.
+pub struct SparseDownRows {
+    layers: Vec<OnceLock<Vec<f32>>>,
+}
+
+impl SparseDownRows {
+    pub fn new(n_layers: usize) -> Self {
+        Self {
+            layers: (0..n_layers).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    /// whether the rows of the layer are built.
+    pub fn is_built(&self, l: usize) -> bool {
+        self.layers[l].get().is_some()
+    }
+
+    fn get_or_build<T: Tensor>(
+        &self,
+        w: &T,
+        hidden_dim: usize,
+        l: usize,
+        device: &T::Device,
+    ) -> Result<&[f32]> {
+        if let Some(rows) = self.layers[l].get() {
+            return Ok(rows);
+        }
+        let rows = transpose_down(w, hidden_dim, device)?;
+        Ok(self.layers[l].get_or_init(|| rows))
+    }
+}
+
+pub(crate) struct SparseFfn {
+    options: SparseFfnOptions,
+    n_active: Cell<usize>,
+    n_total: Cell<usize>,
+}
+
+impl SparseFfn {
+    pub(crate) fn new(options: SparseFfnOptions) -> Self {
+        Self {
+            options,
+            n_active: Cell::new(0),
+            n_total: Cell::new(0),
+        }
+    }
+
+    /// the fraction of the activations kept so far.
+    pub(crate) fn density(&self) -> f32 {
+        if self.n_total.get() == 0 {
+            return 1.0;
+        }
+        self.n_active.get() as f32 / self.n_total.get() as f32
+    }
+
+    /// w (embed_dim, hidden_dim) @ h (n_batch, hidden_dim) => (n_batch, embed_dim) over the
+    /// active rows only, the rows of w are transposed into down_rows. returns None if the
+    /// activations are too dense to skip.
+    pub(crate) fn forward_down<T: Tensor>(
+        &self,
+        w: &T,
+        h: &T,
+        down_rows: &SparseDownRows,
+        l: usize,
+        device: &T::Device,
+    ) -> Result<Option<T>> {
+        let hidden_dim = *h.shape().last().unwrap();
+        let n_batch = h.shape().iter().product::<usize>() / hidden_dim;
+        let mut hv = vec![0.0; n_batch * hidden_dim];
+        h.export(&mut hv)?;
+
+        // a row is active if any token of the batch activates it
+        let threshold = self.options.threshold;
+        let active = (0..hidden_dim)
+            .filter(|&j| (0..n_batch).any(|b| hv[b * hidden_dim + j].abs() > threshold))
+            .collect::<Vec<_>>();
+        self.n_active.set(self.n_active.get() + active.len());
+        self.n_total.set(self.n_total.get() + hidden_dim);
+        if active.len() as f32 > self.options.max_density * hidden_dim as f32 {
+            return Ok(None);
+        }
+
+        let rows = down_rows.get_or_build(w, hidden_dim, l, device)?;
+        let embed_dim = rows.len() / hidden_dim;
+        let mut out = vec![0.0; n_batch * embed_dim];
+        for (b, out_row) in out.chunks_mut(embed_dim).enumerate() {
+            for &j in active.iter() {
+                let a = hv[b * hidden_dim + j];
+                let row = &rows[j * embed_dim..(j + 1) * embed_dim];
+                out_row.iter_mut().zip(row).for_each(|(o, w)| *o += a * w);
+            }
+        }
+        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, device.clone())?;
+        x.import(&out)?;
+        Ok(Some(x))
+    }
+}
+
+// the row j of the transposed weight is the output on the one-hot input j, they're computed
+// with the same kernels as the dense path, so it works on any backend and quantization.
+fn transpose_down<T: Tensor>(w: &T, hidden_dim: usize, device: &T::Device) -> Result<Vec<f32>> {
+    let embed_dim = w.shape()[0];
+    let mut rows = vec![0.0; hidden_dim * embed_dim];
+    for start in (0..hidden_dim).step_by(TRANSPOSE_CHUNK) {
+        let n = TRANSPOSE_CHUNK.min(hidden_dim - start);
+        let mut one_hot = vec![0.0; n * hidden_dim];
+        for i in 0..n {
+            one_hot[i * hidden_dim + start + i] = 1.0;
+        }
+        let mut x = T::alloc(&[n, hidden_dim], GGMLType::F32, device.clone())?;
+        x.import(&one_hot)?;
+        let y = w.matmul_vec(&x)?; // (n, embed_dim)
+        y.export(&mut rows[start * embed_dim..(start + n) * embed_dim])?;
+    }
+    Ok(rows)
+}
@@ -0,0 +1,298 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+
+use crate::event::logprob;
+use crate::event::GenerationEvent;
+use crate::event::StopReason;
+use crate::llama2::Llama2Runner;
+use crate::sampler::Llama2Sampler;
+
+/// the statistics of a speculative decoding session, to tune the draft settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpeculativeStats {
+    /// the number of the draft/verify steps.
+    pub n_steps: usize,
+
+    /// the number of the tokens proposed by the draft model.
+    pub n_drafted: usize,
+
+    /// the number of the drafted tokens accepted by the target model.
+    pub n_accepted: usize,
+
+    /// the number of the generated tokens, the accepted ones plus one from the target per step.
+    pub n_generated: usize,
+
+    pub draft_time: Duration,
+    pub verify_time: Duration,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.n_drafted == 0 {
+            return 0.0;
+        }
+        self.n_accepted as f64 / self.n_drafted as f64
+    }
+
+    pub fn mean_draft_len(&self) -> f64 {
+        if self.n_steps == 0 {
+            return 0.0;
+        }
+        self.n_drafted as f64 / self.n_steps as f64
+    }
+
+    /// the estimated time saved over decoding on the target model alone, a verify step is
+    /// taken as the cost of a forward of the target, which holds while the decoding is bound
+    /// by the memory bandwidth. it's negative if the draft model does not pay off.
+    pub fn time_saved_ms(&self) -> f64 {
+        if self.n_steps == 0 {
+            return 0.0;
+        }
+        let verify_ms = self.verify_time.as_secs_f64() * 1000.0;
+        let draft_ms = self.draft_time.as_secs_f64() * 1000.0;
+        let baseline_ms = verify_ms / self.n_steps as f64 * self.n_generated as f64;
+        baseline_ms - verify_ms - draft_ms
+    }
+}
+
+/// greedy speculative decoding: a small draft model proposes a few tokens, and the target
+/// model verifies them in a single batched forward, the tokens are accepted until the first
+/// one which differs from the argmax of the target. the output is the same as the greedy
+/// decoding on the target model. both models should share the same tokenizer. cpu only, since
+/// the verification runs on forward_tree.
+pub struct SpeculativeDecoder<'r, T: Tensor> {
+    target: &'r mut Llama2Runner<T>,
+    draft: &'r mut Llama2Runner<T>,
+    draft_len: usize,
+    stats: SpeculativeStats,
+}
+
+impl<'r, T: Tensor> SpeculativeDecoder<'r, T> {
+    pub fn new(target: &'r mut Llama2Runner<T>, draft: &'r mut Llama2Runner<T>) -> Result<Self> {
+        if target.tokenizer().vocab() != draft.tokenizer().vocab() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the draft model should share the same tokenizer with the target model",
+            ));
+        }
+        if target.kv_cache_len() != draft.kv_cache_len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                "the draft and the target models should have the same tokens in the KV cache",
+            ));
+        }
+        Ok(Self {
+            target,
+            draft,
+            draft_len: 4,
+            stats: SpeculativeStats::default(),
+        })
+    }
+
+    /// the max number of the tokens drafted per step, 4 by default.
+    pub fn with_draft_len(mut self, draft_len: usize) -> Self {
+        self.draft_len = draft_len;
+        self
+    }
+
+    pub fn stats(&self) -> &SpeculativeStats {
+        &self.stats
+    }
+
+    /// prefill the prompt on both models, returns the next position and the first token
+    /// generated by the target model.
+    pub fn prefill(&mut self, prompt: &str, batched: bool) -> Result<(usize, usize)> {
+        let (pos, _prev_token, token) = self.target.prefill(prompt, true, batched)?;
+        self.draft.prefill(prompt, true, batched)?;
+        Ok((pos, token))
+    }
+
+    /// draft and verify a step after the token at pos, which is generated but not forwarded
+    /// yet. returns the accepted tokens followed by the one sampled by the target, the last one
+    /// is the next token to forward at pos plus the number of the returned tokens.
+    pub fn step(&mut self, pos: usize, token: usize) -> Result<Vec<usize>> {
+        let started_at = Instant::now();
+        let draft_len = self
+            .draft_len
+            .min(self.target.remaining_context().saturating_sub(1));
+
+        let mut tokens = vec![token];
+        let mut last = token;
+        for i in 0..draft_len {
+            let logits = self.draft.forward(&[last], pos + i)?;
+            last = Llama2Sampler::sample_argmax(logits)?;
+            tokens.push(last);
+        }
+        let draft_time = started_at.elapsed();
+
+        // the drafted tokens make a chain in the tree
+        let parents = (0..tokens.len())
+            .map(|i| i.checked_sub(1))
+            .collect::<Vec<_>>();
+        let logits = self.target.forward_tree(&tokens, &parents)?;
+        let vocab_size = self.target.conf().vocab_size;
+        let mut accepted = vec![];
+        let mut logprobs = vec![];
+        for (i, row) in logits.chunks(vocab_size).enumerate() {
+            let next = Llama2Sampler::sample_argmax(row)?;
+            accepted.push(next);
+            logprobs.push(logprob(row, next));
+            if i == draft_len || tokens[i + 1] != next {
+                break;
+            }
+        }
+        let n_accepted = accepted.len() - 1;
+
+        // keep the accepted tokens in both KV caches, the draft model has not forwarded the
+        // last drafted token yet
+        let path = (0..=n_accepted).collect::<Vec<_>>();
+        self.target
+            .commit_tree_path(pos, &tokens, &parents, &path)?;
+        if n_accepted < draft_len {
+            self.draft.truncate(pos + n_accepted + 1)?;
+        } else {
+            self.draft.forward(&[tokens[draft_len]], pos + draft_len)?;
+        }
+        let elapsed = started_at.elapsed();
+
+        self.stats.n_steps += 1;
+        self.stats.n_drafted += draft_len;
+        self.stats.n_accepted += n_accepted;
+        self.stats.n_generated += accepted.len();
+        self.stats.draft_time += draft_time;
+        self.stats.verify_time += elapsed - draft_time;
+        self.target.emit_event(GenerationEvent::SpeculativeStep {
+            n_drafted: draft_len,
+            n_accepted,
+            draft_t_ms: draft_time.as_secs_f64() * 1000.0,
+            verify_t_ms: (elapsed - draft_time).as_secs_f64() * 1000.0,
+        });
+        let t_ms = elapsed.as_secs_f64() * 1000.0 / accepted.len() as f64;
+        for (&id, logprob) in accepted.iter().zip(logprobs) {
+            self.target.emit_event(GenerationEvent::TokenGenerated {
+                id,
+                text: self.target.tokenizer().decode(id)?,
+                logprob,
+                t_ms,
+            });
+        }
+        Ok(accepted)
+    }
+
+    /// generate at most steps tokens from the token sampled on the prefill at pos, the text of
+    /// each token is passed to the callback once it's accepted. returns the number of the
+    /// generated tokens.
+    pub fn generate(
+        &mut self,
+        mut pos: usize,
+        mut token: usize,
+        steps: usize,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<usize> {
+        let mut n_generated = 0;
+        // the generated tokens to yield, the last one is not forwarded yet
+        let mut pending = vec![token];
+        loop {
+            for &next in pending.iter() {
+                // the EOS under the EOS policy of the target, and its stop tokens
+                if self.target.is_stop_token(next) {
+                    self.target.stop(StopReason::Eos);
+                    return Ok(n_generated);
+                }
+                if n_generated == steps {
+                    self.target.stop(StopReason::MaxSteps);
+                    return Ok(n_generated);
+                }
+                on_token(&self.target.tokenizer().decode(next)?);
+                n_generated += 1;
+            }
+            if self.target.remaining_context() == 0 {
+                self.target.stop(StopReason::ContextLength);
+                break;
+            }
+
+            pending = self.step(pos, token)?;
+            pos += pending.len();
+            token = *pending.last().unwrap();
+        }
+        Ok(n_generated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::model::CpuLlama2ModelLoader;
+
+    #[test]
+    fn test_speculative_decoding() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf)?;
+        let gl_draft = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf", false)?;
+        let gf_draft = gl_draft.open()?;
+        let lm_draft = CpuLlama2ModelLoader::new()
+            .with_temperature(0.0)
+            .load(&gf_draft)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let want = runner
+            .prefill_and_generate("Lily is a cat", 20)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+
+        // the output is the same as the greedy decoding on the target model
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut target = Llama2Runner::new(&lm, 200, false)?.with_event_sender(tx);
+        let mut draft = Llama2Runner::new(&lm_draft, 200, false)?;
+        let mut decoder = SpeculativeDecoder::new(&mut target, &mut draft)?.with_draft_len(3);
+        let (pos, token) = decoder.prefill("Lily is a cat", false)?;
+        let mut output = String::new();
+        let n_generated = decoder.generate(pos, token, 20, |s| output.push_str(s))?;
+        assert_eq!(output, want);
+        assert_eq!(n_generated, 20);
+
+        let stats = decoder.stats().clone();
+        assert!(stats.n_steps > 0);
+        assert!(stats.n_accepted <= stats.n_drafted);
+        assert!(stats.mean_draft_len() <= 3.0);
+        assert_eq!(target.stop_reason(), Some(StopReason::MaxSteps));
+        assert_eq!(target.kv_cache_len(), draft.kv_cache_len());
+        let events = rx.try_iter().collect::<Vec<_>>();
+        let n_step_events = events
+            .iter()
+            .filter(|e| matches!(e, GenerationEvent::SpeculativeStep { .. }))
+            .count();
+        assert_eq!(n_step_events, stats.n_steps);
+
+        // the stop tokens of the target stop the generation like the EOS
+        let generated = events
+            .iter()
+            .filter_map(|e| match e {
+                GenerationEvent::TokenGenerated { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let stop_token = generated[4];
+        let mut target = Llama2Runner::new(&lm, 200, false)?;
+        target.set_stop_tokens(vec![stop_token]);
+        let mut draft = Llama2Runner::new(&lm_draft, 200, false)?;
+        let mut decoder = SpeculativeDecoder::new(&mut target, &mut draft)?.with_draft_len(3);
+        let (pos, token) = decoder.prefill("Lily is a cat", false)?;
+        let mut output = String::new();
+        let n_generated = decoder.generate(pos, token, 20, |s| output.push_str(s))?;
+        assert!(n_generated < 20);
+        assert!(want.starts_with(&output) && output.len() < want.len());
+        assert_eq!(target.stop_reason(), Some(StopReason::Eos));
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use crate::event::StopReason;
+
+/// what the stopping criteria see on each generated token.
+#[derive(Debug, Clone, Copy)]
+pub struct StoppingContext<'a> {
+    /// the token just sampled.
+    pub token: usize,
+
+    /// the text of the token, it's empty if the token ends in the middle of a character.
+    pub piece: &'a str,
+
+    /// the text generated so far, ending with the piece.
+    pub text: &'a str,
+
+    /// the number of the tokens generated so far, including this one.
+    pub n_generated: usize,
+
+    /// the raw logits the token was sampled from, before the temperature of the sampler.
+    pub logits: &'a [f32],
+}
+
+/// decides whether the generation stops on a generated token. the criteria are registered on
+/// the runner per request with `set_stopping_criteria`, and are checked after the built-in
+/// checks of the EOS and the context window. the token hit is yielded unless the reason is
+/// `StopReason::Eos`, like the EOS token itself.
+pub trait StoppingCriteria {
+    fn should_stop(&mut self, ctx: &StoppingContext) -> Option<StopReason>;
+}
+
+/// the closures over the context are taken as the custom criteria.
+impl<F: FnMut(&StoppingContext) -> Option<StopReason>> StoppingCriteria for F {
+    fn should_stop(&mut self, ctx: &StoppingContext) -> Option<StopReason> {
+        self(ctx)
+    }
+}
+
+/// stop after max_tokens tokens are generated, the last one is yielded.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxTokens(pub usize);
+
+impl StoppingCriteria for MaxTokens {
+    fn should_stop(&mut self, ctx: &StoppingContext) -> Option<StopReason> {
+        (ctx.n_generated >= self.0).then_some(StopReason::MaxSteps)
+    }
+}
+
+/// stop on these tokens, they're not yielded like the EOS.
+#[derive(Debug, Clone)]
+pub struct StopTokens(pub Vec<usize>);
+
+impl StoppingCriteria for StopTokens {
+    fn should_stop(&mut self, ctx: &StoppingContext) -> Option<StopReason> {
+        self.0.contains(&ctx.token).then_some(StopReason::Eos)
+    }
+}
+
+/// stop once the generated text contains any of the strings, the strings may span over the
+/// tokens. the token completing the string is yielded, the callers trim the text after it.
+#[derive(Debug, Clone)]
+pub struct StopStrings(pub Vec<String>);
+
+impl StopStrings {
+    /// the start of the first stop string in the text, where the text is trimmed.
+    pub fn find(&self, text: &str) -> Option<usize> {
+        self.0
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| text.find(s.as_str()))
+            .min()
+    }
+
+    /// the length of the longest tail of the text which begins a stop string, it's held back
+    /// from the clients until the next pieces tell whether the string completes.
+    pub fn held_len(&self, text: &str) -> usize {
+        self.0
+            .iter()
+            .map(|s| {
+                (1..s.len().min(text.len() + 1))
+                    .rev()
+                    .find(|&n| {
+                        let start = text.len() - n;
+                        text.is_char_boundary(start)
+                            && s.as_bytes().starts_with(&text.as_bytes()[start..])
+                    })
+                    .unwrap_or(0)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl StoppingCriteria for StopStrings {
+    fn should_stop(&mut self, ctx: &StoppingContext) -> Option<StopReason> {
+        let hit = self.0.iter().filter(|s| !s.is_empty()).any(|s| {
+            // only the tail which the new piece may complete a string in is searched
+            let mut start = ctx.text.len().saturating_sub(ctx.piece.len() + s.len() - 1);
+            while !ctx.text.is_char_boundary(start) {
+                start -= 1;
+            }
+            ctx.text[start..].contains(s.as_str())
+        });
+        hit.then_some(StopReason::StopString)
+    }
+}
+
+/// a list of the criteria checked in order, the first one hit stops the generation.
+#[derive(Default)]
+pub struct StoppingCriteriaList {
+    criteria: Vec<Box<dyn StoppingCriteria + Send>>,
+}
+
+impl StoppingCriteriaList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, criteria: impl StoppingCriteria + Send + 'static) -> Self {
+        self.push(criteria);
+        self
+    }
+
+    pub fn push(&mut self, criteria: impl StoppingCriteria + Send + 'static) {
+        self.criteria.push(Box::new(criteria));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.criteria.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.criteria.len()
+    }
+}
+
+impl StoppingCriteria for StoppingCriteriaList {
+    fn should_stop(&mut self, ctx: &StoppingContext) -> Option<StopReason> {
+        self.criteria.iter_mut().find_map(|c| c.should_stop(ctx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx<'a>(
+        token: usize,
+        piece: &'a str,
+        text: &'a str,
+        n_generated: usize,
+    ) -> StoppingContext<'a> {
+        StoppingContext {
+            token,
+            piece,
+            text,
+            n_generated,
+            logits: &[],
+        }
+    }
+
+    #[test]
+    fn test_stopping_criteria() {
+        let bang = |ctx: &StoppingContext| ctx.piece.contains('!').then_some(StopReason::Criteria);
+        let mut criteria = StoppingCriteriaList::new()
+            .with(StopTokens(vec![7]))
+            .with(StopStrings(vec!["\n\n".to_string(), "END".to_string()]))
+            .with(MaxTokens(5))
+            .with(bang);
+        assert_eq!(criteria.len(), 4);
+
+        assert_eq!(criteria.should_stop(&ctx(1, "a", "a", 1)), None);
+        assert_eq!(
+            criteria.should_stop(&ctx(7, "", "a", 2)),
+            Some(StopReason::Eos)
+        );
+        // the stop string spans over the last two pieces
+        assert_eq!(criteria.should_stop(&ctx(2, "\n", "a\n", 2)), None);
+        let got = criteria.should_stop(&ctx(2, "\n", "a\n\n", 3));
+        assert_eq!(got, Some(StopReason::StopString));
+        let got = criteria.should_stop(&ctx(3, "ND", "the EÉND", 3));
+        assert_eq!(got, None);
+        let got = criteria.should_stop(&ctx(3, "ND", "the ÉEND", 3));
+        assert_eq!(got, Some(StopReason::StopString));
+        assert_eq!(
+            criteria.should_stop(&ctx(4, "b", "ab", 5)),
+            Some(StopReason::MaxSteps)
+        );
+        assert_eq!(
+            criteria.should_stop(&ctx(4, "!", "a!", 2)),
+            Some(StopReason::Criteria)
+        );
+    }
+
+    #[test]
+    fn test_stop_strings_find() {
+        let stop = StopStrings(vec!["\n\n".to_string(), "END".to_string(), "".to_string()]);
+        assert_eq!(stop.find("a\n\nEND"), Some(1));
+        assert_eq!(stop.find("the END\n\n"), Some(4));
+        assert_eq!(stop.find("the E"), None);
+        assert_eq!(stop.held_len("the E"), 1);
+        assert_eq!(stop.held_len("the EN"), 2);
+        assert_eq!(stop.held_len("a\n"), 1);
+        assert_eq!(stop.held_len("好"), 0);
+    }
+}
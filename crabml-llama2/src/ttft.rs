@@ -0,0 +1,191 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::llama2::PrefillTiming;
+
+/// the time to the first token broken down into its stages, to tell whether a slow start is
+/// bound by the I/O of the model file or by the compute. the stages a frontend does not go
+/// through are left zero. the weights are not touched ahead, which would warm up the cold start
+/// it measures, their faults are split out of the first prefill by `with_warm_prefill`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TtftReport {
+    /// mapping the model file and decoding its header.
+    pub file_mapping: Duration,
+
+    /// faulting in the pages of the weights on the first prefill.
+    pub weight_touch: Duration,
+
+    /// building the tensors of the model over the mapped weights, and uploading them to the
+    /// GPU if it runs on one.
+    pub model_load: Duration,
+
+    /// the time the request waited before it was served, like for a free replica.
+    pub queueing: Duration,
+
+    /// restoring the history of a chat into the KV cache before its last message.
+    pub history_restore: Duration,
+
+    pub tokenizer: Duration,
+
+    /// forwarding the prompt and sampling the first token.
+    pub prefill: Duration,
+    pub n_prompt_tokens: usize,
+}
+
+/// what a slow start mostly spends its time on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtftBound {
+    /// mapping the file and faulting in the weights.
+    Io,
+
+    /// loading the model, restoring the chat history, encoding and prefilling the prompt.
+    Compute,
+
+    Queueing,
+}
+
+impl Display for TtftBound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TtftBound::Io => write!(f, "io"),
+            TtftBound::Compute => write!(f, "compute"),
+            TtftBound::Queueing => write!(f, "queueing"),
+        }
+    }
+}
+
+impl TtftReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// take the tokenizer and the prefill time of the runner's last prefill.
+    pub fn with_prefill(mut self, timing: PrefillTiming) -> Self {
+        self.tokenizer = timing.tokenize;
+        self.prefill = timing.compute;
+        self.n_prompt_tokens = timing.n_tokens;
+        self
+    }
+
+    /// split the faults of the weights out of the first prefill by a prefill of the same number
+    /// of tokens on the warm weights, see `Llama2Runner::time_warm_prefill`.
+    pub fn with_warm_prefill(mut self, warm: Duration) -> Self {
+        self.weight_touch = self.prefill.saturating_sub(warm);
+        self.prefill -= self.weight_touch;
+        self
+    }
+
+    pub fn io(&self) -> Duration {
+        self.file_mapping + self.weight_touch
+    }
+
+    pub fn compute(&self) -> Duration {
+        self.model_load + self.history_restore + self.tokenizer + self.prefill
+    }
+
+    pub fn total(&self) -> Duration {
+        self.io() + self.compute() + self.queueing
+    }
+
+    pub fn bound(&self) -> TtftBound {
+        let (io, compute) = (self.io(), self.compute());
+        if self.queueing > io && self.queueing > compute {
+            TtftBound::Queueing
+        } else if io > compute {
+            TtftBound::Io
+        } else {
+            TtftBound::Compute
+        }
+    }
+}
+
+impl Display for TtftReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.total();
+        let prompt = format!("{} tokens", self.n_prompt_tokens);
+        let stages = [
+            ("file mapping", self.file_mapping, ""),
+            ("weight touch", self.weight_touch, ""),
+            ("model load", self.model_load, ""),
+            ("queueing", self.queueing, ""),
+            ("history", self.history_restore, ""),
+            ("tokenizer", self.tokenizer, ""),
+            ("prefill", self.prefill, prompt.as_str()),
+        ];
+        for (name, elapsed, note) in stages {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                elapsed.as_secs_f64() / total.as_secs_f64() * 100.0
+            };
+            let line = format!(
+                "ttft: {: <12} {:>10.1}ms {:>5.1}% {}",
+                name,
+                elapsed.as_secs_f64() * 1000.0,
+                share,
+                note
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        write!(
+            f,
+            "ttft: total {:.1}ms, bound by {}",
+            total.as_secs_f64() * 1000.0,
+            self.bound()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttft_report() {
+        // the first prefill of 357ms took 57ms on the warm weights
+        let report = TtftReport {
+            file_mapping: Duration::from_millis(2),
+            model_load: Duration::from_millis(40),
+            ..TtftReport::new()
+        }
+        .with_prefill(PrefillTiming {
+            n_tokens: 12,
+            tokenize: Duration::from_millis(1),
+            compute: Duration::from_millis(357),
+        })
+        .with_warm_prefill(Duration::from_millis(57));
+        assert_eq!(report.weight_touch, Duration::from_millis(300));
+        assert_eq!(report.prefill, Duration::from_millis(57));
+        assert_eq!(report.total(), Duration::from_millis(400));
+        assert_eq!(report.bound(), TtftBound::Io);
+
+        let text = report.to_string();
+        assert!(text.contains("weight touch"), "{}", text);
+        assert!(text.contains("300.0ms  75.0%"), "{}", text);
+        assert!(
+            text.ends_with("ttft: total 400.0ms, bound by io"),
+            "{}",
+            text
+        );
+
+        // a warm start is bound by the prefill
+        let warm = TtftReport {
+            weight_touch: Duration::ZERO,
+            ..report
+        };
+        assert_eq!(warm.bound(), TtftBound::Compute);
+
+        // a warm prefill slower than the first one leaves no faults
+        let noisy = report.with_warm_prefill(Duration::from_millis(80));
+        assert_eq!(noisy.weight_touch, Duration::ZERO);
+        assert_eq!(noisy.prefill, Duration::from_millis(57));
+
+        // the restored history of a chat is compute, not queueing
+        let chat = TtftReport {
+            history_restore: Duration::from_millis(500),
+            ..report
+        };
+        assert_eq!(chat.bound(), TtftBound::Compute);
+        assert!(chat.to_string().contains("history"));
+    }
+}
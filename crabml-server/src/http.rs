@@ -0,0 +1,368 @@
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+use std::time::Instant;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use serde_json::Value;
+
+// the requests are prompts and chat histories, a body larger than it is refused
+const MAX_BODY_BYTES: usize = 16 << 20;
+
+// the request line and the headers are small, a head larger than it is refused before it's
+// buffered
+const MAX_HEAD_BYTES: usize = 64 << 10;
+
+const MAX_HEADERS: usize = 100;
+
+/// a HTTP/1.1 request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// the path without the query string
+    pub path: String,
+    /// the headers in the order they're sent, the names are kept as they're sent
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// the value of the first header of the name, the names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// read a request off the connection, the body is read by its Content-Length. the chunked
+/// bodies are not supported, the OpenAI clients always send the length of the JSON.
+pub fn read_request(reader: &mut impl BufRead) -> Result<HttpRequest> {
+    let mut head_left = MAX_HEAD_BYTES;
+    let line = read_head_line(reader, &mut head_left)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("malformed request line {:?}", line.trim_end()),
+            ));
+        }
+    };
+    let path = target.split('?').next().unwrap_or(target).to_string();
+
+    let mut headers = vec![];
+    let mut content_length = 0;
+    loop {
+        let header = read_head_line(reader, &mut head_left)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("the request has more than {} headers", MAX_HEADERS),
+            ));
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().map_err(|_| {
+                Error::new(
+                    ErrorKind::BadInput,
+                    format!("invalid Content-Length {:?}", value),
+                )
+            })?;
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(Error::new(
+            ErrorKind::BadInput,
+            format!(
+                "the body of {} bytes exceeds the limit of {} bytes",
+                content_length, MAX_BODY_BYTES
+            ),
+        ));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+// read a line of the request head, which is empty at the end of the stream. the lines share
+// the budget of the head
+fn read_head_line(reader: &mut impl BufRead, head_left: &mut usize) -> Result<String> {
+    let mut line = String::new();
+    let n = reader
+        .by_ref()
+        .take(*head_left as u64)
+        .read_line(&mut line)?;
+    *head_left -= n;
+    if *head_left == 0 {
+        return Err(Error::new(
+            ErrorKind::BadInput,
+            format!(
+                "the request head exceeds the limit of {} bytes",
+                MAX_HEAD_BYTES
+            ),
+        ));
+    }
+    Ok(line)
+}
+
+/// reads a connection until a deadline for the whole request, unlike the read timeout of the
+/// socket which a client can keep renewing by sending a byte at a time.
+pub struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineReader {
+    pub fn new(stream: TcpStream, timeout: Duration) -> Self {
+        Self {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "the request is not received in time",
+            ));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// writes a single response on a connection, either a whole body or a stream of server-sent
+/// events. the connection is closed after the response, there's no keep-alive.
+pub struct HttpResponse<W: Write> {
+    inner: W,
+    headers_sent: bool,
+    // sent with every status, like the X-Request-Id
+    extra_headers: Vec<(String, String)>,
+}
+
+impl<W: Write> HttpResponse<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            headers_sent: false,
+            extra_headers: vec![],
+        }
+    }
+
+    /// add a header to the response, it takes no effect once the headers are sent.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.extra_headers
+            .push((name.to_string(), value.to_string()));
+    }
+
+    /// whether the status is sent, the errors after it can only be sent as events.
+    pub fn headers_sent(&self) -> bool {
+        self.headers_sent
+    }
+
+    pub fn send(&mut self, status: u16, content_type: &str, body: &[u8]) -> Result<()> {
+        self.write_head(status, &[
+            ("Content-Type", content_type),
+            ("Content-Length", &body.len().to_string()),
+        ])?;
+        self.inner.write_all(body)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    pub fn send_json(&mut self, status: u16, value: &Value) -> Result<()> {
+        self.send(status, "application/json", value.to_string().as_bytes())
+    }
+
+    /// reply the CORS preflight of the browsers, any origin may call the API.
+    pub fn send_preflight(&mut self) -> Result<()> {
+        self.write_head(204, &[
+            ("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+            (
+                "Access-Control-Allow-Headers",
+                "Content-Type, Authorization, X-Request-Id, Last-Event-ID",
+            ),
+            ("Content-Length", "0"),
+        ])?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// start the stream of the server-sent events, the body ends when the connection closes.
+    pub fn start_events(&mut self) -> Result<()> {
+        self.write_head(200, &[
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+        ])?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    pub fn send_event(&mut self, data: &str) -> Result<()> {
+        write!(self.inner, "data: {}\n\n", data)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// send an event with its ID, the client sends the ID of the last event it received in the
+    /// Last-Event-ID to resume the stream.
+    pub fn send_event_with_id(&mut self, id: &str, data: &str) -> Result<()> {
+        write!(self.inner, "id: {}\ndata: {}\n\n", id, data)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// send a comment, which the clients ignore, to keep a silent stream alive.
+    pub fn send_comment(&mut self, text: &str) -> Result<()> {
+        write!(self.inner, ": {}\n\n", text)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    fn write_head(&mut self, status: u16, headers: &[(&str, &str)]) -> Result<()> {
+        write!(
+            self.inner,
+            "HTTP/1.1 {} {}\r\n",
+            status,
+            reason_phrase(status)
+        )?;
+        for (name, value) in headers {
+            write!(self.inner, "{}: {}\r\n", name, value)?;
+        }
+        for (name, value) in &self.extra_headers {
+            write!(self.inner, "{}: {}\r\n", name, value)?;
+        }
+        // the browsers may call the server from a local web page, and read the request ID
+        write!(
+            self.inner,
+            "Access-Control-Allow-Origin: *\r\nAccess-Control-Expose-Headers: X-Request-Id\r\n\
+             Connection: close\r\n\r\n"
+        )?;
+        self.headers_sent = true;
+        Ok(())
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() -> Result<()> {
+        let raw =
+            "POST /v1/completions?x=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 2\r\n\r\n{}";
+        let req = read_request(&mut raw.as_bytes())?;
+        assert_eq!(req, HttpRequest {
+            method: "POST".to_string(),
+            path: "/v1/completions".to_string(),
+            headers: vec![
+                ("Host".to_string(), "localhost".to_string()),
+                ("content-length".to_string(), "2".to_string()),
+            ],
+            body: b"{}".to_vec(),
+        });
+        assert_eq!(req.header("Content-Length"), Some("2"));
+        assert_eq!(req.header("X-Request-Id"), None);
+
+        let raw = "GET /v1/models HTTP/1.1\r\n\r\n";
+        assert!(read_request(&mut raw.as_bytes())?.body.is_empty());
+
+        assert!(read_request(&mut "\r\n".as_bytes()).is_err());
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}";
+        assert!(read_request(&mut raw.as_bytes()).is_err());
+
+        // the head is bounded in its bytes and its headers
+        let raw = format!(
+            "GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_BYTES)
+        );
+        assert!(read_request(&mut raw.as_bytes()).is_err());
+        let raw = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(read_request(&mut raw.as_bytes()).is_err());
+        let raw = format!("GET / HTTP/1.1\r\n{}\r\n", "X: a\r\n".repeat(MAX_HEADERS));
+        assert_eq!(
+            read_request(&mut raw.as_bytes())?.headers.len(),
+            MAX_HEADERS
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadline_reader() -> Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+
+        // the client trickles the head, the deadline is over the whole of it
+        let started = Instant::now();
+        let mut reader =
+            std::io::BufReader::new(DeadlineReader::new(stream, Duration::from_millis(300)));
+        let trickle = std::thread::spawn(move || {
+            for _ in 0..20 {
+                if client.write_all(b"X").is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+        assert!(read_request(&mut reader).is_err());
+        assert!(started.elapsed() < Duration::from_millis(900));
+        drop(reader);
+        trickle.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_events() -> Result<()> {
+        let mut resp = HttpResponse::new(vec![]);
+        resp.add_header("X-Request-Id", "req-1");
+        resp.start_events()?;
+        assert!(resp.headers_sent());
+        resp.send_comment("keep-alive")?;
+        resp.send_event_with_id("cmpl-req-1:1", "[DONE]")?;
+        let text = String::from_utf8(resp.inner).unwrap();
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n"));
+        assert!(text.contains("\r\nX-Request-Id: req-1\r\n"));
+        assert!(text.ends_with("\r\n\r\n: keep-alive\n\nid: cmpl-req-1:1\ndata: [DONE]\n\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+mod http;
+mod openai;
+mod replica;
+mod stream;
+
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use clap::Parser;
+#[cfg(feature = "wgpu")]
+use crabml::backends::wgpu::WgpuTensorDevice;
+use crabml::backends::BackendOptions;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::model::CpuLlama2ModelLoader;
+use crabml_llama2::ttft::TtftReport;
+
+use crate::openai::OpenAIServer;
+use crate::openai::ServerOptions;
+use crate::replica::serve_replica;
+use crate::replica::ReplicaDevice;
+use crate::stream::StreamRegistry;
+
+/// serves /v1/completions and /v1/chat/completions of the OpenAI API on a local model, so the
+/// OpenAI clients can talk to it by pointing their base url to the server.
+#[derive(Parser, Debug)]
+struct ServerArgs {
+    /// The checkpoint file to load
+    #[arg(short, long)]
+    model: String,
+
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// The context length of a request, defaults to the one of the model
+    #[arg(long)]
+    context: Option<usize>,
+
+    /// The max number of tokens of a completion, if not given in the request
+    #[arg(long, default_value_t = 256)]
+    max_tokens: usize,
+
+    /// The temperature of sampling, if not given in the request
+    #[arg(long, default_value_t = 1.0)]
+    temperature: f32,
+
+    /// The top-p of sampling, if not given in the request
+    #[arg(long, default_value_t = 0.9)]
+    top_p: f32,
+
+    /// The model id returned to the clients, defaults to the file name of the checkpoint
+    #[arg(long)]
+    model_name: Option<String>,
+
+    /// Break down the time to the first token of the first request into the file mapping, the
+    /// page faults of the weights, the model loading, the queueing, the chat history, the
+    /// tokenizer and the prefill, to tell whether a slow start is bound by the I/O or the compute
+    #[arg(long, default_value_t = false)]
+    ttft_report: bool,
+
+    /// Stop the generations stuck in a cycle of repeating tokens, they finish with the "loop"
+    /// finish_reason
+    #[arg(long, default_value_t = false)]
+    loop_watchdog: bool,
+
+    /// Load a replica of the model on each of these GPUs, like 0,1, and share the requests
+    /// between them. The indexes are in the order of the GPUs listed on the startup, the model
+    /// is served on the CPU if not given
+    #[arg(long, value_delimiter = ',')]
+    gpus: Vec<usize>,
+
+    /// How long the generation of a stream goes on after the client is gone, and how long a
+    /// finished stream is kept, for the client to resume it with the Last-Event-ID
+    #[arg(long, default_value_t = 30)]
+    stream_grace_secs: u64,
+
+    /// Send a comment on a stream which is silent for this long, so the proxies do not close
+    /// the idle connections
+    #[arg(long, default_value_t = 15)]
+    keep_alive_secs: u64,
+}
+
+// counts down the live replicas, and exits the process once the last one is gone, even on its
+// panic
+struct ReplicaGuard<'a>(&'a AtomicUsize);
+
+impl Drop for ReplicaGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.fetch_sub(1, Ordering::AcqRel) == 1 {
+            eprintln!("all the replicas are gone, exiting");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = ServerArgs::parse();
+    let started_at = Instant::now();
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    if let Some(err) = gl.prefetch_error() {
+        eprintln!("warning: {}", err);
+    }
+    let gf = gl.open()?;
+    let ttft = args.ttft_report.then(|| TtftReport {
+        file_mapping: started_at.elapsed(),
+        ..TtftReport::new()
+    });
+    let load_started_at = Instant::now();
+    let model = CpuLlama2ModelLoader::new()
+        .with_backend_options(BackendOptions::new().with_threads(args.threads))
+        .load(&gf)?;
+    let context = args.context.unwrap_or(model.conf.seq_len);
+    let devices = ReplicaDevice::from_gpus(&args.gpus)?;
+    let model_name = args.model_name.clone().unwrap_or_else(|| {
+        Path::new(&args.model)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| args.model.clone())
+    });
+    let options = ServerOptions {
+        model_name,
+        max_tokens: args.max_tokens,
+        temperature: args.temperature,
+        top_p: args.top_p,
+        loop_watchdog: args.loop_watchdog,
+    };
+    #[cfg(feature = "wgpu")]
+    if !args.gpus.is_empty() {
+        for (index, name) in WgpuTensorDevice::adapter_names().iter().enumerate() {
+            eprintln!("gpu:{}: {}", index, name);
+        }
+    }
+
+    let listener = TcpListener::bind((args.host.as_str(), args.port))?;
+    let (sender, receiver) = mpsc::channel();
+    let queue = Arc::new(Mutex::new(receiver));
+    let streams = Arc::new(StreamRegistry::new(
+        Duration::from_secs(args.stream_grace_secs),
+        Duration::from_secs(args.keep_alive_secs),
+    ));
+    // the first request which prefills a prompt on any replica completes the TTFT report
+    let ttft = ttft.map(|report| Arc::new(Mutex::new(Some(report))));
+    let live_replicas = AtomicUsize::new(devices.len());
+    thread::scope(|s| {
+        for device in devices.iter() {
+            let (queue, streams, ttft) = (queue.clone(), streams.clone(), ttft.clone());
+            let (model, options) = (&model, options.clone());
+            let live_replicas = &live_replicas;
+            s.spawn(move || {
+                // the listener blocks on accepting, it does not notice the replicas are gone
+                // until the next connection, so the last replica takes the server down
+                let _exit_on_last = ReplicaGuard(live_replicas);
+                let runner = match device.load_runner(model, context) {
+                    Ok(runner) => runner,
+                    Err(err) => {
+                        eprintln!("failed to load the replica on {}: {}", device, err);
+                        return;
+                    }
+                };
+                let mut server = OpenAIServer::new(runner, options, streams);
+                if let Some(shared) = ttft {
+                    // the model is loaded once the first replica is loaded
+                    if let Some(report) = shared.lock().unwrap().as_mut() {
+                        if report.model_load.is_zero() {
+                            report.model_load = load_started_at.elapsed();
+                        }
+                    }
+                    server = server.with_ttft_report(shared);
+                }
+                serve_replica(&mut server, &queue);
+            });
+        }
+        // the queue is closed once all the replicas are gone
+        drop(queue);
+
+        let replicas = devices.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        eprintln!(
+            "listening on http://{}:{}/v1, replicas on {}",
+            args.host,
+            args.port,
+            replicas.join(", ")
+        );
+        // each replica serves one request at a time, the others wait in the queue until a
+        // replica is free
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if sender.send((Instant::now(), stream)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => eprintln!("failed to accept the connection: {}", err),
+            }
+        }
+        Err(Error::new(
+            ErrorKind::Unexpected,
+            "all the replicas are gone",
+        ))
+    })
+}
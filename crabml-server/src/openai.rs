@@ -0,0 +1,947 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crabml::backends::cpu::CpuTensor;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml_llama2::chat::ChatMessage;
+use crabml_llama2::chat::ChatRole;
+use crabml_llama2::chat::ChatTemplate;
+use crabml_llama2::event::StopReason;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::loop_watchdog::LoopWatchdog;
+use crabml_llama2::stopping::MaxTokens;
+use crabml_llama2::stopping::StopStrings;
+use crabml_llama2::stopping::StoppingCriteriaList;
+use crabml_llama2::ttft::TtftReport;
+use crabml_llama2::Llama2Chat;
+use crabml_llama2::Llama2Sampler;
+use crabml_llama2::RequestId;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+use crate::stream::StreamLog;
+use crate::stream::StreamRegistry;
+
+// the request IDs of the clients longer than it are replaced by a generated one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// the defaults of the sampling, which the requests may override.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// the id of the model listed on /v1/models and returned in the responses, the model
+    /// asked in the requests is ignored as there's only one
+    pub model_name: String,
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub top_p: f32,
+
+    /// stop the generations stuck in a cycle of repeating tokens, with the "loop" finish_reason
+    pub loop_watchdog: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+/// the sampling parameters shared by the completions and the chat completions, the others like
+/// n, logprobs or the penalties are not supported and ignored.
+#[derive(Debug, Default, Deserialize)]
+struct SamplingParams {
+    max_tokens: Option<usize>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<u64>,
+    stop: Option<OneOrMany>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionRequest {
+    prompt: OneOrMany,
+    #[serde(flatten)]
+    params: SamplingParams,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<ChatMessageParam>,
+    #[serde(flatten)]
+    params: SamplingParams,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessageParam {
+    role: String,
+    #[serde(default)]
+    content: Option<String>,
+}
+
+impl ChatMessageParam {
+    fn into_message(self) -> Result<ChatMessage> {
+        let role = match self.role.as_str() {
+            "system" => ChatRole::System,
+            "user" => ChatRole::User,
+            "assistant" => ChatRole::Assistant,
+            role => {
+                return Err(Error::new(
+                    ErrorKind::BadInput,
+                    format!("unsupported role {:?} of the message", role),
+                ));
+            }
+        };
+        Ok(ChatMessage::new(role, self.content.unwrap_or_default()))
+    }
+}
+
+/// the TTFT report of the startup shared by the replicas, the first request which prefills a
+/// prompt on any of them completes it.
+pub type SharedTtftReport = Arc<Mutex<Option<TtftReport>>>;
+
+/// serves the OpenAI completions and chat completions on a single runner, the requests are
+/// served one at a time and each starts on an empty KV cache. the streams are shared with the
+/// other replicas, so a client can resume its stream on any of them.
+pub struct OpenAIServer<'a> {
+    runner: Llama2Runner<CpuTensor<'a>>,
+    options: ServerOptions,
+    streams: Arc<StreamRegistry>,
+    created: u64,
+    // the TTFT report waiting for the first request, and when the current request started
+    ttft_report: Option<SharedTtftReport>,
+    request_started_at: Instant,
+}
+
+impl<'a> OpenAIServer<'a> {
+    pub fn new(
+        runner: Llama2Runner<CpuTensor<'a>>,
+        options: ServerOptions,
+        streams: Arc<StreamRegistry>,
+    ) -> Self {
+        Self {
+            runner,
+            options,
+            streams,
+            created: unix_secs(),
+            ttft_report: None,
+            request_started_at: Instant::now(),
+        }
+    }
+
+    /// complete the report of the startup with the first request which prefills a prompt, and
+    /// print it once the request is served.
+    pub fn with_ttft_report(mut self, report: SharedTtftReport) -> Self {
+        self.ttft_report = Some(report);
+        self
+    }
+
+    /// serve the request, the errors of the request are sent to the client in the error
+    /// object of OpenAI, only the errors on writing the response are returned. the request is
+    /// tagged by the X-Request-Id of the client, or a generated one, which is echoed in the
+    /// response, emitted in the RequestStarted event of the runner, and prefixes every log line
+    /// of the request, like the op metrics and the TTFT report.
+    ///
+    /// a request with the Last-Event-ID resumes the stream of the event instead of starting
+    /// a new completion, the events after it are resent and the stream is followed to its end.
+    ///
+    /// accepted_at is when its connection was accepted, the request starts there, so the time
+    /// it waited for a free replica is counted.
+    pub fn handle<W: Write>(
+        &mut self,
+        req: &HttpRequest,
+        resp: &mut HttpResponse<W>,
+        accepted_at: Instant,
+    ) -> Result<()> {
+        self.request_started_at = accepted_at;
+        let request_id = request_id_of(req);
+        resp.add_header("X-Request-Id", request_id.as_str());
+        if req.method == "OPTIONS" {
+            return resp.send_preflight();
+        }
+        self.runner.set_request_id(Some(request_id.clone()));
+        let sent = self.serve(req, resp, &request_id);
+        // a panic on serving leaves the ID set, for the replica to log the panic with it
+        self.runner.set_request_id(None);
+        sent.map_err(|err| {
+            Error::new(
+                err.kind,
+                format!("request {}: failed to send the response", request_id),
+            )
+            .with_cause(err)
+        })
+    }
+
+    /// the ID of the request whose serving panicked, it's cleared once taken.
+    pub fn take_request_id(&mut self) -> Option<RequestId> {
+        let request_id = self.runner.request_id().cloned();
+        self.runner.set_request_id(None);
+        request_id
+    }
+
+    fn serve<W: Write>(
+        &mut self,
+        req: &HttpRequest,
+        resp: &mut HttpResponse<W>,
+        request_id: &RequestId,
+    ) -> Result<()> {
+        self.runner.metrics.reset();
+        let last_event_id = req.header("Last-Event-ID");
+        let result = match (req.method.as_str(), req.path.as_str(), last_event_id) {
+            ("GET", "/v1/models", _) => self.list_models(resp),
+            ("POST", "/v1/completions" | "/v1/chat/completions", Some(last_event_id)) => {
+                self.resume(last_event_id, resp)
+            }
+            ("POST", "/v1/completions", None) => {
+                parse_body(&req.body).and_then(|body| self.completions(body, request_id, resp))
+            }
+            ("POST", "/v1/chat/completions", None) => {
+                parse_body(&req.body).and_then(|body| self.chat_completions(body, request_id, resp))
+            }
+            (method, path, _) => {
+                let body = error_body(
+                    &format!("unknown route {} {}", method, path),
+                    "invalid_request_error",
+                );
+                return resp.send_json(404, &body);
+            }
+        };
+        if let Some(shared) = &self.ttft_report {
+            let mut report = shared.lock().unwrap();
+            if report.is_some_and(|report| report.n_prompt_tokens > 0) {
+                eprintln!("request {}: {}", request_id, report.take().unwrap());
+            }
+        }
+        self.log_request(request_id, req, &result);
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => send_error(resp, &err),
+        }
+    }
+
+    // the replicas on the same device share its metrics, the op walltimes of the requests
+    // served at the same time are mixed up
+    fn log_request(&self, request_id: &RequestId, req: &HttpRequest, result: &Result<()>) {
+        let metrics = &self.runner.metrics;
+        let status = match result {
+            Ok(()) => "ok".to_string(),
+            Err(err) => format!("error: {}", err),
+        };
+        eprintln!(
+            "request {}: {} {} {} in {}ms, forward {:.1}ms, matmul {:.1}ms, batch_matmul \
+             {:.1}ms, sample {:.1}ms",
+            request_id,
+            req.method,
+            req.path,
+            status,
+            self.request_started_at.elapsed().as_millis(),
+            metrics.forward_walltime.as_millis(),
+            metrics.matmul_walltime.as_millis(),
+            metrics.batch_matmul_walltime.as_millis(),
+            metrics.sample_walltime.as_millis(),
+        );
+    }
+
+    // resend the events of the stream after the Last-Event-ID, the generation may still be
+    // running on another replica
+    fn resume<W: Write>(&self, last_event_id: &str, resp: &mut HttpResponse<W>) -> Result<()> {
+        let (log, cursor) = self.streams.resume(last_event_id)?;
+        let result = follow_stream(&log, cursor, self.streams.keep_alive(), resp);
+        log.detach();
+        result
+    }
+
+    fn list_models<W: Write>(&self, resp: &mut HttpResponse<W>) -> Result<()> {
+        resp.send_json(
+            200,
+            &json!({
+                "object": "list",
+                "data": [{
+                    "id": self.options.model_name,
+                    "object": "model",
+                    "created": self.created,
+                    "owned_by": "crabml",
+                }],
+            }),
+        )
+    }
+
+    fn completions<W: Write>(
+        &mut self,
+        req: CompletionRequest,
+        request_id: &RequestId,
+        resp: &mut HttpResponse<W>,
+    ) -> Result<()> {
+        let mut prompts = req.prompt.into_vec();
+        if prompts.len() != 1 {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!("expect exactly one prompt, but got {}", prompts.len()),
+            ));
+        }
+        let prompt = prompts.remove(0);
+        let mut trimmer = self.prepare(&req.params)?;
+        let (pos, _prev_token, token) = self.runner.prefill(&prompt, true, true)?;
+        self.record_ttft(Instant::now(), Duration::ZERO, true)?;
+
+        let mut reply = Reply::new(
+            Endpoint::Completions,
+            request_id,
+            &self.options.model_name,
+            &req.params,
+            &self.streams,
+        );
+        reply.start(resp)?;
+        let pieces = self.runner.generate(pos, token, None);
+        let (text, n_tokens) = reply.send_pieces(resp, pieces, &mut trimmer)?;
+        let finish_reason = self.finish_reason(&trimmer);
+        reply.finish(resp, &text, finish_reason, pos, n_tokens)
+    }
+
+    fn chat_completions<W: Write>(
+        &mut self,
+        req: ChatCompletionRequest,
+        request_id: &RequestId,
+        resp: &mut HttpResponse<W>,
+    ) -> Result<()> {
+        let messages = req
+            .messages
+            .into_iter()
+            .map(ChatMessageParam::into_message)
+            .collect::<Result<Vec<_>>>()?;
+        // a misplaced message is rejected here, before the runner is touched
+        let (system_prompt, turns) = ChatMessage::split_turns(&messages)?;
+        let (last, history) = match turns.split_last() {
+            Some((last, history)) if last.role == ChatRole::User => (last, history),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::BadInput,
+                    "the last message should be from the user",
+                ));
+            }
+        };
+
+        // tell the client up front if the conversation does not fit, instead of failing in
+        // the middle of restoring it
+        let budget = ChatTemplate::for_runner(&self.runner)?.token_budget(
+            self.runner.tokenizer(),
+            &messages,
+            self.runner.context_limit(),
+        )?;
+        if !budget.fits() || budget.remaining == 0 {
+            return Err(Error::new(
+                ErrorKind::ContextOverflow,
+                format!(
+                    "the messages take {} tokens, leave no room in the context of {} tokens",
+                    budget.prompt_tokens, budget.context_limit
+                ),
+            ));
+        }
+
+        let mut trimmer = self.prepare(&req.params)?;
+        let mut system_prompt = system_prompt.map(str::to_string);
+        let restore_started_at = Instant::now();
+        for turn in history.chunks_exact(2) {
+            Llama2Chat::new(&mut self.runner, &turn[0].content, system_prompt.take())?
+                .restore(&turn[1].content)?;
+        }
+        let history_restore = restore_started_at.elapsed();
+
+        let mut reply = Reply::new(
+            Endpoint::ChatCompletions,
+            request_id,
+            &self.options.model_name,
+            &req.params,
+            &self.streams,
+        );
+        reply.start(resp)?;
+        let ((text, n_tokens), prefilled_at) = {
+            let mut chat = Llama2Chat::new(&mut self.runner, &last.content, system_prompt.take())?;
+            let pieces = chat.reply()?;
+            let prefilled_at = Instant::now();
+            let sent = reply.send_pieces(resp, pieces, &mut trimmer)?;
+            (sent, prefilled_at)
+        };
+        self.record_ttft(prefilled_at, history_restore, false)?;
+        let finish_reason = self.finish_reason(&trimmer);
+        reply.finish(resp, &text, finish_reason, budget.prompt_tokens, n_tokens)
+    }
+
+    // empty the KV cache and set up the sampler and the max tokens of the request, returns the
+    // trimmer of its stop strings
+    fn prepare(&mut self, params: &SamplingParams) -> Result<StopTrimmer> {
+        let temperature = params.temperature.unwrap_or(self.options.temperature);
+        let top_p = params.top_p.unwrap_or(self.options.top_p);
+        if temperature < 0.0 || !(0.0..=1.0).contains(&top_p) {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "invalid temperature {} or top_p {}, expect temperature >= 0 and top_p in \
+                     [0, 1]",
+                    temperature, top_p
+                ),
+            ));
+        }
+        let max_tokens = params.max_tokens.unwrap_or(self.options.max_tokens);
+
+        self.runner.reset()?;
+        self.runner.set_sampler(Llama2Sampler::new_with_topk(
+            self.runner.conf().vocab_size,
+            temperature,
+            0,
+            top_p,
+            params.seed,
+        ));
+        self.runner
+            .set_stopping_criteria(StoppingCriteriaList::new().with(MaxTokens(max_tokens)));
+        self.runner
+            .set_loop_watchdog(self.options.loop_watchdog.then(LoopWatchdog::new));
+        let stop = match &params.stop {
+            Some(OneOrMany::One(s)) => vec![s.clone()],
+            Some(OneOrMany::Many(v)) => v.clone(),
+            None => vec![],
+        };
+        Ok(StopTrimmer::new(stop))
+    }
+
+    // fill in the TTFT report with the first request which prefills a prompt. the time it
+    // spent before the first token on neither the chat history, the tokenizer nor the prefill
+    // is its queueing, like waiting for a free replica. the requests wait in the backlog of the
+    // listener before they're accepted, which can not be measured. the faults of the weights
+    // are split out of the prefill by a prefill on the warm weights, which delays this request
+    fn record_ttft(
+        &mut self,
+        prefilled_at: Instant,
+        history_restore: Duration,
+        batched: bool,
+    ) -> Result<()> {
+        let (Some(shared), Some(timing)) = (&self.ttft_report, self.runner.prefill_timing()) else {
+            return Ok(());
+        };
+        let mut shared = shared.lock().unwrap();
+        let Some(report) = shared.as_mut().filter(|r| r.n_prompt_tokens == 0) else {
+            return Ok(());
+        };
+        let elapsed = prefilled_at.duration_since(self.request_started_at);
+        *report = report.with_prefill(timing);
+        report.history_restore = history_restore;
+        report.queueing =
+            elapsed.saturating_sub(history_restore + timing.tokenize + timing.compute);
+        if let Some(warm) = self.runner.time_warm_prefill(timing.n_tokens, batched)? {
+            *report = report.with_warm_prefill(warm.compute);
+        }
+        Ok(())
+    }
+
+    fn finish_reason(&self, trimmer: &StopTrimmer) -> &'static str {
+        finish_reason(trimmer.is_stopped(), self.runner.stop_reason())
+    }
+}
+
+// the end of the context window is a "length" as well to the clients. a generation cut short
+// by the loop watchdog is told apart from a natural stop as "loop"
+fn finish_reason(stopped_by_string: bool, reason: Option<StopReason>) -> &'static str {
+    match reason {
+        _ if stopped_by_string => "stop",
+        Some(reason) if reason.finish_reason().starts_with("length") => "length",
+        Some(StopReason::Loop) => "loop",
+        _ => "stop",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Completions,
+    ChatCompletions,
+}
+
+/// the response of a request in the shape of its endpoint, a JSON body or a stream of the
+/// chunks ending with `[DONE]`. the chunks of a stream are logged with their IDs, and the
+/// generation goes on after the client is gone, until it resumes or abandons the stream.
+struct Reply {
+    endpoint: Endpoint,
+    request_id: RequestId,
+    id: String,
+    created: u64,
+    model: String,
+    stream: bool,
+    log: Option<Arc<StreamLog>>,
+    // whether the client which sent the request is still reading the stream
+    attached: bool,
+    abandoned: bool,
+    keep_alive: Duration,
+    last_sent: Instant,
+}
+
+impl Reply {
+    // the id of the completion is derived from the request ID, so the completions the clients
+    // keep can be looked up in the logs
+    fn new(
+        endpoint: Endpoint,
+        request_id: &RequestId,
+        model: &str,
+        params: &SamplingParams,
+        streams: &StreamRegistry,
+    ) -> Self {
+        let prefix = match endpoint {
+            Endpoint::Completions => "cmpl",
+            Endpoint::ChatCompletions => "chatcmpl",
+        };
+        let id = format!("{}-{}", prefix, request_id);
+        let log = params.stream.then(|| streams.open(&id));
+        Self {
+            endpoint,
+            request_id: request_id.clone(),
+            id,
+            created: unix_secs(),
+            model: model.to_string(),
+            stream: params.stream,
+            log,
+            attached: true,
+            abandoned: false,
+            keep_alive: streams.keep_alive(),
+            last_sent: Instant::now(),
+        }
+    }
+
+    fn start<W: Write>(&mut self, resp: &mut HttpResponse<W>) -> Result<()> {
+        if !self.stream {
+            return Ok(());
+        }
+        resp.start_events()?;
+        if self.endpoint == Endpoint::ChatCompletions {
+            let choice = json!({
+                "index": 0,
+                "delta": { "role": "assistant", "content": "" },
+                "finish_reason": null,
+            });
+            let chunk = self.body(choice, None).to_string();
+            self.emit(resp, chunk);
+        }
+        Ok(())
+    }
+
+    // log the event of the stream, and send it if the client is still there
+    fn emit<W: Write>(&mut self, resp: &mut HttpResponse<W>, data: String) {
+        let Some(log) = &self.log else {
+            return;
+        };
+        let cursor = log.push(data.clone());
+        if !self.attached {
+            return;
+        }
+        let sent = resp.send_event_with_id(&log.event_id(cursor), &data);
+        self.after_send(sent);
+    }
+
+    // send a comment if the stream is silent for a while, the tokens may come slowly on a large
+    // model
+    fn keep_alive<W: Write>(&mut self, resp: &mut HttpResponse<W>) {
+        if self.stream && self.attached && self.last_sent.elapsed() >= self.keep_alive {
+            let sent = resp.send_comment("keep-alive");
+            self.after_send(sent);
+        }
+    }
+
+    fn after_send(&mut self, sent: Result<()>) {
+        match sent {
+            Ok(()) => self.last_sent = Instant::now(),
+            Err(err) => {
+                eprintln!(
+                    "request {}: the client of {} is gone, keep generating for it to resume: {}",
+                    self.request_id, self.id, err
+                );
+                self.attached = false;
+                if let Some(log) = &self.log {
+                    log.detach();
+                }
+            }
+        }
+    }
+
+    // run the pieces through the stop strings and stream them, returns the text and the number
+    // of the generated tokens
+    fn send_pieces<W: Write>(
+        &mut self,
+        resp: &mut HttpResponse<W>,
+        pieces: impl Iterator<Item = Result<String>>,
+        trimmer: &mut StopTrimmer,
+    ) -> Result<(String, usize)> {
+        let mut text = String::new();
+        let mut n_tokens = 0;
+        for piece in pieces {
+            let piece = piece?;
+            n_tokens += 1;
+            let out = trimmer.push(&piece);
+            self.send_piece(resp, &out);
+            text.push_str(&out);
+            if trimmer.is_stopped() {
+                break;
+            }
+            if self.log.as_ref().is_some_and(|log| log.is_abandoned()) {
+                eprintln!(
+                    "request {}: no client resumed the stream {}, stop generating",
+                    self.request_id, self.id
+                );
+                self.abandoned = true;
+                break;
+            }
+            self.keep_alive(resp);
+        }
+        let out = trimmer.finish();
+        self.send_piece(resp, &out);
+        text.push_str(&out);
+        Ok((text, n_tokens))
+    }
+
+    fn send_piece<W: Write>(&mut self, resp: &mut HttpResponse<W>, text: &str) {
+        if !self.stream || text.is_empty() {
+            return;
+        }
+        let choice = match self.endpoint {
+            Endpoint::Completions => {
+                json!({ "index": 0, "text": text, "logprobs": null, "finish_reason": null })
+            }
+            Endpoint::ChatCompletions => {
+                json!({ "index": 0, "delta": { "content": text }, "finish_reason": null })
+            }
+        };
+        let chunk = self.body(choice, None).to_string();
+        self.emit(resp, chunk);
+    }
+
+    fn finish<W: Write>(
+        &mut self,
+        resp: &mut HttpResponse<W>,
+        text: &str,
+        finish_reason: &str,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    ) -> Result<()> {
+        if self.abandoned {
+            let body = error_body(
+                "the stream is abandoned, no client resumed it in the grace period",
+                "server_error",
+            );
+            self.emit(resp, body.to_string());
+            return Ok(());
+        }
+        if self.stream {
+            let choice = match self.endpoint {
+                Endpoint::Completions => json!({
+                    "index": 0,
+                    "text": "",
+                    "logprobs": null,
+                    "finish_reason": finish_reason,
+                }),
+                Endpoint::ChatCompletions => {
+                    json!({ "index": 0, "delta": {}, "finish_reason": finish_reason })
+                }
+            };
+            let chunk = self.body(choice, None).to_string();
+            self.emit(resp, chunk);
+            self.emit(resp, "[DONE]".to_string());
+            return Ok(());
+        }
+
+        let choice = match self.endpoint {
+            Endpoint::Completions => json!({
+                "index": 0,
+                "text": text,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+            Endpoint::ChatCompletions => json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": finish_reason,
+            }),
+        };
+        let usage = json!({
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        });
+        resp.send_json(200, &self.body(choice, Some(usage)))
+    }
+
+    fn body(&self, choice: Value, usage: Option<Value>) -> Value {
+        let object = match (self.endpoint, self.stream) {
+            (Endpoint::Completions, _) => "text_completion",
+            (Endpoint::ChatCompletions, false) => "chat.completion",
+            (Endpoint::ChatCompletions, true) => "chat.completion.chunk",
+        };
+        let mut body = json!({
+            "id": self.id,
+            "object": object,
+            "created": self.created,
+            "model": self.model,
+            "choices": [choice],
+        });
+        if let Some(usage) = usage {
+            body["usage"] = usage;
+        }
+        body
+    }
+}
+
+impl Drop for Reply {
+    // the readers of the stream finish after the last event, even if the generation failed
+    fn drop(&mut self) {
+        if let Some(log) = &self.log {
+            log.finish();
+        }
+    }
+}
+
+// send the events of the stream after the cursor, and wait for the next ones until the stream
+// is finished
+fn follow_stream<W: Write>(
+    log: &StreamLog,
+    mut cursor: usize,
+    keep_alive: Duration,
+    resp: &mut HttpResponse<W>,
+) -> Result<()> {
+    resp.start_events()?;
+    loop {
+        let (events, finished) = log.wait_events(cursor, keep_alive);
+        if events.is_empty() && !finished {
+            resp.send_comment("keep-alive")?;
+        }
+        for data in events {
+            cursor += 1;
+            resp.send_event_with_id(&log.event_id(cursor), &data)?;
+        }
+        if finished {
+            return Ok(());
+        }
+    }
+}
+
+/// cuts the generated text at the first stop string. the stop strings may span over the
+/// tokens, so the tail which may begin a stop string is held back until it's told apart.
+struct StopTrimmer {
+    stop: StopStrings,
+    pending: String,
+    stopped: bool,
+}
+
+impl StopTrimmer {
+    fn new(stop: Vec<String>) -> Self {
+        Self {
+            stop: StopStrings(stop),
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// push a generated piece, returns the text which can be sent.
+    fn push(&mut self, piece: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(piece);
+        if let Some(at) = self.stop.find(&self.pending) {
+            self.pending.truncate(at);
+            self.stopped = true;
+            return std::mem::take(&mut self.pending);
+        }
+        let held = self.stop.held_len(&self.pending);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    /// the text held back at the end of the generation.
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// the X-Request-Id of the client if it's a sane token to echo in the headers and the logs,
+// otherwise a generated one
+fn request_id_of(req: &HttpRequest) -> RequestId {
+    match req.header("X-Request-Id") {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            RequestId::from(id)
+        }
+        _ => RequestId::generate(),
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    serde_json::from_slice(body).map_err(|err| {
+        Error::new(
+            ErrorKind::BadInput,
+            format!("invalid request body: {}", err),
+        )
+    })
+}
+
+fn error_body(message: &str, kind: &str) -> Value {
+    json!({ "error": { "message": message, "type": kind, "code": null } })
+}
+
+/// send the error in the error object of OpenAI, or as an event if the stream has started.
+pub fn send_error<W: Write>(resp: &mut HttpResponse<W>, err: &Error) -> Result<()> {
+    let (status, kind) = match err.kind {
+        ErrorKind::BadInput | ErrorKind::ContextOverflow => (400, "invalid_request_error"),
+        _ => (500, "server_error"),
+    };
+    let body = error_body(&err.message, kind);
+    if resp.headers_sent() {
+        return resp.send_event(&body.to_string());
+    }
+    resp.send_json(status, &body)
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish_reason() {
+        assert_eq!(finish_reason(false, Some(StopReason::Eos)), "stop");
+        assert_eq!(finish_reason(false, Some(StopReason::MaxSteps)), "length");
+        assert_eq!(
+            finish_reason(false, Some(StopReason::ContextLength)),
+            "length"
+        );
+        assert_eq!(finish_reason(false, Some(StopReason::Loop)), "loop");
+        assert_eq!(finish_reason(true, Some(StopReason::Loop)), "stop");
+        assert_eq!(finish_reason(false, None), "stop");
+    }
+
+    #[test]
+    fn test_stop_trimmer() {
+        let mut trimmer = StopTrimmer::new(vec!["\n\n".to_string(), "END".to_string()]);
+        assert_eq!(trimmer.push("Once upon"), "Once upon");
+        assert_eq!(trimmer.push(" a time\n"), " a time");
+        assert_eq!(trimmer.push("there"), "\nthere");
+        assert_eq!(trimmer.push(" E"), " ");
+        assert_eq!(trimmer.push("N"), "");
+        assert_eq!(trimmer.push("D of it"), "");
+        assert!(trimmer.is_stopped());
+        assert_eq!(trimmer.push("more"), "");
+        assert_eq!(trimmer.finish(), "");
+
+        let mut trimmer = StopTrimmer::new(vec!["".to_string()]);
+        assert_eq!(trimmer.push("ab"), "ab");
+        assert!(!trimmer.is_stopped());
+
+        // the held tail is sent at the end if no stop string follows
+        let mut trimmer = StopTrimmer::new(vec!["</s>".to_string()]);
+        assert_eq!(trimmer.push("好</"), "好");
+        assert_eq!(trimmer.finish(), "</");
+    }
+
+    #[test]
+    fn test_follow_stream() -> Result<()> {
+        let streams = StreamRegistry::new(Duration::from_secs(60), Duration::from_secs(1));
+        let log = streams.open("cmpl-req-1");
+        for data in ["a", "b", "[DONE]"] {
+            log.push(data.to_string());
+        }
+        log.finish();
+
+        // the events after the Last-Event-ID are resent with their IDs
+        let (log, cursor) = streams.resume("cmpl-req-1:1")?;
+        let mut out = vec![];
+        follow_stream(
+            &log,
+            cursor,
+            streams.keep_alive(),
+            &mut HttpResponse::new(&mut out),
+        )?;
+        let text = String::from_utf8(out).unwrap();
+        assert!(
+            text.ends_with(
+                "\r\n\r\nid: cmpl-req-1:2\ndata: b\n\nid: cmpl-req-1:3\ndata: [DONE]\n\n"
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_id_of() {
+        let req = |headers: &[(&str, &str)]| HttpRequest {
+            method: "POST".to_string(),
+            path: "/v1/completions".to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            body: vec![],
+        };
+        let id = request_id_of(&req(&[("x-request-id", "trace-42")]));
+        assert_eq!(id, RequestId::from("trace-42"));
+
+        // the missing, the empty, the too long and the unprintable IDs are generated
+        for headers in [vec![], vec![("X-Request-Id", "")], vec![(
+            "X-Request-Id",
+            "a b",
+        )]] {
+            let id = request_id_of(&req(&headers));
+            assert!(id.as_str().starts_with("req-"), "{}", id);
+        }
+        let long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        let id = request_id_of(&req(&[("X-Request-Id", &long)]));
+        assert!(id.as_str().starts_with("req-"));
+    }
+
+    #[test]
+    fn test_parse_requests() -> Result<()> {
+        let req: CompletionRequest =
+            parse_body(br#"{"model":"x","prompt":"hi","max_tokens":8,"stop":"\n"}"#)?;
+        assert_eq!(req.prompt.into_vec(), vec!["hi".to_string()]);
+        assert_eq!(req.params.max_tokens, Some(8));
+        assert!(!req.params.stream);
+
+        let req: ChatCompletionRequest = parse_body(
+            br#"{"messages":[{"role":"user","content":"hi"}],"stream":true,"stop":["a","b"]}"#,
+        )?;
+        assert!(req.params.stream);
+        assert_eq!(req.params.stop.map(OneOrMany::into_vec).unwrap().len(), 2);
+        let message = req.messages.into_iter().next().unwrap().into_message()?;
+        assert_eq!(message, ChatMessage::new(ChatRole::User, "hi"));
+
+        let err = parse_body::<CompletionRequest>(br#"{"max_tokens":8}"#).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+}
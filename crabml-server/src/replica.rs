@@ -0,0 +1,157 @@
+use std::fmt::Display;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crabml::backends::cpu::CpuTensor;
+#[cfg(feature = "wgpu")]
+use crabml::backends::wgpu::WgpuTensorDevice;
+#[cfg(feature = "wgpu")]
+use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::CpuLlama2Model;
+#[cfg(feature = "wgpu")]
+use crabml_llama2::WgpuLlama2Model;
+
+use crate::http::read_request;
+use crate::http::DeadlineReader;
+use crate::http::HttpResponse;
+use crate::openai::send_error;
+use crate::openai::OpenAIServer;
+
+/// the connections accepted by the listener with when they're accepted, the replicas take the
+/// next one whenever they are free, so a long completion on one replica does not hold back the
+/// others.
+pub type ConnectionQueue = Arc<Mutex<Receiver<(Instant, TcpStream)>>>;
+
+// a client which connects but does not send the whole request in time is dropped, so it does
+// not hold the replica, which serves one connection at a time
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// where a replica of the model runs, each replica serves one request at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaDevice {
+    Cpu,
+
+    /// the GPU at this index of `WgpuTensorDevice::adapter_names`.
+    Gpu(usize),
+}
+
+impl Display for ReplicaDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicaDevice::Cpu => write!(f, "cpu"),
+            ReplicaDevice::Gpu(index) => write!(f, "gpu:{}", index),
+        }
+    }
+}
+
+impl ReplicaDevice {
+    /// a replica on each of the GPUs, or a single one on the CPU if no GPU is given. a GPU
+    /// given twice takes two replicas, if it has the memory for them.
+    pub fn from_gpus(gpus: &[usize]) -> Result<Vec<Self>> {
+        if gpus.is_empty() {
+            return Ok(vec![ReplicaDevice::Cpu]);
+        }
+        #[cfg(feature = "wgpu")]
+        {
+            let names = WgpuTensorDevice::adapter_names();
+            if let Some(index) = gpus.iter().find(|index| **index >= names.len()) {
+                return Err(Error::new(
+                    ErrorKind::BadInput,
+                    format!("no GPU at {}, found {} GPUs", index, names.len()),
+                ));
+            }
+            Ok(gpus
+                .iter()
+                .map(|index| ReplicaDevice::Gpu(*index))
+                .collect())
+        }
+        #[cfg(not(feature = "wgpu"))]
+        Err(Error::new(
+            ErrorKind::NotImplemented,
+            "the GPU replicas need the wgpu feature of crabml-server",
+        ))
+    }
+
+    /// the runner of a replica, the GPU replicas upload their own copy of the weights, and move
+    /// the session to the CPU if their GPU fails.
+    pub fn load_runner<'a>(
+        &self,
+        model: &CpuLlama2Model<'a>,
+        context: usize,
+    ) -> Result<Llama2Runner<CpuTensor<'a>>> {
+        let runner = Llama2Runner::new(model, context, true)?;
+        match self {
+            ReplicaDevice::Cpu => Ok(runner),
+            #[cfg(feature = "wgpu")]
+            ReplicaDevice::Gpu(index) => {
+                let options = WgpuTensorDeviceOptions::new()
+                    .with_staging_buf_bytes(model.conf.vocab_size * 4)
+                    .with_adapter_index(*index);
+                let model_wgpu = WgpuLlama2Model::from_cpu(model, WgpuTensorDevice::new(options)?)?;
+                let runner_wgpu = Llama2Runner::new(&model_wgpu, context, false)?;
+                Ok(runner.with_forward_offload(Box::new(runner_wgpu)))
+            }
+            #[cfg(not(feature = "wgpu"))]
+            ReplicaDevice::Gpu(_) => Err(Error::new(
+                ErrorKind::NotImplemented,
+                "the GPU replicas need the wgpu feature of crabml-server",
+            )),
+        }
+    }
+}
+
+/// serve the connections of the queue until the listener is gone.
+pub fn serve_replica(server: &mut OpenAIServer, queue: &ConnectionQueue) {
+    loop {
+        // the lock is released before serving the connection
+        let next = queue.lock().unwrap().recv();
+        let Ok((accepted_at, stream)) = next else {
+            return;
+        };
+        // a panic on a request drops its connection but keeps the replica, the next request
+        // starts over from an empty KV cache
+        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+            serve_connection(server, accepted_at, stream)
+        }));
+        match served {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("failed to serve the connection: {}", err),
+            Err(_) => match server.take_request_id() {
+                Some(request_id) => {
+                    eprintln!(
+                        "request {}: the connection panicked, dropped it",
+                        request_id
+                    )
+                }
+                None => eprintln!("the connection panicked, dropped it"),
+            },
+        }
+    }
+}
+
+fn serve_connection(
+    server: &mut OpenAIServer,
+    accepted_at: Instant,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut reader = BufReader::new(DeadlineReader::new(
+        stream.try_clone()?,
+        REQUEST_READ_TIMEOUT,
+    ));
+    let mut resp = HttpResponse::new(stream);
+    match read_request(&mut reader) {
+        Ok(req) => server.handle(&req, &mut resp, accepted_at),
+        Err(err) => send_error(&mut resp, &err),
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+
+/// the events of the streamed responses, shared by the replicas. a client which lost the
+/// connection resumes the stream on any replica by sending the request again with the
+/// Last-Event-ID of the last event it received.
+///
+/// the generation goes on while the client is gone, and is stopped if no client comes back
+/// within the grace period. the events of a finished stream are kept for the grace period as
+/// well, so a single replica which is busy generating can still be resumed after it.
+#[derive(Debug)]
+pub struct StreamRegistry {
+    grace: Duration,
+    keep_alive: Duration,
+    streams: Mutex<HashMap<String, Arc<StreamLog>>>,
+}
+
+impl StreamRegistry {
+    /// keep_alive is how long a stream may be silent before a comment is sent, so the proxies
+    /// and the clients do not time out the connection on a slow generation.
+    pub fn new(grace: Duration, keep_alive: Duration) -> Self {
+        Self {
+            grace,
+            keep_alive,
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    /// start logging the events of a stream, the client which sent the request is attached.
+    pub fn open(&self, id: &str) -> Arc<StreamLog> {
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|_, log| !log.is_expired());
+        let log = Arc::new(StreamLog::new(id, self.grace));
+        streams.insert(id.to_string(), log.clone());
+        log
+    }
+
+    /// attach to the stream of the Last-Event-ID, returns its log and the cursor of the event.
+    /// the client is detached with `StreamLog::detach` once it's gone.
+    pub fn resume(&self, last_event_id: &str) -> Result<(Arc<StreamLog>, usize)> {
+        let (id, cursor) = last_event_id
+            .rsplit_once(':')
+            .and_then(|(id, cursor)| Some((id, cursor.parse::<usize>().ok()?)))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::BadInput,
+                    format!("invalid Last-Event-ID {:?}", last_event_id),
+                )
+            })?;
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|_, log| !log.is_expired());
+        let log = streams.get(id).cloned().ok_or_else(|| {
+            Error::new(
+                ErrorKind::BadInput,
+                format!("the stream {} is not found or expired", id),
+            )
+        })?;
+        let mut state = log.state.lock().unwrap();
+        if cursor > state.events.len() {
+            return Err(Error::new(
+                ErrorKind::BadInput,
+                format!(
+                    "the event {} is beyond the {} events of the stream {}",
+                    cursor,
+                    state.events.len(),
+                    id
+                ),
+            ));
+        }
+        state.readers += 1;
+        drop(state);
+        Ok((log, cursor))
+    }
+}
+
+/// the events of a stream, the cursor of an event is its 1-based position in the stream.
+#[derive(Debug)]
+pub struct StreamLog {
+    id: String,
+    grace: Duration,
+    state: Mutex<StreamState>,
+    changed: Condvar,
+}
+
+#[derive(Debug)]
+struct StreamState {
+    events: Vec<String>,
+    finished_at: Option<Instant>,
+    // the clients reading the stream, and when the last one left
+    readers: usize,
+    left_at: Instant,
+}
+
+impl StreamLog {
+    fn new(id: &str, grace: Duration) -> Self {
+        Self {
+            id: id.to_string(),
+            grace,
+            state: Mutex::new(StreamState {
+                events: vec![],
+                finished_at: None,
+                readers: 1,
+                left_at: Instant::now(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// the ID of the event at the cursor, which the client sends back in the Last-Event-ID.
+    pub fn event_id(&self, cursor: usize) -> String {
+        format!("{}:{}", self.id, cursor)
+    }
+
+    /// append an event, returns its cursor.
+    pub fn push(&self, data: String) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.events.push(data);
+        self.changed.notify_all();
+        state.events.len()
+    }
+
+    /// no more events are pushed, the readers finish after the last event.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished_at.is_none() {
+            state.finished_at = Some(Instant::now());
+        }
+        self.changed.notify_all();
+    }
+
+    pub fn detach(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.readers = state.readers.saturating_sub(1);
+        state.left_at = Instant::now();
+    }
+
+    /// whether all the clients are gone for longer than the grace period, the generation of
+    /// an abandoned stream is stopped.
+    pub fn is_abandoned(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.readers == 0 && state.left_at.elapsed() > self.grace
+    }
+
+    /// wait until there're events after the cursor or the timeout, returns the events and
+    /// whether the stream is finished, no events follow once it's finished.
+    pub fn wait_events(&self, cursor: usize, timeout: Duration) -> (Vec<String>, bool) {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |s| {
+                s.events.len() <= cursor && s.finished_at.is_none()
+            })
+            .unwrap();
+        let events = state.events[cursor.min(state.events.len())..].to_vec();
+        (events, state.finished_at.is_some())
+    }
+
+    fn is_expired(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .finished_at
+            .is_some_and(|finished_at| finished_at.elapsed() > self.grace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_resume() -> Result<()> {
+        let streams = StreamRegistry::new(Duration::from_secs(60), Duration::from_secs(1));
+        let log = streams.open("cmpl-req-1");
+        assert_eq!(log.push("a".to_string()), 1);
+        assert_eq!(log.push("b".to_string()), 2);
+        assert_eq!(log.event_id(2), "cmpl-req-1:2");
+
+        let (resumed, cursor) = streams.resume("cmpl-req-1:1")?;
+        assert_eq!(cursor, 1);
+        let timeout = Duration::from_millis(1);
+        assert_eq!(
+            resumed.wait_events(cursor, timeout),
+            (vec!["b".to_string()], false)
+        );
+        assert_eq!(resumed.wait_events(2, timeout), (vec![], false));
+        log.push("[DONE]".to_string());
+        log.finish();
+        assert_eq!(
+            resumed.wait_events(2, timeout),
+            (vec!["[DONE]".to_string()], true)
+        );
+
+        assert!(streams.resume("cmpl-req-1:4").is_err());
+        assert!(streams.resume("cmpl-req-2:0").is_err());
+        assert!(streams.resume("cmpl-req-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_grace() -> Result<()> {
+        let streams = StreamRegistry::new(Duration::ZERO, Duration::from_secs(1));
+        let log = streams.open("cmpl-req-1");
+        assert!(!log.is_abandoned());
+        log.detach();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(log.is_abandoned());
+
+        // the finished streams expire after the grace period
+        log.finish();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(streams.resume("cmpl-req-1:0").is_err());
+        Ok(())
+    }
+}